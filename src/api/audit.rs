@@ -0,0 +1,172 @@
+//! Feeds the audit log (`audit_log_enabled`) from the `/v1` routes. The request and response
+//! bodies are observed as they stream through, so nothing is buffered ahead of the handler or
+//! the client, and the entry is queued once the response has been sent.
+
+use axum::{
+    extract::{ConnectInfo, OriginalUri, Query, Request, State},
+    http::{header, HeaderValue},
+    middleware::Next,
+    response::Response,
+};
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+use std::time::{Instant, SystemTime};
+
+use crate::api::routes::resolve_client_ip;
+use crate::utils::audit::{self, AuditEntry, ResponseObserver};
+use crate::utils::auth::{authenticate_request, AuthQuery};
+use crate::AppState;
+
+const REQUEST_ID_HEADER: &str = "x-request-id";
+
+pub async fn audit_requests(
+    State(state): State<AppState>,
+    connect_info: Option<ConnectInfo<SocketAddr>>,
+    query: Option<Query<AuthQuery>>,
+    request: Request,
+    next: Next,
+) -> Response {
+    let log = match &state.audit_log {
+        Some(log) => log.clone(),
+        None => return next.run(request).await,
+    };
+    let started = Instant::now();
+    let timestamp = audit::timestamp(SystemTime::now());
+
+    // A caller's own request id is kept so its logs and ours can be joined
+    let request_id = request
+        .headers()
+        .get(REQUEST_ID_HEADER)
+        .and_then(|value| value.to_str().ok())
+        .filter(|id| !id.is_empty() && id.len() <= 128)
+        .map(str::to_string)
+        .unwrap_or_else(|| uuid::Uuid::new_v4().to_string());
+    let query = query.map(|Query(query)| query).unwrap_or_default();
+    let auth_result = authenticate_request(request.headers(), &query, &state.auth_state);
+    let client = auth_result.client_key.map(|key| key.name).or(auth_result.client_id);
+    let ip = resolve_client_ip(request.headers(), connect_info, &state.auth_state.ip_filter)
+        .ip()
+        .and_then(|ip| audit::mask_ip(&ip.to_string()));
+    let method = request.method().to_string();
+    // Nesting strips `/v1` from the URI the middleware sees
+    let path = match request.extensions().get::<OriginalUri>() {
+        Some(OriginalUri(uri)) => uri.path().to_string(),
+        None => request.uri().path().to_string(),
+    };
+
+    // Only JSON bodies are kept; uploads such as audio are not worth a copy
+    let is_json = request
+        .headers()
+        .get(header::CONTENT_TYPE)
+        .and_then(|value| value.to_str().ok())
+        .is_some_and(|content_type| content_type.starts_with("application/json"));
+    let captured = Arc::new(Mutex::new(Vec::new()));
+    let request = if is_json {
+        let sink = captured.clone();
+        request.map(|body| audit::observe_body(body, move |chunk| {
+            if let Ok(mut captured) = sink.lock() {
+                captured.extend_from_slice(chunk);
+            }
+        }))
+    } else {
+        request
+    };
+
+    let mut response = next.run(request).await;
+
+    let body = std::mem::take(&mut *captured.lock().unwrap_or_else(|e| e.into_inner()));
+    let summary = audit::describe_request(&body, log.include_content());
+    let entry = AuditEntry {
+        timestamp,
+        request_id: request_id.clone(),
+        method,
+        path,
+        client,
+        ip,
+        model: summary.model,
+        end_user: summary.end_user,
+        prompt_tokens: None,
+        completion_tokens: None,
+        total_tokens: None,
+        latency_ms: 0,
+        status: response.status().as_u16(),
+        prompt: summary.prompt,
+        response: None,
+    };
+    if let Ok(value) = HeaderValue::from_str(&request_id) {
+        response.headers_mut().insert(REQUEST_ID_HEADER, value);
+    }
+    let event_stream = response
+        .headers()
+        .get(header::CONTENT_TYPE)
+        .and_then(|value| value.to_str().ok())
+        .is_some_and(|content_type| content_type.starts_with("text/event-stream"));
+    let mut observer = ResponseObserver::new(log, entry, started, event_stream);
+    response.map(|body| audit::observe_body(body, move |chunk| observer.observe(chunk)))
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::config::Settings;
+    use crate::testing::test_state;
+    use http_body_util::BodyExt;
+    use std::sync::Arc;
+
+    #[tokio::test]
+    async fn test_audit_log_records_v1_requests() {
+        use crate::utils::audit::{self, AuditEntry, AuditLog};
+        use tower::ServiceExt;
+
+        let dir = tempfile::tempdir().unwrap();
+        let storage_dir = dir.path().display().to_string();
+        let log = Arc::new(AuditLog::start(audit::audit_dir(&storage_dir), 1024 * 1024, true).unwrap());
+        let mut state = test_state();
+        state.settings = Settings { storage_dir, ..Settings::default() }.into();
+        state.audit_log = Some(log.clone());
+        let app = crate::build_app(state).await.unwrap();
+
+        let chat = hyper::Request::builder()
+            .method("POST")
+            .uri("/v1/chat/completions")
+            .header("authorization", "Bearer 123")
+            .header("content-type", "application/json")
+            .header("x-forwarded-for", "203.0.113.9")
+            .header("x-request-id", "req-audit-1")
+            .body(axum::body::Body::from(r#"{"model":"gemini-2.5-flash","messages":[{"role":"user","content":"hello"}]}"#))
+            .unwrap();
+        let response = app.clone().oneshot(chat).await.unwrap();
+        assert_eq!(response.headers()["x-request-id"], "req-audit-1");
+        let status = response.status().as_u16();
+        response.into_body().collect().await.unwrap();
+        log.flush();
+
+        let get = |uri: &str, auth: bool| {
+            let mut builder = hyper::Request::builder().uri(uri);
+            if auth {
+                builder = builder.header("authorization", "Bearer 123");
+            }
+            builder.body(axum::body::Body::empty()).unwrap()
+        };
+        assert_eq!(app.clone().oneshot(get("/dashboard-api/audit/files", false)).await.unwrap().status(), 401);
+        let response = app.clone().oneshot(get("/dashboard-api/audit/files", true)).await.unwrap();
+        let body = response.into_body().collect().await.unwrap().to_bytes();
+        let listing: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(listing["enabled"], true);
+        let name = listing["files"][0]["name"].as_str().unwrap().to_string();
+
+        let response = app.clone().oneshot(get(&format!("/dashboard-api/audit/files/{}", name), true)).await.unwrap();
+        assert_eq!(response.status(), 200);
+        assert_eq!(response.headers()["content-type"], "application/x-ndjson");
+        let body = response.into_body().collect().await.unwrap().to_bytes();
+        let entry: AuditEntry = serde_json::from_slice(body.split(|&b| b == b'\n').next().unwrap()).unwrap();
+        assert_eq!(entry.request_id, "req-audit-1");
+        assert_eq!(entry.path, "/v1/chat/completions");
+        assert_eq!(entry.model.as_deref(), Some("gemini-2.5-flash"));
+        assert_eq!(entry.ip.as_deref(), Some("203.0.113.0"));
+        assert_eq!(entry.status, status);
+        assert_eq!(entry.prompt.unwrap().text, "user: hello");
+
+        let missing = app.oneshot(get("/dashboard-api/audit/files/settings.json", true)).await.unwrap();
+        assert_eq!(missing.status(), 404);
+    }
+}
@@ -1,39 +1,126 @@
 use axum::{
-    extract::{Query, State},
-    http::{HeaderMap, StatusCode},
-    response::Json,
-    routing::{get, post},
+    extract::{Path, Query, State},
+    http::{header, HeaderMap, StatusCode},
+    response::{sse::{Event, Sse}, IntoResponse, Json, Response},
+    routing::{delete, get, post},
     Router,
 };
+use futures_util::{stream, StreamExt};
 use serde::{Deserialize, Serialize};
-use tracing::{debug, info};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::broadcast::error::RecvError;
+use tracing::{debug, info, warn};
 
 use crate::models::schemas::{ServiceStatus, ApiStats, ConfigInfo, VersionInfo};
-use crate::utils::auth::{authenticate_request, AuthQuery, AuthScope};
+use crate::utils::auth::{authenticate_dashboard_request, unix_now, AuthQuery, AuthScope};
+use crate::utils::ip_filter::parse_ip_net;
+use crate::utils::live_stats::{LiveLogLine, LiveSnapshot, LIVE_LOG_LINES};
+use crate::utils::logging::{log, LOG_MANAGER, VERTEX_LOG_MANAGER};
+use crate::utils::response::sse_response;
+use crate::utils::error_handling::translate_error;
+use crate::utils::cache::CacheFilter;
+use crate::utils::circuit_breaker::{CircuitState, CircuitStatus};
+use crate::utils::endpoints::EndpointStatus;
+use crate::utils::fair_queue::FairQueueStatus;
+use crate::utils::dashboard_sections::{self, fingerprint};
+use crate::utils::{audit, emergency_cleanup, CallOrigin};
+use crate::services::gemini::{GeminiClientTrait, DEFAULT_IMAGE_MODEL};
+use crate::services::moderation::DEFAULT_MODERATION_MODEL;
+use crate::models::schemas::ChatCompletionRequest;
+use crate::utils::insights::Insights;
+use crate::utils::model_policy::ModelPolicy;
+use crate::api::routes::model_catalog;
 use crate::utils::version;
-use crate::config::ConfigManager;
+use crate::config::export::{export_settings, import_settings};
+use crate::config::reload::ReloadReport;
+use crate::config::{startup_summary, ClientKey, ClientKeyScope, ConfigManager, IpBlockEntry, Settings, GEMINI_API_VERSIONS};
 use crate::AppState;
 
 pub fn create_dashboard_routes() -> Router<AppState> {
     Router::new()
         .route("/data", get(get_dashboard_data))
+        .route("/stream", get(stream_live_stats))
         .route("/stats", get(get_stats))
+        .route("/stats/clients", get(get_client_stats))
+        .route("/stats/routes", get(get_route_stats))
+        .route("/stats/models", get(get_model_stats))
+        .route("/stats/models/:model/reset", post(reset_model_stats))
+        .route("/circuits", get(get_circuits))
+        .route("/endpoints", get(get_endpoints))
+        .route("/scheduler", get(get_scheduler))
+        .route("/circuits/:model", post(set_circuit))
         .route("/config", get(get_config))
         .route("/config", post(update_config))
+        .route("/config/warnings", get(get_config_warnings))
+        .route("/config/export", get(export_config))
+        .route("/config/import", post(import_config))
         .route("/update-config", post(update_config))  // Add the update-config endpoint for compatibility
         .route("/reset-stats", post(reset_stats))
         .route("/cache/clear", post(clear_cache))
+        .route("/storage/status", get(get_storage_status))
+        .route("/audit/files", get(list_audit_files))
+        .route("/audit/files/:name", get(download_audit_file))
+        .route("/maintenance/emergency-cleanup", post(run_emergency_cleanup))
         .route("/keys/stats", get(get_key_stats))
+        .route("/keys/:id/reset", post(reset_key))
+        .route("/context-cache", get(get_context_cache))
         .route("/version", get(get_version))
+        .route("/about", get(get_about))
+        .route("/clients", get(list_clients).post(create_client))
+        .route("/clients/:name", delete(revoke_client))
+        .route("/security/block-ip", post(block_ip))
+        .route("/models/refresh", post(refresh_models))
+        .route("/models/policy", get(get_model_policy).put(update_model_policy))
+        .route("/diagnostics/test-chat", post(test_chat))
+        .route("/warmup", post(run_warmup))
+        .route("/vertex-logs", get(get_vertex_logs))
+        .route("/logs/clear", post(clear_logs))
+        .route("/reload", post(reload_settings))
 }
 
-#[derive(Debug, Serialize)]
+/// Every section is present unless the poll passed `since`, in which case only the sections
+/// that changed from then on are
+#[derive(Debug, Default, Serialize)]
 pub struct DashboardResponse {
-    pub status: ServiceStatus,
-    pub stats: ApiStats,
-    pub config: ConfigInfo,
-    pub version: VersionInfo,
-    pub key_stats: Vec<KeyStatInfo>,
+    /// Unix milliseconds to pass as `since` on the next poll
+    pub cursor: u64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub status: Option<ServiceStatus>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub stats: Option<ApiStats>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub config: Option<ConfigInfo>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub version: Option<VersionInfo>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub key_stats: Option<Vec<KeyStatInfo>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub security_warnings: Option<Vec<String>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub insights: Option<Insights>,
+    /// Per-model circuit breaker states
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub circuits: Option<Vec<CircuitStatus>>,
+    /// Upstream endpoints in failover order
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub endpoints: Option<Vec<EndpointStatus>>,
+    /// Most recent last; with `since`, only the lines logged from then on, so a line logged in
+    /// the cursor's millisecond can come twice
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub logs: Option<Vec<LiveLogLine>>,
+    /// Requests and tokens per hour over the last day; with `since`, only the hours that
+    /// end after it
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub hourly_stats: Option<Vec<HourlyBucket>>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct HourlyBucket {
+    /// Unix seconds
+    pub start: u64,
+    pub requests: u32,
+    pub tokens: u64,
 }
 
 #[derive(Debug, Serialize)]
@@ -42,6 +129,139 @@ pub struct KeyStatInfo {
     pub daily_usage: u32,
     pub last_used: String,
     pub consecutive_failures: u32,
+    /// Conversations assigned to this key by `sticky_keys`
+    pub pinned_sessions: usize,
+    /// Requests the key can send right away under its per-minute budget; absent until its
+    /// first request
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub rpm_tokens: Option<u32>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct ClientKeyInfo {
+    pub name: String,
+    pub key_prefix: String,
+    pub scope: ClientKeyScope,
+    pub daily_request_limit: u32,
+    pub daily_token_limit: u64,
+    pub allowed_models: Vec<String>,
+    pub weight: u32,
+}
+
+impl From<&ClientKey> for ClientKeyInfo {
+    fn from(client_key: &ClientKey) -> Self {
+        Self {
+            name: client_key.name.clone(),
+            key_prefix: format!("{}...", &client_key.key[..8.min(client_key.key.len())]),
+            scope: client_key.scope,
+            daily_request_limit: client_key.daily_request_limit,
+            daily_token_limit: client_key.daily_token_limit,
+            allowed_models: client_key.allowed_models.clone(),
+            weight: client_key.weight,
+        }
+    }
+}
+
+#[derive(Debug, Deserialize)]
+pub struct ClientStatsQuery {
+    /// `24h` (default) or `7d`
+    #[serde(default)]
+    pub window: Option<String>,
+    /// `json` (default) or `csv`
+    #[serde(default)]
+    pub format: Option<String>,
+    /// `client` (default), or `end_user` to split keyless traffic by the requests' `user` field
+    #[serde(default)]
+    pub group_by: Option<String>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct ClientUsageInfo {
+    pub client: String,
+    pub named: bool,
+    pub requests: u64,
+    pub errors: u64,
+    pub prompt_tokens: u64,
+    pub completion_tokens: u64,
+    pub total_tokens: u64,
+    pub last_seen: String,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct CreateClientKeyRequest {
+    pub name: String,
+    /// Generated when omitted
+    #[serde(default)]
+    pub key: Option<String>,
+    #[serde(default)]
+    pub scope: ClientKeyScope,
+    #[serde(default)]
+    pub daily_request_limit: u32,
+    #[serde(default)]
+    pub daily_token_limit: u64,
+    #[serde(default)]
+    pub allowed_models: Vec<String>,
+    #[serde(default)]
+    pub weight: Option<u32>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct TestChatRequest {
+    #[serde(default = "default_test_model")]
+    pub model: String,
+    /// Use this configured key instead of the next one in rotation
+    #[serde(default)]
+    pub key_index: Option<usize>,
+}
+
+fn default_test_model() -> String {
+    "gemini-2.0-flash".to_string()
+}
+
+/// How often the live stats task samples the managers while a dashboard is subscribed
+const LIVE_SAMPLE_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Log lines included in `/data`
+const DASHBOARD_LOG_LINES: usize = 50;
+
+/// Characters of the answer included in a test request report
+const TEST_CHAT_PREVIEW_CHARS: usize = 200;
+
+#[derive(Debug, Deserialize)]
+pub struct LogsQuery {
+    #[serde(default = "default_logs_limit")]
+    pub limit: usize,
+    #[serde(default)]
+    pub level: Option<String>,
+}
+
+fn default_logs_limit() -> usize {
+    100
+}
+
+#[derive(Debug, Deserialize)]
+pub struct DashboardDataQuery {
+    /// `cursor` of an earlier response; leaves out the sections unchanged since that poll
+    #[serde(default)]
+    pub since: Option<u64>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct ExportQuery {
+    /// Export passwords, keys and credentials as they are instead of masked
+    #[serde(default)]
+    pub include_secrets: bool,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct BlockIpRequest {
+    /// Address or CIDR range
+    pub ip: String,
+    /// Lift the block after this many seconds; permanent when omitted
+    #[serde(default)]
+    pub expires_in_secs: Option<u64>,
+    #[serde(default)]
+    pub reason: Option<String>,
 }
 
 #[derive(Debug, Deserialize)]
@@ -55,73 +275,240 @@ async fn get_dashboard_data(
     State(state): State<AppState>,
     headers: HeaderMap,
     Query(query): Query<AuthQuery>,
-) -> Result<Json<DashboardResponse>, StatusCode> {
+    Query(data_query): Query<DashboardDataQuery>,
+) -> Result<Response, StatusCode> {
     // Authenticate request
-    let auth_result = authenticate_request(&headers, &query, &state.settings);
+    let auth_result = authenticate_dashboard_request(&headers, &query, &state.auth_state);
     if !auth_result.authenticated {
         return Err(StatusCode::UNAUTHORIZED);
     }
 
-    let uptime = std::time::SystemTime::now()
+    let now = std::time::SystemTime::now()
         .duration_since(std::time::UNIX_EPOCH)
-        .unwrap()
-        .as_secs();
-
-    // Get service status
-    let status = ServiceStatus {
-        running: true,
-        uptime,
-        api_keys_available: state.key_manager.available_keys_count().await,
-        cache_entries: state.cache_manager.size().await,
-    };
-
-    // Get API stats
-    let api_stats = state.stats_manager.get_stats().await;
-    let stats = ApiStats {
-        total_requests: api_stats.total_requests,
-        successful_requests: api_stats.successful_requests,
-        failed_requests: api_stats.failed_requests,
-        tokens_used: api_stats.total_tokens,
-        requests_per_minute: api_stats.requests_last_minute,
-        requests_per_hour: api_stats.requests_last_hour,
-        requests_per_day: api_stats.requests_last_day,
-    };
+        .unwrap();
+    let uptime = now.as_secs();
+    let now_ms = now.as_millis() as u64;
 
-    // Get config info
+    // The cheap inputs of every section come first; the rest is only built when it is sent
+    let api_keys_available = state.key_manager.available_keys_count().await;
+    let requests_waiting_for_key = state.key_manager.waiting_requests();
+    let cache_entries = state.cache_manager.size().await;
+    let cache_hit_ratio = state.cache_manager.hit_ratio();
+    let settings = state.settings.load();
     let config = ConfigInfo {
-        fake_streaming: state.settings.fake_streaming,
-        concurrent_requests: state.settings.concurrent_requests,
-        cache_enabled: state.settings.max_cache_entries > 0,
-        vertex_enabled: state.settings.enable_vertex,
-        search_mode: state.settings.search.search_mode,
+        fake_streaming: settings.fake_streaming,
+        concurrent_requests: settings.concurrent_requests,
+        cache_enabled: settings.max_cache_entries > 0,
+        vertex_enabled: state.vertex_enabled,
+        search_mode: settings.search.search_mode,
+        upstream_header_profile: settings.upstream_header_profile.as_str().to_string(),
     };
+    let version = dashboard_version_info();
+    let lockouts = state.auth_state.login_guard.active_lockouts(std::time::Instant::now());
+    let circuits = state.model_circuits.snapshot(std::time::Instant::now());
+    let endpoints = state.gemini_client.endpoint_status().await;
+    let recent_logs = LOG_MANAGER.get_recent_logs(DASHBOARD_LOG_LINES);
 
-    // Get version info
-    let version = VersionInfo {
-        current: version::get_current_version(),
-        latest: None, // This would be populated by a background task
-        update_available: false,
-    };
+    // Rolling windows move with the clock as well as with new calls
+    let stats_changes = state.stats_manager.changes();
+    let minute = uptime / 60;
+    let fingerprints = [
+        ("status", fingerprint(&(api_keys_available, requests_waiting_for_key, cache_entries))),
+        ("stats", fingerprint(&stats_changes)),
+        ("config", fingerprint(&(serde_json::to_string(&config).ok(), serde_json::to_string(&version).ok()))),
+        ("key_stats", fingerprint(&(stats_changes, api_keys_available, state.settings.load().gemini_api_keys.len(), minute))),
+        ("security_warnings", fingerprint(&lockouts.iter().map(|(ip, _)| ip).collect::<Vec<_>>())),
+        ("insights", fingerprint(&(stats_changes, minute, cache_hit_ratio.to_bits()))),
+        ("circuits", fingerprint(&circuits.iter().map(|c| (&c.model, c.state, c.requests, c.failures)).collect::<Vec<_>>())),
+        ("endpoints", fingerprint(&endpoints.iter().map(|e| (&e.url, e.healthy, e.requests, e.failures)).collect::<Vec<_>>())),
+        ("logs", fingerprint(&(LOG_MANAGER.count(), recent_logs.last().map(|entry| entry.timestamp.timestamp_millis())))),
+        ("hourly_stats", fingerprint(&(stats_changes, minute))),
+    ];
+    let etag = dashboard_sections::etag(&fingerprints);
+    let (cursor, modified) = state.dashboard_sections.observe(&fingerprints, now_ms);
+    let cache_headers = [(header::ETAG, etag.clone()), (header::CACHE_CONTROL, "no-cache".to_string())];
+    if etag_matches(&headers, &etag) {
+        return Ok((StatusCode::NOT_MODIFIED, cache_headers).into_response());
+    }
+    let send = |section: &str| data_query.since.is_none_or(|since| modified[section] > since);
+
+    let mut response = DashboardResponse { cursor, ..Default::default() };
+    if send("status") {
+        response.status = Some(ServiceStatus {
+            running: true,
+            uptime,
+            api_keys_available,
+            requests_waiting_for_key,
+            cache_entries,
+        });
+    }
+    if send("stats") {
+        let api_stats = state.stats_manager.get_stats().await;
+        response.stats = Some(ApiStats {
+            total_requests: api_stats.total_requests,
+            successful_requests: api_stats.successful_requests,
+            failed_requests: api_stats.failed_requests,
+            tokens_used: api_stats.total_tokens,
+            prompt_tokens: api_stats.total_prompt_tokens,
+            completion_tokens: api_stats.total_completion_tokens,
+            reasoning_tokens: api_stats.total_reasoning_tokens,
+            requests_per_minute: api_stats.requests_last_minute,
+            requests_per_hour: api_stats.requests_last_hour,
+            requests_per_day: api_stats.requests_last_day,
+            upstream_timeouts: api_stats.upstream_timeouts,
+            public_requests: api_stats.public_requests,
+            public_tokens: api_stats.public_tokens,
+            fallback_requests: api_stats.fallback_requests,
+            hedged_requests: api_stats.hedged_requests,
+            diagnostic_requests: api_stats.diagnostic_requests,
+        });
+    }
+    if send("config") {
+        response.config = Some(config);
+        response.version = Some(version);
+    }
+    if send("key_stats") {
+        response.key_stats = Some(key_stat_infos(&state).await);
+    }
+    if send("security_warnings") {
+        response.security_warnings = Some(
+            lockouts
+                .into_iter()
+                .map(|(ip, remaining)| format!(
+                    "{} is locked out for {}s after repeated failed logins",
+                    ip,
+                    remaining.as_secs()
+                ))
+                .collect(),
+        );
+    }
+    if send("insights") {
+        response.insights = Some(Insights::compute(
+            &state.stats_manager.usage_windows(),
+            &state.key_manager.get_key_stats().await,
+            cache_hit_ratio,
+        ));
+    }
+    if send("circuits") {
+        response.circuits = Some(circuits);
+    }
+    if send("endpoints") {
+        response.endpoints = Some(endpoints);
+    }
+    if send("logs") {
+        let since = data_query.since.unwrap_or(0);
+        response.logs = Some(
+            recent_logs
+                .into_iter()
+                .filter(|entry| entry.timestamp.timestamp_millis() as u64 >= since)
+                .map(|entry| LiveLogLine { timestamp: entry.timestamp.timestamp(), level: entry.level, message: entry.message })
+                .collect(),
+        );
+    }
+    if send("hourly_stats") {
+        let since = std::time::UNIX_EPOCH + Duration::from_millis(data_query.since.unwrap_or(0));
+        response.hourly_stats = Some(
+            state.stats_manager.get_hourly_stats().await
+                .into_iter()
+                .filter(|(start, _, _)| *start + Duration::from_secs(3600) > since)
+                .map(|(start, requests, tokens)| HourlyBucket {
+                    start: start.duration_since(std::time::UNIX_EPOCH).unwrap_or_default().as_secs(),
+                    requests,
+                    tokens,
+                })
+                .collect(),
+        );
+    }
+
+    Ok((cache_headers, Json(response)).into_response())
+}
+
+/// Whether `If-None-Match` lists `etag`, compared weakly as the ETag is weak
+fn etag_matches(headers: &HeaderMap, etag: &str) -> bool {
+    let opaque = |tag: &str| tag.trim().trim_start_matches("W/").to_string();
+    headers
+        .get_all(header::IF_NONE_MATCH)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .any(|tag| tag.trim() == "*" || opaque(tag) == opaque(etag))
+}
+
+/// Push a compact snapshot every `dashboard_stream_interval_secs`, and straight away when the
+/// key pool changes or a warning is logged. A dashboard that cannot keep up skips to the
+/// newest snapshot. `/data` stays for clients that poll.
+async fn stream_live_stats(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    Query(query): Query<AuthQuery>,
+) -> Result<Response, StatusCode> {
+    let auth_result = authenticate_dashboard_request(&headers, &query, &state.auth_state);
+    if !auth_result.authenticated {
+        return Err(StatusCode::UNAUTHORIZED);
+    }
 
-    // Get API key stats
-    let key_stats_raw = state.key_manager.get_key_stats().await;
-    let key_stats = key_stats_raw
+    let receiver = state.live_stats.subscribe();
+    let first = Arc::new(live_snapshot(&state).await);
+    let published = stream::unfold(receiver, |mut receiver| async move {
+        loop {
+            match receiver.recv().await {
+                Ok(snapshot) => return Some((snapshot, receiver)),
+                Err(RecvError::Lagged(_)) => continue,
+                Err(RecvError::Closed) => return None,
+            }
+        }
+    });
+    let events = stream::once(async move { first }).chain(published).map(|snapshot| {
+        Ok::<Event, std::convert::Infallible>(Event::default().data(serde_json::to_string(&*snapshot).unwrap_or_default()))
+    });
+    Ok(sse_response(Sse::new(events)))
+}
+
+async fn live_snapshot(state: &AppState) -> LiveSnapshot {
+    let (requests_per_minute, tokens_per_minute) = state.stats_manager.recent_throughput(Duration::from_secs(60)).await;
+    let logs = LOG_MANAGER
+        .get_recent_logs(LIVE_LOG_LINES)
         .into_iter()
-        .map(|(key, stats)| KeyStatInfo {
-            key_prefix: format!("{}...", &key[..8.min(key.len())]),
-            daily_usage: stats.daily_usage,
-            last_used: stats.last_used.format("%Y-%m-%d %H:%M:%S UTC").to_string(),
-            consecutive_failures: stats.consecutive_failures,
-        })
+        .map(|entry| LiveLogLine { timestamp: entry.timestamp.timestamp(), level: entry.level, message: entry.message })
         .collect();
 
-    Ok(Json(DashboardResponse {
-        status,
-        stats,
-        config,
-        version,
-        key_stats,
-    }))
+    LiveSnapshot {
+        timestamp: chrono::Utc::now().timestamp(),
+        requests_per_minute,
+        tokens_per_minute,
+        active_streams: state.stats_manager.active_streams(),
+        keys_available: state.key_manager.available_keys_count().await,
+        cache_hit_rate: state.cache_manager.hit_ratio(),
+        logs,
+    }
+}
+
+/// Feed `/dashboard-api/stream`: sample once a second while a dashboard is subscribed and
+/// publish when the interval is up or the change is significant
+pub async fn start_live_stats_task(state: AppState) {
+    let interval = Duration::from_secs(state.settings.load().dashboard_stream_interval_secs.max(1));
+    let mut ticker = tokio::time::interval(LIVE_SAMPLE_INTERVAL);
+    let mut last: Option<(std::time::Instant, LiveSnapshot)> = None;
+
+    loop {
+        ticker.tick().await;
+        if !state.live_stats.has_subscribers() {
+            last = None;
+            continue;
+        }
+
+        let snapshot = live_snapshot(&state).await;
+        let due = match &last {
+            Some((published_at, previous)) => {
+                published_at.elapsed() >= interval || snapshot.is_significant_change(previous)
+            }
+            None => true,
+        };
+        if due {
+            last = Some((std::time::Instant::now(), snapshot.clone()));
+            state.live_stats.publish(snapshot);
+        }
+    }
 }
 
 async fn get_stats(
@@ -129,7 +516,7 @@ async fn get_stats(
     headers: HeaderMap,
     Query(query): Query<AuthQuery>,
 ) -> Result<Json<ApiStats>, StatusCode> {
-    let auth_result = authenticate_request(&headers, &query, &state.settings);
+    let auth_result = authenticate_dashboard_request(&headers, &query, &state.auth_state);
     if !auth_result.authenticated {
         return Err(StatusCode::UNAUTHORIZED);
     }
@@ -140,20 +527,218 @@ async fn get_stats(
         successful_requests: api_stats.successful_requests,
         failed_requests: api_stats.failed_requests,
         tokens_used: api_stats.total_tokens,
+        prompt_tokens: api_stats.total_prompt_tokens,
+        completion_tokens: api_stats.total_completion_tokens,
+        reasoning_tokens: api_stats.total_reasoning_tokens,
         requests_per_minute: api_stats.requests_last_minute,
         requests_per_hour: api_stats.requests_last_hour,
         requests_per_day: api_stats.requests_last_day,
+        upstream_timeouts: api_stats.upstream_timeouts,
+        public_requests: api_stats.public_requests,
+        public_tokens: api_stats.public_tokens,
+        fallback_requests: api_stats.fallback_requests,
+        hedged_requests: api_stats.hedged_requests,
+        diagnostic_requests: api_stats.diagnostic_requests,
     };
 
     Ok(Json(stats))
 }
 
+async fn get_route_stats(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    Query(query): Query<AuthQuery>,
+) -> Result<Json<serde_json::Value>, StatusCode> {
+    let auth_result = authenticate_dashboard_request(&headers, &query, &state.auth_state);
+    if !auth_result.authenticated {
+        return Err(StatusCode::UNAUTHORIZED);
+    }
+
+    Ok(Json(serde_json::json!({
+        "window": "1h",
+        "routes": state.stats_manager.route_metrics().summary(),
+    })))
+}
+
+/// Per-model counters, with upstream time to first chunk and stream duration percentiles
+async fn get_model_stats(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    Query(query): Query<AuthQuery>,
+) -> Result<Json<serde_json::Value>, StatusCode> {
+    let auth_result = authenticate_dashboard_request(&headers, &query, &state.auth_state);
+    if !auth_result.authenticated {
+        return Err(StatusCode::UNAUTHORIZED);
+    }
+
+    let mut models = state.stats_manager.get_model_stats().await;
+    models.sort_by(|a, b| b.request_count.cmp(&a.request_count).then_with(|| a.model_name.cmp(&b.model_name)));
+    let models: Vec<serde_json::Value> = models
+        .into_iter()
+        .map(|stats| {
+            let success_rate = stats.success_rate();
+            let average_response_time_ms = stats.average_response_time();
+            let mut value = serde_json::to_value(stats).unwrap_or_default();
+            value["success_rate"] = serde_json::json!(success_rate);
+            value["average_response_time_ms"] = serde_json::json!(average_response_time_ms);
+            value
+        })
+        .collect();
+    Ok(Json(serde_json::json!({ "models": models })))
+}
+
+async fn get_circuits(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    Query(query): Query<AuthQuery>,
+) -> Result<Json<serde_json::Value>, StatusCode> {
+    let auth_result = authenticate_dashboard_request(&headers, &query, &state.auth_state);
+    if !auth_result.authenticated {
+        return Err(StatusCode::UNAUTHORIZED);
+    }
+
+    let circuits = state.model_circuits.snapshot(std::time::Instant::now());
+    Ok(Json(serde_json::json!({ "circuits": circuits })))
+}
+
+async fn get_endpoints(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    Query(query): Query<AuthQuery>,
+) -> Result<Json<serde_json::Value>, StatusCode> {
+    let auth_result = authenticate_dashboard_request(&headers, &query, &state.auth_state);
+    if !auth_result.authenticated {
+        return Err(StatusCode::UNAUTHORIZED);
+    }
+
+    Ok(Json(serde_json::json!({ "endpoints": state.gemini_client.endpoint_status().await })))
+}
+
+/// Per-caller queue depth and wait times of the fair queue (`fair_queuing`)
+async fn get_scheduler(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    Query(query): Query<AuthQuery>,
+) -> Result<Json<FairQueueStatus>, StatusCode> {
+    let auth_result = authenticate_dashboard_request(&headers, &query, &state.auth_state);
+    if !auth_result.authenticated {
+        return Err(StatusCode::UNAUTHORIZED);
+    }
+
+    Ok(Json(state.fair_queue.status()))
+}
+
+#[derive(Debug, Deserialize)]
+struct SetCircuitRequest {
+    state: CircuitState,
+}
+
+/// Force a model's circuit open, half-open (probe on the next request) or closed
+async fn set_circuit(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    Query(query): Query<AuthQuery>,
+    Path(model): Path<String>,
+    Json(request): Json<SetCircuitRequest>,
+) -> Result<Json<serde_json::Value>, StatusCode> {
+    require_admin(&state, &headers, &query)?;
+
+    let now = std::time::Instant::now();
+    state.model_circuits.set_state(&model, request.state, now);
+    info!("Circuit for {} set to {:?} by an administrator", model, request.state);
+
+    let circuit = state.model_circuits.snapshot(now).into_iter().find(|status| status.model == model);
+    Ok(Json(serde_json::json!({ "success": true, "model": model, "circuit": circuit })))
+}
+
+async fn get_client_stats(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    Query(query): Query<AuthQuery>,
+    Query(stats_query): Query<ClientStatsQuery>,
+) -> Result<Response, StatusCode> {
+    let auth_result = authenticate_dashboard_request(&headers, &query, &state.auth_state);
+    if !auth_result.authenticated {
+        return Err(StatusCode::UNAUTHORIZED);
+    }
+
+    let window = stats_query.window.as_deref().unwrap_or("24h");
+    let window_duration = match window {
+        "24h" => std::time::Duration::from_secs(24 * 3600),
+        "7d" => std::time::Duration::from_secs(7 * 24 * 3600),
+        _ => return Err(StatusCode::BAD_REQUEST),
+    };
+
+    let by_end_user = match stats_query.group_by.as_deref().unwrap_or("client") {
+        "client" => false,
+        "end_user" => true,
+        _ => return Err(StatusCode::BAD_REQUEST),
+    };
+
+    // Only client key names, masked credential ids and hashed end users are stored, never raw keys
+    let clients: Vec<ClientUsageInfo> = state.stats_manager
+        .get_client_usage(window_duration, by_end_user)
+        .await
+        .into_iter()
+        .map(|usage| ClientUsageInfo {
+            client: usage.client,
+            named: usage.named,
+            requests: usage.requests,
+            errors: usage.errors,
+            prompt_tokens: usage.prompt_tokens,
+            completion_tokens: usage.completion_tokens,
+            total_tokens: usage.total_tokens,
+            last_seen: chrono::DateTime::<chrono::Utc>::from(usage.last_seen)
+                .format("%Y-%m-%d %H:%M:%S UTC")
+                .to_string(),
+        })
+        .collect();
+
+    match stats_query.format.as_deref().unwrap_or("json") {
+        "json" => Ok(Json(serde_json::json!({
+            "window": window,
+            "clients": clients
+        }))
+        .into_response()),
+        "csv" => {
+            let filename = format!("attachment; filename=\"client-usage-{}.csv\"", window);
+            Ok((
+                [
+                    (header::CONTENT_TYPE, "text/csv; charset=utf-8".to_string()),
+                    (header::CONTENT_DISPOSITION, filename),
+                ],
+                client_usage_csv(&clients),
+            )
+                .into_response())
+        }
+        _ => Err(StatusCode::BAD_REQUEST),
+    }
+}
+
+fn client_usage_csv(clients: &[ClientUsageInfo]) -> String {
+    let mut csv = String::from("client,named,requests,errors,prompt_tokens,completion_tokens,total_tokens,last_seen\n");
+    for usage in clients {
+        csv.push_str(&format!(
+            "\"{}\",{},{},{},{},{},{},{}\n",
+            usage.client.replace('"', "\"\""),
+            usage.named,
+            usage.requests,
+            usage.errors,
+            usage.prompt_tokens,
+            usage.completion_tokens,
+            usage.total_tokens,
+            usage.last_seen
+        ));
+    }
+    csv
+}
+
 async fn get_config(
     State(state): State<AppState>,
     headers: HeaderMap,
     Query(query): Query<AuthQuery>,
 ) -> Result<Json<ConfigInfo>, StatusCode> {
-    let auth_result = authenticate_request(&headers, &query, &state.settings);
+    let auth_result = authenticate_dashboard_request(&headers, &query, &state.auth_state);
     if !auth_result.authenticated {
         return Err(StatusCode::UNAUTHORIZED);
     }
@@ -165,17 +750,96 @@ async fn get_config(
         fake_streaming: current_settings.fake_streaming,
         concurrent_requests: current_settings.concurrent_requests,
         cache_enabled: current_settings.max_cache_entries > 0,
-        vertex_enabled: current_settings.enable_vertex,
+        vertex_enabled: state.vertex_enabled,
         search_mode: current_settings.search.search_mode,
+        upstream_header_profile: current_settings.upstream_header_profile.as_str().to_string(),
     };
 
     Ok(Json(config))
 }
 
-async fn update_config(
+/// Problems found when the settings were last loaded
+async fn get_vertex_logs(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    Query(query): Query<AuthQuery>,
+    Query(logs_query): Query<LogsQuery>,
+) -> Result<Json<serde_json::Value>, StatusCode> {
+    let auth_result = authenticate_dashboard_request(&headers, &query, &state.auth_state);
+    if !auth_result.authenticated {
+        return Err(StatusCode::UNAUTHORIZED);
+    }
+
+    let level = logs_query.level.as_deref().filter(|level| !level.is_empty());
+    let logs: Vec<serde_json::Value> = VERTEX_LOG_MANAGER
+        .query(level, logs_query.limit)
+        .iter()
+        .map(|entry| entry.to_json())
+        .collect();
+    Ok(Json(serde_json::json!({
+        "count": logs.len(),
+        "logs": logs,
+    })))
+}
+
+#[derive(Debug, Default, Deserialize)]
+struct ClearLogsRequest {
+    /// Only drop entries older than this many seconds
+    older_than_secs: Option<u64>,
+}
+
+/// Empty the dashboard and Vertex AI log buffers, or with `{"older_than_secs": 3600}` only
+/// drop their older entries
+async fn clear_logs(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    Query(query): Query<AuthQuery>,
+    body: axum::body::Bytes,
+) -> Result<Json<serde_json::Value>, StatusCode> {
+    require_admin(&state, &headers, &query)?;
+
+    let request: ClearLogsRequest = if body.iter().all(u8::is_ascii_whitespace) {
+        ClearLogsRequest::default()
+    } else {
+        serde_json::from_slice(&body).map_err(|_| StatusCode::BAD_REQUEST)?
+    };
+    let (logs, vertex_logs) = match request.older_than_secs {
+        Some(secs) => {
+            let cutoff = chrono::Utc::now() - chrono::Duration::seconds(secs as i64);
+            (LOG_MANAGER.prune_before(cutoff), VERTEX_LOG_MANAGER.prune_before(cutoff))
+        }
+        None => (LOG_MANAGER.clear(), VERTEX_LOG_MANAGER.clear()),
+    };
+    info!("Log buffers cleared from the dashboard: {} entries, {} Vertex AI entries", logs, vertex_logs);
+
+    Ok(Json(serde_json::json!({
+        "success": true,
+        "removed": logs,
+        "vertex_removed": vertex_logs,
+    })))
+}
+
+async fn get_config_warnings(
     State(state): State<AppState>,
     headers: HeaderMap,
     Query(query): Query<AuthQuery>,
+) -> Result<Json<serde_json::Value>, StatusCode> {
+    let auth_result = authenticate_dashboard_request(&headers, &query, &state.auth_state);
+    if !auth_result.authenticated {
+        return Err(StatusCode::UNAUTHORIZED);
+    }
+
+    let current_settings = ConfigManager::get_settings().await;
+    Ok(Json(serde_json::json!({
+        "warnings": current_settings.config_warnings,
+        "strict_config": current_settings.strict_config,
+    })))
+}
+
+async fn update_config(
+    State(state): State<AppState>,
+    _headers: HeaderMap,
+    Query(_query): Query<AuthQuery>,
     Json(request): Json<ConfigUpdateRequest>,
 ) -> Result<Json<serde_json::Value>, StatusCode> {
     // Get current settings for password verification (similar to hajimi)
@@ -275,7 +939,7 @@ async fn update_config(
             }
         },
         "gemini_api_keys" => {
-            if let Some(value) = request.value.as_str() {
+            if let Some(_value) = request.value.as_str() {
                 info!("Gemini API keys updated");
                 // Handle API key updates
                 // Parse comma-separated keys and update key_manager
@@ -283,6 +947,28 @@ async fn update_config(
                 return Err(StatusCode::BAD_REQUEST);
             }
         },
+        "upstream_connect_timeout_secs" | "upstream_request_timeout_secs" | "stream_idle_timeout_secs" => {
+            match request.value.as_u64() {
+                Some(value) if value > 0 => info!("{} updated to: {}", request.key, value),
+                _ => return Err(StatusCode::BAD_REQUEST),
+            }
+        },
+        "gemini_base_url" => {
+            let value = request.value.as_str().ok_or(StatusCode::BAD_REQUEST)?;
+            match url::Url::parse(value.trim()) {
+                Ok(parsed) if matches!(parsed.scheme(), "http" | "https") => {
+                    info!("Gemini base URL updated to: {}", value);
+                }
+                _ => return Err(StatusCode::BAD_REQUEST),
+            }
+        },
+        "api_version" => {
+            let value = request.value.as_str().ok_or(StatusCode::BAD_REQUEST)?;
+            if !GEMINI_API_VERSIONS.contains(&value.trim()) {
+                return Err(StatusCode::BAD_REQUEST);
+            }
+            info!("Gemini API version updated to: {}", value);
+        },
         _ => {
             return Ok(Json(serde_json::json!({
                 "status": "error",
@@ -300,10 +986,14 @@ async fn update_config(
             "message": format!("Failed to update configuration: {}", e)
         })));
     }
+    let updated = ConfigManager::get_settings().await;
+    let report = ReloadReport::between(&current_settings, &updated);
+    apply_settings(&state, Arc::new(updated), &report).await;
 
     Ok(Json(serde_json::json!({
         "status": "success",
-        "message": format!("Configuration item {} updated", request.key)
+        "message": format!("Configuration item {} updated", request.key),
+        "restart_required": report.restart_required,
     })))
 }
 
@@ -312,7 +1002,7 @@ async fn reset_stats(
     headers: HeaderMap,
     Query(query): Query<AuthQuery>,
 ) -> Result<Json<serde_json::Value>, StatusCode> {
-    let auth_result = authenticate_request(&headers, &query, &state.settings);
+    let auth_result = authenticate_dashboard_request(&headers, &query, &state.auth_state);
     if !auth_result.authenticated {
         return Err(StatusCode::UNAUTHORIZED);
     }
@@ -332,62 +1022,1322 @@ async fn reset_stats(
     })))
 }
 
-async fn clear_cache(
+/// Clear the totals of one model, e.g. after fixing it, keeping its call records and every
+/// other model's stats
+async fn reset_model_stats(
     State(state): State<AppState>,
     headers: HeaderMap,
     Query(query): Query<AuthQuery>,
+    Path(model): Path<String>,
 ) -> Result<Json<serde_json::Value>, StatusCode> {
-    let auth_result = authenticate_request(&headers, &query, &state.settings);
-    if !auth_result.authenticated {
-        return Err(StatusCode::UNAUTHORIZED);
-    }
+    require_admin(&state, &headers, &query)?;
 
-    // Only admin users can clear cache
-    if !matches!(auth_result.scope, AuthScope::Admin) {
-        return Err(StatusCode::FORBIDDEN);
-    }
+    let cleared = state.stats_manager.reset_model_stats(&model).ok_or(StatusCode::NOT_FOUND)?;
+    log("info", &format!("Statistics of model {} reset by an administrator", model), None);
 
-    info!("Cache clear requested by user: {:?}", auth_result.user_id);
+    Ok(Json(serde_json::json!({ "success": true, "model": model, "cleared": cleared })))
+}
+
+/// Clear the failure counters and cooldown of a key; `id` is its position among the
+/// configured keys, as for `key_index`
+async fn reset_key(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    Query(query): Query<AuthQuery>,
+    Path(id): Path<usize>,
+) -> Result<Json<serde_json::Value>, StatusCode> {
+    require_admin(&state, &headers, &query)?;
 
-    state.cache_manager.clear().await;
+    let (key, stats, cooldown) = state.key_manager.reset_key_failures(id).ok_or(StatusCode::NOT_FOUND)?;
+    let key_prefix = format!("{}...", &key[..8.min(key.len())]);
+    log("info", &format!("Failure counters of API key {} reset by an administrator", key_prefix), None);
 
     Ok(Json(serde_json::json!({
         "success": true,
-        "message": "Cache cleared successfully"
+        "id": id,
+        "cleared": {
+            "key_prefix": key_prefix,
+            "consecutive_failures": stats.consecutive_failures,
+            "daily_failures": stats.daily_failures,
+            "cooldown_secs": cooldown.map(|left| left.as_secs().max(1)),
+        },
     })))
 }
 
-async fn get_key_stats(
+/// Clear the whole cache, or with a JSON body such as `{"model": "...", "older_than_secs": 600}`
+/// only the matching responses
+async fn clear_cache(
     State(state): State<AppState>,
     headers: HeaderMap,
     Query(query): Query<AuthQuery>,
-) -> Result<Json<Vec<KeyStatInfo>>, StatusCode> {
-    let auth_result = authenticate_request(&headers, &query, &state.settings);
+    body: axum::body::Bytes,
+) -> Result<Json<serde_json::Value>, StatusCode> {
+    let auth_result = authenticate_dashboard_request(&headers, &query, &state.auth_state);
+    if !auth_result.authenticated {
+        return Err(StatusCode::UNAUTHORIZED);
+    }
+
+    // Only admin users can clear cache
+    if !matches!(auth_result.scope, AuthScope::Admin) {
+        return Err(StatusCode::FORBIDDEN);
+    }
+
+    let filter: CacheFilter = if body.iter().all(u8::is_ascii_whitespace) {
+        CacheFilter::default()
+    } else {
+        serde_json::from_slice(&body).map_err(|_| StatusCode::BAD_REQUEST)?
+    };
+
+    info!("Cache clear requested by user: {:?}", auth_result.user_id);
+
+    let removed = if filter.is_empty() {
+        let removed = state.cache_manager.get_stats().await.total_responses;
+        state.cache_manager.clear().await;
+        removed
+    } else {
+        state.cache_manager.clear_matching(&filter).await
+    };
+
+    Ok(Json(serde_json::json!({
+        "success": true,
+        "message": "Cache cleared successfully",
+        "removed": removed
+    })))
+}
+
+/// Free memory without a restart: empty the cache, drop per-call stats and clear the logs
+async fn run_emergency_cleanup(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    Query(query): Query<AuthQuery>,
+) -> Result<Json<serde_json::Value>, StatusCode> {
+    require_admin(&state, &headers, &query)?;
+
+    warn!("Emergency cleanup requested from the dashboard");
+    let cleared = emergency_cleanup(Some(&state.cache_manager), Some(&state.stats_manager)).await;
+
+    Ok(Json(serde_json::json!({
+        "success": true,
+        "cleared": cleared,
+    })))
+}
+
+async fn get_key_stats(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    Query(query): Query<AuthQuery>,
+) -> Result<Json<Vec<KeyStatInfo>>, StatusCode> {
+    let auth_result = authenticate_dashboard_request(&headers, &query, &state.auth_state);
+    if !auth_result.authenticated {
+        return Err(StatusCode::UNAUTHORIZED);
+    }
+
+    let key_stats = key_stat_infos(&state).await;
+
+    Ok(Json(key_stats))
+}
+
+/// Gemini context caches created for long system prompts, with their expiry
+async fn get_context_cache(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    Query(query): Query<AuthQuery>,
+) -> Result<Json<serde_json::Value>, StatusCode> {
+    let auth_result = authenticate_dashboard_request(&headers, &query, &state.auth_state);
     if !auth_result.authenticated {
         return Err(StatusCode::UNAUTHORIZED);
     }
 
-    let key_stats_raw = state.key_manager.get_key_stats().await;
-    let key_stats = key_stats_raw
+    let settings = state.settings.load();
+    Ok(Json(serde_json::json!({
+        "enabled": settings.enable_context_caching,
+        "min_tokens": settings.context_cache_min_tokens,
+        "entries": state.gemini_client.context_cache().entries(),
+    })))
+}
+
+async fn key_stat_infos(state: &AppState) -> Vec<KeyStatInfo> {
+    let pinned = state.key_manager.pinned_session_counts();
+    let rpm_tokens = state.key_manager.rpm_tokens_left();
+    state.key_manager.get_key_stats().await
         .into_iter()
         .map(|(key, stats)| KeyStatInfo {
             key_prefix: format!("{}...", &key[..8.min(key.len())]),
             daily_usage: stats.daily_usage,
             last_used: stats.last_used.format("%Y-%m-%d %H:%M:%S UTC").to_string(),
             consecutive_failures: stats.consecutive_failures,
+            pinned_sessions: pinned.get(&key).copied().unwrap_or(0),
+            rpm_tokens: rpm_tokens.get(&key).copied(),
         })
-        .collect();
+        .collect()
+}
 
-    Ok(Json(key_stats))
+/// Which storage backend is active and, for SQLite, the database size and last write
+async fn get_storage_status(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    Query(query): Query<AuthQuery>,
+) -> Result<Json<crate::storage::StorageStatus>, StatusCode> {
+    let auth_result = authenticate_dashboard_request(&headers, &query, &state.auth_state);
+    if !auth_result.authenticated {
+        return Err(StatusCode::UNAUTHORIZED);
+    }
+
+    Ok(Json(crate::storage::status(&state.settings.load())))
+}
+
+/// Audit files on disk, including those written before the audit log was turned off
+async fn list_audit_files(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    Query(query): Query<AuthQuery>,
+) -> Result<Json<serde_json::Value>, StatusCode> {
+    require_admin(&state, &headers, &query)?;
+
+    let dir = audit::audit_dir(&state.settings.load().storage_dir);
+    Ok(Json(serde_json::json!({
+        "enabled": state.audit_log.is_some(),
+        "dropped_entries": state.audit_log.as_ref().map_or(0, |log| log.dropped()),
+        "files": audit::list_files(&dir),
+    })))
+}
+
+async fn download_audit_file(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    Query(query): Query<AuthQuery>,
+    Path(name): Path<String>,
+) -> Result<Response, StatusCode> {
+    require_admin(&state, &headers, &query)?;
+    if !audit::is_audit_file_name(&name) {
+        return Err(StatusCode::NOT_FOUND);
+    }
+
+    let path = audit::audit_dir(&state.settings.load().storage_dir).join(&name);
+    let contents = tokio::fs::read(&path).await.map_err(|_| StatusCode::NOT_FOUND)?;
+    Ok((
+        [
+            (header::CONTENT_TYPE, "application/x-ndjson".to_string()),
+            (header::CONTENT_DISPOSITION, format!("attachment; filename=\"{}\"", name)),
+        ],
+        contents,
+    ).into_response())
 }
 
 async fn get_version(
     State(_state): State<AppState>,
 ) -> Json<serde_json::Value> {
     let build_info = version::get_build_info();
+    let latest = dashboard_version_info();
 
     Json(serde_json::json!({
         "version": version::get_current_version(),
+        "latest": latest.latest,
+        "update_available": latest.update_available,
+        "release_url": latest.release_url,
         "build_info": build_info
     }))
-}
\ No newline at end of file
+}
+
+/// The startup summary for the settings in effect now, with secrets masked
+async fn get_about(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    Query(query): Query<AuthQuery>,
+) -> Result<Json<serde_json::Value>, StatusCode> {
+    let auth_result = authenticate_dashboard_request(&headers, &query, &state.auth_state);
+    if !auth_result.authenticated {
+        return Err(StatusCode::UNAUTHORIZED);
+    }
+
+    Ok(Json(startup_summary(&ConfigManager::get_settings().await)))
+}
+
+/// The current version with the result of the last update check
+fn dashboard_version_info() -> VersionInfo {
+    let latest = version::latest_version_info();
+    VersionInfo {
+        current: version::get_current_version(),
+        latest: latest.latest_version,
+        update_available: latest.has_update_available,
+        release_url: latest.release_url,
+    }
+}
+
+async fn list_clients(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    Query(query): Query<AuthQuery>,
+) -> Result<Json<Vec<ClientKeyInfo>>, StatusCode> {
+    require_admin(&state, &headers, &query)?;
+
+    let clients = state.auth_state.client_keys().iter().map(ClientKeyInfo::from).collect();
+    Ok(Json(clients))
+}
+
+async fn create_client(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    Query(query): Query<AuthQuery>,
+    Json(request): Json<CreateClientKeyRequest>,
+) -> Result<Json<serde_json::Value>, StatusCode> {
+    require_admin(&state, &headers, &query)?;
+
+    let name = request.name.trim().to_string();
+    let key = request
+        .key
+        .map(|key| key.trim().to_string())
+        .unwrap_or_else(|| format!("sk-{}", uuid::Uuid::new_v4().simple()));
+    if name.is_empty() || key.is_empty() {
+        return Err(StatusCode::BAD_REQUEST);
+    }
+
+    let mut client_keys = state.auth_state.client_keys();
+    let settings = state.settings.load();
+    if client_keys.iter().any(|existing| existing.name == name || existing.key == key)
+        || key == settings.password
+        || key == settings.web_password
+    {
+        return Err(StatusCode::CONFLICT);
+    }
+
+    let client_key = ClientKey {
+        key,
+        name,
+        scope: request.scope,
+        daily_request_limit: request.daily_request_limit,
+        daily_token_limit: request.daily_token_limit,
+        allowed_models: request.allowed_models,
+        weight: request.weight.unwrap_or(1).max(1),
+    };
+    let info = ClientKeyInfo::from(&client_key);
+    let key = client_key.key.clone();
+    client_keys.push(client_key);
+    save_client_keys(&state, client_keys).await?;
+
+    info!("Client key {} created", info.name);
+
+    // The full key is only ever returned here
+    Ok(Json(serde_json::json!({
+        "success": true,
+        "client": info,
+        "key": key
+    })))
+}
+
+async fn revoke_client(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    Query(query): Query<AuthQuery>,
+    Path(name): Path<String>,
+) -> Result<Json<serde_json::Value>, StatusCode> {
+    require_admin(&state, &headers, &query)?;
+
+    let mut client_keys = state.auth_state.client_keys();
+    let before = client_keys.len();
+    client_keys.retain(|client_key| client_key.name != name);
+    if client_keys.len() == before {
+        return Err(StatusCode::NOT_FOUND);
+    }
+    save_client_keys(&state, client_keys).await?;
+
+    info!("Client key {} revoked", name);
+
+    Ok(Json(serde_json::json!({
+        "success": true,
+        "message": format!("Client key {} revoked", name)
+    })))
+}
+
+async fn block_ip(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    Query(query): Query<AuthQuery>,
+    Json(request): Json<BlockIpRequest>,
+) -> Result<Json<serde_json::Value>, StatusCode> {
+    require_admin(&state, &headers, &query)?;
+
+    let net = parse_ip_net(&request.ip).map_err(|_| StatusCode::BAD_REQUEST)?;
+    let now = unix_now();
+    let entry = IpBlockEntry {
+        cidr: net.to_string(),
+        expires_at: request.expires_in_secs.map(|secs| now + secs),
+        reason: request.reason,
+    };
+
+    // Expired entries are dropped whenever the list is rewritten
+    let mut blocklist = state.auth_state.ip_filter.blocklist(now);
+    blocklist.retain(|existing| existing.cidr != entry.cidr);
+    blocklist.push(entry.clone());
+
+    let value = serde_json::to_value(&blocklist).map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+    if let Err(e) = ConfigManager::update_config("ip_blocklist", value).await {
+        tracing::error!("Failed to save IP blocklist: {}", e);
+        return Err(StatusCode::INTERNAL_SERVER_ERROR);
+    }
+    state.auth_state.ip_filter.set_blocklist(blocklist.clone());
+
+    info!("Blocked {} (expires at {:?})", entry.cidr, entry.expires_at);
+
+    Ok(Json(serde_json::json!({
+        "success": true,
+        "blocked": entry,
+        "blocklist": blocklist
+    })))
+}
+
+async fn refresh_models(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    Query(query): Query<AuthQuery>,
+) -> Result<Response, StatusCode> {
+    require_admin(&state, &headers, &query)?;
+
+    let api_key = state.key_manager.get_next_key().await.ok_or(StatusCode::SERVICE_UNAVAILABLE)?;
+    match state.gemini_client.refresh_models(&state.key_manager, api_key).await {
+        Ok(count) => Ok(Json(serde_json::json!({
+            "success": true,
+            "models": count,
+            "refreshed_at": state.gemini_client.models_refreshed_at().await.map(|at| at.timestamp()),
+        }))
+        .into_response()),
+        Err(e) => {
+            tracing::error!("Model list refresh failed: {}", e);
+            Ok((
+                StatusCode::BAD_GATEWAY,
+                Json(serde_json::json!({
+                    "success": false,
+                    "message": format!("Model list refresh failed: {}", e),
+                })),
+            )
+                .into_response())
+        }
+    }
+}
+
+async fn reload_settings(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    Query(query): Query<AuthQuery>,
+) -> Result<Response, StatusCode> {
+    require_admin(&state, &headers, &query)?;
+
+    match reload_config(&state).await {
+        Ok(summary) => Ok(Json(summary).into_response()),
+        Err(e) => Ok((
+            StatusCode::UNPROCESSABLE_ENTITY,
+            Json(serde_json::json!({
+                "success": false,
+                "message": format!("Settings reload failed: {:#}", e),
+            })),
+        )
+            .into_response()),
+    }
+}
+
+/// Reload settings from disk and apply every change that does not need a restart. Everything
+/// is reported to the dashboard log; on failure the previous settings stay in effect.
+pub async fn reload_config(state: &AppState) -> anyhow::Result<serde_json::Value> {
+    let report = match ConfigManager::reload_from_disk().await {
+        Ok(report) => report,
+        Err(e) => {
+            log("error", &format!("Settings reload failed, keeping the previous settings: {:#}", e), None);
+            return Err(e);
+        }
+    };
+    let (keys_added, keys_removed) = apply_live_changes(state, &report).await;
+    log_settings_change("reloaded", &report);
+
+    Ok(serde_json::json!({
+        "success": true,
+        "changed": report.changed,
+        "restart_required": report.restart_required,
+        "keys_added": keys_added,
+        "keys_removed": keys_removed,
+    }))
+}
+
+/// Log what changed when settings were `action` (reloaded, imported), and what waits for a restart
+fn log_settings_change(action: &str, report: &ReloadReport) {
+    if report.changed.is_empty() {
+        log("info", &format!("Settings {}, nothing changed", action), None);
+    } else {
+        log("info", &format!("Settings {}, changed: {}", action, report.changed.join(", ")), None);
+    }
+    if !report.restart_required.is_empty() {
+        log(
+            "warning",
+            &format!("Restart to apply changes to: {}", report.restart_required.join(", ")),
+            None,
+        );
+    }
+}
+
+/// Make reloaded settings take effect: swap in the new snapshot, then update what was built
+/// from the old one. Returns how many API keys were added and removed.
+async fn apply_live_changes(state: &AppState, report: &ReloadReport) -> (usize, usize) {
+    apply_settings(state, Arc::new(ConfigManager::get_settings().await), report).await
+}
+
+/// Store `settings` as the snapshot requests read, and rebuild the API key pool, client keys,
+/// IP filter, login limits and model policy where `report` says they changed. Settings in
+/// `ReloadReport::restart_required` stay as they were until a restart.
+async fn apply_settings(state: &AppState, settings: Arc<Settings>, report: &ReloadReport) -> (usize, usize) {
+    state.settings.store(settings.clone());
+    let mut keys_changed = (0, 0);
+    if report.contains("gemini_api_keys") || report.contains("invalid_api_keys") {
+        keys_changed = state.key_manager.update_keys(settings.get_valid_api_keys()).await;
+    }
+    if report.contains("client_keys") {
+        state.auth_state.set_client_keys(settings.client_keys.clone());
+    }
+    if report.contains("ip_allowlist") {
+        state.auth_state.ip_filter.set_allowlist(&settings.ip_allowlist);
+    }
+    if report.contains("ip_blocklist") {
+        state.auth_state.ip_filter.set_blocklist(settings.ip_blocklist.clone());
+    }
+    if report.contains("trusted_proxies") {
+        state.auth_state.ip_filter.set_trusted_proxies(&settings.trusted_proxies);
+    }
+    if ["login_max_failures", "login_failure_window_secs", "login_lockout_secs"].iter().any(|key| report.contains(key)) {
+        state.auth_state.login_guard.set_limits(&settings);
+    }
+    if ["blocked_models", "whitelist_models", "whitelist_user_agent"].iter().any(|key| report.contains(key)) {
+        state.auth_state.model_filter.set_policy(ModelPolicy::from_settings(&settings));
+    }
+    keys_changed
+}
+
+async fn export_config(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    Query(query): Query<AuthQuery>,
+    Query(export): Query<ExportQuery>,
+) -> Result<Json<serde_json::Value>, StatusCode> {
+    require_admin(&state, &headers, &query)?;
+
+    let settings = ConfigManager::get_settings().await;
+    let exported = export_settings(&settings, export.include_secrets).map_err(|e| {
+        tracing::error!("Failed to export settings: {}", e);
+        StatusCode::INTERNAL_SERVER_ERROR
+    })?;
+    Ok(Json(exported))
+}
+
+async fn import_config(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    Query(query): Query<AuthQuery>,
+    Json(import): Json<serde_json::Value>,
+) -> Result<Response, StatusCode> {
+    require_admin(&state, &headers, &query)?;
+
+    let current = ConfigManager::get_settings().await;
+    let imported = match import_settings(&current, import) {
+        Ok(settings) => settings,
+        Err(e) => return Ok(import_rejected(e)),
+    };
+    if let Err(e) = imported.validate() {
+        return Ok(import_rejected(e));
+    }
+    let imported = match imported.checked().await {
+        Ok(settings) => settings,
+        Err(e) => return Ok(import_rejected(e)),
+    };
+    let warnings = imported.config_warnings.clone();
+
+    let imported = Arc::new(imported);
+    let report = match ConfigManager::replace((*imported).clone()).await {
+        Ok(report) => report,
+        Err(e) => {
+            tracing::error!("Failed to save imported settings: {}", e);
+            return Err(StatusCode::INTERNAL_SERVER_ERROR);
+        }
+    };
+    // The imported settings are what requests read from now on; the rest waits for a restart
+    let (keys_added, keys_removed) = apply_settings(&state, imported, &report).await;
+    log_settings_change("imported", &report);
+
+    Ok(Json(serde_json::json!({
+        "success": true,
+        "changed": report.changed,
+        "restart_required": report.restart_required,
+        "keys_added": keys_added,
+        "keys_removed": keys_removed,
+        "warnings": warnings,
+    }))
+    .into_response())
+}
+
+fn import_rejected(error: anyhow::Error) -> Response {
+    (
+        StatusCode::UNPROCESSABLE_ENTITY,
+        Json(serde_json::json!({
+            "success": false,
+            "message": format!("Settings import rejected: {:#}", error),
+        })),
+    )
+        .into_response()
+}
+
+async fn get_model_policy(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    Query(query): Query<AuthQuery>,
+) -> Result<Json<serde_json::Value>, StatusCode> {
+    require_admin(&state, &headers, &query)?;
+
+    let policy = state.auth_state.model_filter.policy();
+    Ok(Json(model_policy_response(&state, &policy).await))
+}
+
+/// Replace the blocked and whitelisted models and user agents. Rejected when a pattern is
+/// malformed or when no model of the catalog would remain allowed.
+async fn update_model_policy(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    Query(query): Query<AuthQuery>,
+    Json(policy): Json<ModelPolicy>,
+) -> Result<Response, StatusCode> {
+    require_admin(&state, &headers, &query)?;
+
+    let rejected = |message: String| {
+        (StatusCode::UNPROCESSABLE_ENTITY, Json(serde_json::json!({"success": false, "message": message}))).into_response()
+    };
+    let policy = match policy.normalized() {
+        Ok(policy) => policy,
+        Err(message) => return Ok(rejected(message)),
+    };
+    // The catalog is empty until models are listed upstream, and the models the settings name
+    // are in use either way
+    let catalog = model_catalog(&state).await;
+    let mut models = catalog.into_iter().map(|model| model.id).chain(configured_models(&state.settings.load()));
+    if !models.any(|model| policy.allows_model(&model)) {
+        return Ok(rejected("This policy would block every available model".to_string()));
+    }
+
+    if let Err(e) = ConfigManager::set_model_policy(&policy).await {
+        tracing::error!("Failed to save model policy: {}", e);
+        return Err(StatusCode::INTERNAL_SERVER_ERROR);
+    }
+    state.auth_state.model_filter.set_policy(policy.clone());
+    info!(
+        "Model policy updated: {} blocked, {} whitelisted",
+        policy.blocked_models.len(),
+        policy.whitelist_models.len()
+    );
+
+    let mut response = model_policy_response(&state, &policy).await;
+    response["success"] = serde_json::json!(true);
+    Ok(Json(response).into_response())
+}
+
+/// Models the settings and the endpoint defaults name, whether or not the catalog lists them
+fn configured_models(settings: &Settings) -> Vec<String> {
+    let defaults = [&settings.warmup_model, &settings.transcription_model].into_iter().cloned();
+    let endpoint_defaults = [DEFAULT_IMAGE_MODEL, DEFAULT_MODERATION_MODEL].into_iter().map(str::to_string);
+    defaults.chain(endpoint_defaults).chain(settings.public_allowed_models.iter().cloned()).collect()
+}
+
+/// The policy with the catalog models it leaves allowed
+async fn model_policy_response(state: &AppState, policy: &ModelPolicy) -> serde_json::Value {
+    let allowed: Vec<String> = model_catalog(state)
+        .await
+        .into_iter()
+        .map(|model| model.id)
+        .filter(|id| policy.allows_model(id))
+        .collect();
+    serde_json::json!({
+        "blocked_models": policy.blocked_models,
+        "whitelist_models": policy.whitelist_models,
+        "whitelist_user_agent": policy.whitelist_user_agent,
+        "allowed_models": allowed,
+    })
+}
+
+/// Send "ping" to a model through the normal Gemini client, skipping the cache and the
+/// per-IP limits, and report how it went. The call is recorded in stats as diagnostic.
+async fn test_chat(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    Query(query): Query<AuthQuery>,
+    Json(test): Json<TestChatRequest>,
+) -> Result<Response, StatusCode> {
+    require_admin(&state, &headers, &query)?;
+
+    let api_key = match test.key_index {
+        Some(index) => match state.key_manager.key_at(index).await {
+            Some(key) => key,
+            None => {
+                let message = format!("No usable API key at index {}", index);
+                return Ok((StatusCode::UNPROCESSABLE_ENTITY, Json(serde_json::json!({"success": false, "message": message}))).into_response());
+            }
+        },
+        None => match state.key_manager.get_next_key().await {
+            Some(key) => key,
+            None => {
+                let body = serde_json::json!({"success": false, "message": "No usable API key"});
+                return Ok((StatusCode::SERVICE_UNAVAILABLE, Json(body)).into_response());
+            }
+        },
+    };
+    let request: ChatCompletionRequest = serde_json::from_value(serde_json::json!({
+        "model": test.model,
+        "messages": [{"role": "user", "content": "ping"}],
+    }))
+    .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+
+    let started = std::time::Instant::now();
+    let result = state.gemini_client.chat_completion(request, &api_key).await;
+    let latency_ms = started.elapsed().as_millis() as u64;
+    let origin = CallOrigin { diagnostic: true, ..Default::default() };
+    let key_prefix = format!("{}...", &api_key[..8.min(api_key.len())]);
+    info!("Diagnostic test request to {} with key {} took {}ms", test.model, key_prefix, latency_ms);
+
+    let report = match result {
+        Ok(response) => {
+            state.key_manager.mark_key_used(&api_key, true).await;
+            let usage = response.usage.clone();
+            state.stats_manager.record_api_usage(test.model.clone(), usage.as_ref(), latency_ms, origin).await;
+
+            let text = match response.choices.first().and_then(|choice| choice.message.content.as_ref()) {
+                Some(serde_json::Value::String(text)) => text.clone(),
+                Some(content) => content.to_string(),
+                None => String::new(),
+            };
+            serde_json::json!({
+                "success": true,
+                "model": test.model,
+                "key": key_prefix,
+                "latency_ms": latency_ms,
+                "status": 200,
+                "usage": usage,
+                "error": null,
+                "response_preview": text.chars().take(TEST_CHAT_PREVIEW_CHARS).collect::<String>(),
+            })
+        }
+        Err(e) => {
+            state.key_manager.record_error(&api_key, &e).await;
+            state.stats_manager.record_api_error(test.model.clone(), e.error_type(), latency_ms, origin).await;
+            serde_json::json!({
+                "success": false,
+                "model": test.model,
+                "key": key_prefix,
+                "latency_ms": latency_ms,
+                "status": e.status().as_u16(),
+                "usage": null,
+                "error": {"type": e.error_type(), "message": translate_error(&e), "detail": e.to_string()},
+                "response_preview": null,
+            })
+        }
+    };
+    Ok(Json(report).into_response())
+}
+
+async fn run_warmup(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    Query(query): Query<AuthQuery>,
+) -> Result<Response, StatusCode> {
+    require_admin(&state, &headers, &query)?;
+    match warm_up(&state).await {
+        Some(report) => Ok(Json(report).into_response()),
+        None => {
+            let body = serde_json::json!({"success": false, "message": "No usable API key"});
+            Ok((StatusCode::SERVICE_UNAVAILABLE, Json(body)).into_response())
+        }
+    }
+}
+
+/// Open the upstream connections ahead of user traffic: reload the model list, then ask
+/// `warmup_model` for a single token, both with one rotated key. The generation is recorded
+/// in stats as diagnostic so it stays out of user metrics. `None` when no key is available.
+pub async fn warm_up(state: &AppState) -> Option<serde_json::Value> {
+    let api_key = match state.key_manager.get_next_key().await {
+        Some(key) => key,
+        None => {
+            warn!("Skipping warm-up, no API keys available");
+            return None;
+        }
+    };
+    let model = state.settings.load().warmup_model.clone();
+
+    let started = std::time::Instant::now();
+    let models = state.gemini_client.refresh_models(&state.key_manager, api_key.clone()).await;
+    let models_ms = started.elapsed().as_millis() as u64;
+
+    let request: ChatCompletionRequest = serde_json::from_value(serde_json::json!({
+        "model": model,
+        "messages": [{"role": "user", "content": "hi"}],
+        "max_tokens": 1,
+    }))
+    .ok()?;
+    let started = std::time::Instant::now();
+    let result = state.gemini_client.chat_completion(request, &api_key).await;
+    let generate_ms = started.elapsed().as_millis() as u64;
+    let origin = CallOrigin { diagnostic: true, ..Default::default() };
+    let generate_error = match result {
+        Ok(response) => {
+            state.key_manager.mark_key_used(&api_key, true).await;
+            state.stats_manager.record_api_usage(model.clone(), response.usage.as_ref(), generate_ms, origin).await;
+            None
+        }
+        Err(e) => {
+            state.key_manager.record_error(&api_key, &e).await;
+            state.stats_manager.record_api_error(model.clone(), e.error_type(), generate_ms, origin).await;
+            Some(e.to_string())
+        }
+    };
+    let models_error = models.err().map(|e| e.to_string());
+
+    if models_error.is_none() && generate_error.is_none() {
+        info!("Warm-up finished in {}ms (model list {}ms, {} {}ms)", models_ms + generate_ms, models_ms, model, generate_ms);
+    } else {
+        warn!(
+            "Warm-up finished with errors in {}ms: model list {:?}, {} {:?}",
+            models_ms + generate_ms,
+            models_error,
+            model,
+            generate_error
+        );
+    }
+    Some(serde_json::json!({
+        "success": models_error.is_none() && generate_error.is_none(),
+        "model": model,
+        "total_ms": models_ms + generate_ms,
+        "models_ms": models_ms,
+        "models_error": models_error,
+        "generate_ms": generate_ms,
+        "generate_error": generate_error,
+    }))
+}
+
+fn require_admin(state: &AppState, headers: &HeaderMap, query: &AuthQuery) -> Result<(), StatusCode> {
+    let auth_result = authenticate_dashboard_request(headers, query, &state.auth_state);
+    if !auth_result.authenticated {
+        return Err(StatusCode::UNAUTHORIZED);
+    }
+    if !matches!(auth_result.scope, AuthScope::Admin) {
+        return Err(StatusCode::FORBIDDEN);
+    }
+    Ok(())
+}
+
+/// Persist client keys via the config manager, then make them live for authentication
+async fn save_client_keys(state: &AppState, client_keys: Vec<ClientKey>) -> Result<(), StatusCode> {
+    let value = serde_json::to_value(&client_keys).map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+    if let Err(e) = ConfigManager::update_config("client_keys", value).await {
+        tracing::error!("Failed to save client keys: {}", e);
+        return Err(StatusCode::INTERNAL_SERVER_ERROR);
+    }
+
+    state.auth_state.set_client_keys(client_keys);
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::testing::test_state;
+    use http_body_util::BodyExt;
+
+    #[tokio::test]
+    async fn test_model_policy_endpoint() {
+        use tower::ServiceExt;
+
+        let state = test_state();
+        state.gemini_client.load_default_models().await;
+        let app = crate::build_app(state).await.unwrap();
+        let send = |method: &str, body: &str| {
+            hyper::Request::builder()
+                .method(method)
+                .uri("/dashboard-api/models/policy")
+                .header("authorization", "Bearer 123")
+                .header("content-type", "application/json")
+                .body(axum::body::Body::from(body.to_string()))
+                .unwrap()
+        };
+
+        let response = app.clone().oneshot(send("GET", "")).await.unwrap();
+        assert_eq!(response.status(), 200);
+        let body = response.into_body().collect().await.unwrap().to_bytes();
+        let json: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(json["allowed_models"].as_array().unwrap().len(), 6);
+
+        // Neither of these reaches the config manager
+        let response = app.clone().oneshot(send("PUT", r#"{"blocked_models": ["*"]}"#)).await.unwrap();
+        assert_eq!(response.status(), 422);
+        let response = app.oneshot(send("PUT", r#"{"whitelist_models": ["gemini-[12]"]}"#)).await.unwrap();
+        assert_eq!(response.status(), 422);
+
+        // Before the catalog is loaded the configured models are what a policy is checked against
+        let app = crate::build_app(test_state()).await.unwrap();
+        let response = app.clone().oneshot(send("PUT", r#"{"blocked_models": ["*"]}"#)).await.unwrap();
+        assert_eq!(response.status(), 422);
+        let response = app.oneshot(send("PUT", r#"{"whitelist_models": ["gpt-4"]}"#)).await.unwrap();
+        assert_eq!(response.status(), 422);
+    }
+
+    #[tokio::test]
+    async fn test_emergency_cleanup_endpoint() {
+        use tower::ServiceExt;
+
+        let state = test_state();
+        let cached: crate::models::schemas::ChatCompletionResponse = serde_json::from_value(serde_json::json!({
+            "id": "chatcmpl-cached", "object": "chat.completion", "created": 0, "model": "gemini-1.5-flash",
+            "choices": [{"index": 0, "message": {"role": "assistant", "content": "cached"}, "finish_reason": "stop"}]
+        }))
+        .unwrap();
+        state.cache_manager.put("one".to_string(), cached.clone()).await;
+        state.cache_manager.put("two".to_string(), cached).await;
+        for _ in 0..3 {
+            state.stats_manager.record_api_call("gemini-1.5-flash".to_string(), 10, 5, true, 100, Default::default()).await;
+        }
+        let app = crate::build_app(state.clone()).await.unwrap();
+
+        let response = app
+            .oneshot(
+                hyper::Request::builder()
+                    .method("POST")
+                    .uri("/dashboard-api/maintenance/emergency-cleanup")
+                    .header("authorization", "Bearer 123")
+                    .body(axum::body::Body::empty())
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+        assert_eq!(response.status(), 200);
+        let body = response.into_body().collect().await.unwrap().to_bytes();
+        let json: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(json["cleared"]["cache_entries"], 2);
+        assert_eq!(json["cleared"]["stats_records"], 3);
+        assert_eq!(state.cache_manager.size().await, 0);
+        assert_eq!(state.stats_manager.get_stats().await.total_requests, 0);
+    }
+
+    #[tokio::test]
+    async fn test_cache_clear_by_model() {
+        use crate::utils::cache::generate_cache_key;
+        use tower::ServiceExt;
+
+        let state = test_state();
+        let cached: crate::models::schemas::ChatCompletionResponse = serde_json::from_value(serde_json::json!({
+            "id": "chatcmpl-cached", "object": "chat.completion", "created": 0, "model": "gemini-1.5-flash",
+            "choices": [{"index": 0, "message": {"role": "assistant", "content": "cached"}, "finish_reason": "stop"}]
+        }))
+        .unwrap();
+        let messages = [serde_json::json!({"role": "user", "content": "Hi"})];
+        for model in ["gemini-1.5-flash", "gemini-1.5-pro"] {
+            state.cache_manager.put(generate_cache_key(&messages, model, 6, false), cached.clone()).await;
+        }
+        let app = crate::build_app(state.clone()).await.unwrap();
+
+        let clear = |body: &'static str| {
+            hyper::Request::builder()
+                .method("POST")
+                .uri("/dashboard-api/cache/clear")
+                .header("authorization", "Bearer 123")
+                .header("content-type", "application/json")
+                .body(axum::body::Body::from(body))
+                .unwrap()
+        };
+        let response = app.clone().oneshot(clear(r#"{"model": "gemini-1.5-pro"}"#)).await.unwrap();
+        let body = response.into_body().collect().await.unwrap().to_bytes();
+        let json: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(json["removed"], 1);
+        assert_eq!(state.cache_manager.size().await, 1);
+
+        let response = app.clone().oneshot(clear("{model}")).await.unwrap();
+        assert_eq!(response.status(), 400);
+
+        let response = app.oneshot(clear("")).await.unwrap();
+        let body = response.into_body().collect().await.unwrap().to_bytes();
+        let json: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(json["removed"], 1);
+        assert_eq!(state.cache_manager.size().await, 0);
+    }
+
+    #[tokio::test]
+    async fn test_vertex_logs_endpoint() {
+        use tower::ServiceExt;
+
+        crate::utils::logging::vertex_log_event("info", "init", "success", "vertex logs endpoint test: init");
+        crate::utils::logging::vertex_log_event("error", "token_refresh", "failed", "vertex logs endpoint test: refresh");
+        let app = crate::build_app(test_state()).await.unwrap();
+
+        let response = app
+            .oneshot(
+                hyper::Request::builder()
+                    .uri("/dashboard-api/vertex-logs?level=error&limit=100")
+                    .header("authorization", "Bearer 123")
+                    .body(axum::body::Body::empty())
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+        assert_eq!(response.status(), 200);
+        let body = response.into_body().collect().await.unwrap().to_bytes();
+        let json: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        let logs = json["logs"].as_array().unwrap();
+        assert!(logs.iter().all(|entry| entry["level"] == "error"));
+        let refresh = logs.iter().find(|entry| entry["message"] == "vertex logs endpoint test: refresh").unwrap();
+        assert_eq!(refresh["operation"], "token_refresh");
+        assert_eq!(refresh["status"], "failed");
+    }
+
+    #[tokio::test]
+    async fn test_dashboard_insights() {
+        use tower::ServiceExt;
+
+        let state = test_state();
+        for (model, tokens) in [("gemini-2.0-flash", 10), ("gemini-2.0-flash", 10), ("gemini-1.5-pro", 500)] {
+            state.stats_manager.record_api_call(model.to_string(), tokens, 0, true, 5, crate::utils::CallOrigin::default()).await;
+        }
+        let app = crate::build_app(state).await.unwrap();
+        let get = |auth: bool| {
+            let mut builder = hyper::Request::builder().uri("/dashboard-api/data");
+            if auth {
+                builder = builder.header("authorization", "Bearer 123");
+            }
+            builder.body(axum::body::Body::empty()).unwrap()
+        };
+
+        assert_eq!(app.clone().oneshot(get(false)).await.unwrap().status(), 401);
+        let response = app.oneshot(get(true)).await.unwrap();
+        assert_eq!(response.status(), 200);
+        let body = response.into_body().collect().await.unwrap().to_bytes();
+        let insights = serde_json::from_slice::<serde_json::Value>(&body).unwrap()["insights"].clone();
+        assert_eq!(insights["top_models_by_requests"][0]["model"], "gemini-2.0-flash");
+        assert_eq!(insights["top_models_by_tokens"][0]["model"], "gemini-1.5-pro");
+        assert!(insights["worst_key"].is_null());
+        assert_eq!(insights["failure_anomaly"]["flagged"], false);
+    }
+
+    #[tokio::test]
+    async fn test_dashboard_data_revalidation_and_delta_polls() {
+        use tower::ServiceExt;
+
+        let state = test_state();
+        let stats_manager = state.stats_manager.clone();
+        let app = crate::build_app(state).await.unwrap();
+        let get = |query: String, etag: Option<String>| {
+            let mut builder = hyper::Request::builder()
+                .uri(format!("/dashboard-api/data{}", query))
+                .header("authorization", "Bearer 123");
+            if let Some(etag) = etag {
+                builder = builder.header("if-none-match", etag);
+            }
+            builder.body(axum::body::Body::empty()).unwrap()
+        };
+        let fetch = |query: String, etag: Option<String>| {
+            let app = app.clone();
+            let request = get(query, etag);
+            async move {
+                let response = app.oneshot(request).await.unwrap();
+                let status = response.status();
+                let etag = response.headers().get("etag").map(|value| value.to_str().unwrap().to_string());
+                let body = response.into_body().collect().await.unwrap().to_bytes();
+                (status, etag, body)
+            }
+        };
+
+        // Lines logged by tests running alongside change the ETag, so allow a few tries
+        let mut revalidated = None;
+        for _ in 0..5 {
+            let (status, etag, full) = fetch(String::new(), None).await;
+            assert_eq!(status, 200);
+            let (status, _, body) = fetch(String::new(), etag.clone()).await;
+            if status == 304 {
+                assert!(body.is_empty());
+                revalidated = Some((etag.unwrap(), full));
+                break;
+            }
+        }
+        let (etag, full) = revalidated.expect("an unchanged dashboard is answered with 304");
+
+        // A new call changes the ETag and only its sections come back after the cursor
+        let cursor = serde_json::from_slice::<serde_json::Value>(&full).unwrap()["cursor"].as_u64().unwrap();
+        stats_manager.record_api_call("gemini-2.0-flash".to_string(), 10, 5, true, 100, Default::default()).await;
+        let (status, new_etag, delta) = fetch(format!("?since={}", cursor), Some(etag.clone())).await;
+        assert_eq!(status, 200);
+        assert_ne!(new_etag.unwrap(), etag);
+        let sections = serde_json::from_slice::<serde_json::Value>(&delta).unwrap();
+        assert_eq!(sections["stats"]["total_requests"], 1);
+        assert!(sections["key_stats"].is_array());
+        assert!(sections.get("config").is_none() && sections.get("version").is_none());
+        assert!(sections["hourly_stats"].as_array().unwrap().len() <= 2);
+        assert!(delta.len() < full.len());
+    }
+
+    #[tokio::test]
+    async fn test_dashboard_reasoning_tokens() {
+        use tower::ServiceExt;
+
+        // Usage of a captured gemini-2.5-flash response with thinking
+        let metadata: crate::models::schemas::GeminiUsageMetadata = serde_json::from_value(serde_json::json!({
+            "promptTokenCount": 41, "candidatesTokenCount": 15, "totalTokenCount": 120, "thoughtsTokenCount": 64
+        }))
+        .unwrap();
+        let usage = crate::models::schemas::Usage::from_gemini(&metadata);
+        let state = test_state();
+        state.stats_manager.record_api_usage("gemini-2.5-flash".to_string(), Some(&usage), 900, Default::default()).await;
+        state.stats_manager.record_api_call("gemini-2.0-flash".to_string(), 10, 5, true, 100, Default::default()).await;
+
+        let model_stats = state.stats_manager.get_model_stats().await;
+        let thinking = model_stats.iter().find(|stats| stats.model_name == "gemini-2.5-flash").unwrap();
+        assert_eq!((thinking.completion_token_count, thinking.reasoning_token_count), (79, 64));
+
+        let app = crate::build_app(state).await.unwrap();
+        let request = hyper::Request::builder()
+            .uri("/dashboard-api/data")
+            .header("authorization", "Bearer 123")
+            .body(axum::body::Body::empty())
+            .unwrap();
+        let response = app.oneshot(request).await.unwrap();
+        let body = response.into_body().collect().await.unwrap().to_bytes();
+        let stats = serde_json::from_slice::<serde_json::Value>(&body).unwrap()["stats"].clone();
+        assert_eq!(stats["completion_tokens"], 84);
+        assert_eq!(stats["reasoning_tokens"], 64);
+    }
+
+    #[tokio::test]
+    async fn test_storage_status_endpoint() {
+        use tower::ServiceExt;
+
+        let app = crate::build_app(test_state()).await.unwrap();
+        let get = |auth: bool| {
+            let mut builder = hyper::Request::builder().uri("/dashboard-api/storage/status");
+            if auth {
+                builder = builder.header("authorization", "Bearer 123");
+            }
+            builder.body(axum::body::Body::empty()).unwrap()
+        };
+
+        assert_eq!(app.clone().oneshot(get(false)).await.unwrap().status(), 401);
+        let response = app.oneshot(get(true)).await.unwrap();
+        assert_eq!(response.status(), 200);
+        let body = response.into_body().collect().await.unwrap().to_bytes();
+        let status: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(status["backend"], "memory");
+        assert!(status["path"].is_null());
+        assert_eq!(status["failed_writes"], 0);
+    }
+
+    #[tokio::test]
+    async fn test_about_endpoint() {
+        use tower::ServiceExt;
+
+        let app = crate::build_app(test_state()).await.unwrap();
+        let get = |auth: &str| {
+            hyper::Request::builder()
+                .uri("/dashboard-api/about")
+                .header("authorization", auth)
+                .body(axum::body::Body::empty())
+                .unwrap()
+        };
+        assert_eq!(app.clone().oneshot(get("Bearer wrong")).await.unwrap().status(), 401);
+
+        let response = app.oneshot(get("Bearer 123")).await.unwrap();
+        assert_eq!(response.status(), 200);
+        let body = response.into_body().collect().await.unwrap().to_bytes();
+        let about: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(about["version"], crate::utils::version::get_current_version());
+        assert_eq!(about["build_info"], crate::utils::version::get_build_info());
+        assert!(about["keys"]["valid"].is_u64());
+        assert!(about["warnings"].is_array());
+    }
+
+    #[tokio::test]
+    async fn test_route_stats_endpoint() {
+        use tower::ServiceExt;
+
+        let app = crate::build_app(test_state()).await.unwrap();
+        let get = |uri: &str| {
+            hyper::Request::builder()
+                .uri(uri)
+                .header("authorization", "Bearer 123")
+                .body(axum::body::Body::empty())
+                .unwrap()
+        };
+        for uri in ["/health", "/health", "/v1/models/gemini-1.5-flash", "/v1/no/such/route"] {
+            app.clone().oneshot(get(uri)).await.unwrap();
+        }
+
+        let response = app.oneshot(get("/dashboard-api/stats/routes")).await.unwrap();
+        assert_eq!(response.status(), 200);
+        let body = response.into_body().collect().await.unwrap().to_bytes();
+        let json: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        let routes = json["routes"].as_array().unwrap();
+        let route = |name: &str| routes.iter().find(|route| route["route"] == name).unwrap().clone();
+        assert_eq!(route("/health")["count"], 2);
+        assert_eq!(route("/health")["method"], "GET");
+        assert_eq!(route("/health")["status_classes"]["2xx"], 2);
+        assert_eq!(route("/v1/models/:id")["count"], 1);
+        assert_eq!(route("unmatched")["status_classes"]["4xx"], 1);
+        assert!(route("/health")["p99_ms"].as_f64().unwrap() >= route("/health")["p50_ms"].as_f64().unwrap());
+    }
+
+    #[tokio::test]
+    async fn test_diagnostic_chat_without_keys() {
+        use tower::ServiceExt;
+
+        let app = crate::build_app(test_state()).await.unwrap();
+        let send = |body: &str, auth: &str| {
+            hyper::Request::builder()
+                .method("POST")
+                .uri("/dashboard-api/diagnostics/test-chat")
+                .header("authorization", auth)
+                .header("content-type", "application/json")
+                .body(axum::body::Body::from(body.to_string()))
+                .unwrap()
+        };
+
+        let response = app.clone().oneshot(send("{}", "Bearer wrong")).await.unwrap();
+        assert_eq!(response.status(), 401);
+        let response = app.clone().oneshot(send("{}", "Bearer 123")).await.unwrap();
+        assert_eq!(response.status(), 503);
+        let response = app.oneshot(send(r#"{"key_index": 3}"#, "Bearer 123")).await.unwrap();
+        assert_eq!(response.status(), 422);
+    }
+
+    #[tokio::test]
+    async fn test_warmup_requires_admin_and_a_key() {
+        use tower::ServiceExt;
+
+        let app = crate::build_app(test_state()).await.unwrap();
+        let send = |auth: &str| {
+            hyper::Request::builder()
+                .method("POST")
+                .uri("/dashboard-api/warmup")
+                .header("authorization", auth)
+                .body(axum::body::Body::empty())
+                .unwrap()
+        };
+
+        let response = app.clone().oneshot(send("Bearer wrong")).await.unwrap();
+        assert_eq!(response.status(), 401);
+        let response = app.oneshot(send("Bearer 123")).await.unwrap();
+        assert_eq!(response.status(), 503);
+        let body = response.into_body().collect().await.unwrap().to_bytes();
+        let json: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(json["success"], false);
+    }
+
+    #[tokio::test]
+    async fn test_reset_one_model_or_key_keeps_the_rest() {
+        use tower::ServiceExt;
+
+        let state = test_state();
+        let stats_manager = state.stats_manager.clone();
+        for model in ["gemini-2.0-flash", "gemini-2.0-flash", "gemini-1.5-pro"] {
+            stats_manager.record_api_call(model.to_string(), 10, 5, false, 100, Default::default()).await;
+        }
+        let app = crate::build_app(state).await.unwrap();
+        let reset = |uri: &str, auth: &str| {
+            hyper::Request::builder()
+                .method("POST")
+                .uri(uri)
+                .header("authorization", auth)
+                .body(axum::body::Body::empty())
+                .unwrap()
+        };
+
+        let response = app.clone().oneshot(reset("/dashboard-api/stats/models/gemini-2.0-flash/reset", "Bearer wrong")).await.unwrap();
+        assert_eq!(response.status(), 401);
+        let response = app.clone().oneshot(reset("/dashboard-api/stats/models/gemini-2.0-flash/reset", "Bearer 123")).await.unwrap();
+        assert_eq!(response.status(), 200);
+        let body = response.into_body().collect().await.unwrap().to_bytes();
+        let json: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(json["cleared"]["failure_count"], 2);
+
+        let models = stats_manager.get_model_stats().await;
+        assert_eq!(models.len(), 1);
+        assert_eq!(models[0].model_name, "gemini-1.5-pro");
+        assert_eq!(stats_manager.get_recent_calls(10).await.len(), 3);
+
+        // Nothing left to reset, and no key at that position
+        let response = app.clone().oneshot(reset("/dashboard-api/stats/models/gemini-2.0-flash/reset", "Bearer 123")).await.unwrap();
+        assert_eq!(response.status(), 404);
+        let response = app.oneshot(reset("/dashboard-api/keys/0/reset", "Bearer 123")).await.unwrap();
+        assert_eq!(response.status(), 404);
+    }
+
+    #[tokio::test]
+    async fn test_dashboard_stream_pushes_snapshots() {
+        use tower::ServiceExt;
+
+        let state = test_state();
+        let live_stats = state.live_stats.clone();
+        let app = crate::build_app(state).await.unwrap();
+        let stream = |auth: &str| {
+            hyper::Request::builder()
+                .uri("/dashboard-api/stream")
+                .header("authorization", auth)
+                .body(axum::body::Body::empty())
+                .unwrap()
+        };
+        async fn next_snapshot(body: &mut axum::body::Body) -> serde_json::Value {
+            let data = body.frame().await.unwrap().unwrap().into_data().unwrap();
+            let text = std::str::from_utf8(&data).unwrap();
+            serde_json::from_str(text.trim().trim_start_matches("data:").trim()).unwrap()
+        }
+
+        let response = app.clone().oneshot(stream("Bearer wrong")).await.unwrap();
+        assert_eq!(response.status(), 401);
+
+        let response = app.oneshot(stream("Bearer 123")).await.unwrap();
+        assert_eq!(response.status(), 200);
+        assert_eq!(response.headers()["content-type"], "text/event-stream");
+        let mut body = response.into_body();
+
+        // The current state straight away, then whatever the sampler publishes
+        let first = next_snapshot(&mut body).await;
+        assert_eq!(first["keys_available"], 0);
+        assert_eq!(first["active_streams"], 0);
+        assert!(first["logs"].is_array());
+
+        live_stats.publish(crate::utils::live_stats::LiveSnapshot {
+            timestamp: 0,
+            requests_per_minute: 0,
+            tokens_per_minute: 0,
+            active_streams: 0,
+            keys_available: 7,
+            cache_hit_rate: 0.0,
+            logs: Vec::new(),
+        });
+        assert_eq!(next_snapshot(&mut body).await["keys_available"], 7);
+    }
+
+    #[tokio::test]
+    async fn test_reloaded_settings_apply_to_requests() {
+        use super::apply_settings;
+        use crate::config::reload::ReloadReport;
+        use crate::config::Settings;
+        use std::sync::Arc;
+        use tower::ServiceExt;
+
+        let state = test_state();
+        let app = crate::build_app(state.clone()).await.unwrap();
+        let chat = || {
+            hyper::Request::builder()
+                .method("POST")
+                .uri("/v1/chat/completions")
+                .header("content-type", "application/json")
+                .body(axum::body::Body::from(r#"{"model": "gemini-2.0-flash", "messages": [{"role": "user", "content": "hi"}]}"#))
+                .unwrap()
+        };
+        assert_eq!(app.clone().oneshot(chat()).await.unwrap().status(), 401);
+
+        // Public mode turned on by a reload admits anonymous callers without a restart
+        let old = state.settings.load();
+        let new = Settings {
+            public_mode: true,
+            public_allowed_models: vec!["gemini-2.0-flash".to_string()],
+            ..(*old).clone()
+        };
+        let report = ReloadReport::between(&old, &new);
+        assert!(report.restart_required.is_empty());
+        apply_settings(&state, Arc::new(new), &report).await;
+        assert_eq!(app.oneshot(chat()).await.unwrap().status(), 503);
+    }
+}
@@ -0,0 +1,326 @@
+//! Cross-provider fallback for chat completions. A request the primary provider cannot
+//! serve for lack of keys or quota is handed once to the other provider; the hand-off is
+//! recorded in `CallOrigin::fallback_provider`, and a call carrying it never falls back again.
+
+use axum::{
+    extract::ConnectInfo,
+    http::HeaderMap,
+    response::{IntoResponse, Json, Response},
+};
+use anyhow::Result;
+use serde::{de::DeserializeOwned, Serialize};
+use serde_json::Value;
+use std::net::SocketAddr;
+use std::time::Instant;
+use tracing::{info, warn};
+
+use crate::config::FallbackProvider;
+use crate::models::schemas::ChatCompletionRequest;
+use crate::utils::{
+    auth::{authenticate_request, AuthQuery},
+    browser::forwarded_header_names,
+    error_handling::{is_quota_error, upstream_error_type},
+    stats::end_user_id,
+    CallOrigin,
+};
+use crate::vertex::{
+    main::{error_response, event_stream_response},
+    models::OpenAIRequest,
+    routes::{chat_api::{self, ChatCompletionOutput}, models_api},
+};
+use crate::AppState;
+
+use super::pipeline::{ChatContext, ChatPipeline};
+use super::routes::{call_origin, client_ip};
+
+/// Whether a failed Vertex AI call should be retried on the Gemini API: out of quota, or
+/// no credential left to try
+pub fn vertex_exhausted(error: &anyhow::Error) -> bool {
+    let message = error.to_string();
+    is_quota_error(&message)
+        || message.contains("not initialized")
+        || message.contains("No usable Vertex AI")
+        || message.contains("No Vertex AI Express key available")
+}
+
+/// Serve a Gemini API request through Vertex AI when `FALLBACK_PROVIDER=vertex` and Vertex
+/// knows the model. `None` means the fallback does not apply and the Gemini outcome stands.
+pub async fn serve_with_vertex(
+    state: &AppState,
+    request: &ChatCompletionRequest,
+    origin: &CallOrigin,
+    start_time: Instant,
+) -> Option<Response> {
+    let (vertex_request, origin) = vertex_fallback(state, request, origin).await?;
+    Some(serve_on_vertex(state, &request.model, vertex_request, origin, start_time).await)
+}
+
+/// Serve a Gemini API request through Vertex AI when Vertex is on and there are no Gemini keys
+/// at all, whatever `FALLBACK_PROVIDER` says: a Vertex-only deployment needs no dummy keys.
+/// Not tagged as a fallback, since there was no other provider to try.
+pub async fn serve_vertex_only(
+    state: &AppState,
+    request: &ChatCompletionRequest,
+    origin: &CallOrigin,
+    start_time: Instant,
+) -> Option<Response> {
+    if !state.vertex_enabled || state.key_manager.available_keys_count().await > 0 {
+        return None;
+    }
+    let mut vertex_request: OpenAIRequest = match convert_request(request) {
+        Ok(vertex_request) => vertex_request,
+        Err(e) => {
+            warn!("Cannot convert Gemini API request for Vertex AI: {}", e);
+            return None;
+        }
+    };
+    vertex_request.forwarded_headers = request.forwarded_headers.clone();
+    Some(serve_on_vertex(state, &request.model, vertex_request, origin.clone(), start_time).await)
+}
+
+async fn serve_on_vertex(
+    state: &AppState,
+    model: &str,
+    vertex_request: OpenAIRequest,
+    origin: CallOrigin,
+    start_time: Instant,
+) -> Response {
+    match chat_api::handle_chat_completion(&state.settings.load(), vertex_request).await {
+        Ok(ChatCompletionOutput::Json(response)) => {
+            record_vertex_success(state, model, &response, origin, start_time).await;
+            Json(response).into_response()
+        }
+        Ok(ChatCompletionOutput::Stream(frames)) => {
+            record_vertex_success(state, model, &Value::Null, origin, start_time).await;
+            event_stream_response(frames)
+        }
+        Err(e) => {
+            warn!("Vertex AI request failed: {:#}", e);
+            record_vertex_error(state, model, &e, origin, start_time).await;
+            error_response(&e)
+        }
+    }
+}
+
+/// Non-streaming Vertex AI fallback, for fake streaming where the SSE response has already
+/// started and only the completion body can be swapped
+pub async fn vertex_completion(
+    state: &AppState,
+    request: &ChatCompletionRequest,
+    origin: &CallOrigin,
+    start_time: Instant,
+) -> Option<Result<Value>> {
+    let (mut vertex_request, origin) = vertex_fallback(state, request, origin).await?;
+    vertex_request.stream = Some(false);
+
+    let result = match chat_api::handle_chat_completion(&state.settings.load(), vertex_request).await {
+        Ok(ChatCompletionOutput::Json(response)) => Ok(response),
+        Ok(ChatCompletionOutput::Stream(_)) => Err(anyhow::anyhow!("Vertex AI answered a non-streaming request with a stream")),
+        Err(e) => Err(e),
+    };
+    match &result {
+        Ok(response) => record_vertex_success(state, &request.model, response, origin, start_time).await,
+        Err(e) => {
+            warn!("Vertex AI fallback failed: {:#}", e);
+            record_vertex_error(state, &request.model, e, origin, start_time).await;
+        }
+    }
+    Some(result)
+}
+
+/// Serve a Vertex AI request through the Gemini API when `FALLBACK_PROVIDER=gemini`, the
+/// model is in the Gemini catalog and a Gemini key is free. `None` leaves the Vertex error in place.
+pub async fn serve_with_gemini(
+    state: AppState,
+    connect_info: Option<ConnectInfo<SocketAddr>>,
+    headers: &HeaderMap,
+    query: &AuthQuery,
+    request: &OpenAIRequest,
+) -> Option<Response> {
+    if state.settings.load().fallback_provider != FallbackProvider::Gemini {
+        return None;
+    }
+    if !state.gemini_client.get_model_catalog().await.iter().any(|model| model.id == request.model) {
+        return None;
+    }
+    let mut gemini_request: ChatCompletionRequest = match convert_request(request) {
+        Ok(gemini_request) => gemini_request,
+        Err(e) => {
+            warn!("Cannot convert Vertex AI request for the Gemini API: {}", e);
+            return None;
+        }
+    };
+    let api_key = match state.key_manager.get_next_key().await {
+        Some(key) => key,
+        None => {
+            warn!("Gemini API fallback skipped: no API keys available");
+            return None;
+        }
+    };
+
+    info!("Falling back to the Gemini API for model {}", request.model);
+    let auth_result = authenticate_request(headers, query, &state.auth_state);
+    let mut origin = call_origin(client_ip(&state, headers, connect_info), &auth_result);
+    origin.fallback_provider = Some(FallbackProvider::Gemini.as_str().to_string());
+    origin.end_user = gemini_request.user.as_deref().map(end_user_id);
+    gemini_request.forwarded_headers = request.forwarded_headers.clone();
+    origin.forwarded_headers = forwarded_header_names(&gemini_request.forwarded_headers);
+    let context = ChatContext {
+        request: gemini_request,
+        auth: auth_result,
+        origin,
+        start_time: Instant::now(),
+        deadline: None,
+        native: false,
+    };
+
+    Some(ChatPipeline::new(state, headers.clone()).dispatch_converted(context, api_key).await)
+}
+
+/// The Vertex AI form of `request` and the origin to record, when the fallback applies
+async fn vertex_fallback(
+    state: &AppState,
+    request: &ChatCompletionRequest,
+    origin: &CallOrigin,
+) -> Option<(OpenAIRequest, CallOrigin)> {
+    if state.settings.load().fallback_provider != FallbackProvider::Vertex
+        || !state.vertex_enabled
+        || origin.fallback_provider.is_some()
+    {
+        return None;
+    }
+    if !models_api::is_model_available(&state.settings.load(), &request.model).await.unwrap_or(false) {
+        return None;
+    }
+    let mut vertex_request: OpenAIRequest = match convert_request(request) {
+        Ok(vertex_request) => vertex_request,
+        Err(e) => {
+            warn!("Cannot convert Gemini API request for Vertex AI: {}", e);
+            return None;
+        }
+    };
+    vertex_request.forwarded_headers = request.forwarded_headers.clone();
+
+    info!("Falling back to Vertex AI for model {}", request.model);
+    let mut origin = origin.clone();
+    origin.fallback_provider = Some(FallbackProvider::Vertex.as_str().to_string());
+    Some((vertex_request, origin))
+}
+
+/// Re-type an OpenAI-style request for the other provider. Unset fields are dropped so each
+/// side's defaults apply; the model name is kept as the client sent it.
+fn convert_request<T: DeserializeOwned>(request: &impl Serialize) -> Result<T> {
+    let mut value = serde_json::to_value(request)?;
+    if let Some(fields) = value.as_object_mut() {
+        fields.retain(|_, field| !field.is_null());
+    }
+    Ok(serde_json::from_value(value)?)
+}
+
+async fn record_vertex_success(state: &AppState, model: &str, response: &Value, origin: CallOrigin, start_time: Instant) {
+    let tokens = |field: &str| response["usage"][field].as_u64().unwrap_or(0) as u32;
+    state.stats_manager.record_api_call(
+        model.to_string(),
+        tokens("prompt_tokens"),
+        tokens("completion_tokens"),
+        true,
+        start_time.elapsed().as_millis() as u64,
+        origin,
+    ).await;
+}
+
+async fn record_vertex_error(state: &AppState, model: &str, error: &anyhow::Error, origin: CallOrigin, start_time: Instant) {
+    state.stats_manager.record_api_error(
+        model.to_string(),
+        upstream_error_type(error),
+        start_time.elapsed().as_millis() as u64,
+        origin,
+    ).await;
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::config::Settings;
+    use crate::services::gemini::GeminiClient;
+    use crate::testing::test_state;
+    use http_body_util::BodyExt;
+    use std::sync::Arc;
+
+    #[tokio::test]
+    async fn test_chat_completions_fall_back_between_providers() {
+        use crate::config::FallbackProvider;
+        use tower::ServiceExt;
+
+        // Vertex and the default Gemini catalog both list gemini-1.5-flash
+        let models = axum::Router::new().route(
+            "/models.json",
+            axum::routing::get(|| async { r#"{"vertex_models": ["gemini-1.5-flash"], "vertex_express_models": []}"# }),
+        );
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}/models.json", listener.local_addr().unwrap());
+        tokio::spawn(async move { axum::serve(listener, models).await.unwrap() });
+
+        // No Gemini keys and no Vertex client, so every provider fails; the error shows which one was tried
+        let chat = |fallback_provider: FallbackProvider, uri: &'static str, model: &'static str| {
+            let url = url.clone();
+            async move {
+                let settings = Arc::new(Settings {
+                    enable_vertex: true,
+                    vertex_models_config_url: url,
+                    fallback_provider,
+                    ..Default::default()
+                });
+                let mut state = test_state();
+                state.vertex_enabled = true;
+                state.settings = settings.clone().into();
+                state.gemini_client = Arc::new(GeminiClient::new(settings));
+                state.gemini_client.load_default_models().await;
+                let stats = state.stats_manager.clone();
+                let app = crate::build_app(state).await.unwrap();
+
+                let body = format!(r#"{{"model": "{}", "messages": [{{"role": "user", "content": "Hi"}}]}}"#, model);
+                let request = hyper::Request::builder()
+                    .method("POST")
+                    .uri(uri)
+                    .header("authorization", "Bearer 123")
+                    .header("content-type", "application/json")
+                    .body(axum::body::Body::from(body))
+                    .unwrap();
+                let response = app.oneshot(request).await.unwrap();
+                let status = response.status().as_u16();
+                let body = response.into_body().collect().await.unwrap().to_bytes();
+                let json: serde_json::Value = serde_json::from_slice(&body).unwrap();
+                (status, json["error"]["message"].as_str().unwrap_or_default().to_string(), stats)
+            }
+        };
+
+        // Without a single Gemini key, Vertex serves the request even with no fallback configured
+        let (status, message, stats) = chat(FallbackProvider::None, "/v1/chat/completions", "gemini-1.5-flash").await;
+        assert_eq!(status, 503);
+        assert!(message.contains("Vertex AI client not initialized"));
+        let calls = stats.get_recent_calls(10).await;
+        assert_eq!(calls.len(), 1);
+        assert_eq!(calls[0].fallback_provider, None);
+
+        let (status, message, stats) = chat(FallbackProvider::Vertex, "/v1/chat/completions", "gemini-1.5-flash").await;
+        assert_eq!(status, 503);
+        assert!(message.contains("Vertex AI client not initialized"));
+        let calls = stats.get_recent_calls(10).await;
+        assert_eq!(calls.len(), 1);
+        assert_eq!(calls[0].model, "gemini-1.5-flash");
+        assert_eq!(calls[0].fallback_provider.as_deref(), Some("vertex"));
+        assert_eq!(stats.get_stats().await.fallback_requests, 1);
+
+        // Models Vertex does not list are not handed over as a fallback, but with no Gemini key
+        // at all Vertex is the only provider and gets them anyway
+        let (_, message, stats) = chat(FallbackProvider::Vertex, "/v1/chat/completions", "gemini-1.5-pro").await;
+        assert!(message.contains("Vertex AI client not initialized"));
+        assert_eq!(stats.get_recent_calls(10).await[0].fallback_provider, None);
+
+        // Vertex requests only reach the Gemini API with a free key, so the Vertex error stands
+        let (status, message, stats) = chat(FallbackProvider::Gemini, "/vertex/v1/chat/completions", "gemini-1.5-flash").await;
+        assert_eq!(status, 503);
+        assert!(message.contains("Vertex AI client not initialized"));
+        assert!(stats.get_recent_calls(10).await.is_empty());
+    }
+}
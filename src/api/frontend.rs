@@ -0,0 +1,214 @@
+//! The dashboard single-page app. Files are read from `static_dir` when it is set, otherwise
+//! from the copy built into the binary with the `embed_assets` feature, otherwise from `assets/`
+//! in the working directory or next to the executable. Paths the app routes on the client get
+//! `index.html`; unknown paths under the server's own prefixes stay 404s.
+
+use axum::{
+    extract::{Path, State},
+    http::{header, HeaderValue, Method, StatusCode, Uri},
+    response::{IntoResponse, Response},
+    routing::get,
+    Router,
+};
+use std::borrow::Cow;
+use std::path::{Component, PathBuf};
+use std::sync::Arc;
+use tracing::warn;
+
+use crate::config::Settings;
+
+/// Path prefixes served by rujimi itself rather than the app
+const SERVER_PREFIXES: &[&str] = &["/v1", "/api", "/dashboard-api", "/vertex", "/health", "/assets"];
+/// Served when the frontend source has no `index.html`
+const BUILT_IN_INDEX: &str = include_str!("../../assets/index.html");
+const CACHE_IMMUTABLE: &str = "public, max-age=31536000, immutable";
+const CACHE_REVALIDATE: &str = "no-cache";
+
+#[cfg(feature = "embed_assets")]
+#[derive(rust_embed::RustEmbed)]
+#[folder = "assets/"]
+struct EmbeddedAssets;
+
+/// Where the frontend files come from
+#[derive(Debug, Clone)]
+pub enum Frontend {
+    Dir(PathBuf),
+    #[cfg(feature = "embed_assets")]
+    Embedded,
+}
+
+impl Frontend {
+    pub fn from_settings(settings: &Settings) -> Self {
+        if !settings.static_dir.is_empty() {
+            let dir = PathBuf::from(&settings.static_dir);
+            if !dir.join("index.html").is_file() {
+                warn!("No index.html in static_dir {}; serving the built-in page", dir.display());
+            }
+            return Self::Dir(dir);
+        }
+        #[cfg(feature = "embed_assets")]
+        {
+            Self::Embedded
+        }
+        #[cfg(not(feature = "embed_assets"))]
+        {
+            Self::Dir(default_dir())
+        }
+    }
+
+    async fn read(&self, path: &str) -> Option<Cow<'static, [u8]>> {
+        match self {
+            Self::Dir(dir) => tokio::fs::read(dir.join(path)).await.ok().map(Cow::Owned),
+            #[cfg(feature = "embed_assets")]
+            Self::Embedded => EmbeddedAssets::get(path).map(|file| file.data),
+        }
+    }
+}
+
+/// `assets/` in the working directory, or next to the executable when the binary is started
+/// from elsewhere
+#[cfg(not(feature = "embed_assets"))]
+fn default_dir() -> PathBuf {
+    let local = PathBuf::from("assets");
+    if local.join("index.html").is_file() {
+        return local;
+    }
+    std::env::current_exe()
+        .ok()
+        .and_then(|exe| exe.parent().map(|dir| dir.join("assets")))
+        .filter(|dir| dir.join("index.html").is_file())
+        .unwrap_or(local)
+}
+
+/// `/`, `/dashboard`, `/assets/*` and the SPA fallback for every other unmatched path
+pub fn create_frontend_routes<S>(frontend: Frontend) -> Router<S> {
+    Router::new()
+        .route("/", get(serve_index))
+        .route("/dashboard", get(serve_index))
+        .route("/assets/*path", get(serve_asset))
+        .fallback(spa_fallback)
+        .with_state(Arc::new(frontend))
+}
+
+async fn serve_index(State(frontend): State<Arc<Frontend>>) -> Response {
+    let body = match frontend.read("index.html").await {
+        Some(body) => body,
+        None => Cow::Borrowed(BUILT_IN_INDEX.as_bytes()),
+    };
+    file_response("index.html", body, CACHE_REVALIDATE)
+}
+
+async fn serve_asset(State(frontend): State<Arc<Frontend>>, Path(path): Path<String>) -> Response {
+    if !is_safe_path(&path) {
+        return StatusCode::NOT_FOUND.into_response();
+    }
+    match frontend.read(&path).await {
+        Some(body) => {
+            let cache_control = if is_hashed_name(&path) { CACHE_IMMUTABLE } else { CACHE_REVALIDATE };
+            file_response(&path, body, cache_control)
+        }
+        None => StatusCode::NOT_FOUND.into_response(),
+    }
+}
+
+async fn spa_fallback(State(frontend): State<Arc<Frontend>>, method: Method, uri: Uri) -> Response {
+    if is_client_route(&method, uri.path()) {
+        serve_index(State(frontend)).await
+    } else {
+        StatusCode::NOT_FOUND.into_response()
+    }
+}
+
+fn file_response(path: &str, body: Cow<'static, [u8]>, cache_control: &'static str) -> Response {
+    let content_type = mime_guess::from_path(path).first_or_octet_stream();
+    let mut response = body.into_owned().into_response();
+    let headers = response.headers_mut();
+    if let Ok(value) = HeaderValue::from_str(content_type.as_ref()) {
+        headers.insert(header::CONTENT_TYPE, value);
+    }
+    headers.insert(header::CACHE_CONTROL, HeaderValue::from_static(cache_control));
+    response
+}
+
+/// A page load the app routes itself: a GET outside the server prefixes for a path that
+/// does not name a file
+fn is_client_route(method: &Method, path: &str) -> bool {
+    if method != Method::GET && method != Method::HEAD {
+        return false;
+    }
+    let under_server_prefix = SERVER_PREFIXES
+        .iter()
+        .any(|prefix| path == *prefix || path.starts_with(&format!("{}/", prefix)));
+    let last_segment = path.rsplit('/').next().unwrap_or_default();
+    !under_server_prefix && !last_segment.contains('.')
+}
+
+/// Relative and free of `..`, so it cannot leave the frontend directory
+fn is_safe_path(path: &str) -> bool {
+    !path.is_empty()
+        && std::path::Path::new(path)
+            .components()
+            .all(|component| matches!(component, Component::Normal(_)))
+}
+
+/// Bundler output such as `index-B3xK9a1Q.js` or `chunk.5f3a2b1c.css`, whose name changes
+/// with its content and can therefore be cached forever
+fn is_hashed_name(path: &str) -> bool {
+    let name = path.rsplit('/').next().unwrap_or(path);
+    let stem = match name.rsplit_once('.') {
+        Some((stem, _extension)) => stem,
+        None => return false,
+    };
+    let hash = stem.rsplit(['-', '.']).next().unwrap_or_default();
+    hash.len() >= 8
+        && hash.chars().all(|c| c.is_ascii_alphanumeric() || c == '_')
+        && hash.chars().any(|c| c.is_ascii_digit() || c.is_ascii_uppercase())
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::config::Settings;
+    use crate::testing::test_state;
+    use http_body_util::BodyExt;
+
+    #[tokio::test]
+    async fn test_frontend_static_dir_and_spa_fallback() {
+        use tower::ServiceExt;
+
+        let dir = tempfile::tempdir().unwrap();
+        std::fs::write(dir.path().join("index.html"), "<html>custom</html>").unwrap();
+        std::fs::write(dir.path().join("app-B3xK9a1Q.js"), "console.log(1)").unwrap();
+        std::fs::write(dir.path().join("logo.svg"), "<svg/>").unwrap();
+        let mut state = test_state();
+        state.settings = Settings { static_dir: dir.path().display().to_string(), ..Settings::default() }.into();
+        let app = crate::build_app(state).await.unwrap();
+        let get = |uri: &str| hyper::Request::builder().uri(uri).body(axum::body::Body::empty()).unwrap();
+        let header = |response: &axum::response::Response, name: &str| {
+            response.headers().get(name).map(|value| value.to_str().unwrap().to_string())
+        };
+
+        // Client-side routes get the app's index.html
+        for uri in ["/", "/dashboard", "/dashboard/keys"] {
+            let response = app.clone().oneshot(get(uri)).await.unwrap();
+            assert_eq!(response.status(), 200, "{}", uri);
+            assert_eq!(header(&response, "content-type").as_deref(), Some("text/html"));
+            assert_eq!(header(&response, "cache-control").as_deref(), Some("no-cache"));
+            let body = response.into_body().collect().await.unwrap().to_bytes();
+            assert_eq!(&body[..], b"<html>custom</html>");
+        }
+
+        let response = app.clone().oneshot(get("/assets/app-B3xK9a1Q.js")).await.unwrap();
+        assert_eq!(response.status(), 200);
+        assert!(header(&response, "content-type").unwrap().contains("javascript"));
+        assert_eq!(header(&response, "cache-control").as_deref(), Some("public, max-age=31536000, immutable"));
+
+        let response = app.clone().oneshot(get("/assets/logo.svg")).await.unwrap();
+        assert_eq!(header(&response, "content-type").as_deref(), Some("image/svg+xml"));
+        assert_eq!(header(&response, "cache-control").as_deref(), Some("no-cache"));
+
+        // API paths, missing assets and files outside the directory stay 404s
+        for uri in ["/v1/no/such/route", "/dashboard-api/nothing", "/assets/missing.js", "/assets/../Cargo.toml", "/favicon.ico"] {
+            assert_eq!(app.clone().oneshot(get(uri)).await.unwrap().status(), 404, "{}", uri);
+        }
+    }
+}
@@ -1,3 +1,7 @@
+pub mod audit;
 pub mod auth;
 pub mod dashboard;
+pub mod fallback;
+pub mod frontend;
+pub mod pipeline;
 pub mod routes;
\ No newline at end of file
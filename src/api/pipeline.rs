@@ -0,0 +1,1416 @@
+//! The chat completion pipeline behind `/v1/chat/completions`, `/v1/completions` and
+//! `/v1/responses`. Each stage takes the `ChatContext` built so far and either hands it on or
+//! answers the request itself; `run` chains them as authorize → limit → validate →
+//! cache_lookup → trim_context → wait_turn → acquire_key → dispatch, and every dispatch path ends in
+//! `record` and `cache_store`.
+
+use axum::{
+    body::Body,
+    extract::ConnectInfo,
+    http::{header, HeaderMap, HeaderValue, StatusCode},
+    response::{sse::Event, IntoResponse, Json, Response, Sse},
+};
+use anyhow::Error as AnyhowError;
+use futures_util::{stream, StreamExt};
+use std::net::SocketAddr;
+use std::time::{Duration, Instant};
+use tracing::{debug, error, info, warn};
+
+use crate::models::schemas::{ChatCompletionChunk, ChatCompletionRequest, ChatCompletionResponse, RequestOverrides, StreamOptions, Usage};
+use crate::models::variant::ModelVariant;
+use crate::services::gemini::{dropped_params, GeminiClientTrait};
+use crate::services::gemini_stream::usage_chunk;
+use crate::services::openai::OpenAIApiError;
+use crate::services::openai_compat::unsupported_param;
+use crate::utils::{
+    auth::{authenticate_request, AuthQuery},
+    browser::{forwarded_header_names, forwarded_headers},
+    cache::{generate_cache_key, replay_chunks, StreamAssembler},
+    circuit_breaker::CircuitOpen,
+    error_handling::{upstream_error_type, GeminiError, UpstreamTimeoutError},
+    fair_queue::{QueueFull, Turn},
+    hedge,
+    logging::log,
+    request_overrides::{conversation_session, override_log_fields, parse_request_overrides},
+    response::{create_error_json, create_error_response, create_param_error_response, sse_response, with_first_item_deadline, with_heartbeat},
+    stats::{end_user_id, StreamTimer},
+    AuthResult, CallOrigin,
+};
+use crate::AppState;
+
+use super::fallback;
+use super::routes::{call_origin, check_rate_limits, client_ip};
+
+/// Lists request fields the Gemini conversion ignored, when `debug_headers` is on
+const DROPPED_PARAMS_HEADER: &str = "x-dropped-params";
+
+/// Number of messages left out to fit the model's context window
+const CONTEXT_TRIMMED_HEADER: &str = "x-rujimi-context-trimmed";
+
+/// Outcome of a stage: what the next stage works with, or `Err` with the answer when the
+/// stage settled the request itself
+pub type Stage<T> = Result<T, Response>;
+
+/// A chat request on its way through the pipeline
+pub struct ChatContext {
+    pub request: ChatCompletionRequest,
+    pub auth: AuthResult,
+    pub origin: CallOrigin,
+    pub start_time: Instant,
+    /// Set by `request_deadline_secs`
+    pub deadline: Option<Instant>,
+    /// Served by Gemini's OpenAI-compatible endpoint; decided by `validate`
+    pub native: bool,
+}
+
+#[derive(Clone)]
+pub struct ChatPipeline {
+    state: AppState,
+    headers: HeaderMap,
+}
+
+impl ChatPipeline {
+    pub fn new(state: AppState, headers: HeaderMap) -> Self {
+        Self { state, headers }
+    }
+
+    /// Answer a chat request, within `request_deadline_secs` when one is set
+    pub async fn run(&self, connect_info: Option<ConnectInfo<SocketAddr>>, query: AuthQuery, request: ChatCompletionRequest) -> Response {
+        let start_time = Instant::now();
+        let auth = self.authenticate(&query);
+        let deadline_secs = self.state.settings.load().request_deadline_secs;
+        if deadline_secs == 0 {
+            return self.complete(connect_info, auth, request, start_time, None).await;
+        }
+
+        // Key acquisition, retries and the upstream call all count against the deadline;
+        // dropping the pipeline on expiry cancels whatever it was waiting on
+        let deadline = start_time + Duration::from_secs(deadline_secs);
+        let model = request.model.clone();
+        let origin = call_origin(client_ip(&self.state, &self.headers, connect_info), &auth);
+        let handled = self.complete(connect_info, auth, request, start_time, Some(deadline));
+        match tokio::time::timeout_at(deadline.into(), handled).await {
+            Ok(response) => response,
+            Err(_) => deadline_exceeded(&self.state, model, origin, start_time).await.into_response(),
+        }
+    }
+
+    /// Who the caller is, from its headers and query
+    pub fn authenticate(&self, query: &AuthQuery) -> AuthResult {
+        authenticate_request(&self.headers, query, &self.state.auth_state)
+    }
+
+    async fn complete(
+        &self,
+        connect_info: Option<ConnectInfo<SocketAddr>>,
+        auth: AuthResult,
+        request: ChatCompletionRequest,
+        start_time: Instant,
+        deadline: Option<Instant>,
+    ) -> Response {
+        let admitted = async {
+            let mut context = self.authorize(auth, request, connect_info, start_time, deadline).await?;
+            self.limit(&mut context).await?;
+            self.validate(&mut context).await?;
+            self.cache_lookup(&context).await?;
+            self.trim_context(&mut context).await?;
+            let turn = self.wait_turn(&context).await?;
+            let api_key = self.acquire_key(&context).await?;
+            Ok::<_, Response>((context, api_key, turn))
+        };
+        match admitted.await {
+            Ok((context, api_key, turn)) => {
+                let streaming = context.request.stream;
+                let response = self.dispatch(context, api_key).await;
+                match turn {
+                    // A streamed answer is still being produced after its headers go out
+                    Some(turn) if streaming => hold_turn(response, turn),
+                    _ => response,
+                }
+            }
+            Err(response) => response,
+        }
+    }
+
+    /// Admit the authenticated caller, check its user agent and work out who to attribute the
+    /// call to. Public mode admits anonymous callers, restricted later by `limit`.
+    pub async fn authorize(
+        &self,
+        auth: AuthResult,
+        mut request: ChatCompletionRequest,
+        connect_info: Option<ConnectInfo<SocketAddr>>,
+        start_time: Instant,
+        deadline: Option<Instant>,
+    ) -> Stage<ChatContext> {
+        if !auth.authenticated && !auth.is_public() {
+            return Err(create_error_response("Unauthorized", "authentication_error"));
+        }
+
+        let user_agent = self.headers.get("user-agent").and_then(|ua| ua.to_str().ok());
+        if !self.state.auth_state.model_filter.allows_user_agent(user_agent) {
+            return Err(create_error_response("Forbidden user agent", "forbidden_error"));
+        }
+
+        let mut origin = call_origin(client_ip(&self.state, &self.headers, connect_info), &auth);
+        origin.end_user = request.user.as_deref().map(end_user_id);
+        request.forwarded_headers = forwarded_headers(&self.headers, &self.state.settings.load().forward_request_headers);
+        origin.forwarded_headers = forwarded_header_names(&request.forwarded_headers);
+        if auth.is_public() && origin.ip_address.is_none() {
+            // Anonymous callers are limited per IP, so those without one share a limit
+            origin.ip_address = Some("unknown".to_string());
+        }
+
+        Ok(ChatContext { request, auth, origin, start_time, deadline, native: false })
+    }
+
+    /// The public-mode profile for anonymous callers, then the per-IP and per-client-key quotas
+    pub async fn limit(&self, context: &mut ChatContext) -> Stage<()> {
+        if context.auth.is_public() {
+            apply_public_restrictions(&self.state, &context.origin, &mut context.request).await?;
+        }
+        check_rate_limits(&self.state, &context.origin.ip_address, context.auth.client_key.as_ref())
+            .await
+            .map_err(IntoResponse::into_response)
+    }
+
+    /// Check the model against the filters, apply the `X-Rujimi-*` overrides and, in strict
+    /// mode, refuse fields the conversion would drop
+    pub async fn validate(&self, context: &mut ChatContext) -> Stage<()> {
+        let request = &mut context.request;
+        if !self.state.auth_state.model_filter.allows_model(&request.model) {
+            return Err(create_error_response("Model not allowed", "invalid_model"));
+        }
+        if context.auth.client_key.as_ref().is_some_and(|key| !key.allows_model(&request.model)) {
+            return Err(create_error_response("Model not allowed for this client key", "invalid_model"));
+        }
+
+        // rujimi's own body field, never sent upstream
+        if let Some(array_content) = request.extra.remove("array_content") {
+            request.array_content = request.array_content.or(array_content.as_bool());
+        }
+
+        // X-Rujimi-* overrides, checked against the caller's scope
+        request.overrides = match parse_request_overrides(&self.headers, &context.auth.scope, &self.state.settings.load()) {
+            Ok(overrides) => overrides,
+            Err(e) => return Err(create_error_response(&e.to_string(), e.error_type())),
+        };
+        if request.overrides != RequestOverrides::default() {
+            let mut extra = override_log_fields(&request.overrides);
+            extra.insert("model".to_string(), serde_json::json!(request.model));
+            extra.insert("client_id".to_string(), serde_json::json!(context.auth.client_id));
+            log("info", "Applying request override headers", Some(extra));
+        }
+        // Public answers are cut to `public_max_tokens`, which the cache key does not cover
+        if context.auth.is_public() {
+            request.overrides.no_cache = true;
+        }
+
+        // Strict mode refuses what the conversion would drop; Gemini's OpenAI-compatible endpoint
+        // gets the fields as they are and judges them itself
+        let settings = self.state.settings.load();
+        context.native = settings.uses_native_openai_endpoint(&request.model) && ModelVariant::parse(&request.model).is_plain();
+        if settings.strict_openai_compat && !context.native {
+            if let Some(unsupported) = unsupported_param(request) {
+                return Err(create_param_error_response(&unsupported.message, &unsupported.param));
+            }
+        }
+        Ok(())
+    }
+
+    /// Answer from the cache, recording the hit. Streamed and buffered answers share the
+    /// cache, so either kind of request can be served from it; answers with array content
+    /// are neither cached nor served from it.
+    pub async fn cache_lookup(&self, context: &ChatContext) -> Stage<()> {
+        let request = &context.request;
+        if request.overrides.no_cache || self.array_content(request) {
+            return Ok(());
+        }
+        let cache_key = chat_cache_key(&self.state, request);
+        let cached_response = match self.state.cache_manager.get(&cache_key).await {
+            Some(cached_response) => cached_response,
+            None => return Ok(()),
+        };
+        debug!("Returning cached response for key: {}", cache_key);
+
+        self.record(request.model.clone(), Ok(cached_response.usage.as_ref()), context.origin.clone(), context.start_time).await;
+        if request.stream {
+            return Err(self.replay_cached_stream(request, cached_response));
+        }
+        Err(Json(cached_response).into_response())
+    }
+
+    /// Leave the oldest messages out of a conversation too long for the model, or refuse it,
+    /// per `context_trim_strategy`. Gemini's OpenAI-compatible endpoint gets it untrimmed.
+    pub async fn trim_context(&self, context: &mut ChatContext) -> Stage<()> {
+        if context.native {
+            return Ok(());
+        }
+        let request = &mut context.request;
+        request.context_trimmed = match self.state.gemini_client.context_trim(request).await {
+            Ok(count) => count,
+            Err(e) => return Err(e.into_response()),
+        };
+        if request.context_trimmed > 0 {
+            info!(
+                "Left out the {} oldest of {} messages for {} to fit its context window",
+                request.context_trimmed,
+                request.messages.len(),
+                request.model
+            );
+        }
+        Ok(())
+    }
+
+    /// Wait in the caller's queue under `fair_queuing`. Callers are told apart by client key,
+    /// else by the client IP `resolve_client_ip` settles on, so forwarding headers only pick
+    /// the queue when a trusted proxy sent them; a caller with too many requests already
+    /// waiting is refused.
+    pub async fn wait_turn(&self, context: &ChatContext) -> Stage<Option<Turn>> {
+        let queue = &self.state.fair_queue;
+        if !queue.enabled() {
+            return Ok(None);
+        }
+        let (caller, weight) = match &context.auth.client_key {
+            Some(client_key) => (format!("client:{}", client_key.name), client_key.weight),
+            None => {
+                let address = context.origin.ip_address.as_deref().or(context.auth.client_id.as_deref()).unwrap_or("unknown");
+                (format!("ip:{}", address), 1)
+            }
+        };
+        match queue.admit(&caller, weight).await {
+            Ok(turn) => Ok(Some(turn)),
+            Err(QueueFull) => {
+                warn!("Fair queue full for {}, refusing the request", caller);
+                Err(StatusCode::TOO_MANY_REQUESTS.into_response())
+            }
+        }
+    }
+
+    /// The key to call with: the one the caller pinned, else the next in rotation. Without a
+    /// key the request goes to Vertex AI when that applies, or waits briefly for a key to
+    /// come off cooldown. A model whose circuit is open is refused before a key is spent.
+    pub async fn acquire_key(&self, context: &ChatContext) -> Stage<String> {
+        let (state, request) = (&self.state, &context.request);
+        if let Err(open) = state.model_circuits.check(&request.model, Instant::now()) {
+            return Err(model_unavailable(&request.model, open));
+        }
+
+        if let Some(index) = request.overrides.key_index {
+            return match state.key_manager.key_at(index).await {
+                Some(key) => Ok(key),
+                None => {
+                    let message = format!("No usable API key at index {}", index);
+                    Err(create_error_response(&message, "invalid_request_error"))
+                }
+            };
+        }
+        if let Some(key) = self.next_key(request).await {
+            return Ok(key);
+        }
+
+        if let Some(response) = fallback::serve_with_vertex(state, request, &context.origin, context.start_time).await {
+            return Err(response);
+        }
+        if let Some(response) = fallback::serve_vertex_only(state, request, &context.origin, context.start_time).await {
+            return Err(response);
+        }
+        // Every key may be cooling down; wait briefly for one rather than invite retries
+        let max_wait = Duration::from_millis(state.settings.load().max_key_wait_ms);
+        match state.key_manager.wait_for_key(max_wait).await {
+            Ok(key) => Ok(key),
+            Err(retry_after) => {
+                error!("No API keys available");
+                let mut response = create_error_response("No API keys available", "service_unavailable");
+                if let Some(retry_after) = retry_after {
+                    let seconds = retry_after.as_secs() + u64::from(retry_after.subsec_nanos() > 0);
+                    response.headers_mut().insert(header::RETRY_AFTER, HeaderValue::from(seconds));
+                }
+                Err(response)
+            }
+        }
+    }
+
+    /// Next key in rotation, or the conversation's pinned key when `sticky_keys` is on
+    async fn next_key(&self, request: &ChatCompletionRequest) -> Option<String> {
+        let session = if self.state.settings.load().sticky_keys {
+            conversation_session(&self.headers, request)
+        } else {
+            None
+        };
+        match session {
+            Some(session) => self.state.key_manager.get_key_for_session(&session).await,
+            None => self.state.key_manager.get_next_key().await,
+        }
+    }
+
+    /// Send the request with `api_key`: to Gemini's OpenAI-compatible endpoint when enabled
+    /// for the model, otherwise converted to Gemini's own format
+    pub async fn dispatch(&self, context: ChatContext, api_key: String) -> Response {
+        // The OpenAI-compatible endpoint knows no suffixes
+        if context.native {
+            if let Some(response) = self.dispatch_native(&context, &api_key).await {
+                return response;
+            }
+        }
+
+        // Fields the conversion ignores are logged once, and reported back when debug headers are on
+        let dropped = dropped_params(&context.request);
+        if !dropped.is_empty() {
+            debug!("Dropping request fields unsupported for {}: {}", context.request.model, dropped.join(", "));
+        }
+
+        let trimmed = context.request.context_trimmed;
+        let mut response = self.dispatch_converted(context, api_key).await;
+        if trimmed > 0 {
+            response.headers_mut().insert(CONTEXT_TRIMMED_HEADER, HeaderValue::from(trimmed));
+        }
+        if self.state.settings.load().debug_headers && !dropped.is_empty() {
+            if let Ok(value) = HeaderValue::from_str(&dropped.join(", ")) {
+                response.headers_mut().insert(DROPPED_PARAMS_HEADER, value);
+            }
+        }
+        response
+    }
+
+    /// Send the request converted to Gemini's format, streamed for real, faked from a buffered
+    /// answer, or buffered
+    pub async fn dispatch_converted(&self, context: ChatContext, api_key: String) -> Response {
+        let ChatContext { request, origin, start_time, deadline, .. } = context;
+        if !request.stream {
+            self.non_streaming(request, api_key, origin, start_time).await
+        } else if self.state.settings.load().fake_streaming {
+            self.fake_streaming(request, api_key, origin, start_time, deadline)
+        } else {
+            self.real_streaming(request, api_key, origin, start_time, deadline).await
+        }
+    }
+
+    /// Record a call in the stats: its usage when it was answered, its error type when not
+    pub async fn record(&self, model: String, outcome: Result<Option<&Usage>, &str>, origin: CallOrigin, start_time: Instant) {
+        let response_time_ms = start_time.elapsed().as_millis() as u64;
+        match outcome {
+            Ok(usage) => self.state.stats_manager.record_api_usage(model, usage, response_time_ms, origin).await,
+            Err(error_type) => self.state.stats_manager.record_api_error(model, error_type, response_time_ms, origin).await,
+        }
+    }
+
+    /// Cache a completed answer unless the caller opted out; the cache itself skips answers not worth replaying
+    pub async fn cache_store(&self, request: &ChatCompletionRequest, response: &ChatCompletionResponse) {
+        if !request.overrides.no_cache && !self.array_content(request) {
+            let ttl = request.overrides.cache_ttl.map(Duration::from_secs);
+            self.state.cache_manager.put_with_ttl(chat_cache_key(&self.state, request), response.clone(), ttl).await;
+        }
+    }
+
+    /// Whether a non-streaming answer to `request` carries its content as an array of parts
+    fn array_content(&self, request: &ChatCompletionRequest) -> bool {
+        request.wants_array_content(self.state.settings.load().array_content_responses)
+    }
+
+    /// Replay a cached answer to a streaming request, paced like fake streaming
+    fn replay_cached_stream(&self, request: &ChatCompletionRequest, response: ChatCompletionResponse) -> Response {
+        let settings = self.state.settings.load();
+        let chunk_size = settings.fake_streaming_chunk_size.max(1) as usize;
+        let delay = Duration::from_secs_f64(settings.fake_streaming_delay_per_chunk.max(0.0));
+        let mut chunks = replay_chunks(&response, chunk_size);
+        if request.include_usage() {
+            let usage = response.usage.clone().unwrap_or(Usage::new(0, 0));
+            chunks.push(usage_chunk(&response.id, &response.model, response.created, usage));
+        }
+
+        let stream = stream::iter(chunks.into_iter().enumerate()).then(move |(index, chunk)| async move {
+            if index > 0 && !delay.is_zero() {
+                tokio::time::sleep(delay).await;
+            }
+            Ok::<Event, AnyhowError>(Event::default().data(serde_json::to_string(&chunk).unwrap_or_default()))
+        });
+        heartbeat_sse_response(&self.state, stream)
+    }
+
+    /// Make a buffered call and send the answer as a single chunk once it is complete
+    fn fake_streaming(
+        &self,
+        request: ChatCompletionRequest,
+        api_key: String,
+        origin: CallOrigin,
+        start_time: Instant,
+        deadline: Option<Instant>,
+    ) -> Response {
+        let model = request.model.clone();
+        let stream = stream::once({
+            let (pipeline, origin, model) = (self.clone(), origin.clone(), model.clone());
+            async move { pipeline.fake_streaming_events(request, api_key, origin, start_time, model).await }
+        })
+        .flat_map(|events| stream::iter(events.into_iter().map(Ok::<Event, AnyhowError>)));
+        let stream = first_event_deadline(stream, deadline, &self.state, &model, &origin, start_time);
+
+        heartbeat_sse_response(&self.state, stream)
+    }
+
+    async fn fake_streaming_events(
+        &self,
+        request: ChatCompletionRequest,
+        api_key: String,
+        origin: CallOrigin,
+        start_time: Instant,
+        model: String,
+    ) -> Vec<Event> {
+        let state = &self.state;
+        let gemini_client = &state.gemini_client;
+        let completion = state.key_manager
+            .with_key_retry(api_key, key_attempts(state, &request), |key| {
+                let request = request.clone();
+                async move { gemini_client.chat_completion(request, &key).await.map(|response| (response, key)) }
+            })
+            .await;
+        match completion {
+            Ok((response, api_key)) => {
+                self.record(model.clone(), Ok(response.usage.as_ref()), origin, start_time).await;
+                state.model_circuits.record_success(&model, Instant::now());
+                self.cache_store(&request, &response).await;
+
+                // Convert to streaming format and return final chunk
+                let chunk_data = serde_json::to_string(&response).unwrap_or_default();
+                let mut events = vec![Event::default().data(chunk_data)];
+
+                if request.include_usage() {
+                    let usage = gemini_client.usage_for_response(&request, &response, &api_key).await
+                        .unwrap_or_else(|e| {
+                            warn!("Failed to count tokens for usage chunk: {}", e);
+                            Usage::new(0, 0)
+                        });
+                    let chunk = usage_chunk(&response.id, &response.model, response.created, usage);
+                    events.push(Event::default().data(serde_json::to_string(&chunk).unwrap_or_default()));
+                }
+                events
+            }
+            Err(e) => {
+                error!("Fake streaming request failed: {}", e);
+                state.model_circuits.record_error(&model, &e, Instant::now());
+
+                if e.is_quota() {
+                    if let Some(result) = fallback::vertex_completion(state, &request, &origin, start_time).await {
+                        let data = match result {
+                            Ok(response) => response,
+                            Err(e) => create_error_json(&e.to_string(), upstream_error_type(&e)),
+                        };
+                        return vec![Event::default().data(serde_json::to_string(&data).unwrap_or_default())];
+                    }
+                }
+
+                self.record(model, Err(e.error_type()), origin, start_time).await;
+                vec![Event::default().data(serde_json::to_string(&e.error_json()).unwrap_or_default())]
+            }
+        }
+    }
+
+    async fn real_streaming(
+        &self,
+        request: ChatCompletionRequest,
+        api_key: String,
+        origin: CallOrigin,
+        start_time: Instant,
+        deadline: Option<Instant>,
+    ) -> Response {
+        let state = &self.state;
+        // Usage is always requested so the call is recorded with its tokens; the usage chunk is
+        // only passed on when the client asked for it
+        let include_usage = request.include_usage();
+        let mut upstream_request = request.clone();
+        upstream_request.stream_options = Some(StreamOptions { include_usage: true });
+
+        let timer = StreamTimer::start();
+        let client = &state.gemini_client;
+        let opened = state.key_manager
+            .with_key_retry(api_key, key_attempts(state, &request), |key| {
+                let upstream_request = upstream_request.clone();
+                async move { client.chat_completion_stream(upstream_request, &key).await }
+            })
+            .await;
+        match opened {
+            Ok(gemini_stream) => {
+                let cache = (!request.overrides.no_cache).then(|| {
+                    let ttl = request.overrides.cache_ttl.map(Duration::from_secs);
+                    (chat_cache_key(state, &request), ttl, StreamAssembler::new())
+                });
+                let progress = StreamProgress {
+                    state: state.clone(),
+                    model: request.model.clone(),
+                    origin: origin.clone(),
+                    start_time,
+                    timer,
+                    include_usage,
+                    usage: None,
+                    error_type: None,
+                    cache,
+                };
+                // The call is recorded when the upstream stream ends, not when the client goes away first
+                let stream = stream::unfold((gemini_stream, progress), |(mut chunks, mut progress)| async move {
+                    while let Some(item) = chunks.next().await {
+                        if let Some(event) = progress.event(item) {
+                            return Some((Ok::<Event, AnyhowError>(event), (chunks, progress)));
+                        }
+                    }
+                    progress.finish().await;
+                    None
+                });
+                let stream = first_event_deadline(stream, deadline, state, &request.model, &origin, start_time);
+
+                heartbeat_sse_response(state, stream)
+            }
+            Err(e) => {
+                error!("Failed to start streaming: {}", e);
+                state.model_circuits.record_error(&request.model, &e, Instant::now());
+
+                if e.is_quota() {
+                    if let Some(response) = fallback::serve_with_vertex(state, &request, &origin, start_time).await {
+                        return response;
+                    }
+                }
+
+                self.record(request.model, Err(e.error_type()), origin, start_time).await;
+                e.into_response()
+            }
+        }
+    }
+
+    async fn non_streaming(&self, request: ChatCompletionRequest, api_key: String, mut origin: CallOrigin, start_time: Instant) -> Response {
+        let state = &self.state;
+        let model = request.model.clone();
+
+        // Each attempt records its keys' outcomes, so a hedge cancelled mid-flight counts as neither
+        let max_attempts = key_attempts(state, &request);
+        let attempt = |key: String| {
+            let state = state.clone();
+            let request = request.clone();
+            async move {
+                let client = &state.gemini_client;
+                state.key_manager
+                    .with_key_retry(key, max_attempts, |key| {
+                        let request = request.clone();
+                        async move { client.chat_completion(request, &key).await }
+                    })
+                    .await
+            }
+        };
+        let result = match hedge_delay(state, &request).await {
+            Some(delay) => {
+                let hedge = async {
+                    match state.key_manager.get_next_key().await {
+                        Some(key) if key != api_key => {
+                            debug!("No response within {:?}, hedging {} on another key", delay, model);
+                            attempt(key).await
+                        }
+                        _ => Err(GeminiError::Upstream { status: 503, body: "No other API key to hedge with".to_string() }),
+                    }
+                };
+                let raced = hedge::race(delay, attempt(api_key.clone()), hedge).await;
+                origin.hedged = raced.hedged;
+                raced.result
+            }
+            None => attempt(api_key.clone()).await,
+        };
+
+        match &result {
+            Ok(_) => state.model_circuits.record_success(&model, Instant::now()),
+            Err(e) => state.model_circuits.record_error(&model, e, Instant::now()),
+        }
+
+        match result {
+            Ok(response) => {
+                self.record(model, Ok(response.usage.as_ref()), origin, start_time).await;
+                self.cache_store(&request, &response).await;
+                Json(response).into_response()
+            }
+            Err(e) => {
+                error!("Non-streaming request failed: {}", e);
+
+                if e.is_quota() {
+                    if let Some(response) = fallback::serve_with_vertex(state, &request, &origin, start_time).await {
+                        return response;
+                    }
+                }
+
+                self.record(model, Err(e.error_type()), origin, start_time).await;
+                e.into_response()
+            }
+        }
+    }
+
+    /// Forward the request to Gemini's OpenAI-compatible endpoint. `None` when the endpoint
+    /// rejects it as unsupported, so the converted path can serve it with the same key.
+    async fn dispatch_native(&self, context: &ChatContext, api_key: &str) -> Option<Response> {
+        let (state, request) = (&self.state, &context.request);
+        if request.stream {
+            return match state.openai_client.stream_chat(request.clone(), api_key).await {
+                Ok(chunks) => {
+                    let stream = chunks.map(|chunk_result| {
+                        let data = match chunk_result {
+                            Ok(chunk) => serde_json::to_string(&chunk).unwrap_or_default(),
+                            Err(e) => {
+                                error!("Native streaming chunk error: {}", e);
+                                serde_json::to_string(&create_error_json(&e.to_string(), "stream_error")).unwrap_or_default()
+                            }
+                        };
+                        Ok::<Event, AnyhowError>(Event::default().data(data))
+                    });
+                    Some(heartbeat_sse_response(state, stream))
+                }
+                Err(e) => self.native_request_failed(context, api_key, e, "stream_error").await,
+            };
+        }
+
+        match state.openai_client.chat(request.clone(), api_key).await {
+            Ok(response) => {
+                self.record(request.model.clone(), Ok(response.usage.as_ref()), context.origin.clone(), context.start_time).await;
+                state.key_manager.mark_key_used(api_key, true).await;
+                self.cache_store(request, &response).await;
+                Some(Json(response).into_response())
+            }
+            Err(e) => self.native_request_failed(context, api_key, e, "api_error").await,
+        }
+    }
+
+    /// Record a failed native call; 400/404 replies yield `None` so the converted path is tried instead
+    async fn native_request_failed(
+        &self,
+        context: &ChatContext,
+        api_key: &str,
+        error: Box<dyn std::error::Error + Send + Sync>,
+        error_type: &str,
+    ) -> Option<Response> {
+        if error.downcast_ref::<OpenAIApiError>().is_some_and(|e| e.is_unsupported()) {
+            warn!("OpenAI-compatible endpoint rejected the request, using the converted path: {}", error);
+            return None;
+        }
+
+        error!("Native OpenAI-compatible request failed: {}", error);
+        self.state.key_manager.mark_key_failed(api_key, &error).await;
+        self.record(context.request.model.clone(), Err(error_type), context.origin.clone(), context.start_time).await;
+
+        Some(create_error_response(&error.to_string(), error_type))
+    }
+}
+
+/// Record a request that ran past `request_deadline_secs` as a timed-out call
+async fn deadline_exceeded(state: &AppState, model: String, origin: CallOrigin, start_time: Instant) -> GeminiError {
+    let seconds = state.settings.load().request_deadline_secs;
+    warn!("Request for {} exceeded the {}s request deadline", model, seconds);
+    let error = GeminiError::from(UpstreamTimeoutError { phase: "request deadline", seconds });
+    state.stats_manager.record_api_error(model, error.error_type(), start_time.elapsed().as_millis() as u64, origin).await;
+    error
+}
+
+/// Streamed responses only have to start by the deadline: when no event has arrived by
+/// then, the stream ends with a timeout error instead
+fn first_event_deadline<S>(
+    stream: S,
+    deadline: Option<Instant>,
+    state: &AppState,
+    model: &str,
+    origin: &CallOrigin,
+    start_time: Instant,
+) -> stream::BoxStream<'static, Result<Event, AnyhowError>>
+where
+    S: futures_util::Stream<Item = Result<Event, AnyhowError>> + Send + 'static,
+{
+    let deadline = match deadline {
+        Some(deadline) => deadline,
+        None => return stream.boxed(),
+    };
+    let (state, model, origin) = (state.clone(), model.to_string(), origin.clone());
+    let timed_out = move || async move {
+        let error = deadline_exceeded(&state, model, origin, start_time).await;
+        Ok(Event::default().data(serde_json::to_string(&error.error_json()).unwrap_or_default()))
+    };
+    with_first_item_deadline(Box::pin(stream), deadline, timed_out).boxed()
+}
+
+/// Cache key of a chat request, shared by its streamed and buffered forms. Suffixes change the
+/// answer, so they are part of the key, in canonical order.
+fn chat_cache_key(state: &AppState, request: &ChatCompletionRequest) -> String {
+    let settings = state.settings.load();
+    generate_cache_key(
+        &request.messages.iter().map(|m| serde_json::to_value(m).unwrap()).collect::<Vec<_>>(),
+        &ModelVariant::parse(&request.model).name(),
+        settings.calculate_cache_entries,
+        settings.precise_cache,
+    )
+}
+
+/// 503 `model_unavailable` for a model whose circuit is open, carrying its last error
+fn model_unavailable(model: &str, open: CircuitOpen) -> Response {
+    warn!("Refusing {} while its circuit is open", model);
+    let message = format!("Model {} is temporarily unavailable: {}", model, open.last_error);
+    let mut response = create_error_response(&message, "model_unavailable");
+    let seconds = open.retry_after.as_secs().max(1);
+    response.headers_mut().insert(header::RETRY_AFTER, HeaderValue::from(seconds));
+    response
+}
+
+/// Keys a chat request may be tried on before its error is returned; a key the caller pinned
+/// is never swapped for another
+fn key_attempts(state: &AppState, request: &ChatCompletionRequest) -> usize {
+    if request.overrides.key_index.is_some() {
+        1
+    } else {
+        state.settings.load().max_retry_num.max(1)
+    }
+}
+
+/// Delay after which a non-streaming request is raced on a second key. `None` when hedging
+/// is off, the caller pinned a key, only one key is available, or the request uses tools or
+/// JSON mode, where a duplicate call doubles the cost of a long answer.
+async fn hedge_delay(state: &AppState, request: &ChatCompletionRequest) -> Option<Duration> {
+    let hedge_after_ms = state.settings.load().hedge_after_ms;
+    if hedge_after_ms == 0 || request.overrides.key_index.is_some() || request.uses_tools_or_json() {
+        return None;
+    }
+    if state.key_manager.available_keys_count().await < 2 {
+        return None;
+    }
+    Some(Duration::from_millis(hedge_after_ms))
+}
+
+/// What a real stream has produced so far, turned into its call record and, for a complete
+/// answer, a cache entry once the stream ends
+struct StreamProgress {
+    state: AppState,
+    model: String,
+    origin: CallOrigin,
+    start_time: Instant,
+    timer: StreamTimer,
+    include_usage: bool,
+    usage: Option<Usage>,
+    error_type: Option<&'static str>,
+    /// Cache key, TTL and the answer assembled so far; dropped when the stream fails
+    cache: Option<(String, Option<Duration>, StreamAssembler)>,
+}
+
+impl StreamProgress {
+    /// The event to send for an upstream item, `None` for a usage chunk the client did not ask for
+    fn event(&mut self, item: Result<ChatCompletionChunk, GeminiError>) -> Option<Event> {
+        match item {
+            Ok(chunk) => {
+                self.timer.chunk();
+                if chunk.usage.is_some() {
+                    self.usage = chunk.usage.clone();
+                }
+                if let Some((_, _, assembler)) = &mut self.cache {
+                    assembler.push(&chunk);
+                }
+                if !self.include_usage && chunk.choices.is_empty() && chunk.usage.is_some() {
+                    return None;
+                }
+                Some(Event::default().data(serde_json::to_string(&chunk).unwrap_or_default()))
+            }
+            Err(e) => {
+                error!("Streaming chunk error: {}", e);
+                if self.error_type.is_none() {
+                    self.state.model_circuits.record_error(&self.model, &e, Instant::now());
+                }
+                self.error_type.get_or_insert(e.error_type());
+                // An answer cut short by an error is never cached
+                self.cache = None;
+                Some(Event::default().data(serde_json::to_string(&e.error_json()).unwrap_or_default()))
+            }
+        }
+    }
+
+    async fn finish(self) {
+        if self.error_type.is_none() {
+            self.state.model_circuits.record_success(&self.model, Instant::now());
+        }
+        let timing = self.timer.finish();
+        let response_time_ms = self.start_time.elapsed().as_millis() as u64;
+        self.state.stats_manager
+            .record_stream(self.model, self.usage.as_ref(), self.error_type, response_time_ms, timing, self.origin)
+            .await;
+        if let Some((cache_key, ttl, assembler)) = self.cache {
+            if let Some(response) = assembler.finish() {
+                self.state.cache_manager.put_with_ttl(cache_key, response, ttl).await;
+            }
+        }
+    }
+}
+
+/// Build the SSE response for every streaming path, adding idle heartbeats when enabled
+fn heartbeat_sse_response<S>(state: &AppState, stream: S) -> Response
+where
+    S: futures_util::Stream<Item = Result<Event, AnyhowError>> + Send + 'static,
+{
+    // Counted as an active stream until the body is dropped
+    let open_stream = state.stats_manager.stream_opened();
+    let stream = stream.map(move |event| {
+        let _open = &open_stream;
+        event
+    });
+
+    let heartbeat_interval_secs = state.settings.load().sse_heartbeat_interval_secs;
+    if heartbeat_interval_secs == 0 {
+        return sse_response(Sse::new(stream));
+    }
+
+    let interval = std::time::Duration::from_secs(heartbeat_interval_secs);
+    sse_response(Sse::new(with_heartbeat(Box::pin(stream), interval)))
+}
+
+/// Keep the caller's fair-queue turn until the response body is finished or dropped
+fn hold_turn(response: Response, turn: Turn) -> Response {
+    response.map(|body| {
+        let stream = body.into_data_stream().map(move |chunk| {
+            let _turn = &turn;
+            chunk
+        });
+        Body::from_stream(stream)
+    })
+}
+
+/// Restricted profile for anonymous public-mode callers: allowed models only, a tight
+/// per-IP rate limit, capped `max_tokens` and no tool calling
+async fn apply_public_restrictions(
+    state: &AppState,
+    origin: &CallOrigin,
+    request: &mut ChatCompletionRequest,
+) -> Result<(), Response> {
+    let settings = state.settings.load();
+
+    if !settings.public_allows_model(&request.model) {
+        return Err(create_error_response("Model not available without an API key", "invalid_model"));
+    }
+
+    if request.tools.as_ref().is_some_and(|tools| !tools.is_empty()) {
+        return Err(create_error_response("Tool calling requires an API key", "forbidden_error"));
+    }
+
+    if settings.public_requests_per_minute_per_ip > 0 {
+        if let Some(ip) = &origin.ip_address {
+            let recent = state.stats_manager.get_public_requests_for_ip(ip, Duration::from_secs(60)).await;
+            if recent >= settings.public_requests_per_minute_per_ip {
+                warn!("Public rate limit exceeded for IP: {}", ip);
+                return Err(StatusCode::TOO_MANY_REQUESTS.into_response());
+            }
+        }
+    }
+
+    if settings.public_max_tokens > 0 {
+        let cap = settings.public_max_tokens;
+        request.max_tokens = Some(request.max_tokens.map_or(cap, |max_tokens| max_tokens.min(cap)));
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::Settings;
+    use crate::services::gemini::GeminiClient;
+    use crate::testing::test_state;
+    use crate::utils::{ApiKeyManager, AuthState, ResponseCacheManager};
+    use http_body_util::BodyExt;
+    use std::sync::Arc;
+
+    fn pipeline(state: &AppState, auth: Option<&str>) -> ChatPipeline {
+        let mut headers = axum::http::HeaderMap::new();
+        if let Some(auth) = auth {
+            headers.insert("authorization", auth.parse().unwrap());
+        }
+        ChatPipeline::new(state.clone(), headers)
+    }
+
+    fn chat_request(body: serde_json::Value) -> crate::models::schemas::ChatCompletionRequest {
+        let mut request = serde_json::json!({"model": "gemini-2.0-flash", "messages": [{"role": "user", "content": "hi"}]});
+        request.as_object_mut().unwrap().extend(body.as_object().unwrap().clone());
+        serde_json::from_value(request).unwrap()
+    }
+
+    async fn authorize(stages: &ChatPipeline, request: ChatCompletionRequest, connect_info: Option<ConnectInfo<SocketAddr>>) -> Stage<ChatContext> {
+        let auth = stages.authenticate(&AuthQuery::default());
+        stages.authorize(auth, request, connect_info, std::time::Instant::now(), None).await
+    }
+
+    #[tokio::test]
+    async fn test_pipeline_admission_stages() {
+        let state = test_state();
+        let refused = authorize(&pipeline(&state, None), chat_request(serde_json::json!({})), None).await;
+        assert_eq!(refused.err().unwrap().status(), 401);
+
+        // Anonymous public callers are admitted, attributed to their peer and restricted by `limit`
+        let mut public = test_state();
+        public.settings = Settings {
+            public_mode: true,
+            public_allowed_models: vec!["gemini-2.0-flash".to_string()],
+            public_max_tokens: 100,
+            ..Default::default()
+        }.into();
+        public.auth_state = Arc::new(AuthState::new(public.settings.clone()));
+        let stages = pipeline(&public, None);
+        let request = chat_request(serde_json::json!({"max_tokens": 500}));
+        let mut context = authorize(&stages, request, None).await.unwrap();
+        assert!(context.auth.is_public());
+        assert_eq!(context.origin.ip_address.as_deref(), Some("unknown"));
+        stages.limit(&mut context).await.unwrap();
+        assert_eq!(context.request.max_tokens, Some(100));
+
+        let request = chat_request(serde_json::json!({"model": "gemini-2.5-pro"}));
+        let mut context = authorize(&stages, request, None).await.unwrap();
+        assert!(stages.limit(&mut context).await.is_err());
+
+        // Strict mode refuses what the conversion would drop
+        let mut strict = test_state();
+        strict.settings = Settings { strict_openai_compat: true, ..Default::default() }.into();
+        let request = chat_request(serde_json::json!({"logprobs": true}));
+        let mut context = authorize(&pipeline(&strict, Some("Bearer 123")), request, None).await.unwrap();
+        assert_eq!(pipeline(&strict, Some("Bearer 123")).validate(&mut context).await.err().unwrap().status(), 400);
+        let request = chat_request(serde_json::json!({"logprobs": true}));
+        let mut context = authorize(&pipeline(&state, Some("Bearer 123")), request, None).await.unwrap();
+        pipeline(&state, Some("Bearer 123")).validate(&mut context).await.unwrap();
+        assert!(!context.native);
+    }
+
+    #[tokio::test]
+    async fn test_pipeline_cache_and_key_stages() {
+        use crate::models::schemas::ChatCompletionResponse;
+        use crate::utils::circuit_breaker::CircuitState;
+        use std::time::Instant;
+
+        let state = test_state();
+        let stages = pipeline(&state, Some("Bearer 123"));
+        let admit = |body: serde_json::Value| async {
+            let mut context = authorize(&stages, chat_request(body), None).await.unwrap();
+            stages.validate(&mut context).await.unwrap();
+            context
+        };
+
+        let context = admit(serde_json::json!({})).await;
+        stages.cache_lookup(&context).await.unwrap();
+        let cached: ChatCompletionResponse = serde_json::from_value(serde_json::json!({
+            "id": "chatcmpl-cached", "object": "chat.completion", "created": 0, "model": "gemini-2.0-flash",
+            "choices": [{"index": 0, "message": {"role": "assistant", "content": "cached answer"}, "finish_reason": "stop"}],
+            "usage": {"prompt_tokens": 1, "completion_tokens": 2, "total_tokens": 3}
+        }))
+        .unwrap();
+        stages.cache_store(&context.request, &cached).await;
+
+        // A hit answers the request and is recorded like a call
+        let hit = stages.cache_lookup(&context).await.err().unwrap();
+        assert_eq!(hit.status(), 200);
+        let body = hit.into_body().collect().await.unwrap().to_bytes();
+        let json: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(json["choices"][0]["message"]["content"], "cached answer");
+        let calls = state.stats_manager.get_recent_calls(10).await;
+        assert_eq!((calls.len(), calls[0].completion_tokens), (1, 2));
+
+        let response = stages.acquire_key(&context).await.err().unwrap();
+        assert_eq!(response.status(), 503);
+        let mut pinned = admit(serde_json::json!({})).await;
+        pinned.request.overrides.key_index = Some(3);
+        let response = stages.acquire_key(&pinned).await.err().unwrap();
+        let body = response.into_body().collect().await.unwrap().to_bytes();
+        assert!(String::from_utf8_lossy(&body).contains("No usable API key at index 3"));
+
+        state.model_circuits.set_state("gemini-2.0-flash", CircuitState::Open, Instant::now());
+        let response = stages.acquire_key(&context).await.err().unwrap();
+        assert_eq!(response.status(), 503);
+        assert!(response.headers().contains_key("retry-after"));
+    }
+
+    #[tokio::test]
+    async fn test_fair_queue_callers_keyed_by_trusted_ip() {
+        let mut state = test_state();
+        let settings = Arc::new(Settings {
+            fair_queuing: true,
+            fair_queue_concurrency: 4,
+            trusted_proxies: vec!["10.0.0.1".to_string()],
+            ..Default::default()
+        });
+        state.auth_state = Arc::new(AuthState::new(settings.clone()));
+        state.fair_queue = Arc::new(crate::utils::fair_queue::FairQueue::new(&settings));
+        let caller = |peer: &str, forwarded_for: &str| {
+            let mut headers = axum::http::HeaderMap::new();
+            headers.insert("authorization", "Bearer 123".parse().unwrap());
+            headers.insert("x-forwarded-for", forwarded_for.parse().unwrap());
+            let stages = ChatPipeline::new(state.clone(), headers);
+            let connect_info = Some(ConnectInfo(SocketAddr::new(peer.parse().unwrap(), 40000)));
+            (stages, connect_info)
+        };
+
+        let mut turns = Vec::new();
+        for (peer, forwarded_for) in [("192.0.2.1", "198.51.100.1"), ("192.0.2.1", "198.51.100.2"), ("10.0.0.1", "198.51.100.3")] {
+            let (stages, connect_info) = caller(peer, forwarded_for);
+            let context = authorize(&stages, chat_request(serde_json::json!({})), connect_info).await.unwrap();
+            turns.push(stages.wait_turn(&context).await.unwrap());
+        }
+
+        let mut callers: Vec<(String, usize)> =
+            state.fair_queue.status().callers.into_iter().map(|caller| (caller.caller, caller.in_flight)).collect();
+        callers.sort();
+        assert_eq!(callers, vec![("ip:192.0.2.1".to_string(), 2), ("ip:198.51.100.3".to_string(), 1)]);
+    }
+
+    #[tokio::test]
+    async fn test_fair_queue_turn_lasts_until_the_stream_ends() {
+        use std::time::Duration;
+        use tower::ServiceExt;
+
+        let settings = Arc::new(Settings {
+            mock_upstream: true,
+            mock_latency_ms: 300,
+            fake_streaming: true,
+            fair_queuing: true,
+            fair_queue_concurrency: 1,
+            ..Default::default()
+        });
+        let mut state = test_state();
+        state.settings = settings.clone().into();
+        state.key_manager = Arc::new(ApiKeyManager::new(settings.clone()));
+        state.gemini_client = Arc::new(GeminiClient::new(settings.clone()));
+        state.cache_manager = Arc::new(ResponseCacheManager::new(settings.clone()));
+        state.fair_queue = Arc::new(crate::utils::fair_queue::FairQueue::new(&settings));
+        state.key_manager.initialize().await.unwrap();
+        let app = crate::build_app(state).await.unwrap();
+        let chat = |stream: bool, content: &str| {
+            let body = serde_json::json!({"model": "gemini-2.0-flash", "stream": stream, "messages": [{"role": "user", "content": content}]});
+            hyper::Request::builder()
+                .method("POST")
+                .uri("/v1/chat/completions")
+                .header("authorization", "Bearer 123")
+                .header("content-type", "application/json")
+                .body(axum::body::Body::from(body.to_string()))
+                .unwrap()
+        };
+
+        // The fake stream calls upstream only once its body is read
+        let streamed = app.clone().oneshot(chat(true, "first")).await.unwrap();
+        assert_eq!(streamed.status(), 200);
+        let mut second = tokio::spawn(app.oneshot(chat(false, "second")));
+        assert!(tokio::time::timeout(Duration::from_millis(800), &mut second).await.is_err());
+
+        let body = streamed.into_body().collect().await.unwrap().to_bytes();
+        assert!(String::from_utf8_lossy(&body).contains("Mock reply to: first"));
+        let response = tokio::time::timeout(Duration::from_secs(5), second).await.unwrap().unwrap().unwrap();
+        assert_eq!(response.status(), 200);
+    }
+
+    #[tokio::test]
+    async fn test_mock_upstream_serves_chat_end_to_end() {
+        use tower::ServiceExt;
+
+        let settings = Arc::new(Settings { mock_upstream: true, fake_streaming: false, ..Default::default() });
+        let mut state = test_state();
+        state.settings = settings.clone().into();
+        state.key_manager = Arc::new(ApiKeyManager::new(settings.clone()));
+        state.gemini_client = Arc::new(GeminiClient::new(settings.clone()));
+        state.cache_manager = Arc::new(ResponseCacheManager::new(settings.clone()));
+        state.key_manager.initialize().await.unwrap();
+        let stats = state.stats_manager.clone();
+        let app = crate::build_app(state).await.unwrap();
+        let chat = |stream: bool| {
+            let body = serde_json::json!({"model": "gemini-2.0-flash", "stream": stream, "messages": [{"role": "user", "content": "ping"}]});
+            hyper::Request::builder()
+                .method("POST")
+                .uri("/v1/chat/completions")
+                .header("authorization", "Bearer 123")
+                .header("content-type", "application/json")
+                .body(axum::body::Body::from(body.to_string()))
+                .unwrap()
+        };
+
+        let response = app.clone().oneshot(chat(false)).await.unwrap();
+        assert_eq!(response.status(), 200);
+        let body = response.into_body().collect().await.unwrap().to_bytes();
+        let json: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(json["choices"][0]["message"]["content"], "Mock reply to: ping");
+        assert!(json["usage"]["total_tokens"].as_u64().unwrap() > 0);
+
+        // Streamed for real, a chunk at a time
+        let body = r#"{"model": "gemini-2.0-flash", "stream": true, "messages": [{"role": "user", "content": "ping again"}]}"#;
+        let mut request = chat(true);
+        *request.body_mut() = axum::body::Body::from(body);
+        let response = app.oneshot(request).await.unwrap();
+        assert_eq!(response.headers()["content-type"], "text/event-stream");
+        let body = response.into_body().collect().await.unwrap().to_bytes();
+        let chunks: Vec<serde_json::Value> = String::from_utf8_lossy(&body)
+            .lines()
+            .filter_map(|line| line.strip_prefix("data: "))
+            .filter_map(|data| serde_json::from_str(data).ok())
+            .collect();
+        assert!(chunks.len() > 2);
+        let text: String = chunks.iter().filter_map(|chunk| chunk["choices"][0]["delta"]["content"].as_str()).collect();
+        assert_eq!(text, "Mock reply to: ping again");
+
+        assert_eq!(stats.get_recent_calls(10).await.len(), 2);
+    }
+
+    #[tokio::test]
+    async fn test_open_circuit_refuses_model() {
+        use tower::ServiceExt;
+
+        let app = crate::build_app(test_state()).await.unwrap();
+        let set_circuit = |auth: &str, circuit: &str| {
+            hyper::Request::builder()
+                .method("POST")
+                .uri("/dashboard-api/circuits/gemini-2.0-flash")
+                .header("authorization", auth)
+                .header("content-type", "application/json")
+                .body(axum::body::Body::from(serde_json::json!({"state": circuit}).to_string()))
+                .unwrap()
+        };
+        let chat = || {
+            let body = serde_json::json!({"model": "gemini-2.0-flash", "messages": [{"role": "user", "content": "hi"}]});
+            hyper::Request::builder()
+                .method("POST")
+                .uri("/v1/chat/completions")
+                .header("authorization", "Bearer 123")
+                .header("content-type", "application/json")
+                .body(axum::body::Body::from(body.to_string()))
+                .unwrap()
+        };
+
+        let response = app.clone().oneshot(set_circuit("Bearer wrong", "open")).await.unwrap();
+        assert_eq!(response.status(), 401);
+        let response = app.clone().oneshot(set_circuit("Bearer 123", "open")).await.unwrap();
+        assert_eq!(response.status(), 200);
+
+        let response = app.clone().oneshot(chat()).await.unwrap();
+        assert_eq!(response.status(), 503);
+        assert!(response.headers().contains_key("retry-after"));
+        let body = response.into_body().collect().await.unwrap().to_bytes();
+        let json: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(json["error"]["type"], "model_unavailable");
+
+        let circuits = hyper::Request::builder()
+            .uri("/dashboard-api/circuits")
+            .header("authorization", "Bearer 123")
+            .body(axum::body::Body::empty())
+            .unwrap();
+        let body = app.clone().oneshot(circuits).await.unwrap().into_body().collect().await.unwrap().to_bytes();
+        let json: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(json["circuits"][0]["state"], "open");
+
+        // Closed again, the request goes on to key selection
+        app.clone().oneshot(set_circuit("Bearer 123", "closed")).await.unwrap();
+        let response = app.oneshot(chat()).await.unwrap();
+        let body = response.into_body().collect().await.unwrap().to_bytes();
+        let json: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        assert_ne!(json["error"]["type"], "model_unavailable");
+    }
+
+    #[tokio::test]
+    async fn test_strict_mode_rejects_unsupported_params() {
+        use tower::ServiceExt;
+
+        let chat = |strict: bool| async move {
+            let mut state = test_state();
+            state.settings = Settings { strict_openai_compat: strict, ..Default::default() }.into();
+            let app = crate::build_app(state).await.unwrap();
+            let body = serde_json::json!({
+                "model": "gemini-2.0-flash",
+                "messages": [{"role": "user", "content": "hi"}],
+                "logprobs": true,
+            });
+            let request = hyper::Request::builder()
+                .method("POST")
+                .uri("/v1/chat/completions")
+                .header("authorization", "Bearer 123")
+                .header("content-type", "application/json")
+                .body(axum::body::Body::from(body.to_string()))
+                .unwrap();
+            let response = app.oneshot(request).await.unwrap();
+            let status = response.status();
+            let body = response.into_body().collect().await.unwrap().to_bytes();
+            (status, serde_json::from_slice::<serde_json::Value>(&body).unwrap())
+        };
+
+        let (status, json) = chat(true).await;
+        assert_eq!(status, 400);
+        assert_eq!(json["error"]["type"], "invalid_request_error");
+        assert_eq!(json["error"]["param"], "logprobs");
+
+        // Permissive by default: the field is dropped and the request goes on to key selection
+        let (status, json) = chat(false).await;
+        assert_ne!(status, 400);
+        assert_ne!(json["error"]["param"], "logprobs");
+    }
+
+    #[tokio::test]
+    async fn test_request_override_headers() {
+        use crate::config::ClientKey;
+        use crate::models::schemas::{ChatCompletionResponse, ChatMessage};
+        use crate::utils::logging::LOG_MANAGER;
+        use tower::ServiceExt;
+
+        let messages: Vec<ChatMessage> = serde_json::from_value(serde_json::json!([{"role": "user", "content": "Hi"}])).unwrap();
+        let chat = |allow_safety_override: bool, overrides: &'static [(&'static str, &'static str)]| {
+            let messages = messages.clone();
+            async move {
+                let user_key: ClientKey = serde_json::from_value(serde_json::json!({"key": "user-key", "name": "tester"})).unwrap();
+                let settings = Arc::new(Settings {
+                    allow_safety_override,
+                    client_keys: vec![user_key],
+                    ..Default::default()
+                });
+                let mut state = test_state();
+                state.settings = settings.clone().into();
+                state.auth_state = Arc::new(AuthState::new(settings.clone()));
+                state.cache_manager = Arc::new(ResponseCacheManager::new(settings.clone()));
+
+                // A cached answer for the request, so a cache hit succeeds without any upstream key
+                let cache_key = crate::utils::cache::generate_cache_key(
+                    &messages.iter().map(|m| serde_json::to_value(m).unwrap()).collect::<Vec<_>>(),
+                    "gemini-1.5-flash",
+                    settings.calculate_cache_entries,
+                    settings.precise_cache,
+                );
+                let cached: ChatCompletionResponse = serde_json::from_value(serde_json::json!({
+                    "id": "chatcmpl-cached", "object": "chat.completion", "created": 0, "model": "gemini-1.5-flash",
+                    "choices": [{"index": 0, "message": {"role": "assistant", "content": "cached"}, "finish_reason": "stop"}]
+                }))
+                .unwrap();
+                state.cache_manager.put(cache_key, cached).await;
+
+                let mut request = hyper::Request::builder()
+                    .method("POST")
+                    .uri("/v1/chat/completions")
+                    .header("content-type", "application/json");
+                for (name, value) in overrides {
+                    request = request.header(*name, *value);
+                }
+                if !overrides.iter().any(|(name, _)| *name == "authorization") {
+                    request = request.header("authorization", "Bearer 123");
+                }
+                let body = r#"{"model": "gemini-1.5-flash", "messages": [{"role": "user", "content": "Hi"}]}"#;
+                let app = crate::build_app(state).await.unwrap();
+                let response = app.oneshot(request.body(axum::body::Body::from(body)).unwrap()).await.unwrap();
+                let status = response.status().as_u16();
+                let body = response.into_body().collect().await.unwrap().to_bytes();
+                let json: serde_json::Value = serde_json::from_slice(&body).unwrap();
+                (status, json)
+            }
+        };
+
+        // X-Rujimi-No-Cache skips the cached answer and goes upstream, where no key is configured
+        let (status, json) = chat(false, &[]).await;
+        assert_eq!((status, json["id"].as_str()), (200, Some("chatcmpl-cached")));
+        let (status, json) = chat(false, &[("x-rujimi-no-cache", "1")]).await;
+        assert_eq!((status, json["error"]["message"].as_str()), (503, Some("No API keys available")));
+        let (status, _) = chat(false, &[("x-rujimi-no-cache", "sometimes")]).await;
+        assert_eq!(status, 400);
+
+        let logged = LOG_MANAGER.get_logs().into_iter().any(|entry| {
+            entry.message == "Applying request override headers"
+                && entry.extra.is_some_and(|extra| extra.get("no_cache") == Some(&serde_json::json!(true)))
+        });
+        assert!(logged);
+
+        // X-Rujimi-Key-Index needs the admin scope and a configured key at that index
+        let (status, _) = chat(false, &[("authorization", "Bearer user-key"), ("x-rujimi-key-index", "0")]).await;
+        assert_eq!(status, 403);
+        let (status, json) = chat(false, &[("x-rujimi-key-index", "5"), ("x-rujimi-no-cache", "1")]).await;
+        assert_eq!((status, json["error"]["message"].as_str()), (400, Some("No usable API key at index 5")));
+        let (status, _) = chat(false, &[("x-rujimi-key-index", "first")]).await;
+        assert_eq!(status, 400);
+
+        // X-Rujimi-Safety only when the settings allow it
+        let (status, _) = chat(false, &[("x-rujimi-safety", "off")]).await;
+        assert_eq!(status, 403);
+        let (status, _) = chat(true, &[("x-rujimi-safety", "loose")]).await;
+        assert_eq!(status, 400);
+        let (status, json) = chat(true, &[("x-rujimi-safety", "off"), ("x-rujimi-no-cache", "1")]).await;
+        assert_eq!((status, json["error"]["message"].as_str()), (503, Some("No API keys available")));
+    }
+
+    #[tokio::test]
+    async fn test_cached_answer_replayed_to_streaming_request() {
+        use crate::models::schemas::{ChatCompletionResponse, ChatMessage};
+        use tower::ServiceExt;
+
+        let settings = Arc::new(Settings {
+            fake_streaming_chunk_size: 4,
+            fake_streaming_delay_per_chunk: 0.0,
+            ..Default::default()
+        });
+        let mut state = test_state();
+        state.settings = settings.clone().into();
+        state.cache_manager = Arc::new(ResponseCacheManager::new(settings.clone()));
+
+        let messages: Vec<ChatMessage> = serde_json::from_value(serde_json::json!([{"role": "user", "content": "Hi"}])).unwrap();
+        let cache_key = crate::utils::cache::generate_cache_key(
+            &messages.iter().map(|m| serde_json::to_value(m).unwrap()).collect::<Vec<_>>(),
+            "gemini-1.5-flash",
+            settings.calculate_cache_entries,
+            settings.precise_cache,
+        );
+        let cached: ChatCompletionResponse = serde_json::from_value(serde_json::json!({
+            "id": "chatcmpl-cached", "object": "chat.completion", "created": 0, "model": "gemini-1.5-flash",
+            "choices": [{"index": 0, "message": {"role": "assistant", "content": "cached answer"}, "finish_reason": "stop"}],
+            "usage": {"prompt_tokens": 1, "completion_tokens": 2, "total_tokens": 3}
+        }))
+        .unwrap();
+        state.cache_manager.put(cache_key, cached).await;
+
+        let body = r#"{"model": "gemini-1.5-flash", "stream": true, "stream_options": {"include_usage": true},
+            "messages": [{"role": "user", "content": "Hi"}]}"#;
+        let request = hyper::Request::builder()
+            .method("POST")
+            .uri("/v1/chat/completions")
+            .header("authorization", "Bearer 123")
+            .header("content-type", "application/json")
+            .body(axum::body::Body::from(body))
+            .unwrap();
+        let app = crate::build_app(state).await.unwrap();
+        let response = app.oneshot(request).await.unwrap();
+        assert_eq!(response.headers()["content-type"], "text/event-stream");
+        let body = response.into_body().collect().await.unwrap().to_bytes();
+        let chunks: Vec<serde_json::Value> = String::from_utf8_lossy(&body)
+            .lines()
+            .filter_map(|line| line.strip_prefix("data: "))
+            .filter_map(|data| serde_json::from_str(data).ok())
+            .collect();
+
+        // "cached answer" in pieces of four characters, the finish chunk, then usage
+        assert_eq!(chunks.len(), 6);
+        let text: String = chunks.iter().filter_map(|chunk| chunk["choices"][0]["delta"]["content"].as_str()).collect();
+        assert_eq!(text, "cached answer");
+        assert_eq!(chunks[0]["choices"][0]["delta"]["content"], "cach");
+        assert_eq!(chunks[4]["choices"][0]["finish_reason"], "stop");
+        assert_eq!(chunks[5]["usage"]["total_tokens"], 3);
+    }
+
+    #[tokio::test]
+    async fn test_public_callers_bypass_the_cache() {
+        use crate::models::schemas::ChatCompletionResponse;
+
+        let mut state = test_state();
+        state.settings.store(Arc::new(Settings {
+            public_mode: true,
+            public_allowed_models: vec!["gemini-2.0-flash".to_string()],
+            public_max_tokens: 100,
+            ..Default::default()
+        }));
+        state.cache_manager = Arc::new(ResponseCacheManager::new(state.settings.load()));
+        let cached: ChatCompletionResponse = serde_json::from_value(serde_json::json!({
+            "id": "chatcmpl-cached", "object": "chat.completion", "created": 0, "model": "gemini-2.0-flash",
+            "choices": [{"index": 0, "message": {"role": "assistant", "content": "a long answer"}, "finish_reason": "stop"}],
+            "usage": {"prompt_tokens": 1, "completion_tokens": 500, "total_tokens": 501}
+        }))
+        .unwrap();
+
+        let keyed = pipeline(&state, Some("Bearer 123"));
+        let mut context = authorize(&keyed, chat_request(serde_json::json!({})), None).await.unwrap();
+        keyed.validate(&mut context).await.unwrap();
+        keyed.cache_store(&context.request, &cached).await;
+        assert!(keyed.cache_lookup(&context).await.is_err());
+
+        // Neither served the uncapped answer nor allowed to cache a capped one
+        let public = pipeline(&state, None);
+        let mut context = authorize(&public, chat_request(serde_json::json!({})), None).await.unwrap();
+        public.limit(&mut context).await.unwrap();
+        public.validate(&mut context).await.unwrap();
+        assert!(public.cache_lookup(&context).await.is_ok());
+        state.cache_manager.clear().await;
+        public.cache_store(&context.request, &cached).await;
+        assert!(keyed.cache_lookup(&authorize(&keyed, chat_request(serde_json::json!({})), None).await.unwrap()).await.is_ok());
+    }
+}
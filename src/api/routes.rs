@@ -1,34 +1,49 @@
 use axum::{
-    extract::{Query, State},
-    http::{HeaderMap, StatusCode},
-    response::{IntoResponse, Response, Sse},
+    extract::{ConnectInfo, Multipart, Path, Query, State},
+    http::{HeaderMap, HeaderValue, StatusCode},
+    response::{IntoResponse, Response},
     routing::{get, post},
     Json, Router,
 };
-use axum::response::sse::Event;
-use futures_util::{stream, StreamExt};
+use std::net::{IpAddr, SocketAddr};
 use std::time::Instant;
-use tracing::{debug, error, warn};
-use anyhow::Error as AnyhowError;
+use tracing::{error, warn};
 
 use crate::models::schemas::{
-    ChatCompletionRequest, ModelResponse, Model,
-    EmbeddingRequest, EmbeddingResponse,
+    ChatCompletionRequest, CompletionRequest, ResponsesRequest, ModelResponse, Model,
+    EmbeddingRequest, EmbeddingResponse, ImageGenerationRequest, ImageGenerationResponse,
+    ModerationRequest, ModerationResponse,
+};
+use crate::models::variant::{ModelVariant, Thinking};
+use crate::services::gemini::{GeminiClientTrait, DEFAULT_IMAGE_MODEL};
+use crate::services::moderation::moderation_model;
+use crate::services::transcription::{
+    audio_mime_type, transcription_instruction, transcription_model, verbose_transcription, wav_duration,
+    TranscriptionFormat, MAX_AUDIO_BYTES, SUPPORTED_FORMATS,
 };
-use crate::services::gemini::GeminiClientTrait;
 use crate::utils::{
-    auth::{authenticate_request, AuthQuery, validate_user_agent},
-    cache::generate_cache_key,
-    response::{create_error_response, create_error_json},
+    auth::{authenticate_request, AuthQuery},
+    ip_filter::IpFilter,
+    response::{create_error_response, create_error_json, generate_random_string, text_completion_response, responses_api_response},
 };
+use crate::config::ClientKey;
+use crate::utils::{AuthResult, CallOrigin};
 use crate::AppState;
 
+use super::pipeline::ChatPipeline;
+
 // V1 API Routes (OpenAI compatible)
 pub fn create_v1_routes() -> Router<AppState> {
     Router::new()
         .route("/chat/completions", post(chat_completions))
+        .route("/completions", post(completions))
+        .route("/responses", post(responses))
         .route("/models", get(list_models))
+        .route("/models/:id", get(get_model))
         .route("/embeddings", post(embeddings))
+        .route("/images/generations", post(image_generations))
+        .route("/moderations", post(moderations))
+        .route("/audio/transcriptions", post(audio_transcriptions))
 }
 
 // Legacy API Routes (for backwards compatibility)
@@ -36,391 +51,943 @@ pub fn create_api_routes() -> Router<AppState> {
     Router::new()
         .route("/chat/completions", post(chat_completions))
         .route("/models", get(list_models))
+        .route("/models/:id", get(get_model))
         .route("/embeddings", post(embeddings))
+        .route("/images/generations", post(image_generations))
+        .route("/moderations", post(moderations))
+        .route("/audio/transcriptions", post(audio_transcriptions))
 }
 
 async fn chat_completions(
     State(state): State<AppState>,
+    connect_info: Option<ConnectInfo<SocketAddr>>,
     headers: HeaderMap,
     Query(query): Query<AuthQuery>,
     Json(request): Json<ChatCompletionRequest>,
 ) -> Result<Response, StatusCode> {
-    let start_time = Instant::now();
+    Ok(ChatPipeline::new(state, headers).run(connect_info, query, request).await)
+}
+
+/// Legacy text completions, served by the chat pipeline and reshaped into `text_completion`
+/// objects
+async fn completions(
+    State(state): State<AppState>,
+    connect_info: Option<ConnectInfo<SocketAddr>>,
+    headers: HeaderMap,
+    Query(query): Query<AuthQuery>,
+    Json(request): Json<CompletionRequest>,
+) -> Result<Response, StatusCode> {
+    let response = chat_completions(State(state), connect_info, headers, Query(query), Json(request.into_chat_request())).await?;
+    Ok(text_completion_response(response).await)
+}
+
+/// The basic Responses API: text and image input, served by the chat pipeline and
+/// reshaped into `response` objects
+async fn responses(
+    State(state): State<AppState>,
+    connect_info: Option<ConnectInfo<SocketAddr>>,
+    headers: HeaderMap,
+    Query(query): Query<AuthQuery>,
+    Json(request): Json<ResponsesRequest>,
+) -> Result<Response, StatusCode> {
+    let request = match request.into_chat_request() {
+        Ok(request) => request,
+        Err(message) => return Ok(create_error_response(&message, "invalid_request_error")),
+    };
+    let response = chat_completions(State(state), connect_info, headers, Query(query), Json(request)).await?;
+    Ok(responses_api_response(response).await)
+}
 
+async fn list_models(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    Query(query): Query<AuthQuery>,
+) -> Result<Json<ModelResponse>, StatusCode> {
     // Authenticate request
-    let auth_result = authenticate_request(&headers, &query, &state.settings);
+    let auth_result = authenticate_request(&headers, &query, &state.auth_state);
     if !auth_result.authenticated {
-        return Ok(create_error_response("Unauthorized", "authentication_error"));
+        return Err(StatusCode::UNAUTHORIZED);
     }
 
-    // Validate user agent if configured
-    let user_agent = headers.get("user-agent").and_then(|ua| ua.to_str().ok());
-    if !validate_user_agent(user_agent, &state.settings) {
-        return Ok(create_error_response("Forbidden user agent", "forbidden_error"));
+    Ok(Json(ModelResponse {
+        object: "list".to_string(),
+        data: visible_models(&state, &auth_result).await,
+        refreshed_at: state.gemini_client.models_refreshed_at().await.map(|at| at.timestamp()),
+    }))
+}
+
+async fn get_model(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    Query(query): Query<AuthQuery>,
+    Path(model_id): Path<String>,
+) -> Response {
+    let auth_result = authenticate_request(&headers, &query, &state.auth_state);
+    if !auth_result.authenticated {
+        return create_error_response("Unauthorized", "authentication_error");
+    }
+
+    match visible_models(&state, &auth_result).await.into_iter().find(|model| model.id == model_id) {
+        Some(model) => Json(model).into_response(),
+        None => create_error_response(&format!("The model '{}' does not exist", model_id), "not_found_error"),
     }
+}
 
-    // Get client IP for rate limiting
-    let client_ip = extract_client_ip(&headers);
+/// Models the caller could actually use: the catalog minus anything the model policy or
+/// the caller's client key would reject
+async fn visible_models(state: &AppState, auth_result: &AuthResult) -> Vec<Model> {
+    let mut models = model_catalog(state).await;
+    models.retain(|model| {
+        state.auth_state.model_filter.allows_model(&model.id)
+            && auth_result.client_key.as_ref().is_none_or(|key| key.allows_model(&model.id))
+    });
+    models
+}
 
-    // Check rate limits
-    if let Err(err) = check_rate_limits(&state, &client_ip).await {
-        return Ok(err.into_response());
+/// The upstream catalog plus its suffixed variants and, with Vertex enabled, the Vertex models
+pub(crate) async fn model_catalog(state: &AppState) -> Vec<Model> {
+    let mut models = Vec::new();
+    for model in state.gemini_client.get_model_catalog().await {
+        // Variants share the base model's limits and capabilities
+        let base = ModelVariant::parse(&model.id);
+        let thinks = model.capabilities.iter().any(|capability| capability == "thinking");
+        let mut variants = Vec::new();
+        if thinks {
+            variants.push(ModelVariant { thinking: Thinking::On, ..base.clone() });
+            if ModelVariant::can_disable_thinking(&base.base) {
+                variants.push(ModelVariant { thinking: Thinking::Off, ..base.clone() });
+            }
+        }
+        if state.settings.load().search.search_mode && model.id.starts_with("gemini") {
+            variants.push(ModelVariant { search: true, ..base.clone() });
+        }
+        let variants: Vec<Model> = variants
+            .into_iter()
+            .filter(|variant| variant.base == model.id)
+            .map(|variant| model.variant(&variant.name()[model.id.len()..]))
+            .collect();
+        models.push(model);
+        models.extend(variants);
     }
 
-    // Validate model
-    if !is_model_allowed(&request.model, &state.settings) {
-        return Ok(create_error_response("Model not allowed", "invalid_model"));
+    if state.vertex_enabled {
+        match crate::vertex::routes::models_api::vertex_model_ids(&state.settings.load()).await {
+            Ok(ids) => {
+                let created = chrono::Utc::now().timestamp() as u64;
+                for id in ids {
+                    if !models.iter().any(|model| model.id == id) {
+                        models.push(Model::named(&id, created));
+                    }
+                }
+            }
+            Err(e) => warn!("Failed to list Vertex AI models: {}", e),
+        }
     }
+    models
+}
 
-    // Check cache if not streaming
-    if !request.stream {
-        let cache_key = generate_cache_key(
-            &request.messages.iter().map(|m| serde_json::to_value(m).unwrap()).collect::<Vec<_>>(),
-            &request.model,
-            state.settings.calculate_cache_entries,
-            state.settings.precise_cache,
-        );
+async fn embeddings(
+    State(state): State<AppState>,
+    connect_info: Option<ConnectInfo<SocketAddr>>,
+    headers: HeaderMap,
+    Query(query): Query<AuthQuery>,
+    Json(request): Json<EmbeddingRequest>,
+) -> Result<Json<EmbeddingResponse>, StatusCode> {
+    let start_time = Instant::now();
 
-        if let Some(cached_response) = state.cache_manager.get(&cache_key).await {
-            debug!("Returning cached response for key: {}", cache_key);
+    // Authenticate request
+    let auth_result = authenticate_request(&headers, &query, &state.auth_state);
+    if !auth_result.authenticated {
+        return Err(StatusCode::UNAUTHORIZED);
+    }
 
-            // Record cache hit in stats
-            state.stats_manager.record_api_call(
-                request.model.clone(),
-                cached_response.usage.as_ref().map(|u| u.total_tokens).unwrap_or(0),
-                true,
-                start_time.elapsed().as_millis() as u64,
-                client_ip,
-            ).await;
+    let origin = call_origin(client_ip(&state, &headers, connect_info), &auth_result);
 
-            return Ok(Json(cached_response).into_response());
-        }
+    check_rate_limits(&state, &origin.ip_address, auth_result.client_key.as_ref()).await?;
+    if auth_result.client_key.as_ref().is_some_and(|key| !key.allows_model(&request.model)) {
+        return Err(StatusCode::FORBIDDEN);
     }
 
     // Get API key
     let api_key = match state.key_manager.get_next_key().await {
         Some(key) => key,
         None => {
-            error!("No API keys available");
-            return Ok(create_error_response("No API keys available", "service_unavailable"));
+            error!("No API keys available for embedding");
+            return Err(StatusCode::SERVICE_UNAVAILABLE);
         }
     };
 
-    // Handle streaming vs non-streaming
-    if request.stream {
-        handle_streaming_request(state, request, api_key, client_ip, start_time).await
-    } else {
-        handle_non_streaming_request(state, request, api_key, client_ip, start_time).await
-    }
-}
-
-async fn handle_streaming_request(
-    state: AppState,
-    request: ChatCompletionRequest,
-    api_key: String,
-    client_ip: Option<String>,
-    start_time: Instant,
-) -> Result<Response, StatusCode> {
-    if state.settings.fake_streaming {
-        // Use fake streaming mode
-        handle_fake_streaming(state, request, api_key, client_ip, start_time).await
-    } else {
-        // Use real streaming
-        handle_real_streaming(state, request, api_key, client_ip, start_time).await
-    }
-}
-
-async fn handle_fake_streaming(
-    state: AppState,
-    request: ChatCompletionRequest,
-    api_key: String,
-    client_ip: Option<String>,
-    start_time: Instant,
-) -> Result<Response, StatusCode> {
-    // Make a non-streaming request in the background
-    let gemini_client = state.gemini_client.clone();
-    let model = request.model.clone();
-
-    let stream = stream::unfold(
-        (state, request, api_key, client_ip, start_time, false, gemini_client, model),
-        move |(state, request, api_key, client_ip, start_time, completed, gemini_client, model)| async move {
-            if completed {
-                return None;
-            }
-
-            match gemini_client.chat_completion(request.clone(), &api_key).await {
-                Ok(response) => {
-                    // Record successful API call
-                    state.stats_manager.record_api_call(
-                        model.clone(),
-                        response.usage.as_ref().map(|u| u.total_tokens).unwrap_or(0),
-                        true,
-                        start_time.elapsed().as_millis() as u64,
-                        client_ip.clone(),
-                    ).await;
-
-                    // Mark API key as successful
-                    state.key_manager.mark_key_used(&api_key, true).await;
-
-                    // Convert to streaming format and return final chunk
-                    let chunk_data = serde_json::to_string(&response).unwrap_or_default();
-                    let event = Event::default().data(chunk_data);
-                    Some((Ok::<Event, AnyhowError>(event), (state, request, api_key, client_ip, start_time, true, gemini_client, model)))
-                }
-                Err(e) => {
-                    error!("Fake streaming request failed: {}", e);
-
-                    // Record failed API call
-                    state.stats_manager.record_api_call(
-                        model.clone(),
-                        0,
-                        false,
-                        start_time.elapsed().as_millis() as u64,
-                        client_ip.clone(),
-                    ).await;
-
-                    // Mark API key as failed
-                    state.key_manager.mark_key_used(&api_key, false).await;
-
-                    let error_data = serde_json::to_string(&create_error_json(&e.to_string(), "api_error")).unwrap_or_default();
-                    let event = Event::default().data(error_data);
-                    Some((Ok::<Event, AnyhowError>(event), (state, request, api_key, client_ip, start_time, true, gemini_client, model)))
-                }
-            }
-        },
-    );
-
-    Ok(Sse::new(stream).into_response())    
-}
-
-async fn handle_real_streaming(
-    state: AppState,
-    request: ChatCompletionRequest,
-    api_key: String,
-    client_ip: Option<String>,
-    start_time: Instant,
-) -> Result<Response, StatusCode> {
-    match state.gemini_client.chat_completion_stream(request.clone(), &api_key).await {
-        Ok(gemini_stream) => {
-            let stream = gemini_stream.map(move |chunk_result| {
-                match chunk_result {
-                    Ok(chunk) => {
-                        let chunk_data = serde_json::to_string(&chunk).unwrap_or_default();
-                        Ok::<Event, AnyhowError>(Event::default().data(chunk_data))
-                    }
-                    Err(e) => {
-                        error!("Streaming chunk error: {}", e);
-                        let error_data = serde_json::to_string(&create_error_json(&e.to_string(), "stream_error")).unwrap_or_default();
-                        Ok::<Event, AnyhowError>(Event::default().data(error_data))
-                    }
-                }
-            });
+    let client = &state.gemini_client;
+    let result = state.key_manager
+        .with_key_retry(api_key, state.settings.load().max_retry_num.max(1), |key| {
+            let request = request.clone();
+            async move { client.embedding(request, &key).await }
+        })
+        .await;
+    match result {
+        Ok(response) => {
+            // Record successful API call
+            state.stats_manager.record_api_call(
+                request.model,
+                response.usage.prompt_tokens,
+                0,
+                true,
+                start_time.elapsed().as_millis() as u64,
+                origin,
+            ).await;
 
-            Ok(Sse::new(stream).into_response())
+            Ok(Json(response))
         }
         Err(e) => {
-            error!("Failed to start streaming: {}", e);
-            state.key_manager.mark_key_used(&api_key, false).await;
+            error!("Embedding request failed: {}", e);
 
-            state.stats_manager.record_api_call(
+            state.stats_manager.record_api_error(
                 request.model,
-                0,
-                false,
+                e.error_type(),
                 start_time.elapsed().as_millis() as u64,
-                client_ip,
+                origin,
             ).await;
 
-            Ok(create_error_response(&e.to_string(), "stream_error"))
+            Err(e.status())
         }
     }
 }
 
-async fn handle_non_streaming_request(
-    state: AppState,
-    request: ChatCompletionRequest,
-    api_key: String,
-    client_ip: Option<String>,
-    start_time: Instant,
-) -> Result<Response, StatusCode> {
-    let model = request.model.clone();
+async fn image_generations(
+    State(state): State<AppState>,
+    connect_info: Option<ConnectInfo<SocketAddr>>,
+    headers: HeaderMap,
+    Query(query): Query<AuthQuery>,
+    Json(request): Json<ImageGenerationRequest>,
+) -> Result<Json<ImageGenerationResponse>, StatusCode> {
+    let start_time = Instant::now();
 
-    match state.gemini_client.chat_completion(request.clone(), &api_key).await {
+    let auth_result = authenticate_request(&headers, &query, &state.auth_state);
+    if !auth_result.authenticated {
+        return Err(StatusCode::UNAUTHORIZED);
+    }
+
+    let origin = call_origin(client_ip(&state, &headers, connect_info), &auth_result);
+    let model = request.model.clone().unwrap_or_else(|| DEFAULT_IMAGE_MODEL.to_string());
+
+    check_rate_limits(&state, &origin.ip_address, auth_result.client_key.as_ref()).await?;
+    check_model_access(&state, &headers, &auth_result, &model)?;
+
+    let api_key = match state.key_manager.get_next_key().await {
+        Some(key) => key,
+        None => {
+            error!("No API keys available for image generation");
+            return Err(StatusCode::SERVICE_UNAVAILABLE);
+        }
+    };
+
+    match state.gemini_client.generate_images(&request, &api_key).await {
         Ok(response) => {
-            // Record successful API call
             state.stats_manager.record_api_call(
-                model.clone(),
-                response.usage.as_ref().map(|u| u.total_tokens).unwrap_or(0),
+                model,
+                0,
+                0,
                 true,
                 start_time.elapsed().as_millis() as u64,
-                client_ip,
+                origin,
             ).await;
 
-            // Mark API key as successful
             state.key_manager.mark_key_used(&api_key, true).await;
-
-            // Cache the response
-            let cache_key = generate_cache_key(
-                &request.messages.iter().map(|m| serde_json::to_value(m).unwrap()).collect::<Vec<_>>(),
-                &request.model,
-                state.settings.calculate_cache_entries,
-                state.settings.precise_cache,
-            );
-
-            state.cache_manager.put(cache_key, response.clone()).await;
-
-            Ok(Json(response).into_response())
+            Ok(Json(response))
         }
         Err(e) => {
-            error!("Non-streaming request failed: {}", e);
+            error!("Image generation request failed: {}", e);
 
-            // Record failed API call
-            state.stats_manager.record_api_call(
+            state.stats_manager.record_api_error(
                 model,
-                0,
-                false,
+                e.error_type(),
                 start_time.elapsed().as_millis() as u64,
-                client_ip,
+                origin,
             ).await;
 
-            // Mark API key as failed
-            state.key_manager.mark_key_used(&api_key, false).await;
-
-            Ok(create_error_response(&e.to_string(), "api_error"))
+            state.key_manager.record_error(&api_key, &e).await;
+            Err(e.status())
         }
     }
 }
 
-async fn list_models(
+/// Moderation from Gemini safety ratings. Cached inputs are answered without an upstream
+/// call, so a key is only taken when something has to be rated.
+async fn moderations(
     State(state): State<AppState>,
+    connect_info: Option<ConnectInfo<SocketAddr>>,
     headers: HeaderMap,
     Query(query): Query<AuthQuery>,
-) -> Result<Json<ModelResponse>, StatusCode> {
-    // Authenticate request
-    let auth_result = authenticate_request(&headers, &query, &state.settings);
+    Json(request): Json<ModerationRequest>,
+) -> Result<Json<ModerationResponse>, StatusCode> {
+    let start_time = Instant::now();
+
+    let auth_result = authenticate_request(&headers, &query, &state.auth_state);
     if !auth_result.authenticated {
         return Err(StatusCode::UNAUTHORIZED);
     }
 
-    let available_models = state.gemini_client.get_available_models().await;
-    let mut models = Vec::new();
+    let origin = call_origin(client_ip(&state, &headers, connect_info), &auth_result);
+    let model = moderation_model(request.model.as_deref()).to_string();
 
-    for model_name in available_models {
-        // Add regular model
-        models.push(Model {
-            id: model_name.clone(),
-            object: "model".to_string(),
-            created: chrono::Utc::now().timestamp() as u64,
-            owned_by: "google".to_string(),
-        });
+    check_rate_limits(&state, &origin.ip_address, auth_result.client_key.as_ref()).await?;
+    check_model_access(&state, &headers, &auth_result, &model)?;
 
-        // Add search variant if search mode is enabled
-        if state.settings.search.search_mode && model_name.starts_with("gemini") {
-            models.push(Model {
-                id: format!("{}-search", model_name),
-                object: "model".to_string(),
-                created: chrono::Utc::now().timestamp() as u64,
-                owned_by: "google".to_string(),
-            });
+    let texts = request.input.texts();
+    let mut results = Vec::with_capacity(texts.len());
+    let mut api_key = None;
+    for text in &texts {
+        if let Some(result) = state.gemini_client.cached_moderation(&model, text).await {
+            results.push(result);
+            continue;
+        }
+
+        let key = match &api_key {
+            Some(key) => key,
+            None => match state.key_manager.get_next_key().await {
+                Some(key) => api_key.insert(key),
+                None => {
+                    error!("No API keys available for moderation");
+                    return Err(StatusCode::SERVICE_UNAVAILABLE);
+                }
+            },
+        };
+        match state.gemini_client.moderate(&model, text, key).await {
+            Ok(result) => results.push(result),
+            Err(e) => {
+                error!("Moderation request failed: {}", e);
+
+                state.stats_manager.record_api_error(
+                    model,
+                    e.error_type(),
+                    start_time.elapsed().as_millis() as u64,
+                    origin,
+                ).await;
+
+                state.key_manager.record_error(key, &e).await;
+                return Err(e.status());
+            }
         }
     }
 
-    Ok(Json(ModelResponse {
-        object: "list".to_string(),
-        data: models,
+    if let Some(key) = api_key {
+        state.stats_manager.record_api_call(
+            model.clone(),
+            0,
+            0,
+            true,
+            start_time.elapsed().as_millis() as u64,
+            origin,
+        ).await;
+        state.key_manager.mark_key_used(&key, true).await;
+    }
+
+    Ok(Json(ModerationResponse {
+        id: format!("modr-{}", generate_random_string(24)),
+        model,
+        results,
     }))
 }
 
-async fn embeddings(
+/// The fields of a `/audio/transcriptions` form
+#[derive(Default)]
+struct TranscriptionUpload {
+    audio: Vec<u8>,
+    file_name: Option<String>,
+    content_type: Option<String>,
+    model: Option<String>,
+    prompt: Option<String>,
+    language: Option<String>,
+    response_format: Option<String>,
+    temperature: Option<f32>,
+}
+
+impl TranscriptionUpload {
+    /// Read the form, refusing audio over `MAX_AUDIO_BYTES` as soon as it is exceeded
+    async fn read(mut multipart: Multipart) -> Result<Self, Response> {
+        let multipart_error = |e: axum::extract::multipart::MultipartError| {
+            let status = e.status();
+            let message = format!("Invalid multipart form: {}", e.body_text());
+            (status, Json(create_error_json(&message, "invalid_request_error"))).into_response()
+        };
+
+        let mut upload = Self::default();
+        let mut has_file = false;
+        while let Some(mut field) = multipart.next_field().await.map_err(multipart_error)? {
+            let name = field.name().unwrap_or_default().to_string();
+            if name == "file" {
+                has_file = true;
+                upload.file_name = field.file_name().map(str::to_string);
+                upload.content_type = field.content_type().map(str::to_string);
+                while let Some(chunk) = field.chunk().await.map_err(multipart_error)? {
+                    if upload.audio.len() + chunk.len() > MAX_AUDIO_BYTES {
+                        let message = format!("Audio file is larger than the {} MB limit", MAX_AUDIO_BYTES / (1024 * 1024));
+                        return Err(create_error_response(&message, "invalid_request_error"));
+                    }
+                    upload.audio.extend_from_slice(&chunk);
+                }
+                continue;
+            }
+
+            let value = field.text().await.map_err(multipart_error)?.trim().to_string();
+            match name.as_str() {
+                "model" => upload.model = Some(value),
+                "prompt" => upload.prompt = Some(value),
+                "language" => upload.language = Some(value),
+                "response_format" => upload.response_format = Some(value),
+                "temperature" => match value.trim().parse() {
+                    Ok(temperature) => upload.temperature = Some(temperature),
+                    Err(_) => return Err(create_error_response("temperature must be a number", "invalid_request_error")),
+                },
+                _ => {}
+            }
+        }
+
+        if !has_file || upload.audio.is_empty() {
+            return Err(create_error_response("An audio file is required in the `file` field", "invalid_request_error"));
+        }
+        Ok(upload)
+    }
+}
+
+/// Whisper-compatible transcription by a Gemini model with audio understanding
+async fn audio_transcriptions(
     State(state): State<AppState>,
+    connect_info: Option<ConnectInfo<SocketAddr>>,
     headers: HeaderMap,
     Query(query): Query<AuthQuery>,
-    Json(request): Json<EmbeddingRequest>,
-) -> Result<Json<EmbeddingResponse>, StatusCode> {
+    multipart: Multipart,
+) -> Result<Response, StatusCode> {
     let start_time = Instant::now();
 
-    // Authenticate request
-    let auth_result = authenticate_request(&headers, &query, &state.settings);
+    let auth_result = authenticate_request(&headers, &query, &state.auth_state);
     if !auth_result.authenticated {
         return Err(StatusCode::UNAUTHORIZED);
     }
 
-    let client_ip = extract_client_ip(&headers);
+    let origin = call_origin(client_ip(&state, &headers, connect_info), &auth_result);
+    check_rate_limits(&state, &origin.ip_address, auth_result.client_key.as_ref()).await?;
+
+    let upload = match TranscriptionUpload::read(multipart).await {
+        Ok(upload) => upload,
+        Err(response) => return Ok(response),
+    };
+    let format = match TranscriptionFormat::parse(upload.response_format.as_deref().unwrap_or("json")) {
+        Ok(format) => format,
+        Err(message) => return Ok(create_error_response(&message, "invalid_request_error")),
+    };
+    let mime_type = match audio_mime_type(upload.file_name.as_deref(), upload.content_type.as_deref()) {
+        Some(mime_type) => mime_type,
+        None => {
+            let message = format!("Unsupported audio format; supported formats are {}", SUPPORTED_FORMATS);
+            return Ok(create_error_response(&message, "invalid_request_error"));
+        }
+    };
+
+    let model = transcription_model(upload.model.as_deref(), &state.settings.load().transcription_model).to_string();
+    check_model_access(&state, &headers, &auth_result, &model)?;
 
-    // Get API key
     let api_key = match state.key_manager.get_next_key().await {
         Some(key) => key,
         None => {
-            error!("No API keys available for embedding");
+            error!("No API keys available for transcription");
             return Err(StatusCode::SERVICE_UNAVAILABLE);
         }
     };
 
-    match state.gemini_client.embedding(request.clone(), &api_key).await {
-        Ok(response) => {
-            // Record successful API call
+    let instruction = transcription_instruction(upload.language.as_deref(), upload.prompt.as_deref());
+    match state.gemini_client.transcribe(&model, &upload.audio, mime_type, instruction, upload.temperature, &api_key).await {
+        Ok(text) => {
             state.stats_manager.record_api_call(
-                request.model,
-                response.usage.total_tokens,
+                model,
+                0,
+                0,
                 true,
                 start_time.elapsed().as_millis() as u64,
-                client_ip,
+                origin,
             ).await;
 
             state.key_manager.mark_key_used(&api_key, true).await;
-            Ok(Json(response))
+            Ok(match format {
+                TranscriptionFormat::Json => Json(serde_json::json!({ "text": text })).into_response(),
+                TranscriptionFormat::Text => text.into_response(),
+                TranscriptionFormat::VerboseJson => {
+                    Json(verbose_transcription(&text, upload.language.as_deref(), wav_duration(&upload.audio))).into_response()
+                }
+            })
         }
         Err(e) => {
-            error!("Embedding request failed: {}", e);
+            error!("Transcription request failed: {}", e);
 
-            state.stats_manager.record_api_call(
-                request.model,
-                0,
-                false,
+            state.stats_manager.record_api_error(
+                model,
+                e.error_type(),
                 start_time.elapsed().as_millis() as u64,
-                client_ip,
+                origin,
             ).await;
 
-            state.key_manager.mark_key_used(&api_key, false).await;
-            Err(StatusCode::INTERNAL_SERVER_ERROR)
+            state.key_manager.record_error(&api_key, &e).await;
+            Err(e.status())
         }
     }
 }
 
 // Helper functions
 
-fn extract_client_ip(headers: &HeaderMap) -> Option<String> {
-    // Check various headers for client IP
-    for header_name in ["x-forwarded-for", "x-real-ip", "cf-connecting-ip"] {
-        if let Some(ip_header) = headers.get(header_name) {
-            if let Ok(ip_str) = ip_header.to_str() {
-                // Take the first IP if there are multiple
-                let ip = ip_str.split(',').next().unwrap_or(ip_str).trim();
-                return Some(ip.to_string());
+pub(crate) fn call_origin(client_ip: Option<String>, auth_result: &AuthResult) -> CallOrigin {
+    CallOrigin {
+        ip_address: client_ip,
+        client_key: auth_result.client_key.as_ref().map(|key| key.name.clone()),
+        client_id: auth_result.client_id.clone(),
+        fallback_provider: None,
+        hedged: false,
+        diagnostic: false,
+        end_user: None,
+        forwarded_headers: Vec::new(),
+    }
+}
+
+/// Where a request comes from, as far as `resolve_client_ip` can tell
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum ClientAddr {
+    Known(IpAddr),
+    /// No peer address and nothing forwarded
+    Unknown,
+    /// A trusted proxy forwarded something that is not an IP address
+    Invalid,
+}
+
+impl ClientAddr {
+    pub(crate) fn ip(self) -> Option<IpAddr> {
+        match self {
+            ClientAddr::Known(ip) => Some(ip),
+            ClientAddr::Unknown | ClientAddr::Invalid => None,
+        }
+    }
+}
+
+/// Client IP of a request. The peer address of the connection decides, unless the peer is
+/// one of `trusted_proxies`: then the forwarding headers name the client, taking the
+/// right-most `X-Forwarded-For` hop that is not a trusted proxy itself. Connections without a
+/// peer address, such as those on `listen_socket`, count as coming from a trusted proxy.
+pub(crate) fn resolve_client_ip(headers: &HeaderMap, connect_info: Option<ConnectInfo<SocketAddr>>, filter: &IpFilter) -> ClientAddr {
+    let peer = connect_info.map(|ConnectInfo(addr)| addr.ip());
+    if let Some(peer) = peer.filter(|peer| !filter.is_trusted_proxy(*peer)) {
+        return ClientAddr::Known(peer);
+    }
+
+    let forwarded_for: Vec<&HeaderValue> = headers.get_all("x-forwarded-for").iter().collect();
+    if !forwarded_for.is_empty() {
+        let mut hops = Vec::new();
+        for value in forwarded_for {
+            let Ok(value) = value.to_str() else {
+                return ClientAddr::Invalid;
+            };
+            hops.extend(value.split(',').map(str::trim));
+        }
+        let mut client = ClientAddr::Invalid;
+        for hop in hops.into_iter().rev() {
+            let Ok(ip) = hop.parse::<IpAddr>() else {
+                return ClientAddr::Invalid;
+            };
+            client = ClientAddr::Known(ip);
+            if !filter.is_trusted_proxy(ip) {
+                break;
             }
         }
+        return client;
     }
-    None
+
+    for header_name in ["x-real-ip", "cf-connecting-ip"] {
+        if let Some(value) = headers.get(header_name) {
+            return match value.to_str().ok().and_then(|value| value.trim().parse::<IpAddr>().ok()) {
+                Some(ip) => ClientAddr::Known(ip),
+                None => ClientAddr::Invalid,
+            };
+        }
+    }
+    peer.map_or(ClientAddr::Unknown, ClientAddr::Known)
+}
+
+/// The client IP calls are attributed to and limited by, see `resolve_client_ip`
+pub(crate) fn client_ip(state: &AppState, headers: &HeaderMap, connect_info: Option<ConnectInfo<SocketAddr>>) -> Option<String> {
+    resolve_client_ip(headers, connect_info, &state.auth_state.ip_filter).ip().map(|ip| ip.to_string())
 }
 
-async fn check_rate_limits(state: &AppState, client_ip: &Option<String>) -> Result<(), StatusCode> {
+/// The model policy, the user-agent filter and the caller's client key, checked the way the
+/// chat pipeline checks them
+pub(super) fn check_model_access(state: &AppState, headers: &HeaderMap, auth_result: &AuthResult, model: &str) -> Result<(), StatusCode> {
+    let filter = &state.auth_state.model_filter;
+    let user_agent = headers.get("user-agent").and_then(|ua| ua.to_str().ok());
+    if !filter.allows_user_agent(user_agent) || !filter.allows_model(model) {
+        return Err(StatusCode::FORBIDDEN);
+    }
+    if auth_result.client_key.as_ref().is_some_and(|key| !key.allows_model(model)) {
+        return Err(StatusCode::FORBIDDEN);
+    }
+    Ok(())
+}
+
+pub(super) async fn check_rate_limits(state: &AppState, client_ip: &Option<String>, client_key: Option<&ClientKey>) -> Result<(), StatusCode> {
     if let Some(ip) = client_ip {
         let requests_today = state.stats_manager.get_requests_for_ip_last_day(ip).await;
-        if requests_today >= state.settings.max_requests_per_day_per_ip {
+        if requests_today >= state.settings.load().max_requests_per_day_per_ip {
             warn!("Rate limit exceeded for IP: {}", ip);
             return Err(StatusCode::TOO_MANY_REQUESTS);
         }
     }
 
+    if let Some(client_key) = client_key {
+        if client_key.daily_request_limit > 0 || client_key.daily_token_limit > 0 {
+            let (requests, tokens) = state.stats_manager.get_client_usage_last_day(&client_key.name).await;
+            let over_requests = client_key.daily_request_limit > 0 && requests >= client_key.daily_request_limit;
+            let over_tokens = client_key.daily_token_limit > 0 && tokens >= client_key.daily_token_limit;
+            if over_requests || over_tokens {
+                warn!("Daily quota exceeded for client key: {}", client_key.name);
+                return Err(StatusCode::TOO_MANY_REQUESTS);
+            }
+        }
+    }
+
     // Additional rate limiting logic could be added here
     Ok(())
 }
 
-fn is_model_allowed(model: &str, settings: &crate::config::Settings) -> bool {
-    // Check whitelist first (if configured)
-    if !settings.whitelist_models.is_empty() {
-        return settings.whitelist_models.contains(&model.to_string());
+#[cfg(test)]
+mod tests {
+    use crate::config::Settings;
+    use crate::services::gemini::GeminiClient;
+    use crate::testing::test_state;
+    use crate::utils::AuthState;
+    use http_body_util::BodyExt;
+    use std::sync::Arc;
+
+    async fn model_ids(settings: Settings, uri: &str) -> (u16, serde_json::Value) {
+        use tower::ServiceExt;
+
+        let settings = Arc::new(settings);
+        let mut state = test_state();
+        state.vertex_enabled = settings.enable_vertex;
+        state.settings = settings.clone().into();
+        state.auth_state = Arc::new(AuthState::new(settings.clone()));
+        state.gemini_client = Arc::new(GeminiClient::new(settings));
+        state.gemini_client.load_default_models().await;
+        let app = crate::build_app(state).await.unwrap();
+
+        let request = hyper::Request::builder()
+            .uri(uri)
+            .header("authorization", "Bearer 123")
+            .body(axum::body::Body::empty())
+            .unwrap();
+        let response = app.oneshot(request).await.unwrap();
+        let status = response.status().as_u16();
+        let body = response.into_body().collect().await.unwrap().to_bytes();
+        (status, serde_json::from_slice(&body).unwrap())
     }
 
-    // Check blacklist
-    !settings.blocked_models.contains(&model.to_string())
-}
\ No newline at end of file
+    fn listed(json: &serde_json::Value) -> Vec<String> {
+        json["data"].as_array().unwrap().iter().map(|m| m["id"].as_str().unwrap().to_string()).collect()
+    }
+
+    #[tokio::test]
+    async fn test_model_listing_respects_filters() {
+        let (status, json) = model_ids(Settings::default(), "/v1/models").await;
+        assert_eq!(status, 200);
+        assert_eq!(listed(&json).len(), 6);
+
+        let whitelist = Settings {
+            whitelist_models: ["gemini-1.5-flash".to_string()].into_iter().collect(),
+            ..Default::default()
+        };
+        let (_, json) = model_ids(whitelist, "/v1/models").await;
+        assert_eq!(listed(&json), vec!["gemini-1.5-flash"]);
+
+        let blacklist = Settings {
+            blocked_models: ["gemini-1.5-pro".to_string(), "text-embedding-004".to_string()].into_iter().collect(),
+            ..Default::default()
+        };
+        let (_, json) = model_ids(blacklist, "/v1/models").await;
+        let ids = listed(&json);
+        assert_eq!(ids.len(), 4);
+        assert!(!ids.contains(&"gemini-1.5-pro".to_string()));
+    }
+
+    #[tokio::test]
+    async fn test_model_detail_route() {
+        let (status, json) = model_ids(Settings::default(), "/v1/models/gemini-1.5-flash").await;
+        assert_eq!(status, 200);
+        assert_eq!(json["id"], "gemini-1.5-flash");
+        assert_eq!(json["object"], "model");
+
+        let (status, json) = model_ids(Settings::default(), "/v1/models/gpt-4").await;
+        assert_eq!(status, 404);
+        assert_eq!(json["error"]["type"], "not_found_error");
+
+        let blacklist = Settings {
+            blocked_models: ["gemini-1.5-flash".to_string()].into_iter().collect(),
+            ..Default::default()
+        };
+        let (status, _) = model_ids(blacklist, "/v1/models/gemini-1.5-flash").await;
+        assert_eq!(status, 404);
+    }
+
+    #[tokio::test]
+    async fn test_model_listing_includes_vertex_models() {
+        let app = axum::Router::new().route(
+            "/models.json",
+            axum::routing::get(|| async {
+                r#"{"vertex_models": ["gemini-1.5-pro", "gemini-2.5-pro"], "vertex_express_models": ["gemini-2.5-flash"]}"#
+            }),
+        );
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}/models.json", listener.local_addr().unwrap());
+        tokio::spawn(async move { axum::serve(listener, app).await.unwrap() });
+
+        let settings = Settings {
+            enable_vertex: true,
+            vertex_models_config_url: url,
+            ..Default::default()
+        };
+        let (_, json) = model_ids(settings.clone(), "/v1/models").await;
+        let ids = listed(&json);
+        assert_eq!(ids.len(), 7);
+        assert_eq!(ids.iter().filter(|id| *id == "gemini-1.5-pro").count(), 1);
+        assert!(ids.contains(&"gemini-2.5-pro".to_string()));
+
+        let express = Settings {
+            enable_vertex_express: true,
+            vertex_express_api_key: "express-key".to_string(),
+            ..settings
+        };
+        let (_, json) = model_ids(express, "/v1/models").await;
+        assert!(listed(&json).contains(&"[EXPRESS] gemini-2.5-flash".to_string()));
+    }
+
+    #[tokio::test]
+    async fn test_legacy_completions() {
+        use crate::models::schemas::{ChatCompletionResponse, CompletionRequest};
+        use tower::ServiceExt;
+
+        let state = test_state();
+        let app = crate::build_app(state.clone()).await.unwrap();
+        for (prompt, cached_text) in [
+            (serde_json::json!("Say hi"), "hi"),
+            (serde_json::json!(["Say hi", "then bye"]), "hi\nbye"),
+        ] {
+            let body = serde_json::json!({"model": "gemini-1.5-flash", "prompt": prompt, "max_tokens": 16, "stop": "\n\n"});
+
+            // Served from the cache entry of the equivalent chat request
+            let chat = serde_json::from_value::<CompletionRequest>(body.clone()).unwrap().into_chat_request();
+            let cache_key = crate::utils::cache::generate_cache_key(
+                &chat.messages.iter().map(|m| serde_json::to_value(m).unwrap()).collect::<Vec<_>>(),
+                "gemini-1.5-flash",
+                state.settings.load().calculate_cache_entries,
+                state.settings.load().precise_cache,
+            );
+            let cached: ChatCompletionResponse = serde_json::from_value(serde_json::json!({
+                "id": "chatcmpl-cached", "object": "chat.completion", "created": 0, "model": "gemini-1.5-flash",
+                "choices": [{"index": 0, "message": {"role": "assistant", "content": cached_text}, "finish_reason": "stop"}]
+            }))
+            .unwrap();
+            state.cache_manager.put(cache_key, cached).await;
+
+            let response = app
+                .clone()
+                .oneshot(
+                    hyper::Request::builder()
+                        .method("POST")
+                        .uri("/v1/completions")
+                        .header("authorization", "Bearer 123")
+                        .header("content-type", "application/json")
+                        .body(axum::body::Body::from(body.to_string()))
+                        .unwrap(),
+                )
+                .await
+                .unwrap();
+            assert_eq!(response.status(), 200);
+            let body = response.into_body().collect().await.unwrap().to_bytes();
+            let json: serde_json::Value = serde_json::from_slice(&body).unwrap();
+            assert_eq!(json["object"], "text_completion");
+            assert_eq!(json["choices"][0]["text"], cached_text);
+            assert_eq!(json["choices"][0]["finish_reason"], "stop");
+        }
+    }
+
+    #[tokio::test]
+    async fn test_responses_endpoint() {
+        use crate::models::schemas::{ChatCompletionResponse, ResponsesRequest};
+        use tower::ServiceExt;
+
+        let state = test_state();
+        let app = crate::build_app(state.clone()).await.unwrap();
+        let post = |body: serde_json::Value| {
+            hyper::Request::builder()
+                .method("POST")
+                .uri("/v1/responses")
+                .header("authorization", "Bearer 123")
+                .header("content-type", "application/json")
+                .body(axum::body::Body::from(body.to_string()))
+                .unwrap()
+        };
+        let body = serde_json::json!({
+            "model": "gemini-1.5-flash",
+            "instructions": "Be brief",
+            "input": [{"role": "user", "content": [{"type": "input_text", "text": "Say hi"}]}],
+            "max_output_tokens": 16,
+        });
+
+        // Served from the cache entry of the equivalent chat request
+        let chat = serde_json::from_value::<ResponsesRequest>(body.clone()).unwrap().into_chat_request().unwrap();
+        assert_eq!(chat.messages[0].role, "system");
+        let cache_key = crate::utils::cache::generate_cache_key(
+            &chat.messages.iter().map(|m| serde_json::to_value(m).unwrap()).collect::<Vec<_>>(),
+            "gemini-1.5-flash",
+            state.settings.load().calculate_cache_entries,
+            state.settings.load().precise_cache,
+        );
+        let cached: ChatCompletionResponse = serde_json::from_value(serde_json::json!({
+            "id": "chatcmpl-cached", "object": "chat.completion", "created": 0, "model": "gemini-1.5-flash",
+            "choices": [{"index": 0, "message": {"role": "assistant", "content": "hi"}, "finish_reason": "stop"}],
+            "usage": {"prompt_tokens": 4, "completion_tokens": 1, "total_tokens": 5}
+        }))
+        .unwrap();
+        state.cache_manager.put(cache_key, cached).await;
+
+        let response = app.clone().oneshot(post(body)).await.unwrap();
+        assert_eq!(response.status(), 200);
+        let body = response.into_body().collect().await.unwrap().to_bytes();
+        let json: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(json["object"], "response");
+        assert_eq!(json["status"], "completed");
+        assert_eq!(json["output"][0]["content"][0]["type"], "output_text");
+        assert_eq!(json["output"][0]["content"][0]["text"], "hi");
+        assert_eq!(json["usage"]["input_tokens"], 4);
+
+        let tools = serde_json::json!({
+            "model": "gemini-1.5-flash",
+            "input": "What is the weather?",
+            "tools": [{"type": "function", "name": "get_weather"}],
+        });
+        let response = app.clone().oneshot(post(tools)).await.unwrap();
+        assert_eq!(response.status(), 400);
+        let body = response.into_body().collect().await.unwrap().to_bytes();
+        assert!(String::from_utf8_lossy(&body).contains("Tools are not supported"));
+
+        let function_output = serde_json::json!({
+            "model": "gemini-1.5-flash",
+            "input": [{"type": "function_call_output", "call_id": "call_1", "output": "sunny"}],
+        });
+        assert_eq!(app.oneshot(post(function_output)).await.unwrap().status(), 400);
+    }
+
+    #[tokio::test]
+    async fn test_moderations_requires_auth_and_keys() {
+        use tower::ServiceExt;
+
+        let app = crate::build_app(test_state()).await.unwrap();
+        let post = |auth: Option<&str>| {
+            let mut builder = hyper::Request::builder()
+                .method("POST")
+                .uri("/v1/moderations")
+                .header("content-type", "application/json");
+            if let Some(auth) = auth {
+                builder = builder.header("authorization", auth);
+            }
+            let body = serde_json::json!({"model": "omni-moderation-latest", "input": ["first", "second"]});
+            builder.body(axum::body::Body::from(body.to_string())).unwrap()
+        };
+
+        assert_eq!(app.clone().oneshot(post(None)).await.unwrap().status(), 401);
+        // Nothing is cached and there is no key to rate with
+        assert_eq!(app.oneshot(post(Some("Bearer 123"))).await.unwrap().status(), 503);
+
+        // The Gemini model that rates the input is subject to the model policy
+        let mut state = test_state();
+        state.auth_state = Arc::new(AuthState::new(Arc::new(Settings {
+            blocked_models: [crate::services::moderation::moderation_model(None).to_string()].into(),
+            ..Default::default()
+        })));
+        let app = crate::build_app(state).await.unwrap();
+        assert_eq!(app.oneshot(post(Some("Bearer 123"))).await.unwrap().status(), 403);
+    }
+
+    #[tokio::test]
+    async fn test_audio_transcription_validation() {
+        use tower::ServiceExt;
+
+        let app = crate::build_app(test_state()).await.unwrap();
+        let upload = |file_name: &str, response_format: &str, auth: bool| {
+            let boundary = "rujimi-boundary";
+            let body = format!(
+                "--{b}\r\nContent-Disposition: form-data; name=\"model\"\r\n\r\nwhisper-1\r\n\
+                 --{b}\r\nContent-Disposition: form-data; name=\"response_format\"\r\n\r\n{format}\r\n\
+                 --{b}\r\nContent-Disposition: form-data; name=\"file\"; filename=\"{name}\"\r\n\
+                 Content-Type: application/octet-stream\r\n\r\nRIFF....WAVE\r\n--{b}--\r\n",
+                b = boundary,
+                format = response_format,
+                name = file_name,
+            );
+            let mut builder = hyper::Request::builder()
+                .method("POST")
+                .uri("/v1/audio/transcriptions")
+                .header("content-type", format!("multipart/form-data; boundary={}", boundary));
+            if auth {
+                builder = builder.header("authorization", "Bearer 123");
+            }
+            builder.body(axum::body::Body::from(body)).unwrap()
+        };
+        let error_message = |response: axum::response::Response| async move {
+            let body = response.into_body().collect().await.unwrap().to_bytes();
+            serde_json::from_slice::<serde_json::Value>(&body).unwrap()["error"]["message"].as_str().unwrap().to_string()
+        };
+
+        assert_eq!(app.clone().oneshot(upload("clip.wav", "json", false)).await.unwrap().status(), 401);
+
+        let response = app.clone().oneshot(upload("movie.mkv", "json", true)).await.unwrap();
+        assert_eq!(response.status(), 400);
+        assert!(error_message(response).await.contains("wav, mp3, m4a, ogg, flac"));
+
+        let response = app.clone().oneshot(upload("clip.wav", "srt", true)).await.unwrap();
+        assert_eq!(response.status(), 400);
+        assert!(error_message(response).await.contains("srt"));
+
+        // Valid, but there is no key to transcribe with
+        assert_eq!(app.oneshot(upload("clip.wav", "verbose_json", true)).await.unwrap().status(), 503);
+
+        // The Gemini model that transcribes is subject to the model policy
+        let mut state = test_state();
+        let model = state.settings.load().transcription_model.clone();
+        state.auth_state = Arc::new(AuthState::new(Arc::new(Settings { blocked_models: [model].into(), ..Default::default() })));
+        let app = crate::build_app(state).await.unwrap();
+        assert_eq!(app.oneshot(upload("clip.wav", "json", true)).await.unwrap().status(), 403);
+    }
+
+    #[tokio::test]
+    async fn test_image_generation_respects_model_policy() {
+        use tower::ServiceExt;
+
+        let mut state = test_state();
+        state.auth_state = Arc::new(AuthState::new(Arc::new(Settings {
+            blocked_models: ["gemini-2.0-flash-exp".to_string()].into(),
+            whitelist_user_agent: ["allowed-client".to_string()].into(),
+            ..Default::default()
+        })));
+        let app = crate::build_app(state).await.unwrap();
+        let post = |model: &str, user_agent: &str| {
+            let body = serde_json::json!({"model": model, "prompt": "a cat"});
+            hyper::Request::builder()
+                .method("POST")
+                .uri("/v1/images/generations")
+                .header("authorization", "Bearer 123")
+                .header("user-agent", user_agent)
+                .header("content-type", "application/json")
+                .body(axum::body::Body::from(body.to_string()))
+                .unwrap()
+        };
+
+        assert_eq!(app.clone().oneshot(post("gemini-2.0-flash-exp", "allowed-client")).await.unwrap().status(), 403);
+        assert_eq!(app.clone().oneshot(post("imagen-3.0-generate-002", "curl/8.0")).await.unwrap().status(), 403);
+        // Allowed through, then refused for lack of an API key
+        assert_eq!(app.oneshot(post("imagen-3.0-generate-002", "allowed-client")).await.unwrap().status(), 503);
+    }
+}
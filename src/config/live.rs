@@ -0,0 +1,38 @@
+//! The settings request handlers read. Reloads and dashboard edits swap in a new snapshot;
+//! a request works from the snapshot it loaded, so it never sees half an update.
+
+use std::sync::{Arc, RwLock};
+
+use super::Settings;
+
+/// A shared handle to the current settings snapshot. Clones share the snapshot.
+#[derive(Debug, Clone)]
+pub struct LiveSettings(Arc<RwLock<Arc<Settings>>>);
+
+impl LiveSettings {
+    pub fn new(settings: Arc<Settings>) -> Self {
+        Self(Arc::new(RwLock::new(settings)))
+    }
+
+    /// The current snapshot
+    pub fn load(&self) -> Arc<Settings> {
+        self.0.read().unwrap_or_else(|e| e.into_inner()).clone()
+    }
+
+    /// Replace the snapshot for every holder of this handle
+    pub fn store(&self, settings: Arc<Settings>) {
+        *self.0.write().unwrap_or_else(|e| e.into_inner()) = settings;
+    }
+}
+
+impl From<Arc<Settings>> for LiveSettings {
+    fn from(settings: Arc<Settings>) -> Self {
+        Self::new(settings)
+    }
+}
+
+impl From<Settings> for LiveSettings {
+    fn from(settings: Settings) -> Self {
+        Self::new(Arc::new(settings))
+    }
+}
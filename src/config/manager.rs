@@ -2,7 +2,10 @@ use std::sync::Arc;
 use tokio::sync::RwLock;
 use once_cell::sync::Lazy;
 
-use super::{Settings, save_settings};
+use super::{Settings, UpstreamHeaderProfile, save_settings};
+use super::reload::ReloadReport;
+use crate::utils::http_client::{NetworkRetry, UpstreamTimeouts};
+use crate::utils::model_policy::ModelPolicy;
 use anyhow::Result;
 
 /// Global configuration manager - similar to hajimi's global settings module
@@ -172,6 +175,25 @@ impl ConfigManager {
                     config.public_mode = val;
                 }
             }
+            "public_allowed_models" => {
+                if let Some(val) = value.as_str() {
+                    config.public_allowed_models = val
+                        .split(',')
+                        .map(|s| s.trim().to_string())
+                        .filter(|s| !s.is_empty())
+                        .collect();
+                }
+            }
+            "public_requests_per_minute_per_ip" => {
+                if let Some(val) = value.as_u64() {
+                    config.public_requests_per_minute_per_ip = val as u32;
+                }
+            }
+            "public_max_tokens" => {
+                if let Some(val) = value.as_u64() {
+                    config.public_max_tokens = val as u32;
+                }
+            }
             "dashboard_url" => {
                 if let Some(val) = value.as_str() {
                     config.dashboard_url = val.to_string();
@@ -187,6 +209,46 @@ impl ConfigManager {
                     config.nonstream_keepalive_interval = val;
                 }
             }
+            "upstream_connect_timeout_secs" => {
+                if let Some(val) = value.as_u64() {
+                    config.upstream_connect_timeout_secs = val;
+                }
+            }
+            "upstream_request_timeout_secs" => {
+                if let Some(val) = value.as_u64() {
+                    config.upstream_request_timeout_secs = val;
+                }
+            }
+            "stream_idle_timeout_secs" => {
+                if let Some(val) = value.as_u64() {
+                    config.stream_idle_timeout_secs = val;
+                }
+            }
+            "gemini_base_url" => {
+                if let Some(val) = value.as_str() {
+                    config.gemini_base_url = super::normalize_base_url(val);
+                }
+            }
+            "api_version" => {
+                if let Some(val) = value.as_str() {
+                    config.api_version = val.trim().to_string();
+                }
+            }
+            "model_overrides" => {
+                let overrides = serde_json::from_value(value)
+                    .map_err(|e| anyhow::anyhow!("Invalid model_overrides: {}", e))?;
+                config.model_overrides = overrides;
+            }
+            "client_keys" => {
+                let client_keys = serde_json::from_value(value)
+                    .map_err(|e| anyhow::anyhow!("Invalid client_keys: {}", e))?;
+                config.client_keys = client_keys;
+            }
+            "ip_blocklist" => {
+                let ip_blocklist = serde_json::from_value(value)
+                    .map_err(|e| anyhow::anyhow!("Invalid ip_blocklist: {}", e))?;
+                config.ip_blocklist = ip_blocklist;
+            }
             _ => {
                 return Err(anyhow::anyhow!("Unsupported configuration key: {}", key));
             }
@@ -202,39 +264,82 @@ impl ConfigManager {
         Ok(())
     }
 
-    /// Get a specific configuration value
-    pub async fn get_config_value(key: &str) -> Option<serde_json::Value> {
+    /// Current primary Gemini API base URL with the configured API version, read per request so
+    /// dashboard changes apply without restart
+    pub async fn get_gemini_base_url() -> String {
         let config = GLOBAL_CONFIG.read().await;
+        super::versioned_base_url(&config.upstream_endpoints().swap_remove(0), &config.api_version)
+    }
 
-        match key {
-            "fake_streaming" => Some(serde_json::Value::Bool(config.fake_streaming)),
-            "fake_streaming_interval" => Some(serde_json::Value::Number(serde_json::Number::from_f64(config.fake_streaming_interval)?)),
-            "concurrent_requests" => Some(serde_json::Value::Number(serde_json::Number::from(config.concurrent_requests as u64))),
-            "enable_vertex" => Some(serde_json::Value::Bool(config.enable_vertex)),
-            "search_mode" => Some(serde_json::Value::Bool(config.search.search_mode)),
-            "show_api_error_message" => Some(serde_json::Value::Bool(config.show_api_error_message)),
-            "max_requests_per_minute" => Some(serde_json::Value::Number(serde_json::Number::from(config.max_requests_per_minute as u64))),
-            "max_requests_per_day_per_ip" => Some(serde_json::Value::Number(serde_json::Number::from(config.max_requests_per_day_per_ip as u64))),
-            "gemini_api_keys" => Some(serde_json::Value::String(config.gemini_api_keys.join(","))),
-            "google_credentials_json" => Some(serde_json::Value::String(config.google_credentials_json.clone())),
-            "vertex_express_api_key" => Some(serde_json::Value::String(config.vertex_express_api_key.clone())),
-            _ => None,
+    /// Like `get_gemini_base_url`, with the API version chosen for `model`
+    pub async fn get_gemini_base_url_for(model: &str) -> String {
+        let config = GLOBAL_CONFIG.read().await;
+        super::versioned_base_url(&config.upstream_endpoints().swap_remove(0), config.api_version_for(model))
+    }
+
+    /// Gemini API version for `model`, honoring model overrides
+    pub async fn get_api_version(model: &str) -> String {
+        GLOBAL_CONFIG.read().await.api_version_for(model).to_string()
+    }
+
+    /// Gemini base URLs in failover order, read per request like the base URL
+    pub async fn get_gemini_endpoints() -> Vec<String> {
+        GLOBAL_CONFIG.read().await.upstream_endpoints()
+    }
+
+    /// Current upstream timeouts, read per request so config API changes take effect immediately
+    pub async fn get_upstream_timeouts() -> UpstreamTimeouts {
+        UpstreamTimeouts::from_settings(&*GLOBAL_CONFIG.read().await)
+    }
+
+    /// Current network retry budget, read per request like the timeouts
+    pub async fn get_network_retry() -> NetworkRetry {
+        NetworkRetry::from_settings(&*GLOBAL_CONFIG.read().await)
+    }
+
+    /// Current upstream header profile, read per request like the timeouts
+    pub async fn get_upstream_header_profile() -> UpstreamHeaderProfile {
+        GLOBAL_CONFIG.read().await.upstream_header_profile
+    }
+
+    /// Set the model and user-agent filters, saving them when storage is enabled
+    pub async fn set_model_policy(policy: &ModelPolicy) -> Result<()> {
+        let mut config = GLOBAL_CONFIG.write().await;
+        config.blocked_models = policy.blocked_models.iter().cloned().collect();
+        config.whitelist_models = policy.whitelist_models.iter().cloned().collect();
+        config.whitelist_user_agent = policy.whitelist_user_agent.iter().cloned().collect();
+        if config.enable_storage {
+            save_settings(&config, &config.storage_dir)?;
         }
+        tracing::info!("Model policy updated");
+        Ok(())
     }
 
-    /// Reload settings from disk - similar to hajimi's load_settings()
-    pub async fn reload_from_disk() -> Result<()> {
-        let current_config = GLOBAL_CONFIG.read().await;
-        let storage_dir = current_config.storage_dir.clone();
-        drop(current_config);
-
-        if let Ok(loaded_settings) = super::load_settings(&storage_dir) {
-            let mut config = GLOBAL_CONFIG.write().await;
-            *config = loaded_settings;
-            tracing::info!("Settings reloaded from disk");
-            Ok(())
-        } else {
-            Err(anyhow::anyhow!("Failed to reload settings from disk"))
+    /// Replace the settings with `settings`, already validated, and save them to disk
+    pub async fn replace(settings: Settings) -> Result<ReloadReport> {
+        let mut config = GLOBAL_CONFIG.write().await;
+        save_settings(&settings, &settings.storage_dir)?;
+        let report = ReloadReport::between(&config, &settings);
+        *config = settings;
+        tracing::info!("Settings replaced and saved");
+        Ok(report)
+    }
+
+    /// Reload settings the way startup loads them: defaults, the config file and the
+    /// environment, replaced by the persisted settings when storage is on. The result is
+    /// validated and swapped in at once; on error the current settings stay in place.
+    pub async fn reload_from_disk() -> Result<ReloadReport> {
+        let mut settings = Settings::load()?;
+        if settings.enable_storage && super::settings_file_exists(&settings.storage_dir) {
+            settings = super::load_settings(&settings.storage_dir)?;
         }
+        settings.validate()?;
+        let settings = settings.checked().await?;
+
+        let mut config = GLOBAL_CONFIG.write().await;
+        let report = ReloadReport::between(&config, &settings);
+        *config = settings;
+        tracing::info!("Settings reloaded from disk");
+        Ok(report)
     }
-}
\ No newline at end of file
+}
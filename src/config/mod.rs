@@ -1,9 +1,15 @@
+pub mod export;
+pub mod live;
 pub mod persistence;
 pub mod safety;
 pub mod settings;
 pub mod manager;
+pub mod reload;
+pub mod summary;
 
-pub use persistence::{save_settings, load_settings, settings_file_exists};
+pub use persistence::{save_settings, load_settings, settings_file_exists, settings_file_path};
 pub use safety::*;
-pub use settings::Settings;
-pub use manager::ConfigManager;
\ No newline at end of file
+pub use settings::{Settings, ModelOverride, ClientKey, ClientKeyScope, CodeExecutionRender, ContextTrimStrategy, FallbackProvider, IpBlockEntry, StorageBackend, UpstreamHeaderProfile, normalize_base_url, versioned_base_url, GEMINI_API_VERSIONS, DEFAULT_GEMINI_API_VERSION};
+pub use live::LiveSettings;
+pub use manager::ConfigManager;
+pub use summary::{log_startup_summary, startup_summary};
\ No newline at end of file
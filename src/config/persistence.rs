@@ -1,7 +1,7 @@
 use anyhow::{Context, Result};
 use serde_json;
 use std::fs;
-use std::path::Path;
+use std::path::{Path, PathBuf};
 
 use super::Settings;
 
@@ -21,6 +21,9 @@ pub fn save_settings(settings: &Settings, storage_dir: &str) -> Result<()> {
         .with_context(|| format!("Failed to write settings to file: {:?}", file_path))?;
 
     tracing::info!("Settings saved to {:?}", file_path);
+    if let Some(storage) = crate::storage::active() {
+        storage.save_snapshot(settings);
+    }
     Ok(())
 }
 
@@ -42,6 +45,9 @@ pub fn load_settings(storage_dir: &str) -> Result<Settings> {
 }
 
 pub fn settings_file_exists(storage_dir: &str) -> bool {
-    let file_path = Path::new(storage_dir).join(SETTINGS_FILE);
-    file_path.exists()
+    settings_file_path(storage_dir).exists()
+}
+
+pub fn settings_file_path(storage_dir: &str) -> PathBuf {
+    Path::new(storage_dir).join(SETTINGS_FILE)
 }
\ No newline at end of file
@@ -0,0 +1,254 @@
+//! Live reloading of settings. The config file named by `RUJIMI_CONFIG` and, with storage
+//! on, the persisted `settings.json` are polled for changes; a change reloads the settings
+//! the same way startup loads them.
+
+use serde_json::Value;
+use std::future::Future;
+use std::path::PathBuf;
+use std::time::{Duration, SystemTime};
+
+use super::settings::CONFIG_PATH_ENV;
+use super::{settings_file_path, Settings};
+
+/// How often watched files are checked for changes
+pub const CONFIG_WATCH_INTERVAL: Duration = Duration::from_secs(2);
+
+/// Settings read when the listeners, the upstream clients and the other long-lived parts of
+/// the server are built; changing them takes a restart. Everything else is read from the
+/// current snapshot and applies on reload.
+const RESTART_REQUIRED: &[&str] = &[
+    "port",
+    "listen_tcp",
+    "listen_socket",
+    "listen_socket_mode",
+    "tls_cert_path",
+    "tls_key_path",
+    "tls_reload_interval_secs",
+    "allowed_origins",
+    "max_request_body_mb",
+    "compression_enabled",
+    "compression_algorithms",
+    "compression_min_size_bytes",
+    "static_dir",
+    "upstream_proxy",
+    "upstream_proxy_auth",
+    "no_proxy",
+    "enable_vertex",
+    "enable_storage",
+    "storage_dir",
+    "storage_backend",
+    "key_state_redis_url",
+    "audit_log_enabled",
+    "audit_log_include_content",
+    "audit_log_max_file_mb",
+    // Held by the Gemini client
+    "gemini_array_stream",
+    "code_execution_render",
+    "array_content_responses",
+    "context_trim_strategy",
+    "enable_context_caching",
+    "context_cache_min_tokens",
+    "context_cache_ttl_secs",
+    "accurate_token_estimates",
+    "search",
+    "random_string",
+    "random_string_length",
+    "end_user_note",
+    "model_overrides",
+    "max_retry_num",
+    "endpoint_failure_threshold",
+    "endpoint_cooldown_secs",
+    "mock_upstream",
+    "mock_latency_ms",
+    "mock_fail_rate",
+    // Held by the key manager, the response cache, the fair queue and the circuit breaker
+    "api_key_daily_limit",
+    "per_key_rpm",
+    "max_requests_per_minute",
+    "cache_expiry_time",
+    "max_cache_entries",
+    "min_tokens_to_cache",
+    "fair_queuing",
+    "fair_queue_concurrency",
+    "min_request_interval_ms",
+    "circuit_breaker_window",
+    "circuit_breaker_threshold_percent",
+    "circuit_breaker_cooldown_secs",
+    // Read once at startup
+    "fallback_provider",
+    "log_buffer_size",
+    "vertex_log_buffer_size",
+    "log_retention_secs",
+    "emergency_cleanup_memory_percent",
+    "model_refresh_interval_secs",
+    "warmup_on_start",
+    "update_check",
+    // Held by the Vertex routes
+    "google_credentials_json",
+    "enable_vertex_express",
+    "vertex_express_api_key",
+    "credentials_dir",
+    "vertex_project_id",
+    "vertex_location",
+    "vertex_models_config_url",
+    "vertex_base_url",
+    "fake_streaming_interval",
+];
+
+/// Runtime state kept in `Settings` that is not configuration
+pub(crate) const RUNTIME_FIELDS: &[&str] = &["base_dir", "version", "api_call_stats"];
+
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct ReloadReport {
+    /// Top-level keys whose value changed
+    pub changed: Vec<String>,
+    /// The changed keys that only take effect after a restart
+    pub restart_required: Vec<String>,
+}
+
+impl ReloadReport {
+    /// Compare two settings key by key. The order of list entries is ignored, since sets
+    /// serialize in no particular order.
+    pub fn between(old: &Settings, new: &Settings) -> Self {
+        let (old, new) = match (serde_json::to_value(old), serde_json::to_value(new)) {
+            (Ok(Value::Object(old)), Ok(Value::Object(new))) => (old, new),
+            _ => return Self::default(),
+        };
+        let mut changed: Vec<String> = new
+            .iter()
+            .filter(|(key, _)| !RUNTIME_FIELDS.contains(&key.as_str()))
+            .filter(|(key, value)| old.get(*key).map(normalized) != Some(normalized(value)))
+            .map(|(key, _)| key.clone())
+            .collect();
+        changed.sort();
+        let restart_required = changed
+            .iter()
+            .filter(|key| RESTART_REQUIRED.contains(&key.as_str()))
+            .cloned()
+            .collect();
+        Self { changed, restart_required }
+    }
+
+    pub fn contains(&self, key: &str) -> bool {
+        self.changed.iter().any(|changed| changed == key)
+    }
+}
+
+fn normalized(value: &Value) -> Value {
+    match value {
+        Value::Array(items) => {
+            let mut items: Vec<Value> = items.iter().map(normalized).collect();
+            items.sort_by_key(|item| item.to_string());
+            Value::Array(items)
+        }
+        Value::Object(map) => Value::Object(map.iter().map(|(key, value)| (key.clone(), normalized(value))).collect()),
+        other => other.clone(),
+    }
+}
+
+/// Files whose changes should reload `settings`
+pub fn watched_files(settings: &Settings) -> Vec<PathBuf> {
+    let mut files = Vec::new();
+    if let Ok(path) = std::env::var(CONFIG_PATH_ENV) {
+        if !path.trim().is_empty() {
+            files.push(PathBuf::from(path.trim()));
+        }
+    }
+    if settings.enable_storage {
+        files.push(settings_file_path(&settings.storage_dir));
+    }
+    files
+}
+
+/// Modification time and length of each file, `None` for missing ones
+fn snapshot(files: &[PathBuf]) -> Vec<Option<(SystemTime, u64)>> {
+    files
+        .iter()
+        .map(|file| {
+            let metadata = std::fs::metadata(file).ok()?;
+            Some((metadata.modified().ok()?, metadata.len()))
+        })
+        .collect()
+}
+
+/// Check `files` every `interval` and run `on_change` whenever any of them is modified,
+/// created or removed
+pub fn spawn_watcher<F, Fut>(files: Vec<PathBuf>, interval: Duration, on_change: F) -> tokio::task::JoinHandle<()>
+where
+    F: Fn() -> Fut + Send + 'static,
+    Fut: Future<Output = ()> + Send,
+{
+    tokio::spawn(async move {
+        let mut last = snapshot(&files);
+        let mut ticker = tokio::time::interval(interval);
+        ticker.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+        ticker.tick().await;
+        loop {
+            ticker.tick().await;
+            let current = snapshot(&files);
+            if current != last {
+                last = current;
+                on_change().await;
+            }
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashSet;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+
+    #[test]
+    fn test_reload_report() {
+        let old = Settings {
+            blocked_models: HashSet::from(["a".to_string(), "b".to_string(), "c".to_string()]),
+            ..Settings::default()
+        };
+        let same = Settings {
+            blocked_models: HashSet::from(["c".to_string(), "b".to_string(), "a".to_string()]),
+            ..Settings::default()
+        };
+        assert_eq!(ReloadReport::between(&old, &same), ReloadReport::default());
+
+        let new = Settings {
+            request_deadline_secs: old.request_deadline_secs + 1,
+            port: Some(8080),
+            ..same
+        };
+        let report = ReloadReport::between(&old, &new);
+        assert_eq!(report.changed, vec!["port", "request_deadline_secs"]);
+        assert_eq!(report.restart_required, vec!["port"]);
+        assert!(report.contains("port") && !report.contains("gemini_api_keys"));
+    }
+
+    #[tokio::test]
+    async fn test_watcher_sees_changes() {
+        let dir = tempfile::tempdir().unwrap();
+        let file = dir.path().join("config.toml");
+        std::fs::write(&file, "port = 7860\n").unwrap();
+
+        let changes = Arc::new(AtomicUsize::new(0));
+        let counter = changes.clone();
+        let watcher = spawn_watcher(vec![file.clone()], Duration::from_millis(20), move || {
+            let counter = counter.clone();
+            async move {
+                counter.fetch_add(1, Ordering::SeqCst);
+            }
+        });
+
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        assert_eq!(changes.load(Ordering::SeqCst), 0);
+
+        std::fs::write(&file, "port = 8080\n").unwrap();
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        assert_eq!(changes.load(Ordering::SeqCst), 1);
+
+        std::fs::remove_file(&file).unwrap();
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        assert_eq!(changes.load(Ordering::SeqCst), 2);
+        watcher.abort();
+    }
+}
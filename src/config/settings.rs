@@ -1,15 +1,256 @@
-use anyhow::Result;
+use anyhow::{Context, Result};
 use serde::{Deserialize, Serialize};
 use std::env;
-use std::path::PathBuf;
-use std::collections::HashSet;
+use std::cell::RefCell;
+use std::path::{Path, PathBuf};
+use std::collections::{HashMap, HashSet};
+use std::str::FromStr;
+
+use crate::models::variant::ModelVariant;
+
+/// Default upstream for Gemini API calls; the API version is appended per request
+pub const DEFAULT_GEMINI_BASE_URL: &str = "https://generativelanguage.googleapis.com";
+
+/// Gemini API versions accepted for `api_version`
+pub const GEMINI_API_VERSIONS: &[&str] = &["v1", "v1beta", "v1alpha"];
+
+/// API version used unless `api_version` or a model override says otherwise
+pub const DEFAULT_GEMINI_API_VERSION: &str = "v1beta";
+
+/// Environment variable naming an optional TOML or YAML settings file
+pub const CONFIG_PATH_ENV: &str = "RUJIMI_CONFIG";
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
 pub struct SearchConfig {
     pub search_mode: bool,
     pub search_prompt: String,
 }
 
+impl Default for SearchConfig {
+    fn default() -> Self {
+        Self {
+            search_mode: false,
+            search_prompt: "（使用搜索工具联网搜索，需要在content中结合搜索内容）".to_string(),
+        }
+    }
+}
+
+/// Per-model parameter caps and defaults, keyed by model pattern in `Settings::model_overrides`
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct ModelOverride {
+    #[serde(default)]
+    pub max_output_tokens_cap: Option<u32>,
+    #[serde(default)]
+    pub default_temperature: Option<f32>,
+    #[serde(default)]
+    pub default_top_p: Option<f32>,
+    #[serde(default)]
+    pub force_safety_threshold: Option<String>,
+    /// Overrides `Settings::use_native_openai_endpoint` for matching models
+    #[serde(default)]
+    pub use_native_openai_endpoint: Option<bool>,
+    /// Overrides `Settings::api_version` for matching models
+    #[serde(default)]
+    pub api_version: Option<String>,
+}
+
+/// What a client access key may do: `user` keys call the API, `admin` keys also manage the dashboard
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ClientKeyScope {
+    #[default]
+    User,
+    Admin,
+}
+
+/// Provider a chat completion is retried on once its own upstream is out of keys or quota
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum FallbackProvider {
+    #[default]
+    None,
+    /// Gemini API requests fall back to Vertex AI
+    Vertex,
+    /// Vertex AI requests fall back to the Gemini API
+    Gemini,
+}
+
+impl FallbackProvider {
+    /// `none`, `vertex` or `gemini`; anything else disables fallback
+    pub fn parse(value: &str) -> Self {
+        match value.trim().trim_matches('"').to_ascii_lowercase().as_str() {
+            "vertex" => Self::Vertex,
+            "gemini" => Self::Gemini,
+            _ => Self::None,
+        }
+    }
+
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Self::None => "none",
+            Self::Vertex => "vertex",
+            Self::Gemini => "gemini",
+        }
+    }
+}
+
+/// Where stats, key state, cache entries and settings snapshots are kept
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum StorageBackend {
+    /// Only in memory, lost on restart
+    #[default]
+    Memory,
+    /// Also written to `<storage_dir>/rujimi.db` and restored at startup
+    Sqlite,
+}
+
+impl StorageBackend {
+    /// `memory` or `sqlite`; anything else keeps everything in memory
+    pub fn parse(value: &str) -> Self {
+        match value.trim().trim_matches('"').to_ascii_lowercase().as_str() {
+            "sqlite" => Self::Sqlite,
+            _ => Self::Memory,
+        }
+    }
+
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Self::Memory => "memory",
+            Self::Sqlite => "sqlite",
+        }
+    }
+}
+
+/// Extra headers sent with upstream requests
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum UpstreamHeaderProfile {
+    /// Only the headers the API needs
+    #[default]
+    None,
+    /// A browser-like User-Agent, Accept-Language and client hints, fixed per API key
+    Browser,
+}
+
+impl UpstreamHeaderProfile {
+    /// `none` or `browser`; anything else sends no extra headers
+    pub fn parse(value: &str) -> Self {
+        match value.trim().trim_matches('"').to_ascii_lowercase().as_str() {
+            "browser" => Self::Browser,
+            _ => Self::None,
+        }
+    }
+
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Self::None => "none",
+            Self::Browser => "browser",
+        }
+    }
+}
+
+/// How `executableCode` / `codeExecutionResult` parts appear in OpenAI message content
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum CodeExecutionRender {
+    /// Fenced code block followed by an `output` block
+    #[default]
+    Markdown,
+    /// Left out of the content
+    Omit,
+}
+
+impl CodeExecutionRender {
+    /// `markdown` or `omit`; anything else renders markdown
+    pub fn parse(value: &str) -> Self {
+        match value.trim().trim_matches('"').to_ascii_lowercase().as_str() {
+            "omit" => Self::Omit,
+            _ => Self::Markdown,
+        }
+    }
+}
+
+/// What to do with a conversation estimated to exceed the model's input window
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ContextTrimStrategy {
+    /// Send it as it is and let Gemini refuse it
+    #[default]
+    Off,
+    /// Leave out the oldest messages other than system messages until it fits
+    DropOldest,
+    /// Refuse it with a 400 before calling Gemini
+    Error,
+}
+
+impl ContextTrimStrategy {
+    /// `off`, `drop_oldest` or `error`; anything else is `off`
+    pub fn parse(value: &str) -> Self {
+        match value.trim().trim_matches('"').to_ascii_lowercase().as_str() {
+            "drop_oldest" => Self::DropOldest,
+            "error" => Self::Error,
+            _ => Self::Off,
+        }
+    }
+}
+
+/// A named access key handed out to one client, with optional daily quotas (0 = unlimited)
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ClientKey {
+    pub key: String,
+    pub name: String,
+    #[serde(default)]
+    pub scope: ClientKeyScope,
+    #[serde(default)]
+    pub daily_request_limit: u32,
+    #[serde(default)]
+    pub daily_token_limit: u64,
+    /// Models this key may use; empty allows every model
+    #[serde(default)]
+    pub allowed_models: Vec<String>,
+    /// Requests let through per round-robin turn under `fair_queuing`; callers without a
+    /// client key count 1
+    #[serde(default = "default_client_key_weight")]
+    pub weight: u32,
+}
+
+fn default_client_key_weight() -> u32 {
+    1
+}
+
+impl ClientKey {
+    pub fn allows_model(&self, model: &str) -> bool {
+        self.allowed_models.is_empty()
+            || self.allowed_models.iter().any(|pattern| model_name_matches(pattern, model))
+    }
+}
+
+/// A blocked address or CIDR range, optionally lifted at `expires_at` (unix seconds)
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct IpBlockEntry {
+    pub cidr: String,
+    #[serde(default)]
+    pub expires_at: Option<u64>,
+    #[serde(default)]
+    pub reason: Option<String>,
+}
+
+impl IpBlockEntry {
+    pub fn permanent(cidr: &str) -> Self {
+        Self {
+            cidr: cidr.to_string(),
+            expires_at: None,
+            reason: None,
+        }
+    }
+
+    pub fn is_active(&self, now: u64) -> bool {
+        self.expires_at.is_none_or(|expires_at| expires_at > now)
+    }
+}
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct ApiCallStats {
     pub calls: Vec<serde_json::Value>,
@@ -22,14 +263,99 @@ pub struct VersionInfo {
     pub has_update: bool,
 }
 
+/// Missing fields take their `Default` values, so config files only list what they change
 #[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
 pub struct Settings {
     // Basic configuration
     pub password: String,
     pub web_password: String,
+    /// Lifetime of dashboard session tokens issued by `/api/auth/login`
+    #[serde(default = "default_session_ttl_secs")]
+    pub session_ttl_secs: u64,
+    /// Failed dashboard logins per client IP (within the window) before a lockout
+    #[serde(default = "default_login_max_failures")]
+    pub login_max_failures: u32,
+    #[serde(default = "default_login_failure_window_secs")]
+    pub login_failure_window_secs: u64,
+    #[serde(default = "default_login_lockout_secs")]
+    pub login_lockout_secs: u64,
     pub gemini_api_keys: Vec<String>,
     pub port: Option<u16>,
 
+    // TLS configuration (HTTPS is served when both paths are set)
+    #[serde(default)]
+    pub tls_cert_path: String,
+    #[serde(default)]
+    pub tls_key_path: String,
+    #[serde(default)]
+    pub tls_reload_interval_secs: u64,
+
+    // Unix socket listener (served in addition to TCP unless listen_tcp is false)
+    #[serde(default)]
+    pub listen_socket: String,
+    #[serde(default = "default_listen_socket_mode")]
+    pub listen_socket_mode: String,
+    #[serde(default = "default_true")]
+    pub listen_tcp: bool,
+    #[serde(default = "default_gemini_base_url")]
+    pub gemini_base_url: String,
+    /// Gemini API version (`v1`, `v1beta` or `v1alpha`) appended to the base URL
+    #[serde(default = "default_api_version")]
+    pub api_version: String,
+    /// Base URLs tried in order, failing over to the next when one is unreachable or answers
+    /// 5xx; empty means `gemini_base_url` alone
+    #[serde(default)]
+    pub gemini_endpoints: Vec<String>,
+    /// Consecutive failures after which an endpoint is skipped
+    #[serde(default = "default_endpoint_failure_threshold")]
+    pub endpoint_failure_threshold: u32,
+    /// How long a failing endpoint is skipped before it is tried again
+    #[serde(default = "default_endpoint_cooldown_secs")]
+    pub endpoint_cooldown_secs: u64,
+    /// Incoming request headers copied onto upstream calls, such as `x-goog-user-project` or
+    /// billing labels; credentials and `host` are never forwarded
+    #[serde(default)]
+    pub forward_request_headers: Vec<String>,
+    /// Stream without `alt=sse`, as a JSON array, for mirrors that only serve that form
+    #[serde(default)]
+    pub gemini_array_stream: bool,
+    /// Forward chat completions to Gemini's OpenAI-compatible endpoint instead of converting them
+    #[serde(default)]
+    pub use_native_openai_endpoint: bool,
+    /// Let callers switch safety settings per request with `X-Rujimi-Safety`
+    #[serde(default)]
+    pub allow_safety_override: bool,
+    /// Add diagnostic response headers such as `X-Dropped-Params`
+    #[serde(default)]
+    pub debug_headers: bool,
+    /// Reject with a 400 the request fields that cannot be honored, such as `logprobs` or
+    /// `n > 1`, instead of dropping them
+    #[serde(default)]
+    pub strict_openai_compat: bool,
+    /// Rendering of Gemini code execution parts in chat completion content
+    #[serde(default)]
+    pub code_execution_render: CodeExecutionRender,
+    /// Answer non-streaming chat completions with `content` as an array of `text` and
+    /// `image_url` parts instead of one string; a request's `array_content` field decides
+    /// for itself
+    #[serde(default)]
+    pub array_content_responses: bool,
+    /// Handling of conversations longer than the model's `inputTokenLimit`, less `max_tokens`
+    #[serde(default)]
+    pub context_trim_strategy: ContextTrimStrategy,
+    /// Send long system prompts as Gemini `cachedContents` instead of inlining them every turn
+    #[serde(default)]
+    pub enable_context_caching: bool,
+    /// Estimated system prompt tokens below which context caching is not used
+    #[serde(default = "default_context_cache_min_tokens")]
+    pub context_cache_min_tokens: u32,
+    #[serde(default = "default_context_cache_ttl_secs")]
+    pub context_cache_ttl_secs: u64,
+    /// Count tokens with Gemini's countTokens where usage would otherwise be estimated
+    #[serde(default)]
+    pub accurate_token_estimates: bool,
+
     // Streaming configuration
     pub fake_streaming: bool,
     pub fake_streaming_interval: f64,
@@ -39,6 +365,21 @@ pub struct Settings {
     // Storage configuration
     pub storage_dir: String,
     pub enable_storage: bool,
+    #[serde(default)]
+    pub storage_backend: StorageBackend,
+    /// Redis shared by replicas for key usage, cooldowns and invalid marks; empty keeps key
+    /// state local to this instance
+    #[serde(default)]
+    pub key_state_redis_url: String,
+    /// Write an NDJSON line per /v1 request to `<storage_dir>/audit/`
+    #[serde(default)]
+    pub audit_log_enabled: bool,
+    /// Also keep the start of each prompt and response, with a hash of the full text
+    #[serde(default)]
+    pub audit_log_include_content: bool,
+    /// A day's audit file is continued in a new part once it reaches this size
+    #[serde(default = "default_audit_log_max_file_mb")]
+    pub audit_log_max_file_mb: u64,
 
     // Concurrency configuration
     pub concurrent_requests: usize,
@@ -50,12 +391,31 @@ pub struct Settings {
     pub max_cache_entries: usize,
     pub calculate_cache_entries: usize,
     pub precise_cache: bool,
+    /// Answers with fewer completion tokens than this are not cached
+    #[serde(default)]
+    pub min_tokens_to_cache: u32,
 
     // Vertex AI configuration
     pub enable_vertex: bool,
     pub google_credentials_json: String,
     pub enable_vertex_express: bool,
     pub vertex_express_api_key: String,
+    /// Service account files for Vertex; empty means `<storage_dir>/credentials`
+    #[serde(default)]
+    pub credentials_dir: String,
+    #[serde(default)]
+    pub vertex_project_id: String,
+    #[serde(default = "default_vertex_location")]
+    pub vertex_location: String,
+    /// Where the Vertex model list is fetched from; empty uses the built-in default
+    #[serde(default)]
+    pub vertex_models_config_url: String,
+    /// Vertex AI API root; empty uses the regional `aiplatform.googleapis.com` endpoint
+    #[serde(default)]
+    pub vertex_base_url: String,
+    /// Where chat completions go when the primary provider has no usable key or quota left
+    #[serde(default)]
+    pub fallback_provider: FallbackProvider,
 
     // Search configuration
     pub search: SearchConfig,
@@ -71,26 +431,176 @@ pub struct Settings {
     pub max_requests_per_minute: u32,
     pub max_requests_per_day_per_ip: u32,
     pub api_key_daily_limit: u32,
+    /// Requests per minute sent on any one key; 0 splits `max_requests_per_minute` across the keys
+    #[serde(default)]
+    pub per_key_rpm: u32,
+    /// Serve every turn of a conversation with the same API key while it stays healthy
+    #[serde(default)]
+    pub sticky_keys: bool,
+    /// Race a non-streaming request on a second key when the first has not answered within
+    /// this many milliseconds; 0 disables hedging
+    #[serde(default)]
+    pub hedge_after_ms: u64,
+    /// How long a chat request waits for a key when every key is cooling down; 0 fails at once
+    #[serde(default)]
+    pub max_key_wait_ms: u64,
+    /// Seconds a chat completion may take before it is abandoned with a 504; for streams
+    /// only the wait for the first chunk counts. 0 disables the deadline
+    #[serde(default)]
+    pub request_deadline_secs: u64,
+    /// Queue chat requests per caller (client key, else IP) and let them through to key
+    /// selection in weighted round-robin, so one busy caller cannot starve the others
+    #[serde(default)]
+    pub fair_queuing: bool,
+    /// Chat requests past the fair queue at once
+    #[serde(default = "default_fair_queue_concurrency")]
+    pub fair_queue_concurrency: usize,
+    /// Least time between the starts of two requests from one caller under `fair_queuing`;
+    /// 0 disables
+    #[serde(default)]
+    pub min_request_interval_ms: u64,
 
     // Model filtering
     pub blocked_models: HashSet<String>,
     pub whitelist_models: HashSet<String>,
     pub whitelist_user_agent: HashSet<String>,
 
+    // Client IP filtering (CIDR or single addresses); an empty allowlist allows everyone
+    #[serde(default)]
+    pub ip_allowlist: Vec<String>,
+    #[serde(default)]
+    pub ip_blocklist: Vec<IpBlockEntry>,
+    /// Reverse proxies whose `X-Forwarded-For`, `X-Real-IP` and `CF-Connecting-IP` headers are
+    /// believed; other peers are taken as the client themselves
+    #[serde(default)]
+    pub trusted_proxies: Vec<String>,
+
+    // Per-model parameter overrides (pattern -> override), `*` acts as a wildcard
+    #[serde(default)]
+    pub model_overrides: HashMap<String, ModelOverride>,
+    /// Transcribes `/v1/audio/transcriptions` uploads unless the request names a Gemini model
+    #[serde(default = "default_transcription_model")]
+    pub transcription_model: String,
+
+    // Named client access keys, in addition to the shared `password`
+    #[serde(default)]
+    pub client_keys: Vec<ClientKey>,
+
     // Other configuration
     pub public_mode: bool,
+    // Restrictions for anonymous callers admitted by `public_mode`
+    #[serde(default)]
+    pub public_allowed_models: Vec<String>,
+    #[serde(default = "default_public_requests_per_minute_per_ip")]
+    pub public_requests_per_minute_per_ip: u32,
+    #[serde(default = "default_public_max_tokens")]
+    pub public_max_tokens: u32,
     pub dashboard_url: String,
+    /// Directory the dashboard is served from instead of the built-in or `assets/` copy
+    #[serde(default)]
+    pub static_dir: String,
     pub allowed_origins: Vec<String>,
 
     // Network configuration
     pub nonstream_keepalive_enabled: bool,
     pub nonstream_keepalive_interval: f64,
+    #[serde(default = "default_sse_heartbeat_interval_secs")]
+    pub sse_heartbeat_interval_secs: u64,
+    #[serde(default = "default_max_request_body_mb")]
+    pub max_request_body_mb: u64,
+    /// Compress responses for clients that accept it; event streams never are
+    #[serde(default = "default_true")]
+    pub compression_enabled: bool,
+    /// Encodings offered, any of `gzip`, `br` and `zstd`
+    #[serde(default = "default_compression_algorithms")]
+    pub compression_algorithms: Vec<String>,
+    /// Bodies smaller than this are sent uncompressed
+    #[serde(default = "default_compression_min_size_bytes")]
+    pub compression_min_size_bytes: u16,
+    /// Reload the upstream model list this often, 0 disables the background refresh
+    #[serde(default = "default_model_refresh_interval_secs")]
+    pub model_refresh_interval_secs: u64,
+    /// Open the upstream connections at startup with a model list fetch and a one-token request
+    #[serde(default)]
+    pub warmup_on_start: bool,
+    /// Model asked for the one-token warm-up request
+    #[serde(default = "default_warmup_model")]
+    pub warmup_model: String,
+    /// Append the hashed `user` field of a request to its prompt as a trailing note, so Google
+    /// can name the end user when it flags content; Vertex AI calls carry it as a label instead
+    #[serde(default)]
+    pub end_user_note: bool,
+    /// Recent calls per model the circuit breaker looks at, 0 disables it
+    #[serde(default = "default_circuit_breaker_window")]
+    pub circuit_breaker_window: u32,
+    /// Share of failed calls in a full window that opens a model's circuit
+    #[serde(default = "default_circuit_breaker_threshold_percent")]
+    pub circuit_breaker_threshold_percent: u32,
+    /// How long an open circuit refuses requests before letting a probe through
+    #[serde(default = "default_circuit_breaker_cooldown_secs")]
+    pub circuit_breaker_cooldown_secs: u64,
+    /// How often `/dashboard-api/stream` pushes a snapshot when nothing significant happens
+    #[serde(default = "default_dashboard_stream_interval_secs")]
+    pub dashboard_stream_interval_secs: u64,
+    /// The health check clears caches, stats and logs when memory use reaches this percentage,
+    /// 0 disables it
+    #[serde(default = "default_emergency_cleanup_memory_percent")]
+    pub emergency_cleanup_memory_percent: f64,
+    /// Entries kept by the dashboard's log buffer before the oldest are dropped
+    #[serde(default = "default_log_buffer_size")]
+    pub log_buffer_size: usize,
+    /// Entries kept by the Vertex AI log buffer
+    #[serde(default = "default_log_buffer_size")]
+    pub vertex_log_buffer_size: usize,
+    /// Log buffer entries older than this are pruned hourly, 0 keeps them until they roll over
+    #[serde(default = "default_log_retention_secs")]
+    pub log_retention_secs: u64,
+    #[serde(default)]
+    pub upstream_proxy: String,
+    #[serde(default)]
+    pub upstream_proxy_auth: String,
+    #[serde(default)]
+    pub no_proxy: Vec<String>,
+    #[serde(default)]
+    pub upstream_header_profile: UpstreamHeaderProfile,
+    #[serde(default = "default_upstream_connect_timeout_secs")]
+    pub upstream_connect_timeout_secs: u64,
+    #[serde(default = "default_upstream_request_timeout_secs")]
+    pub upstream_request_timeout_secs: u64,
+    #[serde(default = "default_stream_idle_timeout_secs")]
+    pub stream_idle_timeout_secs: u64,
+    /// Extra attempts for an upstream call whose connection failed before Gemini saw it
+    /// (DNS, refused, reset); these do not count against the key
+    #[serde(default = "default_network_retries")]
+    pub network_retries: u32,
+    /// Wait before the first of those retries, doubled for each one after it
+    #[serde(default = "default_network_retry_backoff_ms")]
+    pub network_retry_backoff_ms: u64,
+    /// Refuse to start when the settings have problems instead of logging warnings
+    #[serde(default)]
+    pub strict_config: bool,
+    /// Check GitHub daily for a newer release; turn off for air-gapped deployments
+    #[serde(default = "default_true")]
+    pub update_check: bool,
+    /// Answer chat, embedding and model list calls with canned responses instead of calling
+    /// Gemini, for development and CI without keys
+    #[serde(default)]
+    pub mock_upstream: bool,
+    /// Delay added to every mock call
+    #[serde(default)]
+    pub mock_latency_ms: u64,
+    /// Share of mock calls, from 0 to 1, that fail with a 503
+    #[serde(default)]
+    pub mock_fail_rate: f64,
 
     // Runtime information
     pub base_dir: PathBuf,
     pub invalid_api_keys: Vec<String>,
     pub version: VersionInfo,
     pub api_call_stats: ApiCallStats,
+    /// Problems found when the settings were loaded, shown on the dashboard
+    #[serde(skip)]
+    pub config_warnings: Vec<String>,
 }
 
 impl Default for Settings {
@@ -98,8 +608,36 @@ impl Default for Settings {
         Self {
             password: "123".to_string(),
             web_password: "123".to_string(),
+            session_ttl_secs: default_session_ttl_secs(),
+            login_max_failures: default_login_max_failures(),
+            login_failure_window_secs: default_login_failure_window_secs(),
+            login_lockout_secs: default_login_lockout_secs(),
             gemini_api_keys: Vec::new(),
             port: Some(7860),
+            tls_cert_path: String::new(),
+            tls_key_path: String::new(),
+            tls_reload_interval_secs: 0,
+            listen_socket: String::new(),
+            listen_socket_mode: default_listen_socket_mode(),
+            listen_tcp: true,
+            gemini_base_url: DEFAULT_GEMINI_BASE_URL.to_string(),
+            api_version: default_api_version(),
+            gemini_endpoints: Vec::new(),
+            forward_request_headers: Vec::new(),
+            endpoint_failure_threshold: default_endpoint_failure_threshold(),
+            endpoint_cooldown_secs: default_endpoint_cooldown_secs(),
+            gemini_array_stream: false,
+            use_native_openai_endpoint: false,
+            allow_safety_override: false,
+            debug_headers: false,
+            strict_openai_compat: false,
+            code_execution_render: CodeExecutionRender::Markdown,
+            array_content_responses: false,
+            context_trim_strategy: ContextTrimStrategy::Off,
+            enable_context_caching: false,
+            context_cache_min_tokens: default_context_cache_min_tokens(),
+            context_cache_ttl_secs: default_context_cache_ttl_secs(),
+            accurate_token_estimates: false,
 
             fake_streaming: true,
             fake_streaming_interval: 1.0,
@@ -108,6 +646,11 @@ impl Default for Settings {
 
             storage_dir: "/rujimi/settings/".to_string(),
             enable_storage: false,
+            storage_backend: StorageBackend::Memory,
+            key_state_redis_url: String::new(),
+            audit_log_enabled: false,
+            audit_log_include_content: false,
+            audit_log_max_file_mb: default_audit_log_max_file_mb(),
 
             concurrent_requests: 1,
             increase_concurrent_on_failure: 0,
@@ -117,16 +660,20 @@ impl Default for Settings {
             max_cache_entries: 500,
             calculate_cache_entries: 6,
             precise_cache: false,
+            min_tokens_to_cache: 0,
 
             enable_vertex: false,
             google_credentials_json: String::new(),
             enable_vertex_express: false,
             vertex_express_api_key: String::new(),
+            credentials_dir: String::new(),
+            vertex_project_id: String::new(),
+            vertex_location: default_vertex_location(),
+            vertex_models_config_url: String::new(),
+            vertex_base_url: String::new(),
+            fallback_provider: FallbackProvider::None,
 
-            search: SearchConfig {
-                search_mode: false,
-                search_prompt: "（使用搜索工具联网搜索，需要在content中结合搜索内容）".to_string(),
-            },
+            search: SearchConfig::default(),
 
             random_string: true,
             random_string_length: 5,
@@ -137,17 +684,68 @@ impl Default for Settings {
             max_requests_per_minute: 30,
             max_requests_per_day_per_ip: 600,
             api_key_daily_limit: 100,
+            per_key_rpm: 0,
+            sticky_keys: false,
+            hedge_after_ms: 0,
+            max_key_wait_ms: 0,
+            request_deadline_secs: 0,
+            fair_queuing: false,
+            fair_queue_concurrency: default_fair_queue_concurrency(),
+            min_request_interval_ms: 0,
 
             blocked_models: HashSet::new(),
             whitelist_models: HashSet::new(),
             whitelist_user_agent: HashSet::new(),
 
+            ip_allowlist: Vec::new(),
+            ip_blocklist: Vec::new(),
+            trusted_proxies: Vec::new(),
+
+            model_overrides: HashMap::new(),
+            transcription_model: default_transcription_model(),
+            client_keys: Vec::new(),
+
             public_mode: false,
+            public_allowed_models: Vec::new(),
+            public_requests_per_minute_per_ip: default_public_requests_per_minute_per_ip(),
+            public_max_tokens: default_public_max_tokens(),
             dashboard_url: String::new(),
+            static_dir: String::new(),
             allowed_origins: Vec::new(),
 
             nonstream_keepalive_enabled: true,
             nonstream_keepalive_interval: 5.0,
+            sse_heartbeat_interval_secs: default_sse_heartbeat_interval_secs(),
+            max_request_body_mb: default_max_request_body_mb(),
+            compression_enabled: true,
+            compression_algorithms: default_compression_algorithms(),
+            compression_min_size_bytes: default_compression_min_size_bytes(),
+            model_refresh_interval_secs: default_model_refresh_interval_secs(),
+            warmup_on_start: false,
+            warmup_model: default_warmup_model(),
+            end_user_note: false,
+            circuit_breaker_window: default_circuit_breaker_window(),
+            circuit_breaker_threshold_percent: default_circuit_breaker_threshold_percent(),
+            circuit_breaker_cooldown_secs: default_circuit_breaker_cooldown_secs(),
+            dashboard_stream_interval_secs: default_dashboard_stream_interval_secs(),
+            emergency_cleanup_memory_percent: default_emergency_cleanup_memory_percent(),
+            log_buffer_size: default_log_buffer_size(),
+            vertex_log_buffer_size: default_log_buffer_size(),
+            log_retention_secs: default_log_retention_secs(),
+            upstream_proxy: String::new(),
+            upstream_proxy_auth: String::new(),
+            no_proxy: Vec::new(),
+            upstream_header_profile: UpstreamHeaderProfile::None,
+            upstream_connect_timeout_secs: default_upstream_connect_timeout_secs(),
+            upstream_request_timeout_secs: default_upstream_request_timeout_secs(),
+            stream_idle_timeout_secs: default_stream_idle_timeout_secs(),
+            network_retries: default_network_retries(),
+            network_retry_backoff_ms: default_network_retry_backoff_ms(),
+            strict_config: false,
+            update_check: true,
+            mock_upstream: false,
+            mock_latency_ms: 0,
+            mock_fail_rate: 0.0,
 
             base_dir: std::env::current_dir().unwrap_or_else(|_| PathBuf::from(".")),
             invalid_api_keys: Vec::new(),
@@ -159,105 +757,418 @@ impl Default for Settings {
             api_call_stats: ApiCallStats {
                 calls: Vec::new(),
             },
+            config_warnings: Vec::new(),
         }
     }
 }
 
 impl Settings {
+    /// Settings for the server. Precedence, lowest first: the built-in defaults, the TOML or
+    /// YAML file named by `RUJIMI_CONFIG`, then environment variables (including `.env`), so a
+    /// variable only replaces a file value when it is set.
     pub fn load() -> Result<Self> {
         dotenvy::dotenv().ok();
 
-        let mut settings = Self::default();
+        let settings = match env::var(CONFIG_PATH_ENV) {
+            Ok(path) if !path.trim().is_empty() => Self::load_from_file(path.trim())?,
+            _ => Self::default(),
+        };
+        let env = EnvOverrides::new(|name| env::var(name).ok());
+        let mut settings = settings.apply_env(&env);
+        settings.config_warnings = env.problems.into_inner();
 
-        // Load from environment variables
-        settings.password = env::var("PASSWORD").unwrap_or_else(|_| "123".to_string()).trim_matches('"').to_string();
-        settings.web_password = env::var("WEB_PASSWORD").unwrap_or_else(|_| settings.password.clone()).trim_matches('"').to_string();
+        // Set base directory
+        if let Ok(current_dir) = env::current_dir() {
+            if let Some(parent) = current_dir.parent() {
+                if let Some(parent) = parent.parent() {
+                    settings.base_dir = parent.to_path_buf();
+                }
+            }
+        }
+
+        Ok(settings)
+    }
+
+    /// Read settings from a `.toml`, `.yaml` or `.yml` file. Keys use the field names of
+    /// `Settings`; missing keys keep their defaults.
+    pub fn load_from_file(path: impl AsRef<Path>) -> Result<Self> {
+        let path = path.as_ref();
+        let format = ConfigFormat::from_path(path)?;
+        let text = std::fs::read_to_string(path)
+            .with_context(|| format!("Failed to read config file {}", path.display()))?;
+        Self::from_config_str(&text, format).with_context(|| format!("Invalid config file {}", path.display()))
+    }
+
+    fn from_config_str(text: &str, format: ConfigFormat) -> Result<Self> {
+        let mut settings: Self = match format {
+            ConfigFormat::Toml => serde_path_to_error::deserialize(toml::Deserializer::new(text))
+                .map_err(|e| config_error(e.path(), e.inner()))?,
+            ConfigFormat::Yaml => serde_path_to_error::deserialize(serde_yaml::Deserializer::from_str(text))
+                .map_err(|e| config_error(e.path(), e.inner()))?,
+        };
+        // The keys as written, to warn about typos and tell unset keys from defaults
+        let raw: serde_json::Value = match format {
+            ConfigFormat::Toml => serde_json::to_value(toml::from_str::<toml::Table>(text)?)?,
+            ConfigFormat::Yaml => serde_yaml::from_str(text)?,
+        };
+
+        let known = serde_json::to_value(Self::default())?;
+        if let (Some(raw), Some(known)) = (raw.as_object(), known.as_object()) {
+            for key in raw.keys().filter(|key| !known.contains_key(*key)) {
+                tracing::warn!("Ignoring unknown config key `{}`", key);
+            }
+            // Like WEB_PASSWORD, web_password follows password unless given
+            if !raw.contains_key("web_password") {
+                settings.web_password = settings.password.clone();
+            }
+        }
+        settings.gemini_base_url = normalize_base_url(&settings.gemini_base_url);
+        settings.gemini_endpoints = settings.gemini_endpoints.iter().map(|url| normalize_base_url(url)).collect();
+        settings.vertex_base_url = normalize_base_url(&settings.vertex_base_url);
+        Ok(settings)
+    }
+
+    /// Check the settings that would otherwise only fail on the first upstream request
+    pub fn validate(&self) -> Result<()> {
+        match url::Url::parse(&self.gemini_base_url) {
+            Ok(url) if matches!(url.scheme(), "http" | "https") => {}
+            _ => anyhow::bail!("Invalid value for `gemini_base_url`: expected an http(s) URL"),
+        }
+        for endpoint in &self.gemini_endpoints {
+            match url::Url::parse(endpoint) {
+                Ok(url) if matches!(url.scheme(), "http" | "https") => {}
+                _ => anyhow::bail!("Invalid entry {:?} in `gemini_endpoints`: expected an http(s) URL", endpoint),
+            }
+        }
+        if !GEMINI_API_VERSIONS.contains(&self.api_version.as_str()) {
+            anyhow::bail!("Invalid value for `api_version`: `{}` is not one of {}", self.api_version, GEMINI_API_VERSIONS.join(", "));
+        }
+        for (pattern, model_override) in &self.model_overrides {
+            if let Some(version) = model_override.api_version.as_deref().filter(|version| !GEMINI_API_VERSIONS.contains(version)) {
+                anyhow::bail!("Invalid value for `model_overrides.{}.api_version`: `{}` is not one of {}", pattern, version, GEMINI_API_VERSIONS.join(", "));
+            }
+        }
+        for (key, secs) in [
+            ("upstream_connect_timeout_secs", self.upstream_connect_timeout_secs),
+            ("upstream_request_timeout_secs", self.upstream_request_timeout_secs),
+            ("stream_idle_timeout_secs", self.stream_idle_timeout_secs),
+            ("audit_log_max_file_mb", self.audit_log_max_file_mb),
+            ("dashboard_stream_interval_secs", self.dashboard_stream_interval_secs),
+        ] {
+            if secs == 0 {
+                anyhow::bail!("Invalid value for `{}`: must be greater than 0", key);
+            }
+        }
+        if !(0.0..=100.0).contains(&self.emergency_cleanup_memory_percent) {
+            anyhow::bail!("Invalid value for `emergency_cleanup_memory_percent`: must be between 0 and 100");
+        }
+        if self.log_buffer_size == 0 || self.vertex_log_buffer_size == 0 {
+            anyhow::bail!("Invalid value for `log_buffer_size` or `vertex_log_buffer_size`: must be at least 1");
+        }
+        if self.fair_queue_concurrency == 0 {
+            anyhow::bail!("Invalid value for `fair_queue_concurrency`: must be at least 1");
+        }
+        if !(0.0..=1.0).contains(&self.mock_fail_rate) {
+            anyhow::bail!("Invalid value for `mock_fail_rate`: must be between 0 and 1");
+        }
+        if self.transcription_model.trim().is_empty() {
+            anyhow::bail!("Invalid value for `transcription_model`: must name a Gemini model");
+        }
+        if self.warmup_model.trim().is_empty() {
+            anyhow::bail!("Invalid value for `warmup_model`: must name a Gemini model");
+        }
+        if !(1..=100).contains(&self.circuit_breaker_threshold_percent) {
+            anyhow::bail!("Invalid value for `circuit_breaker_threshold_percent`: must be between 1 and 100");
+        }
+        if let Some(unknown) = self.compression_algorithms.iter().find(|algorithm| !matches!(algorithm.as_str(), "gzip" | "br" | "zstd")) {
+            anyhow::bail!("Invalid value for `compression_algorithms`: `{}` is not one of gzip, br, zstd", unknown);
+        }
+        if !self.key_state_redis_url.is_empty() {
+            match url::Url::parse(&self.key_state_redis_url) {
+                Ok(url) if matches!(url.scheme(), "redis" | "redis+unix" | "unix") => {}
+                _ => anyhow::bail!("Invalid value for `key_state_redis_url`: expected a redis:// URL"),
+            }
+        }
+        crate::utils::http_client::build_upstream_proxy(self)?;
+        Ok(())
+    }
+
+    /// Collect every problem with the settings: values that could not be parsed, no API keys
+    /// without Vertex, an unwritable storage directory, models both allowed and blocked and
+    /// an invalid port. With `strict_config` any problem is an error listing all of them;
+    /// otherwise each is logged and kept in `config_warnings`.
+    pub async fn checked(mut self) -> Result<Self> {
+        let mut problems = std::mem::take(&mut self.config_warnings);
+        if self.get_valid_api_keys().is_empty() && !self.vertex_enabled() && !self.mock_upstream {
+            problems.push("No Gemini API keys are configured (GEMINI_API_KEYS) and Vertex is disabled".to_string());
+        }
+        if self.enable_storage {
+            if let Err(e) = crate::utils::health::check_storage_writable(&self.storage_dir).await {
+                problems.push(format!("Storage is enabled but STORAGE_DIR is not writable: {}", e));
+            }
+        }
+        let mut overlap: Vec<&String> = self.whitelist_models.intersection(&self.blocked_models).collect();
+        if !overlap.is_empty() {
+            overlap.sort();
+            problems.push(format!(
+                "Models in both WHITELIST_MODELS and BLOCKED_MODELS are blocked: {}",
+                overlap.iter().map(|model| model.as_str()).collect::<Vec<_>>().join(", ")
+            ));
+        }
+        if self.port == Some(0) {
+            problems.push("PORT must be between 1 and 65535".to_string());
+        }
+        if self.min_request_interval_ms > 0 && !self.fair_queuing {
+            problems.push("MIN_REQUEST_INTERVAL_MS only applies with FAIR_QUEUING on".to_string());
+        }
+        for name in &self.forward_request_headers {
+            if crate::utils::browser::NEVER_FORWARDED_HEADERS.contains(&name.to_lowercase().as_str()) {
+                problems.push(format!("FORWARD_REQUEST_HEADERS lists {}, which is never forwarded", name));
+            }
+        }
+
+        if self.strict_config && !problems.is_empty() {
+            anyhow::bail!("Invalid settings (STRICT_CONFIG is on):\n  - {}", problems.join("\n  - "));
+        }
+        for problem in &problems {
+            tracing::warn!("⚠️  Config: {}", problem);
+        }
+        self.config_warnings = problems;
+        Ok(self)
+    }
+
+    /// Replace each setting whose environment variable is set
+    fn apply_env<F: Fn(&str) -> Option<String>>(mut self, env: &EnvOverrides<F>) -> Self {
+        self.password = env.string("PASSWORD", self.password);
+        // WEB_PASSWORD defaults to PASSWORD
+        self.web_password = match env.get("WEB_PASSWORD") {
+            Some(web_password) => web_password.trim_matches('"').to_string(),
+            None if env.get("PASSWORD").is_some() => self.password.clone(),
+            None => self.web_password,
+        };
+        self.session_ttl_secs = env.number("SESSION_TTL_SECS", self.session_ttl_secs);
+        self.login_max_failures = env.number("LOGIN_MAX_FAILURES", self.login_max_failures);
+        self.login_failure_window_secs = env.number("LOGIN_FAILURE_WINDOW_SECS", self.login_failure_window_secs);
+        self.login_lockout_secs = env.number("LOGIN_LOCKOUT_SECS", self.login_lockout_secs);
+        self.context_cache_min_tokens = env.number("CONTEXT_CACHE_MIN_TOKENS", self.context_cache_min_tokens);
+        self.context_cache_ttl_secs = env.number("CONTEXT_CACHE_TTL_SECS", self.context_cache_ttl_secs);
+        self.accurate_token_estimates = env.flag("ACCURATE_TOKEN_ESTIMATES", self.accurate_token_estimates);
 
         // Parse API keys
-        if let Ok(keys_str) = env::var("GEMINI_API_KEYS") {
-            settings.gemini_api_keys = keys_str
-                .split(',')
-                .map(|s| s.trim().to_string())
-                .filter(|s| !s.is_empty())
-                .collect();
+        if let Some(keys_str) = env.get("GEMINI_API_KEYS") {
+            self.gemini_api_keys = parse_comma_separated(&keys_str);
         }
 
-        if let Ok(port_str) = env::var("PORT") {
-            settings.port = Some(port_str.parse().unwrap_or(7860));
+        if let Some(port_str) = env.get("PORT") {
+            self.port = Some(env.parsed("PORT", &port_str).unwrap_or(7860));
         }
 
+        if let Some(base_url) = env.get("GEMINI_BASE_URL") {
+            if !base_url.trim().is_empty() {
+                self.gemini_base_url = normalize_base_url(base_url.trim_matches('"'));
+            }
+        }
+        if let Some(version) = env.get("GEMINI_API_VERSION") {
+            if !version.trim().is_empty() {
+                self.api_version = version.trim().to_string();
+            }
+        }
+        if let Some(endpoints) = env.get("GEMINI_ENDPOINTS") {
+            self.gemini_endpoints = parse_comma_separated(&endpoints).iter().map(|url| normalize_base_url(url)).collect();
+        }
+        if let Some(headers) = env.get("FORWARD_REQUEST_HEADERS") {
+            self.forward_request_headers = parse_comma_separated(&headers.to_lowercase());
+        }
+        self.endpoint_failure_threshold = env.number("ENDPOINT_FAILURE_THRESHOLD", self.endpoint_failure_threshold);
+        self.endpoint_cooldown_secs = env.number("ENDPOINT_COOLDOWN_SECS", self.endpoint_cooldown_secs);
+        self.gemini_array_stream = env.flag("GEMINI_ARRAY_STREAM", self.gemini_array_stream);
+
         // Boolean configurations
-        settings.fake_streaming = parse_bool(&env::var("FAKE_STREAMING").unwrap_or_else(|_| "true".to_string()));
-        settings.enable_storage = parse_bool(&env::var("ENABLE_STORAGE").unwrap_or_else(|_| "false".to_string()));
-        settings.enable_vertex = parse_bool(&env::var("ENABLE_VERTEX").unwrap_or_else(|_| "false".to_string()));
-        settings.enable_vertex_express = parse_bool(&env::var("ENABLE_VERTEX_EXPRESS").unwrap_or_else(|_| "false".to_string()));
-        settings.search.search_mode = parse_bool(&env::var("SEARCH_MODE").unwrap_or_else(|_| "false".to_string()));
-        settings.random_string = parse_bool(&env::var("RANDOM_STRING").unwrap_or_else(|_| "true".to_string()));
-        settings.show_api_error_message = parse_bool(&env::var("SHOW_API_ERROR_MESSAGE").unwrap_or_else(|_| "true".to_string()));
-        settings.precise_cache = parse_bool(&env::var("PRECISE_CACHE").unwrap_or_else(|_| "false".to_string()));
-        settings.public_mode = parse_bool(&env::var("PUBLIC_MODE").unwrap_or_else(|_| "false".to_string()));
-        settings.nonstream_keepalive_enabled = parse_bool(&env::var("NONSTREAM_KEEPALIVE_ENABLED").unwrap_or_else(|_| "true".to_string()));
+        self.fake_streaming = env.flag("FAKE_STREAMING", self.fake_streaming);
+        self.enable_storage = env.flag("ENABLE_STORAGE", self.enable_storage);
+        self.enable_vertex = env.flag("ENABLE_VERTEX", self.enable_vertex);
+        self.enable_vertex_express = env.flag("ENABLE_VERTEX_EXPRESS", self.enable_vertex_express);
+        self.search.search_mode = env.flag("SEARCH_MODE", self.search.search_mode);
+        self.random_string = env.flag("RANDOM_STRING", self.random_string);
+        self.show_api_error_message = env.flag("SHOW_API_ERROR_MESSAGE", self.show_api_error_message);
+        self.precise_cache = env.flag("PRECISE_CACHE", self.precise_cache);
+        self.public_mode = env.flag("PUBLIC_MODE", self.public_mode);
+        self.listen_tcp = env.flag("LISTEN_TCP", self.listen_tcp);
+        self.use_native_openai_endpoint = env.flag("USE_NATIVE_OPENAI_ENDPOINT", self.use_native_openai_endpoint);
+        self.nonstream_keepalive_enabled = env.flag("NONSTREAM_KEEPALIVE_ENABLED", self.nonstream_keepalive_enabled);
+        self.allow_safety_override = env.flag("ALLOW_SAFETY_OVERRIDE", self.allow_safety_override);
+        self.debug_headers = env.flag("DEBUG_HEADERS", self.debug_headers);
+        self.strict_openai_compat = env.flag("STRICT_OPENAI_COMPAT", self.strict_openai_compat);
+        self.array_content_responses = env.flag("ARRAY_CONTENT_RESPONSES", self.array_content_responses);
+        self.enable_context_caching = env.flag("ENABLE_CONTEXT_CACHING", self.enable_context_caching);
 
         // String configurations
-        settings.storage_dir = env::var("STORAGE_DIR").unwrap_or_else(|_| "/rujimi/settings/".to_string());
-        settings.google_credentials_json = env::var("GOOGLE_CREDENTIALS_JSON").unwrap_or_default();
-        settings.vertex_express_api_key = env::var("VERTEX_EXPRESS_API_KEY").unwrap_or_default();
-        settings.search.search_prompt = env::var("SEARCH_PROMPT")
-            .unwrap_or_else(|_| "（使用搜索工具联网搜索，需要在content中结合搜索内容）".to_string())
-            .trim_matches('"').to_string();
-        settings.dashboard_url = env::var("DASHBOARD_URL").unwrap_or_default();
+        self.storage_dir = env.get("STORAGE_DIR").unwrap_or(self.storage_dir);
+        self.google_credentials_json = env.get("GOOGLE_CREDENTIALS_JSON").unwrap_or(self.google_credentials_json);
+        self.vertex_express_api_key = env.get("VERTEX_EXPRESS_API_KEY").unwrap_or(self.vertex_express_api_key);
+        self.credentials_dir = env.string("CREDENTIALS_DIR", self.credentials_dir);
+        self.vertex_project_id = env.get("VERTEX_PROJECT_ID").unwrap_or(self.vertex_project_id);
+        self.vertex_location = env.get("VERTEX_LOCATION").unwrap_or(self.vertex_location);
+        self.vertex_models_config_url = env.get("VERTEX_MODELS_CONFIG_URL").unwrap_or(self.vertex_models_config_url);
+        if let Some(base_url) = env.get("VERTEX_BASE_URL") {
+            self.vertex_base_url = normalize_base_url(base_url.trim_matches('"'));
+        }
+        if let Some(provider) = env.get("FALLBACK_PROVIDER") {
+            self.fallback_provider = FallbackProvider::parse(&provider);
+        }
+        if let Some(backend) = env.get("STORAGE_BACKEND") {
+            self.storage_backend = StorageBackend::parse(&backend);
+        }
+        if let Some(profile) = env.get("UPSTREAM_HEADER_PROFILE") {
+            self.upstream_header_profile = UpstreamHeaderProfile::parse(&profile);
+        }
+        if let Some(render) = env.get("CODE_EXECUTION_RENDER") {
+            self.code_execution_render = CodeExecutionRender::parse(&render);
+        }
+        if let Some(strategy) = env.get("CONTEXT_TRIM_STRATEGY") {
+            self.context_trim_strategy = ContextTrimStrategy::parse(&strategy);
+        }
+        self.search.search_prompt = env.string("SEARCH_PROMPT", self.search.search_prompt);
+        self.dashboard_url = env.get("DASHBOARD_URL").unwrap_or(self.dashboard_url);
+        self.static_dir = env.string("STATIC_DIR", self.static_dir);
+        self.tls_cert_path = env.string("TLS_CERT_PATH", self.tls_cert_path);
+        self.tls_key_path = env.string("TLS_KEY_PATH", self.tls_key_path);
+        self.listen_socket = env.string("LISTEN_SOCKET", self.listen_socket);
+        self.listen_socket_mode = env.get("LISTEN_SOCKET_MODE").unwrap_or(self.listen_socket_mode);
+        self.upstream_proxy = env.string("UPSTREAM_PROXY", self.upstream_proxy);
+        self.upstream_proxy_auth = env.string("UPSTREAM_PROXY_AUTH", self.upstream_proxy_auth);
+        self.transcription_model = env.string("TRANSCRIPTION_MODEL", self.transcription_model);
+        self.key_state_redis_url = env.string("KEY_STATE_REDIS_URL", self.key_state_redis_url);
+        self.audit_log_enabled = env.flag("AUDIT_LOG_ENABLED", self.audit_log_enabled);
+        self.audit_log_include_content = env.flag("AUDIT_LOG_INCLUDE_CONTENT", self.audit_log_include_content);
+        self.audit_log_max_file_mb = env.number("AUDIT_LOG_MAX_FILE_MB", self.audit_log_max_file_mb);
 
         // Numeric configurations
-        settings.fake_streaming_interval = env::var("FAKE_STREAMING_INTERVAL")
-            .unwrap_or_else(|_| "1".to_string()).parse().unwrap_or(1.0);
-        settings.fake_streaming_chunk_size = env::var("FAKE_STREAMING_CHUNK_SIZE")
-            .unwrap_or_else(|_| "10".to_string()).parse().unwrap_or(10);
-        settings.fake_streaming_delay_per_chunk = env::var("FAKE_STREAMING_DELAY_PER_CHUNK")
-            .unwrap_or_else(|_| "0.1".to_string()).parse().unwrap_or(0.1);
-        settings.concurrent_requests = env::var("CONCURRENT_REQUESTS")
-            .unwrap_or_else(|_| "1".to_string()).parse().unwrap_or(1);
-        settings.increase_concurrent_on_failure = env::var("INCREASE_CONCURRENT_ON_FAILURE")
-            .unwrap_or_else(|_| "0".to_string()).parse().unwrap_or(0);
-        settings.max_concurrent_requests = env::var("MAX_CONCURRENT_REQUESTS")
-            .unwrap_or_else(|_| "3".to_string()).parse().unwrap_or(3);
-        settings.cache_expiry_time = env::var("CACHE_EXPIRY_TIME")
-            .unwrap_or_else(|_| "21600".to_string()).parse().unwrap_or(21600);
-        settings.max_cache_entries = env::var("MAX_CACHE_ENTRIES")
-            .unwrap_or_else(|_| "500".to_string()).parse().unwrap_or(500);
-        settings.calculate_cache_entries = env::var("CALCULATE_CACHE_ENTRIES")
-            .unwrap_or_else(|_| "6".to_string()).parse().unwrap_or(6);
-        settings.random_string_length = env::var("RANDOM_STRING_LENGTH")
-            .unwrap_or_else(|_| "5".to_string()).parse().unwrap_or(5);
-        settings.max_empty_responses = env::var("MAX_EMPTY_RESPONSES")
-            .unwrap_or_else(|_| "5".to_string()).parse().unwrap_or(5);
-        settings.max_retry_num = env::var("MAX_RETRY_NUM")
-            .unwrap_or_else(|_| "15".to_string()).parse().unwrap_or(15);
-        settings.max_requests_per_minute = env::var("MAX_REQUESTS_PER_MINUTE")
-            .unwrap_or_else(|_| "30".to_string()).parse().unwrap_or(30);
-        settings.max_requests_per_day_per_ip = env::var("MAX_REQUESTS_PER_DAY_PER_IP")
-            .unwrap_or_else(|_| "600".to_string()).parse().unwrap_or(600);
-        settings.api_key_daily_limit = env::var("API_KEY_DAILY_LIMIT")
-            .unwrap_or_else(|_| "100".to_string()).parse().unwrap_or(100);
-        settings.nonstream_keepalive_interval = env::var("NONSTREAM_KEEPALIVE_INTERVAL")
-            .unwrap_or_else(|_| "5.0".to_string()).parse().unwrap_or(5.0);
+        self.fake_streaming_interval = env.number("FAKE_STREAMING_INTERVAL", self.fake_streaming_interval);
+        self.fake_streaming_chunk_size = env.number("FAKE_STREAMING_CHUNK_SIZE", self.fake_streaming_chunk_size);
+        self.fake_streaming_delay_per_chunk = env.number("FAKE_STREAMING_DELAY_PER_CHUNK", self.fake_streaming_delay_per_chunk);
+        self.concurrent_requests = env.number("CONCURRENT_REQUESTS", self.concurrent_requests);
+        self.increase_concurrent_on_failure = env.number("INCREASE_CONCURRENT_ON_FAILURE", self.increase_concurrent_on_failure);
+        self.max_concurrent_requests = env.number("MAX_CONCURRENT_REQUESTS", self.max_concurrent_requests);
+        self.cache_expiry_time = env.number("CACHE_EXPIRY_TIME", self.cache_expiry_time);
+        self.max_cache_entries = env.number("MAX_CACHE_ENTRIES", self.max_cache_entries);
+        self.calculate_cache_entries = env.number("CALCULATE_CACHE_ENTRIES", self.calculate_cache_entries);
+        self.min_tokens_to_cache = env.number("MIN_TOKENS_TO_CACHE", self.min_tokens_to_cache);
+        self.random_string_length = env.number("RANDOM_STRING_LENGTH", self.random_string_length);
+        self.max_empty_responses = env.number("MAX_EMPTY_RESPONSES", self.max_empty_responses);
+        self.max_retry_num = env.number("MAX_RETRY_NUM", self.max_retry_num);
+        self.max_requests_per_minute = env.number("MAX_REQUESTS_PER_MINUTE", self.max_requests_per_minute);
+        self.max_requests_per_day_per_ip = env.number("MAX_REQUESTS_PER_DAY_PER_IP", self.max_requests_per_day_per_ip);
+        self.api_key_daily_limit = env.number("API_KEY_DAILY_LIMIT", self.api_key_daily_limit);
+        self.per_key_rpm = env.number("PER_KEY_RPM", self.per_key_rpm);
+        self.sticky_keys = env.flag("STICKY_KEYS", self.sticky_keys);
+        self.hedge_after_ms = env.number("HEDGE_AFTER_MS", self.hedge_after_ms);
+        self.max_key_wait_ms = env.number("MAX_KEY_WAIT_MS", self.max_key_wait_ms);
+        self.request_deadline_secs = env.number("REQUEST_DEADLINE_SECS", self.request_deadline_secs);
+        self.fair_queuing = env.flag("FAIR_QUEUING", self.fair_queuing);
+        self.fair_queue_concurrency = env.number("FAIR_QUEUE_CONCURRENCY", self.fair_queue_concurrency);
+        self.min_request_interval_ms = env.number("MIN_REQUEST_INTERVAL_MS", self.min_request_interval_ms);
+        self.nonstream_keepalive_interval = env.number("NONSTREAM_KEEPALIVE_INTERVAL", self.nonstream_keepalive_interval);
+        self.sse_heartbeat_interval_secs = env.number("SSE_HEARTBEAT_INTERVAL_SECS", self.sse_heartbeat_interval_secs);
+        self.max_request_body_mb = env.number("MAX_REQUEST_BODY_MB", self.max_request_body_mb);
+        self.model_refresh_interval_secs = env.number("MODEL_REFRESH_INTERVAL_SECS", self.model_refresh_interval_secs);
+        self.warmup_on_start = env.flag("WARMUP_ON_START", self.warmup_on_start);
+        self.warmup_model = env.string("WARMUP_MODEL", self.warmup_model);
+        self.end_user_note = env.flag("END_USER_NOTE", self.end_user_note);
+        self.circuit_breaker_window = env.number("CIRCUIT_BREAKER_WINDOW", self.circuit_breaker_window);
+        self.circuit_breaker_threshold_percent =
+            env.number("CIRCUIT_BREAKER_THRESHOLD_PERCENT", self.circuit_breaker_threshold_percent);
+        self.circuit_breaker_cooldown_secs = env.number("CIRCUIT_BREAKER_COOLDOWN_SECS", self.circuit_breaker_cooldown_secs);
+        self.dashboard_stream_interval_secs =
+            env.number("DASHBOARD_STREAM_INTERVAL_SECS", self.dashboard_stream_interval_secs);
+        self.emergency_cleanup_memory_percent =
+            env.number("EMERGENCY_CLEANUP_MEMORY_PERCENT", self.emergency_cleanup_memory_percent);
+        self.log_buffer_size = env.number("LOG_BUFFER_SIZE", self.log_buffer_size);
+        self.vertex_log_buffer_size = env.number("VERTEX_LOG_BUFFER_SIZE", self.vertex_log_buffer_size);
+        self.log_retention_secs = env.number("LOG_RETENTION_SECS", self.log_retention_secs);
+        self.tls_reload_interval_secs = env.number("TLS_RELOAD_INTERVAL_SECS", self.tls_reload_interval_secs);
+        self.upstream_connect_timeout_secs = env.number("UPSTREAM_CONNECT_TIMEOUT_SECS", self.upstream_connect_timeout_secs);
+        self.upstream_request_timeout_secs = env.number("UPSTREAM_REQUEST_TIMEOUT_SECS", self.upstream_request_timeout_secs);
+        self.stream_idle_timeout_secs = env.number("STREAM_IDLE_TIMEOUT_SECS", self.stream_idle_timeout_secs);
+        self.network_retries = env.number("NETWORK_RETRIES", self.network_retries);
+        self.network_retry_backoff_ms = env.number("NETWORK_RETRY_BACKOFF_MS", self.network_retry_backoff_ms);
+        self.strict_config = env.flag("STRICT_CONFIG", self.strict_config);
+        self.update_check = env.flag("UPDATE_CHECK", self.update_check);
+        self.mock_upstream = env.flag("RUJIMI_MOCK", env.flag("MOCK_UPSTREAM", self.mock_upstream));
+        self.mock_latency_ms = env.number("MOCK_LATENCY_MS", self.mock_latency_ms);
+        self.mock_fail_rate = env.number("MOCK_FAIL_RATE", self.mock_fail_rate);
+        self.compression_enabled = env.flag("COMPRESSION_ENABLED", self.compression_enabled);
+        self.compression_min_size_bytes = env.number("COMPRESSION_MIN_SIZE_BYTES", self.compression_min_size_bytes);
+        self.public_requests_per_minute_per_ip = env.number("PUBLIC_REQUESTS_PER_MINUTE_PER_IP", self.public_requests_per_minute_per_ip);
+        self.public_max_tokens = env.number("PUBLIC_MAX_TOKENS", self.public_max_tokens);
 
         // List/Set configurations
-        settings.blocked_models = parse_comma_separated_set(&env::var("BLOCKED_MODELS").unwrap_or_default());
-        settings.whitelist_models = parse_comma_separated_set(&env::var("WHITELIST_MODELS").unwrap_or_default());
-        settings.whitelist_user_agent = parse_comma_separated_set_lowercase(&env::var("WHITELIST_USER_AGENT").unwrap_or_default());
-        settings.allowed_origins = parse_comma_separated(&env::var("ALLOWED_ORIGINS").unwrap_or_default());
-        settings.invalid_api_keys = parse_comma_separated(&env::var("INVALID_API_KEYS").unwrap_or_default());
+        if let Some(models) = env.get("BLOCKED_MODELS") {
+            self.blocked_models = parse_comma_separated_set(&models);
+        }
+        if let Some(models) = env.get("WHITELIST_MODELS") {
+            self.whitelist_models = parse_comma_separated_set(&models);
+        }
+        if let Some(user_agents) = env.get("WHITELIST_USER_AGENT") {
+            self.whitelist_user_agent = parse_comma_separated_set_lowercase(&user_agents);
+        }
+        if let Some(origins) = env.get("ALLOWED_ORIGINS") {
+            self.allowed_origins = parse_comma_separated(&origins);
+        }
+        if let Some(keys) = env.get("INVALID_API_KEYS") {
+            self.invalid_api_keys = parse_comma_separated(&keys);
+        }
+        if let Some(hosts) = env.get("NO_PROXY") {
+            self.no_proxy = parse_comma_separated(&hosts);
+        }
+        if let Some(algorithms) = env.get("COMPRESSION_ALGORITHMS") {
+            self.compression_algorithms = parse_comma_separated(&algorithms.to_lowercase());
+        }
+        if let Some(cidrs) = env.get("IP_ALLOWLIST") {
+            self.ip_allowlist = parse_comma_separated(&cidrs);
+        }
+        if let Some(cidrs) = env.get("IP_BLOCKLIST") {
+            self.ip_blocklist = parse_comma_separated(&cidrs)
+                .iter()
+                .map(|cidr| IpBlockEntry::permanent(cidr))
+                .collect();
+        }
+        if let Some(cidrs) = env.get("TRUSTED_PROXIES") {
+            self.trusted_proxies = parse_comma_separated(&cidrs);
+        }
+        if let Some(models) = env.get("PUBLIC_ALLOWED_MODELS") {
+            self.public_allowed_models = parse_comma_separated(&models);
+        }
 
-        // Set base directory
-        if let Ok(current_dir) = env::current_dir() {
-            if let Some(parent) = current_dir.parent() {
-                if let Some(parent) = parent.parent() {
-                    settings.base_dir = parent.to_path_buf();
-                }
+        // JSON configurations
+        if let Some(overrides_str) = env.get("MODEL_OVERRIDES") {
+            match serde_json::from_str(&overrides_str) {
+                Ok(overrides) => self.model_overrides = overrides,
+                Err(e) => env.problem(format!("Ignoring invalid MODEL_OVERRIDES: {}", e)),
+            }
+        }
+        if let Some(client_keys_str) = env.get("CLIENT_KEYS") {
+            match serde_json::from_str(&client_keys_str) {
+                Ok(client_keys) => self.client_keys = client_keys,
+                Err(e) => env.problem(format!("Ignoring invalid CLIENT_KEYS: {}", e)),
             }
         }
 
-        Ok(settings)
+        self
+    }
+
+    /// Whether Vertex AI is on, through service accounts or express mode
+    pub fn vertex_enabled(&self) -> bool {
+        self.enable_vertex || self.enable_vertex_express
+    }
+
+    /// Gemini base URLs in failover order: `gemini_endpoints`, or `gemini_base_url` alone
+    pub fn upstream_endpoints(&self) -> Vec<String> {
+        if self.gemini_endpoints.is_empty() {
+            vec![self.gemini_base_url.clone()]
+        } else {
+            self.gemini_endpoints.clone()
+        }
     }
 
     pub fn get_valid_api_keys(&self) -> Vec<String> {
@@ -268,9 +1179,243 @@ impl Settings {
             .collect()
     }
 
+    /// Whether anonymous public-mode callers may use `model`; an empty list allows none
+    pub fn public_allows_model(&self, model: &str) -> bool {
+        self.public_allowed_models.iter().any(|pattern| model_pattern_matches(pattern, model))
+    }
+
     pub fn update_invalid_keys(&mut self, invalid_keys: Vec<String>) {
         self.invalid_api_keys = invalid_keys;
     }
+
+    /// Find the override for a model. An exact key wins, otherwise the longest matching pattern.
+    pub fn model_override_for(&self, model: &str) -> Option<&ModelOverride> {
+        if let Some(exact) = self.model_overrides.get(model) {
+            return Some(exact);
+        }
+
+        self.model_overrides
+            .iter()
+            .filter(|(pattern, _)| model_pattern_matches(pattern, model))
+            .max_by_key(|(pattern, _)| pattern.len())
+            .map(|(_, model_override)| model_override)
+    }
+
+    /// Whether chat completions for `model` go to the OpenAI-compatible endpoint; a model
+    /// override takes precedence over the global switch
+    pub fn uses_native_openai_endpoint(&self, model: &str) -> bool {
+        self.model_override_for(model)
+            .and_then(|model_override| model_override.use_native_openai_endpoint)
+            .unwrap_or(self.use_native_openai_endpoint)
+    }
+
+    /// Gemini API version for `model`; a model override takes precedence over `api_version`
+    pub fn api_version_for(&self, model: &str) -> &str {
+        self.model_override_for(model)
+            .and_then(|model_override| model_override.api_version.as_deref())
+            .unwrap_or(&self.api_version)
+    }
+}
+
+/// `model_pattern_matches` against a requested model, or against its base when it carries
+/// `-search` or thinking suffixes, so a rule for a model covers all of its variants
+pub fn model_name_matches(pattern: &str, model: &str) -> bool {
+    if model_pattern_matches(pattern, model) {
+        return true;
+    }
+    let variant = ModelVariant::parse(model);
+    !variant.is_plain() && model_pattern_matches(pattern, &variant.base)
+}
+
+/// Simple glob match where `*` matches any run of characters
+pub fn model_pattern_matches(pattern: &str, model: &str) -> bool {
+    if !pattern.contains('*') {
+        return pattern == model;
+    }
+
+    let segments: Vec<&str> = pattern.split('*').collect();
+    let mut remaining = model;
+
+    for (i, segment) in segments.iter().enumerate() {
+        if segment.is_empty() {
+            continue;
+        }
+        if i == 0 {
+            match remaining.strip_prefix(segment) {
+                Some(rest) => remaining = rest,
+                None => return false,
+            }
+        } else if i == segments.len() - 1 {
+            return remaining.ends_with(segment);
+        } else {
+            match remaining.find(segment) {
+                Some(pos) => remaining = &remaining[pos + segment.len()..],
+                None => return false,
+            }
+        }
+    }
+
+    true
+}
+
+fn default_vertex_location() -> String {
+    "us-central1".to_string()
+}
+
+fn default_session_ttl_secs() -> u64 {
+    3600
+}
+
+fn default_context_cache_min_tokens() -> u32 {
+    4096
+}
+
+fn default_context_cache_ttl_secs() -> u64 {
+    3600
+}
+
+fn default_public_requests_per_minute_per_ip() -> u32 {
+    5
+}
+
+fn default_public_max_tokens() -> u32 {
+    1024
+}
+
+fn default_login_max_failures() -> u32 {
+    10
+}
+
+fn default_login_failure_window_secs() -> u64 {
+    900
+}
+
+fn default_login_lockout_secs() -> u64 {
+    900
+}
+
+fn default_model_refresh_interval_secs() -> u64 {
+    6 * 3600
+}
+
+fn default_warmup_model() -> String {
+    "gemini-2.0-flash-lite".to_string()
+}
+
+fn default_fair_queue_concurrency() -> usize {
+    16
+}
+
+fn default_circuit_breaker_window() -> u32 {
+    20
+}
+
+fn default_circuit_breaker_threshold_percent() -> u32 {
+    90
+}
+
+fn default_circuit_breaker_cooldown_secs() -> u64 {
+    60
+}
+
+fn default_dashboard_stream_interval_secs() -> u64 {
+    5
+}
+
+fn default_emergency_cleanup_memory_percent() -> f64 {
+    95.0
+}
+
+fn default_log_buffer_size() -> usize {
+    100
+}
+
+fn default_log_retention_secs() -> u64 {
+    24 * 3600
+}
+
+fn default_max_request_body_mb() -> u64 {
+    20
+}
+
+fn default_audit_log_max_file_mb() -> u64 {
+    100
+}
+
+fn default_compression_algorithms() -> Vec<String> {
+    vec!["gzip".to_string()]
+}
+
+/// The threshold of tower-http's default predicate
+fn default_compression_min_size_bytes() -> u16 {
+    32
+}
+
+fn default_transcription_model() -> String {
+    "gemini-1.5-flash".to_string()
+}
+
+fn default_sse_heartbeat_interval_secs() -> u64 {
+    15
+}
+
+fn default_true() -> bool {
+    true
+}
+
+fn default_listen_socket_mode() -> String {
+    "0660".to_string()
+}
+
+fn default_upstream_connect_timeout_secs() -> u64 {
+    10
+}
+
+fn default_upstream_request_timeout_secs() -> u64 {
+    600
+}
+
+fn default_stream_idle_timeout_secs() -> u64 {
+    120
+}
+
+fn default_network_retries() -> u32 {
+    2
+}
+
+fn default_network_retry_backoff_ms() -> u64 {
+    200
+}
+
+fn default_endpoint_failure_threshold() -> u32 {
+    3
+}
+
+fn default_endpoint_cooldown_secs() -> u64 {
+    60
+}
+
+fn default_gemini_base_url() -> String {
+    DEFAULT_GEMINI_BASE_URL.to_string()
+}
+
+fn default_api_version() -> String {
+    DEFAULT_GEMINI_API_VERSION.to_string()
+}
+
+/// Append `version` to a base URL, replacing a version the URL already ends in so older
+/// configs that pin `/v1beta` keep working
+pub fn versioned_base_url(base_url: &str, version: &str) -> String {
+    let host = match base_url.rsplit_once('/') {
+        Some((host, last)) if GEMINI_API_VERSIONS.contains(&last) => host,
+        _ => base_url,
+    };
+    format!("{}/{}", host, version)
+}
+
+/// Trim whitespace and trailing slashes so paths can be appended with `format!("{}/...")`
+pub fn normalize_base_url(value: &str) -> String {
+    value.trim().trim_end_matches('/').to_string()
 }
 
 fn parse_bool(value: &str) -> bool {
@@ -311,4 +1456,286 @@ fn parse_comma_separated_set_lowercase(value: &str) -> HashSet<String> {
             .filter(|s| !s.is_empty())
             .collect()
     }
-}
\ No newline at end of file
+}
+
+#[derive(Debug, Clone, Copy)]
+enum ConfigFormat {
+    Toml,
+    Yaml,
+}
+
+impl ConfigFormat {
+    fn from_path(path: &Path) -> Result<Self> {
+        match path.extension().and_then(|extension| extension.to_str()).map(str::to_ascii_lowercase).as_deref() {
+            Some("toml") => Ok(Self::Toml),
+            Some("yaml" | "yml") => Ok(Self::Yaml),
+            _ => Err(anyhow::anyhow!("Config file {} must end in .toml, .yaml or .yml", path.display())),
+        }
+    }
+}
+
+/// Name the offending key, e.g. ``Invalid value for `search.search_mode`: invalid type: ...``
+fn config_error(path: &serde_path_to_error::Path, error: &impl std::fmt::Display) -> anyhow::Error {
+    match path.to_string().as_str() {
+        "." => anyhow::anyhow!("{}", error),
+        key => anyhow::anyhow!("Invalid value for `{}`: {}", key, error),
+    }
+}
+
+/// Setting overrides looked up by variable name; `Settings::load` reads the process environment.
+/// Values that cannot be parsed are kept in `problems`.
+struct EnvOverrides<F: Fn(&str) -> Option<String>> {
+    lookup: F,
+    problems: RefCell<Vec<String>>,
+}
+
+impl<F: Fn(&str) -> Option<String>> EnvOverrides<F> {
+    fn new(lookup: F) -> Self {
+        Self { lookup, problems: RefCell::new(Vec::new()) }
+    }
+
+    fn get(&self, name: &str) -> Option<String> {
+        (self.lookup)(name)
+    }
+
+    fn problem(&self, problem: String) {
+        self.problems.borrow_mut().push(problem);
+    }
+
+    /// `value` of variable `name` parsed, noting a problem with the raw value when it is invalid
+    fn parsed<T: FromStr>(&self, name: &str, value: &str) -> Option<T> {
+        let parsed = value.trim().parse().ok();
+        if parsed.is_none() {
+            self.problem(format!("{}={:?} is not a valid {} and was ignored", name, value, std::any::type_name::<T>()));
+        }
+        parsed
+    }
+
+    /// The variable with surrounding quotes removed, or `current` when unset
+    fn string(&self, name: &str, current: String) -> String {
+        self.get(name).map_or(current, |value| value.trim_matches('"').to_string())
+    }
+
+    fn flag(&self, name: &str, current: bool) -> bool {
+        self.get(name).map_or(current, |value| parse_bool(&value))
+    }
+
+    /// The variable parsed as a number, or `current` when unset or not a number
+    fn number<T: FromStr>(&self, name: &str, current: T) -> T {
+        self.get(name).and_then(|value| self.parsed(name, &value)).unwrap_or(current)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_normalize_base_url() {
+        assert_eq!(normalize_base_url("https://relay.example.com/v1beta/"), "https://relay.example.com/v1beta");
+        assert_eq!(normalize_base_url(" https://relay.example.com/v1beta// "), "https://relay.example.com/v1beta");
+        assert_eq!(normalize_base_url(DEFAULT_GEMINI_BASE_URL), DEFAULT_GEMINI_BASE_URL);
+    }
+
+    #[test]
+    fn test_model_pattern_matches() {
+        assert!(model_pattern_matches("gemini-1.5-pro", "gemini-1.5-pro"));
+        assert!(!model_pattern_matches("gemini-1.5-pro", "gemini-1.5-pro-latest"));
+        assert!(model_pattern_matches("gemini-1.5-*", "gemini-1.5-flash"));
+        assert!(model_pattern_matches("*-pro*", "gemini-2.5-pro-preview"));
+        assert!(model_pattern_matches("*", "anything"));
+        assert!(!model_pattern_matches("gemini-*-pro", "gemini-2.0-flash"));
+    }
+
+    #[test]
+    fn test_model_override_prefers_most_specific() {
+        let mut settings = Settings::default();
+        settings.model_overrides.insert("gemini-*".to_string(), ModelOverride {
+            max_output_tokens_cap: Some(1),
+            ..Default::default()
+        });
+        settings.model_overrides.insert("gemini-1.5-pro*".to_string(), ModelOverride {
+            max_output_tokens_cap: Some(2),
+            ..Default::default()
+        });
+
+        assert_eq!(settings.model_override_for("gemini-1.5-pro-002").unwrap().max_output_tokens_cap, Some(2));
+        assert_eq!(settings.model_override_for("gemini-2.0-flash").unwrap().max_output_tokens_cap, Some(1));
+        assert!(settings.model_override_for("text-embedding-004").is_none());
+    }
+
+    #[test]
+    fn test_client_key_defaults_and_model_filter() {
+        let client_key: ClientKey = serde_json::from_str(
+            r#"{"key": "sk-a", "name": "team-a", "allowed_models": ["gemini-2.5-flash*"]}"#,
+        )
+        .unwrap();
+
+        assert_eq!(client_key.scope, ClientKeyScope::User);
+        assert_eq!(client_key.daily_request_limit, 0);
+        assert!(client_key.allows_model("gemini-2.5-flash-lite"));
+        assert!(!client_key.allows_model("gemini-2.5-pro"));
+    }
+
+    #[test]
+    fn test_fallback_provider_parse() {
+        assert_eq!(FallbackProvider::parse("vertex"), FallbackProvider::Vertex);
+        assert_eq!(FallbackProvider::parse(" \"Gemini\" "), FallbackProvider::Gemini);
+        assert_eq!(FallbackProvider::parse("none"), FallbackProvider::None);
+        assert_eq!(FallbackProvider::parse(""), FallbackProvider::None);
+        assert_eq!(FallbackProvider::parse("openai"), FallbackProvider::None);
+    }
+
+    #[test]
+    fn test_code_execution_render_parse() {
+        assert_eq!(CodeExecutionRender::parse("OMIT"), CodeExecutionRender::Omit);
+        assert_eq!(CodeExecutionRender::parse("markdown"), CodeExecutionRender::Markdown);
+        assert_eq!(CodeExecutionRender::parse(""), CodeExecutionRender::Markdown);
+    }
+
+    #[test]
+    fn test_config_file_round_trip() {
+        let settings = Settings::from_config_str(include_str!("../../config.example.toml"), ConfigFormat::Toml).unwrap();
+        assert_eq!(settings.gemini_api_keys, vec!["your_api_key_1", "your_api_key_2"]);
+        assert_eq!(settings.web_password, "123");
+        assert_eq!(settings.search.search_prompt, "Use the search tool and ground the answer in its results");
+        assert_eq!(
+            settings.model_override_for("gemini-2.5-pro-preview").unwrap().force_safety_threshold.as_deref(),
+            Some("BLOCK_ONLY_HIGH")
+        );
+        assert!(settings.uses_native_openai_endpoint("gemini-2.0-flash-001"));
+        assert_eq!(settings.client_keys[0].allowed_models, vec!["gemini-2.5-flash*"]);
+        assert_eq!(settings.ip_blocklist[0].reason.as_deref(), Some("abuse"));
+        // Unlisted keys keep their defaults
+        assert_eq!(settings.max_cache_entries, Settings::default().max_cache_entries);
+
+        let toml_text = toml::to_string(&settings).unwrap();
+        let from_toml = Settings::from_config_str(&toml_text, ConfigFormat::Toml).unwrap();
+        assert_eq!(serde_json::to_value(&from_toml).unwrap(), serde_json::to_value(&settings).unwrap());
+
+        let yaml_text = serde_yaml::to_string(&settings).unwrap();
+        let from_yaml = Settings::from_config_str(&yaml_text, ConfigFormat::Yaml).unwrap();
+        assert_eq!(serde_json::to_value(&from_yaml).unwrap(), serde_json::to_value(&settings).unwrap());
+    }
+
+    #[test]
+    fn test_config_file_errors_name_the_key() {
+        let error = Settings::from_config_str("[search]\nsearch_mode = \"yes\"\n", ConfigFormat::Toml).unwrap_err();
+        let message = format!("{:#}", error);
+        assert!(message.contains("`search.search_mode`"), "{}", message);
+        assert!(message.contains("expected a boolean"), "{}", message);
+
+        let error = Settings::from_config_str("model_overrides:\n  gemini-*:\n    max_output_tokens_cap: lots\n", ConfigFormat::Yaml).unwrap_err();
+        let message = format!("{:#}", error);
+        assert!(message.contains("`model_overrides.gemini-*.max_output_tokens_cap`"), "{}", message);
+        assert!(message.contains("u32"), "{}", message);
+
+        assert!(ConfigFormat::from_path(Path::new("rujimi.json")).is_err());
+    }
+
+    #[tokio::test]
+    async fn test_checked_reports_every_problem() {
+        let vars: HashMap<&str, &str> = [("MAX_CACHE_ENTRIES", "5OO"), ("PORT", "70000"), ("CACHE_EXPIRY_TIME", "abc")].into();
+        let env = EnvOverrides::new(|name| vars.get(name).map(|value| value.to_string()));
+        let mut settings = Settings {
+            whitelist_models: HashSet::from(["gemini-2.0-flash".to_string()]),
+            blocked_models: HashSet::from(["gemini-2.0-flash".to_string()]),
+            ..Settings::default()
+        }
+        .apply_env(&env);
+        settings.config_warnings = env.problems.into_inner();
+        assert_eq!(settings.max_cache_entries, Settings::default().max_cache_entries);
+
+        let strict = Settings { strict_config: true, ..settings.clone() };
+        let message = strict.checked().await.unwrap_err().to_string();
+        assert!(message.contains("MAX_CACHE_ENTRIES=\"5OO\""), "{}", message);
+
+        let warnings = settings.clone().checked().await.unwrap().config_warnings;
+        assert_eq!(warnings.len(), 5, "{:?}", warnings);
+        assert!(warnings[0].starts_with("PORT=\"70000\""));
+        assert!(warnings[1].starts_with("CACHE_EXPIRY_TIME=\"abc\""));
+        assert!(warnings[3].contains("GEMINI_API_KEYS"));
+        assert!(warnings[4].ends_with("blocked: gemini-2.0-flash"));
+
+        // Vertex, in either mode, serves without Gemini keys
+        let express = Settings { enable_vertex_express: true, config_warnings: Vec::new(), ..settings };
+        let warnings = express.checked().await.unwrap().config_warnings;
+        assert!(!warnings.iter().any(|warning| warning.contains("GEMINI_API_KEYS")), "{:?}", warnings);
+    }
+
+    #[test]
+    fn test_validate() {
+        assert!(Settings::default().validate().is_ok());
+
+        let settings = Settings { gemini_base_url: "generativelanguage.googleapis.com".to_string(), ..Settings::default() };
+        assert!(settings.validate().unwrap_err().to_string().contains("`gemini_base_url`"));
+        let settings = Settings { upstream_request_timeout_secs: 0, ..Settings::default() };
+        assert!(settings.validate().unwrap_err().to_string().contains("`upstream_request_timeout_secs`"));
+    }
+
+    #[test]
+    fn test_env_overrides_config_file() {
+        let settings = Settings::from_config_str(include_str!("../../config.example.toml"), ConfigFormat::Toml).unwrap();
+        let vars: HashMap<&str, &str> = [("PASSWORD", "\"secret\""), ("MAX_REQUESTS_PER_MINUTE", "60"), ("SEARCH_MODE", "true")]
+            .into_iter()
+            .collect();
+        let settings = settings.apply_env(&EnvOverrides::new(|name| vars.get(name).map(|value| value.to_string())));
+
+        assert_eq!((settings.password.as_str(), settings.web_password.as_str()), ("secret", "secret"));
+        assert_eq!(settings.max_requests_per_minute, 60);
+        assert!(settings.search.search_mode);
+        // Variables that are not set leave the file values alone
+        assert_eq!(settings.api_key_daily_limit, 100);
+        assert_eq!(settings.gemini_api_keys.len(), 2);
+        assert_eq!(settings.blocked_models.len(), 1);
+
+        // Without a file or variables, the defaults stand
+        let settings = Settings::default().apply_env(&EnvOverrides::new(|_| None));
+        assert_eq!(settings.web_password, "123");
+        assert!(settings.fake_streaming);
+    }
+
+    #[test]
+    fn test_native_openai_endpoint_per_model() {
+        let mut settings = Settings::default();
+        settings.model_overrides.insert("gemini-2.5-*".to_string(), ModelOverride {
+            use_native_openai_endpoint: Some(true),
+            ..Default::default()
+        });
+        assert!(settings.uses_native_openai_endpoint("gemini-2.5-flash"));
+        assert!(!settings.uses_native_openai_endpoint("gemini-1.5-pro"));
+
+        settings.use_native_openai_endpoint = true;
+        settings.model_overrides.insert("gemini-1.5-*".to_string(), ModelOverride {
+            use_native_openai_endpoint: Some(false),
+            ..Default::default()
+        });
+        assert!(!settings.uses_native_openai_endpoint("gemini-1.5-pro"));
+        assert!(settings.uses_native_openai_endpoint("gemini-2.0-flash"));
+    }
+
+    #[test]
+    fn test_api_version_per_model() {
+        let mut settings = Settings::default();
+        settings.model_overrides.insert("gemini-2.5-*".to_string(), ModelOverride {
+            api_version: Some("v1".to_string()),
+            ..Default::default()
+        });
+        assert_eq!(settings.api_version_for("gemini-2.5-pro"), "v1");
+        assert_eq!(settings.api_version_for("gemini-2.0-flash"), DEFAULT_GEMINI_API_VERSION);
+        assert!(settings.validate().is_ok());
+
+        settings.model_overrides.insert("gemini-exp".to_string(), ModelOverride {
+            api_version: Some("v2".to_string()),
+            ..Default::default()
+        });
+        assert!(settings.validate().unwrap_err().to_string().contains("`model_overrides.gemini-exp.api_version`"));
+    }
+
+    #[test]
+    fn test_versioned_base_url() {
+        assert_eq!(versioned_base_url(DEFAULT_GEMINI_BASE_URL, "v1"), "https://generativelanguage.googleapis.com/v1");
+        assert_eq!(versioned_base_url("https://relay.example.com/v1beta", "v1alpha"), "https://relay.example.com/v1alpha");
+        assert_eq!(versioned_base_url("https://relay.example.com/gemini", "v1beta"), "https://relay.example.com/gemini/v1beta");
+    }
+}
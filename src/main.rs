@@ -1,8 +1,9 @@
 use anyhow::Result;
 use axum::{
-    extract::State,
+    extract::{DefaultBodyLimit, State},
     http::StatusCode,
-    response::{Html, IntoResponse},
+    middleware,
+    response::IntoResponse,
     routing::get,
     Router,
 };
@@ -11,38 +12,69 @@ use tokio::net::TcpListener;
 use tower::ServiceBuilder;
 use tower_http::{
     cors::CorsLayer,
-    services::ServeDir,
     trace::TraceLayer,
-    compression::CompressionLayer,
 };
-use tracing::{info, error};
+use tracing::{info, error, warn};
 use tracing_subscriber::{layer::SubscriberExt, util::SubscriberInitExt};
 
 mod api;
 mod config;
 mod models;
+mod server;
 mod services;
+mod storage;
+#[cfg(test)]
+mod testing;
 mod utils;
+// Parts of the Vertex AI port are not wired into the router yet
+#[allow(dead_code)]
+mod vertex;
 
-use config::{Settings, load_settings, settings_file_exists, ConfigManager};
+use config::{Settings, LiveSettings, load_settings, settings_file_exists, log_startup_summary, ConfigManager, FallbackProvider};
 use utils::{
     api_key::ApiKeyManager,
+    audit::AuditLog,
     browser,
     cache::ResponseCacheManager,
+    circuit_breaker::ModelCircuits,
+    dashboard_sections::DashboardSections,
+    fair_queue::FairQueue,
+    live_stats::LiveStats,
+    maintenance::MaintenanceScheduler,
     stats::ApiStatsManager,
     auth::AuthState,
     error_handling::translate_error,
+    health::{self, ComponentStatus, ReadinessCache, ReadinessReport},
+    http_client,
+    tls,
 };
 use services::gemini::GeminiClient;
+use services::openai::OpenAIClient;
 
 #[derive(Clone)]
 pub struct AppState {
-    pub settings: Arc<Settings>,
+    /// The current settings snapshot, swapped when settings are reloaded
+    pub settings: LiveSettings,
     pub key_manager: Arc<ApiKeyManager>,
     pub cache_manager: Arc<ResponseCacheManager>,
     pub stats_manager: Arc<ApiStatsManager>,
     pub gemini_client: Arc<GeminiClient>,
+    /// Passthrough to Gemini's OpenAI-compatible endpoint (`use_native_openai_endpoint`)
+    pub openai_client: Arc<OpenAIClient>,
     pub auth_state: Arc<AuthState>,
+    pub readiness: Arc<ReadinessCache>,
+    /// Whether the Vertex AI routes were mounted at startup
+    pub vertex_enabled: bool,
+    /// Set with `audit_log_enabled`
+    pub audit_log: Option<Arc<AuditLog>>,
+    /// Per-model circuit breaker, see `circuit_breaker_window`
+    pub model_circuits: Arc<ModelCircuits>,
+    /// Snapshots for `/dashboard-api/stream`
+    pub live_stats: Arc<LiveStats>,
+    /// When each section of `/dashboard-api/data` last changed
+    pub dashboard_sections: Arc<DashboardSections>,
+    /// Per-caller queues in front of key selection, see `fair_queuing`
+    pub fair_queue: Arc<FairQueue>,
 }
 
 #[tokio::main]
@@ -71,27 +103,120 @@ async fn main() -> Result<()> {
         }
     }
 
+    // Report every problem with the settings, or refuse to start when STRICT_CONFIG is on
+    settings = settings.checked().await?;
+    log_startup_summary(&settings);
+
     // Initialize global config manager - mimics hajimi's global settings module
     ConfigManager::initialize(settings.clone()).await;
 
+    // The log buffers exist before the settings are read, so they are sized now
+    utils::logging::LOG_MANAGER.resize(settings.log_buffer_size);
+    utils::logging::VERTEX_LOG_MANAGER.resize(settings.vertex_log_buffer_size);
+
+    // Validate upstream proxy before any HTTP client is built
+    if let Err(e) = http_client::build_upstream_proxy(&settings) {
+        error!("Upstream proxy misconfigured: {:#}", e);
+        return Err(e);
+    }
+    if let Some(proxy) = http_client::resolve_upstream_proxy(&settings) {
+        info!("🛰️ Upstream requests routed through proxy {}", http_client::redact_proxy_url(&proxy));
+    }
+
     let settings = Arc::new(settings);
 
-    // Initialize components
-    let key_manager = Arc::new(ApiKeyManager::new(settings.clone()));
-    let cache_manager = Arc::new(ResponseCacheManager::new(settings.clone()));
-    let stats_manager = Arc::new(ApiStatsManager::new());
+    // Vertex AI is optional; a failed init leaves the routes mounted so they can be reinitialized
+    if settings.vertex_enabled() {
+        match vertex::init_vertex_app(settings.clone()).await {
+            Ok(()) => info!("☁️ Vertex AI routes mounted under /vertex"),
+            Err(e) => warn!("Vertex AI initialization failed, retry via POST /vertex/init: {}", e),
+        }
+    }
+
+    // Initialize components, with durable copies of their state when a storage backend is set
+    let storage = storage::open(&settings)?;
+    let mut key_manager = ApiKeyManager::new(settings.clone());
+    let mut cache_manager = ResponseCacheManager::new(settings.clone());
+    let mut stats_manager = ApiStatsManager::new();
+    if let Some(storage) = &storage {
+        key_manager = key_manager.with_store(storage.clone());
+        cache_manager = cache_manager.with_store(storage.clone());
+        stats_manager = stats_manager.with_store(storage.clone());
+        match stats_manager.restore().await {
+            Ok(count) => info!("💽 Restored {} call records", count),
+            Err(e) => warn!("Failed to restore call records: {}", e),
+        }
+        match cache_manager.restore() {
+            Ok(count) => info!("💽 Restored {} cached responses", count),
+            Err(e) => warn!("Failed to restore cached responses: {}", e),
+        }
+    }
+    if !settings.key_state_redis_url.is_empty() {
+        let shared = storage::shared_keys::RedisKeyState::open(&settings.key_state_redis_url)?;
+        info!("🔗 Sharing API key state with other replicas through {:?}", shared);
+        key_manager = key_manager.with_shared_state(Arc::new(shared));
+    }
+    let key_manager = Arc::new(key_manager);
+    let cache_manager = Arc::new(cache_manager);
+    let stats_manager = Arc::new(stats_manager);
     let gemini_client = Arc::new(GeminiClient::new(settings.clone()));
-    let auth_state = Arc::new(AuthState::new(settings.clone()));
+    let openai_client = Arc::new(OpenAIClient::new(settings.clone()));
+    let live_settings = LiveSettings::new(settings.clone());
+    let auth_state = Arc::new(AuthState::new(live_settings.clone()));
+    let audit_log = if settings.audit_log_enabled {
+        let dir = utils::audit::audit_dir(&settings.storage_dir);
+        Some(Arc::new(AuditLog::start(dir, settings.audit_log_max_file_mb * 1024 * 1024, settings.audit_log_include_content)?))
+    } else {
+        None
+    };
 
     // Initialize API keys
     if let Err(e) = key_manager.initialize().await {
         error!("Failed to initialize API keys: {}", e);
         return Err(e);
     }
+    if settings.mock_upstream {
+        warn!("🧪 Mock upstream mode: chat, embedding and model list calls get canned answers, Gemini is never called");
+    }
+    if key_manager.available_keys_count().await == 0 && settings.vertex_enabled() {
+        info!("☁️ No Gemini API keys, chat completions are served by Vertex AI");
+    }
+
+    // Load the model list, falling back to the defaults when upstream is unreachable
+    match key_manager.get_next_key().await {
+        Some(api_key) => gemini_client.initialize_models(&key_manager, api_key).await?,
+        None => {
+            warn!("No API key available to load the model list, using defaults");
+            gemini_client.load_default_models().await;
+        }
+    }
 
     // Start background tasks
     tokio::spawn(cache_manager.clone().start_cleanup_task());
     tokio::spawn(stats_manager.clone().start_cleanup_task());
+    if !settings.key_state_redis_url.is_empty() {
+        tokio::spawn(key_manager.clone().start_shared_state_sync());
+    }
+    if settings.model_refresh_interval_secs > 0 {
+        tokio::spawn(gemini_client.clone().start_model_refresh_task(
+            key_manager.clone(),
+            std::time::Duration::from_secs(settings.model_refresh_interval_secs),
+        ));
+    }
+
+    // The health check runs an emergency cleanup when memory runs short
+    let mut maintenance = MaintenanceScheduler::new(settings.clone()).await?;
+    maintenance.set_cache_manager(cache_manager.clone());
+    maintenance.set_stats_manager(stats_manager.clone());
+    maintenance.schedule_health_check().await?;
+    if settings.log_retention_secs > 0 {
+        maintenance.schedule_log_cleanup().await?;
+    }
+    maintenance.start().await?;
+
+    if settings.update_check {
+        tokio::spawn(utils::version::start_update_check_task());
+    }
 
     info!("🔑 API key manager initialized");
     info!("💾 Cache manager started");
@@ -99,21 +224,130 @@ async fn main() -> Result<()> {
 
     // Create application state
     let app_state = AppState {
-        settings: settings.clone(),
+        settings: live_settings,
         key_manager,
         cache_manager,
         stats_manager,
         gemini_client,
+        openai_client,
         auth_state,
+        readiness: Arc::new(ReadinessCache::default()),
+        vertex_enabled: settings.vertex_enabled(),
+        audit_log: audit_log.clone(),
+        model_circuits: Arc::new(ModelCircuits::new(&settings)),
+        live_stats: Arc::new(LiveStats::new()),
+        dashboard_sections: Arc::new(DashboardSections::new()),
+        fair_queue: Arc::new(FairQueue::new(&settings)),
     };
+    tokio::spawn(api::dashboard::start_live_stats_task(app_state.clone()));
+
+    // Runs alongside startup; a failed warm-up is only logged
+    if settings.warmup_on_start {
+        let state = app_state.clone();
+        tokio::spawn(async move {
+            api::dashboard::warm_up(&state).await;
+        });
+    }
+
+    // Reload settings when the config or settings file changes
+    let watched_files = config::reload::watched_files(&settings);
+    if !watched_files.is_empty() {
+        info!("👀 Watching {:?} for settings changes", watched_files);
+        let state = app_state.clone();
+        config::reload::spawn_watcher(watched_files, config::reload::CONFIG_WATCH_INTERVAL, move || {
+            let state = state.clone();
+            async move {
+                // Failures are logged by reload_config and keep the previous settings
+                let _ = api::dashboard::reload_config(&state).await;
+            }
+        });
+    }
 
     // Build our application with routes
     let app = build_app(app_state).await?;
 
+    // Shared shutdown notification for every listener
+    let (shutdown_tx, shutdown_rx) = tokio::sync::watch::channel(false);
+    tokio::spawn(async move {
+        server::shutdown_signal().await;
+        let _ = shutdown_tx.send(true);
+    });
+
+    // Optional Unix socket listener
+    let unix_server = if settings.listen_socket.trim().is_empty() {
+        None
+    } else {
+        let path = std::path::PathBuf::from(settings.listen_socket.trim());
+        let mode = server::parse_socket_mode(&settings.listen_socket_mode)?;
+        let listener = server::bind_unix_socket(&path, mode)?;
+        Some(tokio::spawn(server::serve_unix(listener, path, app.clone(), shutdown_rx.clone())))
+    };
+
+    // Queued storage writes are finished before the process exits
+    let flush_storage = || {
+        if let Some(storage) = &storage {
+            storage.flush();
+        }
+        if let Some(audit_log) = &audit_log {
+            audit_log.flush();
+        }
+    };
+
+    if !settings.listen_tcp {
+        match unix_server {
+            Some(handle) => {
+                let result = handle.await?;
+                flush_storage();
+                return result;
+            }
+            None => return Err(anyhow::anyhow!("LISTEN_TCP=false requires LISTEN_SOCKET to be set")),
+        }
+    }
+
     // Determine bind address
     let port = settings.port.unwrap_or(7860);
     let addr = SocketAddr::from(([0, 0, 0, 0], port));
 
+    if tls::tls_enabled(&settings) {
+        let tls_config = match tls::load_rustls_config(&settings.tls_cert_path, &settings.tls_key_path).await {
+            Ok(config) => config,
+            Err(e) => {
+                error!("Failed to enable HTTPS: {:#}", e);
+                return Err(e);
+            }
+        };
+        tls::spawn_tls_reloader(
+            tls_config.clone(),
+            settings.tls_cert_path.clone(),
+            settings.tls_key_path.clone(),
+            settings.tls_reload_interval_secs,
+        );
+
+        info!("🌐 Server starting on https://0.0.0.0:{}", port);
+        info!("📱 Dashboard available at https://127.0.0.1:{}", port);
+        info!("🎯 Listening on {} (TLS)", addr);
+
+        let handle = axum_server::Handle::new();
+        let shutdown_handle = handle.clone();
+        let tls_shutdown = shutdown_rx.clone();
+        tokio::spawn(async move {
+            server::wait_for_shutdown(tls_shutdown).await;
+            shutdown_handle.graceful_shutdown(Some(std::time::Duration::from_secs(10)));
+        });
+
+        axum_server::bind_rustls(addr, tls_config)
+            .handle(handle)
+            .serve(app.into_make_service_with_connect_info::<SocketAddr>())
+            .await?;
+
+        if let Some(unix_server) = unix_server {
+            unix_server.await??;
+        }
+
+        flush_storage();
+        return Ok(());
+    }
+
     info!("🌐 Server starting on http://0.0.0.0:{}", port);
     info!("📱 Dashboard available at http://127.0.0.1:{}", port);
 
@@ -124,8 +358,16 @@ async fn main() -> Result<()> {
     // 创建异步任务，在后台延迟打开浏览器
     tokio::spawn(browser::open_browser_delayed_with_port(port));
 
-    axum::serve(listener, app).await?;
+    axum::serve(listener, app.into_make_service_with_connect_info::<SocketAddr>())
+        .with_graceful_shutdown(server::wait_for_shutdown(shutdown_rx))
+        .await?;
 
+    // Let the Unix listener finish so its socket file gets removed
+    if let Some(unix_server) = unix_server {
+        unix_server.await??;
+    }
+
+    flush_storage();
     Ok(())
 }
 
@@ -136,55 +378,260 @@ async fn build_app(state: AppState) -> Result<Router> {
         .allow_methods(tower_http::cors::Any)
         .allow_headers(tower_http::cors::Any);
 
+    let compression = server::compression_layer(&state.settings.load());
+
+    // Oversized bodies are rejected while buffering, before a handler runs
+    let body_limit_mb = state.settings.load().max_request_body_mb.max(1);
+    let body_limit = ServiceBuilder::new()
+        .layer(middleware::map_response_with_state(body_limit_mb, utils::response::request_too_large))
+        .layer(DefaultBodyLimit::max(body_limit_mb as usize * 1024 * 1024));
+
+    let mut v1_routes = api::routes::create_v1_routes().layer(body_limit.clone());
+    if state.audit_log.is_some() {
+        v1_routes = v1_routes.layer(middleware::from_fn_with_state(state.clone(), api::audit::audit_requests));
+    }
+
     // Build router
-    let app = Router::new()
+    let mut app = Router::new()
         // API routes
-        .nest("/v1", api::routes::create_v1_routes())
-        .nest("/api", api::routes::create_api_routes().merge(api::dashboard::create_dashboard_routes()))
-        .nest("/api/auth", api::auth::create_auth_routes())
-
-        // Static file serving for frontend
-        .nest_service("/assets", ServeDir::new("assets"))
+        .nest("/v1", v1_routes)
+        .nest("/api", api::routes::create_api_routes().merge(
+            api::dashboard::create_dashboard_routes()
+                .route_layer(middleware::from_fn_with_state(state.clone(), api::auth::login_guard)),
+        ).layer(body_limit.clone()))
+        .nest("/dashboard-api", api::dashboard::create_dashboard_routes()
+            .route_layer(middleware::from_fn_with_state(state.clone(), api::auth::login_guard)))
+        .nest("/api/auth", api::auth::create_auth_routes()
+            .route_layer(middleware::from_fn_with_state(state.clone(), api::auth::login_guard)))
+
+        // Dashboard app, including the fallback for its client-side routes
+        .merge(api::frontend::create_frontend_routes(api::frontend::Frontend::from_settings(&state.settings.load())));
+
+    // Vertex AI handlers do no authentication of their own, so require client credentials here
+    if state.vertex_enabled {
+        let gemini_fallback = (state.settings.load().fallback_provider == FallbackProvider::Gemini).then(|| state.clone());
+        app = app.nest_service("/vertex", vertex::create_vertex_router(state.settings.load(), gemini_fallback)
+            .layer(middleware::from_fn_with_state(state.clone(), api::auth::client_auth_guard))
+            .layer(body_limit));
+    }
 
-        // Root routes
-        .route("/", get(serve_login_page))
-        .route("/dashboard", get(serve_dashboard_page))
+    let mut app = app
+        // IP filtering covers everything above; /health is added after it so orchestrator
+        // probes are never rejected
+        .layer(middleware::from_fn_with_state(state.clone(), api::auth::ip_access_guard))
 
-        // Health check
+        // Health checks: fast liveness and upstream-aware readiness
         .route("/health", get(health_check))
+        .route("/health/ready", get(readiness_check));
 
+    if state.vertex_enabled {
+        app = app.route("/health/vertex", get(vertex::vertex_health_check));
+    }
+
+    // Time spent inside rujimi per route, next to the upstream latency kept in stats
+    let mut app = app
+        .layer(middleware::from_fn_with_state(
+            state.stats_manager.route_metrics(),
+            utils::route_metrics::track_route_metrics,
+        ))
         // State
         .with_state(state)
+        .layer(cors);
 
-        // Middleware
-        .layer(
-            ServiceBuilder::new()
-                .layer(TraceLayer::new_for_http())
-                .layer(CompressionLayer::new())
-                .layer(cors)
-        );
+    // Middleware
+    if let Some(compression) = compression {
+        app = app.layer(compression);
+    }
+    let app = app.layer(TraceLayer::new_for_http());
 
     Ok(app)
 }
 
-async fn serve_login_page() -> impl IntoResponse {
-    let html = include_str!("../assets/index.html");
-    Html(html)
-}
-
-async fn serve_dashboard_page() -> impl IntoResponse {
-    let html = include_str!("../assets/index.html");
-    Html(html)
-}
-
 async fn health_check(State(state): State<AppState>) -> impl IntoResponse {
+    let upstream_proxy = http_client::resolve_upstream_proxy(&state.settings.load());
     let status = serde_json::json!({
         "status": "healthy",
         "version": "1.0.2",
         "timestamp": chrono::Utc::now().to_rfc3339(),
         "api_keys_available": state.key_manager.available_keys_count().await,
+        "requests_waiting_for_key": state.key_manager.waiting_requests(),
         "cache_entries": state.cache_manager.size().await,
+        "proxy": {
+            "enabled": upstream_proxy.is_some(),
+            "url": upstream_proxy.map(|proxy| http_client::redact_proxy_url(&proxy)),
+        },
     });
 
     (StatusCode::OK, axum::Json(status))
 }
+
+async fn readiness_check(State(state): State<AppState>) -> impl IntoResponse {
+    let report = state
+        .readiness
+        .get_or_refresh(health::READINESS_CACHE_TTL, || check_readiness(&state))
+        .await;
+
+    let status = if report.is_ready() { StatusCode::OK } else { StatusCode::SERVICE_UNAVAILABLE };
+    (status, axum::Json(report))
+}
+
+/// Probe upstream with a rotated key and check the local dependencies
+async fn check_readiness(state: &AppState) -> ReadinessReport {
+    let mut components = Vec::new();
+    let available_keys = state.key_manager.available_keys_count().await;
+
+    match state.key_manager.get_next_key().await {
+        // Vertex AI serves every request, so there is no Gemini upstream to probe
+        None if state.vertex_enabled => {
+            components.push(ComponentStatus::healthy("api_keys", "none configured, serving through Vertex AI".to_string()));
+        }
+        None => {
+            components.push(ComponentStatus::failing("upstream", "no API key to probe with"));
+            components.push(ComponentStatus::failing("api_keys", "no valid API keys"));
+        }
+        Some(key) => match state.gemini_client.probe_upstream(&key).await {
+            Ok(status) if status.is_success() || status == StatusCode::TOO_MANY_REQUESTS => {
+                components.push(ComponentStatus::healthy("upstream", None));
+                components.push(ComponentStatus::healthy("api_keys", format!("{} available", available_keys)));
+            }
+            Ok(status) if matches!(status.as_u16(), 400 | 401 | 403) => {
+                components.push(ComponentStatus::healthy("upstream", None));
+                components.push(ComponentStatus::failing("api_keys", format!("probe key rejected with {}", status)));
+            }
+            Ok(status) => {
+                components.push(ComponentStatus::failing("upstream", format!("upstream returned {}", status)));
+                components.push(ComponentStatus::healthy("api_keys", format!("{} available, unverified", available_keys)));
+            }
+            Err(e) => {
+                components.push(ComponentStatus::failing("upstream", e.to_string()));
+                components.push(ComponentStatus::healthy("api_keys", format!("{} available, unverified", available_keys)));
+            }
+        },
+    }
+
+    // Endpoints are skipped in turn; only once all of them are does the upstream look down
+    let endpoints = state.gemini_client.endpoint_status().await;
+    if endpoints.len() > 1 {
+        let healthy = endpoints.iter().filter(|endpoint| endpoint.healthy).count();
+        let detail = format!("{} of {} healthy", healthy, endpoints.len());
+        components.push(match healthy {
+            0 => ComponentStatus::failing("endpoints", detail),
+            _ => ComponentStatus::healthy("endpoints", detail),
+        });
+    }
+
+    let cache_entries = state.cache_manager.size().await;
+    components.push(ComponentStatus::healthy("cache", format!("{} entries", cache_entries)));
+
+    if state.settings.load().enable_storage {
+        components.push(match health::check_storage_writable(&state.settings.load().storage_dir).await {
+            Ok(()) => ComponentStatus::healthy("storage", None),
+            Err(e) => ComponentStatus::failing("storage", e),
+        });
+    }
+
+    ReadinessReport::new(components).with_endpoints(endpoints)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::config::Settings;
+    use crate::testing::test_state;
+    use http_body_util::BodyExt;
+
+    #[tokio::test]
+    async fn test_oversized_body_gets_json_413() {
+        use tower::ServiceExt;
+
+        let mut state = test_state();
+        state.settings = Settings { max_request_body_mb: 1, ..Default::default() }.into();
+        let app = crate::build_app(state).await.unwrap();
+
+        let image = "A".repeat(2 * 1024 * 1024);
+        let body = serde_json::json!({
+            "model": "gemini-2.0-flash",
+            "messages": [{"role": "user", "content": [
+                {"type": "image_url", "image_url": {"url": format!("data:image/png;base64,{}", image)}}
+            ]}]
+        });
+        let request = hyper::Request::builder()
+            .method("POST")
+            .uri("/v1/chat/completions")
+            .header("content-type", "application/json")
+            .body(axum::body::Body::from(body.to_string()))
+            .unwrap();
+
+        let response = app.oneshot(request).await.unwrap();
+        assert_eq!(response.status(), 413);
+        let body = response.into_body().collect().await.unwrap().to_bytes();
+        let json: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(json["error"]["type"], "request_too_large");
+        assert!(json["error"]["message"].as_str().unwrap().contains("1 MB"));
+    }
+
+    #[tokio::test]
+    async fn test_vertex_routes_mounted_only_when_enabled() {
+        use tower::ServiceExt;
+
+        let request = |uri: &str, auth: Option<&str>| {
+            let mut builder = hyper::Request::builder().uri(uri);
+            if let Some(auth) = auth {
+                builder = builder.header("authorization", auth);
+            }
+            builder.body(axum::body::Body::empty()).unwrap()
+        };
+
+        let app = crate::build_app(test_state()).await.unwrap();
+        let response = app.clone().oneshot(request("/vertex/status", Some("Bearer 123"))).await.unwrap();
+        assert_eq!(response.status(), 404);
+        let response = app.oneshot(request("/health/vertex", None)).await.unwrap();
+        assert_eq!(response.status(), 404);
+
+        let mut state = test_state();
+        state.vertex_enabled = true;
+        let app = crate::build_app(state).await.unwrap();
+        let response = app.clone().oneshot(request("/vertex/status", Some("Bearer wrong"))).await.unwrap();
+        assert_eq!(response.status(), 401);
+        let response = app.clone().oneshot(request("/vertex/status", Some("Bearer 123"))).await.unwrap();
+        assert_eq!(response.status(), 200);
+        let response = app.clone().oneshot(request("/vertex/credentials/stats", None)).await.unwrap();
+        assert_eq!(response.status(), 401);
+        let response = app.clone().oneshot(request("/vertex/credentials/stats", Some("Bearer 123"))).await.unwrap();
+        assert_eq!(response.status(), 200);
+        let body = response.into_body().collect().await.unwrap().to_bytes();
+        let json: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        assert!(json["credentials"].is_array());
+
+        let response = app.oneshot(request("/health/vertex", None)).await.unwrap();
+        assert_eq!(response.status(), 200);
+        let body = response.into_body().collect().await.unwrap().to_bytes();
+        let json: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(json["service"], "vertex_ai");
+    }
+
+    #[tokio::test]
+    async fn test_vertex_only_deployment_is_ready_without_keys() {
+        use tower::ServiceExt;
+
+        let ready = |vertex_enabled: bool| async move {
+            let mut state = test_state();
+            state.vertex_enabled = vertex_enabled;
+            let app = crate::build_app(state).await.unwrap();
+            let request = hyper::Request::builder().uri("/health/ready").body(axum::body::Body::empty()).unwrap();
+            let response = app.oneshot(request).await.unwrap();
+            let status = response.status();
+            let body = response.into_body().collect().await.unwrap().to_bytes();
+            (status, serde_json::from_slice::<serde_json::Value>(&body).unwrap())
+        };
+
+        let (status, _) = ready(false).await;
+        assert_eq!(status, 503);
+
+        let (status, report) = ready(true).await;
+        assert_eq!(status, 200, "{}", report);
+        let components = report["components"].as_array().unwrap();
+        let api_keys = components.iter().find(|component| component["name"] == "api_keys").unwrap();
+        assert!(api_keys["detail"].as_str().unwrap().contains("Vertex AI"));
+        assert!(!components.iter().any(|component| component["name"] == "upstream"));
+    }
+}
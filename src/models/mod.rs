@@ -1 +1,2 @@
-pub mod schemas;
\ No newline at end of file
+pub mod schemas;
+pub mod variant;
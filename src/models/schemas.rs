@@ -1,4 +1,4 @@
-use serde::{Deserialize, Serialize};
+use serde::{de::{self, DeserializeOwned}, Deserialize, Deserializer, Serialize};
 use std::collections::HashMap;
 
 // OpenAI compatible request/response models
@@ -19,8 +19,75 @@ pub struct ChatCompletionRequest {
     pub tools: Option<Vec<Tool>>,
     #[serde(default)]
     pub tool_choice: Option<ToolChoice>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub stream_options: Option<StreamOptions>,
+    /// The caller's end-user id, recorded hashed for abuse attribution
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub user: Option<String>,
     #[serde(flatten)]
     pub extra: HashMap<String, serde_json::Value>,
+    /// Set from `X-Rujimi-*` headers, never from the body
+    #[serde(skip)]
+    pub overrides: RequestOverrides,
+    /// Oldest non-system messages left out of the Gemini request to fit the context window
+    #[serde(skip)]
+    pub context_trimmed: usize,
+    /// Incoming headers sent on with the upstream call (`forward_request_headers`)
+    #[serde(skip)]
+    pub forwarded_headers: Vec<(String, String)>,
+    /// The body's `array_content` field, taken out of `extra`; see `array_content_responses`
+    #[serde(skip)]
+    pub array_content: Option<bool>,
+}
+
+/// Per-request adjustments a caller asked for through `X-Rujimi-*` headers
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct RequestOverrides {
+    /// Neither read nor store the response cache
+    pub no_cache: bool,
+    /// Keep the cached answer for this many seconds instead of `cache_expiry_time`
+    pub cache_ttl: Option<u64>,
+    /// Serve with this configured upstream key instead of the rotation
+    pub key_index: Option<usize>,
+    pub safety: Option<SafetyOverride>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SafetyOverride {
+    /// Every category set to `OFF`
+    Off,
+    /// No safety settings sent, so Gemini applies its own defaults
+    Default,
+}
+
+impl ChatCompletionRequest {
+    /// Whether the client asked for a trailing usage chunk via `stream_options.include_usage`
+    pub fn include_usage(&self) -> bool {
+        self.stream_options.as_ref().is_some_and(|options| options.include_usage)
+    }
+
+    /// Whether the answer's content should be an array of parts, `default` unless the request
+    /// said otherwise. Streams always carry text deltas.
+    pub fn wants_array_content(&self, default: bool) -> bool {
+        !self.stream && self.array_content.unwrap_or(default)
+    }
+
+    /// Whether the request declares tools or asks for a JSON `response_format`
+    pub fn uses_tools_or_json(&self) -> bool {
+        let json_response = self.extra
+            .get("response_format")
+            .and_then(|format| format.get("type"))
+            .and_then(serde_json::Value::as_str)
+            .is_some_and(|format| format.starts_with("json"));
+        let uses_tools = self.tools.as_ref().is_some_and(|tools| !tools.is_empty());
+        json_response || uses_tools
+    }
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct StreamOptions {
+    #[serde(default)]
+    pub include_usage: bool,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -121,6 +188,8 @@ pub struct ChatCompletionChunk {
     pub choices: Vec<ChatChoiceDelta>,
     #[serde(default)]
     pub system_fingerprint: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub usage: Option<Usage>,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -164,14 +233,56 @@ pub struct FunctionCallDelta {
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Usage {
     pub prompt_tokens: u32,
+    /// Includes any reasoning tokens, as in OpenAI's o-series usage
     pub completion_tokens: u32,
     pub total_tokens: u32,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub completion_tokens_details: Option<CompletionTokensDetails>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct CompletionTokensDetails {
+    /// Tokens a thinking model spent before answering
+    #[serde(default)]
+    pub reasoning_tokens: u32,
+}
+
+impl Usage {
+    pub fn new(prompt_tokens: u32, completion_tokens: u32) -> Self {
+        Self {
+            prompt_tokens,
+            completion_tokens,
+            total_tokens: prompt_tokens + completion_tokens,
+            completion_tokens_details: None,
+        }
+    }
+
+    /// Gemini counts thinking separately from the answer; OpenAI counts it in
+    /// `completion_tokens` and breaks it out in `completion_tokens_details`
+    pub fn from_gemini(meta: &GeminiUsageMetadata) -> Self {
+        let prompt_tokens = meta.prompt_token_count.unwrap_or(0);
+        let reasoning_tokens = meta.thoughts_token_count.unwrap_or(0);
+        let completion_tokens = meta.candidates_token_count.unwrap_or(0) + reasoning_tokens;
+        Self {
+            prompt_tokens,
+            completion_tokens,
+            total_tokens: meta.total_token_count.unwrap_or(prompt_tokens + completion_tokens),
+            completion_tokens_details: meta.thoughts_token_count.map(|reasoning_tokens| CompletionTokensDetails { reasoning_tokens }),
+        }
+    }
+
+    pub fn reasoning_tokens(&self) -> u32 {
+        self.completion_tokens_details.as_ref().map_or(0, |details| details.reasoning_tokens)
+    }
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct ModelResponse {
     pub object: String,
     pub data: Vec<Model>,
+    /// Unix time the list was last loaded from upstream, absent while using the defaults
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub refreshed_at: Option<i64>,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -180,6 +291,289 @@ pub struct Model {
     pub object: String,
     pub created: u64,
     pub owned_by: String,
+    // Extensions from the upstream model metadata, so clients can auto-configure limits
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub context_length: Option<u32>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub max_output_tokens: Option<u32>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub capabilities: Vec<String>,
+}
+
+impl Model {
+    /// A model known only by name, without upstream metadata
+    pub fn named(id: &str, created: u64) -> Self {
+        Self {
+            id: id.to_string(),
+            object: "model".to_string(),
+            created,
+            owned_by: "google".to_string(),
+            context_length: None,
+            max_output_tokens: None,
+            capabilities: Vec::new(),
+        }
+    }
+
+    /// A synthetic variant such as `-search` sharing this model's metadata
+    pub fn variant(&self, suffix: &str) -> Self {
+        Self {
+            id: format!("{}{}", self.id, suffix),
+            ..self.clone()
+        }
+    }
+}
+
+/// One page of Gemini's `GET /models` listing
+#[derive(Debug, Clone, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct GeminiModelList {
+    #[serde(default)]
+    pub models: Vec<GeminiModelInfo>,
+    #[serde(default)]
+    pub next_page_token: Option<String>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct GeminiModelInfo {
+    /// `models/<id>`
+    pub name: String,
+    #[serde(default)]
+    pub input_token_limit: Option<u32>,
+    #[serde(default)]
+    pub output_token_limit: Option<u32>,
+    #[serde(default)]
+    pub supported_generation_methods: Vec<String>,
+    #[serde(default)]
+    pub thinking: bool,
+}
+
+impl GeminiModelInfo {
+    pub fn id(&self) -> &str {
+        self.name.strip_prefix("models/").unwrap_or(&self.name)
+    }
+
+    /// Gemini generation models are multimodal and support function calling; the
+    /// listing does not say so explicitly, so it is inferred from the family
+    pub fn capabilities(&self) -> Vec<String> {
+        let supports = |method: &str| self.supported_generation_methods.iter().any(|m| m == method);
+        let mut capabilities = Vec::new();
+
+        if supports("generateContent") && self.id().starts_with("gemini") {
+            capabilities.push("vision".to_string());
+            capabilities.push("tools".to_string());
+        }
+        if self.thinking {
+            capabilities.push("thinking".to_string());
+        }
+        if supports("embedContent") {
+            capabilities.push("embeddings".to_string());
+        }
+        capabilities
+    }
+
+    pub fn to_model(&self, created: u64) -> Model {
+        Model {
+            context_length: self.input_token_limit,
+            max_output_tokens: self.output_token_limit,
+            capabilities: self.capabilities(),
+            ..Model::named(self.id(), created)
+        }
+    }
+}
+
+// Legacy text completion models
+
+/// A `/v1/completions` request. It is served as a chat request with one user message.
+#[derive(Debug, Clone, Deserialize)]
+pub struct CompletionRequest {
+    pub model: String,
+    pub prompt: CompletionPrompt,
+    #[serde(default)]
+    pub max_tokens: Option<u32>,
+    #[serde(default)]
+    pub temperature: Option<f32>,
+    #[serde(default)]
+    pub top_p: Option<f32>,
+    /// A stop sequence or a list of them
+    #[serde(default)]
+    pub stop: Option<serde_json::Value>,
+    #[serde(default)]
+    pub stream: bool,
+    #[serde(default)]
+    pub user: Option<String>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(untagged)]
+pub enum CompletionPrompt {
+    Text(String),
+    Texts(Vec<String>),
+}
+
+impl CompletionRequest {
+    /// The equivalent chat request; an array prompt is joined into one message by newlines
+    pub fn into_chat_request(self) -> ChatCompletionRequest {
+        let prompt = match self.prompt {
+            CompletionPrompt::Text(text) => text,
+            CompletionPrompt::Texts(texts) => texts.join("\n"),
+        };
+        let mut extra = HashMap::new();
+        if let Some(stop) = self.stop {
+            extra.insert("stop".to_string(), stop);
+        }
+        ChatCompletionRequest {
+            model: self.model,
+            messages: vec![ChatMessage {
+                role: "user".to_string(),
+                content: Some(serde_json::Value::String(prompt)),
+                name: None,
+                tool_calls: None,
+                tool_call_id: None,
+            }],
+            stream: self.stream,
+            temperature: self.temperature,
+            top_p: self.top_p,
+            max_tokens: self.max_tokens,
+            tools: None,
+            tool_choice: None,
+            stream_options: None,
+            user: self.user,
+            extra,
+            overrides: RequestOverrides::default(),
+            context_trimmed: 0,
+            forwarded_headers: Vec::new(),
+            // Text completions are built from the answer as one string
+            array_content: Some(false),
+        }
+    }
+}
+
+// Responses API models
+
+/// A `/v1/responses` request. Only text and image input is supported; it is served as a
+/// chat request.
+#[derive(Debug, Clone, Deserialize)]
+pub struct ResponsesRequest {
+    pub model: String,
+    pub input: ResponsesInput,
+    /// Sent as a system message ahead of the input
+    #[serde(default)]
+    pub instructions: Option<String>,
+    #[serde(default)]
+    pub temperature: Option<f32>,
+    #[serde(default)]
+    pub top_p: Option<f32>,
+    #[serde(default)]
+    pub max_output_tokens: Option<u32>,
+    #[serde(default)]
+    pub stream: bool,
+    #[serde(default)]
+    pub tools: Option<Vec<serde_json::Value>>,
+    #[serde(default)]
+    pub previous_response_id: Option<String>,
+    #[serde(default)]
+    pub user: Option<String>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(untagged)]
+pub enum ResponsesInput {
+    Text(String),
+    Items(Vec<serde_json::Value>),
+}
+
+impl ResponsesRequest {
+    /// The equivalent chat request, or why the request uses something that is not supported
+    pub fn into_chat_request(self) -> Result<ChatCompletionRequest, String> {
+        if self.tools.as_ref().is_some_and(|tools| !tools.is_empty()) {
+            return Err("Tools are not supported on /v1/responses; use /v1/chat/completions for tool calling".to_string());
+        }
+        if self.previous_response_id.is_some() {
+            return Err("previous_response_id is not supported; responses are not stored, so send the whole conversation as input".to_string());
+        }
+
+        let mut messages = Vec::new();
+        if let Some(instructions) = self.instructions.filter(|instructions| !instructions.is_empty()) {
+            messages.push(chat_message("system", serde_json::Value::String(instructions)));
+        }
+        match self.input {
+            ResponsesInput::Text(text) => messages.push(chat_message("user", serde_json::Value::String(text))),
+            ResponsesInput::Items(items) => {
+                for (index, item) in items.iter().enumerate() {
+                    messages.push(responses_item_message(item).map_err(|e| format!("input[{}]: {}", index, e))?);
+                }
+            }
+        }
+
+        Ok(ChatCompletionRequest {
+            model: self.model,
+            messages,
+            stream: self.stream,
+            temperature: self.temperature,
+            top_p: self.top_p,
+            max_tokens: self.max_output_tokens,
+            tools: None,
+            tool_choice: None,
+            // The completed event reports usage, so streams always ask for it
+            stream_options: self.stream.then_some(StreamOptions { include_usage: true }),
+            user: self.user,
+            extra: HashMap::new(),
+            overrides: RequestOverrides::default(),
+            context_trimmed: 0,
+            forwarded_headers: Vec::new(),
+            // Responses output items are built from the answer as one string
+            array_content: Some(false),
+        })
+    }
+}
+
+fn chat_message(role: &str, content: serde_json::Value) -> ChatMessage {
+    ChatMessage {
+        role: role.to_string(),
+        content: Some(content),
+        name: None,
+        tool_calls: None,
+        tool_call_id: None,
+    }
+}
+
+/// A Responses input message as a chat message. `developer` becomes `system`, and the
+/// `input_text`, `output_text` and `input_image` parts become chat content parts.
+fn responses_item_message(item: &serde_json::Value) -> Result<ChatMessage, String> {
+    let item_type = item.get("type").and_then(serde_json::Value::as_str).unwrap_or("message");
+    if item_type != "message" {
+        return Err(format!("input items of type `{}` are not supported", item_type));
+    }
+    let role = match item.get("role").and_then(serde_json::Value::as_str) {
+        Some("developer") => "system",
+        Some(role @ ("system" | "user" | "assistant")) => role,
+        Some(role) => return Err(format!("unsupported role `{}`", role)),
+        None => return Err("missing role".to_string()),
+    };
+
+    let content = match item.get("content") {
+        Some(serde_json::Value::String(text)) => serde_json::Value::String(text.clone()),
+        Some(serde_json::Value::Array(parts)) => {
+            let parts = parts
+                .iter()
+                .map(|part| match part.get("type").and_then(serde_json::Value::as_str) {
+                    Some("input_text" | "output_text") => Ok(serde_json::json!({
+                        "type": "text",
+                        "text": part.get("text").cloned().unwrap_or_default(),
+                    })),
+                    Some("input_image") => match part.get("image_url").and_then(serde_json::Value::as_str) {
+                        Some(url) => Ok(serde_json::json!({"type": "image_url", "image_url": {"url": url}})),
+                        None => Err("input_image parts need an image_url".to_string()),
+                    },
+                    other => Err(format!("content parts of type `{}` are not supported", other.unwrap_or("unknown"))),
+                })
+                .collect::<Result<Vec<_>, String>>()?;
+            serde_json::Value::Array(parts)
+        }
+        _ => return Err("content must be a string or an array of content parts".to_string()),
+    };
+    Ok(chat_message(role, content))
 }
 
 // Embedding models
@@ -226,6 +620,76 @@ pub struct EmbeddingUsage {
     pub total_tokens: u32,
 }
 
+/// OpenAI `/v1/moderations` request, answered from Gemini safety ratings
+#[derive(Debug, Clone, Deserialize)]
+pub struct ModerationRequest {
+    pub input: ModerationInput,
+    /// OpenAI moderation model names are accepted; a `gemini-*` name picks the rating model
+    #[serde(default)]
+    pub model: Option<String>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(untagged)]
+pub enum ModerationInput {
+    Text(String),
+    Texts(Vec<String>),
+    /// Multimodal input; only the `text` parts are rated
+    Parts(Vec<serde_json::Value>),
+}
+
+impl ModerationInput {
+    /// The texts to rate, one result each
+    pub fn texts(self) -> Vec<String> {
+        match self {
+            ModerationInput::Text(text) => vec![text],
+            ModerationInput::Texts(texts) => texts,
+            ModerationInput::Parts(parts) => parts
+                .iter()
+                .filter(|part| part.get("type").and_then(serde_json::Value::as_str) == Some("text"))
+                .filter_map(|part| part.get("text").and_then(serde_json::Value::as_str))
+                .map(str::to_string)
+                .collect(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ModerationResponse {
+    pub id: String,
+    pub model: String,
+    pub results: Vec<ModerationResult>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ModerationResult {
+    pub flagged: bool,
+    pub categories: std::collections::BTreeMap<String, bool>,
+    pub category_scores: std::collections::BTreeMap<String, f64>,
+}
+
+/// OpenAI `/v1/images/generations` request; Gemini decides the image size itself
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ImageGenerationRequest {
+    pub prompt: String,
+    #[serde(default)]
+    pub model: Option<String>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ImageGenerationResponse {
+    pub created: u64,
+    pub data: Vec<ImageData>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ImageData {
+    pub b64_json: String,
+    /// Text the model returned alongside the image
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub revised_prompt: Option<String>,
+}
+
 // Gemini specific models
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -239,6 +703,9 @@ pub struct GeminiRequest {
     pub tools: Option<Vec<GeminiTool>>,
     #[serde(default)]
     pub tool_config: Option<GeminiToolConfig>,
+    /// `cachedContents/...` holding the system prompt, which is then left out of `contents`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub cached_content: Option<String>,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -247,17 +714,65 @@ pub struct GeminiContent {
     pub parts: Vec<GeminiPart>,
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
+/// One part of a Gemini message. Deserialization picks the variant by which field is present
+/// (snake_case or camelCase), so extra keys such as `thought` or `thoughtSignature` are ignored
+/// and part types this proxy does not know are kept as `Unknown`.
+#[derive(Debug, Clone, Serialize)]
 #[serde(untagged)]
 pub enum GeminiPart {
     Text { text: String },
     InlineData { inline_data: GeminiInlineData },
     FunctionCall { function_call: GeminiFunctionCall },
     FunctionResponse { function_response: GeminiFunctionResponse },
+    ExecutableCode { executable_code: GeminiExecutableCode },
+    CodeExecutionResult { code_execution_result: GeminiCodeExecutionResult },
+    Unknown(serde_json::Value),
+}
+
+impl<'de> Deserialize<'de> for GeminiPart {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let mut fields = match serde_json::Value::deserialize(deserializer)? {
+            serde_json::Value::Object(fields) => fields,
+            other => return Ok(GeminiPart::Unknown(other)),
+        };
+
+        if let Some(function_call) = take_part_field(&mut fields, "function_call", "functionCall")? {
+            return Ok(GeminiPart::FunctionCall { function_call });
+        }
+        if let Some(function_response) = take_part_field(&mut fields, "function_response", "functionResponse")? {
+            return Ok(GeminiPart::FunctionResponse { function_response });
+        }
+        if let Some(executable_code) = take_part_field(&mut fields, "executable_code", "executableCode")? {
+            return Ok(GeminiPart::ExecutableCode { executable_code });
+        }
+        if let Some(code_execution_result) = take_part_field(&mut fields, "code_execution_result", "codeExecutionResult")? {
+            return Ok(GeminiPart::CodeExecutionResult { code_execution_result });
+        }
+        if let Some(inline_data) = take_part_field(&mut fields, "inline_data", "inlineData")? {
+            return Ok(GeminiPart::InlineData { inline_data });
+        }
+        if let Some(text) = take_part_field(&mut fields, "text", "text")? {
+            return Ok(GeminiPart::Text { text });
+        }
+        Ok(GeminiPart::Unknown(serde_json::Value::Object(fields)))
+    }
+}
+
+/// Remove and parse the part field stored under either spelling
+fn take_part_field<T: DeserializeOwned, E: de::Error>(
+    fields: &mut serde_json::Map<String, serde_json::Value>,
+    name: &str,
+    camel_name: &str,
+) -> Result<Option<T>, E> {
+    match fields.remove(name).or_else(|| fields.remove(camel_name)) {
+        Some(value) => serde_json::from_value(value).map(Some).map_err(E::custom),
+        None => Ok(None),
+    }
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct GeminiInlineData {
+    #[serde(alias = "mimeType")]
     pub mime_type: String,
     pub data: String,
 }
@@ -274,6 +789,22 @@ pub struct GeminiFunctionResponse {
     pub response: serde_json::Value,
 }
 
+/// Code the model ran with the `codeExecution` tool
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct GeminiExecutableCode {
+    #[serde(default)]
+    pub language: String,
+    pub code: String,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct GeminiCodeExecutionResult {
+    #[serde(default)]
+    pub outcome: String,
+    #[serde(default)]
+    pub output: Option<String>,
+}
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct GeminiGenerationConfig {
     #[serde(default)]
@@ -288,6 +819,12 @@ pub struct GeminiGenerationConfig {
     pub max_output_tokens: Option<u32>,
     #[serde(default)]
     pub stop_sequences: Option<Vec<String>>,
+    /// `["TEXT", "IMAGE"]` asks image-capable models for inline images
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub response_modalities: Option<Vec<String>>,
+    /// Passed through from the client's `thinking_config`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub thinking_config: Option<serde_json::Value>,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -296,9 +833,16 @@ pub struct GeminiSafetySetting {
     pub threshold: String,
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
 pub struct GeminiTool {
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
     pub function_declarations: Vec<GeminiFunctionDeclaration>,
+    /// Set (to `{}`) to let the model run code
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub code_execution: Option<serde_json::Map<String, serde_json::Value>>,
+    /// Set (to `{}`) to ground answers with Google Search
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub google_search: Option<serde_json::Map<String, serde_json::Value>>,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -322,17 +866,18 @@ pub struct GeminiFunctionCallingConfig {
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct GeminiResponse {
-    pub candidates: Vec<GeminiCandidate>,
     #[serde(default)]
+    pub candidates: Vec<GeminiCandidate>,
+    #[serde(default, alias = "usageMetadata")]
     pub usage_metadata: Option<GeminiUsageMetadata>,
-    #[serde(default)]
+    #[serde(default, alias = "promptFeedback")]
     pub prompt_feedback: Option<GeminiPromptFeedback>,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct GeminiCandidate {
     pub content: GeminiContent,
-    #[serde(default)]
+    #[serde(default, alias = "finishReason")]
     pub finish_reason: Option<String>,
     #[serde(default)]
     pub index: Option<u32>,
@@ -348,17 +893,20 @@ pub struct GeminiSafetyRating {
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct GeminiUsageMetadata {
-    #[serde(default)]
+    #[serde(default, alias = "promptTokenCount")]
     pub prompt_token_count: Option<u32>,
-    #[serde(default)]
+    #[serde(default, alias = "candidatesTokenCount")]
     pub candidates_token_count: Option<u32>,
-    #[serde(default)]
+    #[serde(default, alias = "totalTokenCount")]
     pub total_token_count: Option<u32>,
+    /// Thinking tokens of 2.5 models, not included in `candidates_token_count`
+    #[serde(default, alias = "thoughtsTokenCount")]
+    pub thoughts_token_count: Option<u32>,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct GeminiPromptFeedback {
-    #[serde(default)]
+    #[serde(default, alias = "blockReason")]
     pub block_reason: Option<String>,
     #[serde(default)]
     pub safety_ratings: Option<Vec<GeminiSafetyRating>>,
@@ -397,6 +945,9 @@ pub struct ServiceStatus {
     pub running: bool,
     pub uptime: u64,
     pub api_keys_available: usize,
+    /// Requests parked until a cooling-down key is usable again
+    #[serde(default)]
+    pub requests_waiting_for_key: usize,
     pub cache_entries: usize,
 }
 
@@ -406,9 +957,32 @@ pub struct ApiStats {
     pub successful_requests: u64,
     pub failed_requests: u64,
     pub tokens_used: u64,
+    #[serde(default)]
+    pub prompt_tokens: u64,
+    #[serde(default)]
+    pub completion_tokens: u64,
+    /// Part of `completion_tokens` that thinking models spent reasoning
+    #[serde(default)]
+    pub reasoning_tokens: u64,
     pub requests_per_minute: u32,
     pub requests_per_hour: u32,
     pub requests_per_day: u32,
+    #[serde(default)]
+    pub upstream_timeouts: u64,
+    /// Anonymous public-mode traffic
+    #[serde(default)]
+    pub public_requests: u64,
+    #[serde(default)]
+    pub public_tokens: u64,
+    /// Requests served by the fallback provider
+    #[serde(default)]
+    pub fallback_requests: u64,
+    /// Requests raced on a second key
+    #[serde(default)]
+    pub hedged_requests: u64,
+    /// Test requests sent from the dashboard
+    #[serde(default)]
+    pub diagnostic_requests: u64,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -418,6 +992,8 @@ pub struct ConfigInfo {
     pub cache_enabled: bool,
     pub vertex_enabled: bool,
     pub search_mode: bool,
+    /// `none` or `browser`, see `upstream_header_profile`
+    pub upstream_header_profile: String,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -425,4 +1001,6 @@ pub struct VersionInfo {
     pub current: String,
     pub latest: Option<String>,
     pub update_available: bool,
+    /// Page with the release notes of the latest release
+    pub release_url: Option<String>,
 }
\ No newline at end of file
@@ -0,0 +1,148 @@
+//! Synthetic model names: a Gemini model id followed by any of the `-search`, `-thinking` and
+//! `-nothinking` suffixes. Upstream only ever sees the base id; the suffixes turn into request
+//! options, and everything keyed by model (policy, cache, stats) goes through `ModelVariant`.
+
+const SEARCH_SUFFIX: &str = "-search";
+const THINKING_SUFFIX: &str = "-thinking";
+const NO_THINKING_SUFFIX: &str = "-nothinking";
+
+/// Thinking asked for by a suffix
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Thinking {
+    /// No suffix: the model's own default
+    #[default]
+    Default,
+    /// `-thinking`: dynamic thinking, also on models that do not think by default
+    On,
+    /// `-nothinking`: a thinking budget of 0, which only the 2.5 Flash models accept
+    Off,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ModelVariant {
+    pub base: String,
+    /// Ground the answer with Google Search (when `search_mode` is on)
+    pub search: bool,
+    pub thinking: Thinking,
+}
+
+impl ModelVariant {
+    /// Split a requested model into its base id and suffix flags, in any suffix order. Each
+    /// suffix counts once; a repeated one is left as part of the base.
+    pub fn parse(model: &str) -> Self {
+        let mut variant = Self { base: model.to_string(), search: false, thinking: Thinking::Default };
+        loop {
+            let base = variant.base.as_str();
+            let strip = |suffix: &str| base.strip_suffix(suffix).filter(|rest| !rest.is_empty()).map(str::to_string);
+            let stripped = if variant.search {
+                None
+            } else {
+                strip(SEARCH_SUFFIX).map(|rest| (rest, true, variant.thinking))
+            };
+            let stripped = stripped.or_else(|| match variant.thinking {
+                Thinking::Default => strip(NO_THINKING_SUFFIX)
+                    .map(|rest| (rest, variant.search, Thinking::Off))
+                    .or_else(|| strip(THINKING_SUFFIX).map(|rest| (rest, variant.search, Thinking::On))),
+                _ => None,
+            });
+            match stripped {
+                Some((base, search, thinking)) => variant = Self { base, search, thinking },
+                None => return variant,
+            }
+        }
+    }
+
+    pub fn is_plain(&self) -> bool {
+        !self.search && self.thinking == Thinking::Default
+    }
+
+    /// Suffix flags, e.g. `["thinking", "search"]`
+    pub fn flags(&self) -> Vec<&'static str> {
+        let mut flags = Vec::new();
+        match self.thinking {
+            Thinking::Default => {}
+            Thinking::On => flags.push("thinking"),
+            Thinking::Off => flags.push("nothinking"),
+        }
+        if self.search {
+            flags.push("search");
+        }
+        flags
+    }
+
+    /// The model name with its suffixes in canonical order: thinking first, then search
+    pub fn name(&self) -> String {
+        let thinking = match self.thinking {
+            Thinking::Default => "",
+            Thinking::On => THINKING_SUFFIX,
+            Thinking::Off => NO_THINKING_SUFFIX,
+        };
+        let search = if self.search { SEARCH_SUFFIX } else { "" };
+        format!("{}{}{}", self.base, thinking, search)
+    }
+
+    /// `thinking_budget` the suffix forces: -1 (dynamic) or 0
+    pub fn thinking_budget(&self) -> Option<i64> {
+        match self.thinking {
+            Thinking::Default => None,
+            Thinking::On => Some(-1),
+            Thinking::Off => Some(0),
+        }
+    }
+
+    /// Whether a `-nothinking` variant of `base` is offered
+    pub fn can_disable_thinking(base: &str) -> bool {
+        base.contains("2.5-flash")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_every_suffix_combination() {
+        let cases = [
+            ("gemini-2.5-flash", false, Thinking::Default, "gemini-2.5-flash"),
+            ("gemini-2.5-flash-search", true, Thinking::Default, "gemini-2.5-flash-search"),
+            ("gemini-2.5-flash-thinking", false, Thinking::On, "gemini-2.5-flash-thinking"),
+            ("gemini-2.5-flash-nothinking", false, Thinking::Off, "gemini-2.5-flash-nothinking"),
+            ("gemini-2.5-flash-thinking-search", true, Thinking::On, "gemini-2.5-flash-thinking-search"),
+            ("gemini-2.5-flash-search-thinking", true, Thinking::On, "gemini-2.5-flash-thinking-search"),
+            ("gemini-2.5-flash-nothinking-search", true, Thinking::Off, "gemini-2.5-flash-nothinking-search"),
+            ("gemini-2.5-flash-search-nothinking", true, Thinking::Off, "gemini-2.5-flash-nothinking-search"),
+        ];
+        for (requested, search, thinking, canonical) in cases {
+            let variant = ModelVariant::parse(requested);
+            assert_eq!(variant.base, "gemini-2.5-flash", "{}", requested);
+            assert_eq!((variant.search, variant.thinking), (search, thinking), "{}", requested);
+            assert_eq!(variant.name(), canonical);
+            assert_eq!(ModelVariant::parse(&variant.name()), variant);
+        }
+
+        assert_eq!(ModelVariant::parse("gemini-2.5-flash-thinking-search").flags(), ["thinking", "search"]);
+        assert_eq!(ModelVariant::parse("gemini-2.5-flash-nothinking").thinking_budget(), Some(0));
+        assert_eq!(ModelVariant::parse("gemini-2.5-pro-thinking").thinking_budget(), Some(-1));
+        assert!(ModelVariant::parse("gemini-2.5-flash").is_plain());
+        assert!(ModelVariant::parse("gemini-2.5-flash").flags().is_empty());
+    }
+
+    #[test]
+    fn test_repeated_or_bare_suffixes_stay_in_the_base() {
+        let variant = ModelVariant::parse("gemini-2.0-flash-search-search");
+        assert_eq!((variant.base.as_str(), variant.search), ("gemini-2.0-flash-search", true));
+
+        let variant = ModelVariant::parse("gemini-2.5-flash-thinking-nothinking");
+        assert_eq!((variant.base.as_str(), variant.thinking), ("gemini-2.5-flash-thinking", Thinking::Off));
+
+        assert!(ModelVariant::parse("-search").is_plain());
+        assert!(ModelVariant::parse("gemini-2.0-flash-thinking-exp").is_plain());
+    }
+
+    #[test]
+    fn test_nothinking_offered_on_flash_only() {
+        assert!(ModelVariant::can_disable_thinking("gemini-2.5-flash"));
+        assert!(ModelVariant::can_disable_thinking("gemini-2.5-flash-lite"));
+        assert!(!ModelVariant::can_disable_thinking("gemini-2.5-pro"));
+    }
+}
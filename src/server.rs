@@ -0,0 +1,291 @@
+use anyhow::{Context, Result};
+use axum::Router;
+use hyper_util::{
+    rt::{TokioExecutor, TokioIo},
+    server::conn::auto,
+    service::TowerToHyperService,
+};
+use std::path::{Path, PathBuf};
+use tokio::net::UnixListener;
+use tower_http::compression::{
+    predicate::{NotForContentType, Predicate, SizeAbove},
+    CompressionLayer,
+};
+use tokio::sync::watch;
+use tracing::{debug, info, warn};
+
+/// Response compression as configured, `None` when disabled. Streaming responses are
+/// never compressed, since the encoder would buffer streamed chunks until the response ends;
+/// the layer only sees responses, so they are told apart by content type.
+pub fn compression_layer(settings: &crate::config::Settings) -> Option<CompressionLayer<impl Predicate>> {
+    if !settings.compression_enabled {
+        return None;
+    }
+    let offers = |algorithm: &str| settings.compression_algorithms.iter().any(|offered| offered == algorithm);
+    let predicate = SizeAbove::new(settings.compression_min_size_bytes)
+        .and(NotForContentType::GRPC)
+        .and(NotForContentType::IMAGES)
+        .and(NotForContentType::SSE);
+    Some(
+        CompressionLayer::new()
+            .gzip(offers("gzip"))
+            .br(offers("br"))
+            .zstd(offers("zstd"))
+            .compress_when(predicate),
+    )
+}
+
+/// Resolves when the process receives Ctrl+C or SIGTERM
+pub async fn shutdown_signal() {
+    let ctrl_c = async {
+        if let Err(e) = tokio::signal::ctrl_c().await {
+            warn!("Failed to listen for Ctrl+C: {}", e);
+            std::future::pending::<()>().await;
+        }
+    };
+
+    #[cfg(unix)]
+    let terminate = async {
+        match tokio::signal::unix::signal(tokio::signal::unix::SignalKind::terminate()) {
+            Ok(mut signal) => {
+                signal.recv().await;
+            }
+            Err(e) => {
+                warn!("Failed to listen for SIGTERM: {}", e);
+                std::future::pending::<()>().await;
+            }
+        }
+    };
+
+    #[cfg(not(unix))]
+    let terminate = std::future::pending::<()>();
+
+    tokio::select! {
+        _ = ctrl_c => {},
+        _ = terminate => {},
+    }
+
+    info!("🛑 Shutdown signal received");
+}
+
+/// Wait until the shutdown channel fires
+pub async fn wait_for_shutdown(mut shutdown: watch::Receiver<bool>) {
+    while !*shutdown.borrow() {
+        if shutdown.changed().await.is_err() {
+            return;
+        }
+    }
+}
+
+/// Parse a permission mode such as `0660`, `660` or `0o660`
+pub fn parse_socket_mode(mode: &str) -> Result<u32> {
+    let digits = mode.trim().trim_start_matches("0o");
+    u32::from_str_radix(digits, 8).with_context(|| format!("Invalid listen_socket_mode '{}', expected octal like 0660", mode))
+}
+
+/// Bind a Unix domain socket, removing a stale socket file first and applying `mode`
+pub fn bind_unix_socket(path: &Path, mode: u32) -> Result<UnixListener> {
+    if path.exists() {
+        std::fs::remove_file(path)
+            .with_context(|| format!("Failed to remove stale socket file {:?}", path))?;
+    }
+
+    let listener = UnixListener::bind(path)
+        .with_context(|| format!("Failed to bind Unix socket {:?}", path))?;
+
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        std::fs::set_permissions(path, std::fs::Permissions::from_mode(mode))
+            .with_context(|| format!("Failed to set mode {:o} on {:?}", mode, path))?;
+    }
+
+    Ok(listener)
+}
+
+/// Serve the router on a Unix socket until shutdown, then remove the socket file
+pub async fn serve_unix(listener: UnixListener, path: PathBuf, app: Router, shutdown: watch::Receiver<bool>) -> Result<()> {
+    let _guard = SocketFileGuard(path.clone());
+    let shutdown_future = wait_for_shutdown(shutdown);
+    tokio::pin!(shutdown_future);
+
+    info!("🧦 Listening on unix:{}", path.display());
+
+    loop {
+        tokio::select! {
+            accepted = listener.accept() => {
+                let (stream, _) = match accepted {
+                    Ok(conn) => conn,
+                    Err(e) => {
+                        warn!("Failed to accept Unix socket connection: {}", e);
+                        continue;
+                    }
+                };
+
+                let service = TowerToHyperService::new(app.clone());
+                tokio::spawn(async move {
+                    if let Err(e) = auto::Builder::new(TokioExecutor::new())
+                        .serve_connection_with_upgrades(TokioIo::new(stream), service)
+                        .await
+                    {
+                        debug!("Unix socket connection closed with error: {}", e);
+                    }
+                });
+            }
+            _ = &mut shutdown_future => break,
+        }
+    }
+
+    Ok(())
+}
+
+/// Removes the socket file when the server stops, however it stops
+struct SocketFileGuard(PathBuf);
+
+impl Drop for SocketFileGuard {
+    fn drop(&mut self) {
+        if let Err(e) = std::fs::remove_file(&self.0) {
+            if e.kind() != std::io::ErrorKind::NotFound {
+                warn!("Failed to remove socket file {:?}: {}", self.0, e);
+            }
+        } else {
+            info!("Removed socket file {:?}", self.0);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::Settings;
+    use crate::testing::test_state;
+    use crate::utils::AuthState;
+    use http_body_util::BodyExt;
+    use std::sync::Arc;
+
+    #[tokio::test]
+    async fn test_sse_first_chunk_not_buffered_by_compression() {
+        use axum::response::sse::{Event, Sse};
+        use futures_util::{stream, StreamExt};
+        use std::time::{Duration, Instant};
+
+        // Mocked slow upstream: one event now, the next after a long pause
+        async fn slow_stream() -> axum::response::Response {
+            let events = stream::iter(0..2).then(|i| async move {
+                if i > 0 {
+                    tokio::time::sleep(Duration::from_millis(800)).await;
+                }
+                Ok::<Event, std::convert::Infallible>(Event::default().data(format!("chunk-{}", i)))
+            });
+            crate::utils::response::sse_response(Sse::new(events))
+        }
+
+        let app = Router::new()
+            .route("/stream", axum::routing::get(slow_stream))
+            .layer(compression_layer(&crate::config::Settings::default()).unwrap());
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move { axum::serve(listener, app).await.unwrap() });
+
+        let started = Instant::now();
+        let response = reqwest::Client::new()
+            .get(format!("http://{}/stream", addr))
+            .header("accept-encoding", "gzip")
+            .send()
+            .await
+            .unwrap();
+
+        assert!(response.headers().get("content-encoding").is_none());
+        assert_eq!(response.headers()["x-accel-buffering"], "no");
+        assert_eq!(response.headers()["cache-control"], "no-cache");
+
+        let mut body = response.bytes_stream();
+        let first = body.next().await.unwrap().unwrap();
+        assert!(String::from_utf8_lossy(&first).contains("chunk-0"));
+        assert!(started.elapsed() < Duration::from_millis(700));
+    }
+
+    #[tokio::test]
+    async fn test_compression_settings() {
+        use tower::ServiceExt;
+
+        let app_with = |settings: Settings| async move {
+            let mut state = test_state();
+            state.auth_state = Arc::new(AuthState::new(Arc::new(settings.clone())));
+            state.settings = settings.into();
+            // The default model list makes a response of a few kilobytes
+            state.gemini_client.load_default_models().await;
+            crate::build_app(state).await
+        };
+        let get = |authorized: bool| {
+            let mut builder = hyper::Request::builder().uri("/v1/models").header("accept-encoding", "gzip, br");
+            if authorized {
+                builder = builder.header("authorization", "Bearer 123");
+            }
+            builder.body(axum::body::Body::empty()).unwrap()
+        };
+        let encoding = |response: &axum::response::Response| {
+            response.headers().get("content-encoding").map(|value| value.to_str().unwrap().to_string())
+        };
+
+        let app = app_with(Settings { compression_min_size_bytes: 256, ..Default::default() }).await.unwrap();
+        // An empty error body is below the minimum size
+        let small = app.clone().oneshot(get(false)).await.unwrap();
+        assert_eq!(small.status(), 401);
+        assert_eq!(encoding(&small), None);
+        let large = app.oneshot(get(true)).await.unwrap();
+        assert_eq!(large.status(), 200);
+        assert_eq!(encoding(&large).as_deref(), Some("gzip"));
+        let body = large.into_body().collect().await.unwrap().to_bytes();
+        assert!(body.len() > 2 && body[..2] == [0x1f, 0x8b]);
+
+        let brotli = Settings { compression_algorithms: vec!["br".to_string()], ..Default::default() };
+        let response = app_with(brotli).await.unwrap().oneshot(get(true)).await.unwrap();
+        assert_eq!(encoding(&response).as_deref(), Some("br"));
+
+        let disabled = Settings { compression_enabled: false, ..Default::default() };
+        let response = app_with(disabled).await.unwrap().oneshot(get(true)).await.unwrap();
+        assert_eq!(encoding(&response), None);
+    }
+
+    #[test]
+    fn test_parse_socket_mode() {
+        assert_eq!(parse_socket_mode("0660").unwrap(), 0o660);
+        assert_eq!(parse_socket_mode("0o600").unwrap(), 0o600);
+        assert_eq!(parse_socket_mode("777").unwrap(), 0o777);
+        assert!(parse_socket_mode("rw-rw----").is_err());
+    }
+
+    #[tokio::test]
+    async fn test_health_over_unix_socket() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("rujimi.sock");
+        // A stale file must not prevent binding
+        std::fs::write(&path, "stale").unwrap();
+
+        let listener = bind_unix_socket(&path, 0o660).unwrap();
+        let app = crate::build_app(test_state()).await.unwrap();
+        let (shutdown_tx, shutdown_rx) = watch::channel(false);
+        let server = tokio::spawn(serve_unix(listener, path.clone(), app, shutdown_rx));
+
+        let stream = tokio::net::UnixStream::connect(&path).await.unwrap();
+        let (mut sender, connection) = hyper::client::conn::http1::handshake(TokioIo::new(stream)).await.unwrap();
+        tokio::spawn(connection);
+
+        let request = hyper::Request::builder()
+            .uri("/health")
+            .header("host", "localhost")
+            .body(http_body_util::Empty::<bytes::Bytes>::new())
+            .unwrap();
+        let response = sender.send_request(request).await.unwrap();
+        assert_eq!(response.status(), 200);
+
+        let body = response.into_body().collect().await.unwrap().to_bytes();
+        let json: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(json["status"], "healthy");
+
+        shutdown_tx.send(true).unwrap();
+        server.await.unwrap().unwrap();
+        assert!(!path.exists());
+    }
+}
@@ -0,0 +1,306 @@
+//! Gemini context caching. Long system prompts are uploaded once as a `cachedContents`
+//! resource and referenced by name on later turns. Caches belong to the project of the key
+//! that created them, so entries are keyed by API key as well as model and prompt.
+
+use anyhow::{Context, Result};
+use chrono::{DateTime, Utc};
+use dashmap::DashMap;
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use std::time::{Duration, Instant};
+use tracing::{debug, info};
+
+use crate::utils::auth::client_fingerprint;
+use crate::utils::browser;
+
+/// Entries are extended once less than this much lifetime remains
+pub const CACHE_REFRESH_MARGIN: Duration = Duration::from_secs(300);
+
+/// How long a prompt whose cache could not be created is sent inline before retrying
+const CREATE_FAILURE_BACKOFF: Duration = Duration::from_secs(600);
+
+/// Cache calls sit in front of a chat request, so they get a short timeout of their own
+const CACHE_REQUEST_TIMEOUT: Duration = Duration::from_secs(30);
+
+#[derive(Debug, Clone, Serialize)]
+pub struct ContextCacheEntry {
+    /// `cachedContents/...` resource name
+    pub name: String,
+    pub model: String,
+    /// Masked id of the API key owning the cache
+    pub key_id: String,
+    pub token_count: u32,
+    pub expires_at: DateTime<Utc>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct CachedContentResponse {
+    name: String,
+    expire_time: DateTime<Utc>,
+    #[serde(default)]
+    usage_metadata: Option<CachedContentUsage>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct CachedContentUsage {
+    #[serde(default)]
+    total_token_count: u32,
+}
+
+#[derive(Debug, Default)]
+pub struct ContextCacheManager {
+    entries: DashMap<String, ContextCacheEntry>,
+    failures: DashMap<String, Instant>,
+    /// Held while creating, so concurrent turns of one conversation create a single cache
+    create_lock: tokio::sync::Mutex<()>,
+}
+
+impl ContextCacheManager {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Name of the cached content holding `system_prompt` for `model`, creating it or
+    /// extending its TTL as needed. `None` while a failed creation is backing off.
+    pub async fn cached_content(
+        &self,
+        http: &reqwest::Client,
+        base_url: &str,
+        api_key: &str,
+        model: &str,
+        system_prompt: &str,
+        ttl: Duration,
+    ) -> Result<Option<String>> {
+        let key = cache_key(api_key, model, system_prompt);
+        if let Some(name) = self.fresh_entry(&key) {
+            return Ok(Some(name));
+        }
+
+        let _guard = self.create_lock.lock().await;
+        if let Some(name) = self.fresh_entry(&key) {
+            return Ok(Some(name));
+        }
+        if self.failures.get(&key).is_some_and(|failed_at| failed_at.elapsed() < CREATE_FAILURE_BACKOFF) {
+            return Ok(None);
+        }
+
+        let existing = self.entries.get(&key).map(|entry| entry.name.clone());
+        if let Some(name) = existing {
+            match self.extend(http, base_url, api_key, &name, ttl).await {
+                Ok(response) => {
+                    debug!("Extended context cache {}", name);
+                    if let Some(mut entry) = self.entries.get_mut(&key) {
+                        entry.expires_at = response.expire_time;
+                    }
+                    return Ok(Some(name));
+                }
+                Err(e) => debug!("Could not extend context cache {}, creating a new one: {:#}", name, e),
+            }
+        }
+
+        match self.create(http, base_url, api_key, model, system_prompt, ttl).await {
+            Ok(response) => {
+                info!("Created context cache {} for model {}", response.name, model);
+                self.failures.remove(&key);
+                let entry = ContextCacheEntry {
+                    name: response.name.clone(),
+                    model: model.to_string(),
+                    key_id: client_fingerprint(api_key),
+                    token_count: response.usage_metadata.map_or(0, |usage| usage.total_token_count),
+                    expires_at: response.expire_time,
+                };
+                self.entries.insert(key, entry);
+                Ok(Some(response.name))
+            }
+            Err(e) => {
+                self.entries.remove(&key);
+                self.failures.insert(key, Instant::now());
+                Err(e)
+            }
+        }
+    }
+
+    /// Live entries, soonest expiry first; expired ones are dropped
+    pub fn entries(&self) -> Vec<ContextCacheEntry> {
+        let now = Utc::now();
+        self.entries.retain(|_, entry| entry.expires_at > now);
+        let mut entries: Vec<ContextCacheEntry> = self.entries.iter().map(|entry| entry.value().clone()).collect();
+        entries.sort_by_key(|entry| entry.expires_at);
+        entries
+    }
+
+    fn fresh_entry(&self, key: &str) -> Option<String> {
+        let margin = chrono::Duration::from_std(CACHE_REFRESH_MARGIN).unwrap_or_default();
+        self.entries
+            .get(key)
+            .filter(|entry| entry.expires_at > Utc::now() + margin)
+            .map(|entry| entry.name.clone())
+    }
+
+    async fn create(
+        &self,
+        http: &reqwest::Client,
+        base_url: &str,
+        api_key: &str,
+        model: &str,
+        system_prompt: &str,
+        ttl: Duration,
+    ) -> Result<CachedContentResponse> {
+        let body = json!({
+            "model": format!("models/{}", model),
+            "systemInstruction": {"parts": [{"text": system_prompt}]},
+            "ttl": format!("{}s", ttl.as_secs()),
+        });
+        let request = http.post(format!("{}/cachedContents", base_url)).json(&body);
+        send(request, api_key).await.context("Failed to create Gemini context cache")
+    }
+
+    async fn extend(
+        &self,
+        http: &reqwest::Client,
+        base_url: &str,
+        api_key: &str,
+        name: &str,
+        ttl: Duration,
+    ) -> Result<CachedContentResponse> {
+        let request = http
+            .patch(format!("{}/{}?updateMask=ttl", base_url, name))
+            .json(&json!({"ttl": format!("{}s", ttl.as_secs())}));
+        send(request, api_key).await
+    }
+}
+
+async fn send(request: reqwest::RequestBuilder, api_key: &str) -> Result<CachedContentResponse> {
+    let response = browser::with_upstream_headers(request, api_key).await
+        .header("x-goog-api-key", api_key)
+        .timeout(CACHE_REQUEST_TIMEOUT)
+        .send()
+        .await?;
+    if !response.status().is_success() {
+        let status = response.status();
+        let error_text = response.text().await.unwrap_or_default();
+        return Err(anyhow::anyhow!("Gemini API error: {} - {}", status, error_text));
+    }
+    Ok(response.json().await?)
+}
+
+fn cache_key(api_key: &str, model: &str, system_prompt: &str) -> String {
+    let mut hasher = blake3::Hasher::new();
+    for field in [api_key, model, system_prompt] {
+        hasher.update(field.as_bytes());
+        hasher.update(&[0]);
+    }
+    hasher.finalize().to_hex().to_string()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::{
+        extract::{Path, State},
+        routing::{patch, post},
+        Json, Router,
+    };
+    use serde_json::Value;
+    use std::sync::atomic::{AtomicI64, AtomicUsize, Ordering};
+    use std::sync::Arc;
+
+    #[derive(Clone, Default)]
+    struct CacheEndpoint {
+        creates: Arc<AtomicUsize>,
+        extends: Arc<AtomicUsize>,
+        lifetime_secs: Arc<AtomicI64>,
+    }
+
+    impl CacheEndpoint {
+        fn response(&self, name: &str) -> Json<Value> {
+            let expire_time = Utc::now() + chrono::Duration::seconds(self.lifetime_secs.load(Ordering::SeqCst));
+            Json(json!({
+                "name": name,
+                "model": "models/gemini-2.0-flash",
+                "expireTime": expire_time.to_rfc3339(),
+                "usageMetadata": {"totalTokenCount": 5000}
+            }))
+        }
+    }
+
+    async fn mock_create(State(endpoint): State<CacheEndpoint>, Json(body): Json<Value>) -> Json<Value> {
+        assert_eq!(body["model"], "models/gemini-2.0-flash");
+        assert_eq!(body["ttl"], "3600s");
+        let call = endpoint.creates.fetch_add(1, Ordering::SeqCst) + 1;
+        endpoint.response(&format!("cachedContents/cache-{}", call))
+    }
+
+    async fn mock_extend(State(endpoint): State<CacheEndpoint>, Path(id): Path<String>) -> Json<Value> {
+        endpoint.extends.fetch_add(1, Ordering::SeqCst);
+        endpoint.lifetime_secs.store(3600, Ordering::SeqCst);
+        endpoint.response(&format!("cachedContents/{}", id))
+    }
+
+    async fn cache_endpoint() -> (CacheEndpoint, String) {
+        let endpoint = CacheEndpoint::default();
+        endpoint.lifetime_secs.store(3600, Ordering::SeqCst);
+        let app = Router::new()
+            .route("/cachedContents", post(mock_create))
+            .route("/cachedContents/:id", patch(mock_extend))
+            .with_state(endpoint.clone());
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let base_url = format!("http://{}", listener.local_addr().unwrap());
+        tokio::spawn(async move { axum::serve(listener, app).await.unwrap() });
+        (endpoint, base_url)
+    }
+
+    #[tokio::test]
+    async fn test_cache_created_once_and_reused() {
+        let (endpoint, base_url) = cache_endpoint().await;
+        let manager = ContextCacheManager::new();
+        let http = reqwest::Client::new();
+        let ttl = Duration::from_secs(3600);
+
+        let lookups = (0..4).map(|_| manager.cached_content(&http, &base_url, "key-1", "gemini-2.0-flash", "You are an agent.", ttl));
+        let names = futures_util::future::join_all(lookups).await;
+        assert!(names.iter().all(|name| name.as_ref().unwrap().as_deref() == Some("cachedContents/cache-1")));
+        assert_eq!(endpoint.creates.load(Ordering::SeqCst), 1);
+
+        // Another key belongs to another project and gets its own cache
+        let other = manager.cached_content(&http, &base_url, "key-2", "gemini-2.0-flash", "You are an agent.", ttl).await.unwrap();
+        assert_eq!(other.as_deref(), Some("cachedContents/cache-2"));
+
+        let entries = manager.entries();
+        assert_eq!(entries.len(), 2);
+        assert_eq!(entries[0].token_count, 5000);
+        assert_eq!(entries[0].key_id, client_fingerprint("key-1"));
+    }
+
+    #[tokio::test]
+    async fn test_cache_close_to_expiry_is_extended() {
+        let (endpoint, base_url) = cache_endpoint().await;
+        // Inside the refresh margin, so the next lookup extends the TTL
+        endpoint.lifetime_secs.store(CACHE_REFRESH_MARGIN.as_secs() as i64 - 10, Ordering::SeqCst);
+        let manager = ContextCacheManager::new();
+        let http = reqwest::Client::new();
+        let ttl = Duration::from_secs(3600);
+
+        let lookup = || manager.cached_content(&http, &base_url, "key-1", "gemini-2.0-flash", "prompt", ttl);
+        assert_eq!(lookup().await.unwrap().as_deref(), Some("cachedContents/cache-1"));
+        assert_eq!(lookup().await.unwrap().as_deref(), Some("cachedContents/cache-1"));
+        assert_eq!(lookup().await.unwrap().as_deref(), Some("cachedContents/cache-1"));
+        assert_eq!(endpoint.creates.load(Ordering::SeqCst), 1);
+        assert_eq!(endpoint.extends.load(Ordering::SeqCst), 1);
+    }
+
+    #[tokio::test]
+    async fn test_failed_creation_backs_off() {
+        let manager = ContextCacheManager::new();
+        let http = reqwest::Client::new();
+        let ttl = Duration::from_secs(3600);
+
+        // Nothing listens on the discard port
+        let lookup = || manager.cached_content(&http, "http://127.0.0.1:9", "key-1", "gemini-2.0-flash", "prompt", ttl);
+        assert!(lookup().await.is_err());
+        assert!(lookup().await.unwrap().is_none());
+        assert!(manager.entries().is_empty());
+    }
+}
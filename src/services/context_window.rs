@@ -0,0 +1,82 @@
+//! Fitting long conversations into a model's input window (`context_trim_strategy`). Messages
+//! are measured with the shared token estimator; trimming leaves out the oldest messages that
+//! are not system messages, never the last one, and keeps the rest in order.
+
+use crate::models::schemas::ChatMessage;
+use crate::utils::response::extract_text_from_value;
+use crate::utils::tokens::estimate_tokens;
+
+fn message_tokens(message: &ChatMessage) -> u32 {
+    message.content.as_ref().map_or(0, |content| estimate_tokens(&extract_text_from_value(content)))
+}
+
+/// Estimated prompt tokens of `messages`
+pub fn prompt_tokens(messages: &[ChatMessage]) -> u32 {
+    messages.iter().map(message_tokens).sum()
+}
+
+/// How many of the oldest non-system messages to leave out for `messages` to fit in `budget`
+/// tokens. `None` when the system messages and the last message alone do not fit.
+pub fn messages_to_drop(messages: &[ChatMessage], budget: u32) -> Option<usize> {
+    let mut total = prompt_tokens(messages);
+    let mut droppable = messages[..messages.len().saturating_sub(1)].iter().filter(|message| message.role != "system");
+    let mut dropped = 0;
+    while total > budget {
+        total -= message_tokens(droppable.next()?);
+        dropped += 1;
+    }
+    Some(dropped)
+}
+
+/// `messages` without the `count` oldest non-system ones
+pub fn without_oldest(messages: &[ChatMessage], count: usize) -> impl Iterator<Item = &ChatMessage> {
+    let mut skipped = 0;
+    messages.iter().filter(move |message| {
+        if skipped < count && message.role != "system" {
+            skipped += 1;
+            return false;
+        }
+        true
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn messages() -> Vec<ChatMessage> {
+        serde_json::from_value(serde_json::json!([
+            {"role": "system", "content": "Answer briefly and politely."},
+            {"role": "user", "content": "a first question that is quite long"},
+            {"role": "assistant", "content": "a first answer that is quite long too"},
+            {"role": "system", "content": "Mind the new rule."},
+            {"role": "user", "content": "a second question"},
+        ]))
+        .unwrap()
+    }
+
+    fn kept(messages: &[ChatMessage], count: usize) -> Vec<&str> {
+        without_oldest(messages, count).map(|message| message.content.as_ref().unwrap().as_str().unwrap()).collect()
+    }
+
+    #[test]
+    fn test_oldest_turns_dropped_first_and_system_messages_kept() {
+        let messages = messages();
+        let total = prompt_tokens(&messages);
+        assert_eq!(messages_to_drop(&messages, total), Some(0));
+
+        let count = messages_to_drop(&messages, total - 1).unwrap();
+        assert_eq!(count, 1);
+        assert_eq!(
+            kept(&messages, count),
+            ["Answer briefly and politely.", "a first answer that is quite long too", "Mind the new rule.", "a second question"]
+        );
+
+        let tight = prompt_tokens(&[messages[0].clone(), messages[3].clone(), messages[4].clone()]);
+        let count = messages_to_drop(&messages, tight).unwrap();
+        assert_eq!(kept(&messages, count), ["Answer briefly and politely.", "Mind the new rule.", "a second question"]);
+
+        // The last message is never dropped
+        assert_eq!(messages_to_drop(&messages, tight - 1), None);
+    }
+}
@@ -1,16 +1,17 @@
-use reqwest::Client;
 use serde::{Deserialize, Serialize};
 use serde_json::json;
-use std::time::Duration;
 use tracing::{debug, error};
 
-use crate::config::Settings;
+use crate::config::{ConfigManager, Settings};
 use crate::models::schemas::{EmbeddingRequest, EmbeddingResponse, EmbeddingData, EmbeddingUsage, EmbeddingInput};
+use crate::utils::browser;
+use crate::utils::http_client::UpstreamClient;
 use crate::utils::logging::log;
+use crate::utils::tokens::estimate_tokens;
 
 #[derive(Debug, Clone)]
 pub struct EmbeddingClient {
-    client: Client,
+    client: UpstreamClient,
     settings: std::sync::Arc<Settings>,
 }
 
@@ -56,9 +57,7 @@ struct GeminiBatchEmbeddingResponse {
 
 impl EmbeddingClient {
     pub fn new(settings: std::sync::Arc<Settings>) -> Self {
-        let client = Client::builder()
-            .timeout(Duration::from_secs(60))
-            .build()
+        let client = UpstreamClient::new(settings.clone(), false)
             .expect("Failed to create HTTP client");
 
         Self { client, settings }
@@ -96,7 +95,11 @@ impl EmbeddingClient {
             }
         };
 
-        let total_tokens = embeddings.len() as i32; // Simplified token count
+        let total_tokens: u32 = match &request.input {
+            EmbeddingInput::String(text) => estimate_tokens(text),
+            EmbeddingInput::ArrayOfStrings(texts) => texts.iter().map(|text| estimate_tokens(text)).sum(),
+            EmbeddingInput::ArrayOfTokens(_) | EmbeddingInput::ArrayOfTokenArrays(_) => 0,
+        };
 
         let response = EmbeddingResponse {
             object: "list".to_string(),
@@ -109,8 +112,8 @@ impl EmbeddingClient {
             }).collect(),
             model: request.model.clone(),
             usage: EmbeddingUsage {
-                prompt_tokens: total_tokens as u32,
-                total_tokens: total_tokens as u32,
+                prompt_tokens: total_tokens,
+                total_tokens,
             },
         };
 
@@ -135,8 +138,8 @@ impl EmbeddingClient {
         api_key: &str,
     ) -> Result<Vec<f64>, Box<dyn std::error::Error>> {
         let url = format!(
-            "https://generativelanguage.googleapis.com/v1beta/models/{}:embedContent?key={}",
-            model, api_key
+            "{}/models/{}:embedContent?key={}",
+            ConfigManager::get_gemini_base_url_for(model).await, model, api_key
         );
 
         let request_body = GeminiEmbeddingRequest {
@@ -152,10 +155,12 @@ impl EmbeddingClient {
 
         debug!("发送单个嵌入请求到: {}", url);
 
-        let response = self
-            .client
-            .post(&url)
+        let timeouts = ConfigManager::get_upstream_timeouts().await;
+        let request = self.client.get(timeouts.connect).post(&url);
+        let response = browser::with_upstream_headers(request, api_key)
+            .await
             .json(&request_body)
+            .timeout(timeouts.request)
             .send()
             .await?;
 
@@ -176,8 +181,8 @@ impl EmbeddingClient {
         api_key: &str,
     ) -> Result<Vec<Vec<f64>>, Box<dyn std::error::Error>> {
         let url = format!(
-            "https://generativelanguage.googleapis.com/v1beta/models/{}:batchEmbedContents?key={}",
-            model, api_key
+            "{}/models/{}:batchEmbedContents?key={}",
+            ConfigManager::get_gemini_base_url_for(model).await, model, api_key
         );
 
         let requests: Vec<GeminiEmbeddingRequest> = texts
@@ -200,10 +205,12 @@ impl EmbeddingClient {
 
         debug!("发送批量嵌入请求到: {}", url);
 
-        let response = self
-            .client
-            .post(&url)
+        let timeouts = ConfigManager::get_upstream_timeouts().await;
+        let request = self.client.get(timeouts.connect).post(&url);
+        let response = browser::with_upstream_headers(request, api_key)
+            .await
             .json(&batch_request)
+            .timeout(timeouts.request)
             .send()
             .await?;
 
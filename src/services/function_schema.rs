@@ -0,0 +1,433 @@
+//! Function parameters in the JSON Schema subset Gemini accepts. Tool schemas written for
+//! OpenAI often carry keywords Gemini rejects (`$schema`, `additionalProperties`, `oneOf`,
+//! `format: uri`, ...): `sanitize_parameters` rewrites those that have a Gemini equivalent,
+//! inlines `$ref`s and leaves the rest out.
+
+use serde_json::{json, Map, Value};
+
+/// Fields of Gemini's `Schema` object
+const SUPPORTED_KEYWORDS: &[&str] = &[
+    "type", "format", "title", "description", "nullable", "enum", "maxItems", "minItems", "properties", "required",
+    "minProperties", "maxProperties", "minLength", "maxLength", "pattern", "example", "anyOf", "propertyOrdering",
+    "default", "items", "minimum", "maximum",
+];
+
+const TYPES: &[&str] = &["string", "number", "integer", "boolean", "array", "object"];
+
+/// Nested `$ref`s followed before giving up, which also ends recursive definitions
+const MAX_REF_DEPTH: usize = 8;
+
+fn supported_format(schema_type: Option<&str>, format: &str) -> bool {
+    matches!(
+        (schema_type, format),
+        (Some("string"), "enum" | "date-time") | (Some("number"), "float" | "double") | (Some("integer"), "int32" | "int64")
+    )
+}
+
+fn empty_object() -> Value {
+    json!({"type": "object", "properties": {}})
+}
+
+fn is_null_type(schema: &Value) -> bool {
+    schema.get("type").and_then(Value::as_str) == Some("null")
+}
+
+fn child(path: &str, name: &str) -> String {
+    if path.is_empty() {
+        name.to_string()
+    } else {
+        format!("{}.{}", path, name)
+    }
+}
+
+/// `parameters` as Gemini accepts them, and the paths of the keywords left out. Missing or
+/// empty parameters become an object without properties.
+pub fn sanitize_parameters(parameters: Option<&Value>) -> (Value, Vec<String>) {
+    let root = match parameters {
+        Some(root @ Value::Object(fields)) if !fields.is_empty() => root,
+        _ => return (empty_object(), Vec::new()),
+    };
+    let mut sanitizer = Sanitizer { root, dropped: Vec::new() };
+    let mut schema = sanitizer.schema(root, "", 0);
+    if schema.get("type").is_none() && schema.get("anyOf").is_none() {
+        schema["type"] = json!("object");
+    }
+    if schema["type"] == "object" && schema.get("properties").is_none() {
+        schema["properties"] = json!({});
+    }
+    (schema, sanitizer.dropped)
+}
+
+struct Sanitizer<'a> {
+    root: &'a Value,
+    dropped: Vec<String>,
+}
+
+impl Sanitizer<'_> {
+    /// The definition a local `$ref` such as `#/$defs/Item` points to
+    fn definition(&self, reference: &str) -> Option<&Map<String, Value>> {
+        let pointer = reference.strip_prefix('#')?;
+        self.root.pointer(pointer)?.as_object()
+    }
+
+    /// `fields` with its `$ref` replaced by the definition, the fields beside it winning
+    fn resolve(&mut self, fields: &Map<String, Value>, path: &str, depth: usize) -> Option<Map<String, Value>> {
+        let reference = match fields.get("$ref").and_then(Value::as_str) {
+            Some(reference) => reference,
+            None => return Some(fields.clone()),
+        };
+        let definition = match self.definition(reference) {
+            Some(definition) if depth < MAX_REF_DEPTH => definition.clone(),
+            _ => {
+                self.dropped.push(child(path, "$ref"));
+                return None;
+            }
+        };
+        let mut resolved = self.resolve(&definition, path, depth + 1)?;
+        resolved.extend(fields.iter().filter(|(key, _)| *key != "$ref").map(|(key, value)| (key.clone(), value.clone())));
+        Some(resolved)
+    }
+
+    /// `fields` with each `allOf` member merged in
+    fn merge_all_of(&mut self, mut fields: Map<String, Value>, path: &str, depth: usize) -> Map<String, Value> {
+        let members = match fields.remove("allOf") {
+            Some(Value::Array(members)) => members,
+            Some(_) => {
+                self.dropped.push(child(path, "allOf"));
+                return fields;
+            }
+            None => return fields,
+        };
+        for member in members.iter().filter_map(Value::as_object) {
+            let member = match self.resolve(member, path, depth + 1) {
+                Some(member) => member,
+                None => continue,
+            };
+            for (key, value) in member {
+                match (key.as_str(), fields.get_mut(&key)) {
+                    ("properties", Some(Value::Object(properties))) => {
+                        if let Value::Object(more) = value {
+                            properties.extend(more);
+                        }
+                    }
+                    ("required", Some(Value::Array(required))) => {
+                        if let Value::Array(more) = value {
+                            required.extend(more);
+                        }
+                    }
+                    (_, Some(_)) => {}
+                    (_, None) => {
+                        fields.insert(key, value);
+                    }
+                }
+            }
+        }
+        fields
+    }
+
+    fn schema(&mut self, schema: &Value, path: &str, depth: usize) -> Value {
+        // Everything under a `$ref` counts as one level deeper
+        let depth = if schema.get("$ref").is_some() { depth + 1 } else { depth };
+        let fields = match schema.as_object().and_then(|fields| self.resolve(fields, path, depth)) {
+            Some(fields) => self.merge_all_of(fields, path, depth),
+            None => {
+                if !schema.is_object() {
+                    self.dropped.push(if path.is_empty() { "(schema)".to_string() } else { path.to_string() });
+                }
+                return json!({"type": "object"});
+            }
+        };
+        let mut out = Map::new();
+
+        match fields.get("type") {
+            Some(Value::String(schema_type)) if schema_type == "null" => {
+                out.insert("nullable".to_string(), json!(true));
+            }
+            Some(Value::String(schema_type)) if TYPES.contains(&schema_type.to_lowercase().as_str()) => {
+                out.insert("type".to_string(), json!(schema_type.to_lowercase()));
+            }
+            Some(Value::Array(types)) => {
+                let types: Vec<&str> = types.iter().filter_map(Value::as_str).collect();
+                if types.contains(&"null") {
+                    out.insert("nullable".to_string(), json!(true));
+                }
+                let types: Vec<&str> = types.into_iter().filter(|schema_type| TYPES.contains(schema_type)).collect();
+                match types.as_slice() {
+                    [] => {}
+                    [schema_type] => {
+                        out.insert("type".to_string(), json!(schema_type));
+                    }
+                    types => {
+                        let variants = types.iter().map(|schema_type| json!({"type": schema_type})).collect();
+                        out.insert("anyOf".to_string(), Value::Array(variants));
+                    }
+                }
+            }
+            Some(_) => self.dropped.push(child(path, "type")),
+            None => {}
+        }
+
+        // `oneOf` becomes `anyOf`, and a `null` variant makes the schema nullable
+        for keyword in ["anyOf", "oneOf"] {
+            let variants = match fields.get(keyword) {
+                Some(Value::Array(variants)) => variants,
+                Some(_) => {
+                    self.dropped.push(child(path, keyword));
+                    continue;
+                }
+                None => continue,
+            };
+            if variants.iter().any(is_null_type) {
+                out.insert("nullable".to_string(), json!(true));
+            }
+            let mut variants: Vec<Value> = variants
+                .iter()
+                .filter(|variant| !is_null_type(variant))
+                .enumerate()
+                .map(|(index, variant)| self.schema(variant, &child(path, &format!("{}[{}]", keyword, index)), depth))
+                .collect();
+            if variants.len() == 1 {
+                if let Some(Value::Object(variant)) = variants.pop() {
+                    for (key, value) in variant {
+                        out.entry(key).or_insert(value);
+                    }
+                }
+            } else if !variants.is_empty() {
+                out.insert("anyOf".to_string(), Value::Array(variants));
+            }
+        }
+
+        let schema_type = out.get("type").and_then(Value::as_str).map(str::to_string);
+        for (key, value) in &fields {
+            match key.as_str() {
+                "type" | "anyOf" | "oneOf" => {}
+                // Inlined where they are referenced
+                "$defs" | "definitions" => {}
+                "nullable" => {
+                    if value.as_bool() == Some(true) {
+                        out.insert("nullable".to_string(), json!(true));
+                    }
+                }
+                "const" => match value {
+                    Value::String(_) => {
+                        out.insert("enum".to_string(), json!([value]));
+                    }
+                    _ => self.dropped.push(child(path, key)),
+                },
+                "enum" => match value.as_array() {
+                    Some(values) if values.iter().all(Value::is_string) => {
+                        out.insert("enum".to_string(), value.clone());
+                    }
+                    _ => self.dropped.push(child(path, key)),
+                },
+                "format" => match value.as_str() {
+                    Some(format) if supported_format(schema_type.as_deref(), format) => {
+                        out.insert("format".to_string(), value.clone());
+                    }
+                    _ => self.dropped.push(child(path, key)),
+                },
+                "examples" => match value.as_array().and_then(|examples| examples.first()) {
+                    Some(example) if !fields.contains_key("example") => {
+                        out.insert("example".to_string(), example.clone());
+                    }
+                    _ => {}
+                },
+                "properties" => match value.as_object() {
+                    Some(properties) => {
+                        let properties: Map<String, Value> = properties
+                            .iter()
+                            .map(|(name, property)| (name.clone(), self.schema(property, &child(path, &format!("properties.{}", name)), depth)))
+                            .collect();
+                        out.insert("properties".to_string(), Value::Object(properties));
+                    }
+                    None => self.dropped.push(child(path, key)),
+                },
+                "items" => match value {
+                    Value::Object(_) => {
+                        let items = self.schema(value, &child(path, "items"), depth);
+                        out.insert("items".to_string(), items);
+                    }
+                    // Tuple validation: every item is taken to be like the first
+                    Value::Array(items) if !items.is_empty() => {
+                        let items = self.schema(&items[0], &child(path, "items"), depth);
+                        out.insert("items".to_string(), items);
+                    }
+                    _ => self.dropped.push(child(path, key)),
+                },
+                "required" => {}
+                key if SUPPORTED_KEYWORDS.contains(&key) => {
+                    out.insert(key.to_string(), value.clone());
+                }
+                _ => self.dropped.push(child(path, key)),
+            }
+        }
+
+        // Only properties that exist can be required
+        if let Some(Value::Array(required)) = fields.get("required") {
+            let properties = out.get("properties").and_then(Value::as_object);
+            let required: Vec<Value> = required
+                .iter()
+                .filter(|name| name.as_str().is_some_and(|name| properties.is_some_and(|properties| properties.contains_key(name))))
+                .cloned()
+                .collect();
+            if !required.is_empty() {
+                out.insert("required".to_string(), Value::Array(required));
+            }
+        }
+        Value::Object(out)
+    }
+}
+
+/// Check `schema` against Gemini's documented `Schema` subset; `Err` names the first problem
+pub fn validate_parameters(schema: &Value) -> Result<(), String> {
+    if schema.get("type").and_then(Value::as_str) != Some("object") {
+        return Err("parameters must be an object schema".to_string());
+    }
+    validate_schema(schema, "parameters")
+}
+
+fn validate_schema(schema: &Value, path: &str) -> Result<(), String> {
+    let fields = schema.as_object().ok_or_else(|| format!("{} is not a schema object", path))?;
+    if let Some(key) = fields.keys().find(|key| !SUPPORTED_KEYWORDS.contains(&key.as_str())) {
+        return Err(format!("{}.{} is not supported", path, key));
+    }
+    let schema_type = match fields.get("type") {
+        Some(Value::String(schema_type)) if TYPES.contains(&schema_type.as_str()) => Some(schema_type.as_str()),
+        Some(schema_type) => return Err(format!("{}.type {} is not supported", path, schema_type)),
+        None => None,
+    };
+    if let Some(format) = fields.get("format") {
+        if !format.as_str().is_some_and(|format| supported_format(schema_type, format)) {
+            return Err(format!("{}.format {} is not supported for type {:?}", path, format, schema_type));
+        }
+    }
+    if let Some(values) = fields.get("enum") {
+        if !values.as_array().is_some_and(|values| values.iter().all(Value::is_string)) {
+            return Err(format!("{}.enum must list strings", path));
+        }
+    }
+    if schema_type == Some("array") && !fields.contains_key("items") {
+        return Err(format!("{} is an array without items", path));
+    }
+    if let Some(items) = fields.get("items") {
+        validate_schema(items, &format!("{}.items", path))?;
+    }
+    if let Some(variants) = fields.get("anyOf") {
+        let variants = variants.as_array().ok_or_else(|| format!("{}.anyOf is not a list", path))?;
+        for (index, variant) in variants.iter().enumerate() {
+            validate_schema(variant, &format!("{}.anyOf[{}]", path, index))?;
+        }
+    }
+    let properties = match fields.get("properties") {
+        Some(Value::Object(properties)) => Some(properties),
+        Some(_) => return Err(format!("{}.properties is not an object", path)),
+        None => None,
+    };
+    for (name, property) in properties.into_iter().flatten() {
+        validate_schema(property, &format!("{}.properties.{}", path, name))?;
+    }
+    if let Some(required) = fields.get("required") {
+        let required = required.as_array().ok_or_else(|| format!("{}.required is not a list", path))?;
+        for name in required {
+            let known = name.as_str().is_some_and(|name| properties.is_some_and(|properties| properties.contains_key(name)));
+            if !known {
+                return Err(format!("{}.required names {} which is not a property", path, name));
+            }
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Tool schemas as LangChain and the OpenAI cookbook generate them
+    fn fixtures() -> Vec<Value> {
+        vec![
+            // OpenAI cookbook: get_current_weather, strict mode
+            json!({
+                "type": "object",
+                "properties": {
+                    "location": {"type": "string", "description": "The city and state, e.g. San Francisco, CA"},
+                    "format": {"type": "string", "enum": ["celsius", "fahrenheit"]},
+                    "num_days": {"type": "integer", "minimum": 1}
+                },
+                "required": ["location", "format", "num_days"],
+                "additionalProperties": false
+            }),
+            // LangChain / Pydantic v2 with a nested model and optional fields
+            json!({
+                "$schema": "http://json-schema.org/draft-07/schema#",
+                "title": "SearchInput",
+                "type": "object",
+                "$defs": {
+                    "Filter": {
+                        "title": "Filter",
+                        "type": "object",
+                        "properties": {
+                            "field": {"title": "Field", "type": "string"},
+                            "value": {"anyOf": [{"type": "string"}, {"type": "integer"}], "title": "Value"}
+                        },
+                        "required": ["field", "value"]
+                    }
+                },
+                "properties": {
+                    "query": {"title": "Query", "type": "string", "examples": ["rust async"]},
+                    "url": {"type": "string", "format": "uri"},
+                    "limit": {"anyOf": [{"type": "integer"}, {"type": "null"}], "default": null, "title": "Limit"},
+                    "filters": {"type": "array", "items": {"$ref": "#/$defs/Filter"}},
+                    "since": {"type": ["string", "null"], "format": "date-time"}
+                },
+                "required": ["query", "missing"]
+            }),
+            // OpenAI cookbook: a union of shapes and a constant
+            json!({
+                "type": "object",
+                "properties": {
+                    "shape": {"oneOf": [
+                        {"type": "object", "properties": {"kind": {"const": "circle"}, "radius": {"type": "number"}}},
+                        {"type": "object", "properties": {"kind": {"const": "square"}, "side": {"type": "number", "exclusiveMinimum": 0}}}
+                    ]},
+                    "tags": {"type": "array", "items": {"type": "string"}, "uniqueItems": true}
+                },
+                "allOf": [{"properties": {"id": {"type": "integer", "format": "int64"}}, "required": ["id"]}]
+            }),
+        ]
+    }
+
+    #[test]
+    fn test_fixture_schemas_sanitized_into_the_gemini_subset() {
+        for fixture in fixtures() {
+            assert!(validate_parameters(&fixture).is_err(), "{}", fixture);
+            let (schema, _) = sanitize_parameters(Some(&fixture));
+            assert_eq!(validate_parameters(&schema), Ok(()), "{}", schema);
+        }
+
+        let fixtures = fixtures();
+        let (schema, dropped) = sanitize_parameters(Some(&fixtures[1]));
+        assert_eq!(dropped, ["$schema", "properties.url.format"]);
+        assert_eq!(schema["properties"]["limit"], json!({"type": "integer", "nullable": true, "default": null, "title": "Limit"}));
+        assert_eq!(schema["properties"]["since"], json!({"type": "string", "nullable": true, "format": "date-time"}));
+        assert_eq!(schema["properties"]["filters"]["items"]["required"], json!(["field", "value"]));
+        assert_eq!(schema["properties"]["query"]["example"], "rust async");
+        assert_eq!(schema["required"], json!(["query"]));
+
+        let (schema, dropped) = sanitize_parameters(Some(&fixtures[2]));
+        assert_eq!(dropped, ["properties.shape.oneOf[1].properties.side.exclusiveMinimum", "properties.tags.uniqueItems"]);
+        assert_eq!(schema["properties"]["shape"]["anyOf"][0]["properties"]["kind"], json!({"enum": ["circle"]}));
+        assert_eq!(schema["required"], json!(["id"]));
+    }
+
+    #[test]
+    fn test_empty_parameters_become_an_object_without_properties() {
+        for parameters in [None, Some(json!({})), Some(json!(null))] {
+            assert_eq!(sanitize_parameters(parameters.as_ref()), (json!({"type": "object", "properties": {}}), Vec::new()));
+        }
+        // Recursive definitions are cut off rather than followed forever
+        let recursive = json!({"type": "object", "properties": {"node": {"$ref": "#"}}});
+        let (schema, dropped) = sanitize_parameters(Some(&recursive));
+        assert_eq!(validate_parameters(&schema), Ok(()));
+        assert!(dropped.iter().all(|path| path.ends_with("$ref")) && !dropped.is_empty());
+    }
+}
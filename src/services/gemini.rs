@@ -1,120 +1,436 @@
-use anyhow::{Context, Result};
+use anyhow::Result;
 use async_trait::async_trait;
-use futures_util::{Stream, StreamExt};
-use reqwest::Client;
+use futures_util::{future, stream, Stream, StreamExt};
 use serde_json::{json, Value};
 use std::pin::Pin;
 use std::sync::Arc;
+use std::time::Instant;
 use tokio::sync::RwLock;
 use tracing::{debug, info, warn};
 
-use crate::config::{Settings, get_safety_settings, get_safety_settings_g2};
+use crate::config::{
+    ConfigManager, ContextTrimStrategy, Settings, ModelOverride, get_safety_settings, get_safety_settings_g2, versioned_base_url,
+    DEFAULT_GEMINI_API_VERSION,
+};
 use crate::models::schemas::{
     ChatCompletionRequest, ChatCompletionResponse, ChatChoice, ChatMessage, Usage,
-    ChatCompletionChunk, ChatChoiceDelta, ChatMessageDelta,
+    ChatCompletionChunk,
     GeminiRequest, GeminiResponse, GeminiContent, GeminiPart, GeminiGenerationConfig,
     GeminiSafetySetting, GeminiTool, GeminiFunctionDeclaration, ToolCall, FunctionCall,
-    Model, ModelResponse, EmbeddingRequest, EmbeddingResponse,
+    Model, GeminiModelList, EmbeddingRequest, EmbeddingResponse,
+    ImageGenerationRequest, ImageGenerationResponse, ImageData, ModerationResult, SafetyOverride,
+};
+use crate::models::variant::ModelVariant;
+use crate::services::context_cache::ContextCacheManager;
+use crate::services::context_window::{messages_to_drop, prompt_tokens, without_oldest};
+use crate::services::function_schema::{sanitize_parameters, validate_parameters};
+use crate::services::mock_upstream::MockUpstream;
+use crate::services::openai_compat::MAPPED_PARAMS;
+use crate::services::gemini_stream::{
+    inline_image_part, push_inline_image, push_text_part, render_code_execution_part, GeminiStreamConverter, StreamEventParser,
 };
-use crate::utils::response::generate_random_string;
+use crate::utils::api_key::ApiKeyManager;
+use crate::utils::browser;
+use crate::utils::endpoints::{is_endpoint_failure, EndpointHealth, EndpointStatus};
+use crate::utils::error_handling::{GeminiError, UpstreamTimeoutError};
+use crate::utils::http_client::{with_idle_timeout, UpstreamClient, UpstreamTimeouts};
+use crate::utils::logging::log;
+use crate::utils::response::{extract_text_from_value, generate_random_string};
+use crate::utils::tokens::{estimate_tokens, TokenCountCache};
+use crate::utils::stats::end_user_id;
+use super::moderation::{moderation_from_gemini, moderation_request, ModerationCache};
+
+/// Readiness probes must answer quickly, whatever the configured request timeout
+const UPSTREAM_PROBE_TIMEOUT: std::time::Duration = std::time::Duration::from_secs(10);
+
+/// Used by `/v1/images/generations` when the request names no model
+pub const DEFAULT_IMAGE_MODEL: &str = "gemini-2.0-flash-exp";
+
+/// Built-in model list, used until one is loaded from upstream
+pub fn default_models() -> Vec<Model> {
+    let created = chrono::Utc::now().timestamp() as u64;
+    [
+        "gemini-1.5-pro",
+        "gemini-1.5-pro-exp-0827",
+        "gemini-1.5-flash",
+        "gemini-1.5-flash-8b",
+        "gemini-2.0-flash-exp",
+        "text-embedding-004",
+    ]
+    .iter()
+    .map(|id| Model::named(id, created))
+    .collect()
+}
 
-const GEMINI_BASE_URL: &str = "https://generativelanguage.googleapis.com/v1beta";
-const GEMINI_SEARCH_TOOLS: &str = r#"[{"googleSearchRetrieval": {}}]"#;
+/// Wraps the `random_string` marker so models read it as markup rather than prompt text
+const RANDOM_MARKER_OPEN: &str = "<!-- ";
+const RANDOM_MARKER_CLOSE: &str = " -->";
+
+/// Function name that requests Gemini's built-in `codeExecution` tool instead of a declaration
+const CODE_EXECUTION_TOOL: &str = "code_execution";
 
 #[async_trait]
 pub trait GeminiClientTrait {
-    async fn chat_completion(&self, request: ChatCompletionRequest, api_key: &str) -> Result<ChatCompletionResponse>;
-    async fn chat_completion_stream(&self, request: ChatCompletionRequest, api_key: &str) -> Result<Pin<Box<dyn Stream<Item = Result<ChatCompletionChunk>> + Send>>>;
-    async fn list_models(&self, api_key: &str) -> Result<Vec<Model>>;
-    async fn embedding(&self, request: EmbeddingRequest, api_key: &str) -> Result<EmbeddingResponse>;
+    async fn chat_completion(&self, request: ChatCompletionRequest, api_key: &str) -> Result<ChatCompletionResponse, GeminiError>;
+    async fn chat_completion_stream(&self, request: ChatCompletionRequest, api_key: &str) -> Result<ChatCompletionStream, GeminiError>;
+    async fn list_models(&self, api_key: &str) -> Result<Vec<Model>, GeminiError>;
+    async fn embedding(&self, request: EmbeddingRequest, api_key: &str) -> Result<EmbeddingResponse, GeminiError>;
 }
 
+pub type ChatCompletionStream = Pin<Box<dyn Stream<Item = Result<ChatCompletionChunk, GeminiError>> + Send>>;
+
 #[derive(Debug, Clone)]
 pub struct GeminiClient {
     settings: Arc<Settings>,
-    client: Client,
-    available_models: Arc<RwLock<Vec<String>>>,
+    client: UpstreamClient,
+    available_models: Arc<RwLock<Vec<Model>>>,
+    models_refreshed_at: Arc<RwLock<Option<chrono::DateTime<chrono::Utc>>>>,
+    context_cache: Arc<ContextCacheManager>,
+    token_counts: TokenCountCache,
+    moderations: ModerationCache,
+    /// Set in mock mode, which answers every `GeminiClientTrait` call without going upstream
+    mock: Option<Arc<MockUpstream>>,
+    endpoints: EndpointHealth,
 }
 
 impl GeminiClient {
     pub fn new(settings: Arc<Settings>) -> Self {
-        let client = Client::builder()
-            .timeout(std::time::Duration::from_secs(600))
-            .http2_adaptive_window(true)
-            .build()
+        let client = UpstreamClient::new(settings.clone(), true)
             .expect("Failed to create HTTP client");
 
+        let mock = settings.mock_upstream.then(|| Arc::new(MockUpstream::new(&settings)));
+        let endpoints = EndpointHealth::new(&settings);
+
         Self {
             settings,
             client,
             available_models: Arc::new(RwLock::new(Vec::new())),
+            models_refreshed_at: Arc::new(RwLock::new(None)),
+            context_cache: Arc::new(ContextCacheManager::new()),
+            token_counts: TokenCountCache::new(),
+            moderations: ModerationCache::new(),
+            mock,
+            endpoints,
+        }
+    }
+
+    /// Context caches created for long system prompts
+    pub fn context_cache(&self) -> &ContextCacheManager {
+        &self.context_cache
+    }
+
+    /// Load the model list at startup, falling back to the built-in defaults
+    pub async fn initialize_models(&self, key_manager: &ApiKeyManager, api_key: String) -> Result<()> {
+        if let Err(e) = self.refresh_models(key_manager, api_key).await {
+            warn!("Failed to load available models, using defaults: {}", e);
         }
+        Ok(())
     }
 
-    pub async fn initialize_models(&self, api_key: &str) -> Result<()> {
-        match self.fetch_available_models(api_key).await {
+    /// Replace the model list with the one reported upstream, starting with `api_key` and
+    /// moving on to the next key when it fails. On failure the current list is kept, or the
+    /// defaults are used if nothing was loaded yet.
+    pub async fn refresh_models(&self, key_manager: &ApiKeyManager, api_key: String) -> Result<usize> {
+        let fetched = key_manager
+            .with_key_retry(api_key, self.settings.max_retry_num.max(1), |key| async move {
+                self.fetch_available_models(&key).await
+            })
+            .await;
+        match fetched {
             Ok(models) => {
-                let model_names: Vec<String> = models
-                    .into_iter()
-                    .map(|model| model.id.replace("models/", ""))
-                    .collect();
+                let count = models.len();
 
-                let mut available_models = self.available_models.write().await;
-                *available_models = model_names.clone();
+                *self.available_models.write().await = models;
+                *self.models_refreshed_at.write().await = Some(chrono::Utc::now());
 
-                info!("Loaded {} available models", model_names.len());
-                Ok(())
+                info!("Loaded {} available models", count);
+                Ok(count)
             }
             Err(e) => {
-                warn!("Failed to load available models: {}", e);
-                // Use default models if fetching fails
-                let mut available_models = self.available_models.write().await;
-                *available_models = self.get_default_models();
-                Ok(())
+                self.load_default_models().await;
+                Err(e.into())
             }
         }
     }
 
-    async fn fetch_available_models(&self, api_key: &str) -> Result<Vec<Model>> {
-        let url = format!("{}/models", GEMINI_BASE_URL);
+    /// Use the built-in model list unless one was already loaded
+    pub async fn load_default_models(&self) {
+        let mut available_models = self.available_models.write().await;
+        if available_models.is_empty() {
+            *available_models = default_models();
+        }
+    }
+
+    /// When the model list was last loaded from upstream
+    pub async fn models_refreshed_at(&self) -> Option<chrono::DateTime<chrono::Utc>> {
+        *self.models_refreshed_at.read().await
+    }
 
-        let response = self.client
-            .get(&url)
+    /// Periodically reload the model list with a key from the key manager
+    pub async fn start_model_refresh_task(self: Arc<Self>, key_manager: Arc<ApiKeyManager>, interval: std::time::Duration) {
+        let mut ticker = tokio::time::interval(interval);
+        // The first tick fires immediately and startup already loaded the list
+        ticker.tick().await;
+
+        loop {
+            ticker.tick().await;
+            let api_key = match key_manager.get_next_key().await {
+                Some(key) => key,
+                None => {
+                    warn!("Skipping model list refresh, no API keys available");
+                    continue;
+                }
+            };
+            if let Err(e) = self.refresh_models(&key_manager, api_key).await {
+                warn!("Model list refresh failed, keeping the current list: {}", e);
+            }
+        }
+    }
+
+    async fn fetch_available_models(&self, api_key: &str) -> Result<Vec<Model>, GeminiError> {
+        if let Some(mock) = &self.mock {
+            return mock.list_models(api_key).await;
+        }
+        let version = ConfigManager::get_settings().await.api_version;
+        match self.fetch_models_from(&version, api_key).await {
+            // Not every version lists models everywhere; v1beta always does
+            Err(GeminiError::Upstream { status: 404, .. }) if version != DEFAULT_GEMINI_API_VERSION => {
+                warn!("Model list not found on Gemini API {}, retrying on {}", version, DEFAULT_GEMINI_API_VERSION);
+                self.fetch_models_from(DEFAULT_GEMINI_API_VERSION, api_key).await
+            }
+            result => result,
+        }
+    }
+
+    async fn fetch_models_from(&self, version: &str, api_key: &str) -> Result<Vec<Model>, GeminiError> {
+        let endpoint = ConfigManager::get_gemini_endpoints().await.swap_remove(0);
+        let url = format!("{}/models", versioned_base_url(&endpoint, version));
+        debug!("Fetching models from Gemini API {}", version);
+        let timeouts = ConfigManager::get_upstream_timeouts().await;
+        let created = chrono::Utc::now().timestamp() as u64;
+        let mut models = Vec::new();
+        let mut page_token: Option<String> = None;
+
+        loop {
+            let mut query = vec![("pageSize", "1000".to_string())];
+            if let Some(token) = page_token.take() {
+                query.push(("pageToken", token));
+            }
+
+            let request = self.client
+                .get(timeouts.connect)
+                .get(&url)
+                .query(&query);
+            let response = browser::with_upstream_headers(request, api_key).await
+                .header("x-goog-api-key", api_key)
+                .timeout(timeouts.request)
+                .send()
+                .await
+                .map_err(|e| GeminiError::transport(e, &timeouts, "Failed to fetch models from Gemini API"))?;
+
+            if !response.status().is_success() {
+                return Err(error_from_response(response).await);
+            }
+
+            let page: GeminiModelList = response.json().await
+                .map_err(|e| GeminiError::transport(e, &timeouts, "Failed to parse models response"))?;
+            models.extend(page.models.iter().map(|info| info.to_model(created)));
+
+            match page.next_page_token {
+                Some(token) if !token.is_empty() => page_token = Some(token),
+                _ => break,
+            }
+        }
+
+        Ok(models)
+    }
+
+    /// Cheap authenticated request used by the readiness check; returns the upstream status
+    pub async fn probe_upstream(&self, api_key: &str) -> Result<reqwest::StatusCode> {
+        if self.mock.is_some() {
+            return Ok(reqwest::StatusCode::OK);
+        }
+        let url = format!("{}/models?pageSize=1", ConfigManager::get_gemini_base_url().await);
+        let timeouts = UpstreamTimeouts {
+            request: UPSTREAM_PROBE_TIMEOUT,
+            ..ConfigManager::get_upstream_timeouts().await
+        };
+
+        let request = self.client.get(timeouts.connect).get(&url);
+        let response = browser::with_upstream_headers(request, api_key).await
             .header("x-goog-api-key", api_key)
+            .timeout(timeouts.request)
             .send()
             .await
-            .context("Failed to fetch models from Gemini API")?;
+            .map_err(|e| GeminiError::transport(e, &timeouts, "Failed to reach Gemini API"))?;
 
-        if !response.status().is_success() {
-            return Err(anyhow::anyhow!("Failed to fetch models: {}", response.status()));
-        }
+        Ok(response.status())
+    }
+
+    /// Single-turn image generation for `/v1/images/generations`
+    pub async fn generate_images(&self, request: &ImageGenerationRequest, api_key: &str) -> Result<ImageGenerationResponse, GeminiError> {
+        let model = request.model.as_deref().unwrap_or(DEFAULT_IMAGE_MODEL);
+        let path = format!("models/{}:generateContent", model);
+        let body = json!(self.image_request(model, &request.prompt));
+
+        let timeouts = ConfigManager::get_upstream_timeouts().await;
+        let response = self.make_gemini_request(&path, api_key, body, &timeouts, false, &[]).await?;
+
+        let gemini_response: GeminiResponse = response.json().await
+            .map_err(|e| GeminiError::transport(e, &timeouts, "Failed to parse Gemini response"))?;
+        images_from_response(gemini_response)
+    }
+
+    /// A cached moderation result for `text` rated by `model`
+    pub async fn cached_moderation(&self, model: &str, text: &str) -> Option<ModerationResult> {
+        self.moderations.get(model, text).await
+    }
+
+    /// Rate `text` with `model` and cache the result
+    pub async fn moderate(&self, model: &str, text: &str, api_key: &str) -> Result<ModerationResult, GeminiError> {
+        let path = format!("models/{}:generateContent", model);
+        let timeouts = ConfigManager::get_upstream_timeouts().await;
+        let response = self.make_gemini_request(&path, api_key, moderation_request(text), &timeouts, false, &[]).await?;
+
+        let body: Value = response.json().await
+            .map_err(|e| GeminiError::transport(e, &timeouts, "Failed to parse Gemini response"))?;
+        let result = moderation_from_gemini(&body);
+        self.moderations.insert(model, text, result.clone()).await;
+        Ok(result)
+    }
 
-        let model_response: ModelResponse = response.json().await
-            .context("Failed to parse models response")?;
+    /// The transcript of `audio`, sent inline after `instruction`
+    pub async fn transcribe(
+        &self,
+        model: &str,
+        audio: &[u8],
+        mime_type: &str,
+        instruction: String,
+        temperature: Option<f32>,
+        api_key: &str,
+    ) -> Result<String, GeminiError> {
+        use base64::Engine;
+
+        let path = format!("models/{}:generateContent", model);
+        let request = GeminiRequest {
+            contents: vec![GeminiContent {
+                role: "user".to_string(),
+                parts: vec![
+                    GeminiPart::Text { text: instruction },
+                    GeminiPart::InlineData {
+                        inline_data: crate::models::schemas::GeminiInlineData {
+                            mime_type: mime_type.to_string(),
+                            data: base64::engine::general_purpose::STANDARD.encode(audio),
+                        },
+                    },
+                ],
+            }],
+            generation_config: Some(GeminiGenerationConfig { temperature, ..Default::default() }),
+            safety_settings: Some(self.get_safety_settings(model, self.settings.model_override_for(model))),
+            tools: None,
+            tool_config: None,
+            cached_content: None,
+        };
 
-        Ok(model_response.data)
+        let timeouts = ConfigManager::get_upstream_timeouts().await;
+        let response = self.make_gemini_request(&path, api_key, json!(request), &timeouts, false, &[]).await?;
+        let gemini_response: GeminiResponse = response.json().await
+            .map_err(|e| GeminiError::transport(e, &timeouts, "Failed to parse Gemini response"))?;
+
+        let block_reason = gemini_response.prompt_feedback.and_then(|feedback| feedback.block_reason);
+        let text: String = gemini_response
+            .candidates
+            .into_iter()
+            .next()
+            .map(|candidate| {
+                candidate.content.parts.into_iter().filter_map(|part| match part {
+                    GeminiPart::Text { text } => Some(text),
+                    _ => None,
+                }).collect()
+            })
+            .unwrap_or_default();
+        if text.trim().is_empty() {
+            return Err(match block_reason {
+                Some(reason) => GeminiError::Blocked { reason },
+                None => GeminiError::Upstream { status: 200, body: "Gemini returned no transcript for the audio".to_string() },
+            });
+        }
+        Ok(text.trim().to_string())
     }
 
-    fn get_default_models(&self) -> Vec<String> {
-        vec![
-            "gemini-1.5-pro".to_string(),
-            "gemini-1.5-pro-exp-0827".to_string(),
-            "gemini-1.5-flash".to_string(),
-            "gemini-1.5-flash-8b".to_string(),
-            "gemini-2.0-flash-exp".to_string(),
-            "text-embedding-004".to_string(),
-        ]
+    fn image_request(&self, model: &str, prompt: &str) -> GeminiRequest {
+        GeminiRequest {
+            contents: vec![GeminiContent {
+                role: "user".to_string(),
+                parts: vec![GeminiPart::Text { text: prompt.to_string() }],
+            }],
+            generation_config: Some(GeminiGenerationConfig {
+                // Image models reject IMAGE on its own
+                response_modalities: Some(vec!["TEXT".to_string(), "IMAGE".to_string()]),
+                ..Default::default()
+            }),
+            safety_settings: Some(self.get_safety_settings(model, self.settings.model_override_for(model))),
+            tools: None,
+            tool_config: None,
+            cached_content: None,
+        }
     }
 
     pub async fn get_available_models(&self) -> Vec<String> {
         let models = self.available_models.read().await;
-        models.clone()
+        models.iter().map(|model| model.id.clone()).collect()
     }
 
-    fn convert_to_gemini_request(&self, request: &ChatCompletionRequest) -> Result<GeminiRequest> {
+    /// Available models with their upstream metadata (limits, capabilities)
+    pub async fn get_model_catalog(&self) -> Vec<Model> {
+        self.available_models.read().await.clone()
+    }
+
+    /// `convert_to_gemini_request`, referencing a context cache instead of the system prompt
+    /// when caching is on and the prompt is long enough
+    async fn prepare_request(&self, request: &ChatCompletionRequest, model: &str, api_key: &str) -> Result<GeminiRequest> {
+        let cached_content = self.cached_system_prompt(request, model, api_key).await;
+        self.convert_to_gemini_request(request, cached_content)
+    }
+
+    async fn cached_system_prompt(&self, request: &ChatCompletionRequest, model: &str, api_key: &str) -> Option<String> {
+        // Requests using a cache cannot carry their own tools, Google Search included
+        if !self.settings.enable_context_caching || request.tools.is_some() || self.uses_search(request) {
+            return None;
+        }
+        let system_prompt = system_prompt(request)?;
+        if estimate_tokens(&system_prompt) < self.settings.context_cache_min_tokens {
+            return None;
+        }
+
+        let base_url = ConfigManager::get_gemini_base_url_for(model).await;
+        let timeouts = ConfigManager::get_upstream_timeouts().await;
+        let ttl = std::time::Duration::from_secs(self.settings.context_cache_ttl_secs);
+        let http = self.client.get(timeouts.connect);
+        match self.context_cache.cached_content(&http, &base_url, api_key, model, &system_prompt, ttl).await {
+            Ok(name) => name,
+            Err(e) => {
+                warn!("Sending system prompt inline: {:#}", e);
+                None
+            }
+        }
+    }
+
+    /// A `-search` model while search mode is on
+    fn uses_search(&self, request: &ChatCompletionRequest) -> bool {
+        self.settings.search.search_mode && ModelVariant::parse(&request.model).search
+    }
+
+    /// `cached_content` names a context cache holding the system prompt; system messages are
+    /// then left out
+    fn convert_to_gemini_request(&self, request: &ChatCompletionRequest, cached_content: Option<String>) -> Result<GeminiRequest> {
+        let variant = ModelVariant::parse(&request.model);
         let mut gemini_contents = Vec::new();
 
-        for message in &request.messages {
+        for message in without_oldest(&request.messages, request.context_trimmed) {
+            if cached_content.is_some() && message.role == "system" {
+                continue;
+            }
             let role = match message.role.as_str() {
                 "user" => "user",
                 "assistant" => "model",
@@ -130,40 +446,106 @@ impl GeminiClient {
             });
         }
 
-        let generation_config = GeminiGenerationConfig {
+        let mut generation_config = GeminiGenerationConfig {
             temperature: request.temperature,
             top_p: request.top_p,
             max_output_tokens: request.max_tokens,
             candidate_count: Some(1),
+            top_k: request.extra.get("top_k").and_then(Value::as_u64).map(|top_k| top_k as u32),
+            thinking_config: thinking_config(request, &variant),
+            stop_sequences: request.extra.get("stop").and_then(stop_sequences),
             ..Default::default()
         };
 
+        let model_override = self.settings.model_override_for(&variant.base);
+        if let Some(model_override) = model_override {
+            apply_model_override(&mut generation_config, model_override, &request.model);
+        }
+
         let mut tools = None;
         if let Some(openai_tools) = &request.tools {
-            tools = Some(vec![GeminiTool {
-                function_declarations: openai_tools
-                    .iter()
-                    .map(|tool| GeminiFunctionDeclaration {
-                        name: tool.function.name.clone(),
-                        description: tool.function.description.clone().unwrap_or_default(),
-                        parameters: tool.function.parameters.clone().unwrap_or(json!({})),
-                    })
-                    .collect(),
-            }]);
+            let (code_execution, functions): (Vec<_>, Vec<_>) = openai_tools
+                .iter()
+                .partition(|tool| tool.function.name == CODE_EXECUTION_TOOL);
+
+            let mut gemini_tools = Vec::new();
+            if !functions.is_empty() {
+                gemini_tools.push(GeminiTool {
+                    function_declarations: functions
+                        .into_iter()
+                        .map(|tool| {
+                            let (parameters, dropped) = sanitize_parameters(tool.function.parameters.as_ref());
+                            if !dropped.is_empty() {
+                                info!("Left unsupported schema keywords out of function {}: {}", tool.function.name, dropped.join(", "));
+                            }
+                            if let Err(problem) = validate_parameters(&parameters) {
+                                warn!("Gemini may reject the parameters of function {}: {}", tool.function.name, problem);
+                            }
+                            GeminiFunctionDeclaration {
+                                name: tool.function.name.clone(),
+                                description: tool.function.description.clone().unwrap_or_default(),
+                                parameters,
+                            }
+                        })
+                        .collect(),
+                    ..Default::default()
+                });
+            }
+            if !code_execution.is_empty() {
+                gemini_tools.push(GeminiTool {
+                    code_execution: Some(serde_json::Map::new()),
+                    ..Default::default()
+                });
+            }
+            tools = Some(gemini_tools);
+        }
+
+        let mut safety_settings = self.get_safety_settings(&variant.base, model_override);
+        if let Some(requested) = request.extra.get("safety_settings") {
+            if model_override.is_some_and(|o| o.force_safety_threshold.is_some()) {
+                debug!("Ignoring requested safety_settings: {} forces a threshold", request.model);
+            } else {
+                match serde_json::from_value::<Vec<GeminiSafetySetting>>(requested.clone()) {
+                    Ok(requested) => merge_safety_settings(&mut safety_settings, requested),
+                    Err(e) => warn!("Ignoring malformed safety_settings: {}", e),
+                }
+            }
         }
 
-        // Add search tools if search mode is enabled and model supports it
-        if self.settings.search.search_mode && request.model.contains("-search") {
-            let search_tools: Vec<Value> = serde_json::from_str(GEMINI_SEARCH_TOOLS)?;
-            // Merge with existing tools if any
+        // X-Rujimi-Safety, unless the model forces a threshold
+        let safety_settings = match request.overrides.safety {
+            Some(safety) if model_override.is_some_and(|o| o.force_safety_threshold.is_some()) => {
+                debug!("Ignoring safety override {:?}: {} forces a threshold", safety, request.model);
+                Some(safety_settings)
+            }
+            Some(SafetyOverride::Off) => Some(
+                safety_settings
+                    .into_iter()
+                    .map(|setting| GeminiSafetySetting { threshold: "OFF".to_string(), ..setting })
+                    .collect(),
+            ),
+            Some(SafetyOverride::Default) => None,
+            None => Some(safety_settings),
+        };
+
+        if self.uses_search(request) {
+            tools.get_or_insert_with(Vec::new).push(GeminiTool {
+                google_search: Some(serde_json::Map::new()),
+                ..Default::default()
+            });
         }
 
         // Add random string for stealth if enabled
-        if self.settings.random_string {
-            let random_str = generate_random_string(self.settings.random_string_length);
-            if let Some(first_content) = gemini_contents.first_mut() {
-                if let Some(GeminiPart::Text { text }) = first_content.parts.first_mut() {
-                    text.push_str(&format!(" {}", random_str));
+        if self.settings.random_string && random_marker_allowed(request) {
+            let marker = generate_random_string(self.settings.random_string_length);
+            append_random_marker(&mut gemini_contents, &marker);
+        }
+
+        // The Gemini API has no request labels, so the end user can only travel in the prompt
+        if self.settings.end_user_note && random_marker_allowed(request) {
+            if let Some(user) = request.user.as_deref().filter(|user| !user.is_empty()) {
+                if let Some(text) = last_user_text(&mut gemini_contents) {
+                    text.push_str(&format!("\n\n{}end-user: {}{}", RANDOM_MARKER_OPEN, end_user_id(user), RANDOM_MARKER_CLOSE));
                 }
             }
         }
@@ -171,9 +553,10 @@ impl GeminiClient {
         Ok(GeminiRequest {
             contents: gemini_contents,
             generation_config: Some(generation_config),
-            safety_settings: Some(self.get_safety_settings()),
+            safety_settings,
             tools,
             tool_config: None,
+            cached_content,
         })
     }
 
@@ -238,18 +621,20 @@ impl GeminiClient {
         Err(anyhow::anyhow!("Invalid base64 image format"))
     }
 
-    fn get_safety_settings(&self) -> Vec<GeminiSafetySetting> {
+    fn get_safety_settings(&self, model: &str, model_override: Option<&ModelOverride>) -> Vec<GeminiSafetySetting> {
         // Use configured safety settings (check if model supports Gemini 2.0)
-        let config_settings = if self.is_gemini_2_model("") {
+        let config_settings = if self.is_gemini_2_model(model) {
             get_safety_settings_g2()
         } else {
             get_safety_settings()
         };
 
+        let forced_threshold = model_override.and_then(|o| o.force_safety_threshold.clone());
+
         // Convert config SafetySetting to GeminiSafetySetting
         config_settings.into_iter().map(|setting| GeminiSafetySetting {
             category: setting.category,
-            threshold: setting.threshold,
+            threshold: forced_threshold.clone().unwrap_or(setting.threshold),
         }).collect()
     }
 
@@ -257,11 +642,19 @@ impl GeminiClient {
         model.contains("gemini-2.0") || model.contains("gemini-exp")
     }
 
-    fn convert_gemini_response(&self, gemini_response: GeminiResponse, request: &ChatCompletionRequest) -> Result<ChatCompletionResponse> {
+    fn convert_gemini_response(&self, gemini_response: GeminiResponse, request: &ChatCompletionRequest) -> Result<ChatCompletionResponse, GeminiError> {
+        if gemini_response.candidates.is_empty() {
+            if let Some(reason) = gemini_response.prompt_feedback.and_then(|feedback| feedback.block_reason) {
+                return Err(GeminiError::Blocked { reason });
+            }
+        }
+
         let mut choices = Vec::new();
+        let array_content = request.wants_array_content(self.settings.array_content_responses);
 
         for (index, candidate) in gemini_response.candidates.into_iter().enumerate() {
-            let message = self.convert_gemini_content_to_message(candidate.content)?;
+            let message = self.convert_gemini_content_to_message(candidate.content, array_content)
+                .map_err(|e| GeminiError::Parse(e.to_string()))?;
 
             choices.push(ChatChoice {
                 index: index as u32,
@@ -271,11 +664,7 @@ impl GeminiClient {
             });
         }
 
-        let usage = gemini_response.usage_metadata.map(|meta| Usage {
-            prompt_tokens: meta.prompt_token_count.unwrap_or(0),
-            completion_tokens: meta.candidates_token_count.unwrap_or(0),
-            total_tokens: meta.total_token_count.unwrap_or(0),
-        });
+        let usage = gemini_response.usage_metadata.as_ref().map(Usage::from_gemini);
 
         Ok(ChatCompletionResponse {
             id: format!("chatcmpl-{}", uuid::Uuid::new_v4()),
@@ -288,14 +677,36 @@ impl GeminiClient {
         })
     }
 
-    fn convert_gemini_content_to_message(&self, content: GeminiContent) -> Result<ChatMessage> {
-        let mut text_parts = Vec::new();
+    /// `content` as an OpenAI message: its text and images in one string, or with
+    /// `array_content` as `text` and `image_url` parts
+    fn convert_gemini_content_to_message(&self, content: GeminiContent, array_content: bool) -> Result<ChatMessage> {
+        // Built in place so multi-megabyte inline images are copied only once
+        let mut text = String::new();
+        let mut has_text = false;
+        // Array content: the finished parts, `text` holding the one being built
+        let mut parts = Vec::new();
         let mut tool_calls = Vec::new();
 
         for part in content.parts {
             match part {
-                GeminiPart::Text { text } => {
-                    text_parts.push(text);
+                GeminiPart::Text { text: part_text } => {
+                    text.push_str(&part_text);
+                    has_text = true;
+                }
+                GeminiPart::InlineData { inline_data } if array_content => {
+                    if let Some(image) = inline_image_part(&inline_data) {
+                        push_text_part(&mut parts, &mut text);
+                        parts.push(image);
+                    }
+                }
+                GeminiPart::InlineData { inline_data } => {
+                    has_text |= push_inline_image(&mut text, &inline_data);
+                }
+                part @ (GeminiPart::ExecutableCode { .. } | GeminiPart::CodeExecutionResult { .. }) => {
+                    if let Some(rendered) = render_code_execution_part(&part, self.settings.code_execution_render) {
+                        text.push_str(&rendered);
+                        has_text = true;
+                    }
                 }
                 GeminiPart::FunctionCall { function_call } => {
                     tool_calls.push(ToolCall {
@@ -316,33 +727,370 @@ impl GeminiClient {
             _ => "user",
         };
 
+        let content = if array_content {
+            push_text_part(&mut parts, &mut text);
+            (!parts.is_empty()).then_some(Value::Array(parts))
+        } else {
+            has_text.then_some(Value::String(text))
+        };
+
         Ok(ChatMessage {
             role: role.to_string(),
-            content: if text_parts.is_empty() {
-                None
-            } else {
-                Some(Value::String(text_parts.join("")))
-            },
+            content,
             name: None,
             tool_calls: if tool_calls.is_empty() { None } else { Some(tool_calls) },
             tool_call_id: None,
         })
     }
 
-    async fn make_gemini_request(&self, url: &str, api_key: &str, body: Value) -> Result<reqwest::Response> {
-        let response = self.client
-            .post(url)
-            .header("Content-Type", "application/json")
-            .header("x-goog-api-key", api_key)
-            .json(&body)
-            .send()
-            .await
-            .context("Failed to send request to Gemini API")?;
+    /// How many of the oldest messages to leave out of `request` so its prompt fits the
+    /// model's input limit less `max_tokens`, per `context_trim_strategy`. Models without a
+    /// known limit are never trimmed; an `InvalidRequest` error when the prompt cannot fit or
+    /// the strategy is `error`.
+    pub async fn context_trim(&self, request: &ChatCompletionRequest) -> Result<usize, GeminiError> {
+        let strategy = self.settings.context_trim_strategy;
+        if strategy == ContextTrimStrategy::Off {
+            return Ok(0);
+        }
+        let base = ModelVariant::parse(&request.model).base;
+        let limit = self.available_models.read().await.iter().find(|model| model.id == base).and_then(|model| model.context_length);
+        let budget = match limit {
+            Some(limit) => limit.saturating_sub(request.max_tokens.unwrap_or(0)),
+            None => return Ok(0),
+        };
+
+        let count = match strategy {
+            ContextTrimStrategy::DropOldest => messages_to_drop(&request.messages, budget),
+            _ => (prompt_tokens(&request.messages) <= budget).then_some(0),
+        };
+        count.ok_or_else(|| GeminiError::InvalidRequest {
+            message: format!(
+                "The conversation is about {} tokens, more than the {} that fit the context window of {}",
+                prompt_tokens(&request.messages),
+                budget,
+                request.model
+            ),
+        })
+    }
+
+    /// Count tokens for `contents` with Gemini's countTokens endpoint
+    pub async fn count_tokens(&self, model: &str, contents: Vec<GeminiContent>, api_key: &str) -> Result<u32> {
+        let path = format!("models/{}:countTokens", ModelVariant::parse(model).base);
+        let timeouts = ConfigManager::get_upstream_timeouts().await;
+        let response = self.make_gemini_request(&path, api_key, json!({ "contents": contents }), &timeouts, false, &[]).await?;
+
+        let body: Value = response.json().await
+            .map_err(|e| GeminiError::transport(e, &timeouts, "Failed to parse countTokens response"))?;
+        Ok(body.get("totalTokens").and_then(|v| v.as_u64()).unwrap_or(0) as u32)
+    }
+
+    /// Usage for a completed response when Gemini did not report it: estimated locally, or
+    /// counted upstream when `accurate_token_estimates` is on
+    pub async fn usage_for_response(&self, request: &ChatCompletionRequest, response: &ChatCompletionResponse, api_key: &str) -> Result<Usage> {
+        if let Some(usage) = &response.usage {
+            return Ok(usage.clone());
+        }
+
+        let prompt = self.convert_to_gemini_request(request, None)?.contents;
+        let completion_text: String = response.choices
+            .iter()
+            .filter_map(|choice| choice.message.content.as_ref().map(extract_text_from_value))
+            .collect();
+        let completion = vec![GeminiContent {
+            role: "model".to_string(),
+            parts: vec![GeminiPart::Text { text: completion_text }],
+        }];
+
+        let (prompt_tokens, completion_tokens) = tokio::join!(
+            self.token_count(&request.model, prompt, api_key),
+            self.token_count(&request.model, completion, api_key),
+        );
+
+        Ok(Usage::new(prompt_tokens, completion_tokens))
+    }
+
+    /// Tokens in `contents`: Gemini's cached count in accurate mode, the local estimate
+    /// otherwise or when counting fails
+    async fn token_count(&self, model: &str, contents: Vec<GeminiContent>, api_key: &str) -> u32 {
+        let text: String = contents
+            .iter()
+            .flat_map(|content| &content.parts)
+            .filter_map(|part| match part {
+                GeminiPart::Text { text } => Some(text.as_str()),
+                _ => None,
+            })
+            .collect::<Vec<_>>()
+            .join("\n");
+        if !self.settings.accurate_token_estimates {
+            return estimate_tokens(&text);
+        }
+
+        let input = serde_json::to_string(&contents).unwrap_or_default();
+        match self.token_counts.get_or_count(model, &input, self.count_tokens(model, contents, api_key)).await {
+            Ok(tokens) => tokens,
+            Err(e) => {
+                warn!("Counting tokens failed, estimating instead: {}", e);
+                estimate_tokens(&text)
+            }
+        }
+    }
+
+    /// Send a request upstream. Non-streaming calls get the total request timeout; streaming
+    /// calls only bound the wait for response headers and rely on the idle timeout afterwards.
+    /// A connection that fails before Gemini answers is retried per `network_retries`, which
+    /// is safe as every call made here can be repeated. A non-success status is returned as
+    /// the matching `GeminiError`.
+    /// Send `body` to `path` on the first upstream endpoint that answers, trying the configured
+    /// endpoints in order. Unreachable endpoints and 5xx answers move on to the next one; when
+    /// there are several and all of them fail, the error is reported as a connection failure
+    /// so the key is not blamed for it. `forwarded_headers` come from the incoming request.
+    /// The API version is the one configured for the model named in `path`.
+    async fn make_gemini_request(
+        &self,
+        path: &str,
+        api_key: &str,
+        body: Value,
+        timeouts: &UpstreamTimeouts,
+        streaming: bool,
+        forwarded_headers: &[(String, String)],
+    ) -> Result<reqwest::Response, GeminiError> {
+        let endpoints = ConfigManager::get_gemini_endpoints().await;
+        let network_retry = ConfigManager::get_network_retry().await;
+        let version = ConfigManager::get_api_version(path_model(path)).await;
+        debug!("Using Gemini API {} for {}", version, path);
+        let mut last_error = None;
+
+        for endpoint in self.endpoints.order(&endpoints, Instant::now()) {
+            let url = format!("{}/{}", versioned_base_url(endpoint, &version), path);
+            let send = || async {
+                let builder = self.client.get(timeouts.connect).post(&url);
+                let builder = browser::with_forwarded_headers(builder, forwarded_headers);
+                let mut builder = browser::with_upstream_headers(builder, api_key).await
+                    .header("Content-Type", "application/json")
+                    .header("x-goog-api-key", api_key)
+                    .json(&body);
+
+                if !streaming {
+                    builder = builder.timeout(timeouts.request);
+                }
+
+                match tokio::time::timeout(timeouts.request, builder.send()).await {
+                    Ok(result) => result.map_err(|e| GeminiError::transport(e, timeouts, "Failed to send request to Gemini API")),
+                    Err(_) => Err(UpstreamTimeoutError {
+                        phase: "request",
+                        seconds: timeouts.request.as_secs(),
+                    }.into()),
+                }
+            };
+            let result = match network_retry.run(send).await {
+                Ok(response) if !response.status().is_success() => Err(error_from_response(response).await),
+                result => result,
+            };
+
+            match result {
+                Err(e) if is_endpoint_failure(&e) => {
+                    if endpoints.len() > 1 {
+                        warn!("Upstream endpoint {} failed, trying the next one: {}", endpoint, e);
+                    }
+                    self.endpoints.record_failure(endpoint, &e, Instant::now());
+                    last_error = Some(e);
+                }
+                result => {
+                    self.endpoints.record_success(endpoint);
+                    return result;
+                }
+            }
+        }
+
+        match last_error {
+            Some(e) if endpoints.len() > 1 => Err(GeminiError::Connection(format!("Every upstream endpoint failed, the last with: {}", e))),
+            Some(e) => Err(e),
+            None => Err(GeminiError::Connection("No upstream endpoint configured".to_string())),
+        }
+    }
+
+    /// Health of each configured upstream endpoint, in failover order
+    pub async fn endpoint_status(&self) -> Vec<EndpointStatus> {
+        self.endpoints.snapshot(&ConfigManager::get_gemini_endpoints().await, Instant::now())
+    }
+}
+
+async fn error_from_response(response: reqwest::Response) -> GeminiError {
+    let status = response.status();
+    let retry_after = response
+        .headers()
+        .get(reqwest::header::RETRY_AFTER)
+        .and_then(|value| value.to_str().ok())
+        .map(str::to_string);
+    let body = response.text().await.unwrap_or_default();
+    GeminiError::from_status(status, retry_after.as_deref(), body)
+}
 
-        Ok(response)
+/// Model named by an upstream path such as `models/gemini-2.5-pro:generateContent`
+fn path_model(path: &str) -> &str {
+    let model = path.strip_prefix("models/").unwrap_or(path);
+    model.split([':', '?']).next().unwrap_or(model)
+}
+
+/// The random string is left out where it could change the output: JSON responses and tool use
+fn random_marker_allowed(request: &ChatCompletionRequest) -> bool {
+    !request.uses_tools_or_json()
+}
+
+/// Append `marker` as an HTML comment to the last user text, once: text already ending in a
+/// marker (a retried request) is left alone
+fn append_random_marker(contents: &mut [GeminiContent], marker: &str) {
+    if let Some(text) = last_user_text(contents) {
+        if !(text.ends_with(RANDOM_MARKER_CLOSE) && text.contains(RANDOM_MARKER_OPEN)) {
+            text.push_str(&format!("\n\n{}{}{}", RANDOM_MARKER_OPEN, marker, RANDOM_MARKER_CLOSE));
+        }
     }
 }
 
+fn last_user_text(contents: &mut [GeminiContent]) -> Option<&mut String> {
+    contents
+        .iter_mut()
+        .rev()
+        .find(|content| content.role == "user")
+        .and_then(|content| content.parts.iter_mut().rev().find_map(|part| match part {
+            GeminiPart::Text { text } => Some(text),
+            _ => None,
+        }))
+}
+
+/// Extra request fields the Gemini conversion drops, sorted
+pub fn dropped_params(request: &ChatCompletionRequest) -> Vec<String> {
+    let mut dropped: Vec<String> = request.extra
+        .keys()
+        .filter(|name| !MAPPED_PARAMS.contains(&name.as_str()))
+        .cloned()
+        .collect();
+    dropped.sort();
+    dropped
+}
+
+/// Client-requested thresholds replace the configured ones per category
+fn merge_safety_settings(settings: &mut Vec<GeminiSafetySetting>, requested: Vec<GeminiSafetySetting>) {
+    for setting in requested {
+        match settings.iter_mut().find(|existing| existing.category == setting.category) {
+            Some(existing) => existing.threshold = setting.threshold,
+            None => settings.push(setting),
+        }
+    }
+}
+
+/// Text of every system message, in order; `None` when there are none
+fn system_prompt(request: &ChatCompletionRequest) -> Option<String> {
+    let parts: Vec<String> = request.messages
+        .iter()
+        .filter(|message| message.role == "system")
+        .filter_map(|message| message.content.as_ref().map(extract_text_from_value))
+        .collect();
+    (!parts.is_empty()).then(|| parts.join("\n\n"))
+}
+
+/// Collect the inline images of every candidate, moving the base64 data out of the parsed
+/// response rather than copying it
+fn images_from_response(response: GeminiResponse) -> Result<ImageGenerationResponse, GeminiError> {
+    let response_block_reason = response.prompt_feedback.and_then(|feedback| feedback.block_reason);
+    let mut data = Vec::new();
+    for candidate in response.candidates {
+        let mut text = String::new();
+        let first_image = data.len();
+        for part in candidate.content.parts {
+            match part {
+                GeminiPart::InlineData { inline_data } if inline_data.mime_type.starts_with("image/") => {
+                    data.push(ImageData { b64_json: inline_data.data, revised_prompt: None });
+                }
+                GeminiPart::Text { text: part_text } => text.push_str(&part_text),
+                _ => {}
+            }
+        }
+        if !text.trim().is_empty() {
+            for image in &mut data[first_image..] {
+                image.revised_prompt = Some(text.trim().to_string());
+            }
+        }
+    }
+
+    if data.is_empty() {
+        return Err(match response_block_reason {
+            Some(reason) => GeminiError::Blocked { reason },
+            None => GeminiError::Upstream { status: 200, body: "Gemini returned no image for the prompt".to_string() },
+        });
+    }
+    Ok(ImageGenerationResponse {
+        created: chrono::Utc::now().timestamp() as u64,
+        data,
+    })
+}
+
+/// Parse one streamed event, an SSE `data` payload or a JSON array element, and convert it;
+/// malformed events are logged and skipped
+fn convert_stream_event(converter: &mut GeminiStreamConverter, data: &str) -> Vec<ChatCompletionChunk> {
+    match serde_json::from_str::<GeminiResponse>(data) {
+        Ok(response) => converter.convert(response),
+        Err(e) => {
+            warn!("Skipping unparseable Gemini stream event: {}", e);
+            Vec::new()
+        }
+    }
+}
+
+/// Apply per-model caps and defaults to the generation config. Caps only ever lower the
+/// client's value and defaults only fill fields the client omitted; clamps are logged, not rejected.
+fn apply_model_override(config: &mut GeminiGenerationConfig, model_override: &ModelOverride, model: &str) {
+    if let Some(cap) = model_override.max_output_tokens_cap {
+        match config.max_output_tokens {
+            Some(requested) if requested > cap => {
+                config.max_output_tokens = Some(cap);
+                let mut extra = std::collections::HashMap::new();
+                extra.insert("model".to_string(), json!(model));
+                extra.insert("requested".to_string(), json!(requested));
+                extra.insert("cap".to_string(), json!(cap));
+                log(
+                    "warning",
+                    &format!("Clamped max_tokens for {} from {} to {}", model, requested, cap),
+                    Some(extra),
+                );
+            }
+            Some(_) => {}
+            None => config.max_output_tokens = Some(cap),
+        }
+    }
+
+    if config.temperature.is_none() {
+        config.temperature = model_override.default_temperature;
+    }
+
+    if config.top_p.is_none() {
+        config.top_p = model_override.default_top_p;
+    }
+}
+
+/// The client's `thinking_config`, with the budget a `-thinking` or `-nothinking` suffix forces
+fn thinking_config(request: &ChatCompletionRequest, variant: &ModelVariant) -> Option<Value> {
+    let requested = request.extra.get("thinking_config").cloned();
+    match variant.thinking_budget() {
+        Some(budget) => {
+            let mut config = requested.filter(Value::is_object).unwrap_or_else(|| json!({}));
+            config["thinking_budget"] = json!(budget);
+            Some(config)
+        }
+        None => requested,
+    }
+}
+
+/// OpenAI's `stop`, a string or a list of strings, as Gemini stop sequences
+fn stop_sequences(stop: &Value) -> Option<Vec<String>> {
+    let sequences: Vec<String> = match stop {
+        Value::String(sequence) => vec![sequence.clone()],
+        Value::Array(sequences) => sequences.iter().filter_map(Value::as_str).map(str::to_string).collect(),
+        _ => Vec::new(),
+    };
+    (!sequences.is_empty()).then_some(sequences)
+}
+
 impl Default for GeminiGenerationConfig {
     fn default() -> Self {
         Self {
@@ -352,105 +1100,106 @@ impl Default for GeminiGenerationConfig {
             candidate_count: Some(1),
             max_output_tokens: None,
             stop_sequences: None,
+            response_modalities: None,
+            thinking_config: None,
         }
     }
 }
 
 #[async_trait]
 impl GeminiClientTrait for GeminiClient {
-    async fn chat_completion(&self, request: ChatCompletionRequest, api_key: &str) -> Result<ChatCompletionResponse> {
-        let model_name = if request.model.contains("-search") {
-            request.model.replace("-search", "")
-        } else {
-            request.model.clone()
-        };
+    async fn chat_completion(&self, request: ChatCompletionRequest, api_key: &str) -> Result<ChatCompletionResponse, GeminiError> {
+        if let Some(mock) = &self.mock {
+            return mock.chat_completion(request, api_key).await;
+        }
+        let model_name = ModelVariant::parse(&request.model).base;
 
-        let url = format!("{}/models/{}:generateContent", GEMINI_BASE_URL, model_name);
+        let path = format!("models/{}:generateContent", model_name);
 
-        let gemini_request = self.convert_to_gemini_request(&request)?;
-        let body = serde_json::to_value(gemini_request)?;
+        let gemini_request = self.prepare_request(&request, &model_name, api_key).await
+            .map_err(|e| GeminiError::InvalidRequest { message: e.to_string() })?;
+        let body = json!(gemini_request);
 
-        debug!("Sending request to Gemini API: {}", url);
+        debug!("Sending request to Gemini API: {}", path);
 
-        let response = self.make_gemini_request(&url, api_key, body).await?;
-
-        if !response.status().is_success() {
-            let status = response.status();
-            let error_text = response.text().await.unwrap_or_default();
-            return Err(anyhow::anyhow!("Gemini API error: {} - {}", status, error_text));
-        }
+        let timeouts = ConfigManager::get_upstream_timeouts().await;
+        let response = self.make_gemini_request(&path, api_key, body, &timeouts, false, &request.forwarded_headers).await?;
 
         let gemini_response: GeminiResponse = response.json().await
-            .context("Failed to parse Gemini response")?;
+            .map_err(|e| GeminiError::transport(e, &timeouts, "Failed to parse Gemini response"))?;
 
         self.convert_gemini_response(gemini_response, &request)
     }
 
-    async fn chat_completion_stream(&self, request: ChatCompletionRequest, api_key: &str) -> Result<Pin<Box<dyn Stream<Item = Result<ChatCompletionChunk>> + Send>>> {
-        let model_name = if request.model.contains("-search") {
-            request.model.replace("-search", "")
-        } else {
-            request.model.clone()
-        };
-
-        let url = format!("{}/models/{}:streamGenerateContent", GEMINI_BASE_URL, model_name);
-
-        let gemini_request = self.convert_to_gemini_request(&request)?;
-        let body = serde_json::to_value(gemini_request)?;
-
-        let response = self.make_gemini_request(&url, api_key, body).await?;
-
-        if !response.status().is_success() {
-            let status = response.status();
-            let error_text = response.text().await.unwrap_or_default();
-            return Err(anyhow::anyhow!("Gemini API error: {} - {}", status, error_text));
-        }
-
-        let stream = response.bytes_stream()
-            .map(move |chunk_result| {
-                match chunk_result {
-                    Ok(chunk) => {
-                        // Parse streaming response and convert to OpenAI format
-                        // This is a simplified implementation
-                        let chunk_str = String::from_utf8_lossy(&chunk);
-
-                        // Create a chat completion chunk
-                        Ok(ChatCompletionChunk {
-                            id: format!("chatcmpl-{}", uuid::Uuid::new_v4()),
-                            object: "chat.completion.chunk".to_string(),
-                            created: chrono::Utc::now().timestamp() as u64,
-                            model: request.model.clone(),
-                            choices: vec![ChatChoiceDelta {
-                                index: 0,
-                                delta: ChatMessageDelta {
-                                    role: Some("assistant".to_string()),
-                                    content: Some(chunk_str.to_string()),
-                                    tool_calls: None,
-                                },
-                                finish_reason: None,
-                                logprobs: None,
-                            }],
-                            system_fingerprint: None,
-                        })
+    async fn chat_completion_stream(&self, request: ChatCompletionRequest, api_key: &str) -> Result<ChatCompletionStream, GeminiError> {
+        if let Some(mock) = &self.mock {
+            return mock.chat_completion_stream(request, api_key).await;
+        }
+        let model_name = ModelVariant::parse(&request.model).base;
+
+        // Either form of the body is understood, whatever was asked for
+        let alt = if self.settings.gemini_array_stream { "" } else { "?alt=sse" };
+        let path = format!("models/{}:streamGenerateContent{}", model_name, alt);
+
+        let gemini_request = self.prepare_request(&request, &model_name, api_key).await
+            .map_err(|e| GeminiError::InvalidRequest { message: e.to_string() })?;
+        let body = json!(gemini_request);
+
+        let timeouts = ConfigManager::get_upstream_timeouts().await;
+        let response = self.make_gemini_request(&path, api_key, body, &timeouts, true, &request.forwarded_headers).await?;
+
+        let bytes = response.bytes_stream()
+            .map(|chunk| chunk.map_err(|e| GeminiError::Network(format!("Stream error: {}", e))));
+        let bytes = with_idle_timeout(Box::pin(bytes), timeouts.stream_idle)
+            .map(Some)
+            .chain(stream::once(async { None }));
+
+        // `None` marks the end of the body so a trailing unterminated event is still converted
+        let include_usage = request.include_usage();
+        let converter = GeminiStreamConverter::new(&request.model)
+            .with_code_execution_render(self.settings.code_execution_render);
+        let state = (StreamEventParser::new(), converter);
+        let stream = bytes
+            .scan(state, move |(parser, converter), item| {
+                let items: Vec<Result<ChatCompletionChunk, GeminiError>> = match item {
+                    Some(Ok(bytes)) => parser.push(&bytes)
+                        .into_iter()
+                        .flat_map(|data| convert_stream_event(converter, &data))
+                        .map(Ok)
+                        .collect(),
+                    Some(Err(e)) => vec![Err(e)],
+                    None => {
+                        let mut chunks: Vec<ChatCompletionChunk> = parser.finish()
+                            .into_iter()
+                            .flat_map(|data| convert_stream_event(converter, &data))
+                            .collect();
+                        if include_usage {
+                            chunks.push(converter.usage_chunk());
+                        }
+                        chunks.into_iter().map(Ok).collect()
                     }
-                    Err(e) => Err(anyhow::anyhow!("Stream error: {}", e)),
-                }
-            });
+                };
+                future::ready(Some(stream::iter(items)))
+            })
+            .flatten();
 
         Ok(Box::pin(stream))
     }
 
-    async fn list_models(&self, api_key: &str) -> Result<Vec<Model>> {
+    async fn list_models(&self, api_key: &str) -> Result<Vec<Model>, GeminiError> {
         self.fetch_available_models(api_key).await
     }
 
-    async fn embedding(&self, request: EmbeddingRequest, api_key: &str) -> Result<EmbeddingResponse> {
-        let url = format!("{}/models/{}:embedContent", GEMINI_BASE_URL, request.model);
+    async fn embedding(&self, request: EmbeddingRequest, api_key: &str) -> Result<EmbeddingResponse, GeminiError> {
+        if let Some(mock) = &self.mock {
+            return mock.embedding(request, api_key).await;
+        }
+        let path = format!("models/{}:embedContent", request.model);
 
         let content = match &request.input {
             crate::models::schemas::EmbeddingInput::String(text) => text.clone(),
             crate::models::schemas::EmbeddingInput::ArrayOfStrings(texts) => texts.join(" "),
-            _ => return Err(anyhow::anyhow!("Unsupported embedding input format")),
+            _ => return Err(GeminiError::InvalidRequest { message: "Unsupported embedding input format".to_string() }),
         };
 
         let body = json!({
@@ -459,23 +1208,18 @@ impl GeminiClientTrait for GeminiClient {
             }
         });
 
-        let response = self.make_gemini_request(&url, api_key, body).await?;
+        let timeouts = ConfigManager::get_upstream_timeouts().await;
+        let response = self.make_gemini_request(&path, api_key, body, &timeouts, false, &[]).await?;
 
-        if !response.status().is_success() {
-            let status = response.status();
-            let error_text = response.text().await.unwrap_or_default();
-            return Err(anyhow::anyhow!("Gemini API error: {} - {}", status, error_text));
-        }
-        
         let gemini_response: Value = response.json().await
-            .context("Failed to parse Gemini embedding response")?;
+            .map_err(|e| GeminiError::transport(e, &timeouts, "Failed to parse Gemini embedding response"))?;
 
         // Convert Gemini embedding response to OpenAI format
         let embedding_data = gemini_response
             .get("embedding")
             .and_then(|e| e.get("values"))
             .and_then(|v| v.as_array())
-            .ok_or_else(|| anyhow::anyhow!("Invalid embedding response format"))?;
+            .ok_or_else(|| GeminiError::Parse("Invalid embedding response format".to_string()))?;
 
         let embedding: Vec<f64> = embedding_data
             .iter()
@@ -491,9 +1235,561 @@ impl GeminiClientTrait for GeminiClient {
             }],
             model: request.model,
             usage: crate::models::schemas::EmbeddingUsage {
-                prompt_tokens: content.len() as u32 / 4, // Rough estimation
-                total_tokens: content.len() as u32 / 4,
+                prompt_tokens: estimate_tokens(&content),
+                total_tokens: estimate_tokens(&content),
             },
         })
     }
-}
\ No newline at end of file
+}
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::CodeExecutionRender;
+
+    fn test_request(model: &str) -> ChatCompletionRequest {
+        serde_json::from_value(json!({
+            "model": model,
+            "messages": [{"role": "user", "content": "hello"}]
+        }))
+        .unwrap()
+    }
+
+    fn client_with_override(pattern: &str, model_override: ModelOverride) -> GeminiClient {
+        let mut settings = Settings {
+            random_string: false,
+            ..Default::default()
+        };
+        settings.model_overrides.insert(pattern.to_string(), model_override);
+        GeminiClient::new(Arc::new(settings))
+    }
+
+    #[test]
+    fn test_model_listing_keeps_metadata() {
+        let page: GeminiModelList = serde_json::from_value(json!({
+            "models": [
+                {
+                    "name": "models/gemini-2.5-flash",
+                    "inputTokenLimit": 1048576,
+                    "outputTokenLimit": 65536,
+                    "supportedGenerationMethods": ["generateContent", "countTokens"],
+                    "thinking": true
+                },
+                {
+                    "name": "models/text-embedding-004",
+                    "inputTokenLimit": 2048,
+                    "supportedGenerationMethods": ["embedContent"]
+                }
+            ],
+            "nextPageToken": "page-2"
+        }))
+        .unwrap();
+        assert_eq!(page.next_page_token.as_deref(), Some("page-2"));
+
+        let flash = page.models[0].to_model(0);
+        assert_eq!(flash.id, "gemini-2.5-flash");
+        assert_eq!(flash.context_length, Some(1048576));
+        assert_eq!(flash.max_output_tokens, Some(65536));
+        assert_eq!(flash.capabilities, vec!["vision", "tools", "thinking"]);
+
+        let search = flash.variant("-search");
+        assert_eq!(search.id, "gemini-2.5-flash-search");
+        assert_eq!(search.context_length, Some(1048576));
+
+        let embedding = page.models[1].to_model(0);
+        assert_eq!(embedding.capabilities, vec!["embeddings"]);
+        assert_eq!(embedding.max_output_tokens, None);
+    }
+
+    #[test]
+    fn test_max_tokens_clamped_to_cap() {
+        let client = client_with_override("gemini-1.5-pro*", ModelOverride {
+            max_output_tokens_cap: Some(1024),
+            ..Default::default()
+        });
+
+        let mut request = test_request("gemini-1.5-pro-latest");
+        request.max_tokens = Some(8192);
+        let config = client.convert_to_gemini_request(&request, None).unwrap().generation_config.unwrap();
+        assert_eq!(config.max_output_tokens, Some(1024));
+
+        request.max_tokens = Some(512);
+        let config = client.convert_to_gemini_request(&request, None).unwrap().generation_config.unwrap();
+        assert_eq!(config.max_output_tokens, Some(512));
+    }
+
+    #[test]
+    fn test_defaults_only_fill_omitted_fields() {
+        let client = client_with_override("gemini-1.5-flash", ModelOverride {
+            default_temperature: Some(0.3),
+            default_top_p: Some(0.8),
+            ..Default::default()
+        });
+
+        let mut request = test_request("gemini-1.5-flash");
+        let config = client.convert_to_gemini_request(&request, None).unwrap().generation_config.unwrap();
+        assert_eq!(config.temperature, Some(0.3));
+        assert_eq!(config.top_p, Some(0.8));
+
+        request.temperature = Some(1.2);
+        let config = client.convert_to_gemini_request(&request, None).unwrap().generation_config.unwrap();
+        assert_eq!(config.temperature, Some(1.2));
+        assert_eq!(config.top_p, Some(0.8));
+    }
+
+    #[test]
+    fn test_override_does_not_apply_to_other_models() {
+        let client = client_with_override("gemini-1.5-pro", ModelOverride {
+            max_output_tokens_cap: Some(100),
+            default_temperature: Some(0.1),
+            ..Default::default()
+        });
+
+        let mut request = test_request("gemini-1.5-flash");
+        request.max_tokens = Some(4096);
+        let config = client.convert_to_gemini_request(&request, None).unwrap().generation_config.unwrap();
+        assert_eq!(config.max_output_tokens, Some(4096));
+        assert_eq!(config.temperature, None);
+    }
+
+    #[test]
+    fn test_forced_safety_threshold() {
+        let client = client_with_override("gemini-2.0-*", ModelOverride {
+            force_safety_threshold: Some("BLOCK_MEDIUM_AND_ABOVE".to_string()),
+            ..Default::default()
+        });
+
+        let gemini_request = client.convert_to_gemini_request(&test_request("gemini-2.0-flash"), None).unwrap();
+        let safety = gemini_request.safety_settings.unwrap();
+        assert!(safety.iter().all(|s| s.threshold == "BLOCK_MEDIUM_AND_ABOVE"));
+
+        let gemini_request = client.convert_to_gemini_request(&test_request("gemini-1.5-flash"), None).unwrap();
+        let safety = gemini_request.safety_settings.unwrap();
+        assert!(safety.iter().all(|s| s.threshold == "BLOCK_NONE"));
+    }
+
+    #[test]
+    fn test_thinking_response_with_function_call() {
+        // Captured from gemini-2.5-flash with thinking and a tool declared
+        let gemini_response: GeminiResponse = serde_json::from_value(json!({
+            "candidates": [{
+                "content": {
+                    "parts": [
+                        {"text": "**Checking the weather**\n\nThe user wants the forecast.", "thought": true},
+                        {
+                            "functionCall": {"name": "get_weather", "args": {"city": "Paris"}},
+                            "thoughtSignature": "CiQB0e2Kb3Vm"
+                        }
+                    ],
+                    "role": "model"
+                },
+                "finishReason": "STOP",
+                "index": 0
+            }],
+            "usageMetadata": {"promptTokenCount": 41, "candidatesTokenCount": 15, "totalTokenCount": 120, "thoughtsTokenCount": 64},
+            "modelVersion": "gemini-2.5-flash"
+        }))
+        .unwrap();
+
+        let client = client_with_override("unused", ModelOverride::default());
+        let response = client.convert_gemini_response(gemini_response, &test_request("gemini-2.5-flash")).unwrap();
+        let tool_calls = response.choices[0].message.tool_calls.as_ref().unwrap();
+        assert_eq!(tool_calls[0].function.name, "get_weather");
+        assert_eq!(tool_calls[0].function.arguments, r#"{"city":"Paris"}"#);
+
+        // Thinking tokens count as completion tokens and are broken out as reasoning tokens
+        let usage = serde_json::to_value(response.usage.as_ref().unwrap()).unwrap();
+        assert_eq!(usage, json!({
+            "prompt_tokens": 41,
+            "completion_tokens": 79,
+            "total_tokens": 120,
+            "completion_tokens_details": {"reasoning_tokens": 64}
+        }));
+    }
+
+    #[test]
+    fn test_code_execution_and_inline_data_parts() {
+        // Captured from gemini-2.0-flash with code execution enabled
+        let content: GeminiContent = serde_json::from_value(json!({
+            "parts": [
+                {"text": "Let me compute that."},
+                {"executableCode": {"language": "PYTHON", "code": "print(2 ** 10)\n"}},
+                {"codeExecutionResult": {"outcome": "OUTCOME_OK", "output": "1024\n"}},
+                {"inlineData": {"mimeType": "image/png", "data": "iVBORw0KGgo="}},
+                {"videoMetadata": {"startOffset": "1s"}}
+            ],
+            "role": "model"
+        }))
+        .unwrap();
+
+        match &content.parts[3] {
+            GeminiPart::InlineData { inline_data } => assert_eq!(inline_data.mime_type, "image/png"),
+            other => panic!("expected inline data, got {:?}", other),
+        }
+        // Unknown parts are sent back unchanged
+        assert!(matches!(&content.parts[4], GeminiPart::Unknown(_)));
+        assert_eq!(serde_json::to_value(&content.parts[4]).unwrap()["videoMetadata"]["startOffset"], "1s");
+
+        let client = client_with_override("unused", ModelOverride::default());
+        let message = client.convert_gemini_content_to_message(content.clone(), false).unwrap();
+        assert_eq!(
+            message.content.unwrap(),
+            "Let me compute that.\n```python\nprint(2 ** 10)\n```\n\n```output\n1024\n```\n![image](data:image/png;base64,iVBORw0KGgo=)"
+        );
+        assert!(message.tool_calls.is_none());
+
+        let client = GeminiClient::new(Arc::new(Settings {
+            random_string: false,
+            code_execution_render: CodeExecutionRender::Omit,
+            ..Default::default()
+        }));
+        let message = client.convert_gemini_content_to_message(content, false).unwrap();
+        assert_eq!(message.content.unwrap(), "Let me compute that.![image](data:image/png;base64,iVBORw0KGgo=)");
+    }
+
+    #[test]
+    fn test_code_execution_tool_request() {
+        let mut request = test_request("gemini-2.0-flash");
+        request.tools = Some(serde_json::from_value(json!([
+            {"type": "function", "function": {"name": "code_execution"}},
+            {"type": "function", "function": {"name": "get_weather", "parameters": {"type": "object"}}}
+        ])).unwrap());
+
+        let client = client_with_override("unused", ModelOverride::default());
+        let gemini_request = client.convert_to_gemini_request(&request, None).unwrap();
+        let tools = serde_json::to_value(gemini_request.tools.unwrap()).unwrap();
+        assert_eq!(tools, json!([
+            {"function_declarations": [{"name": "get_weather", "description": "", "parameters": {"type": "object", "properties": {}}}]},
+            {"code_execution": {}}
+        ]));
+    }
+
+    #[test]
+    fn test_path_model() {
+        assert_eq!(path_model("models/gemini-2.5-pro:generateContent"), "gemini-2.5-pro");
+        assert_eq!(path_model("models/gemini-2.5-flash:streamGenerateContent?alt=sse"), "gemini-2.5-flash");
+    }
+
+    #[test]
+    fn test_generated_images() {
+        // Captured from gemini-2.0-flash-exp with responseModalities TEXT and IMAGE
+        let gemini_response = || -> GeminiResponse {
+            serde_json::from_value(json!({
+                "candidates": [{
+                    "content": {
+                        "parts": [
+                            {"text": "Here is a cat on the moon."},
+                            {"inlineData": {"mimeType": "image/png", "data": "iVBORw0KGgoAAAANSUhEUg=="}}
+                        ],
+                        "role": "model"
+                    },
+                    "finishReason": "STOP",
+                    "index": 0
+                }],
+                "modelVersion": "gemini-2.0-flash-exp"
+            }))
+            .unwrap()
+        };
+
+        let images = images_from_response(gemini_response()).unwrap();
+        assert_eq!(images.data.len(), 1);
+        assert_eq!(images.data[0].b64_json, "iVBORw0KGgoAAAANSUhEUg==");
+        assert_eq!(images.data[0].revised_prompt.as_deref(), Some("Here is a cat on the moon."));
+
+        let client = client_with_override("unused", ModelOverride::default());
+        let response = client.convert_gemini_response(gemini_response(), &test_request("gemini-2.0-flash-exp")).unwrap();
+        assert_eq!(
+            response.choices[0].message.content.as_ref().unwrap(),
+            "Here is a cat on the moon.![image](data:image/png;base64,iVBORw0KGgoAAAANSUhEUg==)"
+        );
+
+        let text_only: GeminiResponse = serde_json::from_value(json!({
+            "candidates": [{"content": {"parts": [{"text": "I can't draw that."}], "role": "model"}}]
+        }))
+        .unwrap();
+        assert!(images_from_response(text_only).is_err());
+
+        let request = serde_json::to_value(client.image_request(DEFAULT_IMAGE_MODEL, "a cat")).unwrap();
+        assert_eq!(request["generation_config"]["response_modalities"], json!(["TEXT", "IMAGE"]));
+        assert_eq!(request["contents"][0]["parts"][0]["text"], "a cat");
+    }
+
+    #[test]
+    fn test_array_content_responses_serialize_like_openai() {
+        let gemini_response = || -> GeminiResponse {
+            serde_json::from_value(json!({
+                "candidates": [{
+                    "content": {
+                        "parts": [
+                            {"text": "Here is a cat"},
+                            {"text": " on the moon."},
+                            {"inlineData": {"mimeType": "image/png", "data": "iVBORw0KGgo="}},
+                            {"text": "Want another?"}
+                        ],
+                        "role": "model"
+                    },
+                    "finishReason": "STOP"
+                }]
+            }))
+            .unwrap()
+        };
+        let client = client_with_override("unused", ModelOverride::default());
+        // Serialized as sent, then read back the way an OpenAI SDK client would
+        let answer = |request: &ChatCompletionRequest| {
+            let response = client.convert_gemini_response(gemini_response(), request).unwrap();
+            let json = serde_json::to_value(&response).unwrap();
+            let parsed: ChatCompletionResponse = serde_json::from_value(json.clone()).unwrap();
+            assert_eq!(serde_json::to_value(parsed).unwrap(), json);
+            json["choices"][0]["message"].clone()
+        };
+
+        let mut request = test_request("gemini-2.0-flash-exp");
+        let message = answer(&request);
+        assert_eq!(message["role"], "assistant");
+        assert_eq!(message["content"], "Here is a cat on the moon.![image](data:image/png;base64,iVBORw0KGgo=)Want another?");
+
+        request.array_content = Some(true);
+        let message = answer(&request);
+        assert_eq!(
+            message["content"],
+            json!([
+                {"type": "text", "text": "Here is a cat on the moon."},
+                {"type": "image_url", "image_url": {"url": "data:image/png;base64,iVBORw0KGgo="}},
+                {"type": "text", "text": "Want another?"}
+            ])
+        );
+        // The parts read back as request content too, so the answer can be sent as history
+        let history: ChatMessage = serde_json::from_value(message).unwrap();
+        let parts = client.convert_message_content(&history.content).unwrap();
+        assert_eq!(parts.len(), 3);
+
+        // The setting is the default a request can override; streams keep text deltas
+        let client = GeminiClient::new(Arc::new(Settings { array_content_responses: true, ..Default::default() }));
+        request.array_content = None;
+        let response = client.convert_gemini_response(gemini_response(), &request).unwrap();
+        assert!(response.choices[0].message.content.as_ref().unwrap().is_array());
+        request.array_content = Some(false);
+        let response = client.convert_gemini_response(gemini_response(), &request).unwrap();
+        assert!(response.choices[0].message.content.as_ref().unwrap().is_string());
+        request.array_content = None;
+        request.stream = true;
+        assert!(!request.wants_array_content(true));
+    }
+
+    #[test]
+    fn test_cached_system_prompt_left_out_of_contents() {
+        let request: ChatCompletionRequest = serde_json::from_value(json!({
+            "model": "gemini-2.0-flash",
+            "messages": [
+                {"role": "system", "content": "You are a careful reviewer."},
+                {"role": "system", "content": [{"type": "text", "text": "Answer briefly."}]},
+                {"role": "user", "content": "hello"}
+            ]
+        }))
+        .unwrap();
+        assert_eq!(system_prompt(&request).as_deref(), Some("You are a careful reviewer.\n\nAnswer briefly."));
+        assert!(system_prompt(&test_request("gemini-2.0-flash")).is_none());
+
+        let client = client_with_override("unused", ModelOverride::default());
+        let inline = client.convert_to_gemini_request(&request, None).unwrap();
+        assert_eq!(inline.contents.len(), 3);
+        assert!(!serde_json::to_string(&inline).unwrap().contains("cached_content"));
+
+        let cached = client.convert_to_gemini_request(&request, Some("cachedContents/abc".to_string())).unwrap();
+        assert_eq!(cached.contents.len(), 1);
+        assert_eq!(serde_json::to_value(&cached).unwrap()["cached_content"], "cachedContents/abc");
+    }
+
+    #[test]
+    fn test_model_suffixes_become_request_options() {
+        let mut settings = Settings { random_string: false, ..Default::default() };
+        settings.search.search_mode = true;
+        let client = GeminiClient::new(Arc::new(settings));
+        let convert = |model: &str, extra: Value| {
+            let mut request = test_request(model);
+            request.extra = serde_json::from_value(extra).unwrap();
+            serde_json::to_value(client.convert_to_gemini_request(&request, None).unwrap()).unwrap()
+        };
+
+        let plain = convert("gemini-2.5-flash", json!({}));
+        assert!(plain["tools"].is_null());
+        assert!(plain["generation_config"]["thinking_config"].is_null());
+
+        let search = convert("gemini-2.5-flash-search", json!({}));
+        assert_eq!(search["tools"], json!([{"google_search": {}}]));
+
+        let both = convert("gemini-2.5-flash-search-nothinking", json!({}));
+        assert_eq!(both["tools"], json!([{"google_search": {}}]));
+        assert_eq!(both["generation_config"]["thinking_config"], json!({"thinking_budget": 0}));
+
+        // The suffix wins over the client's budget but keeps its other thinking options
+        let thinking = convert("gemini-2.5-pro-thinking", json!({"thinking_config": {"thinking_budget": 128, "include_thoughts": true}}));
+        assert_eq!(thinking["generation_config"]["thinking_config"], json!({"thinking_budget": -1, "include_thoughts": true}));
+        assert!(thinking["tools"].is_null());
+
+        // Without search mode the suffix is only stripped
+        let client = client_with_override("unused", ModelOverride::default());
+        let request = test_request("gemini-2.5-flash-search");
+        assert!(client.convert_to_gemini_request(&request, None).unwrap().tools.is_none());
+    }
+
+    #[test]
+    fn test_extra_params_mapped_or_dropped() {
+        let request: ChatCompletionRequest = serde_json::from_value(json!({
+            "model": "gemini-2.5-flash",
+            "messages": [{"role": "user", "content": "hello"}],
+            "top_k": 40,
+            "thinking_config": {"thinking_budget": 1024},
+            "stop": ["END", "STOP"],
+            "safety_settings": [
+                {"category": "HARM_CATEGORY_HARASSMENT", "threshold": "BLOCK_ONLY_HIGH"}
+            ],
+            "parallel_tool_calls": false,
+            "logprobs": true
+        }))
+        .unwrap();
+        assert_eq!(dropped_params(&request), vec!["logprobs", "parallel_tool_calls"]);
+
+        let client = client_with_override("unused", ModelOverride::default());
+        let gemini_request = client.convert_to_gemini_request(&request, None).unwrap();
+        let config = gemini_request.generation_config.unwrap();
+        assert_eq!(config.top_k, Some(40));
+        assert_eq!(config.thinking_config, Some(json!({"thinking_budget": 1024})));
+        assert_eq!(config.stop_sequences, Some(vec!["END".to_string(), "STOP".to_string()]));
+        let safety = gemini_request.safety_settings.unwrap();
+        let harassment = safety.iter().find(|s| s.category == "HARM_CATEGORY_HARASSMENT").unwrap();
+        assert_eq!(harassment.threshold, "BLOCK_ONLY_HIGH");
+        assert!(safety.iter().filter(|s| s.category != "HARM_CATEGORY_HARASSMENT").all(|s| s.threshold == "BLOCK_NONE"));
+
+        // A forced threshold is operator policy and wins over the client
+        let client = client_with_override("gemini-2.5-*", ModelOverride {
+            force_safety_threshold: Some("BLOCK_MEDIUM_AND_ABOVE".to_string()),
+            ..Default::default()
+        });
+        let safety = client.convert_to_gemini_request(&request, None).unwrap().safety_settings.unwrap();
+        assert!(safety.iter().all(|s| s.threshold == "BLOCK_MEDIUM_AND_ABOVE"));
+    }
+
+    fn random_string_client() -> GeminiClient {
+        GeminiClient::new(Arc::new(Settings {
+            random_string: true,
+            random_string_length: 8,
+            ..Default::default()
+        }))
+    }
+
+    fn conversation(extra: Value) -> ChatCompletionRequest {
+        let mut request = json!({
+            "model": "gemini-2.0-flash",
+            "messages": [
+                {"role": "system", "content": "Be terse."},
+                {"role": "user", "content": "first question"},
+                {"role": "assistant", "content": "first answer"},
+                {"role": "user", "content": "second question"}
+            ]
+        });
+        request.as_object_mut().unwrap().extend(extra.as_object().unwrap().clone());
+        serde_json::from_value(request).unwrap()
+    }
+
+    fn content_texts(request: &GeminiRequest) -> Vec<String> {
+        request.contents
+            .iter()
+            .map(|content| match &content.parts[0] {
+                GeminiPart::Text { text } => text.clone(),
+                other => panic!("expected text, got {:?}", other),
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_random_marker_appended_to_last_user_message() {
+        let client = random_string_client();
+        let texts = content_texts(&client.convert_to_gemini_request(&conversation(json!({})), None).unwrap());
+        assert_eq!(&texts[..3], ["Be terse.", "first question", "first answer"]);
+
+        let marker = texts[3].strip_prefix("second question\n\n<!-- ").unwrap();
+        let marker = marker.strip_suffix(" -->").unwrap();
+        assert_eq!(marker.len(), 8);
+        assert!(marker.chars().all(|c| c.is_ascii_alphanumeric()));
+    }
+
+    #[tokio::test]
+    async fn test_context_trimmed_to_the_model_window() {
+        let client = |strategy| GeminiClient::new(Arc::new(Settings { context_trim_strategy: strategy, random_string: false, ..Default::default() }));
+        let mut request = conversation(json!({"max_tokens": 100}));
+        let total = prompt_tokens(&request.messages);
+        let model = Model { context_length: Some(total + 99), ..Model::named("gemini-2.0-flash", 0) };
+
+        let trimming = client(ContextTrimStrategy::DropOldest);
+        *trimming.available_models.write().await = vec![model.clone()];
+        request.context_trimmed = trimming.context_trim(&request).await.unwrap();
+        assert_eq!(request.context_trimmed, 1);
+        let texts = content_texts(&trimming.convert_to_gemini_request(&request, None).unwrap());
+        assert_eq!(texts, ["Be terse.", "first answer", "second question"]);
+
+        let refusing = client(ContextTrimStrategy::Error);
+        *refusing.available_models.write().await = vec![model];
+        assert!(matches!(refusing.context_trim(&request).await, Err(GeminiError::InvalidRequest { .. })));
+        request.max_tokens = Some(99);
+        assert_eq!(refusing.context_trim(&request).await.unwrap(), 0);
+
+        // Nothing is known about the window of a model missing from the catalog
+        assert_eq!(client(ContextTrimStrategy::Error).context_trim(&request).await.unwrap(), 0);
+    }
+
+    #[test]
+    fn test_random_marker_skipped_for_json_and_tools() {
+        let client = random_string_client();
+        let plain = |request: &ChatCompletionRequest| content_texts(&client.convert_to_gemini_request(request, None).unwrap())[3] == "second question";
+
+        assert!(plain(&conversation(json!({"response_format": {"type": "json_object"}}))));
+        assert!(plain(&conversation(json!({"response_format": {"type": "json_schema", "json_schema": {"name": "x"}}}))));
+        assert!(plain(&conversation(json!({
+            "tools": [{"type": "function", "function": {"name": "get_weather"}}]
+        }))));
+        assert!(!plain(&conversation(json!({"response_format": {"type": "text"}}))));
+    }
+
+    #[test]
+    fn test_random_marker_not_added_twice() {
+        let client = random_string_client();
+        let mut request = conversation(json!({}));
+        request.messages[3].content = Some(json!("second question\n\n<!-- abc123 -->"));
+
+        let texts = content_texts(&client.convert_to_gemini_request(&request, None).unwrap());
+        assert_eq!(texts[3], "second question\n\n<!-- abc123 -->");
+    }
+
+    #[test]
+    fn test_end_user_note_only_when_enabled() {
+        let client = |end_user_note| GeminiClient::new(Arc::new(Settings { random_string: false, end_user_note, ..Default::default() }));
+        let request = conversation(json!({"user": "alice"}));
+        let texts = content_texts(&client(false).convert_to_gemini_request(&request, None).unwrap());
+        assert_eq!(texts[3], "second question");
+
+        let client = client(true);
+        let texts = content_texts(&client.convert_to_gemini_request(&request, None).unwrap());
+        assert_eq!(texts[3], format!("second question\n\n<!-- end-user: {} -->", end_user_id("alice")));
+        assert!(dropped_params(&request).is_empty());
+    }
+
+    #[test]
+    fn test_safety_override() {
+        let client = client_with_override("unused", ModelOverride::default());
+        let mut request = test_request("gemini-2.0-flash");
+
+        request.overrides.safety = Some(SafetyOverride::Off);
+        let safety = client.convert_to_gemini_request(&request, None).unwrap().safety_settings.unwrap();
+        assert!(safety.iter().all(|s| s.threshold == "OFF"));
+
+        request.overrides.safety = Some(SafetyOverride::Default);
+        assert!(client.convert_to_gemini_request(&request, None).unwrap().safety_settings.is_none());
+
+        let client = client_with_override("gemini-2.0-*", ModelOverride {
+            force_safety_threshold: Some("BLOCK_MEDIUM_AND_ABOVE".to_string()),
+            ..Default::default()
+        });
+        let safety = client.convert_to_gemini_request(&request, None).unwrap().safety_settings.unwrap();
+        assert!(safety.iter().all(|s| s.threshold == "BLOCK_MEDIUM_AND_ABOVE"));
+    }
+}
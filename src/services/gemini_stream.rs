@@ -0,0 +1,587 @@
+use serde_json::{json, Value};
+
+use crate::config::CodeExecutionRender;
+use crate::models::schemas::{
+    ChatChoiceDelta, ChatCompletionChunk, ChatMessageDelta, FunctionCallDelta, GeminiInlineData, GeminiPart, GeminiResponse,
+    ToolCallDelta, Usage,
+};
+
+/// Incremental parser for `streamGenerateContent?alt=sse` bodies. Bytes may arrive split at
+/// any position, so incomplete lines are buffered until their newline shows up.
+#[derive(Debug, Default)]
+pub struct SseEventParser {
+    buffer: Vec<u8>,
+    data: String,
+}
+
+impl SseEventParser {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Feed raw bytes and return the `data` payloads of every event completed by them
+    pub fn push(&mut self, bytes: &[u8]) -> Vec<String> {
+        self.buffer.extend_from_slice(bytes);
+
+        let mut events = Vec::new();
+        while let Some(pos) = self.buffer.iter().position(|&b| b == b'\n') {
+            let line: Vec<u8> = self.buffer.drain(..=pos).collect();
+            let line = String::from_utf8_lossy(&line);
+            let line = line.trim_end_matches(['\r', '\n']);
+
+            if line.is_empty() {
+                if !self.data.is_empty() {
+                    events.push(std::mem::take(&mut self.data));
+                }
+            } else if let Some(value) = line.strip_prefix("data:") {
+                if !self.data.is_empty() {
+                    self.data.push('\n');
+                }
+                self.data.push_str(value.strip_prefix(' ').unwrap_or(value));
+            }
+        }
+
+        events
+    }
+
+    /// Flush an event left unterminated when the upstream closes the connection
+    pub fn finish(&mut self) -> Option<String> {
+        let rest = std::mem::take(&mut self.buffer);
+        let line = String::from_utf8_lossy(&rest);
+        if let Some(value) = line.trim_end_matches('\r').strip_prefix("data:") {
+            if !self.data.is_empty() {
+                self.data.push('\n');
+            }
+            self.data.push_str(value.strip_prefix(' ').unwrap_or(value));
+        }
+
+        if self.data.is_empty() {
+            None
+        } else {
+            Some(std::mem::take(&mut self.data))
+        }
+    }
+}
+
+/// Incremental parser for `streamGenerateContent` bodies requested without `alt=sse`: a single
+/// JSON array whose elements arrive over time. Each top-level object is returned once its
+/// closing brace arrives; brackets and braces inside strings are skipped.
+#[derive(Debug, Default)]
+pub struct JsonArrayParser {
+    /// Bytes of the object being read
+    element: Vec<u8>,
+    depth: usize,
+    in_string: bool,
+    escaped: bool,
+}
+
+impl JsonArrayParser {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Feed raw bytes and return every object completed by them
+    pub fn push(&mut self, bytes: &[u8]) -> Vec<String> {
+        let mut objects = Vec::new();
+        for &byte in bytes {
+            if self.depth == 0 {
+                // Between elements: the array brackets, commas and whitespace
+                if byte == b'{' {
+                    self.depth = 1;
+                    self.element.push(byte);
+                }
+                continue;
+            }
+
+            self.element.push(byte);
+            if self.in_string {
+                if self.escaped {
+                    self.escaped = false;
+                } else if byte == b'\\' {
+                    self.escaped = true;
+                } else if byte == b'"' {
+                    self.in_string = false;
+                }
+                continue;
+            }
+            match byte {
+                b'"' => self.in_string = true,
+                b'{' | b'[' => self.depth += 1,
+                b'}' | b']' => {
+                    self.depth -= 1;
+                    if self.depth == 0 {
+                        let element = std::mem::take(&mut self.element);
+                        objects.push(String::from_utf8_lossy(&element).into_owned());
+                    }
+                }
+                _ => {}
+            }
+        }
+        objects
+    }
+
+    /// An object cut off when the upstream closed the connection, passed on so it is
+    /// reported as unparseable rather than dropped silently
+    pub fn finish(&mut self) -> Option<String> {
+        self.depth = 0;
+        self.in_string = false;
+        self.escaped = false;
+        let element = std::mem::take(&mut self.element);
+        (!element.is_empty()).then(|| String::from_utf8_lossy(&element).into_owned())
+    }
+}
+
+/// Either body form of `streamGenerateContent`, told apart by the first non-blank byte: `[`
+/// opens a JSON array, anything else is read as SSE. Relays may drop `alt=sse` or rewrite
+/// the content type, so the body is what counts.
+#[derive(Debug, Default)]
+pub enum StreamEventParser {
+    /// Nothing but whitespace has arrived yet
+    #[default]
+    Detecting,
+    Sse(SseEventParser),
+    JsonArray(JsonArrayParser),
+}
+
+impl StreamEventParser {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Feed raw bytes and return the JSON payload of every event completed by them
+    pub fn push(&mut self, bytes: &[u8]) -> Vec<String> {
+        match self {
+            Self::Sse(parser) => parser.push(bytes),
+            Self::JsonArray(parser) => parser.push(bytes),
+            // Leading blank lines mean nothing in either form
+            Self::Detecting => match bytes.iter().find(|byte| !byte.is_ascii_whitespace()) {
+                Some(b'[') => {
+                    *self = Self::JsonArray(JsonArrayParser::new());
+                    self.push(bytes)
+                }
+                Some(_) => {
+                    *self = Self::Sse(SseEventParser::new());
+                    self.push(bytes)
+                }
+                None => Vec::new(),
+            },
+        }
+    }
+
+    pub fn finish(&mut self) -> Option<String> {
+        match self {
+            Self::Sse(parser) => parser.finish(),
+            Self::JsonArray(parser) => parser.finish(),
+            Self::Detecting => None,
+        }
+    }
+}
+
+/// Converts streamed Gemini responses into OpenAI `chat.completion.chunk`s, keeping the
+/// state needed across events: one completion id, the role announcement and tool call indices.
+#[derive(Debug)]
+pub struct GeminiStreamConverter {
+    id: String,
+    model: String,
+    created: u64,
+    role_sent: bool,
+    next_tool_index: u32,
+    usage: Option<Usage>,
+    code_execution_render: CodeExecutionRender,
+}
+
+impl GeminiStreamConverter {
+    pub fn new(model: &str) -> Self {
+        Self {
+            id: format!("chatcmpl-{}", uuid::Uuid::new_v4()),
+            model: model.to_string(),
+            created: chrono::Utc::now().timestamp() as u64,
+            role_sent: false,
+            next_tool_index: 0,
+            usage: None,
+            code_execution_render: CodeExecutionRender::default(),
+        }
+    }
+
+    pub fn with_code_execution_render(mut self, render: CodeExecutionRender) -> Self {
+        self.code_execution_render = render;
+        self
+    }
+
+    /// The trailing `stream_options.include_usage` chunk: no choices, usage from the last
+    /// `usageMetadata` Gemini sent (zeros if it never did)
+    pub fn usage_chunk(&self) -> ChatCompletionChunk {
+        let usage = self.usage.clone().unwrap_or(Usage::new(0, 0));
+        usage_chunk(&self.id, &self.model, self.created, usage)
+    }
+
+    /// Convert one streamed Gemini event into zero or more chunks
+    pub fn convert(&mut self, response: GeminiResponse) -> Vec<ChatCompletionChunk> {
+        let mut chunks = Vec::new();
+
+        // Gemini reports cumulative counts, so the latest event wins
+        if let Some(meta) = &response.usage_metadata {
+            self.usage = Some(Usage::from_gemini(meta));
+        }
+
+        for candidate in response.candidates {
+            let choice_index = candidate.index.unwrap_or(0);
+
+            for part in candidate.content.parts {
+                match part {
+                    GeminiPart::Text { text } if !text.is_empty() => {
+                        let delta = self.delta(Some(text), None);
+                        chunks.push(self.chunk(choice_index, delta, None));
+                    }
+                    GeminiPart::InlineData { inline_data } => {
+                        let mut text = String::new();
+                        if push_inline_image(&mut text, &inline_data) {
+                            let delta = self.delta(Some(text), None);
+                            chunks.push(self.chunk(choice_index, delta, None));
+                        }
+                    }
+                    part @ (GeminiPart::ExecutableCode { .. } | GeminiPart::CodeExecutionResult { .. }) => {
+                        if let Some(text) = render_code_execution_part(&part, self.code_execution_render) {
+                            let delta = self.delta(Some(text), None);
+                            chunks.push(self.chunk(choice_index, delta, None));
+                        }
+                    }
+                    GeminiPart::FunctionCall { function_call } => {
+                        let index = self.next_tool_index;
+                        self.next_tool_index += 1;
+
+                        // First fragment carries the id and name, later fragments only arguments
+                        let start = ToolCallDelta {
+                            index,
+                            id: Some(format!("call_{}", uuid::Uuid::new_v4())),
+                            tool_type: Some("function".to_string()),
+                            function: Some(FunctionCallDelta {
+                                name: Some(function_call.name),
+                                arguments: Some(String::new()),
+                            }),
+                        };
+                        let delta = self.delta(None, Some(start));
+                        chunks.push(self.chunk(choice_index, delta, None));
+
+                        let arguments = ToolCallDelta {
+                            index,
+                            id: None,
+                            tool_type: None,
+                            function: Some(FunctionCallDelta {
+                                name: None,
+                                arguments: Some(function_call.args.to_string()),
+                            }),
+                        };
+                        let delta = self.delta(None, Some(arguments));
+                        chunks.push(self.chunk(choice_index, delta, None));
+                    }
+                    _ => {}
+                }
+            }
+
+            if let Some(reason) = candidate.finish_reason {
+                let finish_reason = if self.next_tool_index > 0 {
+                    "tool_calls".to_string()
+                } else {
+                    map_finish_reason(&reason)
+                };
+                let delta = self.delta(None, None);
+                chunks.push(self.chunk(choice_index, delta, Some(finish_reason)));
+            }
+        }
+
+        chunks
+    }
+
+    fn delta(&mut self, content: Option<String>, tool_call: Option<ToolCallDelta>) -> ChatMessageDelta {
+        let role = if self.role_sent {
+            None
+        } else {
+            self.role_sent = true;
+            Some("assistant".to_string())
+        };
+
+        ChatMessageDelta {
+            role,
+            content,
+            tool_calls: tool_call.map(|call| vec![call]),
+        }
+    }
+
+    fn chunk(&self, index: u32, delta: ChatMessageDelta, finish_reason: Option<String>) -> ChatCompletionChunk {
+        ChatCompletionChunk {
+            id: self.id.clone(),
+            object: "chat.completion.chunk".to_string(),
+            created: self.created,
+            model: self.model.clone(),
+            choices: vec![ChatChoiceDelta {
+                index,
+                delta,
+                finish_reason,
+                logprobs: None,
+            }],
+            system_fingerprint: None,
+            usage: None,
+        }
+    }
+}
+
+/// Append an inline image as a markdown data URL; other inline data is skipped. Returns
+/// whether anything was written.
+pub fn push_inline_image(content: &mut String, inline_data: &GeminiInlineData) -> bool {
+    if !inline_data.mime_type.starts_with("image/") {
+        return false;
+    }
+    content.reserve(inline_data.data.len() + inline_data.mime_type.len() + 24);
+    content.push_str("![image](data:");
+    content.push_str(&inline_data.mime_type);
+    content.push_str(";base64,");
+    content.push_str(&inline_data.data);
+    content.push(')');
+    true
+}
+
+/// An inline image as an OpenAI `image_url` content part with a data URL; `None` for other
+/// inline data
+pub fn inline_image_part(inline_data: &GeminiInlineData) -> Option<Value> {
+    if !inline_data.mime_type.starts_with("image/") {
+        return None;
+    }
+    let url = format!("data:{};base64,{}", inline_data.mime_type, inline_data.data);
+    Some(json!({"type": "image_url", "image_url": {"url": url}}))
+}
+
+/// Close the text `text` has gathered as a `text` content part; nothing when it is empty
+pub fn push_text_part(parts: &mut Vec<Value>, text: &mut String) {
+    if !text.is_empty() {
+        parts.push(json!({"type": "text", "text": std::mem::take(text)}));
+    }
+}
+
+/// Message content for a code execution part: the code as a fenced block in its language,
+/// the result as an `output` block. `None` for other parts or when rendering is off.
+pub fn render_code_execution_part(part: &GeminiPart, render: CodeExecutionRender) -> Option<String> {
+    if render == CodeExecutionRender::Omit {
+        return None;
+    }
+    match part {
+        GeminiPart::ExecutableCode { executable_code } => Some(format!(
+            "\n```{}\n{}\n```\n",
+            executable_code.language.to_ascii_lowercase(),
+            executable_code.code.trim_end()
+        )),
+        GeminiPart::CodeExecutionResult { code_execution_result } => {
+            let output = code_execution_result.output.as_deref().unwrap_or_default().trim_end();
+            Some(format!("\n```output\n{}\n```\n", output))
+        }
+        _ => None,
+    }
+}
+
+/// Build a usage-only chunk with an empty `choices` array
+pub fn usage_chunk(id: &str, model: &str, created: u64, usage: Usage) -> ChatCompletionChunk {
+    ChatCompletionChunk {
+        id: id.to_string(),
+        object: "chat.completion.chunk".to_string(),
+        created,
+        model: model.to_string(),
+        choices: Vec::new(),
+        system_fingerprint: None,
+        usage: Some(usage),
+    }
+}
+
+/// Map a Gemini finish reason to its OpenAI equivalent
+fn map_finish_reason(reason: &str) -> String {
+    match reason {
+        "MAX_TOKENS" => "length",
+        "SAFETY" | "RECITATION" | "BLOCKLIST" | "PROHIBITED_CONTENT" | "SPII" => "content_filter",
+        _ => "stop",
+    }
+    .to_string()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn collect_chunks(body: &str, split_at: usize) -> Vec<ChatCompletionChunk> {
+        let mut parser = SseEventParser::new();
+        let mut converter = GeminiStreamConverter::new("gemini-2.0-flash");
+        let (head, tail) = body.as_bytes().split_at(split_at);
+
+        let mut events = parser.push(head);
+        events.extend(parser.push(tail));
+        events.extend(parser.finish());
+
+        events
+            .iter()
+            .flat_map(|data| converter.convert(serde_json::from_str(data).unwrap()))
+            .collect()
+    }
+
+    #[test]
+    fn test_sse_parser_handles_split_events() {
+        let mut parser = SseEventParser::new();
+        assert!(parser.push(b"data: {\"a\":").is_empty());
+        assert_eq!(parser.push(b"1}\r\n\r\ndata: {\"b\":2}\n\n"), vec!["{\"a\":1}", "{\"b\":2}"]);
+        assert!(parser.push(b"data: {\"c\":3}").is_empty());
+        assert_eq!(parser.finish().as_deref(), Some("{\"c\":3}"));
+    }
+
+    /// Objects containing every character the depth tracking has to see past
+    const ARRAY_BODY: &str = concat!(
+        "[{\"candidates\":[{\"content\":{\"role\":\"model\",\"parts\":[{\"text\":\"a } ] { [ \\\" \\\\\"}]}}]}\n",
+        ",\r\n{\"candidates\":[{\"content\":{\"role\":\"model\",\"parts\":[{\"text\":\"héllo 世界 ✓\"}]},",
+        "\"finishReason\":\"STOP\"}]}\n]",
+    );
+
+    fn array_texts(events: &[String]) -> Vec<String> {
+        let mut converter = GeminiStreamConverter::new("gemini-2.0-flash");
+        events
+            .iter()
+            .flat_map(|event| converter.convert(serde_json::from_str(event).unwrap()))
+            .filter_map(|chunk| chunk.choices[0].delta.content.clone())
+            .filter(|text| !text.is_empty())
+            .collect()
+    }
+
+    #[test]
+    fn test_json_array_split_at_every_byte() {
+        let expected = ["a } ] { [ \" \\", "héllo 世界 ✓"];
+        let bytes = ARRAY_BODY.as_bytes();
+
+        for split in 0..=bytes.len() {
+            let mut parser = StreamEventParser::new();
+            let mut events = parser.push(&bytes[..split]);
+            events.extend(parser.push(&bytes[split..]));
+            events.extend(parser.finish());
+            assert_eq!(array_texts(&events), expected, "split at {}", split);
+        }
+
+        let mut parser = StreamEventParser::new();
+        let mut events: Vec<String> = bytes.iter().flat_map(|byte| parser.push(std::slice::from_ref(byte))).collect();
+        events.extend(parser.finish());
+        assert_eq!(array_texts(&events), expected);
+        assert!(matches!(parser, StreamEventParser::JsonArray(_)));
+    }
+
+    #[test]
+    fn test_stream_format_detection() {
+        let mut parser = StreamEventParser::new();
+        assert!(parser.push(b"\r\n").is_empty());
+        assert_eq!(parser.push(b"data: {\"a\":[1]}\n\n"), vec!["{\"a\":[1]}"]);
+        assert!(matches!(parser, StreamEventParser::Sse(_)));
+
+        // A truncated array element is handed on to be reported, not dropped
+        let mut parser = StreamEventParser::new();
+        assert_eq!(parser.push(b"  [{\"a\":1},{\"b\""), vec!["{\"a\":1}"]);
+        assert_eq!(parser.finish().as_deref(), Some("{\"b\""));
+        assert_eq!(StreamEventParser::new().finish(), None);
+    }
+
+    #[test]
+    fn test_two_streamed_function_calls() {
+        let body = concat!(
+            "data: {\"candidates\":[{\"content\":{\"role\":\"model\",\"parts\":[",
+            "{\"function_call\":{\"name\":\"get_weather\",\"args\":{\"city\":\"Paris\"}}}]},\"index\":0}]}\r\n\r\n",
+            "data: {\"candidates\":[{\"content\":{\"role\":\"model\",\"parts\":[",
+            "{\"function_call\":{\"name\":\"get_time\",\"args\":{\"tz\":\"CET\"}}}]},",
+            "\"finish_reason\":\"STOP\",\"index\":0}]}\r\n\r\n",
+        );
+        let chunks = collect_chunks(body, 37);
+
+        let tool_deltas: Vec<&ToolCallDelta> = chunks
+            .iter()
+            .filter_map(|chunk| chunk.choices[0].delta.tool_calls.as_ref())
+            .flatten()
+            .collect();
+        assert_eq!(tool_deltas.len(), 4);
+
+        // Each call opens with id, type and name, then streams its arguments
+        let first = &tool_deltas[0];
+        assert_eq!(first.index, 0);
+        assert!(first.id.as_ref().unwrap().starts_with("call_"));
+        assert_eq!(first.tool_type.as_deref(), Some("function"));
+        assert_eq!(first.function.as_ref().unwrap().name.as_deref(), Some("get_weather"));
+        assert_eq!(tool_deltas[1].index, 0);
+        assert!(tool_deltas[1].id.is_none());
+        assert_eq!(tool_deltas[1].function.as_ref().unwrap().arguments.as_deref(), Some("{\"city\":\"Paris\"}"));
+
+        let second = &tool_deltas[2];
+        assert_eq!(second.index, 1);
+        assert_ne!(second.id, first.id);
+        assert_eq!(second.function.as_ref().unwrap().name.as_deref(), Some("get_time"));
+        assert_eq!(tool_deltas[3].index, 1);
+
+        // Role is announced once and every chunk shares the completion id
+        assert_eq!(chunks.iter().filter(|c| c.choices[0].delta.role.is_some()).count(), 1);
+        assert!(chunks.iter().all(|c| c.id == chunks[0].id));
+
+        let last = chunks.last().unwrap();
+        assert_eq!(last.choices[0].finish_reason.as_deref(), Some("tool_calls"));
+        assert!(last.choices[0].delta.tool_calls.is_none());
+    }
+
+    #[test]
+    fn test_text_stream_finish_reason() {
+        let body = concat!(
+            "data: {\"candidates\":[{\"content\":{\"role\":\"model\",\"parts\":[{\"text\":\"Hel\"}]}}]}\n\n",
+            "data: {\"candidates\":[{\"content\":{\"role\":\"model\",\"parts\":[{\"text\":\"lo\"}]},",
+            "\"finish_reason\":\"MAX_TOKENS\"}]}\n\n",
+        );
+        let chunks = collect_chunks(body, 10);
+
+        let text: String = chunks.iter().filter_map(|c| c.choices[0].delta.content.clone()).collect();
+        assert_eq!(text, "Hello");
+        assert_eq!(chunks.last().unwrap().choices[0].finish_reason.as_deref(), Some("length"));
+    }
+
+    #[test]
+    fn test_usage_chunk_from_final_event() {
+        let body = concat!(
+            "data: {\"candidates\":[{\"content\":{\"role\":\"model\",\"parts\":[{\"text\":\"Hi\"}]}}],",
+            "\"usageMetadata\":{\"promptTokenCount\":5,\"candidatesTokenCount\":1,\"totalTokenCount\":6}}\n\n",
+            "data: {\"candidates\":[{\"content\":{\"role\":\"model\",\"parts\":[{\"text\":\"!\"}]},",
+            "\"finishReason\":\"STOP\"}],",
+            "\"usageMetadata\":{\"promptTokenCount\":5,\"candidatesTokenCount\":2,\"totalTokenCount\":7}}\n\n",
+        );
+        let mut parser = SseEventParser::new();
+        let mut converter = GeminiStreamConverter::new("gemini-2.0-flash");
+        let chunks: Vec<ChatCompletionChunk> = parser
+            .push(body.as_bytes())
+            .iter()
+            .flat_map(|data| converter.convert(serde_json::from_str(data).unwrap()))
+            .collect();
+
+        // Content chunks never carry usage, so their JSON is unchanged
+        assert!(chunks.iter().all(|c| c.usage.is_none()));
+        assert!(!serde_json::to_string(&chunks[0]).unwrap().contains("usage"));
+        assert_eq!(chunks.last().unwrap().choices[0].finish_reason.as_deref(), Some("stop"));
+
+        let usage_chunk = converter.usage_chunk();
+        assert!(usage_chunk.choices.is_empty());
+        assert_eq!(usage_chunk.id, chunks[0].id);
+        let usage = usage_chunk.usage.unwrap();
+        assert_eq!((usage.prompt_tokens, usage.completion_tokens, usage.total_tokens), (5, 2, 7));
+    }
+
+    #[test]
+    fn test_code_execution_parts_stream_as_content() {
+        let body = concat!(
+            "data: {\"candidates\":[{\"content\":{\"role\":\"model\",\"parts\":[",
+            "{\"executableCode\":{\"language\":\"PYTHON\",\"code\":\"print(1 + 1)\"}}]}}]}\n\n",
+            "data: {\"candidates\":[{\"content\":{\"role\":\"model\",\"parts\":[",
+            "{\"codeExecutionResult\":{\"outcome\":\"OUTCOME_OK\",\"output\":\"2\\n\"}},",
+            "{\"text\":\"The sum is 2.\"}]},\"finishReason\":\"STOP\"}]}\n\n",
+        );
+        let chunks = collect_chunks(body, 40);
+
+        let text: String = chunks.iter().filter_map(|c| c.choices[0].delta.content.clone()).collect();
+        assert_eq!(text, "\n```python\nprint(1 + 1)\n```\n\n```output\n2\n```\nThe sum is 2.");
+        assert_eq!(chunks.last().unwrap().choices[0].finish_reason.as_deref(), Some("stop"));
+
+        let part: GeminiPart = serde_json::from_str(r#"{"executableCode":{"language":"PYTHON","code":"x"}}"#).unwrap();
+        assert!(render_code_execution_part(&part, CodeExecutionRender::Omit).is_none());
+    }
+}
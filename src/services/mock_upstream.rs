@@ -0,0 +1,216 @@
+//! Canned upstream for development and CI, used when `mock_upstream` (`RUJIMI_MOCK=1`) is on.
+//! It answers `GeminiClientTrait` calls without a network or real keys: the reply echoes the
+//! last user message, usage is estimated from the text, and `mock_latency_ms` and
+//! `mock_fail_rate` slow every call down or fail a share of them.
+
+use async_trait::async_trait;
+use futures_util::stream;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::Duration;
+
+use crate::config::Settings;
+use crate::models::schemas::{
+    ChatChoice, ChatChoiceDelta, ChatCompletionChunk, ChatCompletionRequest, ChatCompletionResponse, ChatMessage,
+    ChatMessageDelta, EmbeddingData, EmbeddingInput, EmbeddingRequest, EmbeddingResponse, EmbeddingUsage, Model, Usage,
+};
+use crate::services::gemini::{default_models, ChatCompletionStream, GeminiClientTrait};
+use crate::services::gemini_stream::usage_chunk;
+use crate::utils::error_handling::GeminiError;
+use crate::utils::response::extract_text_from_value;
+use crate::utils::tokens::estimate_tokens;
+
+/// Key put in rotation when mock mode starts without any configured key
+pub const MOCK_API_KEY: &str = "mock-api-key";
+
+/// Length of the mock embedding vectors, as for `text-embedding-004`
+const EMBEDDING_DIMENSIONS: usize = 768;
+
+#[derive(Debug)]
+pub struct MockUpstream {
+    latency: Duration,
+    fail_rate: f64,
+    calls: AtomicU64,
+}
+
+impl MockUpstream {
+    pub fn new(settings: &Settings) -> Self {
+        Self {
+            latency: Duration::from_millis(settings.mock_latency_ms),
+            fail_rate: settings.mock_fail_rate.clamp(0.0, 1.0),
+            calls: AtomicU64::new(0),
+        }
+    }
+
+    /// Wait out the configured latency, then fail the call if its turn has come. Failures are
+    /// spread evenly rather than drawn at random, so a run is repeatable.
+    async fn call(&self) -> Result<(), GeminiError> {
+        if !self.latency.is_zero() {
+            tokio::time::sleep(self.latency).await;
+        }
+        let call = self.calls.fetch_add(1, Ordering::Relaxed);
+        if fails(call, self.fail_rate) {
+            return Err(GeminiError::Upstream { status: 503, body: "Mock upstream failure".to_string() });
+        }
+        Ok(())
+    }
+}
+
+/// Whether call number `call` is one of the failing share `rate` of all calls
+fn fails(call: u64, rate: f64) -> bool {
+    ((call + 1) as f64 * rate).floor() > (call as f64 * rate).floor()
+}
+
+/// The canned answer to `request`
+fn reply(request: &ChatCompletionRequest) -> String {
+    let last_user = request.messages.iter().rev().find(|message| message.role == "user");
+    match last_user.and_then(|message| message.content.as_ref()).map(extract_text_from_value) {
+        Some(text) if !text.is_empty() => format!("Mock reply to: {}", text),
+        _ => "Mock reply".to_string(),
+    }
+}
+
+fn usage(request: &ChatCompletionRequest, reply: &str) -> Usage {
+    let prompt: String = request.messages
+        .iter()
+        .filter_map(|message| message.content.as_ref())
+        .map(extract_text_from_value)
+        .collect::<Vec<_>>()
+        .join("\n");
+    Usage::new(estimate_tokens(&prompt), estimate_tokens(reply))
+}
+
+fn chunk(response: &ChatCompletionResponse, delta: ChatMessageDelta, finish_reason: Option<&str>) -> ChatCompletionChunk {
+    ChatCompletionChunk {
+        id: response.id.clone(),
+        object: "chat.completion.chunk".to_string(),
+        created: response.created,
+        model: response.model.clone(),
+        choices: vec![ChatChoiceDelta { index: 0, delta, finish_reason: finish_reason.map(str::to_string), logprobs: None }],
+        system_fingerprint: None,
+        usage: None,
+    }
+}
+
+/// Embedding derived from the text alone, so the same input always gets the same vector
+fn embedding(text: &str) -> Vec<f64> {
+    let mut bytes = [0u8; EMBEDDING_DIMENSIONS];
+    blake3::Hasher::new().update(text.as_bytes()).finalize_xof().fill(&mut bytes);
+    bytes.iter().map(|byte| *byte as f64 / 255.0 - 0.5).collect()
+}
+
+#[async_trait]
+impl GeminiClientTrait for MockUpstream {
+    async fn chat_completion(&self, request: ChatCompletionRequest, _api_key: &str) -> Result<ChatCompletionResponse, GeminiError> {
+        self.call().await?;
+        let content = reply(&request);
+        Ok(ChatCompletionResponse {
+            model: request.model.clone(),
+            usage: Some(usage(&request, &content)),
+            choices: vec![ChatChoice {
+                index: 0,
+                message: ChatMessage {
+                    role: "assistant".to_string(),
+                    content: Some(content.into()),
+                    name: None,
+                    tool_calls: None,
+                    tool_call_id: None,
+                },
+                finish_reason: Some("stop".to_string()),
+                logprobs: None,
+            }],
+            ..Default::default()
+        })
+    }
+
+    /// The reply a word per chunk, then the finish chunk and, when asked for, the usage chunk
+    async fn chat_completion_stream(&self, request: ChatCompletionRequest, _api_key: &str) -> Result<ChatCompletionStream, GeminiError> {
+        self.call().await?;
+        let content = reply(&request);
+        let response = ChatCompletionResponse { model: request.model.clone(), ..Default::default() };
+
+        let mut chunks: Vec<ChatCompletionChunk> = content
+            .split_inclusive(' ')
+            .enumerate()
+            .map(|(index, word)| {
+                let role = (index == 0).then(|| "assistant".to_string());
+                chunk(&response, ChatMessageDelta { role, content: Some(word.to_string()), tool_calls: None }, None)
+            })
+            .collect();
+        chunks.push(chunk(&response, ChatMessageDelta { role: None, content: None, tool_calls: None }, Some("stop")));
+        if request.include_usage() {
+            chunks.push(usage_chunk(&response.id, &response.model, response.created, usage(&request, &content)));
+        }
+        Ok(Box::pin(stream::iter(chunks.into_iter().map(Ok))))
+    }
+
+    async fn list_models(&self, _api_key: &str) -> Result<Vec<Model>, GeminiError> {
+        self.call().await?;
+        Ok(default_models())
+    }
+
+    async fn embedding(&self, request: EmbeddingRequest, _api_key: &str) -> Result<EmbeddingResponse, GeminiError> {
+        self.call().await?;
+        let texts = match &request.input {
+            EmbeddingInput::String(text) => vec![text.clone()],
+            EmbeddingInput::ArrayOfStrings(texts) => texts.clone(),
+            _ => return Err(GeminiError::InvalidRequest { message: "Unsupported embedding input format".to_string() }),
+        };
+        let prompt_tokens = texts.iter().map(|text| estimate_tokens(text)).sum();
+        Ok(EmbeddingResponse {
+            object: "list".to_string(),
+            data: texts
+                .iter()
+                .enumerate()
+                .map(|(index, text)| EmbeddingData { object: "embedding".to_string(), embedding: embedding(text), index: index as u32 })
+                .collect(),
+            model: request.model,
+            usage: EmbeddingUsage { prompt_tokens, total_tokens: prompt_tokens },
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures_util::StreamExt;
+
+    fn request(body: serde_json::Value) -> ChatCompletionRequest {
+        let mut request = serde_json::json!({"model": "gemini-2.0-flash", "messages": [
+            {"role": "user", "content": "first"},
+            {"role": "assistant", "content": "ok"},
+            {"role": "user", "content": "say hello"},
+        ]});
+        request.as_object_mut().unwrap().extend(body.as_object().unwrap().clone());
+        serde_json::from_value(request).unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_replies_echo_the_last_user_message() {
+        let mock = MockUpstream::new(&Settings::default());
+        let response = mock.chat_completion(request(serde_json::json!({})), MOCK_API_KEY).await.unwrap();
+        assert_eq!(response.choices[0].message.content, Some("Mock reply to: say hello".into()));
+        assert!(response.usage.unwrap().prompt_tokens > 0);
+
+        let stream = mock.chat_completion_stream(request(serde_json::json!({"stream_options": {"include_usage": true}})), MOCK_API_KEY);
+        let chunks: Vec<ChatCompletionChunk> = stream.await.unwrap().map(Result::unwrap).collect().await;
+        let text: String = chunks.iter().filter_map(|chunk| chunk.choices.first()?.delta.content.clone()).collect();
+        assert_eq!(text, "Mock reply to: say hello");
+        // A chunk per word, the finish chunk and the usage chunk
+        assert_eq!(chunks.len(), 5 + 2);
+        assert_eq!(chunks[5].choices[0].finish_reason.as_deref(), Some("stop"));
+        assert!(chunks[6].usage.is_some());
+    }
+
+    #[tokio::test]
+    async fn test_failures_follow_the_configured_rate() {
+        assert_eq!((0..100).filter(|&call| fails(call, 0.25)).count(), 25);
+        assert!(!(0..100).any(|call| fails(call, 0.0)));
+        assert!((0..100).all(|call| fails(call, 1.0)));
+
+        let mock = MockUpstream::new(&Settings { mock_fail_rate: 0.5, ..Default::default() });
+        assert!(mock.list_models(MOCK_API_KEY).await.is_ok());
+        assert!(mock.list_models(MOCK_API_KEY).await.is_err());
+        assert_eq!(embedding("hi"), embedding("hi"));
+        assert_eq!(embedding("hi").len(), EMBEDDING_DIMENSIONS);
+    }
+}
@@ -1,17 +1,24 @@
+pub mod context_cache;
+pub mod context_window;
+pub mod function_schema;
 pub mod gemini;
+pub mod gemini_stream;
+pub mod mock_upstream;
 pub mod embedding;
+pub mod moderation;
 pub mod openai;
+pub mod openai_compat;
 pub mod response_wrapper;
+pub mod transcription;
 
 // Re-export main service structs and traits for easy access - equivalent to Python's __init__.py
 pub use gemini::GeminiClient;
 
-// Note: EmbeddingClient and OpenAIClient exist for API completeness but are not currently used
-// in rujimi since GeminiClient handles all API requests. This differs from hajimi's architecture
-// where separate clients are used for different services.
+// Note: EmbeddingClient exists for API completeness but is not currently used in rujimi since
+// GeminiClient handles embeddings too. OpenAIClient serves chat completions only when
+// `use_native_openai_endpoint` is on.
 #[allow(dead_code)]
 pub use embedding::EmbeddingClient;
-#[allow(dead_code)]
 pub use openai::OpenAIClient;
 
 // Response wrappers are available for advanced response processing but not currently used
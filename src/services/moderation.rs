@@ -0,0 +1,187 @@
+//! OpenAI moderation results built from Gemini safety ratings. The input is sent to a cheap
+//! model with every threshold at `BLOCK_NONE` and a one-token answer, and the ratings Gemini
+//! attaches are mapped onto OpenAI's categories.
+
+use moka::future::Cache;
+use serde_json::{json, Value};
+use std::collections::BTreeMap;
+use std::time::Duration;
+
+use crate::models::schemas::ModerationResult;
+
+/// Rates the input when the request does not name a `gemini-*` model
+pub const DEFAULT_MODERATION_MODEL: &str = "gemini-2.0-flash-lite";
+
+/// Moderation inputs repeat a lot, so results are kept for a day
+const CACHE_TTL: Duration = Duration::from_secs(24 * 60 * 60);
+const MAX_CACHED_RESULTS: u64 = 10_000;
+
+/// Every OpenAI category, reported in each result whether or not Gemini rates it
+const OPENAI_CATEGORIES: &[&str] = &[
+    "harassment",
+    "harassment/threatening",
+    "hate",
+    "hate/threatening",
+    "illicit",
+    "illicit/violent",
+    "self-harm",
+    "self-harm/instructions",
+    "self-harm/intent",
+    "sexual",
+    "sexual/minors",
+    "violence",
+    "violence/graphic",
+];
+
+/// Which OpenAI categories each Gemini harm category scores:
+///
+/// | Gemini                            | OpenAI                   |
+/// |-----------------------------------|--------------------------|
+/// | `HARM_CATEGORY_HARASSMENT`        | `harassment`             |
+/// | `HARM_CATEGORY_HATE_SPEECH`       | `hate`                   |
+/// | `HARM_CATEGORY_SEXUALLY_EXPLICIT` | `sexual`                 |
+/// | `HARM_CATEGORY_DANGEROUS_CONTENT` | `illicit`, `violence`    |
+/// | `HARM_CATEGORY_CIVIC_INTEGRITY`   | none                     |
+///
+/// Categories Gemini has no rating for, such as `self-harm`, always score 0.
+const CATEGORY_MAP: &[(&str, &[&str])] = &[
+    ("HARM_CATEGORY_HARASSMENT", &["harassment"]),
+    ("HARM_CATEGORY_HATE_SPEECH", &["hate"]),
+    ("HARM_CATEGORY_SEXUALLY_EXPLICIT", &["sexual"]),
+    ("HARM_CATEGORY_DANGEROUS_CONTENT", &["illicit", "violence"]),
+    ("HARM_CATEGORY_CIVIC_INTEGRITY", &[]),
+];
+
+/// A category is flagged at `MEDIUM` probability or above
+const FLAG_SCORE: f64 = 0.5;
+
+/// Score for a Gemini probability, the middle of the range it stands for
+fn probability_score(probability: &str) -> f64 {
+    match probability {
+        "LOW" => 0.25,
+        "MEDIUM" => 0.6,
+        "HIGH" => 0.9,
+        // NEGLIGIBLE and unspecified
+        _ => 0.01,
+    }
+}
+
+/// The Gemini model to rate with: the requested one when it is a Gemini model
+pub fn moderation_model(requested: Option<&str>) -> &str {
+    requested.filter(|model| model.starts_with("gemini")).unwrap_or(DEFAULT_MODERATION_MODEL)
+}
+
+/// A `generateContent` body that only asks for safety ratings of `text`
+pub fn moderation_request(text: &str) -> Value {
+    let safety_settings: Vec<Value> = CATEGORY_MAP
+        .iter()
+        .map(|(category, _)| json!({"category": category, "threshold": "BLOCK_NONE"}))
+        .collect();
+    json!({
+        "contents": [{"role": "user", "parts": [{"text": text}]}],
+        "generationConfig": {"candidateCount": 1, "maxOutputTokens": 1},
+        "safetySettings": safety_settings,
+    })
+}
+
+/// Map the safety ratings of a `generateContent` response. Ratings come from the first
+/// candidate, or from the prompt feedback when the prompt itself was blocked; a blocked
+/// prompt is always flagged.
+pub fn moderation_from_gemini(response: &Value) -> ModerationResult {
+    let ratings = response
+        .pointer("/candidates/0/safetyRatings")
+        .or_else(|| response.pointer("/promptFeedback/safetyRatings"))
+        .and_then(Value::as_array);
+    let blocked = response.pointer("/promptFeedback/blockReason").is_some_and(|reason| !reason.is_null());
+
+    let mut category_scores: BTreeMap<String, f64> = OPENAI_CATEGORIES.iter().map(|category| (category.to_string(), 0.0)).collect();
+    for rating in ratings.into_iter().flatten() {
+        let category = rating.get("category").and_then(Value::as_str).unwrap_or_default();
+        let score = rating
+            .get("probabilityScore")
+            .and_then(Value::as_f64)
+            .unwrap_or_else(|| probability_score(rating.get("probability").and_then(Value::as_str).unwrap_or_default()));
+        let targets = CATEGORY_MAP.iter().find(|(gemini, _)| *gemini == category).map(|(_, targets)| *targets);
+        for target in targets.unwrap_or_default() {
+            if let Some(current) = category_scores.get_mut(*target) {
+                *current = current.max(score);
+            }
+        }
+    }
+
+    let categories: BTreeMap<String, bool> = category_scores.iter().map(|(category, score)| (category.clone(), *score >= FLAG_SCORE)).collect();
+    ModerationResult {
+        flagged: blocked || categories.values().any(|flagged| *flagged),
+        categories,
+        category_scores,
+    }
+}
+
+/// Moderation results keyed by rating model and input
+#[derive(Debug, Clone)]
+pub struct ModerationCache {
+    results: Cache<String, ModerationResult>,
+}
+
+impl Default for ModerationCache {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl ModerationCache {
+    pub fn new() -> Self {
+        Self { results: Cache::builder().max_capacity(MAX_CACHED_RESULTS).time_to_live(CACHE_TTL).build() }
+    }
+
+    fn key(model: &str, text: &str) -> String {
+        blake3::hash(format!("{}\0{}", model, text).as_bytes()).to_hex().to_string()
+    }
+
+    pub async fn get(&self, model: &str, text: &str) -> Option<ModerationResult> {
+        self.results.get(&Self::key(model, text)).await
+    }
+
+    pub async fn insert(&self, model: &str, text: &str, result: ModerationResult) {
+        self.results.insert(Self::key(model, text), result).await;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_moderation_from_gemini() {
+        let response = json!({
+            "candidates": [{
+                "content": {"role": "model", "parts": [{"text": "I"}]},
+                "finishReason": "MAX_TOKENS",
+                "safetyRatings": [
+                    {"category": "HARM_CATEGORY_HARASSMENT", "probability": "MEDIUM"},
+                    {"category": "HARM_CATEGORY_HATE_SPEECH", "probability": "NEGLIGIBLE"},
+                    {"category": "HARM_CATEGORY_DANGEROUS_CONTENT", "probability": "LOW"},
+                    {"category": "HARM_CATEGORY_CIVIC_INTEGRITY", "probability": "HIGH"}
+                ]
+            }]
+        });
+        let result = moderation_from_gemini(&response);
+        assert!(result.flagged);
+        assert_eq!(result.categories.len(), OPENAI_CATEGORIES.len());
+        assert!(result.categories["harassment"]);
+        assert!(!result.categories["hate"]);
+        assert_eq!(result.category_scores["illicit"], 0.25);
+        assert_eq!(result.category_scores["violence"], 0.25);
+        assert_eq!(result.category_scores["self-harm"], 0.0);
+
+        let clean = json!({"candidates": [{"safetyRatings": [{"category": "HARM_CATEGORY_SEXUALLY_EXPLICIT", "probability": "NEGLIGIBLE"}]}]});
+        assert!(!moderation_from_gemini(&clean).flagged);
+
+        // A blocked prompt has no candidates
+        let blocked = json!({"promptFeedback": {"blockReason": "OTHER", "safetyRatings": []}});
+        assert!(moderation_from_gemini(&blocked).flagged);
+
+        assert_eq!(moderation_model(Some("omni-moderation-latest")), DEFAULT_MODERATION_MODEL);
+        assert_eq!(moderation_model(Some("gemini-2.0-flash")), "gemini-2.0-flash");
+    }
+}
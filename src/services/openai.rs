@@ -1,41 +1,50 @@
 use futures_util::{Stream, StreamExt};
-use reqwest::Client;
-use serde::{Deserialize, Serialize};
+use reqwest::StatusCode;
 use serde_json::{json, Value};
 use std::collections::HashMap;
 use std::pin::Pin;
 use std::sync::Arc;
-use std::time::Duration;
 use tokio_stream::wrappers::ReceiverStream;
 use tracing::{debug, error, warn};
 
-use crate::config::Settings;
+use crate::config::{ConfigManager, Settings, UpstreamHeaderProfile};
 use crate::models::schemas::{
-    ChatCompletionRequest, ChatCompletionChunk as ChatCompletionStreamResponse, ChatMessage,
+    ChatCompletionRequest, ChatCompletionChunk as ChatCompletionStreamResponse, ChatCompletionResponse, ChatMessage,
 };
+use crate::utils::error_handling::UpstreamTimeoutError;
+use crate::utils::browser;
+use crate::utils::http_client::UpstreamClient;
 use crate::utils::logging::log;
 
+/// Client for Gemini's OpenAI-compatible endpoint. It holds no key of its own: callers take
+/// one from `ApiKeyManager::get_next_key` and report the outcome with `mark_key_used`.
 #[derive(Debug, Clone)]
 pub struct OpenAIClient {
-    client: Client,
-    settings: Arc<Settings>,
+    client: UpstreamClient,
     whitelist: Vec<String>,
 }
 
-#[derive(Debug, Serialize, Deserialize)]
-struct FilteredRequest {
-    #[serde(flatten)]
-    base: ChatCompletionRequest,
-    #[serde(skip_serializing_if = "Option::is_none")]
-    search: Option<bool>,
+type BoxError = Box<dyn std::error::Error + Send + Sync>;
+
+/// Non-2xx reply from the OpenAI-compatible endpoint
+#[derive(Debug, thiserror::Error)]
+#[error("OpenAI API error: {status} - {body}")]
+pub struct OpenAIApiError {
+    pub status: StatusCode,
+    pub body: String,
+}
+
+impl OpenAIApiError {
+    /// The endpoint rejects something the converted Gemini path may still handle
+    pub fn is_unsupported(&self) -> bool {
+        matches!(self.status, StatusCode::BAD_REQUEST | StatusCode::NOT_FOUND)
+    }
 }
 
 impl OpenAIClient {
     /// Create a new OpenAI client - equivalent to Python's __init__
     pub fn new(settings: Arc<Settings>) -> Self {
-        let client = Client::builder()
-            .timeout(Duration::from_secs(120))
-            .build()
+        let client = UpstreamClient::new(settings.clone(), false)
             .expect("Failed to create HTTP client");
 
         // Default whitelist of allowed fields
@@ -61,7 +70,6 @@ impl OpenAIClient {
 
         Self {
             client,
-            settings,
             whitelist,
         }
     }
@@ -70,12 +78,20 @@ impl OpenAIClient {
     pub async fn stream_chat(
         &self,
         request: ChatCompletionRequest,
-    ) -> Result<
-        Pin<Box<dyn Stream<Item = Result<ChatCompletionStreamResponse, Box<dyn std::error::Error + Send + Sync>>> + Send>>,
-        Box<dyn std::error::Error + Send + Sync>,
-    > {
+        api_key: &str,
+    ) -> Result<Pin<Box<dyn Stream<Item = Result<ChatCompletionStreamResponse, BoxError>> + Send>>, BoxError> {
+        let base_url = ConfigManager::get_gemini_base_url_for(&request.model).await;
+        self.stream_chat_at(&base_url, request, api_key).await
+    }
+
+    async fn stream_chat_at(
+        &self,
+        base_url: &str,
+        request: ChatCompletionRequest,
+        api_key: &str,
+    ) -> Result<Pin<Box<dyn Stream<Item = Result<ChatCompletionStreamResponse, BoxError>> + Send>>, BoxError> {
         // Filter request data based on whitelist
-        let filtered_data = self.filter_request_data(&request)?;
+        let mut streaming_request = self.filter_request_data(&request);
 
         // Log the streaming request
         log(
@@ -86,70 +102,52 @@ impl OpenAIClient {
                 extra.insert("model".to_string(), json!(request.model));
                 extra.insert("messages_count".to_string(), json!(request.messages.len()));
                 extra.insert("stream".to_string(), json!(true));
-                extra.insert("search_mode".to_string(), json!(filtered_data.search.unwrap_or(false)));
+                extra.insert("search_mode".to_string(), json!(streaming_request.get("search").and_then(Value::as_bool).unwrap_or(false)));
                 extra
             }),
         );
 
-        // Construct the URL for Gemini's OpenAI-compatible endpoint
-        let url = format!(
-            "https://generativelanguage.googleapis.com/v1beta/openai/chat/completions?key={}",
-            self.settings.gemini_api_keys.first().unwrap_or(&String::new())
-        );
-
         // Ensure streaming is enabled
-        let mut streaming_request = filtered_data;
-        streaming_request.base.stream = true;
-
-        debug!("发送流式请求到OpenAI兼容端点: {}", url);
+        streaming_request.insert("stream".to_string(), json!(true));
 
         // Make the streaming request
+        let timeouts = ConfigManager::get_upstream_timeouts().await;
+        debug!("发送流式请求到OpenAI兼容端点: {}/openai/chat/completions", base_url);
         let response = self
-            .client
-            .post(&url)
-            .header("Content-Type", "application/json")
+            .completions_request(base_url, api_key, timeouts.connect, ConfigManager::get_upstream_header_profile().await)
             .json(&streaming_request)
             .send()
             .await?;
-
-        if !response.status().is_success() {
-            let status = response.status();
-            let error_text = response.text().await?;
-            error!("OpenAI兼容API请求失败: {} - {}", status, error_text);
-            return Err(format!("OpenAI API error: {} - {}", status, error_text).into());
-        }
+        let response = Self::check_status(response).await?;
 
         let (tx, rx) = tokio::sync::mpsc::channel(100);
 
         // Spawn a task to handle the streaming response
-        let client_clone = self.client.clone();
+        let idle_timeout = timeouts.stream_idle;
         tokio::spawn(async move {
             let mut response_stream = response.bytes_stream();
             let mut buffer = Vec::new();
 
-            while let Some(chunk_result) = response_stream.next().await {
+            loop {
+                let chunk_result = match tokio::time::timeout(idle_timeout, response_stream.next()).await {
+                    Ok(Some(chunk_result)) => chunk_result,
+                    Ok(None) => break,
+                    Err(_) => {
+                        let timeout_error = UpstreamTimeoutError {
+                            phase: "stream idle",
+                            seconds: idle_timeout.as_secs(),
+                        };
+                        let _ = tx.send(Err(timeout_error.into())).await;
+                        return;
+                    }
+                };
+
                 match chunk_result {
                     Ok(chunk) => {
                         buffer.extend_from_slice(&chunk);
 
-                        // Process complete lines
-                        let buffer_str = String::from_utf8_lossy(&buffer);
-                        let all_lines: Vec<String> = buffer_str.lines().map(|s| s.to_string()).collect();
-
-                        // Determine which lines to process and what to keep in buffer
-                        let (lines_to_process, new_buffer) = if !buffer_str.ends_with('\n') && !all_lines.is_empty() {
-                            // Keep the last line in buffer, process the rest
-                            let last_line = all_lines.last().unwrap();
-                            let process_lines = &all_lines[..all_lines.len()-1];
-                            (process_lines.to_vec(), last_line.as_bytes().to_vec())
-                        } else {
-                            // Process all lines, clear buffer
-                            (all_lines.clone(), Vec::new())
-                        };
-
-                        buffer = new_buffer;
-
-                        for line in lines_to_process {
+                        // Process complete lines; a partial line stays buffered as raw bytes
+                        for line in Self::take_complete_lines(&mut buffer) {
                             if let Some(chunk_response) = Self::parse_sse_line(&line) {
                                 if tx.send(Ok(chunk_response)).await.is_err() {
                                     return; // Receiver dropped
@@ -180,25 +178,80 @@ impl OpenAIClient {
         Ok(Box::pin(ReceiverStream::new(rx)))
     }
 
-    /// Filter request data based on whitelist - equivalent to Python's filter_data
-    fn filter_request_data(&self, request: &ChatCompletionRequest) -> Result<FilteredRequest, Box<dyn std::error::Error + Send + Sync>> {
-        let request_json = serde_json::to_value(request)?;
+    /// Non-streaming chat completion through the OpenAI-compatible endpoint
+    pub async fn chat(&self, request: ChatCompletionRequest, api_key: &str) -> Result<ChatCompletionResponse, BoxError> {
+        let base_url = ConfigManager::get_gemini_base_url_for(&request.model).await;
+        self.chat_at(&base_url, request, api_key).await
+    }
+
+    async fn chat_at(&self, base_url: &str, request: ChatCompletionRequest, api_key: &str) -> Result<ChatCompletionResponse, BoxError> {
+        let mut filtered_request = self.filter_request_data(&request);
+        filtered_request.insert("stream".to_string(), json!(false));
+
+        let timeouts = ConfigManager::get_upstream_timeouts().await;
+        let response = self
+            .completions_request(base_url, api_key, timeouts.connect, ConfigManager::get_upstream_header_profile().await)
+            .json(&filtered_request)
+            .timeout(timeouts.request)
+            .send()
+            .await?;
+        let response = Self::check_status(response).await?;
+        Ok(response.json().await?)
+    }
+
+    /// Turn a non-2xx reply into `OpenAIApiError`
+    async fn check_status(response: reqwest::Response) -> Result<reqwest::Response, BoxError> {
+        if response.status().is_success() {
+            return Ok(response);
+        }
+
+        let status = response.status();
+        let body = response.text().await?;
+        error!("OpenAI兼容API请求失败: {} - {}", status, body);
+        Err(Box::new(OpenAIApiError { status, body }))
+    }
+
+    /// POST to the OpenAI-compatible endpoint. The key goes in the `Authorization` header so
+    /// it never appears in a URL that could end up in logs.
+    fn completions_request(&self, base_url: &str, api_key: &str, connect_timeout: std::time::Duration, header_profile: UpstreamHeaderProfile) -> reqwest::RequestBuilder {
+        let request = self.client
+            .get(connect_timeout)
+            .post(format!("{}/openai/chat/completions", base_url));
+        browser::with_header_profile(request, header_profile, api_key)
+            .header("Content-Type", "application/json")
+            .bearer_auth(api_key)
+    }
+
+    /// Filter request data based on whitelist - equivalent to Python's filter_data.
+    /// Unset fields are left out rather than sent as `null`.
+    fn filter_request_data(&self, request: &ChatCompletionRequest) -> serde_json::Map<String, Value> {
         let mut filtered = serde_json::Map::new();
 
-        if let Value::Object(obj) = request_json {
+        if let Ok(Value::Object(obj)) = serde_json::to_value(request) {
             for (key, value) in obj {
-                if self.whitelist.contains(&key) {
-                    filtered.insert(key, value);
-                } else {
+                if !self.whitelist.contains(&key) {
                     debug!("过滤掉不支持的字段: {}", key);
+                } else if !value.is_null() {
+                    filtered.insert(key, value);
                 }
             }
         }
 
-        let filtered_value = Value::Object(filtered);
-        let filtered_request: FilteredRequest = serde_json::from_value(filtered_value)?;
+        filtered
+    }
+
+    /// Remove and decode every `\n`-terminated line from `buffer`. Splitting happens on bytes,
+    /// so a multi-byte character cut across network chunks is only decoded once whole.
+    fn take_complete_lines(buffer: &mut Vec<u8>) -> Vec<String> {
+        let complete = match buffer.iter().rposition(|&byte| byte == b'\n') {
+            Some(last_newline) => buffer.drain(..=last_newline).collect::<Vec<u8>>(),
+            None => return Vec::new(),
+        };
 
-        Ok(filtered_request)
+        complete
+            .split(|&byte| byte == b'\n')
+            .map(|line| String::from_utf8_lossy(line).into_owned())
+            .collect()
     }
 
     /// Parse Server-Sent Events line - equivalent to Python's SSE parsing
@@ -273,12 +326,7 @@ impl OpenAIClient {
     }
 
     /// Health check for OpenAI-compatible endpoint
-    pub async fn health_check(&self) -> bool {
-        let url = format!(
-            "https://generativelanguage.googleapis.com/v1beta/openai/chat/completions?key={}",
-            self.settings.gemini_api_keys.first().unwrap_or(&String::new())
-        );
-
+    pub async fn health_check(&self, api_key: &str) -> bool {
         // Simple health check request
         let health_request = json!({
             "model": "gemini-1.5-flash",
@@ -287,10 +335,12 @@ impl OpenAIClient {
             "stream": false
         });
 
-        match self.client
-            .post(&url)
-            .header("Content-Type", "application/json")
+        let timeouts = ConfigManager::get_upstream_timeouts().await;
+        let base_url = ConfigManager::get_gemini_base_url().await;
+        match self
+            .completions_request(&base_url, api_key, timeouts.connect, ConfigManager::get_upstream_header_profile().await)
             .json(&health_request)
+            .timeout(timeouts.request)
             .send()
             .await
         {
@@ -347,7 +397,13 @@ mod tests {
             max_tokens: None,
             tools: None,
             tool_choice: None,
+            stream_options: None,
+            user: None,
             extra: std::collections::HashMap::new(),
+            overrides: Default::default(),
+            context_trimmed: 0,
+            forwarded_headers: Vec::new(),
+            array_content: None,
         };
 
         assert!(OpenAIClient::is_search_mode_enabled(&request));
@@ -356,6 +412,156 @@ mod tests {
         assert!(!OpenAIClient::is_search_mode_enabled(&request));
     }
 
+    #[test]
+    fn test_api_key_sent_as_bearer_header() {
+        let client = OpenAIClient::new(Arc::new(Settings::default()));
+        let request = client
+            .completions_request("https://upstream.example/v1beta", "AIza-test-key", std::time::Duration::from_secs(5), UpstreamHeaderProfile::Browser)
+            .build()
+            .unwrap();
+
+        assert_eq!(request.url().as_str(), "https://upstream.example/v1beta/openai/chat/completions");
+        assert!(request.url().query().is_none());
+        assert_eq!(request.headers()["authorization"], "Bearer AIza-test-key");
+        assert_eq!(request.headers()["content-type"], "application/json");
+    }
+
+    type Captured = Arc<std::sync::Mutex<Vec<(String, Value)>>>;
+
+    async fn mock_completions(
+        axum::extract::State(captured): axum::extract::State<Captured>,
+        headers: axum::http::HeaderMap,
+        axum::Json(body): axum::Json<Value>,
+    ) -> axum::response::Response {
+        use axum::response::IntoResponse;
+
+        let authorization = headers["authorization"].to_str().unwrap().to_string();
+        captured.lock().unwrap().push((authorization, body.clone()));
+
+        if body["model"] == "unsupported-model" {
+            let error = json!({"error": {"code": 404, "message": "Model not found"}});
+            return (axum::http::StatusCode::NOT_FOUND, axum::Json(error)).into_response();
+        }
+        if body["stream"] == true && body["model"] == "cjk-model" {
+            // One event cut in the middle of a three-byte character, sent as two network chunks
+            let event = format!("data: {}\n\n", cjk_chunk());
+            let split = event.find('好').unwrap() + 1;
+            let (first, second) = event.as_bytes().split_at(split);
+            let parts = vec![first.to_vec(), second.to_vec()];
+            let body = futures_util::stream::iter(parts).then(|part| async move {
+                tokio::time::sleep(std::time::Duration::from_millis(20)).await;
+                Ok::<_, std::convert::Infallible>(part)
+            });
+            return axum::body::Body::from_stream(body).into_response();
+        }
+        if body["stream"] == true {
+            let chunk = |content: &str| {
+                let chunk = json!({
+                    "id": "chatcmpl-1", "object": "chat.completion.chunk", "created": 1, "model": "gemini-2.5-flash",
+                    "choices": [{"index": 0, "delta": {"content": content}, "finish_reason": null}]
+                });
+                format!("data: {}\n\n", chunk)
+            };
+            let body = format!("{}{}data: [DONE]\n\n", chunk("Hel"), chunk("lo"));
+            return ([(axum::http::header::CONTENT_TYPE, "text/event-stream")], body).into_response();
+        }
+        axum::Json(json!({
+            "id": "chatcmpl-1", "object": "chat.completion", "created": 1, "model": "gemini-2.5-flash",
+            "choices": [{"index": 0, "message": {"role": "assistant", "content": "Hello"}, "finish_reason": "stop"}],
+            "usage": {"prompt_tokens": 3, "completion_tokens": 1, "total_tokens": 4}
+        }))
+        .into_response()
+    }
+
+    fn cjk_chunk() -> Value {
+        json!({
+            "id": "chatcmpl-1", "object": "chat.completion.chunk", "created": 1, "model": "cjk-model",
+            "choices": [{"index": 0, "delta": {"content": "你好，世界"}, "finish_reason": null}]
+        })
+    }
+
+    async fn mock_endpoint() -> (Captured, String) {
+        let captured = Captured::default();
+        let app = axum::Router::new()
+            .route("/openai/chat/completions", axum::routing::post(mock_completions))
+            .with_state(captured.clone());
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let base_url = format!("http://{}", listener.local_addr().unwrap());
+        tokio::spawn(async move { axum::serve(listener, app).await.unwrap() });
+        (captured, base_url)
+    }
+
+    fn chat_request(model: &str) -> ChatCompletionRequest {
+        serde_json::from_value(json!({
+            "model": model,
+            "messages": [{"role": "user", "content": "Hi"}],
+            "top_k": 5
+        }))
+        .unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_chat_passthrough() {
+        let (captured, base_url) = mock_endpoint().await;
+        let client = OpenAIClient::new(Arc::new(Settings::default()));
+
+        let response = client.chat_at(&base_url, chat_request("gemini-2.5-flash"), "key-1").await.unwrap();
+        assert_eq!(response.choices[0].message.content, Some(json!("Hello")));
+        assert_eq!(response.usage.unwrap().total_tokens, 4);
+
+        let (authorization, body) = captured.lock().unwrap()[0].clone();
+        assert_eq!(authorization, "Bearer key-1");
+        assert_eq!(body["stream"], false);
+        // Unset fields are not sent as null and non-whitelisted ones are dropped
+        assert!(body.get("temperature").is_none());
+        assert!(body.get("top_k").is_none());
+
+        let error = client.chat_at(&base_url, chat_request("unsupported-model"), "key-1").await.unwrap_err();
+        let error = error.downcast_ref::<OpenAIApiError>().unwrap();
+        assert_eq!(error.status, StatusCode::NOT_FOUND);
+        assert!(error.is_unsupported());
+    }
+
+    #[tokio::test]
+    async fn test_stream_chat_passthrough() {
+        let (captured, base_url) = mock_endpoint().await;
+        let client = OpenAIClient::new(Arc::new(Settings::default()));
+
+        let stream = client.stream_chat_at(&base_url, chat_request("gemini-2.5-flash"), "key-2").await.unwrap();
+        let chunks: Vec<_> = stream.map(|chunk| chunk.unwrap()).collect().await;
+        let text: String = chunks.iter().filter_map(|chunk| chunk.choices[0].delta.content.clone()).collect();
+        assert_eq!(text, "Hello");
+        assert_eq!(captured.lock().unwrap()[0].1["stream"], true);
+
+        assert!(client.stream_chat_at(&base_url, chat_request("unsupported-model"), "key-2").await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_stream_keeps_characters_split_across_chunks() {
+        let (_, base_url) = mock_endpoint().await;
+        let client = OpenAIClient::new(Arc::new(Settings::default()));
+
+        let stream = client.stream_chat_at(&base_url, chat_request("cjk-model"), "key-3").await.unwrap();
+        let chunks: Vec<_> = stream.map(|chunk| chunk.unwrap()).collect().await;
+        assert_eq!(chunks.len(), 1);
+        assert_eq!(chunks[0].choices[0].delta.content.as_deref(), Some("你好，世界"));
+    }
+
+    #[test]
+    fn test_take_complete_lines_splits_on_bytes() {
+        let event = format!("data: {}\n", cjk_chunk());
+        let split = event.find('你').unwrap() + 1;
+        let mut buffer = event.as_bytes()[..split].to_vec();
+        assert!(OpenAIClient::take_complete_lines(&mut buffer).is_empty());
+        assert_eq!(buffer.len(), split);
+
+        buffer.extend_from_slice(&event.as_bytes()[split..]);
+        buffer.extend_from_slice(b"data: [DO");
+        let lines = OpenAIClient::take_complete_lines(&mut buffer);
+        assert_eq!(lines[0], event.trim_end());
+        assert_eq!(buffer, b"data: [DO");
+    }
+
     #[test]
     fn test_whitelist_management() {
         let settings = Arc::new(Settings::default());
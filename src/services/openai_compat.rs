@@ -0,0 +1,84 @@
+//! What the Gemini conversion does with the fields of an OpenAI chat request that fall outside
+//! the typed `ChatCompletionRequest`: mapped onto the Gemini request, dropped as harmless, or
+//! impossible to honor. The converter reads the mapped fields by these names, so a field is
+//! added here in the same change that starts mapping it.
+
+use serde_json::Value;
+
+use crate::models::schemas::ChatCompletionRequest;
+
+/// Extra fields the Gemini conversion maps; any other extra field is dropped
+pub const MAPPED_PARAMS: &[&str] = &["top_k", "safety_settings", "thinking_config", "stop"];
+
+/// A field whose value asks for something rujimi cannot deliver
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct UnsupportedParam {
+    pub param: String,
+    pub message: String,
+}
+
+/// Why the value of `name` cannot be honored. `None` when it can be, or when dropping it
+/// leaves the answer the caller asked for.
+fn unsupported_reason(name: &str, value: &Value) -> Option<&'static str> {
+    match name {
+        "logprobs" if value.as_bool() == Some(true) => Some("Gemini does not return token log probabilities"),
+        "top_logprobs" if value.as_u64().is_some_and(|count| count > 0) => {
+            Some("Gemini does not return token log probabilities")
+        }
+        "n" if value.as_u64().is_some_and(|count| count > 1) => {
+            Some("Only one choice is generated per request; send separate requests for more")
+        }
+        "modalities" if value.as_array().is_some_and(|modalities| modalities.iter().any(|m| m == "audio")) => {
+            Some("Audio output is not supported; only text is generated")
+        }
+        "audio" if !value.is_null() => Some("Audio output is not supported; only text is generated"),
+        "prediction" if !value.is_null() => Some("Predicted outputs are not supported"),
+        _ => None,
+    }
+}
+
+/// The first field of `request` that cannot be honored, in name order, for strict mode
+pub fn unsupported_param(request: &ChatCompletionRequest) -> Option<UnsupportedParam> {
+    let mut names: Vec<&String> = request.extra.keys().collect();
+    names.sort();
+    names.into_iter().find_map(|name| {
+        unsupported_reason(name, &request.extra[name]).map(|reason| UnsupportedParam {
+            param: name.clone(),
+            message: format!("Unsupported parameter `{}`: {}", name, reason),
+        })
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn request(extra: Value) -> ChatCompletionRequest {
+        let mut body = serde_json::json!({"model": "gemini-2.0-flash", "messages": [{"role": "user", "content": "hi"}]});
+        body.as_object_mut().unwrap().extend(extra.as_object().unwrap().clone());
+        serde_json::from_value(body).unwrap()
+    }
+
+    #[test]
+    fn test_values_that_cannot_be_honored() {
+        for (extra, param) in [
+            (serde_json::json!({"logprobs": true}), "logprobs"),
+            (serde_json::json!({"logprobs": false, "top_logprobs": 3}), "top_logprobs"),
+            (serde_json::json!({"n": 2, "stream": true}), "n"),
+            (serde_json::json!({"modalities": ["text", "audio"]}), "modalities"),
+            (serde_json::json!({"prediction": {"type": "content", "content": "x"}}), "prediction"),
+        ] {
+            let unsupported = unsupported_param(&request(extra)).unwrap();
+            assert_eq!(unsupported.param, param);
+            assert!(unsupported.message.contains(&format!("`{}`", param)));
+        }
+
+        // Values that ask for nothing beyond what is generated anyway
+        for extra in [
+            serde_json::json!({"logprobs": false, "n": 1, "modalities": ["text"], "audio": null}),
+            serde_json::json!({"top_k": 40, "parallel_tool_calls": false, "seed": 7}),
+        ] {
+            assert_eq!(unsupported_param(&request(extra)), None);
+        }
+    }
+}
@@ -114,11 +114,7 @@ impl GeminiResponseWrapper {
 
     /// Get token count - equivalent to Python's get_token_count()
     pub fn get_token_count(&self) -> Option<Usage> {
-        self.response.usage_metadata.as_ref().map(|meta| Usage {
-            prompt_tokens: meta.prompt_token_count.unwrap_or(0),
-            completion_tokens: meta.candidates_token_count.unwrap_or(0),
-            total_tokens: meta.total_token_count.unwrap_or(0),
-        })
+        self.response.usage_metadata.as_ref().map(Usage::from_gemini)
     }
 
     /// Get finish reason - equivalent to Python's get_finish_reason()
@@ -298,6 +294,7 @@ mod tests {
                 prompt_token_count: Some(10),
                 candidates_token_count: Some(20),
                 total_token_count: Some(30),
+                thoughts_token_count: None,
             }),
             prompt_feedback: None,
         }
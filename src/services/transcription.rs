@@ -0,0 +1,167 @@
+//! Whisper-style transcription on Gemini audio understanding. The upload is sent inline with
+//! an instruction to transcribe it verbatim; Gemini gives no timestamps, so `verbose_json`
+//! segments are sentences with times spread by length over the duration when it is known.
+
+use serde_json::{json, Value};
+
+/// Base64 grows the audio by a third and Gemini accepts at most 20 MB of inline request data
+pub const MAX_AUDIO_BYTES: usize = 15 * 1024 * 1024;
+
+/// Listed in the error for an unsupported upload
+pub const SUPPORTED_FORMATS: &str = "wav, mp3, m4a, ogg, flac";
+
+/// File extensions and content types accepted for each MIME type sent to Gemini
+const AUDIO_FORMATS: &[(&str, &[&str], &str)] = &[
+    ("wav", &["audio/wav", "audio/x-wav", "audio/wave", "audio/vnd.wave"], "audio/wav"),
+    ("mp3", &["audio/mpeg", "audio/mp3", "audio/mpeg3"], "audio/mp3"),
+    ("m4a", &["audio/mp4", "audio/m4a", "audio/x-m4a"], "audio/mp4"),
+    ("ogg", &["audio/ogg", "application/ogg"], "audio/ogg"),
+    ("flac", &["audio/flac", "audio/x-flac"], "audio/flac"),
+];
+
+/// The MIME type to send for an upload, by file extension or else by content type
+pub fn audio_mime_type(file_name: Option<&str>, content_type: Option<&str>) -> Option<&'static str> {
+    let extension = file_name
+        .and_then(|name| name.rsplit_once('.'))
+        .map(|(_, extension)| extension.to_ascii_lowercase());
+    if let Some((_, _, mime)) = AUDIO_FORMATS.iter().find(|(ext, _, _)| extension.as_deref() == Some(*ext)) {
+        return Some(mime);
+    }
+    let content_type = content_type?.split(';').next()?.trim().to_ascii_lowercase();
+    AUDIO_FORMATS
+        .iter()
+        .find(|(_, types, _)| types.contains(&content_type.as_str()))
+        .map(|(_, _, mime)| *mime)
+}
+
+/// The Gemini model to transcribe with: the requested one when it is a Gemini model
+pub fn transcription_model<'a>(requested: Option<&'a str>, configured: &'a str) -> &'a str {
+    requested.filter(|model| model.starts_with("gemini")).unwrap_or(configured)
+}
+
+/// The instruction sent along with the audio
+pub fn transcription_instruction(language: Option<&str>, prompt: Option<&str>) -> String {
+    let mut instruction = String::from(
+        "Transcribe this audio verbatim. Reply with the transcript only, without timestamps, speaker labels or commentary.",
+    );
+    if let Some(language) = language.filter(|language| !language.trim().is_empty()) {
+        instruction.push_str(&format!(" The audio is in the language with ISO-639-1 code `{}`.", language.trim()));
+    }
+    if let Some(prompt) = prompt.filter(|prompt| !prompt.trim().is_empty()) {
+        instruction.push_str(&format!(" Use this context for spelling and style: {}", prompt.trim()));
+    }
+    instruction
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TranscriptionFormat {
+    Json,
+    Text,
+    VerboseJson,
+}
+
+impl TranscriptionFormat {
+    pub fn parse(format: &str) -> Result<Self, String> {
+        match format {
+            "json" => Ok(Self::Json),
+            "text" => Ok(Self::Text),
+            "verbose_json" => Ok(Self::VerboseJson),
+            "srt" | "vtt" => Err(format!("response_format `{}` is not supported: Gemini gives no timestamps", format)),
+            other => Err(format!("Unknown response_format `{}`; use json, text or verbose_json", other)),
+        }
+    }
+}
+
+/// Length in seconds of a PCM WAV file, from its byte rate. Other formats are not measured.
+pub fn wav_duration(audio: &[u8]) -> Option<f64> {
+    if audio.len() < 44 || &audio[0..4] != b"RIFF" || &audio[8..12] != b"WAVE" {
+        return None;
+    }
+    let byte_rate = u32::from_le_bytes(audio[28..32].try_into().ok()?);
+    if byte_rate == 0 {
+        return None;
+    }
+    Some((audio.len() - 44) as f64 / byte_rate as f64)
+}
+
+/// A `verbose_json` transcription with one segment per sentence
+pub fn verbose_transcription(text: &str, language: Option<&str>, duration: Option<f64>) -> Value {
+    let mut sentences = Vec::new();
+    let mut current = String::new();
+    for c in text.chars() {
+        current.push(c);
+        if matches!(c, '.' | '!' | '?' | '。' | '！' | '？' | '\n') && !current.trim().is_empty() {
+            sentences.push(std::mem::take(&mut current));
+        }
+    }
+    if !current.trim().is_empty() {
+        sentences.push(current);
+    }
+
+    let total_chars: usize = sentences.iter().map(|sentence| sentence.chars().count()).sum();
+    let seconds_per_char = match duration {
+        Some(duration) if total_chars > 0 => duration / total_chars as f64,
+        _ => 0.0,
+    };
+    let mut start = 0.0;
+    let segments: Vec<Value> = sentences
+        .iter()
+        .enumerate()
+        .map(|(id, sentence)| {
+            let end = start + sentence.chars().count() as f64 * seconds_per_char;
+            let segment = json!({"id": id, "start": round_secs(start), "end": round_secs(end), "text": sentence.trim()});
+            start = end;
+            segment
+        })
+        .collect();
+
+    json!({
+        "task": "transcribe",
+        "language": language.unwrap_or_default(),
+        "duration": duration.map(round_secs),
+        "text": text,
+        "segments": segments,
+    })
+}
+
+fn round_secs(secs: f64) -> f64 {
+    (secs * 100.0).round() / 100.0
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_audio_mime_type() {
+        assert_eq!(audio_mime_type(Some("meeting.M4A"), Some("application/octet-stream")), Some("audio/mp4"));
+        assert_eq!(audio_mime_type(Some("upload"), Some("audio/x-wav; codecs=1")), Some("audio/wav"));
+        assert_eq!(audio_mime_type(Some("clip.flac"), None), Some("audio/flac"));
+        assert_eq!(audio_mime_type(Some("movie.mkv"), Some("video/x-matroska")), None);
+        assert_eq!(audio_mime_type(None, None), None);
+    }
+
+    #[test]
+    fn test_verbose_transcription() {
+        // 1 second of 16 kHz mono 16-bit audio
+        let mut wav = b"RIFF\0\0\0\0WAVEfmt ".to_vec();
+        wav.resize(28, 0);
+        wav.extend_from_slice(&32_000u32.to_le_bytes());
+        wav.resize(44 + 32_000, 0);
+        let duration = wav_duration(&wav);
+        assert_eq!(duration, Some(1.0));
+
+        let verbose = verbose_transcription("Hello there. How are you?", Some("en"), duration);
+        let segments = verbose["segments"].as_array().unwrap();
+        assert_eq!(segments.len(), 2);
+        assert_eq!(segments[0]["text"], "Hello there.");
+        assert_eq!(segments[0]["start"], 0.0);
+        assert_eq!(segments[1]["text"], "How are you?");
+        assert_eq!(segments[1]["end"], 1.0);
+        assert_eq!(verbose["duration"], 1.0);
+
+        let unknown = verbose_transcription("No timing", None, None);
+        assert_eq!(unknown["segments"][0]["end"], 0.0);
+        assert!(unknown["duration"].is_null());
+    }
+}
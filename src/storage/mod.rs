@@ -0,0 +1,89 @@
+//! Optional durable storage. With the default `memory` backend the managers keep everything
+//! in memory as before; with `sqlite` they also write call records, key counters, cached
+//! responses and settings snapshots through the small traits below, and restore them at
+//! startup. `shared_keys` separately lets replicas share API key state through Redis.
+
+pub mod shared_keys;
+pub mod sqlite;
+
+use anyhow::Result;
+use serde::Serialize;
+use std::sync::{Arc, OnceLock};
+use std::time::{Duration, SystemTime};
+
+use crate::config::{Settings, StorageBackend};
+use crate::utils::api_key::ApiKeyStats;
+use crate::utils::cache::CacheEntry;
+use crate::utils::stats::ApiCallRecord;
+
+pub use sqlite::SqliteStorage;
+
+/// The storage opened at startup, used where no manager is at hand (settings saves, status)
+static ACTIVE_STORAGE: OnceLock<Arc<SqliteStorage>> = OnceLock::new();
+
+/// Call records, read back when the stats manager starts
+pub trait CallRecordStore: Send + Sync + std::fmt::Debug {
+    fn append_call(&self, record: &ApiCallRecord);
+    fn load_calls(&self, since: SystemTime) -> Result<Vec<ApiCallRecord>>;
+    fn clear_calls(&self);
+}
+
+/// Per-key counters; keys are only stored as hashes
+pub trait KeyStateStore: Send + Sync + std::fmt::Debug {
+    fn save_key_stats(&self, key: &str, stats: &ApiKeyStats);
+    /// Stored stats for those of `keys` that have any
+    fn load_key_stats(&self, keys: &[String]) -> Result<Vec<(String, ApiKeyStats)>>;
+}
+
+/// Cached responses, at most three per cache key like the in-memory cache
+pub trait CacheStore: Send + Sync + std::fmt::Debug {
+    fn put_entry(&self, cache_key: &str, entry: &CacheEntry);
+    fn remove_key(&self, cache_key: &str);
+    fn clear_entries(&self);
+    /// Entries younger than `ttl`, oldest first
+    fn load_entries(&self, ttl: Duration) -> Result<Vec<(String, CacheEntry)>>;
+}
+
+/// API keys are only ever stored, locally or shared, as this hash
+pub fn key_hash(key: &str) -> String {
+    blake3::hash(key.as_bytes()).to_hex().to_string()
+}
+
+/// Open the configured backend: `None` for `memory`, the database for `sqlite`
+pub fn open(settings: &Settings) -> Result<Option<Arc<SqliteStorage>>> {
+    if settings.storage_backend != StorageBackend::Sqlite {
+        return Ok(None);
+    }
+    let storage = Arc::new(SqliteStorage::open(sqlite::database_path(&settings.storage_dir))?);
+    let _ = ACTIVE_STORAGE.set(storage.clone());
+    Ok(Some(storage))
+}
+
+/// The storage opened at startup, if the backend is `sqlite`
+pub fn active() -> Option<Arc<SqliteStorage>> {
+    ACTIVE_STORAGE.get().cloned()
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct StorageStatus {
+    pub backend: &'static str,
+    pub path: Option<String>,
+    /// Size of the database and its write-ahead log
+    pub size_bytes: Option<u64>,
+    pub last_write: Option<String>,
+    /// Writes that failed since startup; each is also logged
+    pub failed_writes: u64,
+}
+
+pub fn status(settings: &Settings) -> StorageStatus {
+    match active() {
+        Some(storage) => storage.status(),
+        None => StorageStatus {
+            backend: settings.storage_backend.as_str(),
+            path: None,
+            size_bytes: None,
+            last_write: None,
+            failed_writes: 0,
+        },
+    }
+}
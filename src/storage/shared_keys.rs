@@ -0,0 +1,131 @@
+//! Key state shared by rujimi replicas behind one load balancer. Each replica keeps choosing
+//! keys from its own state; its changes are queued and pushed every few seconds, and what the
+//! other replicas pushed is merged back in. While the store is unreachable a replica runs on
+//! its local state and keeps the queued changes for the next sync that gets through.
+
+use anyhow::{Context, Result};
+use async_trait::async_trait;
+use redis::aio::ConnectionManager;
+use std::collections::HashMap;
+use std::time::Duration;
+use tokio::sync::Mutex;
+
+use crate::utils::http_client::redact_proxy_url;
+
+const PREFIX: &str = "rujimi:keys";
+/// Daily counters outlive their UTC day by a day, then Redis drops them
+const DAILY_COUNTER_TTL_SECS: i64 = 2 * 24 * 3600;
+/// A store that does not answer within this counts as unavailable for the current sync
+const STORE_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// One change made by a replica, named by key hash (see `storage::key_hash`)
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum KeyStateChange {
+    /// A call on the key, counted on the UTC day it was made
+    Used { key_hash: String, day: String, success: bool },
+    /// The key is skipped until this Unix time in milliseconds
+    CooldownUntil { key_hash: String, until_ms: i64 },
+    /// The key is out of the rotation until this Unix time in milliseconds
+    InvalidUntil { key_hash: String, until_ms: i64 },
+}
+
+/// What every replica has pushed, by key hash
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct SharedKeySnapshot {
+    /// Successful calls on the day pulled
+    pub daily_usage: HashMap<String, u32>,
+    /// Failed calls on the day pulled
+    pub daily_failures: HashMap<String, u32>,
+    /// Unix milliseconds each cooldown ends; may be in the past
+    pub cooldowns: HashMap<String, i64>,
+    /// Unix milliseconds each invalid mark ends; may be in the past
+    pub invalid: HashMap<String, i64>,
+}
+
+#[async_trait]
+pub trait SharedKeyState: Send + Sync + std::fmt::Debug {
+    async fn push(&self, changes: &[KeyStateChange]) -> Result<()>;
+    /// Counters for the UTC `day` (`YYYY-MM-DD`) and all cooldowns and invalid marks
+    async fn pull(&self, day: &str) -> Result<SharedKeySnapshot>;
+}
+
+/// Shared key state in Redis: a hash per day for usage and failures, and one hash each for
+/// cooldowns and invalid marks
+pub struct RedisKeyState {
+    url: String,
+    client: redis::Client,
+    /// Connected on first use and after a failed connect, so startup never waits on Redis
+    connection: Mutex<Option<ConnectionManager>>,
+}
+
+impl std::fmt::Debug for RedisKeyState {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("RedisKeyState").field("url", &redact_proxy_url(&self.url)).finish()
+    }
+}
+
+impl RedisKeyState {
+    pub fn open(url: &str) -> Result<Self> {
+        let client = redis::Client::open(url).context("Invalid `key_state_redis_url`")?;
+        Ok(Self { url: url.to_string(), client, connection: Mutex::new(None) })
+    }
+
+    async fn connection(&self) -> Result<ConnectionManager> {
+        let mut connection = self.connection.lock().await;
+        if let Some(connection) = connection.as_ref() {
+            return Ok(connection.clone());
+        }
+        let connected = tokio::time::timeout(STORE_TIMEOUT, ConnectionManager::new(self.client.clone()))
+            .await
+            .context("Timed out connecting to Redis")??;
+        *connection = Some(connected.clone());
+        Ok(connected)
+    }
+}
+
+fn daily_key(kind: &str, day: &str) -> String {
+    format!("{}:{}:{}", PREFIX, kind, day)
+}
+
+#[async_trait]
+impl SharedKeyState for RedisKeyState {
+    async fn push(&self, changes: &[KeyStateChange]) -> Result<()> {
+        if changes.is_empty() {
+            return Ok(());
+        }
+        let mut pipe = redis::pipe();
+        for change in changes {
+            match change {
+                KeyStateChange::Used { key_hash, day, success } => {
+                    let counter = daily_key(if *success { "usage" } else { "failures" }, day);
+                    pipe.hincr(&counter, key_hash, 1).ignore();
+                    pipe.expire(&counter, DAILY_COUNTER_TTL_SECS).ignore();
+                }
+                KeyStateChange::CooldownUntil { key_hash, until_ms } => {
+                    pipe.hset(format!("{}:cooldowns", PREFIX), key_hash, until_ms).ignore();
+                }
+                KeyStateChange::InvalidUntil { key_hash, until_ms } => {
+                    pipe.hset(format!("{}:invalid", PREFIX), key_hash, until_ms).ignore();
+                }
+            }
+        }
+        let mut connection = self.connection().await?;
+        tokio::time::timeout(STORE_TIMEOUT, pipe.query_async::<_, ()>(&mut connection))
+            .await
+            .context("Redis did not answer in time")??;
+        Ok(())
+    }
+
+    async fn pull(&self, day: &str) -> Result<SharedKeySnapshot> {
+        let mut connection = self.connection().await?;
+        let mut pipe = redis::pipe();
+        pipe.hgetall(daily_key("usage", day))
+            .hgetall(daily_key("failures", day))
+            .hgetall(format!("{}:cooldowns", PREFIX))
+            .hgetall(format!("{}:invalid", PREFIX));
+        let (daily_usage, daily_failures, cooldowns, invalid) = tokio::time::timeout(STORE_TIMEOUT, pipe.query_async(&mut connection))
+            .await
+            .context("Redis did not answer in time")??;
+        Ok(SharedKeySnapshot { daily_usage, daily_failures, cooldowns, invalid })
+    }
+}
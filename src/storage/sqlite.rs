@@ -0,0 +1,570 @@
+//! SQLite backend. Writes go through a queue to one writer thread, so request handlers never
+//! wait on the disk and writes land in the order they were made; reads only happen at
+//! startup and use their own connection.
+
+use anyhow::{Context, Result};
+use rusqlite::{params, Connection, OptionalExtension};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicI64, AtomicU64, Ordering};
+use std::sync::mpsc;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tracing::{info, warn};
+
+use super::{key_hash, CacheStore, CallRecordStore, KeyStateStore, StorageStatus};
+use crate::config::Settings;
+use crate::models::schemas::ChatCompletionResponse;
+use crate::utils::api_key::ApiKeyStats;
+use crate::utils::cache::CacheEntry;
+use crate::utils::stats::ApiCallRecord;
+
+const DATABASE_FILE: &str = "rujimi.db";
+
+/// Call records older than this are deleted when the database is opened
+const CALL_RECORD_RETENTION: Duration = Duration::from_secs(90 * 24 * 3600);
+/// Settings snapshots kept, newest first
+const MAX_SETTINGS_SNAPSHOTS: i64 = 50;
+/// Cached responses kept per cache key, as in memory
+const MAX_ENTRIES_PER_CACHE_KEY: i64 = 3;
+
+/// Schema changes in order; `PRAGMA user_version` records how many have been applied
+const MIGRATIONS: &[&str] = &[
+    "CREATE TABLE api_call_records (
+        id INTEGER PRIMARY KEY,
+        timestamp_ms INTEGER NOT NULL,
+        model TEXT NOT NULL,
+        prompt_tokens INTEGER NOT NULL,
+        completion_tokens INTEGER NOT NULL,
+        tokens_used INTEGER NOT NULL,
+        success INTEGER NOT NULL,
+        response_time_ms INTEGER NOT NULL,
+        ip_address TEXT,
+        error_type TEXT,
+        client_key TEXT,
+        client_id TEXT,
+        fallback_provider TEXT,
+        hedged INTEGER NOT NULL,
+        diagnostic INTEGER NOT NULL
+    );
+    CREATE INDEX idx_api_call_records_timestamp ON api_call_records (timestamp_ms);
+    CREATE TABLE key_stats (
+        key_hash TEXT PRIMARY KEY,
+        daily_usage INTEGER NOT NULL,
+        daily_failures INTEGER NOT NULL,
+        consecutive_failures INTEGER NOT NULL,
+        last_used_ms INTEGER NOT NULL
+    );
+    CREATE TABLE cache_entries (
+        id INTEGER PRIMARY KEY,
+        cache_key TEXT NOT NULL,
+        created_at_ms INTEGER NOT NULL,
+        response TEXT NOT NULL
+    );
+    CREATE INDEX idx_cache_entries_key ON cache_entries (cache_key);
+    CREATE TABLE settings_snapshots (
+        id INTEGER PRIMARY KEY,
+        saved_at_ms INTEGER NOT NULL,
+        settings TEXT NOT NULL
+    );",
+    "ALTER TABLE api_call_records ADD COLUMN reasoning_tokens INTEGER NOT NULL DEFAULT 0;",
+    "ALTER TABLE cache_entries ADD COLUMN ttl_secs INTEGER;",
+    "ALTER TABLE api_call_records ADD COLUMN ttfb_ms INTEGER;
+    ALTER TABLE api_call_records ADD COLUMN stream_duration_ms INTEGER;",
+    "ALTER TABLE api_call_records ADD COLUMN end_user TEXT;",
+    "ALTER TABLE api_call_records ADD COLUMN model_flags TEXT;",
+    "ALTER TABLE api_call_records ADD COLUMN forwarded_headers TEXT;",
+];
+
+pub fn database_path(storage_dir: &str) -> PathBuf {
+    Path::new(storage_dir).join(DATABASE_FILE)
+}
+
+enum Write {
+    Call(Box<ApiCallRecord>),
+    ClearCalls,
+    KeyStats { key_hash: String, stats: ApiKeyStats },
+    CacheEntry { cache_key: String, created_at_ms: i64, ttl_secs: Option<i64>, response: String },
+    RemoveCacheKey(String),
+    ClearCache,
+    Snapshot { saved_at_ms: i64, settings: String },
+    /// Answered once every earlier write is done
+    Flush(mpsc::SyncSender<()>),
+}
+
+#[derive(Debug)]
+pub struct SqliteStorage {
+    path: PathBuf,
+    reader: Mutex<Connection>,
+    writes: mpsc::Sender<Write>,
+    /// Unix milliseconds of the last successful write, 0 before the first
+    last_write_ms: Arc<AtomicI64>,
+    failed_writes: Arc<AtomicU64>,
+}
+
+impl std::fmt::Debug for Write {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            Write::Call(_) => "Call",
+            Write::ClearCalls => "ClearCalls",
+            Write::KeyStats { .. } => "KeyStats",
+            Write::CacheEntry { .. } => "CacheEntry",
+            Write::RemoveCacheKey(_) => "RemoveCacheKey",
+            Write::ClearCache => "ClearCache",
+            Write::Snapshot { .. } => "Snapshot",
+            Write::Flush(_) => "Flush",
+        })
+    }
+}
+
+impl SqliteStorage {
+    /// Open or create the database, apply pending migrations, drop expired call records
+    /// and start the writer thread
+    pub fn open(path: PathBuf) -> Result<Self> {
+        if let Some(dir) = path.parent() {
+            std::fs::create_dir_all(dir).with_context(|| format!("Failed to create storage directory: {:?}", dir))?;
+        }
+        let mut writer = connect(&path)?;
+        migrate(&mut writer)?;
+        let cutoff = to_millis(SystemTime::now() - CALL_RECORD_RETENTION);
+        let pruned = writer.execute("DELETE FROM api_call_records WHERE timestamp_ms < ?1", params![cutoff])?;
+        if pruned > 0 {
+            info!("Deleted {} call records older than {} days", pruned, CALL_RECORD_RETENTION.as_secs() / 86400);
+        }
+        let reader = connect(&path)?;
+
+        let (writes, queue) = mpsc::channel();
+        let last_write_ms = Arc::new(AtomicI64::new(0));
+        let failed_writes = Arc::new(AtomicU64::new(0));
+        let (last, failed) = (last_write_ms.clone(), failed_writes.clone());
+        std::thread::Builder::new()
+            .name("rujimi-sqlite".to_string())
+            .spawn(move || {
+                for write in queue {
+                    if let Write::Flush(done) = write {
+                        let _ = done.send(());
+                        continue;
+                    }
+                    let kind = format!("{:?}", write);
+                    match apply(&writer, write) {
+                        Ok(()) => last.store(to_millis(SystemTime::now()), Ordering::Relaxed),
+                        Err(e) => {
+                            failed.fetch_add(1, Ordering::Relaxed);
+                            warn!("SQLite storage write {} failed: {}", kind, e);
+                        }
+                    }
+                }
+            })
+            .context("Failed to start the SQLite writer thread")?;
+
+        info!("💽 SQLite storage opened at {:?}", path);
+        Ok(Self { path, reader: Mutex::new(reader), writes, last_write_ms, failed_writes })
+    }
+
+    fn queue(&self, write: Write) {
+        if self.writes.send(write).is_err() {
+            self.failed_writes.fetch_add(1, Ordering::Relaxed);
+            warn!("SQLite writer thread has stopped; write dropped");
+        }
+    }
+
+    /// Block until every write queued so far is on disk
+    pub fn flush(&self) {
+        let (done, wait) = mpsc::sync_channel(1);
+        self.queue(Write::Flush(done));
+        let _ = wait.recv_timeout(Duration::from_secs(10));
+    }
+
+    /// Keep a copy of the settings each time they are saved
+    pub fn save_snapshot(&self, settings: &Settings) {
+        match serde_json::to_string(settings) {
+            Ok(settings) => self.queue(Write::Snapshot { saved_at_ms: to_millis(SystemTime::now()), settings }),
+            Err(e) => warn!("Failed to serialize a settings snapshot: {}", e),
+        }
+    }
+
+    pub fn status(&self) -> StorageStatus {
+        let size = |path: PathBuf| std::fs::metadata(path).map(|metadata| metadata.len()).unwrap_or(0);
+        let mut wal = self.path.clone().into_os_string();
+        wal.push("-wal");
+        let last_write_ms = self.last_write_ms.load(Ordering::Relaxed);
+        StorageStatus {
+            backend: "sqlite",
+            path: Some(self.path.display().to_string()),
+            size_bytes: Some(size(self.path.clone()) + size(PathBuf::from(wal))),
+            last_write: chrono::DateTime::from_timestamp_millis(last_write_ms)
+                .filter(|_| last_write_ms > 0)
+                .map(|time| time.to_rfc3339()),
+            failed_writes: self.failed_writes.load(Ordering::Relaxed),
+        }
+    }
+}
+
+impl CallRecordStore for SqliteStorage {
+    fn append_call(&self, record: &ApiCallRecord) {
+        self.queue(Write::Call(Box::new(record.clone())));
+    }
+
+    fn load_calls(&self, since: SystemTime) -> Result<Vec<ApiCallRecord>> {
+        let reader = self.reader.lock().unwrap_or_else(|e| e.into_inner());
+        let mut statement = reader.prepare(
+            "SELECT timestamp_ms, model, prompt_tokens, completion_tokens, tokens_used, success, response_time_ms,
+                    ip_address, error_type, client_key, client_id, fallback_provider, hedged, diagnostic, reasoning_tokens,
+                    ttfb_ms, stream_duration_ms, end_user, model_flags, forwarded_headers
+             FROM api_call_records WHERE timestamp_ms >= ?1 ORDER BY timestamp_ms, id",
+        )?;
+        let records = statement.query_map(params![to_millis(since)], |row| {
+            Ok(ApiCallRecord {
+                timestamp: from_millis(row.get(0)?),
+                model: row.get(1)?,
+                prompt_tokens: row.get(2)?,
+                completion_tokens: row.get(3)?,
+                tokens_used: row.get(4)?,
+                success: row.get(5)?,
+                response_time_ms: row.get::<_, i64>(6)? as u64,
+                ip_address: row.get(7)?,
+                error_type: row.get(8)?,
+                client_key: row.get(9)?,
+                client_id: row.get(10)?,
+                fallback_provider: row.get(11)?,
+                hedged: row.get(12)?,
+                diagnostic: row.get(13)?,
+                reasoning_tokens: row.get(14)?,
+                ttfb_ms: row.get::<_, Option<i64>>(15)?.map(|ms| ms as u64),
+                stream_duration_ms: row.get::<_, Option<i64>>(16)?.map(|ms| ms as u64),
+                end_user: row.get(17)?,
+                model_flags: row
+                    .get::<_, Option<String>>(18)?
+                    .map(|flags| flags.split(',').map(str::to_string).collect())
+                    .unwrap_or_default(),
+                forwarded_headers: row
+                    .get::<_, Option<String>>(19)?
+                    .map(|names| names.split(',').map(str::to_string).collect())
+                    .unwrap_or_default(),
+            })
+        })?;
+        Ok(records.collect::<Result<_, _>>()?)
+    }
+
+    fn clear_calls(&self) {
+        self.queue(Write::ClearCalls);
+    }
+}
+
+impl KeyStateStore for SqliteStorage {
+    fn save_key_stats(&self, key: &str, stats: &ApiKeyStats) {
+        self.queue(Write::KeyStats { key_hash: key_hash(key), stats: stats.clone() });
+    }
+
+    fn load_key_stats(&self, keys: &[String]) -> Result<Vec<(String, ApiKeyStats)>> {
+        let reader = self.reader.lock().unwrap_or_else(|e| e.into_inner());
+        let mut statement = reader.prepare(
+            "SELECT daily_usage, daily_failures, consecutive_failures, last_used_ms FROM key_stats WHERE key_hash = ?1",
+        )?;
+        let today = chrono::Utc::now().date_naive();
+        let mut loaded = Vec::new();
+        for key in keys {
+            let stats = statement
+                .query_row(params![key_hash(key)], |row| {
+                    Ok(ApiKeyStats {
+                        daily_usage: row.get(0)?,
+                        daily_failures: row.get(1)?,
+                        consecutive_failures: row.get(2)?,
+                        last_used: chrono::DateTime::from_timestamp_millis(row.get(3)?).unwrap_or_default(),
+                    })
+                })
+                .optional()?;
+            if let Some(mut stats) = stats {
+                // Daily counters from an earlier day were reset while rujimi was down
+                if stats.last_used.date_naive() != today {
+                    stats.daily_usage = 0;
+                    stats.daily_failures = 0;
+                }
+                loaded.push((key.clone(), stats));
+            }
+        }
+        Ok(loaded)
+    }
+}
+
+impl CacheStore for SqliteStorage {
+    fn put_entry(&self, cache_key: &str, entry: &CacheEntry) {
+        match serde_json::to_string(&entry.response) {
+            Ok(response) => self.queue(Write::CacheEntry {
+                cache_key: cache_key.to_string(),
+                created_at_ms: to_millis(entry.created_at),
+                ttl_secs: entry.ttl.map(|ttl| ttl.as_secs() as i64),
+                response,
+            }),
+            Err(e) => warn!("Failed to serialize a cached response: {}", e),
+        }
+    }
+
+    fn remove_key(&self, cache_key: &str) {
+        self.queue(Write::RemoveCacheKey(cache_key.to_string()));
+    }
+
+    fn clear_entries(&self) {
+        self.queue(Write::ClearCache);
+    }
+
+    fn load_entries(&self, ttl: Duration) -> Result<Vec<(String, CacheEntry)>> {
+        let reader = self.reader.lock().unwrap_or_else(|e| e.into_inner());
+        let cutoff = to_millis(SystemTime::now().checked_sub(ttl).unwrap_or(UNIX_EPOCH));
+        let mut statement = reader.prepare(
+            "SELECT cache_key, created_at_ms, ttl_secs, response FROM cache_entries WHERE created_at_ms >= ?1 ORDER BY created_at_ms, id",
+        )?;
+        let rows = statement.query_map(params![cutoff], |row| {
+            Ok((row.get::<_, String>(0)?, row.get::<_, i64>(1)?, row.get::<_, Option<i64>>(2)?, row.get::<_, String>(3)?))
+        })?;
+
+        let mut entries = Vec::new();
+        for row in rows {
+            let (cache_key, created_at_ms, ttl_secs, response) = row?;
+            match serde_json::from_str::<ChatCompletionResponse>(&response) {
+                Ok(response) => {
+                    let mut entry = CacheEntry::new(response);
+                    entry.created_at = from_millis(created_at_ms);
+                    entry.ttl = ttl_secs.map(|secs| Duration::from_secs(secs.max(0) as u64));
+                    // Entries with their own shorter lifetime may have run out already
+                    if !entry.is_expired(ttl) {
+                        entries.push((cache_key, entry));
+                    }
+                }
+                Err(e) => warn!("Skipping a stored cache entry that no longer parses: {}", e),
+            }
+        }
+        Ok(entries)
+    }
+}
+
+fn connect(path: &Path) -> Result<Connection> {
+    let connection = Connection::open(path).with_context(|| format!("Failed to open SQLite database {:?}", path))?;
+    connection.busy_timeout(Duration::from_secs(5))?;
+    connection.pragma_update(None, "journal_mode", "WAL")?;
+    connection.pragma_update(None, "synchronous", "NORMAL")?;
+    Ok(connection)
+}
+
+fn migrate(connection: &mut Connection) -> Result<()> {
+    let applied: usize = connection.query_row("PRAGMA user_version", [], |row| row.get::<_, i64>(0))? as usize;
+    if applied > MIGRATIONS.len() {
+        anyhow::bail!(
+            "SQLite database schema version {} is newer than this build supports ({})",
+            applied,
+            MIGRATIONS.len()
+        );
+    }
+    for (index, migration) in MIGRATIONS.iter().enumerate().skip(applied) {
+        let transaction = connection.transaction()?;
+        transaction.execute_batch(migration).with_context(|| format!("SQLite migration {} failed", index + 1))?;
+        transaction.pragma_update(None, "user_version", (index + 1) as i64)?;
+        transaction.commit()?;
+        info!("Applied SQLite migration {}", index + 1);
+    }
+    Ok(())
+}
+
+fn apply(connection: &Connection, write: Write) -> rusqlite::Result<()> {
+    match write {
+        Write::Call(record) => {
+            connection.execute(
+                "INSERT INTO api_call_records (timestamp_ms, model, prompt_tokens, completion_tokens, tokens_used, success,
+                     response_time_ms, ip_address, error_type, client_key, client_id, fallback_provider, hedged, diagnostic,
+                     reasoning_tokens, ttfb_ms, stream_duration_ms, end_user, model_flags, forwarded_headers)
+                 VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10, ?11, ?12, ?13, ?14, ?15, ?16, ?17, ?18, ?19, ?20)",
+                params![
+                    to_millis(record.timestamp),
+                    record.model,
+                    record.prompt_tokens,
+                    record.completion_tokens,
+                    record.tokens_used,
+                    record.success,
+                    record.response_time_ms as i64,
+                    record.ip_address,
+                    record.error_type,
+                    record.client_key,
+                    record.client_id,
+                    record.fallback_provider,
+                    record.hedged,
+                    record.diagnostic,
+                    record.reasoning_tokens,
+                    record.ttfb_ms.map(|ms| ms as i64),
+                    record.stream_duration_ms.map(|ms| ms as i64),
+                    record.end_user,
+                    (!record.model_flags.is_empty()).then(|| record.model_flags.join(",")),
+                    (!record.forwarded_headers.is_empty()).then(|| record.forwarded_headers.join(",")),
+                ],
+            )?;
+        }
+        Write::ClearCalls => {
+            connection.execute("DELETE FROM api_call_records", [])?;
+        }
+        Write::KeyStats { key_hash, stats } => {
+            connection.execute(
+                "INSERT INTO key_stats (key_hash, daily_usage, daily_failures, consecutive_failures, last_used_ms)
+                 VALUES (?1, ?2, ?3, ?4, ?5)
+                 ON CONFLICT (key_hash) DO UPDATE SET daily_usage = ?2, daily_failures = ?3,
+                     consecutive_failures = ?4, last_used_ms = ?5",
+                params![
+                    key_hash,
+                    stats.daily_usage,
+                    stats.daily_failures,
+                    stats.consecutive_failures,
+                    stats.last_used.timestamp_millis(),
+                ],
+            )?;
+        }
+        Write::CacheEntry { cache_key, created_at_ms, ttl_secs, response } => {
+            connection.execute(
+                "INSERT INTO cache_entries (cache_key, created_at_ms, ttl_secs, response) VALUES (?1, ?2, ?3, ?4)",
+                params![cache_key, created_at_ms, ttl_secs, response],
+            )?;
+            connection.execute(
+                "DELETE FROM cache_entries WHERE cache_key = ?1 AND id NOT IN
+                     (SELECT id FROM cache_entries WHERE cache_key = ?1 ORDER BY id DESC LIMIT ?2)",
+                params![cache_key, MAX_ENTRIES_PER_CACHE_KEY],
+            )?;
+        }
+        Write::RemoveCacheKey(cache_key) => {
+            connection.execute("DELETE FROM cache_entries WHERE cache_key = ?1", params![cache_key])?;
+        }
+        Write::ClearCache => {
+            connection.execute("DELETE FROM cache_entries", [])?;
+        }
+        Write::Snapshot { saved_at_ms, settings } => {
+            connection.execute(
+                "INSERT INTO settings_snapshots (saved_at_ms, settings) VALUES (?1, ?2)",
+                params![saved_at_ms, settings],
+            )?;
+            connection.execute(
+                "DELETE FROM settings_snapshots WHERE id NOT IN (SELECT id FROM settings_snapshots ORDER BY id DESC LIMIT ?1)",
+                params![MAX_SETTINGS_SNAPSHOTS],
+            )?;
+        }
+        Write::Flush(_) => {}
+    }
+    Ok(())
+}
+
+fn to_millis(time: SystemTime) -> i64 {
+    time.duration_since(UNIX_EPOCH).unwrap_or_default().as_millis() as i64
+}
+
+fn from_millis(millis: i64) -> SystemTime {
+    UNIX_EPOCH + Duration::from_millis(millis.max(0) as u64)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn record(model: &str, age: Duration, success: bool) -> ApiCallRecord {
+        ApiCallRecord {
+            timestamp: SystemTime::now() - age,
+            model: model.to_string(),
+            prompt_tokens: 3,
+            completion_tokens: 4,
+            tokens_used: 7,
+            reasoning_tokens: 2,
+            success,
+            response_time_ms: 120,
+            ip_address: Some("10.0.0.1".to_string()),
+            error_type: (!success).then(|| "upstream_timeout".to_string()),
+            client_key: Some("team".to_string()),
+            client_id: None,
+            fallback_provider: None,
+            hedged: true,
+            diagnostic: false,
+            ttfb_ms: success.then_some(45),
+            stream_duration_ms: success.then_some(900),
+            end_user: success.then(|| "5d41402abc4b2a76".to_string()),
+            model_flags: if success { vec!["thinking".to_string(), "search".to_string()] } else { Vec::new() },
+            forwarded_headers: if success { vec!["x-goog-user-project".to_string()] } else { Vec::new() },
+        }
+    }
+
+    fn response(content: &str) -> ChatCompletionResponse {
+        serde_json::from_value(serde_json::json!({
+            "id": "chatcmpl-1", "object": "chat.completion", "created": 0, "model": "gemini-2.0-flash",
+            "choices": [{"index": 0, "message": {"role": "assistant", "content": content}, "finish_reason": "stop"}]
+        }))
+        .unwrap()
+    }
+
+    #[test]
+    fn test_records_and_key_stats_survive_reopening() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = database_path(dir.path().to_str().unwrap());
+
+        let storage = SqliteStorage::open(path.clone()).unwrap();
+        storage.append_call(&record("gemini-2.0-flash", Duration::from_secs(60), true));
+        storage.append_call(&record("gemini-1.5-pro", Duration::from_secs(30), false));
+        storage.append_call(&record("gemini-1.5-pro", Duration::from_secs(100 * 24 * 3600), true));
+        let stats = ApiKeyStats { daily_usage: 7, daily_failures: 2, consecutive_failures: 1, ..Default::default() };
+        storage.save_key_stats("AIza-first", &stats);
+        storage.save_snapshot(&Settings::default());
+        storage.flush();
+        assert!(storage.status().last_write.is_some());
+        drop(storage);
+
+        // Reopening runs no migration twice and drops records past the retention
+        let storage = SqliteStorage::open(path).unwrap();
+        let records = storage.load_calls(SystemTime::now() - Duration::from_secs(7 * 24 * 3600)).unwrap();
+        assert_eq!(records.iter().map(|r| r.model.as_str()).collect::<Vec<_>>(), ["gemini-2.0-flash", "gemini-1.5-pro"]);
+        assert_eq!(records[1].error_type.as_deref(), Some("upstream_timeout"));
+        assert!(records[0].hedged && records[0].success);
+        assert_eq!(records[0].reasoning_tokens, 2);
+        assert_eq!((records[0].ttfb_ms, records[0].stream_duration_ms), (Some(45), Some(900)));
+        assert_eq!((records[1].ttfb_ms, records[1].stream_duration_ms), (None, None));
+        assert_eq!(records[0].end_user.as_deref(), Some("5d41402abc4b2a76"));
+        assert_eq!(records[0].model_flags, ["thinking", "search"]);
+        assert!(records[1].model_flags.is_empty());
+        assert_eq!(records[0].forwarded_headers, ["x-goog-user-project"]);
+        assert!(records[1].forwarded_headers.is_empty());
+        assert!(storage.load_calls(UNIX_EPOCH).unwrap().len() == 2);
+
+        let keys = vec!["AIza-first".to_string(), "AIza-second".to_string()];
+        let loaded = storage.load_key_stats(&keys).unwrap();
+        assert_eq!(loaded.len(), 1);
+        assert_eq!((loaded[0].1.daily_usage, loaded[0].1.daily_failures, loaded[0].1.consecutive_failures), (7, 2, 1));
+        let snapshots: i64 = storage.reader.lock().unwrap().query_row("SELECT COUNT(*) FROM settings_snapshots", [], |row| row.get(0)).unwrap();
+        assert_eq!(snapshots, 1);
+
+        storage.clear_calls();
+        storage.flush();
+        assert!(storage.load_calls(UNIX_EPOCH).unwrap().is_empty());
+        let status = storage.status();
+        assert_eq!(status.backend, "sqlite");
+        assert!(status.size_bytes.unwrap() > 0);
+    }
+
+    #[test]
+    fn test_cache_entries_keep_the_newest_three() {
+        let dir = tempfile::tempdir().unwrap();
+        let storage = SqliteStorage::open(database_path(dir.path().to_str().unwrap())).unwrap();
+        for i in 0..5 {
+            storage.put_entry("key-a", &CacheEntry::new(response(&format!("answer {}", i))));
+        }
+        let mut old = CacheEntry::new(response("stale"));
+        old.created_at = SystemTime::now() - Duration::from_secs(7200);
+        storage.put_entry("key-b", &old);
+        let mut short_lived = CacheEntry::new(response("short-lived"));
+        short_lived.created_at = SystemTime::now() - Duration::from_secs(120);
+        short_lived.ttl = Some(Duration::from_secs(60));
+        storage.put_entry("key-d", &short_lived);
+        storage.put_entry("key-c", &CacheEntry::new(response("removed")));
+        storage.remove_key("key-c");
+        storage.flush();
+
+        let entries = storage.load_entries(Duration::from_secs(3600)).unwrap();
+        let contents: Vec<String> = entries
+            .iter()
+            .map(|(key, entry)| format!("{} {}", key, entry.response.choices[0].message.content.as_ref().unwrap().as_str().unwrap()))
+            .collect();
+        assert_eq!(contents, ["key-a answer 2", "key-a answer 3", "key-a answer 4"]);
+
+        storage.clear_entries();
+        storage.flush();
+        assert!(storage.load_entries(Duration::from_secs(3600)).unwrap().is_empty());
+    }
+}
@@ -0,0 +1,33 @@
+//! Fixtures for tests that drive the app through its routes
+
+use std::sync::Arc;
+
+use crate::config::{LiveSettings, Settings};
+use crate::services::gemini::GeminiClient;
+use crate::services::openai::OpenAIClient;
+use crate::utils::circuit_breaker::ModelCircuits;
+use crate::utils::live_stats::LiveStats;
+use crate::utils::{ApiKeyManager, ApiStatsManager, AuthState, ResponseCacheManager};
+use crate::AppState;
+
+/// Default settings, no API keys and no upstream; the password is `123`
+pub fn test_state() -> AppState {
+    let settings = Arc::new(Settings::default());
+    let live_settings = LiveSettings::new(settings.clone());
+    AppState {
+        settings: live_settings.clone(),
+        key_manager: Arc::new(ApiKeyManager::new(settings.clone())),
+        cache_manager: Arc::new(ResponseCacheManager::new(settings.clone())),
+        stats_manager: Arc::new(ApiStatsManager::new()),
+        gemini_client: Arc::new(GeminiClient::new(settings.clone())),
+        openai_client: Arc::new(OpenAIClient::new(settings.clone())),
+        model_circuits: Arc::new(ModelCircuits::new(&settings)),
+        live_stats: Arc::new(LiveStats::new()),
+        dashboard_sections: Arc::new(crate::utils::dashboard_sections::DashboardSections::new()),
+        fair_queue: Arc::new(crate::utils::fair_queue::FairQueue::new(&settings)),
+        auth_state: Arc::new(AuthState::new(live_settings)),
+        readiness: Arc::new(Default::default()),
+        vertex_enabled: false,
+        audit_log: None,
+    }
+}
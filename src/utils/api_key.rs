@@ -1,18 +1,48 @@
 use anyhow::{Context, Result};
 use dashmap::DashMap;
+use moka::{future::Cache, policy::EvictionPolicy};
 use rand::seq::SliceRandom;
-use std::collections::VecDeque;
-use std::sync::Arc;
-use tokio::sync::RwLock;
+use std::collections::{HashMap, VecDeque};
+use std::future::Future;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio::sync::{Notify, RwLock};
 use tracing::{info, warn};
 
-use crate::config::Settings;
+use crate::config::{ConfigManager, Settings};
+use crate::services::mock_upstream::MOCK_API_KEY;
+use crate::storage::shared_keys::{KeyStateChange, SharedKeySnapshot, SharedKeyState};
+use crate::storage::{key_hash, KeyStateStore};
+use crate::utils::clock::{system_clock, SharedClock};
+use crate::utils::error_handling::{is_quota_error, GeminiError};
+use crate::utils::http_client::build_upstream_client;
+
+/// Conversations remembered by sticky key assignment before the least recent is forgotten
+const MAX_PINNED_SESSIONS: u64 = 10_000;
+
+/// How long a key is skipped after Gemini rate-limits it
+pub const KEY_COOLDOWN: Duration = Duration::from_secs(60);
+
+/// How often key state is exchanged with the other replicas
+pub const KEY_STATE_SYNC_INTERVAL: Duration = Duration::from_secs(2);
+
+/// Changes kept for other replicas while the shared store is unreachable; the oldest go first
+const MAX_PENDING_KEY_CHANGES: usize = 10_000;
+
+/// How long a key marked invalid stays out of every replica's rotation
+const SHARED_INVALID_TTL: Duration = Duration::from_secs(24 * 3600);
+
+/// Requests allowed to wait for a key at once; later ones fail straight away
+const MAX_KEY_WAITERS: usize = 256;
 
 #[derive(Debug, Clone)]
 pub struct ApiKeyStats {
     pub daily_usage: u32,
     pub last_used: chrono::DateTime<chrono::Utc>,
     pub consecutive_failures: u32,
+    /// Failed calls since the daily reset; `daily_usage` counts the successful ones
+    pub daily_failures: u32,
 }
 
 impl Default for ApiKeyStats {
@@ -21,7 +51,36 @@ impl Default for ApiKeyStats {
             daily_usage: 0,
             last_used: chrono::Utc::now(),
             consecutive_failures: 0,
+            daily_failures: 0,
+        }
+    }
+}
+
+/// Requests-per-minute budget of one key, refilled continuously
+#[derive(Debug, Clone, Copy)]
+struct RpmBucket {
+    tokens: f64,
+    refilled: Instant,
+    rpm: u32,
+}
+
+impl RpmBucket {
+    fn full(rpm: u32, now: Instant) -> Self {
+        Self { tokens: rpm as f64, refilled: now, rpm }
+    }
+
+    fn tokens_at(&self, now: Instant) -> f64 {
+        let refill = now.duration_since(self.refilled).as_secs_f64() * self.rpm as f64 / 60.0;
+        (self.tokens + refill).min(self.rpm as f64)
+    }
+
+    /// Time until a token is available again, zero when one already is
+    fn refill_in(&self, now: Instant) -> Duration {
+        let missing = 1.0 - self.tokens_at(now);
+        if missing <= 0.0 {
+            return Duration::ZERO;
         }
+        Duration::from_secs_f64(missing * 60.0 / self.rpm as f64)
     }
 }
 
@@ -31,6 +90,32 @@ pub struct ApiKeyManager {
     available_keys: Arc<RwLock<VecDeque<String>>>,
     key_stats: Arc<DashMap<String, ApiKeyStats>>,
     invalid_keys: Arc<RwLock<Vec<String>>>,
+    /// Conversation hash to the key serving it, when `sticky_keys` is on
+    pinned_sessions: Cache<String, String>,
+    /// Keys skipped by the rotation until the given instant
+    cooldowns: Arc<DashMap<String, Instant>>,
+    /// Per-key token buckets enforcing the per-key requests per minute
+    rpm_buckets: Arc<DashMap<String, RpmBucket>>,
+    key_freed: Arc<Notify>,
+    waiters: Arc<AtomicUsize>,
+    /// Durable copy of the per-key counters when a storage backend is configured
+    store: Option<Arc<dyn KeyStateStore>>,
+    /// Key state shared with other replicas, when `key_state_redis_url` is set
+    shared: Option<Arc<dyn SharedKeyState>>,
+    /// Changes not yet pushed to `shared`
+    pending_changes: Arc<Mutex<VecDeque<KeyStateChange>>>,
+    /// UTC day the daily counters are counting
+    usage_day: Arc<Mutex<chrono::NaiveDate>>,
+    clock: SharedClock,
+}
+
+/// Counts a request in `waiters` for as long as it waits, including when it is cancelled
+struct WaitSlot<'a>(&'a AtomicUsize);
+
+impl Drop for WaitSlot<'_> {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::SeqCst);
+    }
 }
 
 impl ApiKeyManager {
@@ -40,13 +125,128 @@ impl ApiKeyManager {
             available_keys: Arc::new(RwLock::new(VecDeque::new())),
             key_stats: Arc::new(DashMap::new()),
             invalid_keys: Arc::new(RwLock::new(Vec::new())),
+            pinned_sessions: Cache::builder()
+                .max_capacity(MAX_PINNED_SESSIONS)
+                .eviction_policy(EvictionPolicy::lru())
+                .build(),
+            cooldowns: Arc::new(DashMap::new()),
+            rpm_buckets: Arc::new(DashMap::new()),
+            key_freed: Arc::new(Notify::new()),
+            waiters: Arc::new(AtomicUsize::new(0)),
+            store: None,
+            shared: None,
+            pending_changes: Arc::new(Mutex::new(VecDeque::new())),
+            usage_day: Arc::new(Mutex::new(chrono::Utc::now().date_naive())),
+            clock: system_clock(),
+        }
+    }
+
+    pub fn with_store(mut self, store: Arc<dyn KeyStateStore>) -> Self {
+        self.store = Some(store);
+        self
+    }
+
+    pub fn with_shared_state(mut self, shared: Arc<dyn SharedKeyState>) -> Self {
+        self.shared = Some(shared);
+        self
+    }
+
+    pub fn with_clock(mut self, clock: SharedClock) -> Self {
+        self.usage_day = Arc::new(Mutex::new(clock.now_utc().date_naive()));
+        self.clock = clock;
+        self
+    }
+
+    /// Queue a change for the other replicas
+    fn share(&self, change: KeyStateChange) {
+        if self.shared.is_none() {
+            return;
+        }
+        let mut pending = self.pending_changes.lock().unwrap_or_else(|e| e.into_inner());
+        pending.push_back(change);
+        while pending.len() > MAX_PENDING_KEY_CHANGES {
+            pending.pop_front();
+        }
+    }
+
+    /// Push the queued changes and merge in what the other replicas pushed. When the store
+    /// is unreachable the changes stay queued for the next sync and local state is kept.
+    pub async fn sync_shared_state(&self) -> Result<()> {
+        let shared = match &self.shared {
+            Some(shared) => shared,
+            None => return Ok(()),
+        };
+        let changes: Vec<KeyStateChange> = self.pending_changes.lock().unwrap_or_else(|e| e.into_inner()).drain(..).collect();
+        if let Err(e) = shared.push(&changes).await {
+            // Back ahead of the changes queued meanwhile, so they are pushed in order
+            let mut pending = self.pending_changes.lock().unwrap_or_else(|e| e.into_inner());
+            for change in changes.into_iter().rev() {
+                pending.push_front(change);
+            }
+            while pending.len() > MAX_PENDING_KEY_CHANGES {
+                pending.pop_front();
+            }
+            return Err(e);
+        }
+        let snapshot = shared.pull(&utc_day(self.clock.now_utc())).await?;
+        self.merge_shared_state(&snapshot).await;
+        Ok(())
+    }
+
+    /// Take the larger daily counters, the later cooldown and any live invalid mark
+    async fn merge_shared_state(&self, snapshot: &SharedKeySnapshot) {
+        let now_ms = self.clock.now_utc().timestamp_millis();
+        let keys: Vec<String> = self.available_keys.read().await.iter().cloned().collect();
+        for key in keys {
+            let hash = key_hash(&key);
+            if snapshot.invalid.get(&hash).is_some_and(|until| *until > now_ms) {
+                warn!("API key {}... was marked invalid by another replica", &key[..8.min(key.len())]);
+                self.remove_invalid_key(&key).await;
+                continue;
+            }
+            if let Some(until_ms) = snapshot.cooldowns.get(&hash).filter(|until| **until > now_ms) {
+                let until = self.clock.instant() + Duration::from_millis((until_ms - now_ms) as u64);
+                let mut cooldown = self.cooldowns.entry(key.clone()).or_insert(until);
+                if *cooldown < until {
+                    *cooldown = until;
+                }
+            }
+            if let Some(mut stats) = self.key_stats.get_mut(&key) {
+                stats.daily_usage = stats.daily_usage.max(snapshot.daily_usage.get(&hash).copied().unwrap_or(0));
+                stats.daily_failures = stats.daily_failures.max(snapshot.daily_failures.get(&hash).copied().unwrap_or(0));
+            }
+        }
+    }
+
+    /// Sync with the other replicas every `KEY_STATE_SYNC_INTERVAL`, logging only when the
+    /// store becomes unreachable and when it is back
+    pub async fn start_shared_state_sync(self: Arc<Self>) {
+        let mut interval = tokio::time::interval(KEY_STATE_SYNC_INTERVAL);
+        let mut reachable = true;
+        loop {
+            interval.tick().await;
+            match self.sync_shared_state().await {
+                Ok(()) if !reachable => {
+                    info!("Shared key state store is reachable again");
+                    reachable = true;
+                }
+                Ok(()) => {}
+                Err(e) if reachable => {
+                    warn!("Shared key state store unavailable, using local key state: {:#}", e);
+                    reachable = false;
+                }
+                Err(_) => {}
+            }
         }
     }
 
     pub async fn initialize(&self) -> Result<()> {
         info!("Initializing API key manager...");
 
-        let valid_keys = self.settings.get_valid_api_keys();
+        let mut valid_keys = self.settings.get_valid_api_keys();
+        if valid_keys.is_empty() && self.settings.mock_upstream {
+            valid_keys.push(MOCK_API_KEY.to_string());
+        }
 
         if valid_keys.is_empty() {
             warn!("No valid API keys found in configuration");
@@ -55,32 +255,19 @@ impl ApiKeyManager {
 
         info!("Found {} API keys to validate", valid_keys.len());
 
-        // Test all keys in parallel and collect results
-        let mut valid_tested_keys = Vec::new();
-        let mut invalid_tested_keys = Vec::new();
-
-        let futures = valid_keys.iter().map(|key| {
-            let key = key.clone();
-            async move {
-                match self.test_api_key(&key).await {
-                    Ok(true) => (key, true),
-                    Ok(false) => (key, false),
-                    Err(e) => {
-                        warn!("Error testing API key {}: {}", &key[..8.min(key.len())], e);
-                        (key, false)
+        let (valid_tested_keys, invalid_tested_keys) = self.test_keys(&valid_keys).await;
+        for key in &valid_tested_keys {
+            self.key_stats.insert(key.clone(), ApiKeyStats::default());
+        }
+        if let Some(store) = &self.store {
+            match store.load_key_stats(&valid_tested_keys) {
+                Ok(stored) => {
+                    info!("Restored stats for {} API keys", stored.len());
+                    for (key, stats) in stored {
+                        self.key_stats.insert(key, stats);
                     }
                 }
-            }
-        });
-
-        let results = futures::future::join_all(futures).await;
-
-        for (key, is_valid) in results {
-            if is_valid {
-                valid_tested_keys.push(key.clone());
-                self.key_stats.insert(key, ApiKeyStats::default());
-            } else {
-                invalid_tested_keys.push(key);
+                Err(e) => warn!("Failed to restore API key stats: {}", e),
             }
         }
 
@@ -107,49 +294,297 @@ impl ApiKeyManager {
         Ok(())
     }
 
+    /// Switch to a new set of configured keys without a restart. Only keys not seen before
+    /// are tested; removed keys leave the rotation along with their stats and cooldowns.
+    /// Returns how many keys were added and removed.
+    pub async fn update_keys(&self, keys: Vec<String>) -> (usize, usize) {
+        let known: Vec<String> = {
+            let available_keys = self.available_keys.read().await;
+            let invalid_keys = self.invalid_keys.read().await;
+            available_keys.iter().chain(invalid_keys.iter()).cloned().collect()
+        };
+        let new_keys: Vec<String> = keys.iter().filter(|key| !known.contains(key)).cloned().collect();
+        let (valid, invalid) = self.test_keys(&new_keys).await;
+
+        let removed = {
+            let mut available_keys = self.available_keys.write().await;
+            let before = available_keys.len();
+            available_keys.retain(|key| keys.contains(key));
+            let removed = before - available_keys.len();
+            for key in &valid {
+                self.key_stats.insert(key.clone(), ApiKeyStats::default());
+                available_keys.push_back(key.clone());
+            }
+            removed
+        };
+        {
+            let mut invalid_keys = self.invalid_keys.write().await;
+            invalid_keys.retain(|key| keys.contains(key));
+            invalid_keys.extend(invalid);
+        }
+        self.key_stats.retain(|key, _| keys.contains(key));
+        self.cooldowns.retain(|key, _| keys.contains(key));
+        self.rpm_buckets.retain(|key, _| keys.contains(key));
+
+        info!("API keys updated: {} added, {} removed", valid.len(), removed);
+        (valid.len(), removed)
+    }
+
+    /// Test `keys` in parallel, splitting them into working and rejected keys
+    async fn test_keys(&self, keys: &[String]) -> (Vec<String>, Vec<String>) {
+        let futures = keys.iter().map(|key| async move {
+            match self.test_api_key(key).await {
+                Ok(is_valid) => (key.clone(), is_valid),
+                Err(e) => {
+                    warn!("Error testing API key {}: {}", &key[..8.min(key.len())], e);
+                    (key.clone(), false)
+                }
+            }
+        });
+
+        let (valid, invalid): (Vec<_>, Vec<_>) = futures::future::join_all(futures)
+            .await
+            .into_iter()
+            .partition(|(_, is_valid)| *is_valid);
+        (
+            valid.into_iter().map(|(key, _)| key).collect(),
+            invalid.into_iter().map(|(key, _)| key).collect(),
+        )
+    }
+
+    /// Next key in rotation. Keys cooling down or out of requests for the minute are skipped;
+    /// `None` when there are no keys or none of them can take a request now.
     pub async fn get_next_key(&self) -> Option<String> {
         let mut available_keys = self.available_keys.write().await;
+        let rpm = self.key_rpm(available_keys.len());
+        let now = self.clock.instant();
 
         // Try to find a key that hasn't exceeded daily limit
-        while let Some(key) = available_keys.pop_front() {
-            if let Some(stats) = self.key_stats.get(&key) {
-                if stats.daily_usage < self.settings.api_key_daily_limit {
-                    // Key is still within daily limit, use it
-                    available_keys.push_back(key.clone());
-                    return Some(key);
-                } else {
-                    // Key has exceeded daily limit, put it at the back
-                    available_keys.push_back(key);
-                    continue;
-                }
-            } else {
-                // No stats for this key, initialize and use it
+        for _ in 0..available_keys.len() {
+            let key = available_keys.pop_front()?;
+            available_keys.push_back(key.clone());
+            if self.is_cooling_down(&key) {
+                continue;
+            }
+            let within_daily_limit = self.key_stats.get(&key).map(|stats| stats.daily_usage < self.settings.api_key_daily_limit);
+            if within_daily_limit == Some(false) {
+                // Key has exceeded daily limit, it stays at the back
+                continue;
+            }
+            if !self.take_rpm_token(&key, rpm, now) {
+                continue;
+            }
+            if within_daily_limit.is_none() {
+                // No stats for this key yet
                 self.key_stats.insert(key.clone(), ApiKeyStats::default());
-                available_keys.push_back(key.clone());
+            }
+            return Some(key);
+        }
+
+        // If we get here, all keys have exceeded daily limit, are cooling down or are out of
+        // requests for the minute
+        let position = available_keys
+            .iter()
+            .position(|key| !self.is_cooling_down(key) && self.take_rpm_token(key, rpm, now))?;
+        warn!("All API keys have exceeded daily limits, recycling oldest key");
+        let key = available_keys.remove(position)?;
+        available_keys.push_back(key.clone());
+        Some(key)
+    }
+
+    /// Run `call` with `first_key`, moving on to the next key in rotation while the failure
+    /// is one another key may not have, for at most `max_attempts` calls and never twice on
+    /// the same key. Each key's outcome is recorded, except a network failure before Gemini
+    /// saw the request; the result is that of the last call.
+    pub async fn with_key_retry<T, F, Fut>(&self, first_key: String, max_attempts: usize, mut call: F) -> Result<T, GeminiError>
+    where
+        F: FnMut(String) -> Fut,
+        Fut: Future<Output = Result<T, GeminiError>>,
+    {
+        let mut tried = Vec::new();
+        let mut key = first_key;
+        loop {
+            match call(key.clone()).await {
+                Ok(value) => {
+                    self.mark_key_used(&key, true).await;
+                    return Ok(value);
+                }
+                // Already retried on the same key; another key would not fare better
+                Err(e) if e.is_connection_failure() => return Err(e),
+                Err(e) => {
+                    self.mark_key_failed(&key, &e).await;
+                    tried.push(key);
+                    if !e.is_key_failure() || tried.len() >= max_attempts {
+                        return Err(e);
+                    }
+                    match self.get_next_key().await {
+                        Some(next) if !tried.contains(&next) => {
+                            warn!("Retrying on another API key after: {}", e);
+                            key = next;
+                        }
+                        _ => return Err(e),
+                    }
+                }
+            }
+        }
+    }
+
+    /// Like `get_next_key`, but when every key is cooling down and one comes back within
+    /// `max_wait`, wait for it. `Err` holds the time until the soonest cooldown ends, if any.
+    pub async fn wait_for_key(&self, max_wait: Duration) -> Result<String, Option<Duration>> {
+        if let Some(key) = self.get_next_key().await {
+            return Ok(key);
+        }
+        let soonest = self.soonest_cooldown_end();
+        if soonest.is_none_or(|remaining| remaining > max_wait) {
+            return Err(soonest);
+        }
+        if self.waiters.fetch_add(1, Ordering::SeqCst) >= MAX_KEY_WAITERS {
+            self.waiters.fetch_sub(1, Ordering::SeqCst);
+            warn!("Key wait queue is full, failing the request");
+            return Err(soonest);
+        }
+        let _slot = WaitSlot(&self.waiters);
+
+        let deadline = Instant::now() + max_wait;
+        loop {
+            let freed = self.key_freed.notified();
+            if let Some(key) = self.get_next_key().await {
+                return Ok(key);
+            }
+            let soonest = self.soonest_cooldown_end();
+            let now = Instant::now();
+            if now >= deadline {
+                return Err(soonest);
+            }
+            let wake = soonest.map_or(deadline, |remaining| (now + remaining).min(deadline));
+            tokio::select! {
+                _ = freed => {}
+                _ = tokio::time::sleep_until(wake.into()) => {}
+            }
+        }
+    }
+
+    /// Requests currently waiting in `wait_for_key`
+    pub fn waiting_requests(&self) -> usize {
+        self.waiters.load(Ordering::SeqCst)
+    }
+
+    /// Time until the first key cooling down or out of requests for the minute is usable again
+    pub fn soonest_cooldown_end(&self) -> Option<Duration> {
+        let now = self.clock.instant();
+        let cooldowns = self.cooldowns
+            .iter()
+            .filter(|entry| *entry.value() > now)
+            .map(|entry| *entry.value() - now);
+        let refills = self.rpm_buckets
+            .iter()
+            .map(|entry| entry.value().refill_in(now))
+            .filter(|remaining| !remaining.is_zero());
+        cooldowns.chain(refills).min()
+    }
+
+    /// Requests per minute allowed on each key: `per_key_rpm`, or `max_requests_per_minute`
+    /// split across `key_count` keys; 0 when neither limit is set
+    fn key_rpm(&self, key_count: usize) -> u32 {
+        if self.settings.per_key_rpm > 0 {
+            return self.settings.per_key_rpm;
+        }
+        match key_count {
+            0 => 0,
+            count => self.settings.max_requests_per_minute.div_ceil(count as u32),
+        }
+    }
+
+    /// Take a request from `key`'s bucket; `false` when it has none left for now
+    fn take_rpm_token(&self, key: &str, rpm: u32, now: Instant) -> bool {
+        if rpm == 0 {
+            return true;
+        }
+        let mut bucket = self.rpm_buckets.entry(key.to_string()).or_insert_with(|| RpmBucket::full(rpm, now));
+        let tokens = RpmBucket { rpm, ..*bucket }.tokens_at(now);
+        *bucket = RpmBucket { tokens, refilled: now, rpm };
+        if tokens < 1.0 {
+            return false;
+        }
+        bucket.tokens -= 1.0;
+        true
+    }
+
+    /// Requests each key can send right away under its per-minute budget; keys that have
+    /// not sent any yet are left out, their bucket being full
+    pub fn rpm_tokens_left(&self) -> HashMap<String, u32> {
+        let now = self.clock.instant();
+        self.rpm_buckets
+            .iter()
+            .map(|entry| (entry.key().clone(), entry.value().tokens_at(now) as u32))
+            .collect()
+    }
+
+    fn is_cooling_down(&self, key: &str) -> bool {
+        self.cooldowns.get(key).is_some_and(|until| *until > self.clock.instant())
+    }
+
+    /// Key for one conversation: the key it is pinned to while that key stays healthy,
+    /// otherwise the next key in rotation, which becomes the new pin
+    pub async fn get_key_for_session(&self, session: &str) -> Option<String> {
+        if let Some(key) = self.pinned_sessions.get(session).await {
+            if self.is_healthy(&key).await {
                 return Some(key);
             }
         }
+        let key = self.get_next_key().await?;
+        self.pinned_sessions.insert(session.to_string(), key.clone()).await;
+        Some(key)
+    }
 
-        // If we get here, all keys have exceeded daily limit
-        if !available_keys.is_empty() {
-            warn!("All API keys have exceeded daily limits, recycling oldest key");
-            let key = available_keys.pop_front().unwrap();
-            available_keys.push_back(key.clone());
-            return Some(key);
+    /// Number of conversations pinned to each key
+    pub fn pinned_session_counts(&self) -> HashMap<String, usize> {
+        let mut counts = HashMap::new();
+        for (_, key) in self.pinned_sessions.iter() {
+            *counts.entry(key).or_insert(0) += 1;
         }
+        counts
+    }
 
-        None
+    /// Available, within its daily limit and without a failure since its last success
+    async fn is_healthy(&self, key: &str) -> bool {
+        let stats_ok = self.key_stats.get(key).is_some_and(|stats| {
+            stats.consecutive_failures == 0 && stats.daily_usage < self.settings.api_key_daily_limit
+        });
+        stats_ok && self.available_keys.read().await.iter().any(|k| k == key)
+    }
+
+    /// The `index`-th configured key, for pinning one while debugging; `None` when out of
+    /// range or marked invalid
+    pub async fn key_at(&self, index: usize) -> Option<String> {
+        let key = self.settings.get_valid_api_keys().into_iter().nth(index)?;
+        if self.invalid_keys.read().await.contains(&key) {
+            return None;
+        }
+        Some(key)
     }
 
     pub async fn mark_key_used(&self, key: &str, success: bool) {
+        self.share(KeyStateChange::Used { key_hash: key_hash(key), day: utc_day(self.clock.now_utc()), success });
         if let Some(mut stats) = self.key_stats.get_mut(key) {
-            stats.last_used = chrono::Utc::now();
+            stats.last_used = self.clock.now_utc();
 
             if success {
                 stats.daily_usage += 1;
                 stats.consecutive_failures = 0;
+                if let Some(store) = &self.store {
+                    store.save_key_stats(key, &stats);
+                }
+                if self.cooldowns.remove(key).is_some() {
+                    self.key_freed.notify_waiters();
+                }
             } else {
                 stats.consecutive_failures += 1;
+                stats.daily_failures += 1;
+                if let Some(store) = &self.store {
+                    store.save_key_stats(key, &stats);
+                }
 
                 // If a key fails too many times consecutively, mark it as invalid
                 if stats.consecutive_failures >= 5 {
@@ -160,7 +595,37 @@ impl ApiKeyManager {
         }
     }
 
+    /// `mark_key_used` for a failed call; a rate-limit error also puts the key in cooldown
+    pub async fn mark_key_failed(&self, key: &str, error: &(dyn std::fmt::Display + Sync)) {
+        if is_quota_error(&error.to_string()) {
+            warn!("Cooling down API key {}... for {:?}", &key[..8.min(key.len())], KEY_COOLDOWN);
+            self.cooldowns.insert(key.to_string(), self.clock.instant() + KEY_COOLDOWN);
+            self.share(KeyStateChange::CooldownUntil {
+                key_hash: key_hash(key),
+                until_ms: self.clock.now_utc().timestamp_millis() + KEY_COOLDOWN.as_millis() as i64,
+            });
+        }
+        self.mark_key_used(key, false).await;
+    }
+
+    /// `mark_key_failed` for a failed Gemini call; a network failure before Gemini saw the
+    /// request is not held against the key
+    pub async fn record_error(&self, key: &str, error: &GeminiError) {
+        if !error.is_connection_failure() {
+            self.mark_key_failed(key, error).await;
+        }
+    }
+
     pub async fn mark_key_invalid(&self, key: &str) {
+        self.share(KeyStateChange::InvalidUntil {
+            key_hash: key_hash(key),
+            until_ms: self.clock.now_utc().timestamp_millis() + SHARED_INVALID_TTL.as_millis() as i64,
+        });
+        self.remove_invalid_key(key).await;
+    }
+
+    /// Take an invalid key out of the rotation
+    async fn remove_invalid_key(&self, key: &str) {
         // Remove from available keys
         {
             let mut available_keys = self.available_keys.write().await;
@@ -185,7 +650,32 @@ impl ApiKeyManager {
         info!("Resetting daily usage for all API keys");
         for mut entry in self.key_stats.iter_mut() {
             entry.daily_usage = 0;
+            entry.daily_failures = 0;
+            if let Some(store) = &self.store {
+                store.save_key_stats(entry.key(), entry.value());
+            }
+        }
+    }
+
+    /// Clear the failure counters and cooldown of the `index`-th configured key, returning its
+    /// stats and remaining cooldown as they were. `None` when out of range or marked invalid.
+    pub fn reset_key_failures(&self, index: usize) -> Option<(String, ApiKeyStats, Option<Duration>)> {
+        let key = self.settings.get_valid_api_keys().into_iter().nth(index)?;
+        let before = {
+            let mut stats = self.key_stats.get_mut(&key)?;
+            let before = stats.clone();
+            stats.consecutive_failures = 0;
+            stats.daily_failures = 0;
+            if let Some(store) = &self.store {
+                store.save_key_stats(&key, &stats);
+            }
+            before
+        };
+        let cooldown = self.cooldowns.remove(&key).map(|(_, until)| until.saturating_duration_since(self.clock.instant()));
+        if cooldown.is_some() {
+            self.key_freed.notify_waiters();
         }
+        Some((key, before, cooldown.filter(|left| !left.is_zero())))
     }
 
     pub async fn available_keys_count(&self) -> usize {
@@ -213,8 +703,11 @@ impl ApiKeyManager {
     }
 
     async fn test_api_key(&self, api_key: &str) -> Result<bool> {
-        let client = reqwest::Client::new();
-        let url = "https://generativelanguage.googleapis.com/v1beta/models";
+        if self.settings.mock_upstream {
+            return Ok(true);
+        }
+        let client = build_upstream_client(&self.settings, std::time::Duration::from_secs(10))?;
+        let url = format!("{}/models", ConfigManager::get_gemini_base_url().await);
 
         let response = client
             .get(url)
@@ -239,28 +732,372 @@ impl ApiKeyManager {
         Ok(is_valid)
     }
 
+    /// Reset the daily counters once the UTC day has changed since they started counting;
+    /// `true` when they were reset
+    pub async fn roll_over_daily_usage(&self) -> bool {
+        let today = self.clock.now_utc().date_naive();
+        {
+            let mut usage_day = self.usage_day.lock().unwrap_or_else(|e| e.into_inner());
+            if *usage_day >= today {
+                return false;
+            }
+            *usage_day = today;
+        }
+        self.reset_daily_usage().await;
+        true
+    }
+
     // Background task to clean up expired daily usage
     pub async fn start_daily_cleanup_task(self: Arc<Self>) {
         let mut interval = tokio::time::interval(std::time::Duration::from_secs(3600)); // Check every hour
 
         loop {
             interval.tick().await;
+            self.roll_over_daily_usage().await;
+        }
+    }
+}
 
-            let now = chrono::Utc::now();
-            let mut reset_needed = false;
+/// The UTC day that daily counters belong to
+fn utc_day(now: chrono::DateTime<chrono::Utc>) -> String {
+    now.format("%Y-%m-%d").to_string()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::utils::clock::MockClock;
+
+    async fn manager_with_keys(keys: &[&str]) -> ApiKeyManager {
+        let manager = ApiKeyManager::new(Arc::new(Settings::default()));
+        manager.available_keys.write().await.extend(keys.iter().map(|key| key.to_string()));
+        manager
+    }
+
+    #[tokio::test]
+    async fn test_sessions_stick_to_a_healthy_key() {
+        let manager = manager_with_keys(&["key-a", "key-b", "key-c"]).await;
+
+        let first = manager.get_key_for_session("conversation-1").await.unwrap();
+        let second = manager.get_key_for_session("conversation-2").await.unwrap();
+        assert_ne!(first, second);
+        for _ in 0..3 {
+            assert_eq!(manager.get_key_for_session("conversation-1").await.as_ref(), Some(&first));
+            assert!(manager.get_next_key().await.is_some());
+        }
+
+        // A failure moves the conversation to another key, which it then sticks to
+        manager.mark_key_used(&first, false).await;
+        let moved = manager.get_key_for_session("conversation-1").await.unwrap();
+        assert_ne!(moved, first);
+        assert_eq!(manager.get_key_for_session("conversation-1").await, Some(moved.clone()));
+
+        manager.pinned_sessions.run_pending_tasks().await;
+        let counts = manager.pinned_session_counts();
+        assert_eq!(counts.values().sum::<usize>(), 2);
+        assert_eq!(counts.get(&first), None);
+    }
+
+    #[tokio::test]
+    async fn test_rate_limited_keys_cool_down() {
+        let manager = manager_with_keys(&["key-a", "key-b"]).await;
+        let rate_limited = anyhow::anyhow!("Gemini API error: 429 Too Many Requests");
+
+        manager.mark_key_failed("key-a", &rate_limited).await;
+        assert_eq!(manager.get_next_key().await.as_deref(), Some("key-b"));
+        assert_eq!(manager.get_next_key().await.as_deref(), Some("key-b"));
+
+        manager.mark_key_failed("key-b", &rate_limited).await;
+        assert_eq!(manager.get_next_key().await, None);
+        let retry_after = manager.soonest_cooldown_end().unwrap();
+        assert!(retry_after <= KEY_COOLDOWN && retry_after > KEY_COOLDOWN - Duration::from_secs(5));
+
+        // Not worth waiting for, so it fails straight away with the time to retry
+        let waited = manager.wait_for_key(Duration::from_millis(10)).await;
+        assert!(matches!(waited, Err(Some(_))));
+        assert_eq!(manager.waiting_requests(), 0);
+    }
 
-            // Check if we've crossed into a new day
-            for entry in self.key_stats.iter() {
-                let last_used = entry.value().last_used;
-                if now.date_naive() > last_used.date_naive() {
-                    reset_needed = true;
-                    break;
+    #[tokio::test]
+    async fn test_reset_key_failures_clears_counters_and_cooldown() {
+        let settings = Settings { gemini_api_keys: vec!["key-a".to_string(), "key-b".to_string()], ..Default::default() };
+        let manager = ApiKeyManager::new(Arc::new(settings));
+        manager.available_keys.write().await.extend(["key-a".to_string(), "key-b".to_string()]);
+        manager.key_stats.insert("key-b".to_string(), ApiKeyStats::default());
+        manager.mark_key_failed("key-b", &"429 RESOURCE_EXHAUSTED").await;
+        manager.mark_key_failed("key-b", &"500 internal").await;
+        assert!(manager.is_cooling_down("key-b") && !manager.is_healthy("key-b").await);
+
+        let (key, before, cooldown) = manager.reset_key_failures(1).unwrap();
+        assert_eq!(key, "key-b");
+        assert_eq!((before.consecutive_failures, before.daily_failures), (2, 2));
+        assert!(cooldown.is_some());
+        assert!(!manager.is_cooling_down("key-b") && manager.is_healthy("key-b").await);
+
+        // No stats yet, and out of range
+        assert!(manager.reset_key_failures(0).is_none());
+        assert!(manager.reset_key_failures(2).is_none());
+    }
+
+    #[tokio::test]
+    async fn test_cooldown_ends_with_the_clock() {
+        let clock = MockClock::new();
+        let manager = ApiKeyManager::new(Arc::new(Settings::default())).with_clock(clock.clone());
+        manager.available_keys.write().await.push_back("key-a".to_string());
+        manager.key_stats.insert("key-a".to_string(), ApiKeyStats::default());
+        manager.mark_key_failed("key-a", &"429 RESOURCE_EXHAUSTED").await;
+        assert_eq!(manager.get_next_key().await, None);
+        assert_eq!(manager.soonest_cooldown_end(), Some(KEY_COOLDOWN));
+
+        clock.advance(KEY_COOLDOWN - Duration::from_secs(1));
+        assert_eq!(manager.get_next_key().await, None);
+        clock.advance(Duration::from_secs(1));
+        assert_eq!(manager.get_next_key().await.as_deref(), Some("key-a"));
+    }
+
+    #[tokio::test]
+    async fn test_daily_limit_rolls_over_at_midnight() {
+        let clock = MockClock::at_utc("2026-03-01T23:59:30Z");
+        let settings = Settings { api_key_daily_limit: 1, ..Default::default() };
+        let manager = ApiKeyManager::new(Arc::new(settings)).with_clock(clock.clone());
+        manager.available_keys.write().await.extend(["key-a", "key-b"].map(String::from));
+        for key in ["key-a", "key-b"] {
+            manager.key_stats.insert(key.to_string(), ApiKeyStats::default());
+            manager.mark_key_used(key, true).await;
+        }
+        assert!(!manager.is_healthy("key-a").await && !manager.is_healthy("key-b").await);
+
+        // Still the same day: nothing to reset
+        clock.advance(Duration::from_secs(29));
+        assert!(!manager.roll_over_daily_usage().await);
+        assert_eq!(manager.key_stats.get("key-a").unwrap().daily_usage, 1);
+
+        // Past midnight the counters start over, once
+        clock.advance(Duration::from_secs(2));
+        assert!(manager.roll_over_daily_usage().await);
+        assert!(manager.is_healthy("key-a").await && manager.is_healthy("key-b").await);
+        manager.mark_key_used("key-a", true).await;
+        clock.advance(Duration::from_secs(3600));
+        assert!(!manager.roll_over_daily_usage().await);
+        assert_eq!(manager.key_stats.get("key-a").unwrap().daily_usage, 1);
+    }
+
+    #[tokio::test]
+    async fn test_per_key_rpm_smooths_a_burst() {
+        let clock = MockClock::new();
+        let manager = ApiKeyManager::new(Arc::new(Settings { per_key_rpm: 5, ..Settings::default() })).with_clock(clock.clone());
+        manager.available_keys.write().await.extend(["key-a", "key-b", "key-c"].map(String::from));
+        let manager = Arc::new(manager);
+
+        let burst = (0..20).map(|_| {
+            let manager = manager.clone();
+            tokio::spawn(async move { manager.get_next_key().await })
+        });
+        let keys: Vec<Option<String>> = futures::future::join_all(burst).await.into_iter().map(Result::unwrap).collect();
+        let mut per_key = HashMap::new();
+        for key in keys.iter().flatten() {
+            *per_key.entry(key.as_str()).or_insert(0) += 1;
+        }
+        assert_eq!(per_key, HashMap::from([("key-a", 5), ("key-b", 5), ("key-c", 5)]));
+        assert_eq!(keys.iter().filter(|key| key.is_none()).count(), 5);
+        assert_eq!(manager.rpm_tokens_left().get("key-a"), Some(&0));
+
+        // The next request is possible once a token is back, 60s / 5 later
+        assert_eq!(manager.soonest_cooldown_end(), Some(Duration::from_secs(12)));
+        clock.advance(Duration::from_secs(12));
+        assert_eq!(manager.rpm_tokens_left().get("key-a"), Some(&1));
+        assert!(manager.get_next_key().await.is_some());
+
+        // Without `per_key_rpm`, the global limit is split across the keys
+        assert_eq!(manager_with_keys(&[]).await.key_rpm(4), Settings::default().max_requests_per_minute.div_ceil(4));
+    }
+
+    #[tokio::test]
+    async fn test_wait_for_key_until_cooldown_ends() {
+        let manager = Arc::new(manager_with_keys(&["key-a"]).await);
+        manager.cooldowns.insert("key-a".to_string(), Instant::now() + Duration::from_millis(100));
+
+        let waiting = tokio::spawn({
+            let manager = manager.clone();
+            async move { manager.wait_for_key(Duration::from_secs(5)).await }
+        });
+        tokio::time::sleep(Duration::from_millis(20)).await;
+        assert_eq!(manager.waiting_requests(), 1);
+
+        assert_eq!(waiting.await.unwrap().as_deref(), Ok("key-a"));
+        assert_eq!(manager.waiting_requests(), 0);
+    }
+
+    #[tokio::test]
+    async fn test_key_retry_moves_on_from_failing_keys() {
+        let manager = manager_with_keys(&["key-a", "key-b", "key-c"]).await;
+        let first = manager.get_next_key().await.unwrap();
+        let calls = Mutex::new(Vec::new());
+
+        let result = manager
+            .with_key_retry(first, 5, |key| {
+                calls.lock().unwrap().push(key.clone());
+                async move {
+                    match key.as_str() {
+                        "key-a" => Err(GeminiError::RateLimited { retry_after: None, body: String::new() }),
+                        "key-b" => Err(GeminiError::Upstream { status: 503, body: "unavailable".to_string() }),
+                        _ => Ok(key),
+                    }
                 }
+            })
+            .await;
+        assert_eq!(result.unwrap(), "key-c");
+        assert_eq!(*calls.lock().unwrap(), ["key-a", "key-b", "key-c"]);
+        assert!(manager.is_cooling_down("key-a"));
+        assert_eq!(manager.key_stats.get("key-b").unwrap().consecutive_failures, 1);
+        assert_eq!(manager.key_stats.get("key-c").unwrap().daily_usage, 1);
+
+        // The caller's own mistake is not retried, and neither is a pinned key
+        let bad_request = || async { Err::<(), _>(GeminiError::InvalidRequest { message: "bad".to_string() }) };
+        let mut attempts = 0;
+        let result = manager.with_key_retry("key-c".to_string(), 5, |_| { attempts += 1; bad_request() }).await;
+        assert!(matches!(result, Err(GeminiError::InvalidRequest { .. })));
+        assert_eq!(attempts, 1);
+
+        // A connection that failed before Gemini saw the request neither rotates nor counts
+        let failures = manager.key_stats.get("key-c").unwrap().consecutive_failures;
+        let dropped = || async { Err::<(), _>(GeminiError::Connection("connection reset".to_string())) };
+        let mut attempts = 0;
+        let result = manager.with_key_retry("key-c".to_string(), 5, |_| { attempts += 1; dropped() }).await;
+        assert!(matches!(result, Err(GeminiError::Connection(_))));
+        assert_eq!(attempts, 1);
+        assert_eq!(manager.key_stats.get("key-c").unwrap().consecutive_failures, failures);
+
+        let unavailable = || async { Err::<(), _>(GeminiError::Upstream { status: 503, body: String::new() }) };
+        let mut attempts = 0;
+        let result = manager.with_key_retry("key-c".to_string(), 1, |_| { attempts += 1; unavailable() }).await;
+        assert!(result.is_err());
+        assert_eq!(attempts, 1);
+    }
+
+    #[tokio::test]
+    async fn test_update_keys_drops_removed_keys() {
+        let manager = manager_with_keys(&["key-a", "key-b"]).await;
+        manager.key_stats.insert("key-b".to_string(), ApiKeyStats::default());
+        manager.cooldowns.insert("key-b".to_string(), Instant::now() + KEY_COOLDOWN);
+
+        // Nothing new to test, so no key is sent upstream
+        assert_eq!(manager.update_keys(vec!["key-a".to_string()]).await, (0, 1));
+        for _ in 0..3 {
+            assert_eq!(manager.get_next_key().await.as_deref(), Some("key-a"));
+        }
+        assert!(manager.key_stats.get("key-b").is_none());
+        assert_eq!(manager.soonest_cooldown_end(), None);
+    }
+
+    /// In-process stand-in for the shared Redis that can be switched off
+    #[derive(Debug, Default)]
+    struct MockSharedState {
+        down: std::sync::atomic::AtomicBool,
+        days: Mutex<HashMap<String, SharedKeySnapshot>>,
+        marks: Mutex<SharedKeySnapshot>,
+    }
+
+    #[async_trait::async_trait]
+    impl SharedKeyState for MockSharedState {
+        async fn push(&self, changes: &[KeyStateChange]) -> Result<()> {
+            if self.down.load(Ordering::SeqCst) {
+                anyhow::bail!("connection refused");
             }
+            for change in changes {
+                match change.clone() {
+                    KeyStateChange::Used { key_hash, day, success } => {
+                        let mut days = self.days.lock().unwrap();
+                        let day = days.entry(day).or_default();
+                        let counters = if success { &mut day.daily_usage } else { &mut day.daily_failures };
+                        *counters.entry(key_hash).or_default() += 1;
+                    }
+                    KeyStateChange::CooldownUntil { key_hash, until_ms } => {
+                        self.marks.lock().unwrap().cooldowns.insert(key_hash, until_ms);
+                    }
+                    KeyStateChange::InvalidUntil { key_hash, until_ms } => {
+                        self.marks.lock().unwrap().invalid.insert(key_hash, until_ms);
+                    }
+                }
+            }
+            Ok(())
+        }
 
-            if reset_needed {
-                self.reset_daily_usage().await;
+        async fn pull(&self, day: &str) -> Result<SharedKeySnapshot> {
+            if self.down.load(Ordering::SeqCst) {
+                anyhow::bail!("connection refused");
             }
+            let mut snapshot = self.days.lock().unwrap().get(day).cloned().unwrap_or_default();
+            let marks = self.marks.lock().unwrap();
+            snapshot.cooldowns = marks.cooldowns.clone();
+            snapshot.invalid = marks.invalid.clone();
+            Ok(snapshot)
         }
     }
-}
\ No newline at end of file
+
+    async fn replica(keys: &[&str], shared: Arc<MockSharedState>) -> ApiKeyManager {
+        let settings = Arc::new(Settings { api_key_daily_limit: 3, ..Default::default() });
+        let manager = ApiKeyManager::new(settings).with_shared_state(shared);
+        for key in keys {
+            manager.available_keys.write().await.push_back(key.to_string());
+            manager.key_stats.insert(key.to_string(), ApiKeyStats::default());
+        }
+        manager
+    }
+
+    #[tokio::test]
+    async fn test_replicas_share_key_state() {
+        let shared = Arc::new(MockSharedState::default());
+        let first = replica(&["key-a", "key-b", "key-c"], shared.clone()).await;
+        let second = replica(&["key-a", "key-b", "key-c"], shared.clone()).await;
+
+        // The first replica uses up key-a and gets key-b rate-limited
+        for _ in 0..3 {
+            first.mark_key_used("key-a", true).await;
+        }
+        first.mark_key_failed("key-b", &anyhow::anyhow!("Gemini API error: 429 Too Many Requests")).await;
+        first.sync_shared_state().await.unwrap();
+        second.sync_shared_state().await.unwrap();
+        assert_eq!(second.key_stats.get("key-a").unwrap().daily_usage, 3);
+        assert!(second.is_cooling_down("key-b"));
+        for _ in 0..3 {
+            assert_eq!(second.get_next_key().await.as_deref(), Some("key-c"));
+        }
+
+        // Calls on both replicas add up
+        second.mark_key_used("key-c", true).await;
+        first.mark_key_used("key-c", true).await;
+        for replica in [&first, &second, &first] {
+            replica.sync_shared_state().await.unwrap();
+        }
+        assert_eq!(first.key_stats.get("key-c").unwrap().daily_usage, 2);
+
+        second.mark_key_invalid("key-c").await;
+        second.sync_shared_state().await.unwrap();
+        first.sync_shared_state().await.unwrap();
+        assert!(first.invalid_keys.read().await.contains(&"key-c".to_string()));
+        assert_eq!(first.available_keys_count().await, 2);
+    }
+
+    #[tokio::test]
+    async fn test_unreachable_shared_state_keeps_changes() {
+        let shared = Arc::new(MockSharedState::default());
+        let first = replica(&["key-a"], shared.clone()).await;
+        let second = replica(&["key-a"], shared.clone()).await;
+
+        shared.down.store(true, Ordering::SeqCst);
+        first.mark_key_used("key-a", true).await;
+        first.mark_key_used("key-a", false).await;
+        assert!(first.sync_shared_state().await.is_err());
+        // Local state carries on meanwhile
+        assert_eq!(first.get_next_key().await.as_deref(), Some("key-a"));
+        assert_eq!(first.pending_changes.lock().unwrap().len(), 2);
+
+        shared.down.store(false, Ordering::SeqCst);
+        first.sync_shared_state().await.unwrap();
+        second.sync_shared_state().await.unwrap();
+        assert!(first.pending_changes.lock().unwrap().is_empty());
+        let stats = second.key_stats.get("key-a").unwrap().clone();
+        assert_eq!((stats.daily_usage, stats.daily_failures), (1, 1));
+    }
+}
@@ -0,0 +1,524 @@
+//! Opt-in audit log of `/v1` requests, one NDJSON line per request in daily files under
+//! `<storage_dir>/audit/`. Lines are queued to one writer thread; when the queue is full a line
+//! is dropped and counted rather than making the request wait. Prompts and responses are only
+//! kept with `audit_log_include_content`, truncated and tagged with a hash of the full text.
+
+use anyhow::{Context, Result};
+use axum::body::{Body, Bytes};
+use futures_util::StreamExt;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::fs::{File, OpenOptions};
+use std::io::Write as _;
+use std::net::IpAddr;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{mpsc, Arc};
+use std::time::{Duration, Instant, SystemTime};
+use tracing::{info, warn};
+
+use crate::utils::response::extract_text_from_value;
+use crate::utils::stats::end_user_id;
+
+const AUDIT_DIR: &str = "audit";
+/// Entries waiting for the writer; more are dropped
+const QUEUE_CAPACITY: usize = 10_000;
+/// Characters of prompt and response text kept with `audit_log_include_content`
+const CONTENT_MAX_CHARS: usize = 2000;
+/// Larger JSON responses are logged without usage or content
+const MAX_JSON_CAPTURE_BYTES: usize = 8 * 1024 * 1024;
+
+/// One audited request
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct AuditEntry {
+    /// When the request arrived, RFC 3339 in UTC
+    pub timestamp: String,
+    pub request_id: String,
+    pub method: String,
+    pub path: String,
+    /// Client key name, or the masked credential id when no named key was used
+    pub client: Option<String>,
+    /// Client IP with the host part zeroed (/24 for IPv4, /48 for IPv6)
+    pub ip: Option<String>,
+    pub model: Option<String>,
+    /// Hash of the request's `user` field (see `stats::end_user_id`)
+    #[serde(default)]
+    pub end_user: Option<String>,
+    pub prompt_tokens: Option<u32>,
+    pub completion_tokens: Option<u32>,
+    pub total_tokens: Option<u32>,
+    /// Until the last byte of the response was sent
+    pub latency_ms: u64,
+    pub status: u16,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub prompt: Option<AuditContent>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub response: Option<AuditContent>,
+}
+
+/// The start of a prompt or response and a hash of all of it, so a full copy kept elsewhere
+/// can be matched without the log holding it
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct AuditContent {
+    /// `blake3:` and the first 16 hex digits of the hash of the full text
+    pub hash: String,
+    pub text: String,
+    pub truncated: bool,
+}
+
+/// Builds an `AuditContent` from text arriving in pieces
+#[derive(Default)]
+struct ContentDigest {
+    hasher: blake3::Hasher,
+    text: String,
+    chars: usize,
+}
+
+impl ContentDigest {
+    fn push(&mut self, text: &str) {
+        self.hasher.update(text.as_bytes());
+        for c in text.chars() {
+            if self.chars < CONTENT_MAX_CHARS {
+                self.text.push(c);
+            }
+            self.chars += 1;
+        }
+    }
+
+    fn finish(self) -> AuditContent {
+        AuditContent {
+            hash: format!("blake3:{}", &self.hasher.finalize().to_hex()[..16]),
+            text: self.text,
+            truncated: self.chars > CONTENT_MAX_CHARS,
+        }
+    }
+}
+
+enum AuditWrite {
+    Entry(Box<AuditEntry>),
+    Flush(mpsc::SyncSender<()>),
+}
+
+#[derive(Debug)]
+pub struct AuditLog {
+    include_content: bool,
+    queue: mpsc::SyncSender<AuditWrite>,
+    dropped: AtomicU64,
+}
+
+/// `<storage_dir>/audit`
+pub fn audit_dir(storage_dir: &str) -> PathBuf {
+    Path::new(storage_dir).join(AUDIT_DIR)
+}
+
+impl AuditLog {
+    /// Create the directory and start the writer thread. A day's file that reaches
+    /// `max_file_bytes` is continued in `audit-<day>.1.ndjson`, `.2` and so on.
+    pub fn start(dir: PathBuf, max_file_bytes: u64, include_content: bool) -> Result<Self> {
+        std::fs::create_dir_all(&dir).with_context(|| format!("Failed to create audit log directory: {:?}", dir))?;
+        let (queue, writes) = mpsc::sync_channel(QUEUE_CAPACITY);
+        let mut writer = AuditWriter { dir: dir.clone(), max_file_bytes, current: None };
+        std::thread::Builder::new()
+            .name("rujimi-audit".to_string())
+            .spawn(move || {
+                for write in writes {
+                    match write {
+                        AuditWrite::Entry(entry) => {
+                            if let Err(e) = writer.write(&entry) {
+                                warn!("Failed to write audit log entry: {}", e);
+                            }
+                        }
+                        AuditWrite::Flush(done) => {
+                            let _ = done.send(());
+                        }
+                    }
+                }
+            })
+            .context("Failed to start the audit log writer thread")?;
+
+        info!("📝 Audit log enabled in {:?}{}", dir, if include_content { ", with content" } else { "" });
+        Ok(Self { include_content, queue, dropped: AtomicU64::new(0) })
+    }
+
+    pub fn include_content(&self) -> bool {
+        self.include_content
+    }
+
+    /// Entries lost because the queue was full
+    pub fn dropped(&self) -> u64 {
+        self.dropped.load(Ordering::Relaxed)
+    }
+
+    /// Queue an entry without waiting
+    pub fn record(&self, entry: AuditEntry) {
+        if self.queue.try_send(AuditWrite::Entry(Box::new(entry))).is_err() {
+            let dropped = self.dropped.fetch_add(1, Ordering::Relaxed) + 1;
+            if dropped == 1 || dropped.is_multiple_of(1000) {
+                warn!("Audit log queue full; {} entries dropped so far", dropped);
+            }
+        }
+    }
+
+    /// Block until every entry queued so far is written
+    pub fn flush(&self) {
+        let (done, wait) = mpsc::sync_channel(1);
+        if self.queue.send(AuditWrite::Flush(done)).is_ok() {
+            let _ = wait.recv_timeout(Duration::from_secs(10));
+        }
+    }
+}
+
+struct AuditWriter {
+    dir: PathBuf,
+    max_file_bytes: u64,
+    /// Day, part number, open file and its size
+    current: Option<(String, u32, File, u64)>,
+}
+
+impl AuditWriter {
+    fn write(&mut self, entry: &AuditEntry) -> Result<()> {
+        let mut line = serde_json::to_vec(entry)?;
+        line.push(b'\n');
+        let day = entry.timestamp.get(..10).unwrap_or_default().to_string();
+
+        // Move on to the next part until one has room; an empty part takes any line
+        loop {
+            let next_part = match &self.current {
+                Some((current_day, part, _, size)) if *current_day == day => {
+                    if *size == 0 || *size + line.len() as u64 <= self.max_file_bytes {
+                        break;
+                    }
+                    part + 1
+                }
+                _ => 0,
+            };
+            let path = self.dir.join(file_name(&day, next_part));
+            let file = OpenOptions::new().create(true).append(true).open(&path)
+                .with_context(|| format!("Failed to open {:?}", path))?;
+            let size = file.metadata().map(|metadata| metadata.len()).unwrap_or(0);
+            self.current = Some((day.clone(), next_part, file, size));
+        }
+
+        if let Some((_, _, file, size)) = self.current.as_mut() {
+            file.write_all(&line)?;
+            *size += line.len() as u64;
+        }
+        Ok(())
+    }
+}
+
+fn file_name(day: &str, part: u32) -> String {
+    if part == 0 {
+        format!("audit-{}.ndjson", day)
+    } else {
+        format!("audit-{}.{}.ndjson", day, part)
+    }
+}
+
+/// An audit file as listed on the dashboard
+#[derive(Debug, Clone, Serialize)]
+pub struct AuditFile {
+    pub name: String,
+    pub size_bytes: u64,
+    pub modified: Option<String>,
+}
+
+/// Audit files in `dir`, newest name first
+pub fn list_files(dir: &Path) -> Vec<AuditFile> {
+    let mut files: Vec<AuditFile> = match std::fs::read_dir(dir) {
+        Ok(entries) => entries
+            .filter_map(|entry| entry.ok())
+            .filter_map(|entry| {
+                let name = entry.file_name().into_string().ok()?;
+                if !is_audit_file_name(&name) {
+                    return None;
+                }
+                let metadata = entry.metadata().ok()?;
+                let modified = metadata
+                    .modified()
+                    .ok()
+                    .map(|modified| chrono::DateTime::<chrono::Utc>::from(modified).to_rfc3339());
+                Some(AuditFile { name, size_bytes: metadata.len(), modified })
+            })
+            .collect(),
+        Err(_) => Vec::new(),
+    };
+    files.sort_by(|a, b| b.name.cmp(&a.name));
+    files
+}
+
+/// A name `list_files` could return; anything else, paths included, is rejected for download
+pub fn is_audit_file_name(name: &str) -> bool {
+    name.starts_with("audit-")
+        && name.ends_with(".ndjson")
+        && name.chars().all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '.')
+        && !name.contains("..")
+}
+
+/// The address with its host part zeroed, or `None` when it is not an IP address
+pub fn mask_ip(ip: &str) -> Option<String> {
+    match ip.parse::<IpAddr>().ok()? {
+        IpAddr::V4(ip) => {
+            let [a, b, c, _] = ip.octets();
+            Some(format!("{}.{}.{}.0", a, b, c))
+        }
+        IpAddr::V6(ip) => {
+            let segments = ip.segments();
+            Some(format!("{:x}:{:x}:{:x}::", segments[0], segments[1], segments[2]))
+        }
+    }
+}
+
+/// What the audit log keeps of a JSON request body
+#[derive(Debug, Default, PartialEq)]
+pub struct RequestSummary {
+    pub model: Option<String>,
+    pub end_user: Option<String>,
+    pub prompt: Option<AuditContent>,
+}
+
+/// Model, end user and prompt of a JSON request body
+pub fn describe_request(body: &[u8], include_content: bool) -> RequestSummary {
+    let request: Value = match serde_json::from_slice(body) {
+        Ok(request) => request,
+        Err(_) => return RequestSummary::default(),
+    };
+    let model = request.get("model").and_then(Value::as_str).map(str::to_string);
+    let end_user = request
+        .get("user")
+        .and_then(Value::as_str)
+        .filter(|user| !user.is_empty())
+        .map(end_user_id);
+    let summary = |prompt| RequestSummary { model, end_user, prompt };
+    if !include_content {
+        return summary(None);
+    }
+
+    let mut digest = ContentDigest::default();
+    if let Some(messages) = request.get("messages").and_then(Value::as_array) {
+        for (i, message) in messages.iter().enumerate() {
+            if i > 0 {
+                digest.push("\n");
+            }
+            let role = message.get("role").and_then(Value::as_str).unwrap_or("user");
+            let text = message.get("content").map(extract_text_from_value).unwrap_or_default();
+            digest.push(&format!("{}: {}", role, text));
+        }
+    } else if let Some(input) = request.get("input").or_else(|| request.get("prompt")) {
+        digest.push(&extract_text_from_value(input));
+    } else {
+        return summary(None);
+    }
+    summary(Some(digest.finish()))
+}
+
+/// Wrap a body so `observe` sees each chunk as it passes through. `observe` is dropped with
+/// the body, whether it was read to the end or abandoned.
+pub fn observe_body<F>(body: Body, mut observe: F) -> Body
+where
+    F: FnMut(&Bytes) + Send + 'static,
+{
+    Body::from_stream(body.into_data_stream().map(move |chunk| {
+        if let Ok(bytes) = &chunk {
+            observe(bytes);
+        }
+        chunk
+    }))
+}
+
+/// Reads usage and content from a response as it is sent, and records the entry once the
+/// response is done
+pub struct ResponseObserver {
+    log: Arc<AuditLog>,
+    entry: Option<AuditEntry>,
+    started: Instant,
+    event_stream: bool,
+    buffer: Vec<u8>,
+    overflowed: bool,
+    content: Option<ContentDigest>,
+}
+
+impl ResponseObserver {
+    pub fn new(log: Arc<AuditLog>, entry: AuditEntry, started: Instant, event_stream: bool) -> Self {
+        let content = log.include_content.then(ContentDigest::default);
+        Self { log, entry: Some(entry), started, event_stream, buffer: Vec::new(), overflowed: false, content }
+    }
+
+    pub fn observe(&mut self, chunk: &[u8]) {
+        if self.event_stream {
+            self.buffer.extend_from_slice(chunk);
+            while let Some(end) = self.buffer.iter().position(|&b| b == b'\n') {
+                let line: Vec<u8> = self.buffer.drain(..=end).collect();
+                self.observe_event_line(&String::from_utf8_lossy(&line));
+            }
+        } else if !self.overflowed {
+            if self.buffer.len() + chunk.len() > MAX_JSON_CAPTURE_BYTES {
+                self.overflowed = true;
+                self.buffer = Vec::new();
+            } else {
+                self.buffer.extend_from_slice(chunk);
+            }
+        }
+    }
+
+    fn observe_event_line(&mut self, line: &str) {
+        let data = match line.trim().strip_prefix("data:") {
+            Some(data) => data.trim(),
+            None => return,
+        };
+        // Only chunks that carry usage need parsing unless content is kept
+        if data == "[DONE]" || (self.content.is_none() && !data.contains("\"usage\"")) {
+            return;
+        }
+        if let Ok(chunk) = serde_json::from_str::<Value>(data) {
+            self.take_usage(&chunk);
+            if let (Some(content), Some(text)) = (
+                self.content.as_mut(),
+                chunk.pointer("/choices/0/delta/content").and_then(Value::as_str),
+            ) {
+                content.push(text);
+            }
+        }
+    }
+
+    fn take_usage(&mut self, value: &Value) {
+        let usage = match value.get("usage") {
+            Some(usage) if usage.is_object() => usage,
+            _ => return,
+        };
+        if let Some(entry) = self.entry.as_mut() {
+            let count = |name: &str| usage.get(name).and_then(Value::as_u64).map(|count| count as u32);
+            entry.prompt_tokens = count("prompt_tokens");
+            entry.completion_tokens = count("completion_tokens");
+            entry.total_tokens = count("total_tokens");
+        }
+    }
+}
+
+impl Drop for ResponseObserver {
+    fn drop(&mut self) {
+        if !self.event_stream && !self.overflowed && !self.buffer.is_empty() {
+            if let Ok(response) = serde_json::from_slice::<Value>(&self.buffer) {
+                self.take_usage(&response);
+                if let (Some(content), Some(text)) = (
+                    self.content.as_mut(),
+                    response.pointer("/choices/0/message/content").and_then(Value::as_str),
+                ) {
+                    content.push(text);
+                }
+            }
+        }
+        if let Some(mut entry) = self.entry.take() {
+            entry.latency_ms = self.started.elapsed().as_millis() as u64;
+            entry.response = self.content.take().filter(|content| content.chars > 0).map(ContentDigest::finish);
+            self.log.record(entry);
+        }
+    }
+}
+
+/// RFC 3339 in UTC
+pub fn timestamp(time: SystemTime) -> String {
+    chrono::DateTime::<chrono::Utc>::from(time).to_rfc3339_opts(chrono::SecondsFormat::Millis, true)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn entry(timestamp: &str) -> AuditEntry {
+        AuditEntry {
+            timestamp: timestamp.to_string(),
+            request_id: "req-1".to_string(),
+            method: "POST".to_string(),
+            path: "/v1/chat/completions".to_string(),
+            client: Some("team-a".to_string()),
+            ip: mask_ip("203.0.113.9"),
+            model: Some("gemini-2.5-flash".to_string()),
+            end_user: None,
+            prompt_tokens: None,
+            completion_tokens: None,
+            total_tokens: None,
+            latency_ms: 0,
+            status: 200,
+            prompt: None,
+            response: None,
+        }
+    }
+
+    #[test]
+    fn test_daily_files_split_at_size_cap() {
+        let dir = tempfile::tempdir().unwrap();
+        let line_len = serde_json::to_vec(&entry("2026-10-16T10:00:00.000Z")).unwrap().len() as u64 + 1;
+        let mut writer = AuditWriter { dir: dir.path().to_path_buf(), max_file_bytes: line_len * 2, current: None };
+        for _ in 0..3 {
+            writer.write(&entry("2026-10-16T10:00:00.000Z")).unwrap();
+        }
+        writer.write(&entry("2026-10-17T00:00:01.000Z")).unwrap();
+
+        let names: Vec<String> = list_files(dir.path()).into_iter().map(|file| file.name).collect();
+        assert_eq!(names, ["audit-2026-10-17.ndjson", "audit-2026-10-16.ndjson", "audit-2026-10-16.1.ndjson"]);
+        let first = std::fs::read_to_string(dir.path().join("audit-2026-10-16.ndjson")).unwrap();
+        assert_eq!(first.lines().count(), 2);
+        let parsed: AuditEntry = serde_json::from_str(first.lines().next().unwrap()).unwrap();
+        assert_eq!(parsed, entry("2026-10-16T10:00:00.000Z"));
+
+        // A restarted writer continues in the part that still has room
+        let mut writer = AuditWriter { dir: dir.path().to_path_buf(), max_file_bytes: line_len * 2, current: None };
+        writer.write(&entry("2026-10-16T11:00:00.000Z")).unwrap();
+        let second = std::fs::read_to_string(dir.path().join("audit-2026-10-16.1.ndjson")).unwrap();
+        assert_eq!(second.lines().count(), 2);
+    }
+
+    #[test]
+    fn test_stream_usage_and_content_observed() {
+        let dir = tempfile::tempdir().unwrap();
+        let log = Arc::new(AuditLog::start(dir.path().to_path_buf(), 1024 * 1024, true).unwrap());
+        let mut observer = ResponseObserver::new(log.clone(), entry(&timestamp(SystemTime::now())), Instant::now(), true);
+        observer.observe(b"data: {\"choices\":[{\"delta\":{\"content\":\"Hel\"}}]}\n\ndata: {\"choices\":[{\"delta\":{\"con");
+        observer.observe(b"tent\":\"lo\"}}]}\n\ndata: {\"choices\":[],\"usage\":{\"prompt_tokens\":7,\"completion_tokens\":2,\"total_tokens\":9}}\n\n");
+        observer.observe(b"data: [DONE]\n\n");
+        drop(observer);
+        log.flush();
+
+        let file = &list_files(dir.path())[0];
+        let contents = std::fs::read_to_string(dir.path().join(&file.name)).unwrap();
+        let logged: AuditEntry = serde_json::from_str(contents.trim()).unwrap();
+        assert_eq!((logged.prompt_tokens, logged.completion_tokens, logged.total_tokens), (Some(7), Some(2), Some(9)));
+        let response = logged.response.unwrap();
+        assert_eq!(response.text, "Hello");
+        assert!(!response.truncated);
+    }
+
+    #[test]
+    fn test_masking_and_file_names() {
+        assert_eq!(mask_ip("203.0.113.9").as_deref(), Some("203.0.113.0"));
+        assert_eq!(mask_ip("2001:db8:85a3:8d3:1319:8a2e:370:7348").as_deref(), Some("2001:db8:85a3::"));
+        assert_eq!(mask_ip("unknown"), None);
+
+        assert!(is_audit_file_name("audit-2026-10-16.1.ndjson"));
+        assert!(!is_audit_file_name("audit-../settings.json.ndjson"));
+        assert!(!is_audit_file_name("settings.json"));
+    }
+
+    #[test]
+    fn test_request_content_truncated_and_hashed() {
+        let long = "x".repeat(CONTENT_MAX_CHARS + 10);
+        let body = serde_json::json!({
+            "model": "gemini-2.5-pro",
+            "messages": [{"role": "system", "content": "Be brief"}, {"role": "user", "content": long}],
+            "user": "alice",
+        });
+        let body = serde_json::to_vec(&body).unwrap();
+
+        let summary = describe_request(&body, false);
+        assert_eq!(summary.model.as_deref(), Some("gemini-2.5-pro"));
+        assert_eq!(summary.end_user, Some(end_user_id("alice")));
+        assert!(summary.prompt.is_none());
+        let prompt = describe_request(&body, true).prompt.unwrap();
+        assert!(prompt.truncated);
+        assert_eq!(prompt.text.chars().count(), CONTENT_MAX_CHARS);
+        assert!(prompt.text.starts_with("system: Be brief\nuser: xxx"));
+        let full = format!("system: Be brief\nuser: {}", long);
+        assert_eq!(prompt.hash, format!("blake3:{}", &blake3::hash(full.as_bytes()).to_hex()[..16]));
+    }
+}
@@ -5,24 +5,154 @@ use axum::{
     middleware::Next,
     response::Response,
 };
-use serde::Deserialize;
+use dashmap::DashMap;
+use jsonwebtoken::{decode, encode, Algorithm, DecodingKey, EncodingKey, Header, Validation};
+use rand::RngCore;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeSet;
 use std::sync::Arc;
 use tracing::{debug, warn};
 
-use crate::config::Settings;
+use crate::config::{ClientKey, ClientKeyScope, LiveSettings, Settings};
+use crate::utils::ip_filter::IpFilter;
+use crate::utils::login_guard::LoginGuard;
+use crate::utils::model_policy::ModelFilter;
+use crate::utils::stats::PUBLIC_CLIENT_ID;
+
+/// Tolerated clock skew when checking session `iat`/`exp`
+const SESSION_LEEWAY_SECS: u64 = 30;
 
 #[derive(Debug, Clone)]
 pub struct AuthState {
-    settings: Arc<Settings>,
+    /// Shared with `AppState`, so reloaded passwords and public mode apply right away
+    settings: LiveSettings,
+    /// Random per-process salt, so restarting the server invalidates every session
+    session_salt: [u8; 32],
+    /// Logged-out session ids and their expiry, kept until the token would have expired anyway
+    revoked_sessions: Arc<DashMap<String, u64>>,
+    pub login_guard: LoginGuard,
+    pub ip_filter: IpFilter,
+    pub model_filter: ModelFilter,
+    /// Client keys, kept here so keys created or revoked at runtime take effect immediately
+    client_keys: Arc<std::sync::RwLock<Vec<ClientKey>>>,
+}
+
+/// Claims carried by a dashboard session token
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SessionClaims {
+    pub sub: String,
+    pub iat: u64,
+    pub exp: u64,
+    pub jti: String,
 }
 
 impl AuthState {
-    pub fn new(settings: Arc<Settings>) -> Self {
-        Self { settings }
+    pub fn new(settings: impl Into<LiveSettings>) -> Self {
+        let settings = settings.into();
+        let current = settings.load();
+        let mut session_salt = [0u8; 32];
+        rand::thread_rng().fill_bytes(&mut session_salt);
+
+        Self {
+            login_guard: LoginGuard::new(&current),
+            ip_filter: IpFilter::new(&current),
+            model_filter: ModelFilter::new(&current),
+            client_keys: Arc::new(std::sync::RwLock::new(current.client_keys.clone())),
+            settings,
+            session_salt,
+            revoked_sessions: Arc::new(DashMap::new()),
+        }
+    }
+
+    pub fn client_keys(&self) -> Vec<ClientKey> {
+        self.client_keys.read().unwrap_or_else(|e| e.into_inner()).clone()
+    }
+
+    pub fn set_client_keys(&self, client_keys: Vec<ClientKey>) {
+        *self.client_keys.write().unwrap_or_else(|e| e.into_inner()) = client_keys;
+    }
+
+    fn find_client_key(&self, token: &str) -> Option<ClientKey> {
+        self.client_keys
+            .read()
+            .unwrap_or_else(|e| e.into_inner())
+            .iter()
+            .find(|client_key| client_key.key == token)
+            .cloned()
+    }
+
+    /// Whether `password` opens the dashboard, checked against the current settings like the
+    /// session signing key, so a changed password locks out old sessions and old passwords alike
+    pub fn verify_login_password(&self, password: &str) -> bool {
+        let settings = self.settings.load();
+        password == settings.web_password || password == settings.password
+    }
+
+    /// Issue a session token for the dashboard. Returns the token and its expiry (unix seconds).
+    pub fn issue_session(&self) -> anyhow::Result<(String, u64)> {
+        self.issue_session_at(&self.settings.load().web_password, unix_now())
+    }
+
+    /// Verify a session token, returning its claims when it is valid, unexpired and not revoked
+    pub fn verify_session(&self, token: &str) -> Option<SessionClaims> {
+        self.verify_session_at(token, &self.settings.load().web_password, unix_now())
+    }
+
+    /// Revoke a session so it can no longer be used or refreshed
+    pub fn revoke_session(&self, claims: &SessionClaims) {
+        let now = unix_now();
+        self.revoked_sessions.retain(|_, exp| *exp + SESSION_LEEWAY_SECS >= now);
+        self.revoked_sessions.insert(claims.jti.clone(), claims.exp);
+    }
+
+    // The signing key depends on the password, so changing it invalidates existing sessions
+    fn signing_key(&self, password: &str) -> [u8; 32] {
+        *blake3::keyed_hash(&self.session_salt, password.as_bytes()).as_bytes()
+    }
+
+    fn issue_session_at(&self, password: &str, now: u64) -> anyhow::Result<(String, u64)> {
+        let claims = SessionClaims {
+            sub: "dashboard".to_string(),
+            iat: now,
+            exp: now + self.settings.load().session_ttl_secs.max(1),
+            jti: uuid::Uuid::new_v4().to_string(),
+        };
+        let token = encode(
+            &Header::new(Algorithm::HS256),
+            &claims,
+            &EncodingKey::from_secret(&self.signing_key(password)),
+        )?;
+        Ok((token, claims.exp))
+    }
+
+    fn verify_session_at(&self, token: &str, password: &str, now: u64) -> Option<SessionClaims> {
+        // Expiry is checked below against `now` so the leeway applies to both ends
+        let mut validation = Validation::new(Algorithm::HS256);
+        validation.validate_exp = false;
+        validation.required_spec_claims.clear();
+
+        let claims = decode::<SessionClaims>(token, &DecodingKey::from_secret(&self.signing_key(password)), &validation)
+            .ok()?
+            .claims;
+
+        if claims.exp + SESSION_LEEWAY_SECS < now || claims.iat > now + SESSION_LEEWAY_SECS {
+            return None;
+        }
+        if self.revoked_sessions.contains_key(&claims.jti) {
+            return None;
+        }
+        Some(claims)
     }
 }
 
-#[derive(Debug, Deserialize)]
+pub(crate) fn unix_now() -> u64 {
+    std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0)
+}
+
+#[derive(Debug, Default, Deserialize)]
 pub struct AuthQuery {
     key: Option<String>,
     password: Option<String>,
@@ -45,7 +175,7 @@ pub async fn auth_middleware(
     let auth_token = extract_auth_token(&headers, &query);
 
     if let Some(token) = auth_token {
-        if validate_auth_token(&token, &auth_state.settings) {
+        if validate_auth_token(&token, &auth_state.settings.load()) {
             debug!("Authentication successful");
             Ok(next.run(request).await)
         } else {
@@ -129,7 +259,7 @@ pub async fn web_auth_middleware(
     let auth_token = extract_auth_token(&headers, &query);
 
     if let Some(token) = auth_token {
-        if token == auth_state.settings.web_password {
+        if token == auth_state.settings.load().web_password {
             debug!("Web authentication successful");
             Ok(next.run(request).await)
         } else {
@@ -142,15 +272,14 @@ pub async fn web_auth_middleware(
     }
 }
 
-pub fn validate_user_agent(user_agent: Option<&str>, settings: &Settings) -> bool {
-    if settings.whitelist_user_agent.is_empty() {
+pub fn validate_user_agent(user_agent: Option<&str>, whitelist: &BTreeSet<String>) -> bool {
+    if whitelist.is_empty() {
         return true; // No whitelist configured, allow all
     }
 
     if let Some(ua) = user_agent {
         let ua_lower = ua.to_lowercase();
-        return settings
-            .whitelist_user_agent
+        return whitelist
             .iter()
             .any(|allowed| ua_lower.contains(&allowed.to_lowercase()));
     }
@@ -168,31 +297,77 @@ pub struct AuthResult {
     pub authenticated: bool,
     pub user_id: Option<String>,
     pub scope: AuthScope,
+    /// The named client key used, `None` for the shared password and sessions
+    pub client_key: Option<ClientKey>,
+    /// Masked identity of the credential used, safe to store and show on the dashboard
+    pub client_id: Option<String>,
+}
+
+/// Stable, non-reversible label for a credential so usage can be attributed without storing it
+pub fn client_fingerprint(token: &str) -> String {
+    format!("key-{}", &blake3::hash(token.as_bytes()).to_hex()[..12])
+}
+
+impl AuthResult {
+    /// Anonymous caller admitted by public mode with the restricted profile
+    pub fn is_public(&self) -> bool {
+        matches!(self.scope, AuthScope::Public)
+    }
 }
 
 #[derive(Debug, Clone)]
 pub enum AuthScope {
+    /// No valid credentials
+    Unauthenticated,
+    /// Anonymous access granted by `public_mode`; not `authenticated`, so only the
+    /// chat completions endpoint accepts it
     Public,
     Authenticated,
     Admin,
 }
 
+/// Authenticate an API call with a raw password, API key or client key. Dashboard session
+/// tokens only work on the dashboard, see `authenticate_dashboard_request`.
 pub fn authenticate_request(
     headers: &HeaderMap,
     query: &AuthQuery,
-    settings: &Settings,
+    auth_state: &AuthState,
 ) -> AuthResult {
-    if settings.public_mode {
-        return AuthResult {
-            authenticated: true,
-            user_id: Some("public".to_string()),
-            scope: AuthScope::Public,
-        };
-    }
+    authenticate(headers, query, auth_state, false)
+}
+
+/// Authenticate a dashboard or `/api/auth` request, which may also carry a dashboard session token
+pub fn authenticate_dashboard_request(
+    headers: &HeaderMap,
+    query: &AuthQuery,
+    auth_state: &AuthState,
+) -> AuthResult {
+    authenticate(headers, query, auth_state, true)
+}
+
+fn authenticate(headers: &HeaderMap, query: &AuthQuery, auth_state: &AuthState, accept_session: bool) -> AuthResult {
+    let settings = auth_state.settings.load();
+    let token = extract_auth_token(headers, query);
+
+    if let Some(token) = &token {
+        if let Some(client_key) = auth_state.find_client_key(token) {
+            let scope = match client_key.scope {
+                ClientKeyScope::Admin => AuthScope::Admin,
+                ClientKeyScope::User => AuthScope::Authenticated,
+            };
+
+            return AuthResult {
+                authenticated: true,
+                user_id: Some(format!("client_{}", client_key.name)),
+                scope,
+                client_key: Some(client_key),
+                client_id: Some(client_fingerprint(token)),
+            };
+        }
 
-    if let Some(token) = extract_auth_token(headers, query) {
-        if validate_auth_token(&token, settings) {
-            let scope = if token == settings.web_password {
+        if validate_auth_token(token, &settings) {
+            // The shared password acts as an implicit admin key
+            let scope = if *token == settings.web_password || *token == settings.password {
                 AuthScope::Admin
             } else {
                 AuthScope::Authenticated
@@ -202,14 +377,40 @@ pub fn authenticate_request(
                 authenticated: true,
                 user_id: Some(format!("user_{}", &token[..8.min(token.len())])),
                 scope,
+                client_key: None,
+                client_id: Some(client_fingerprint(token)),
+            };
+        }
+
+        // Session tokens are issued for the web password, so they carry the admin scope
+        if let Some(claims) = accept_session.then(|| auth_state.verify_session(token)).flatten() {
+            return AuthResult {
+                authenticated: true,
+                user_id: Some(format!("session_{}", &claims.jti[..8])),
+                scope: AuthScope::Admin,
+                client_key: None,
+                client_id: Some("dashboard-session".to_string()),
             };
         }
     }
 
+    // A wrong key is rejected even in public mode rather than silently downgraded
+    if settings.public_mode && token.is_none() {
+        return AuthResult {
+            authenticated: false,
+            user_id: Some(PUBLIC_CLIENT_ID.to_string()),
+            scope: AuthScope::Public,
+            client_key: None,
+            client_id: Some(PUBLIC_CLIENT_ID.to_string()),
+        };
+    }
+
     AuthResult {
         authenticated: false,
         user_id: None,
-        scope: AuthScope::Public,
+        scope: AuthScope::Unauthenticated,
+        client_key: None,
+        client_id: None,
     }
 }
 
@@ -238,20 +439,137 @@ mod tests {
 
     #[test]
     fn test_validate_user_agent() {
-        use std::collections::HashSet;
-
-        let mut whitelist = HashSet::new();
+        let mut whitelist = BTreeSet::new();
         whitelist.insert("mozilla".to_string());
         whitelist.insert("curl".to_string());
 
-        let settings = Settings {
-            whitelist_user_agent: whitelist,
+        assert!(validate_user_agent(Some("Mozilla/5.0"), &whitelist));
+        assert!(validate_user_agent(Some("curl/7.68.0"), &whitelist));
+        assert!(!validate_user_agent(Some("BadBot/1.0"), &whitelist));
+        assert!(!validate_user_agent(None, &whitelist));
+        assert!(validate_user_agent(None, &BTreeSet::new()));
+    }
+
+    fn session_state() -> AuthState {
+        AuthState::new(Arc::new(Settings {
+            web_password: "dashboard-secret".to_string(),
+            session_ttl_secs: 600,
             ..Default::default()
-        };
+        }))
+    }
+
+    fn bearer(token: &str) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        headers.insert("authorization", HeaderValue::from_str(&format!("Bearer {}", token)).unwrap());
+        headers
+    }
+
+    #[test]
+    fn test_session_token_authenticates_as_admin() {
+        let state = session_state();
+        let (token, _) = state.issue_session().unwrap();
+
+        let query = AuthQuery { key: None, password: None };
+        let result = authenticate_dashboard_request(&bearer(&token), &query, &state);
+        assert!(result.authenticated);
+        assert!(matches!(result.scope, AuthScope::Admin));
+
+        // Not a credential for the API itself
+        assert!(!authenticate_request(&bearer(&token), &query, &state).authenticated);
+    }
 
-        assert!(validate_user_agent(Some("Mozilla/5.0"), &settings));
-        assert!(validate_user_agent(Some("curl/7.68.0"), &settings));
-        assert!(!validate_user_agent(Some("BadBot/1.0"), &settings));
-        assert!(!validate_user_agent(None, &settings));
+    #[test]
+    fn test_expired_session_rejected_after_leeway() {
+        let state = session_state();
+        let (token, exp) = state.issue_session_at("dashboard-secret", 1_000_000).unwrap();
+
+        assert!(state.verify_session_at(&token, "dashboard-secret", exp + SESSION_LEEWAY_SECS).is_some());
+        assert!(state.verify_session_at(&token, "dashboard-secret", exp + SESSION_LEEWAY_SECS + 1).is_none());
+        // Issued too far in the future for the allowed skew
+        assert!(state.verify_session_at(&token, "dashboard-secret", 1_000_000 - SESSION_LEEWAY_SECS - 1).is_none());
+    }
+
+    #[test]
+    fn test_tampered_session_rejected() {
+        let state = session_state();
+        let (token, _) = state.issue_session().unwrap();
+
+        let mut parts: Vec<String> = token.split('.').map(str::to_string).collect();
+        let claims: SessionClaims = serde_json::from_slice(
+            &base64::Engine::decode(&base64::engine::general_purpose::URL_SAFE_NO_PAD, &parts[1]).unwrap(),
+        )
+        .unwrap();
+        let forged = SessionClaims { exp: claims.exp + 86_400, ..claims };
+        parts[1] = base64::Engine::encode(
+            &base64::engine::general_purpose::URL_SAFE_NO_PAD,
+            serde_json::to_vec(&forged).unwrap(),
+        );
+        assert!(state.verify_session(&parts.join(".")).is_none());
+
+        // A token from another process (different salt) is not accepted either
+        assert!(session_state().verify_session(&token).is_none());
+    }
+
+    #[test]
+    fn test_client_keys_resolve_and_revoke_at_runtime() {
+        let state = session_state();
+        let query = AuthQuery { key: None, password: None };
+        assert!(!authenticate_request(&bearer("sk-team-a"), &query, &state).authenticated);
+
+        state.set_client_keys(vec![ClientKey {
+            key: "sk-team-a".to_string(),
+            name: "team-a".to_string(),
+            scope: ClientKeyScope::User,
+            daily_request_limit: 10,
+            daily_token_limit: 0,
+            allowed_models: vec![],
+            weight: 1,
+        }]);
+        let result = authenticate_request(&bearer("sk-team-a"), &query, &state);
+        assert!(result.authenticated);
+        assert!(matches!(result.scope, AuthScope::Authenticated));
+        assert_eq!(result.client_key.unwrap().name, "team-a");
+
+        // The shared password keeps working as an admin key
+        let result = authenticate_request(&bearer("123"), &query, &state);
+        assert!(matches!(result.scope, AuthScope::Admin));
+        assert!(result.client_key.is_none());
+        assert_eq!(result.client_id.as_deref(), Some(client_fingerprint("123").as_str()));
+        assert!(!result.client_id.unwrap().contains("123"));
+
+        state.set_client_keys(Vec::new());
+        assert!(!authenticate_request(&bearer("sk-team-a"), &query, &state).authenticated);
+    }
+
+    #[test]
+    fn test_public_mode_admits_only_anonymous_callers() {
+        let query = AuthQuery { key: None, password: None };
+
+        let state = session_state();
+        let result = authenticate_request(&HeaderMap::new(), &query, &state);
+        assert!(matches!(result.scope, AuthScope::Unauthenticated));
+
+        let state = AuthState::new(Arc::new(Settings { public_mode: true, ..Default::default() }));
+        let result = authenticate_request(&HeaderMap::new(), &query, &state);
+        assert!(result.is_public());
+        // Not authenticated, so the dashboard keeps rejecting it
+        assert!(!result.authenticated);
+        assert_eq!(result.client_id.as_deref(), Some(PUBLIC_CLIENT_ID));
+
+        // Credentials keep their full capability, wrong ones are not downgraded to public
+        assert!(matches!(authenticate_request(&bearer("123"), &query, &state).scope, AuthScope::Admin));
+        let result = authenticate_request(&bearer("wrong"), &query, &state);
+        assert!(!result.authenticated && !result.is_public());
+    }
+
+    #[test]
+    fn test_password_change_and_logout_revoke_sessions() {
+        let state = session_state();
+        let (token, _) = state.issue_session().unwrap();
+        assert!(state.verify_session_at(&token, "new-password", unix_now()).is_none());
+
+        let claims = state.verify_session(&token).unwrap();
+        state.revoke_session(&claims);
+        assert!(state.verify_session(&token).is_none());
     }
 }
\ No newline at end of file
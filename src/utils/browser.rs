@@ -2,6 +2,112 @@ use std::process::Command;
 use std::env;
 use tokio::time::{sleep, Duration};
 use tracing::{info, warn, error};
+use xxhash_rust::xxh3::xxh3_64;
+
+use crate::config::{ConfigManager, UpstreamHeaderProfile};
+
+/// A desktop Chromium build as it identifies itself
+struct BrowserProfile {
+    user_agent: &'static str,
+    sec_ch_ua: &'static str,
+    platform: &'static str,
+}
+
+const BROWSER_PROFILES: &[BrowserProfile] = &[
+    BrowserProfile {
+        user_agent: "Mozilla/5.0 (Windows NT 10.0; Win64; x64) AppleWebKit/537.36 (KHTML, like Gecko) Chrome/131.0.0.0 Safari/537.36",
+        sec_ch_ua: "\"Google Chrome\";v=\"131\", \"Chromium\";v=\"131\", \"Not_A Brand\";v=\"24\"",
+        platform: "\"Windows\"",
+    },
+    BrowserProfile {
+        user_agent: "Mozilla/5.0 (Macintosh; Intel Mac OS X 10_15_7) AppleWebKit/537.36 (KHTML, like Gecko) Chrome/131.0.0.0 Safari/537.36",
+        sec_ch_ua: "\"Google Chrome\";v=\"131\", \"Chromium\";v=\"131\", \"Not_A Brand\";v=\"24\"",
+        platform: "\"macOS\"",
+    },
+    BrowserProfile {
+        user_agent: "Mozilla/5.0 (X11; Linux x86_64) AppleWebKit/537.36 (KHTML, like Gecko) Chrome/130.0.0.0 Safari/537.36",
+        sec_ch_ua: "\"Chromium\";v=\"130\", \"Google Chrome\";v=\"130\", \"Not?A_Brand\";v=\"99\"",
+        platform: "\"Linux\"",
+    },
+    BrowserProfile {
+        user_agent: "Mozilla/5.0 (Windows NT 10.0; Win64; x64) AppleWebKit/537.36 (KHTML, like Gecko) Chrome/131.0.0.0 Safari/537.36 Edg/131.0.0.0",
+        sec_ch_ua: "\"Microsoft Edge\";v=\"131\", \"Chromium\";v=\"131\", \"Not_A Brand\";v=\"24\"",
+        platform: "\"Windows\"",
+    },
+];
+
+const ACCEPT_LANGUAGES: &[&str] = &[
+    "en-US,en;q=0.9",
+    "en-GB,en;q=0.9",
+    "en-US,en;q=0.9,zh-CN;q=0.8,zh;q=0.7",
+    "de-DE,de;q=0.9,en;q=0.8",
+    "ja-JP,ja;q=0.9,en;q=0.8",
+];
+
+/// Browser-like headers for requests made with `api_key`. The same key always gets the same
+/// set, so a key does not appear to switch browsers between requests.
+pub fn browser_headers(api_key: &str) -> Vec<(&'static str, &'static str)> {
+    let hash = xxh3_64(api_key.as_bytes());
+    let profile = &BROWSER_PROFILES[(hash % BROWSER_PROFILES.len() as u64) as usize];
+    let language = ACCEPT_LANGUAGES[((hash >> 32) % ACCEPT_LANGUAGES.len() as u64) as usize];
+    vec![
+        ("User-Agent", profile.user_agent),
+        ("Accept-Language", language),
+        ("sec-ch-ua", profile.sec_ch_ua),
+        ("sec-ch-ua-mobile", "?0"),
+        ("sec-ch-ua-platform", profile.platform),
+    ]
+}
+
+/// Add the headers of `profile` to an upstream request. They never include the API key or
+/// content type headers, which callers set themselves.
+pub fn with_header_profile(builder: reqwest::RequestBuilder, profile: UpstreamHeaderProfile, api_key: &str) -> reqwest::RequestBuilder {
+    match profile {
+        UpstreamHeaderProfile::None => builder,
+        UpstreamHeaderProfile::Browser => browser_headers(api_key)
+            .into_iter()
+            .fold(builder, |builder, (name, value)| builder.header(name, value)),
+    }
+}
+
+/// `with_header_profile` with the profile currently configured
+pub async fn with_upstream_headers(builder: reqwest::RequestBuilder, api_key: &str) -> reqwest::RequestBuilder {
+    with_header_profile(builder, ConfigManager::get_upstream_header_profile().await, api_key)
+}
+
+/// Headers never copied from an incoming request, whatever `forward_request_headers` lists:
+/// credentials, and those describing the incoming connection or body
+pub const NEVER_FORWARDED_HEADERS: &[&str] =
+    &["authorization", "x-goog-api-key", "host", "content-length", "content-type", "connection", "transfer-encoding"];
+
+/// The incoming `headers` named in `allowed`, to be sent on with the upstream call
+pub fn forwarded_headers(headers: &axum::http::HeaderMap, allowed: &[String]) -> Vec<(String, String)> {
+    allowed
+        .iter()
+        .map(|name| name.to_lowercase())
+        .filter(|name| !NEVER_FORWARDED_HEADERS.contains(&name.as_str()))
+        .flat_map(|name| {
+            headers
+                .get_all(name.as_str())
+                .iter()
+                .filter_map(|value| value.to_str().ok())
+                .map(|value| (name.clone(), value.to_string()))
+                .collect::<Vec<_>>()
+        })
+        .collect()
+}
+
+/// Names of `headers`, once each, for the request log
+pub fn forwarded_header_names(headers: &[(String, String)]) -> Vec<String> {
+    let mut names: Vec<String> = headers.iter().map(|(name, _)| name.clone()).collect();
+    names.dedup();
+    names
+}
+
+/// Add the headers `forwarded_headers` picked from the incoming request
+pub fn with_forwarded_headers(builder: reqwest::RequestBuilder, headers: &[(String, String)]) -> reqwest::RequestBuilder {
+    headers.iter().fold(builder, |builder, (name, value)| builder.header(name.as_str(), value.as_str()))
+}
 
 pub fn open_browser_with_port(port: u16) {
     // 检查是否在无 GUI 的 Linux 环境中（但不检查 macOS）
@@ -66,4 +172,41 @@ pub async fn open_browser_delayed_with_port(port: u16) {
     }).await.unwrap_or_else(|e| {
         error!("打开浏览器任务执行失败: {}", e);
     });
-}
\ No newline at end of file
+}
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_browser_headers_stable_per_key() {
+        assert_eq!(browser_headers("AIza-key-1"), browser_headers("AIza-key-1"));
+        let user_agents: std::collections::HashSet<&str> = (0..50)
+            .map(|i| browser_headers(&format!("AIza-key-{}", i))[0].1)
+            .collect();
+        assert!(user_agents.len() > 1);
+    }
+
+    #[test]
+    fn test_profile_keeps_required_headers() {
+        let build = |profile| {
+            let builder = reqwest::Client::new()
+                .post("https://upstream.example/v1beta/models/gemini-2.5-flash:generateContent")
+                .header("Content-Type", "application/json")
+                .header("x-goog-api-key", "AIza-test-key");
+            with_header_profile(builder, profile, "AIza-test-key").build().unwrap()
+        };
+
+        let plain = build(UpstreamHeaderProfile::None);
+        assert!(plain.headers().get("user-agent").is_none());
+
+        let browser = build(UpstreamHeaderProfile::Browser);
+        let headers = browser.headers();
+        assert_eq!(headers.get_all("content-type").iter().count(), 1);
+        assert_eq!(headers["content-type"], "application/json");
+        assert_eq!(headers.get_all("x-goog-api-key").iter().count(), 1);
+        assert_eq!(headers["x-goog-api-key"], "AIza-test-key");
+        assert!(headers["user-agent"].to_str().unwrap().starts_with("Mozilla/5.0"));
+        assert!(headers.contains_key("accept-language"));
+        assert!(headers["sec-ch-ua"].to_str().unwrap().contains("Chromium"));
+    }
+}
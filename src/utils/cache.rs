@@ -1,19 +1,28 @@
 use dashmap::DashMap;
 use serde::{Deserialize, Serialize};
-use std::collections::VecDeque;
+use std::collections::{BTreeMap, VecDeque};
+use std::sync::atomic::{AtomicU64, Ordering};
 use std::sync::Arc;
 use std::time::{Duration, SystemTime};
 use tracing::{debug, info};
 use xxhash_rust::xxh3::xxh3_64;
 
 use crate::config::Settings;
-use crate::models::schemas::ChatCompletionResponse;
+use crate::models::schemas::{
+    ChatChoice, ChatChoiceDelta, ChatCompletionChunk, ChatCompletionResponse, ChatMessage, ChatMessageDelta, Usage,
+};
+use crate::storage::CacheStore;
+use crate::utils::clock::{system_clock, SharedClock};
+use crate::utils::tokens::estimate_tokens;
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct CacheEntry {
     pub response: ChatCompletionResponse,
     pub created_at: SystemTime,
     pub access_count: usize,
+    /// Lifetime requested with `X-Rujimi-Cache-TTL`, never longer than the cache-wide one
+    #[serde(default)]
+    pub ttl: Option<Duration>,
 }
 
 impl CacheEntry {
@@ -22,11 +31,18 @@ impl CacheEntry {
             response,
             created_at: SystemTime::now(),
             access_count: 0,
+            ttl: None,
         }
     }
 
     pub fn is_expired(&self, ttl: Duration) -> bool {
-        self.created_at.elapsed().unwrap_or(Duration::MAX) > ttl
+        self.is_expired_at(ttl, SystemTime::now())
+    }
+
+    /// Whether the entry has outlived `ttl`, or its own shorter one, at `now`
+    pub fn is_expired_at(&self, ttl: Duration, now: SystemTime) -> bool {
+        let ttl = self.ttl.map_or(ttl, |own| own.min(ttl));
+        age_at(self.created_at, now) > ttl
     }
 
     pub fn access(&mut self) {
@@ -39,6 +55,11 @@ pub struct ResponseCacheManager {
     settings: Arc<Settings>,
     cache: Arc<DashMap<String, VecDeque<CacheEntry>>>,
     access_times: Arc<DashMap<String, SystemTime>>,
+    hits: Arc<AtomicU64>,
+    misses: Arc<AtomicU64>,
+    /// Durable copy of the cached responses when a storage backend is configured
+    store: Option<Arc<dyn CacheStore>>,
+    clock: SharedClock,
 }
 
 impl ResponseCacheManager {
@@ -47,7 +68,42 @@ impl ResponseCacheManager {
             settings,
             cache: Arc::new(DashMap::new()),
             access_times: Arc::new(DashMap::new()),
+            hits: Arc::new(AtomicU64::new(0)),
+            misses: Arc::new(AtomicU64::new(0)),
+            store: None,
+            clock: system_clock(),
+        }
+    }
+
+    pub fn with_store(mut self, store: Arc<dyn CacheStore>) -> Self {
+        self.store = Some(store);
+        self
+    }
+
+    pub fn with_clock(mut self, clock: SharedClock) -> Self {
+        self.clock = clock;
+        self
+    }
+
+    /// Load the stored responses that have not expired yet
+    pub fn restore(&self) -> anyhow::Result<usize> {
+        let store = match &self.store {
+            Some(store) => store,
+            None => return Ok(0),
+        };
+        let entries = store.load_entries(Duration::from_secs(self.settings.cache_expiry_time))?;
+        let count = entries.len();
+        for (cache_key, entry) in entries {
+            let created_at = entry.created_at;
+            let mut queue = self.cache.entry(cache_key.clone()).or_default();
+            queue.push_back(entry);
+            while queue.len() > 3 {
+                queue.pop_front();
+            }
+            drop(queue);
+            self.access_times.insert(cache_key, created_at);
         }
+        Ok(count)
     }
 
     pub async fn get(&self, cache_key: &str) -> Option<ChatCompletionResponse> {
@@ -55,8 +111,9 @@ impl ResponseCacheManager {
             if let Some(mut entry) = entries.pop_front() {
                 // Check if entry is expired
                 let ttl = Duration::from_secs(self.settings.cache_expiry_time);
-                if entry.is_expired(ttl) {
+                if entry.is_expired_at(ttl, self.clock.now()) {
                     debug!("Cache entry expired for key: {}", cache_key);
+                    self.misses.fetch_add(1, Ordering::Relaxed);
                     return None;
                 }
 
@@ -67,19 +124,46 @@ impl ResponseCacheManager {
                 entries.push_back(entry);
 
                 // Update access time
-                self.access_times.insert(cache_key.to_string(), SystemTime::now());
+                self.access_times.insert(cache_key.to_string(), self.clock.now());
 
                 debug!("Cache hit for key: {}", cache_key);
+                self.hits.fetch_add(1, Ordering::Relaxed);
                 return Some(response);
             }
         }
 
         debug!("Cache miss for key: {}", cache_key);
+        self.misses.fetch_add(1, Ordering::Relaxed);
         None
     }
 
+    /// Share of lookups since startup that found a response, 0 before the first lookup
+    pub fn hit_ratio(&self) -> f64 {
+        let hits = self.hits.load(Ordering::Relaxed);
+        let lookups = hits + self.misses.load(Ordering::Relaxed);
+        if lookups == 0 {
+            return 0.0;
+        }
+        hits as f64 / lookups as f64
+    }
+
     pub async fn put(&self, cache_key: String, response: ChatCompletionResponse) {
-        let entry = CacheEntry::new(response);
+        self.put_with_ttl(cache_key, response, None).await;
+    }
+
+    /// Cache a response for `ttl`, or `cache_expiry_time` when `None`. Responses with a
+    /// [`skip_reason`] are left out.
+    pub async fn put_with_ttl(&self, cache_key: String, response: ChatCompletionResponse, ttl: Option<Duration>) {
+        if let Some(reason) = skip_reason(&response, self.settings.min_tokens_to_cache) {
+            debug!("Not caching response for key {}: {}", cache_key, reason);
+            return;
+        }
+        let mut entry = CacheEntry::new(response);
+        entry.created_at = self.clock.now();
+        entry.ttl = ttl;
+        if let Some(store) = &self.store {
+            store.put_entry(&cache_key, &entry);
+        }
 
         // Get or create the entry queue for this cache key
         let mut entries = self.cache.entry(cache_key.clone()).or_insert_with(VecDeque::new);
@@ -91,9 +175,11 @@ impl ResponseCacheManager {
         while entries.len() > 3 {
             entries.pop_front();
         }
+        // Release the shard lock before `enforce_size_limit` walks the whole map
+        drop(entries);
 
         // Update access time
-        self.access_times.insert(cache_key.clone(), SystemTime::now());
+        self.access_times.insert(cache_key.clone(), self.clock.now());
 
         debug!("Cached response for key: {}", cache_key);
 
@@ -106,19 +192,63 @@ impl ResponseCacheManager {
     }
 
     pub async fn clear(&self) {
-        self.cache.clear();
-        self.access_times.clear();
-        info!("Cache cleared");
+        self.clear_sync();
+    }
+
+    /// Remove the responses matching `filter`, returning how many were removed. Keys are
+    /// handled one at a time, so lookups on other keys are never held up by a large scan.
+    pub async fn clear_matching(&self, filter: &CacheFilter) -> usize {
+        let keys: Vec<String> = self
+            .cache
+            .iter()
+            .map(|entry| entry.key().clone())
+            .filter(|key| filter.model.as_deref().is_none_or(|model| key_model(key) == model))
+            .collect();
+
+        let now = self.clock.now();
+        let mut removed_count = 0;
+        for key in keys {
+            let (removed, remaining) = match self.cache.get_mut(&key) {
+                Some(mut entries) => {
+                    let before = entries.len();
+                    entries.retain(|entry| !filter.matches_age(entry, now));
+                    (before - entries.len(), entries.clone())
+                }
+                None => continue,
+            };
+            if removed == 0 {
+                continue;
+            }
+            removed_count += removed;
+            if remaining.is_empty() {
+                self.cache.remove_if(&key, |_, entries| entries.is_empty());
+                self.access_times.remove(&key);
+            }
+            // The store only removes whole keys, so the survivors are written back
+            if let Some(store) = &self.store {
+                store.remove_key(&key);
+                for entry in &remaining {
+                    store.put_entry(&key, entry);
+                }
+            }
+        }
+
+        info!("Cleared {} cached responses matching {:?}", removed_count, filter);
+        removed_count
     }
 
     pub fn clear_sync(&self) {
         self.cache.clear();
         self.access_times.clear();
+        if let Some(store) = &self.store {
+            store.clear_entries();
+        }
         info!("Cache cleared");
     }
 
     pub async fn cleanup_expired(&self) -> usize {
         let ttl = Duration::from_secs(self.settings.cache_expiry_time);
+        let now = self.clock.now();
         let mut removed_count = 0;
         let mut keys_to_remove = Vec::new();
 
@@ -131,7 +261,7 @@ impl ResponseCacheManager {
             // Remove expired entries from the queue
             let mut new_entries = VecDeque::new();
             for cache_entry in entries.iter() {
-                if !cache_entry.is_expired(ttl) {
+                if !cache_entry.is_expired_at(ttl, now) {
                     new_entries.push_back(cache_entry.clone());
                 } else {
                     removed_count += 1;
@@ -156,6 +286,9 @@ impl ResponseCacheManager {
         for key in keys_to_remove {
             self.cache.remove(&key);
             self.access_times.remove(&key);
+            if let Some(store) = &self.store {
+                store.remove_key(&key);
+            }
         }
 
         if removed_count > 0 {
@@ -184,6 +317,9 @@ impl ResponseCacheManager {
         for (key, _) in keys_by_access_time.into_iter().take(excess_count) {
             self.cache.remove(&key);
             self.access_times.remove(&key);
+            if let Some(store) = &self.store {
+                store.remove_key(&key);
+            }
         }
 
         info!("Evicted {} cache entries to enforce size limit", excess_count);
@@ -212,17 +348,18 @@ impl ResponseCacheManager {
             total_keys: total_entries,
             total_responses,
             expired_entries: expired_count,
-            hit_ratio: 0.0, // This would require tracking hits/misses separately
+            hit_ratio: self.hit_ratio(),
         }
     }
 
     async fn count_expired_entries(&self) -> usize {
         let ttl = Duration::from_secs(self.settings.cache_expiry_time);
+        let now = self.clock.now();
         let mut expired_count = 0;
 
         for entry in self.cache.iter() {
             for cache_entry in entry.value().iter() {
-                if cache_entry.is_expired(ttl) {
+                if cache_entry.is_expired_at(ttl, now) {
                     expired_count += 1;
                 }
             }
@@ -232,6 +369,34 @@ impl ResponseCacheManager {
     }
 }
 
+/// Which responses a partial clear removes; every field that is set must match
+#[derive(Debug, Default, Clone, Deserialize)]
+pub struct CacheFilter {
+    /// Responses cached for this model
+    #[serde(default)]
+    pub model: Option<String>,
+    /// Responses cached more than this many seconds ago
+    #[serde(default)]
+    pub older_than_secs: Option<u64>,
+}
+
+impl CacheFilter {
+    /// Whether the filter matches every response
+    pub fn is_empty(&self) -> bool {
+        self.model.is_none() && self.older_than_secs.is_none()
+    }
+
+    fn matches_age(&self, entry: &CacheEntry, now: SystemTime) -> bool {
+        self.older_than_secs
+            .is_none_or(|secs| age_at(entry.created_at, now) > Duration::from_secs(secs))
+    }
+}
+
+/// Time from `created_at` to `now`; an entry dated in the future is treated as expired
+fn age_at(created_at: SystemTime, now: SystemTime) -> Duration {
+    now.duration_since(created_at).unwrap_or(Duration::MAX)
+}
+
 #[derive(Debug, Serialize)]
 pub struct CacheStats {
     pub total_keys: usize,
@@ -264,9 +429,168 @@ pub fn generate_cache_key(
     format!("{}_{:x}", model, hash)
 }
 
+/// The model a key from [`generate_cache_key`] was made for
+pub fn key_model(cache_key: &str) -> &str {
+    cache_key.rsplit_once('_').map_or(cache_key, |(model, _hash)| model)
+}
+
+/// Why a response should stay out of the cache, `None` when it may be cached. Only plain
+/// answers are replayed: every choice stopped normally with text and no tool calls, so empty,
+/// blocked (`content_filter`), truncated and tool-call responses are skipped, as are answers
+/// of fewer than `min_tokens` completion tokens.
+pub fn skip_reason(response: &ChatCompletionResponse, min_tokens: u32) -> Option<&'static str> {
+    if response.choices.is_empty() {
+        return Some("no choices");
+    }
+    let mut estimated_tokens = 0;
+    for choice in &response.choices {
+        if choice.finish_reason.as_deref() != Some("stop") {
+            return Some("did not finish with stop");
+        }
+        if choice.message.tool_calls.as_ref().is_some_and(|calls| !calls.is_empty()) {
+            return Some("tool calls");
+        }
+        let text = choice.message.content.as_ref().and_then(|content| content.as_str()).unwrap_or_default();
+        if text.trim().is_empty() {
+            return Some("blank content");
+        }
+        estimated_tokens += estimate_tokens(text);
+    }
+    // Reported usage wins; an answer without it is estimated from its text
+    let completion_tokens = match &response.usage {
+        Some(usage) if usage.completion_tokens > 0 => usage.completion_tokens,
+        _ => estimated_tokens,
+    };
+    if completion_tokens < min_tokens {
+        return Some("below min_tokens_to_cache");
+    }
+    None
+}
+
+pub fn is_cacheable(response: &ChatCompletionResponse) -> bool {
+    skip_reason(response, 0).is_none()
+}
+
+/// Collects streamed chunks into the complete response they add up to, so a streamed answer
+/// can be cached like a buffered one
+#[derive(Debug, Default)]
+pub struct StreamAssembler {
+    id: String,
+    created: u64,
+    model: String,
+    system_fingerprint: Option<String>,
+    /// Text and finish reason per choice index
+    choices: BTreeMap<u32, (String, Option<String>)>,
+    usage: Option<Usage>,
+    has_tool_calls: bool,
+}
+
+impl StreamAssembler {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn push(&mut self, chunk: &ChatCompletionChunk) {
+        if self.id.is_empty() {
+            self.id = chunk.id.clone();
+            self.created = chunk.created;
+            self.model = chunk.model.clone();
+        }
+        if chunk.system_fingerprint.is_some() {
+            self.system_fingerprint = chunk.system_fingerprint.clone();
+        }
+        if chunk.usage.is_some() {
+            self.usage = chunk.usage.clone();
+        }
+        for choice in &chunk.choices {
+            if choice.delta.tool_calls.as_ref().is_some_and(|calls| !calls.is_empty()) {
+                self.has_tool_calls = true;
+            }
+            let (text, finish_reason) = self.choices.entry(choice.index).or_default();
+            if let Some(content) = &choice.delta.content {
+                text.push_str(content);
+            }
+            if choice.finish_reason.is_some() {
+                *finish_reason = choice.finish_reason.clone();
+            }
+        }
+    }
+
+    /// The assembled response, or `None` when it is not worth caching
+    pub fn finish(self) -> Option<ChatCompletionResponse> {
+        if self.has_tool_calls {
+            return None;
+        }
+        let choices = self
+            .choices
+            .into_iter()
+            .map(|(index, (text, finish_reason))| ChatChoice {
+                index,
+                message: ChatMessage {
+                    role: "assistant".to_string(),
+                    content: Some(serde_json::Value::String(text)),
+                    name: None,
+                    tool_calls: None,
+                    tool_call_id: None,
+                },
+                finish_reason,
+                logprobs: None,
+            })
+            .collect();
+        let response = ChatCompletionResponse {
+            id: self.id,
+            object: "chat.completion".to_string(),
+            created: self.created,
+            model: self.model,
+            choices,
+            usage: self.usage,
+            system_fingerprint: self.system_fingerprint,
+        };
+        Some(response).filter(is_cacheable)
+    }
+}
+
+/// Split a cached response back into stream chunks of `chunk_size` characters per choice,
+/// each choice ending with a chunk carrying its finish reason
+pub fn replay_chunks(response: &ChatCompletionResponse, chunk_size: usize) -> Vec<ChatCompletionChunk> {
+    let chunk = |index: u32, delta: ChatMessageDelta, finish_reason: Option<String>| ChatCompletionChunk {
+        id: response.id.clone(),
+        object: "chat.completion.chunk".to_string(),
+        created: response.created,
+        model: response.model.clone(),
+        choices: vec![ChatChoiceDelta { index, delta, finish_reason, logprobs: None }],
+        system_fingerprint: response.system_fingerprint.clone(),
+        usage: None,
+    };
+
+    let mut chunks = Vec::new();
+    for choice in &response.choices {
+        let text: Vec<char> = choice
+            .message
+            .content
+            .as_ref()
+            .and_then(|content| content.as_str())
+            .unwrap_or_default()
+            .chars()
+            .collect();
+        for (position, piece) in text.chunks(chunk_size.max(1)).enumerate() {
+            let delta = ChatMessageDelta {
+                role: (position == 0).then(|| choice.message.role.clone()),
+                content: Some(piece.iter().collect()),
+                tool_calls: None,
+            };
+            chunks.push(chunk(choice.index, delta, None));
+        }
+        let delta = ChatMessageDelta { role: None, content: None, tool_calls: None };
+        chunks.push(chunk(choice.index, delta, choice.finish_reason.clone()));
+    }
+    chunks
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::utils::clock::MockClock;
     use serde_json::json;
 
     #[test]
@@ -289,11 +613,185 @@ mod tests {
     fn test_cache_entry_expiry() {
         let response = ChatCompletionResponse::default();
         let mut entry = CacheEntry::new(response);
+        let created = entry.created_at;
+        let ttl = Duration::from_secs(60);
+
+        assert!(!entry.is_expired(ttl));
+        assert!(!entry.is_expired_at(ttl, created + ttl));
+        assert!(entry.is_expired_at(ttl, created + Duration::from_secs(120)));
+
+        // Its own shorter lifetime wins, and an entry from the future counts as expired
+        entry.ttl = Some(Duration::from_secs(10));
+        assert!(entry.is_expired_at(ttl, created + Duration::from_secs(11)));
+        assert!(entry.is_expired_at(ttl, created - Duration::from_secs(1)));
+    }
 
-        assert!(!entry.is_expired(Duration::from_secs(60)));
+    fn answer(text: &str, finish_reason: &str) -> ChatCompletionResponse {
+        serde_json::from_value(json!({
+            "id": "chatcmpl-1",
+            "object": "chat.completion",
+            "created": 1,
+            "model": "gemini-2.0-flash",
+            "choices": [{
+                "index": 0,
+                "message": {"role": "assistant", "content": text},
+                "finish_reason": finish_reason,
+            }],
+            "usage": {"prompt_tokens": 3, "completion_tokens": 4, "total_tokens": 7},
+        }))
+        .unwrap()
+    }
 
-        // Simulate an old entry
-        entry.created_at = SystemTime::now() - Duration::from_secs(120);
-        assert!(entry.is_expired(Duration::from_secs(60)));
+    #[test]
+    fn test_stream_assembler_round_trips_replayed_chunks() {
+        let response = answer("Hello, wörld! A longer answer.", "stop");
+        let chunks = replay_chunks(&response, 4);
+        assert_eq!(chunks.len(), 9);
+        assert_eq!(chunks[0].choices[0].delta.role.as_deref(), Some("assistant"));
+        assert_eq!(chunks[1].choices[0].delta.content.as_deref(), Some("o, w"));
+        assert_eq!(chunks[2].choices[0].delta.content.as_deref(), Some("örld"));
+        assert_eq!(chunks[8].choices[0].finish_reason.as_deref(), Some("stop"));
+
+        let mut assembler = StreamAssembler::new();
+        for chunk in &chunks {
+            assembler.push(chunk);
+        }
+        let mut usage_chunk = chunks[0].clone();
+        usage_chunk.choices.clear();
+        usage_chunk.usage = response.usage.clone();
+        assembler.push(&usage_chunk);
+
+        let assembled = assembler.finish().unwrap();
+        assert_eq!(assembled.id, "chatcmpl-1");
+        assert_eq!(assembled.choices[0].message.content, Some(json!("Hello, wörld! A longer answer.")));
+        assert_eq!(assembled.usage.unwrap().total_tokens, 7);
+    }
+
+    #[test]
+    fn test_skip_reasons() {
+        assert_eq!(skip_reason(&answer("Fine", "stop"), 0), None);
+        assert_eq!(skip_reason(&ChatCompletionResponse::default(), 0), Some("no choices"));
+        assert_eq!(skip_reason(&answer("Partial", "content_filter"), 0), Some("did not finish with stop"));
+        assert_eq!(skip_reason(&answer("Cut off", "length"), 0), Some("did not finish with stop"));
+        assert_eq!(skip_reason(&answer("", "stop"), 0), Some("blank content"));
+        assert_eq!(skip_reason(&answer("  \n", "stop"), 0), Some("blank content"));
+
+        let mut with_tool_call = answer("Calling", "stop");
+        with_tool_call.choices[0].message.tool_calls = Some(vec![serde_json::from_value(json!({
+            "id": "call_1",
+            "type": "function",
+            "function": {"name": "lookup", "arguments": "{}"},
+        }))
+        .unwrap()]);
+        assert_eq!(skip_reason(&with_tool_call, 0), Some("tool calls"));
+
+        // Reported usage (4 completion tokens) counts, else the text is estimated
+        assert_eq!(skip_reason(&answer("Fine", "stop"), 4), None);
+        assert_eq!(skip_reason(&answer("Fine", "stop"), 5), Some("below min_tokens_to_cache"));
+        let mut unreported = answer("Fine", "stop");
+        unreported.usage = None;
+        assert_eq!(skip_reason(&unreported, 4), Some("below min_tokens_to_cache"));
     }
-}
\ No newline at end of file
+
+    #[tokio::test]
+    async fn test_put_skips_uncacheable_responses_and_honours_entry_ttl() {
+        let settings = Arc::new(Settings { min_tokens_to_cache: 5, ..Default::default() });
+        let clock = MockClock::new();
+        let cache = ResponseCacheManager::new(settings).with_clock(clock.clone());
+
+        cache.put("blocked".to_string(), answer("Partial", "content_filter")).await;
+        cache.put("short".to_string(), answer("Hi", "stop")).await;
+        assert!(cache.get("blocked").await.is_none());
+        assert!(cache.get("short").await.is_none());
+
+        let mut long_enough = answer("Fine", "stop");
+        long_enough.usage = Some(Usage::new(3, 5));
+        cache.put_with_ttl("brief".to_string(), long_enough, Some(Duration::from_secs(60))).await;
+        assert!(cache.get("brief").await.is_some());
+        clock.advance(Duration::from_secs(120));
+        assert!(cache.get("brief").await.is_none());
+    }
+
+    #[tokio::test]
+    async fn test_clear_matching_by_model_and_age() {
+        let clock = MockClock::new();
+        let cache = ResponseCacheManager::new(Arc::new(Settings::default())).with_clock(clock.clone());
+        let messages = vec![json!({"role": "user", "content": "Hello"})];
+        let flash = generate_cache_key(&messages, "gemini_2.0-flash", 6, false);
+        let pro = generate_cache_key(&messages, "gemini-2.5-pro", 6, false);
+        assert_eq!(key_model(&flash), "gemini_2.0-flash");
+
+        cache.put(flash.clone(), answer("Fine", "stop")).await;
+        clock.advance(Duration::from_secs(600));
+        cache.put(flash.clone(), answer("Fine", "stop")).await;
+        cache.put(pro.clone(), answer("Fine", "stop")).await;
+
+        let old_flash = CacheFilter { model: Some("gemini_2.0-flash".to_string()), older_than_secs: Some(300) };
+        assert_eq!(cache.clear_matching(&old_flash).await, 1);
+        assert_eq!(cache.get_stats().await.total_responses, 2);
+
+        let flash_only = CacheFilter { model: Some("gemini_2.0-flash".to_string()), older_than_secs: None };
+        assert_eq!(cache.clear_matching(&flash_only).await, 1);
+        assert!(cache.get(&flash).await.is_none());
+        assert!(cache.get(&pro).await.is_some());
+        assert_eq!(cache.size().await, 1);
+    }
+
+    #[tokio::test]
+    async fn test_expiry_boundary() {
+        let settings = Arc::new(Settings { cache_expiry_time: 60, ..Default::default() });
+        let clock = MockClock::new();
+        let cache = ResponseCacheManager::new(settings).with_clock(clock.clone());
+        cache.put("key".to_string(), answer("Fine", "stop")).await;
+
+        // An entry exactly as old as the TTL is still served; one second more and it is not
+        clock.advance(Duration::from_secs(60));
+        assert!(cache.get("key").await.is_some());
+        assert_eq!(cache.get_stats().await.expired_entries, 0);
+        clock.advance(Duration::from_secs(1));
+        assert_eq!(cache.get_stats().await.expired_entries, 1);
+        assert!(cache.get("key").await.is_none());
+    }
+
+    #[tokio::test]
+    async fn test_cleanup_expired_keeps_fresh_entries() {
+        let settings = Arc::new(Settings { cache_expiry_time: 60, ..Default::default() });
+        let clock = MockClock::new();
+        let cache = ResponseCacheManager::new(settings).with_clock(clock.clone());
+        cache.put("old".to_string(), answer("Fine", "stop")).await;
+        clock.advance(Duration::from_secs(45));
+        cache.put("new".to_string(), answer("Fine", "stop")).await;
+
+        clock.advance(Duration::from_secs(20));
+        assert_eq!(cache.cleanup_expired().await, 1);
+        assert_eq!(cache.size().await, 1);
+        assert!(cache.get("new").await.is_some());
+
+        clock.advance(Duration::from_secs(41));
+        assert_eq!(cache.cleanup_expired().await, 1);
+        assert_eq!(cache.size().await, 0);
+    }
+
+    #[test]
+    fn test_blocked_truncated_and_tool_call_responses_are_not_cacheable() {
+        assert!(is_cacheable(&answer("Fine", "stop")));
+        assert!(!is_cacheable(&answer("Partial", "content_filter")));
+        assert!(!is_cacheable(&answer("Cut off", "length")));
+        assert!(!is_cacheable(&answer("", "stop")));
+        assert!(!is_cacheable(&ChatCompletionResponse::default()));
+
+        let mut chunks = replay_chunks(&answer("Calling", "stop"), 100);
+        chunks[0].choices[0].delta.tool_calls = Some(vec![serde_json::from_value(json!({
+            "index": 0,
+            "id": "call_1",
+            "type": "function",
+            "function": {"name": "lookup", "arguments": "{}"},
+        }))
+        .unwrap()]);
+        let mut assembler = StreamAssembler::new();
+        for chunk in &chunks {
+            assembler.push(chunk);
+        }
+        assert!(assembler.finish().is_none());
+    }
+}
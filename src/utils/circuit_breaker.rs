@@ -0,0 +1,298 @@
+use dashmap::DashMap;
+use serde::{Deserialize, Serialize};
+use std::collections::VecDeque;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use crate::config::Settings;
+use crate::utils::error_handling::GeminiError;
+use crate::utils::logging::log;
+
+const FORCED_OPEN_MESSAGE: &str = "Model disabled by an administrator";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum CircuitState {
+    /// Requests go upstream as usual
+    Closed,
+    /// Requests are refused without an upstream call until the cooldown ends
+    Open,
+    /// The cooldown ended; one probe request decides whether the circuit closes again
+    HalfOpen,
+}
+
+/// Refusal while a model's circuit is open
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CircuitOpen {
+    /// Last error the model returned before the circuit opened
+    pub last_error: String,
+    pub retry_after: Duration,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct CircuitStatus {
+    pub model: String,
+    pub state: CircuitState,
+    /// Outcomes in the window, and how many of them were failures
+    pub requests: usize,
+    pub failures: usize,
+    pub last_error: Option<String>,
+    /// Seconds until an open circuit lets a probe through
+    pub retry_after_secs: Option<u64>,
+}
+
+#[derive(Debug, Default)]
+struct Circuit {
+    /// Most recent outcomes, `true` for a failure
+    outcomes: VecDeque<bool>,
+    /// Set while the circuit is open or half-open
+    open_until: Option<Instant>,
+    /// When the half-open probe was let through
+    probe_started: Option<Instant>,
+    last_error: Option<String>,
+}
+
+impl Circuit {
+    fn state(&self, now: Instant) -> CircuitState {
+        match self.open_until {
+            Some(until) if until > now => CircuitState::Open,
+            Some(_) => CircuitState::HalfOpen,
+            None => CircuitState::Closed,
+        }
+    }
+
+    fn open(&mut self, now: Instant, cooldown: Duration) {
+        self.open_until = Some(now + cooldown);
+        self.probe_started = None;
+    }
+}
+
+/// Per-model circuit breaker. A model whose recent calls keep failing upstream gets its
+/// circuit opened, so its requests are refused straight away instead of spending keys on
+/// calls that are bound to fail. Rate limits and the caller's own mistakes do not count.
+#[derive(Debug, Clone)]
+pub struct ModelCircuits {
+    circuits: Arc<DashMap<String, Circuit>>,
+    window: usize,
+    threshold_percent: usize,
+    cooldown: Duration,
+}
+
+impl ModelCircuits {
+    pub fn new(settings: &Settings) -> Self {
+        Self {
+            circuits: Arc::new(DashMap::new()),
+            window: settings.circuit_breaker_window as usize,
+            threshold_percent: settings.circuit_breaker_threshold_percent.clamp(1, 100) as usize,
+            cooldown: Duration::from_secs(settings.circuit_breaker_cooldown_secs.max(1)),
+        }
+    }
+
+    fn enabled(&self) -> bool {
+        self.window > 0
+    }
+
+    /// `Err` while the model's circuit is open. Once the cooldown has passed a single probe
+    /// is let through; should it never report back, another one is allowed a cooldown later.
+    pub fn check(&self, model: &str, now: Instant) -> Result<(), CircuitOpen> {
+        let mut circuit = match self.circuits.get_mut(model) {
+            Some(circuit) => circuit,
+            None => return Ok(()),
+        };
+        let refuse = |circuit: &Circuit, retry_after: Duration| CircuitOpen {
+            last_error: circuit.last_error.clone().unwrap_or_default(),
+            retry_after,
+        };
+
+        match circuit.open_until {
+            None => Ok(()),
+            Some(until) if until > now => Err(refuse(&circuit, until - now)),
+            Some(_) => match circuit.probe_started {
+                Some(started) if now.duration_since(started) < self.cooldown => {
+                    Err(refuse(&circuit, self.cooldown - now.duration_since(started)))
+                }
+                _ => {
+                    circuit.probe_started = Some(now);
+                    Ok(())
+                }
+            },
+        }
+    }
+
+    pub fn record_success(&self, model: &str, now: Instant) {
+        if !self.enabled() {
+            return;
+        }
+        let mut circuit = self.circuits.entry(model.to_string()).or_default();
+        if circuit.state(now) != CircuitState::Closed {
+            log("info", &format!("Circuit for {} closed, the model answered again", model), None);
+            *circuit = Circuit::default();
+        }
+        self.push(&mut circuit, false);
+    }
+
+    /// Count a failed call; errors that say nothing about the model itself are ignored
+    pub fn record_error(&self, model: &str, error: &GeminiError, now: Instant) {
+        if self.enabled() && error.is_model_failure() {
+            self.record_failure(model, &error.to_string(), now);
+        }
+    }
+
+    fn record_failure(&self, model: &str, message: &str, now: Instant) {
+        let mut circuit = self.circuits.entry(model.to_string()).or_default();
+        circuit.last_error = Some(message.to_string());
+
+        if circuit.open_until.is_some() {
+            // A failed probe, or a call let through before the circuit opened
+            circuit.open(now, self.cooldown);
+            return;
+        }
+        self.push(&mut circuit, true);
+
+        let failures = circuit.outcomes.iter().filter(|failed| **failed).count();
+        if circuit.outcomes.len() >= self.window && failures * 100 >= self.threshold_percent * circuit.outcomes.len() {
+            circuit.open(now, self.cooldown);
+            log(
+                "warning",
+                &format!(
+                    "Circuit for {} opened for {}s after {} of the last {} calls failed: {}",
+                    model,
+                    self.cooldown.as_secs(),
+                    failures,
+                    circuit.outcomes.len(),
+                    message
+                ),
+                None,
+            );
+        }
+    }
+
+    fn push(&self, circuit: &mut Circuit, failed: bool) {
+        circuit.outcomes.push_back(failed);
+        while circuit.outcomes.len() > self.window {
+            circuit.outcomes.pop_front();
+        }
+    }
+
+    /// Admin override: `Open` refuses the model for a cooldown, `HalfOpen` lets the next request
+    /// probe it, and `Closed` forgets its history
+    pub fn set_state(&self, model: &str, state: CircuitState, now: Instant) {
+        match state {
+            CircuitState::Closed => {
+                self.circuits.remove(model);
+            }
+            CircuitState::Open => {
+                let mut circuit = self.circuits.entry(model.to_string()).or_default();
+                circuit.open(now, self.cooldown);
+                circuit.last_error.get_or_insert_with(|| FORCED_OPEN_MESSAGE.to_string());
+            }
+            CircuitState::HalfOpen => {
+                let mut circuit = self.circuits.entry(model.to_string()).or_default();
+                circuit.open_until = Some(now);
+                circuit.probe_started = None;
+            }
+        }
+    }
+
+    /// Every tracked model, sorted by name
+    pub fn snapshot(&self, now: Instant) -> Vec<CircuitStatus> {
+        let mut statuses: Vec<CircuitStatus> = self
+            .circuits
+            .iter()
+            .map(|entry| {
+                let circuit = entry.value();
+                CircuitStatus {
+                    model: entry.key().clone(),
+                    state: circuit.state(now),
+                    requests: circuit.outcomes.len(),
+                    failures: circuit.outcomes.iter().filter(|failed| **failed).count(),
+                    last_error: circuit.last_error.clone(),
+                    retry_after_secs: circuit
+                        .open_until
+                        .filter(|until| *until > now)
+                        .map(|until| (until - now).as_secs()),
+                }
+            })
+            .collect();
+        statuses.sort_by(|a, b| a.model.cmp(&b.model));
+        statuses
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn circuits() -> ModelCircuits {
+        ModelCircuits::new(&Settings {
+            circuit_breaker_window: 10,
+            circuit_breaker_threshold_percent: 90,
+            circuit_breaker_cooldown_secs: 60,
+            ..Default::default()
+        })
+    }
+
+    fn server_error() -> GeminiError {
+        GeminiError::Upstream { status: 500, body: "internal".to_string() }
+    }
+
+    #[test]
+    fn test_failing_model_opens_and_probe_closes() {
+        let circuits = circuits();
+        let start = Instant::now();
+        let model = "gemini-broken";
+
+        circuits.record_success(model, start);
+        for _ in 0..8 {
+            circuits.record_error(model, &server_error(), start);
+        }
+        // 8 of 9 is below the window size, 9 of 10 reaches the threshold
+        assert!(circuits.check(model, start).is_ok());
+        circuits.record_error(model, &server_error(), start);
+
+        let refused = circuits.check(model, start + Duration::from_secs(10)).unwrap_err();
+        assert_eq!(refused.retry_after, Duration::from_secs(50));
+        assert!(refused.last_error.contains("internal"));
+        assert!(circuits.check("gemini-other", start).is_ok());
+
+        // After the cooldown only one probe goes through
+        let later = start + Duration::from_secs(61);
+        assert!(circuits.check(model, later).is_ok());
+        assert!(circuits.check(model, later).is_err());
+        assert_eq!(circuits.snapshot(later)[0].state, CircuitState::HalfOpen);
+
+        // A failed probe reopens the circuit, a successful one closes it
+        circuits.record_error(model, &server_error(), later);
+        assert!(circuits.check(model, later + Duration::from_secs(1)).is_err());
+        let recovered = later + Duration::from_secs(62);
+        assert!(circuits.check(model, recovered).is_ok());
+        circuits.record_success(model, recovered);
+        assert!(circuits.check(model, recovered).is_ok());
+        assert_eq!(circuits.snapshot(recovered)[0].state, CircuitState::Closed);
+    }
+
+    #[test]
+    fn test_rate_limits_and_bad_requests_do_not_count() {
+        let circuits = circuits();
+        let now = Instant::now();
+        for _ in 0..20 {
+            circuits.record_error("gemini-pro", &GeminiError::RateLimited { retry_after: None, body: String::new() }, now);
+            circuits.record_error("gemini-pro", &GeminiError::InvalidRequest { message: "bad".to_string() }, now);
+        }
+        assert!(circuits.check("gemini-pro", now).is_ok());
+        assert!(circuits.snapshot(now).is_empty());
+    }
+
+    #[test]
+    fn test_admin_override() {
+        let circuits = circuits();
+        let now = Instant::now();
+
+        circuits.set_state("gemini-pro", CircuitState::Open, now);
+        assert_eq!(circuits.check("gemini-pro", now).unwrap_err().last_error, FORCED_OPEN_MESSAGE);
+
+        circuits.set_state("gemini-pro", CircuitState::Closed, now);
+        assert!(circuits.check("gemini-pro", now).is_ok());
+        assert!(circuits.snapshot(now).is_empty());
+    }
+}
@@ -0,0 +1,110 @@
+//! Where the time-dependent managers read the time: cache expiry, key cooldowns and daily
+//! limits, stats windows and the rate limiter. Production uses `SystemClock`; tests swap in a
+//! `MockClock` they advance by hand instead of sleeping.
+
+use chrono::{DateTime, Utc};
+use std::sync::Arc;
+use std::time::{Instant, SystemTime};
+
+pub trait Clock: Send + Sync + std::fmt::Debug {
+    /// Wall-clock time, for timestamps and expiry
+    fn now(&self) -> SystemTime;
+
+    /// Monotonic time, for cooldowns and waits
+    fn instant(&self) -> Instant;
+
+    /// `now` as a UTC date-time, for day boundaries
+    fn now_utc(&self) -> DateTime<Utc> {
+        self.now().into()
+    }
+}
+
+/// Shared handle injected into the managers with their `with_clock`
+pub type SharedClock = Arc<dyn Clock>;
+
+/// The real time
+#[derive(Debug, Default, Clone, Copy)]
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> SystemTime {
+        SystemTime::now()
+    }
+
+    fn instant(&self) -> Instant {
+        Instant::now()
+    }
+}
+
+/// The clock managers use unless given another one
+pub fn system_clock() -> SharedClock {
+    Arc::new(SystemClock)
+}
+
+/// A clock that stands still until `advance` is called. Wall-clock and monotonic time move
+/// together, starting from `start` and the moment the clock was made.
+#[cfg(test)]
+#[derive(Debug)]
+pub struct MockClock {
+    start: SystemTime,
+    start_instant: Instant,
+    elapsed: std::sync::Mutex<std::time::Duration>,
+}
+
+#[cfg(test)]
+impl MockClock {
+    pub fn new() -> Arc<Self> {
+        Self::at(SystemTime::now())
+    }
+
+    pub fn at(start: SystemTime) -> Arc<Self> {
+        Arc::new(Self {
+            start,
+            start_instant: Instant::now(),
+            elapsed: std::sync::Mutex::new(std::time::Duration::ZERO),
+        })
+    }
+
+    /// Starting at `rfc3339`, e.g. `2026-01-01T23:59:59Z`
+    pub fn at_utc(rfc3339: &str) -> Arc<Self> {
+        Self::at(DateTime::parse_from_rfc3339(rfc3339).unwrap().with_timezone(&Utc).into())
+    }
+
+    pub fn advance(&self, by: std::time::Duration) {
+        *self.elapsed.lock().unwrap() += by;
+    }
+
+    fn elapsed(&self) -> std::time::Duration {
+        *self.elapsed.lock().unwrap()
+    }
+}
+
+#[cfg(test)]
+impl Clock for MockClock {
+    fn now(&self) -> SystemTime {
+        self.start + self.elapsed()
+    }
+
+    fn instant(&self) -> Instant {
+        self.start_instant + self.elapsed()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    #[test]
+    fn test_mock_clock_only_moves_when_advanced() {
+        let clock = MockClock::at_utc("2026-01-01T23:59:59Z");
+        let (now, instant) = (clock.now(), clock.instant());
+        assert_eq!(clock.now(), now);
+        assert_eq!(clock.instant(), instant);
+
+        clock.advance(Duration::from_secs(2));
+        assert_eq!(clock.now().duration_since(now).unwrap(), Duration::from_secs(2));
+        assert_eq!(clock.instant() - instant, Duration::from_secs(2));
+        assert_eq!(clock.now_utc().format("%Y-%m-%d %H:%M:%S").to_string(), "2026-01-02 00:00:01");
+    }
+}
@@ -0,0 +1,85 @@
+//! Change tracking for `/dashboard-api/data`. Each section of the response gets a cheap
+//! fingerprint of the counters behind it, taken before anything is serialized. The fingerprints
+//! together make the ETag, and the tracker remembers when each one last changed so a poll with
+//! `?since=` only carries the sections that moved.
+
+use std::collections::hash_map::DefaultHasher;
+use std::collections::HashMap;
+use std::hash::{Hash, Hasher};
+use std::sync::Mutex;
+
+/// Fingerprint of the values a section is built from
+pub fn fingerprint<T: Hash>(value: &T) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    value.hash(&mut hasher);
+    hasher.finish()
+}
+
+/// Weak ETag over every section's fingerprint. Weak because fields that only tell the time,
+/// like `uptime`, are left out of it.
+pub fn etag(fingerprints: &[(&'static str, u64)]) -> String {
+    format!("W/\"{:016x}\"", fingerprint(&fingerprints))
+}
+
+#[derive(Debug, Default)]
+struct Seen {
+    /// Last fingerprint of each section and the poll that first saw it
+    sections: HashMap<&'static str, (u64, u64)>,
+    last_poll: u64,
+}
+
+/// When each section of the dashboard data last changed, in unix milliseconds
+#[derive(Debug, Default)]
+pub struct DashboardSections {
+    seen: Mutex<Seen>,
+}
+
+impl DashboardSections {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Record the fingerprints of a poll made at `now`. Returns the poll's cursor, `now` moved
+    /// past any earlier poll so no two share one, and when each section last changed. A change
+    /// is dated by the first poll that sees it, so a section changed after the poll that
+    /// handed out a cursor is always dated after that cursor.
+    pub fn observe(&self, fingerprints: &[(&'static str, u64)], now: u64) -> (u64, HashMap<&'static str, u64>) {
+        let mut seen = self.seen.lock().unwrap();
+        let poll = now.max(seen.last_poll + 1);
+        seen.last_poll = poll;
+        let modified = fingerprints
+            .iter()
+            .map(|&(section, fingerprint)| {
+                let entry = seen.sections.entry(section).or_insert((fingerprint, poll));
+                if entry.0 != fingerprint {
+                    *entry = (fingerprint, poll);
+                }
+                (section, entry.1)
+            })
+            .collect();
+        (poll, modified)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_sections_are_dated_by_their_last_change() {
+        let sections = DashboardSections::new();
+        let (cursor, modified) = sections.observe(&[("stats", 1), ("config", 7)], 100);
+        assert_eq!((cursor, modified["stats"], modified["config"]), (100, 100, 100));
+
+        let (_, modified) = sections.observe(&[("stats", 2), ("config", 7)], 105);
+        assert_eq!((modified["stats"], modified["config"]), (105, 100));
+
+        // Polls in the same millisecond still get distinct cursors
+        let (cursor, modified) = sections.observe(&[("stats", 3), ("config", 7), ("logs", 3)], 105);
+        assert_eq!((cursor, modified["stats"], modified["logs"]), (106, 106, 106));
+
+        assert_eq!(etag(&[("stats", 2)]), etag(&[("stats", 2)]));
+        assert_ne!(etag(&[("stats", 2)]), etag(&[("stats", 3)]));
+        assert!(etag(&[("stats", 2)]).starts_with("W/\""));
+    }
+}
@@ -0,0 +1,165 @@
+use dashmap::DashMap;
+use serde::Serialize;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use crate::config::Settings;
+use crate::utils::error_handling::GeminiError;
+use crate::utils::logging::log;
+
+#[derive(Debug, Clone, Serialize)]
+pub struct EndpointStatus {
+    pub url: String,
+    /// `false` while the endpoint is skipped after repeated failures
+    pub healthy: bool,
+    pub consecutive_failures: u32,
+    /// Requests sent to the endpoint, and how many of them failed there
+    pub requests: u64,
+    pub failures: u64,
+    pub last_error: Option<String>,
+    /// Seconds until a skipped endpoint is tried again
+    pub retry_after_secs: Option<u64>,
+}
+
+#[derive(Debug, Default)]
+struct Health {
+    consecutive_failures: u32,
+    skipped_until: Option<Instant>,
+    requests: u64,
+    failures: u64,
+    last_error: Option<String>,
+}
+
+impl Health {
+    fn skipped(&self, now: Instant) -> bool {
+        self.skipped_until.is_some_and(|until| until > now)
+    }
+}
+
+/// Health of the configured upstream endpoints (`gemini_endpoints`). An endpoint that is
+/// unreachable or answers 5xx `endpoint_failure_threshold` times in a row is skipped for
+/// `endpoint_cooldown_secs`. Keys play no part: an endpoint failing says nothing about them.
+#[derive(Debug, Clone)]
+pub struct EndpointHealth {
+    endpoints: Arc<DashMap<String, Health>>,
+    threshold: u32,
+    cooldown: Duration,
+}
+
+/// Whether `error` points at the endpoint rather than at the request or the key
+pub fn is_endpoint_failure(error: &GeminiError) -> bool {
+    match error {
+        GeminiError::Upstream { status, .. } => *status >= 500,
+        _ => error.is_connection_failure(),
+    }
+}
+
+impl EndpointHealth {
+    pub fn new(settings: &Settings) -> Self {
+        Self {
+            endpoints: Arc::new(DashMap::new()),
+            threshold: settings.endpoint_failure_threshold.max(1),
+            cooldown: Duration::from_secs(settings.endpoint_cooldown_secs),
+        }
+    }
+
+    fn skipped(&self, endpoint: &str, now: Instant) -> bool {
+        self.endpoints.get(endpoint).is_some_and(|health| health.skipped(now))
+    }
+
+    /// `endpoints` in the order to try them: the configured order, with skipped endpoints
+    /// moved to the end so they are still tried when every other one has failed
+    pub fn order<'a>(&self, endpoints: &'a [String], now: Instant) -> Vec<&'a String> {
+        let (ready, skipped): (Vec<_>, Vec<_>) = endpoints.iter().partition(|endpoint| !self.skipped(endpoint, now));
+        ready.into_iter().chain(skipped).collect()
+    }
+
+    pub fn record_success(&self, endpoint: &str) {
+        let mut health = self.endpoints.entry(endpoint.to_string()).or_default();
+        if health.skipped_until.is_some() {
+            log("info", &format!("Upstream endpoint {} answered again", endpoint), None);
+        }
+        health.requests += 1;
+        health.consecutive_failures = 0;
+        health.skipped_until = None;
+    }
+
+    pub fn record_failure(&self, endpoint: &str, error: &GeminiError, now: Instant) {
+        let mut health = self.endpoints.entry(endpoint.to_string()).or_default();
+        health.requests += 1;
+        health.failures += 1;
+        health.consecutive_failures += 1;
+        health.last_error = Some(error.to_string());
+        if health.consecutive_failures >= self.threshold && !health.skipped(now) {
+            log(
+                "warning",
+                &format!(
+                    "Skipping upstream endpoint {} for {}s after {} consecutive failures: {}",
+                    endpoint,
+                    self.cooldown.as_secs(),
+                    health.consecutive_failures,
+                    error
+                ),
+                None,
+            );
+            health.skipped_until = Some(now + self.cooldown);
+        }
+    }
+
+    /// Status of each of `endpoints`, in the configured order
+    pub fn snapshot(&self, endpoints: &[String], now: Instant) -> Vec<EndpointStatus> {
+        endpoints
+            .iter()
+            .map(|url| {
+                let health = self.endpoints.get(url);
+                let health = health.as_deref();
+                let skipped_until = health.and_then(|health| health.skipped_until).filter(|until| *until > now);
+                EndpointStatus {
+                    url: url.clone(),
+                    healthy: skipped_until.is_none(),
+                    consecutive_failures: health.map_or(0, |health| health.consecutive_failures),
+                    requests: health.map_or(0, |health| health.requests),
+                    failures: health.map_or(0, |health| health.failures),
+                    last_error: health.and_then(|health| health.last_error.clone()),
+                    retry_after_secs: skipped_until.map(|until| (until - now).as_secs().max(1)),
+                }
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_failing_endpoint_skipped_until_cooldown_ends() {
+        let health = EndpointHealth::new(&Settings {
+            endpoint_failure_threshold: 2,
+            endpoint_cooldown_secs: 60,
+            ..Default::default()
+        });
+        let endpoints = vec!["https://a".to_string(), "https://b".to_string()];
+        let down = GeminiError::Connection("refused".to_string());
+        let now = Instant::now();
+
+        health.record_failure("https://a", &down, now);
+        assert_eq!(health.order(&endpoints, now), [&endpoints[0], &endpoints[1]]);
+        health.record_failure("https://a", &down, now);
+        assert_eq!(health.order(&endpoints, now), [&endpoints[1], &endpoints[0]]);
+
+        let status = health.snapshot(&endpoints, now);
+        assert!(!status[0].healthy);
+        assert_eq!(status[0].retry_after_secs, Some(60));
+        assert_eq!(status[0].last_error.as_deref(), Some(down.to_string().as_str()));
+        assert!(status[1].healthy);
+
+        let later = now + Duration::from_secs(61);
+        assert_eq!(health.order(&endpoints, later), [&endpoints[0], &endpoints[1]]);
+        health.record_success("https://a");
+        assert_eq!(health.snapshot(&endpoints, later)[0].consecutive_failures, 0);
+
+        assert!(is_endpoint_failure(&GeminiError::Upstream { status: 502, body: String::new() }));
+        assert!(!is_endpoint_failure(&GeminiError::Upstream { status: 400, body: String::new() }));
+    }
+}
@@ -1,8 +1,225 @@
+use axum::{
+    http::{header, HeaderValue, StatusCode},
+    response::{IntoResponse, Response},
+};
 use serde_json::Value;
 use std::collections::HashMap;
+use std::time::Duration;
 use tracing::error;
 
-pub fn translate_error(error_message: &str) -> String {
+use crate::utils::response::{create_error_json, create_error_response};
+
+/// Upstream call exceeded one of the configured timeouts (connect, request or stream idle)
+#[derive(Debug, thiserror::Error)]
+#[error("Upstream {phase} timed out after {seconds}s")]
+pub struct UpstreamTimeoutError {
+    pub phase: &'static str,
+    pub seconds: u64,
+}
+
+/// Classify an upstream failure into the error type used in responses and stats
+pub fn upstream_error_type(error: &anyhow::Error) -> &'static str {
+    let timed_out = error.chain().any(|cause| {
+        cause.is::<UpstreamTimeoutError>()
+            || cause.downcast_ref::<reqwest::Error>().is_some_and(|e| e.is_timeout())
+    });
+
+    if timed_out {
+        "upstream_timeout"
+    } else {
+        "api_error"
+    }
+}
+
+/// Why a call to the Gemini API failed
+#[derive(Debug, thiserror::Error)]
+pub enum GeminiError {
+    /// The request or response body could not be transferred
+    #[error("{0}")]
+    Network(String),
+    /// No response came back: the connection could not be made (DNS, refused) or was dropped
+    /// before Gemini answered
+    #[error("{0}")]
+    Connection(String),
+    #[error(transparent)]
+    Timeout(#[from] UpstreamTimeoutError),
+    /// 429 / RESOURCE_EXHAUSTED; `retry_after` comes from the Retry-After header or the
+    /// `retryDelay` Gemini puts in the error details
+    #[error("Gemini API rate limit exceeded (429): {body}")]
+    RateLimited { retry_after: Option<Duration>, body: String },
+    /// The API key was rejected
+    #[error("Gemini API rejected the API key: {body}")]
+    Unauthorized { body: String },
+    /// Gemini, or the conversion to its format, refused the request as malformed
+    #[error("Invalid request: {message}")]
+    InvalidRequest { message: String },
+    /// The prompt was blocked and no candidate was generated
+    #[error("Content blocked by Gemini: {reason}")]
+    Blocked { reason: String },
+    /// Any other non-success status
+    #[error("Gemini API error: {status} - {body}")]
+    Upstream { status: u16, body: String },
+    /// A success response Gemini sent could not be read
+    #[error("Failed to parse Gemini response: {0}")]
+    Parse(String),
+}
+
+impl GeminiError {
+    /// Classify a non-success response from its status, Retry-After header and body
+    pub fn from_status(status: StatusCode, retry_after: Option<&str>, body: String) -> Self {
+        let reason = serde_json::from_str::<Value>(&body)
+            .ok()
+            .and_then(|json| json["error"]["status"].as_str().map(str::to_string))
+            .unwrap_or_default();
+
+        if status == StatusCode::TOO_MANY_REQUESTS || reason == "RESOURCE_EXHAUSTED" {
+            let retry_after = retry_after
+                .and_then(|value| value.trim().parse().ok())
+                .map(Duration::from_secs)
+                .or_else(|| retry_delay(&body));
+            return Self::RateLimited { retry_after, body };
+        }
+        // Gemini answers an unknown key with 400 API_KEY_INVALID rather than 401
+        if matches!(status, StatusCode::UNAUTHORIZED | StatusCode::FORBIDDEN)
+            || body.contains("API_KEY_INVALID")
+            || reason == "UNAUTHENTICATED"
+        {
+            return Self::Unauthorized { body };
+        }
+        if status == StatusCode::BAD_REQUEST {
+            let message = serde_json::from_str::<Value>(&body)
+                .ok()
+                .and_then(|json| json["error"]["message"].as_str().map(str::to_string))
+                .unwrap_or(body);
+            return Self::InvalidRequest { message };
+        }
+        Self::Upstream { status: status.as_u16(), body }
+    }
+
+    /// A reqwest failure, as `Timeout` when it was caused by one of `timeouts`
+    pub fn transport(error: reqwest::Error, timeouts: &crate::utils::http_client::UpstreamTimeouts, context: &str) -> Self {
+        match crate::utils::http_client::timeout_error(&error, timeouts) {
+            Some(timeout) => Self::Timeout(timeout),
+            None if error.is_decode() => Self::Parse(format!("{}: {}", context, error)),
+            None if error.is_connect() || (error.is_request() && !error.is_body()) => {
+                Self::Connection(format!("{}: {}", context, error))
+            }
+            None => Self::Network(format!("{}: {}", context, error)),
+        }
+    }
+
+    /// Error type used in responses and stats
+    pub fn error_type(&self) -> &'static str {
+        match self {
+            Self::Network(_) | Self::Connection(_) => "api_connection_error",
+            Self::Timeout(_) => "upstream_timeout",
+            Self::RateLimited { .. } => "rate_limit_error",
+            Self::Unauthorized { .. } => "upstream_authentication_error",
+            Self::InvalidRequest { .. } => "invalid_request_error",
+            Self::Blocked { .. } => "content_filter",
+            Self::Upstream { status: 404, .. } => "not_found_error",
+            Self::Upstream { .. } | Self::Parse(_) => "api_error",
+        }
+    }
+
+    /// Status returned to the client. Failures of the upstream itself are reported as 502,
+    /// including a rejected key, which is the proxy's fault rather than the caller's.
+    pub fn status(&self) -> StatusCode {
+        match self {
+            Self::Timeout(_) => StatusCode::GATEWAY_TIMEOUT,
+            Self::RateLimited { .. } => StatusCode::TOO_MANY_REQUESTS,
+            Self::InvalidRequest { .. } | Self::Blocked { .. } => StatusCode::BAD_REQUEST,
+            Self::Upstream { status: 404, .. } => StatusCode::NOT_FOUND,
+            Self::Network(_) | Self::Connection(_) | Self::Unauthorized { .. } | Self::Upstream { .. } | Self::Parse(_) => {
+                StatusCode::BAD_GATEWAY
+            }
+        }
+    }
+
+    pub fn retry_after(&self) -> Option<Duration> {
+        match self {
+            Self::RateLimited { retry_after, .. } => *retry_after,
+            _ => None,
+        }
+    }
+
+    /// Whether the key ran out of quota, so the request may succeed on another key or provider
+    pub fn is_quota(&self) -> bool {
+        matches!(self, Self::RateLimited { .. })
+    }
+
+    /// A failure of the model itself rather than of the key, the quota or the request
+    pub fn is_model_failure(&self) -> bool {
+        match self {
+            Self::Timeout(_) | Self::Parse(_) => true,
+            Self::Upstream { status, .. } => *status == 404 || *status >= 500,
+            _ => false,
+        }
+    }
+
+    /// A network failure before Gemini saw the request, which says nothing about the key and
+    /// may well succeed when simply repeated
+    pub fn is_connection_failure(&self) -> bool {
+        match self {
+            Self::Connection(_) => true,
+            Self::Timeout(timeout) => timeout.phase == "connect",
+            _ => false,
+        }
+    }
+
+    /// Whether the same request may succeed on another key: quota, a rejected key, or a
+    /// transient failure of the connection or the upstream
+    pub fn is_key_failure(&self) -> bool {
+        match self {
+            Self::RateLimited { .. } | Self::Unauthorized { .. } | Self::Network(_) => true,
+            Self::Upstream { status, .. } => matches!(status, 500 | 502 | 503),
+            _ => false,
+        }
+    }
+
+    /// OpenAI-style error body, for errors reported inside an event stream
+    pub fn error_json(&self) -> Value {
+        create_error_json(&translate_error(self), self.error_type())
+    }
+}
+
+impl IntoResponse for GeminiError {
+    fn into_response(self) -> Response {
+        let mut response = create_error_response(&translate_error(&self), self.error_type());
+        *response.status_mut() = self.status();
+        if let Some(retry_after) = self.retry_after() {
+            if let Ok(value) = HeaderValue::from_str(&retry_after.as_secs().max(1).to_string()) {
+                response.headers_mut().insert(header::RETRY_AFTER, value);
+            }
+        }
+        response
+    }
+}
+
+/// `retryDelay` of the RetryInfo detail Gemini attaches to quota errors, e.g. `"37s"`
+fn retry_delay(body: &str) -> Option<Duration> {
+    let json: Value = serde_json::from_str(body).ok()?;
+    json["error"]["details"]
+        .as_array()?
+        .iter()
+        .find_map(|detail| detail["retryDelay"].as_str())
+        .and_then(|delay| delay.trim_end_matches('s').parse::<f64>().ok())
+        .map(Duration::from_secs_f64)
+}
+
+/// Message shown to clients for a Gemini failure. Upstream details that explain a rejected
+/// request are kept; key and quota problems get a fixed message.
+pub fn translate_error(error: &GeminiError) -> String {
+    match error {
+        GeminiError::RateLimited { .. } => "Rate limit exceeded, please try again later".to_string(),
+        GeminiError::Unauthorized { .. } => "API key is invalid or expired".to_string(),
+        GeminiError::Blocked { reason } => format!("Content was blocked by content filter: {}", reason),
+        GeminiError::InvalidRequest { message } => sanitize_error_message(message),
+        other => translate_error_message(&other.to_string()),
+    }
+}
+
+pub fn translate_error_message(error_message: &str) -> String {
     // Map of common Gemini API errors to user-friendly messages
     let error_mappings = create_error_mappings();
 
@@ -86,7 +303,7 @@ pub fn create_gemini_error_response(status: u16, message: &str) -> Value {
 
     serde_json::json!({
         "error": {
-            "message": translate_error(message),
+            "message": translate_error_message(message),
             "type": error_type,
             "code": status.to_string(),
         }
@@ -118,6 +335,14 @@ pub fn is_retryable_error(error_message: &str) -> bool {
     retryable_patterns.iter().any(|pattern| error_lower.contains(pattern))
 }
 
+/// Whether the upstream refused a call for lack of quota rather than because of the request
+pub fn is_quota_error(error_message: &str) -> bool {
+    let quota_patterns = ["429", "resource_exhausted", "quota", "rate limit"];
+
+    let error_lower = error_message.to_lowercase();
+    quota_patterns.iter().any(|pattern| error_lower.contains(pattern))
+}
+
 pub fn extract_error_code(error_message: &str) -> Option<String> {
     // Try to extract HTTP status codes or error codes from error messages
     let patterns = [
@@ -193,23 +418,69 @@ mod tests {
     use super::*;
 
     #[test]
-    fn test_translate_error() {
+    fn test_translate_error_message() {
         assert_eq!(
-            translate_error("Invalid API key provided"),
+            translate_error_message("Invalid API key provided"),
             "API key is invalid or expired"
         );
 
         assert_eq!(
-            translate_error("Rate limit exceeded"),
+            translate_error_message("Rate limit exceeded"),
             "Rate limit exceeded, please try again later"
         );
 
         assert_eq!(
-            translate_error("Some unknown error"),
+            translate_error_message("Some unknown error"),
             "Some unknown error"
         );
     }
 
+    #[test]
+    fn test_gemini_error_from_status() {
+        let quota = r#"{"error": {"code": 429, "status": "RESOURCE_EXHAUSTED", "details": [
+            {"@type": "type.googleapis.com/google.rpc.RetryInfo", "retryDelay": "37s"}]}}"#;
+        let error = GeminiError::from_status(StatusCode::TOO_MANY_REQUESTS, None, quota.to_string());
+        assert_eq!(error.retry_after(), Some(Duration::from_secs(37)));
+        assert!(error.is_quota());
+        // The Retry-After header wins over the body
+        let error = GeminiError::from_status(StatusCode::TOO_MANY_REQUESTS, Some("5"), quota.to_string());
+        assert_eq!(error.retry_after(), Some(Duration::from_secs(5)));
+        // Key cooldowns still recognise the message
+        assert!(is_quota_error(&error.to_string()));
+
+        let bad_key = r#"{"error": {"code": 400, "message": "API key not valid.", "status": "INVALID_ARGUMENT",
+            "details": [{"reason": "API_KEY_INVALID"}]}}"#;
+        assert!(matches!(
+            GeminiError::from_status(StatusCode::BAD_REQUEST, None, bad_key.to_string()),
+            GeminiError::Unauthorized { .. }
+        ));
+
+        let invalid = r#"{"error": {"code": 400, "message": "temperature must be at most 2", "status": "INVALID_ARGUMENT"}}"#;
+        let error = GeminiError::from_status(StatusCode::BAD_REQUEST, None, invalid.to_string());
+        assert_eq!(translate_error(&error), "temperature must be at most 2");
+        assert_eq!(error.status(), StatusCode::BAD_REQUEST);
+
+        let error = GeminiError::from_status(StatusCode::SERVICE_UNAVAILABLE, None, "overloaded".to_string());
+        assert_eq!(error.to_string(), "Gemini API error: 503 - overloaded");
+        assert_eq!((error.status(), error.error_type()), (StatusCode::BAD_GATEWAY, "api_error"));
+    }
+
+    #[test]
+    fn test_gemini_error_response() {
+        let error = GeminiError::RateLimited { retry_after: Some(Duration::from_secs(12)), body: String::new() };
+        let response = error.into_response();
+        assert_eq!(response.status(), StatusCode::TOO_MANY_REQUESTS);
+        assert_eq!(response.headers()[header::RETRY_AFTER], "12");
+
+        let error = GeminiError::Timeout(UpstreamTimeoutError { phase: "request", seconds: 30 });
+        assert_eq!(error.error_type(), "upstream_timeout");
+        assert_eq!(error.into_response().status(), StatusCode::GATEWAY_TIMEOUT);
+
+        let error = GeminiError::Blocked { reason: "SAFETY".to_string() };
+        assert_eq!(error.error_json()["error"]["type"], "content_filter");
+        assert_eq!(error.into_response().status(), StatusCode::BAD_REQUEST);
+    }
+
     #[test]
     fn test_sanitize_error_message() {
         let message = "Error with API_KEY_abc123def456 token";
@@ -227,6 +498,27 @@ mod tests {
         assert!(!is_retryable_error("Content blocked"));
     }
 
+    #[test]
+    fn test_is_quota_error() {
+        assert!(is_quota_error("Gemini API error: 429 Too Many Requests - {}"));
+        assert!(is_quota_error("Vertex AI rate limit or quota exceeded: Resource exhausted"));
+        assert!(is_quota_error(r#"{"status": "RESOURCE_EXHAUSTED"}"#));
+        assert!(!is_quota_error("Gemini API error: 400 Bad Request - invalid argument"));
+        assert!(!is_quota_error("Vertex AI authentication failed: denied"));
+    }
+
+    #[test]
+    fn test_upstream_error_type() {
+        let timeout = anyhow::Error::new(UpstreamTimeoutError { phase: "stream idle", seconds: 30 });
+        assert_eq!(upstream_error_type(&timeout), "upstream_timeout");
+        assert_eq!(timeout.to_string(), "Upstream stream idle timed out after 30s");
+
+        let wrapped = timeout.context("Gemini streaming failed");
+        assert_eq!(upstream_error_type(&wrapped), "upstream_timeout");
+
+        assert_eq!(upstream_error_type(&anyhow::anyhow!("Gemini API error: 500")), "api_error");
+    }
+
     #[test]
     fn test_extract_error_code() {
         assert_eq!(extract_error_code("HTTP 404 Not Found"), Some("404".to_string()));
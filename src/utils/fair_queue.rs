@@ -0,0 +1,366 @@
+use serde::Serialize;
+use std::collections::{HashMap, VecDeque};
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::time::{Duration, Instant};
+use tokio::sync::Notify;
+
+use crate::config::Settings;
+use crate::utils::stats::percentile;
+
+/// Requests one caller may have waiting; later ones are refused with a 429
+pub const MAX_QUEUED_PER_CALLER: usize = 256;
+
+/// Recent waits kept per caller for `wait_p95_ms`
+const WAIT_SAMPLES: usize = 100;
+
+/// Callers with nothing queued or in flight are forgotten after this long
+const IDLE_FORGET: Duration = Duration::from_secs(600);
+
+#[derive(Debug, Clone, Serialize)]
+pub struct CallerQueueStatus {
+    /// `client:<name>` for a client key, `ip:<address>` otherwise
+    pub caller: String,
+    pub weight: u32,
+    pub queued: usize,
+    pub in_flight: usize,
+    pub served: u64,
+    /// Over the caller's last 100 requests
+    pub wait_p95_ms: Option<u64>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct FairQueueStatus {
+    pub enabled: bool,
+    pub concurrency: usize,
+    pub min_request_interval_ms: u64,
+    pub in_flight: usize,
+    pub callers: Vec<CallerQueueStatus>,
+}
+
+/// The caller already has `MAX_QUEUED_PER_CALLER` requests waiting
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct QueueFull;
+
+#[derive(Debug)]
+struct Caller {
+    weight: u32,
+    /// Tickets of the waiting requests, oldest first
+    waiting: VecDeque<u64>,
+    in_flight: usize,
+    served: u64,
+    /// Requests started in the caller's current round-robin turn
+    turn_served: u32,
+    last_start: Option<Instant>,
+    last_active: Instant,
+    waits_ms: VecDeque<u64>,
+}
+
+impl Caller {
+    fn new(now: Instant) -> Self {
+        Self {
+            weight: 1,
+            waiting: VecDeque::new(),
+            in_flight: 0,
+            served: 0,
+            turn_served: 0,
+            last_start: None,
+            last_active: now,
+            waits_ms: VecDeque::new(),
+        }
+    }
+
+    /// When `min_interval` lets the caller start its next request
+    fn ready_at(&self, min_interval: Duration) -> Option<Instant> {
+        self.last_start.map(|started| started + min_interval)
+    }
+}
+
+#[derive(Debug, Default)]
+struct Queues {
+    callers: HashMap<String, Caller>,
+    /// Callers with waiting requests, in round-robin order
+    rotation: VecDeque<String>,
+    in_flight: usize,
+    next_ticket: u64,
+}
+
+impl Queues {
+    /// Ticket of the request to start next: the oldest of the first caller in the rotation
+    /// that `min_interval` does not hold back, as long as there is capacity left
+    fn next_up(&self, now: Instant, concurrency: usize, min_interval: Duration) -> Option<u64> {
+        if self.in_flight >= concurrency {
+            return None;
+        }
+        self.rotation
+            .iter()
+            .filter_map(|name| self.callers.get(name))
+            .find(|caller| caller.ready_at(min_interval).is_none_or(|ready| ready <= now))
+            .and_then(|caller| caller.waiting.front().copied())
+    }
+
+    /// Soonest moment `min_interval` lets a waiting caller through
+    fn next_ready_at(&self, min_interval: Duration) -> Option<Instant> {
+        self.rotation
+            .iter()
+            .filter_map(|name| self.callers.get(name)?.ready_at(min_interval))
+            .min()
+    }
+
+    fn start(&mut self, name: &str, now: Instant, queued_at: Instant) {
+        let caller = match self.callers.get_mut(name) {
+            Some(caller) => caller,
+            None => return,
+        };
+        caller.waiting.pop_front();
+        caller.in_flight += 1;
+        caller.served += 1;
+        caller.turn_served += 1;
+        caller.last_start = Some(now);
+        caller.last_active = now;
+        if caller.waits_ms.len() == WAIT_SAMPLES {
+            caller.waits_ms.pop_front();
+        }
+        caller.waits_ms.push_back(now.duration_since(queued_at).as_millis() as u64);
+        self.in_flight += 1;
+
+        // A caller's turn ends after `weight` requests, or when it has none left waiting
+        if caller.turn_served >= caller.weight || caller.waiting.is_empty() {
+            caller.turn_served = 0;
+            let requeue = !caller.waiting.is_empty();
+            self.rotation.retain(|queued| queued != name);
+            if requeue {
+                self.rotation.push_back(name.to_string());
+            }
+        }
+    }
+
+    fn withdraw(&mut self, name: &str, ticket: u64) {
+        if let Some(caller) = self.callers.get_mut(name) {
+            caller.waiting.retain(|queued| *queued != ticket);
+            if caller.waiting.is_empty() {
+                caller.turn_served = 0;
+                self.rotation.retain(|queued| queued != name);
+            }
+        }
+    }
+
+    fn forget_idle(&mut self, now: Instant) {
+        self.callers.retain(|_, caller| {
+            !caller.waiting.is_empty() || caller.in_flight > 0 || now.duration_since(caller.last_active) < IDLE_FORGET
+        });
+    }
+}
+
+/// Fair queuing of chat requests in front of key selection (`fair_queuing`). Each caller
+/// waits in its own queue and callers are let through in weighted round-robin, at most
+/// `fair_queue_concurrency` requests at a time, so one busy caller cannot starve the rest.
+/// `min_request_interval_ms` additionally spaces out the requests of any one caller.
+#[derive(Debug)]
+pub struct FairQueue {
+    queues: Mutex<Queues>,
+    changed: Notify,
+    enabled: bool,
+    concurrency: usize,
+    min_interval: Duration,
+}
+
+/// A request's place among the `fair_queue_concurrency` running ones, given back on drop
+#[derive(Debug)]
+pub struct Turn {
+    queue: Arc<FairQueue>,
+    caller: String,
+}
+
+impl Drop for Turn {
+    fn drop(&mut self) {
+        {
+            let mut queues = self.queue.lock();
+            queues.in_flight = queues.in_flight.saturating_sub(1);
+            if let Some(caller) = queues.callers.get_mut(&self.caller) {
+                caller.in_flight = caller.in_flight.saturating_sub(1);
+                caller.last_active = Instant::now();
+            }
+        }
+        self.queue.changed.notify_waiters();
+    }
+}
+
+/// Takes a waiting request out of its queue when it is abandoned before its turn
+struct Waiting<'a> {
+    queue: &'a FairQueue,
+    caller: &'a str,
+    ticket: u64,
+    started: bool,
+}
+
+impl Drop for Waiting<'_> {
+    fn drop(&mut self) {
+        if !self.started {
+            self.queue.lock().withdraw(self.caller, self.ticket);
+            self.queue.changed.notify_waiters();
+        }
+    }
+}
+
+impl FairQueue {
+    pub fn new(settings: &Settings) -> Self {
+        Self {
+            queues: Mutex::new(Queues::default()),
+            changed: Notify::new(),
+            enabled: settings.fair_queuing,
+            concurrency: settings.fair_queue_concurrency.max(1),
+            min_interval: Duration::from_millis(settings.min_request_interval_ms),
+        }
+    }
+
+    pub fn enabled(&self) -> bool {
+        self.enabled
+    }
+
+    fn lock(&self) -> MutexGuard<'_, Queues> {
+        self.queues.lock().unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+
+    /// Wait for `caller`'s turn. `weight` is how many requests the caller may start per
+    /// round-robin turn.
+    pub async fn admit(self: &Arc<Self>, caller: &str, weight: u32) -> Result<Turn, QueueFull> {
+        let queued_at = Instant::now();
+        let ticket = {
+            let mut queues = self.lock();
+            queues.forget_idle(queued_at);
+            let ticket = queues.next_ticket;
+            let entry = queues.callers.entry(caller.to_string()).or_insert_with(|| Caller::new(queued_at));
+            if entry.waiting.len() >= MAX_QUEUED_PER_CALLER {
+                return Err(QueueFull);
+            }
+            entry.weight = weight.max(1);
+            entry.last_active = queued_at;
+            entry.waiting.push_back(ticket);
+            queues.next_ticket += 1;
+            if !queues.rotation.iter().any(|queued| queued == caller) {
+                queues.rotation.push_back(caller.to_string());
+            }
+            ticket
+        };
+        let mut waiting = Waiting { queue: self, caller, ticket, started: false };
+
+        loop {
+            let changed = self.changed.notified();
+            let wake = {
+                let mut queues = self.lock();
+                let now = Instant::now();
+                if queues.next_up(now, self.concurrency, self.min_interval) == Some(ticket) {
+                    queues.start(caller, now, queued_at);
+                    waiting.started = true;
+                    None
+                } else if queues.in_flight < self.concurrency {
+                    queues.next_ready_at(self.min_interval).filter(|ready| *ready > now)
+                } else {
+                    None
+                }
+            };
+            if waiting.started {
+                // Capacity may be left for whoever is next
+                self.changed.notify_waiters();
+                return Ok(Turn { queue: self.clone(), caller: caller.to_string() });
+            }
+            match wake {
+                Some(ready) => {
+                    tokio::select! {
+                        _ = changed => {}
+                        _ = tokio::time::sleep_until(ready.into()) => {}
+                    }
+                }
+                None => changed.await,
+            }
+        }
+    }
+
+    pub fn status(&self) -> FairQueueStatus {
+        let queues = self.lock();
+        let mut callers: Vec<CallerQueueStatus> = queues
+            .callers
+            .iter()
+            .map(|(name, caller)| {
+                let mut waits: Vec<u64> = caller.waits_ms.iter().copied().collect();
+                CallerQueueStatus {
+                    caller: name.clone(),
+                    weight: caller.weight,
+                    queued: caller.waiting.len(),
+                    in_flight: caller.in_flight,
+                    served: caller.served,
+                    wait_p95_ms: percentile(&mut waits, 95),
+                }
+            })
+            .collect();
+        callers.sort_by(|a, b| b.queued.cmp(&a.queued).then_with(|| a.caller.cmp(&b.caller)));
+        FairQueueStatus {
+            enabled: self.enabled,
+            concurrency: self.concurrency,
+            min_request_interval_ms: self.min_interval.as_millis() as u64,
+            in_flight: queues.in_flight,
+            callers,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn queue(concurrency: usize, min_request_interval_ms: u64) -> Arc<FairQueue> {
+        Arc::new(FairQueue::new(&Settings {
+            fair_queuing: true,
+            fair_queue_concurrency: concurrency,
+            min_request_interval_ms,
+            ..Default::default()
+        }))
+    }
+
+    #[tokio::test]
+    async fn test_callers_served_in_weighted_round_robin() {
+        let queue = queue(1, 0);
+        let running = queue.admit("client:busy", 1).await.unwrap();
+
+        // A burst from one caller queues up before a single request from another
+        let order = Arc::new(Mutex::new(Vec::new()));
+        let mut waiting = Vec::new();
+        for (caller, weight) in [("client:busy", 1), ("client:busy", 1), ("client:busy", 1), ("client:other", 2), ("client:other", 2)] {
+            let (queue, order) = (queue.clone(), order.clone());
+            waiting.push(tokio::spawn(async move {
+                let _turn = queue.admit(caller, weight).await.unwrap();
+                order.lock().unwrap().push(caller);
+                tokio::task::yield_now().await;
+            }));
+            tokio::task::yield_now().await;
+        }
+        let status = queue.status();
+        assert_eq!((status.in_flight, status.callers[0].caller.as_str(), status.callers[0].queued), (1, "client:busy", 3));
+
+        drop(running);
+        for task in waiting {
+            task.await.unwrap();
+        }
+        assert_eq!(*order.lock().unwrap(), ["client:busy", "client:other", "client:other", "client:busy", "client:busy"]);
+        let status = queue.status();
+        assert_eq!(status.in_flight, 0);
+        assert!(status.callers.iter().all(|caller| caller.queued == 0 && caller.wait_p95_ms.is_some()));
+    }
+
+    #[tokio::test]
+    async fn test_min_interval_spaces_out_one_caller() {
+        let queue = queue(4, 50);
+        let started = Instant::now();
+        drop(queue.admit("ip:10.0.0.1", 1).await.unwrap());
+        drop(queue.admit("ip:10.0.0.2", 1).await.unwrap());
+        assert!(started.elapsed() < Duration::from_millis(50));
+        drop(queue.admit("ip:10.0.0.1", 1).await.unwrap());
+        assert!(started.elapsed() >= Duration::from_millis(50));
+
+        // An abandoned wait leaves the queue
+        let _running = queue.admit("ip:10.0.0.3", 1).await.unwrap();
+        let abandoned = tokio::time::timeout(Duration::from_millis(10), queue.admit("ip:10.0.0.3", 1)).await;
+        assert!(abandoned.is_err());
+        assert!(queue.status().callers.iter().all(|caller| caller.queued == 0));
+    }
+}
@@ -0,0 +1,146 @@
+use serde::Serialize;
+use std::future::Future;
+use std::path::Path;
+use std::time::{Duration, Instant};
+use tokio::sync::Mutex;
+
+use crate::utils::endpoints::EndpointStatus;
+
+/// How long a readiness report is reused before upstream is probed again
+pub const READINESS_CACHE_TTL: Duration = Duration::from_secs(60);
+
+const STORAGE_PROBE_FILE: &str = ".rujimi-ready-probe";
+
+#[derive(Debug, Clone, Serialize)]
+pub struct ComponentStatus {
+    pub name: &'static str,
+    pub healthy: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub detail: Option<String>,
+}
+
+impl ComponentStatus {
+    pub fn healthy(name: &'static str, detail: impl Into<Option<String>>) -> Self {
+        Self { name, healthy: true, detail: detail.into() }
+    }
+
+    pub fn failing(name: &'static str, detail: impl Into<String>) -> Self {
+        Self { name, healthy: false, detail: Some(detail.into()) }
+    }
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct ReadinessReport {
+    pub status: &'static str,
+    pub checked_at: String,
+    pub components: Vec<ComponentStatus>,
+    /// Names of the failing components, empty when ready
+    pub failing: Vec<&'static str>,
+    /// Health of each upstream endpoint, in failover order
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub endpoints: Vec<EndpointStatus>,
+}
+
+impl ReadinessReport {
+    pub fn new(components: Vec<ComponentStatus>) -> Self {
+        let failing: Vec<&'static str> = components
+            .iter()
+            .filter(|component| !component.healthy)
+            .map(|component| component.name)
+            .collect();
+
+        Self {
+            status: if failing.is_empty() { "ready" } else { "degraded" },
+            checked_at: chrono::Utc::now().to_rfc3339(),
+            components,
+            failing,
+            endpoints: Vec::new(),
+        }
+    }
+
+    pub fn with_endpoints(self, endpoints: Vec<EndpointStatus>) -> Self {
+        Self { endpoints, ..self }
+    }
+
+    pub fn is_ready(&self) -> bool {
+        self.failing.is_empty()
+    }
+}
+
+/// Keeps the last readiness report so frequent probes don't hammer upstream
+#[derive(Debug, Default)]
+pub struct ReadinessCache {
+    last: Mutex<Option<(Instant, ReadinessReport)>>,
+}
+
+impl ReadinessCache {
+    /// Return the cached report if younger than `ttl`, otherwise run `check`.
+    /// Concurrent callers wait for a single refresh instead of all probing upstream.
+    pub async fn get_or_refresh<F, Fut>(&self, ttl: Duration, check: F) -> ReadinessReport
+    where
+        F: FnOnce() -> Fut,
+        Fut: Future<Output = ReadinessReport>,
+    {
+        let mut last = self.last.lock().await;
+        if let Some((checked, report)) = last.as_ref() {
+            if checked.elapsed() < ttl {
+                return report.clone();
+            }
+        }
+
+        let report = check().await;
+        *last = Some((Instant::now(), report.clone()));
+        report
+    }
+}
+
+/// Verify the storage directory can be written by creating and removing a probe file
+pub async fn check_storage_writable(dir: &str) -> Result<(), String> {
+    let probe = Path::new(dir).join(STORAGE_PROBE_FILE);
+    tokio::fs::create_dir_all(dir)
+        .await
+        .map_err(|e| format!("cannot create {}: {}", dir, e))?;
+    tokio::fs::write(&probe, b"ok")
+        .await
+        .map_err(|e| format!("cannot write to {}: {}", dir, e))?;
+    let _ = tokio::fs::remove_file(&probe).await;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    #[tokio::test]
+    async fn test_report_is_cached_within_ttl() {
+        let cache = ReadinessCache::default();
+        let checks = AtomicUsize::new(0);
+        let check = || async {
+            checks.fetch_add(1, Ordering::SeqCst);
+            ReadinessReport::new(vec![ComponentStatus::failing("upstream", "connection refused")])
+        };
+
+        let report = cache.get_or_refresh(Duration::from_secs(60), check).await;
+        assert!(!report.is_ready());
+        assert_eq!(report.failing, vec!["upstream"]);
+
+        cache.get_or_refresh(Duration::from_secs(60), check).await;
+        assert_eq!(checks.load(Ordering::SeqCst), 1);
+
+        cache.get_or_refresh(Duration::ZERO, check).await;
+        assert_eq!(checks.load(Ordering::SeqCst), 2);
+    }
+
+    #[tokio::test]
+    async fn test_storage_check() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("settings");
+        assert!(check_storage_writable(path.to_str().unwrap()).await.is_ok());
+        assert!(!path.join(STORAGE_PROBE_FILE).exists());
+
+        let file = dir.path().join("not-a-dir");
+        std::fs::write(&file, "x").unwrap();
+        assert!(check_storage_writable(file.to_str().unwrap()).await.is_err());
+    }
+}
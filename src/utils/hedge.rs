@@ -0,0 +1,76 @@
+//! Request hedging: when an upstream call is slow, a second identical call is started and
+//! whichever succeeds first is used. The other call is dropped, which cancels it.
+
+use std::future::Future;
+use std::time::Duration;
+
+pub struct Raced<T, E> {
+    /// The first success, or the error of the attempt that finished last
+    pub result: Result<T, E>,
+    /// Whether `hedge` was started
+    pub hedged: bool,
+}
+
+/// Await `primary`; if it has not finished after `delay`, also start `hedge` and return the
+/// first of the two to succeed. A failure only wins once the other attempt has failed too.
+pub async fn race<T, E>(
+    delay: Duration,
+    primary: impl Future<Output = Result<T, E>>,
+    hedge: impl Future<Output = Result<T, E>>,
+) -> Raced<T, E> {
+    tokio::pin!(primary);
+    tokio::pin!(hedge);
+
+    tokio::select! {
+        result = &mut primary => return Raced { result, hedged: false },
+        _ = tokio::time::sleep(delay) => {}
+    }
+
+    let result = tokio::select! {
+        result = &mut primary => match result {
+            Ok(value) => Ok(value),
+            Err(_) => hedge.await,
+        },
+        result = &mut hedge => match result {
+            Ok(value) => Ok(value),
+            Err(_) => primary.await,
+        },
+    };
+    Raced { result, hedged: true }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::{AtomicBool, Ordering};
+
+    async fn attempt(after_ms: u64, result: Result<&'static str, &'static str>, finished: &AtomicBool) -> Result<&'static str, &'static str> {
+        tokio::time::sleep(Duration::from_millis(after_ms)).await;
+        finished.store(true, Ordering::SeqCst);
+        result
+    }
+
+    #[tokio::test]
+    async fn test_race() {
+        let delay = Duration::from_millis(50);
+        let (first, second) = (AtomicBool::new(false), AtomicBool::new(false));
+
+        // A fast primary never starts the hedge
+        let raced = race(delay, attempt(0, Ok("primary"), &first), attempt(0, Ok("hedge"), &second)).await;
+        assert_eq!((raced.result, raced.hedged), (Ok("primary"), false));
+        assert!(!second.load(Ordering::SeqCst));
+
+        // A slow primary loses to the hedge and is cancelled
+        let first = AtomicBool::new(false);
+        let raced = race(delay, attempt(500, Ok("primary"), &first), attempt(0, Ok("hedge"), &second)).await;
+        assert_eq!((raced.result, raced.hedged), (Ok("hedge"), true));
+        assert!(!first.load(Ordering::SeqCst));
+
+        // A failing hedge leaves the primary to finish
+        let raced = race(delay, attempt(150, Ok("primary"), &first), attempt(0, Err("hedge failed"), &second)).await;
+        assert_eq!(raced.result, Ok("primary"));
+
+        let raced = race(delay, attempt(60, Err("primary failed"), &first), attempt(150, Err("hedge failed"), &second)).await;
+        assert_eq!(raced.result, Err("hedge failed"));
+    }
+}
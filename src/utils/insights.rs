@@ -0,0 +1,286 @@
+//! Derived numbers for the dashboard: the busiest models of the last day, the least healthy
+//! key, the cache hit ratio and a failure spike warning. Calls are folded into hourly and
+//! per-minute buckets as they are recorded, so reading the insights never walks the raw
+//! call records.
+
+use serde::Serialize;
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use super::api_key::ApiKeyStats;
+
+/// Hourly buckets kept for the model leaderboard
+const HOURS: usize = 24;
+/// Minute buckets kept for the failure comparison: the recent window plus the hour before it
+const MINUTES: usize = 70;
+/// Failures in this many recent minutes are compared with the hour before
+const RECENT_MINUTES: u64 = 10;
+const TOP_MODELS: usize = 5;
+/// Keys with fewer calls today are too new to call unhealthy
+const MIN_KEY_CALLS: u32 = 5;
+/// A spike needs this many recent requests, a failure rate of at least
+/// `ANOMALY_MIN_FAILURE_RATE` percent and `ANOMALY_FACTOR` times the rate of the hour before
+const ANOMALY_MIN_REQUESTS: u64 = 10;
+const ANOMALY_MIN_FAILURE_RATE: f64 = 20.0;
+const ANOMALY_FACTOR: f64 = 2.0;
+
+#[derive(Debug, Clone, Default)]
+struct HourBucket {
+    hour: u64,
+    /// Requests and tokens per model
+    models: HashMap<String, (u64, u64)>,
+}
+
+#[derive(Debug, Clone, Copy, Default)]
+struct MinuteBucket {
+    minute: u64,
+    requests: u64,
+    failures: u64,
+}
+
+#[derive(Debug)]
+struct Buckets {
+    hours: Vec<HourBucket>,
+    minutes: Vec<MinuteBucket>,
+}
+
+/// Rolling per-model usage over the last day and failure counts over the last 70 minutes
+#[derive(Debug)]
+pub struct UsageWindows {
+    buckets: Mutex<Buckets>,
+}
+
+impl Default for UsageWindows {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl UsageWindows {
+    pub fn new() -> Self {
+        Self {
+            buckets: Mutex::new(Buckets {
+                hours: vec![HourBucket::default(); HOURS],
+                minutes: vec![MinuteBucket::default(); MINUTES],
+            }),
+        }
+    }
+
+    /// Record a call made at `now_secs`, live or restored from storage
+    pub fn record_at(&self, model: &str, tokens: u64, success: bool, now_secs: u64) {
+        let mut buckets = self.buckets.lock().unwrap_or_else(|e| e.into_inner());
+
+        // A sample older than what a bucket already holds is dropped rather than resetting it
+        let hour = now_secs / 3600;
+        let bucket = &mut buckets.hours[(hour % HOURS as u64) as usize];
+        if bucket.hour > hour {
+            return;
+        }
+        if bucket.hour != hour {
+            *bucket = HourBucket { hour, models: HashMap::new() };
+        }
+        let usage = bucket.models.entry(model.to_string()).or_default();
+        usage.0 += 1;
+        usage.1 += tokens;
+
+        let minute = now_secs / 60;
+        let bucket = &mut buckets.minutes[(minute % MINUTES as u64) as usize];
+        if bucket.minute > minute {
+            return;
+        }
+        if bucket.minute != minute {
+            *bucket = MinuteBucket { minute, ..Default::default() };
+        }
+        bucket.requests += 1;
+        if !success {
+            bucket.failures += 1;
+        }
+    }
+
+    pub fn clear(&self) {
+        let mut buckets = self.buckets.lock().unwrap_or_else(|e| e.into_inner());
+        buckets.hours.fill(HourBucket::default());
+        buckets.minutes.fill(MinuteBucket::default());
+    }
+
+    /// Requests and tokens per model over the last 24 hours
+    fn model_usage(&self, now_secs: u64) -> Vec<ModelUsage> {
+        let current = now_secs / 3600;
+        let buckets = self.buckets.lock().unwrap_or_else(|e| e.into_inner());
+        let mut totals: HashMap<&str, (u64, u64)> = HashMap::new();
+        for bucket in buckets.hours.iter().filter(|bucket| bucket.hour + (HOURS as u64) > current && bucket.hour <= current) {
+            for (model, (requests, tokens)) in &bucket.models {
+                let total = totals.entry(model.as_str()).or_default();
+                total.0 += requests;
+                total.1 += tokens;
+            }
+        }
+        totals
+            .into_iter()
+            .map(|(model, (requests, tokens))| ModelUsage { model: model.to_string(), requests, tokens })
+            .collect()
+    }
+
+    fn failure_anomaly(&self, now_secs: u64) -> FailureAnomaly {
+        let current = now_secs / 60;
+        let buckets = self.buckets.lock().unwrap_or_else(|e| e.into_inner());
+        let (mut recent, mut baseline) = ((0, 0), (0, 0));
+        for bucket in buckets.minutes.iter().filter(|bucket| bucket.minute <= current) {
+            let age = current - bucket.minute;
+            let window = match age {
+                age if age < RECENT_MINUTES => &mut recent,
+                age if age < MINUTES as u64 => &mut baseline,
+                _ => continue,
+            };
+            window.0 += bucket.requests;
+            window.1 += bucket.failures;
+        }
+
+        let rate = |(requests, failures): (u64, u64)| if requests == 0 { 0.0 } else { failures as f64 * 100.0 / requests as f64 };
+        let (recent_failure_rate, baseline_failure_rate) = (rate(recent), rate(baseline));
+        FailureAnomaly {
+            flagged: recent.0 >= ANOMALY_MIN_REQUESTS
+                && recent_failure_rate >= ANOMALY_MIN_FAILURE_RATE
+                && recent_failure_rate >= baseline_failure_rate * ANOMALY_FACTOR,
+            recent_requests: recent.0,
+            recent_failure_rate,
+            baseline_failure_rate,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct ModelUsage {
+    pub model: String,
+    pub requests: u64,
+    pub tokens: u64,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct KeyHealth {
+    pub key_prefix: String,
+    /// Calls since the daily reset
+    pub requests: u32,
+    /// Successful calls as a percentage
+    pub success_rate: f64,
+}
+
+/// Failure rate of the last 10 minutes against the hour before them, in percent
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct FailureAnomaly {
+    pub flagged: bool,
+    pub recent_requests: u64,
+    pub recent_failure_rate: f64,
+    pub baseline_failure_rate: f64,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct Insights {
+    /// The five models with the most requests in the last 24 hours
+    pub top_models_by_requests: Vec<ModelUsage>,
+    /// The five models with the most tokens in the last 24 hours
+    pub top_models_by_tokens: Vec<ModelUsage>,
+    /// The key with the lowest success rate today, among keys with a few calls
+    pub worst_key: Option<KeyHealth>,
+    pub cache_hit_ratio: f64,
+    pub failure_anomaly: FailureAnomaly,
+}
+
+impl Insights {
+    pub fn compute(windows: &UsageWindows, key_stats: &[(String, ApiKeyStats)], cache_hit_ratio: f64) -> Self {
+        Self::compute_at(windows, key_stats, cache_hit_ratio, unix_secs())
+    }
+
+    fn compute_at(windows: &UsageWindows, key_stats: &[(String, ApiKeyStats)], cache_hit_ratio: f64, now_secs: u64) -> Self {
+        let mut usage = windows.model_usage(now_secs);
+        usage.sort_by(|a, b| b.requests.cmp(&a.requests).then_with(|| a.model.cmp(&b.model)));
+        let top_models_by_requests = usage.iter().take(TOP_MODELS).cloned().collect();
+        usage.sort_by(|a, b| b.tokens.cmp(&a.tokens).then_with(|| a.model.cmp(&b.model)));
+        let top_models_by_tokens = usage.into_iter().take(TOP_MODELS).collect();
+
+        Self {
+            top_models_by_requests,
+            top_models_by_tokens,
+            worst_key: worst_key(key_stats),
+            cache_hit_ratio,
+            failure_anomaly: windows.failure_anomaly(now_secs),
+        }
+    }
+}
+
+fn worst_key(key_stats: &[(String, ApiKeyStats)]) -> Option<KeyHealth> {
+    key_stats
+        .iter()
+        .filter_map(|(key, stats)| {
+            let requests = stats.daily_usage + stats.daily_failures;
+            if requests < MIN_KEY_CALLS {
+                return None;
+            }
+            Some(KeyHealth {
+                key_prefix: format!("{}...", &key[..8.min(key.len())]),
+                requests,
+                success_rate: stats.daily_usage as f64 * 100.0 / requests as f64,
+            })
+        })
+        .min_by(|a, b| a.success_rate.total_cmp(&b.success_rate).then_with(|| b.requests.cmp(&a.requests)))
+}
+
+fn unix_secs() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default().as_secs()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_model_leaderboard_covers_the_last_day() {
+        let windows = UsageWindows::new();
+        let now = 500_000 * 3600;
+        for i in 0..6u64 {
+            for _ in 0..=i {
+                windows.record_at(&format!("model-{}", i), 10, true, now - 60);
+            }
+        }
+        windows.record_at("model-0", 1_000, true, now - 2 * 3600);
+        // More than a day old
+        windows.record_at("model-old", 5_000, true, now - 25 * 3600);
+
+        let insights = Insights::compute_at(&windows, &[], 0.5, now);
+        let by_requests: Vec<&str> = insights.top_models_by_requests.iter().map(|usage| usage.model.as_str()).collect();
+        assert_eq!(by_requests, ["model-5", "model-4", "model-3", "model-2", "model-0"]);
+        assert_eq!(insights.top_models_by_tokens[0], ModelUsage { model: "model-0".to_string(), requests: 2, tokens: 1_010 });
+        assert!(insights.top_models_by_tokens.iter().all(|usage| usage.model != "model-old"));
+        assert_eq!(insights.cache_hit_ratio, 0.5);
+    }
+
+    #[test]
+    fn test_failure_anomaly_and_worst_key() {
+        let windows = UsageWindows::new();
+        let now = 500_000 * 3600;
+        // A quiet hour with 5% failures, then half of the last 10 minutes failing
+        for i in 0..40u64 {
+            windows.record_at("gemini-2.0-flash", 0, i % 20 != 0, now - 30 * 60 - i * 30);
+        }
+        assert!(!Insights::compute_at(&windows, &[], 0.0, now).failure_anomaly.flagged);
+        for i in 0..12u64 {
+            windows.record_at("gemini-2.0-flash", 0, i % 2 == 0, now - i * 30);
+        }
+        let anomaly = Insights::compute_at(&windows, &[], 0.0, now).failure_anomaly;
+        assert!(anomaly.flagged);
+        assert_eq!(anomaly.recent_requests, 12);
+        assert_eq!(anomaly.recent_failure_rate, 50.0);
+        assert_eq!(anomaly.baseline_failure_rate, 5.0);
+
+        let stats = |successes, failures| ApiKeyStats { daily_usage: successes, daily_failures: failures, ..Default::default() };
+        let keys = vec![
+            ("AIzaHealthy-key".to_string(), stats(20, 1)),
+            ("AIzaFlaky-key".to_string(), stats(6, 4)),
+            ("AIzaNew-key".to_string(), stats(0, 2)),
+        ];
+        let worst = worst_key(&keys).unwrap();
+        assert_eq!(worst.key_prefix, "AIzaFlak...");
+        assert_eq!((worst.requests, worst.success_rate), (10, 60.0));
+    }
+}
@@ -0,0 +1,156 @@
+use anyhow::{Context, Result};
+use ipnet::IpNet;
+use std::net::IpAddr;
+use std::sync::{Arc, RwLock};
+use tracing::warn;
+
+use crate::config::{IpBlockEntry, Settings};
+
+/// Parse a CIDR range, accepting a bare address as a single-host range
+pub fn parse_ip_net(value: &str) -> Result<IpNet> {
+    let value = value.trim();
+    if let Ok(addr) = value.parse::<IpAddr>() {
+        return Ok(IpNet::from(addr));
+    }
+    value
+        .parse::<IpNet>()
+        .with_context(|| format!("'{}' is not an IP address or CIDR range", value))
+}
+
+fn parse_ip_nets(cidrs: &[String], setting: &str) -> Vec<IpNet> {
+    cidrs
+        .iter()
+        .filter_map(|cidr| match parse_ip_net(cidr) {
+            Ok(net) => Some(net),
+            Err(e) => {
+                warn!("Ignoring {} entry: {}", setting, e);
+                None
+            }
+        })
+        .collect()
+}
+
+/// Client IP allowlist and blocklist, and the proxies trusted to name the client. All three
+/// can change at runtime.
+#[derive(Debug, Clone)]
+pub struct IpFilter {
+    allowlist: Arc<RwLock<Vec<IpNet>>>,
+    blocklist: Arc<RwLock<Vec<(IpNet, IpBlockEntry)>>>,
+    trusted_proxies: Arc<RwLock<Vec<IpNet>>>,
+}
+
+impl IpFilter {
+    pub fn new(settings: &Settings) -> Self {
+        let filter = Self {
+            allowlist: Arc::new(RwLock::new(Vec::new())),
+            blocklist: Arc::new(RwLock::new(Vec::new())),
+            trusted_proxies: Arc::new(RwLock::new(Vec::new())),
+        };
+        filter.set_allowlist(&settings.ip_allowlist);
+        filter.set_blocklist(settings.ip_blocklist.clone());
+        filter.set_trusted_proxies(&settings.trusted_proxies);
+        filter
+    }
+
+    /// Whether the allowlist is configured at all
+    pub fn has_allowlist(&self) -> bool {
+        !self.allowlist.read().unwrap_or_else(|e| e.into_inner()).is_empty()
+    }
+
+    /// Whether forwarding headers from `ip` are believed
+    pub fn is_trusted_proxy(&self, ip: IpAddr) -> bool {
+        let trusted_proxies = self.trusted_proxies.read().unwrap_or_else(|e| e.into_inner());
+        trusted_proxies.iter().any(|net| net.contains(&ip))
+    }
+
+    /// Whether `ip` may reach the proxy at unix time `now`
+    pub fn is_allowed(&self, ip: IpAddr, now: u64) -> bool {
+        let allowlist = self.allowlist.read().unwrap_or_else(|e| e.into_inner());
+        if !allowlist.is_empty() && !allowlist.iter().any(|net| net.contains(&ip)) {
+            return false;
+        }
+
+        let blocklist = self.blocklist.read().unwrap_or_else(|e| e.into_inner());
+        !blocklist.iter().any(|(net, entry)| net.contains(&ip) && entry.is_active(now))
+    }
+
+    /// Blocklist entries that have not expired yet
+    pub fn blocklist(&self, now: u64) -> Vec<IpBlockEntry> {
+        let blocklist = self.blocklist.read().unwrap_or_else(|e| e.into_inner());
+        blocklist
+            .iter()
+            .filter(|(_, entry)| entry.is_active(now))
+            .map(|(_, entry)| entry.clone())
+            .collect()
+    }
+
+    pub fn set_blocklist(&self, entries: Vec<IpBlockEntry>) {
+        let parsed = entries
+            .into_iter()
+            .filter_map(|entry| match parse_ip_net(&entry.cidr) {
+                Ok(net) => Some((net, entry)),
+                Err(e) => {
+                    warn!("Ignoring ip_blocklist entry: {}", e);
+                    None
+                }
+            })
+            .collect();
+        *self.blocklist.write().unwrap_or_else(|e| e.into_inner()) = parsed;
+    }
+
+    pub fn set_allowlist(&self, cidrs: &[String]) {
+        *self.allowlist.write().unwrap_or_else(|e| e.into_inner()) = parse_ip_nets(cidrs, "ip_allowlist");
+    }
+
+    pub fn set_trusted_proxies(&self, cidrs: &[String]) {
+        *self.trusted_proxies.write().unwrap_or_else(|e| e.into_inner()) = parse_ip_nets(cidrs, "trusted_proxies");
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ip(value: &str) -> IpAddr {
+        value.parse().unwrap()
+    }
+
+    #[test]
+    fn test_allowlist_and_blocklist() {
+        let filter = IpFilter::new(&Settings {
+            ip_allowlist: vec!["10.8.0.0/16".to_string(), "fd00::/8".to_string()],
+            ip_blocklist: vec![IpBlockEntry::permanent("10.8.4.2")],
+            ..Default::default()
+        });
+
+        assert!(filter.is_allowed(ip("10.8.1.1"), 0));
+        assert!(filter.is_allowed(ip("fd00::1"), 0));
+        assert!(!filter.is_allowed(ip("10.9.0.1"), 0));
+        assert!(!filter.is_allowed(ip("10.8.4.2"), 0));
+    }
+
+    #[test]
+    fn test_blocklist_entries_expire() {
+        let filter = IpFilter::new(&Settings::default());
+        assert!(filter.is_allowed(ip("203.0.113.9"), 100));
+
+        filter.set_blocklist(vec![IpBlockEntry {
+            cidr: "203.0.113.0/24".to_string(),
+            expires_at: Some(200),
+            reason: Some("scraping".to_string()),
+        }]);
+        assert!(!filter.is_allowed(ip("203.0.113.9"), 100));
+        assert_eq!(filter.blocklist(100).len(), 1);
+
+        assert!(filter.is_allowed(ip("203.0.113.9"), 200));
+        assert!(filter.blocklist(200).is_empty());
+    }
+
+    #[test]
+    fn test_parse_ip_net() {
+        assert_eq!(parse_ip_net("192.0.2.7").unwrap().prefix_len(), 32);
+        assert_eq!(parse_ip_net(" 192.0.2.0/24 ").unwrap().prefix_len(), 24);
+        assert!(parse_ip_net("192.0.2.0/33").is_err());
+        assert!(parse_ip_net("example.com").is_err());
+    }
+}
@@ -0,0 +1,120 @@
+//! Snapshots pushed to dashboards subscribed to `/dashboard-api/stream`. A single task samples
+//! the managers and publishes to a broadcast channel; a subscriber that falls behind skips to
+//! the newest snapshot instead of having the ones it missed buffered for it.
+
+use serde::Serialize;
+use std::sync::Arc;
+use tokio::sync::broadcast;
+
+/// Snapshots a subscriber can fall behind by; older ones are dropped for it
+const SNAPSHOT_BACKLOG: usize = 1;
+
+/// Log lines included in a snapshot
+pub const LIVE_LOG_LINES: usize = 5;
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct LiveLogLine {
+    pub timestamp: i64,
+    pub level: String,
+    pub message: String,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct LiveSnapshot {
+    pub timestamp: i64,
+    pub requests_per_minute: u64,
+    pub tokens_per_minute: u64,
+    pub active_streams: usize,
+    pub keys_available: usize,
+    pub cache_hit_rate: f64,
+    /// Most recent last
+    pub logs: Vec<LiveLogLine>,
+}
+
+impl LiveSnapshot {
+    /// Whether `self` is worth pushing before the next scheduled snapshot: the key pool
+    /// changed, or a warning or error was logged since `previous`
+    pub fn is_significant_change(&self, previous: &LiveSnapshot) -> bool {
+        if self.keys_available != previous.keys_available {
+            return true;
+        }
+        self.logs
+            .iter()
+            .filter(|line| !previous.logs.contains(line))
+            .any(|line| matches!(line.level.to_ascii_lowercase().as_str(), "warning" | "error" | "critical"))
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct LiveStats {
+    sender: broadcast::Sender<Arc<LiveSnapshot>>,
+}
+
+impl Default for LiveStats {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl LiveStats {
+    pub fn new() -> Self {
+        let (sender, _) = broadcast::channel(SNAPSHOT_BACKLOG);
+        Self { sender }
+    }
+
+    pub fn subscribe(&self) -> broadcast::Receiver<Arc<LiveSnapshot>> {
+        self.sender.subscribe()
+    }
+
+    /// Sampling is skipped while no dashboard is listening
+    pub fn has_subscribers(&self) -> bool {
+        self.sender.receiver_count() > 0
+    }
+
+    pub fn publish(&self, snapshot: LiveSnapshot) {
+        // Fails only when every subscriber has gone away in the meantime
+        let _ = self.sender.send(Arc::new(snapshot));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn snapshot(keys_available: usize, logs: &[(&str, &str)]) -> LiveSnapshot {
+        LiveSnapshot {
+            timestamp: 0,
+            requests_per_minute: 0,
+            tokens_per_minute: 0,
+            active_streams: 0,
+            keys_available,
+            cache_hit_rate: 0.0,
+            logs: logs
+                .iter()
+                .map(|(level, message)| LiveLogLine { timestamp: 0, level: level.to_string(), message: message.to_string() })
+                .collect(),
+        }
+    }
+
+    #[test]
+    fn test_significant_changes() {
+        let previous = snapshot(3, &[("info", "started")]);
+        assert!(!snapshot(3, &[("info", "started"), ("info", "request")]).is_significant_change(&previous));
+        assert!(snapshot(2, &[("info", "started")]).is_significant_change(&previous));
+        assert!(snapshot(3, &[("info", "started"), ("ERROR", "upstream down")]).is_significant_change(&previous));
+    }
+
+    #[tokio::test]
+    async fn test_slow_subscriber_gets_the_latest_snapshot() {
+        let live = LiveStats::new();
+        assert!(!live.has_subscribers());
+        let mut receiver = live.subscribe();
+        assert!(live.has_subscribers());
+
+        for keys in 1..=3 {
+            live.publish(snapshot(keys, &[]));
+        }
+        assert!(matches!(receiver.recv().await, Err(broadcast::error::RecvError::Lagged(2))));
+        assert_eq!(receiver.recv().await.unwrap().keys_available, 3);
+    }
+}
@@ -1,4 +1,5 @@
 use std::collections::{VecDeque, HashMap};
+use std::sync::atomic::{AtomicUsize, Ordering};
 use std::sync::{Arc, RwLock, Mutex};
 use chrono::{DateTime, Utc};
 use serde_json::{Value, json};
@@ -142,6 +143,24 @@ impl VertexLogEntry {
         self.error_message = Some(error_message.to_string());
         self
     }
+
+    pub fn with_extra(mut self, extra: HashMap<String, Value>) -> Self {
+        self.extra = Some(extra);
+        self
+    }
+
+    pub fn to_json(&self) -> Value {
+        json!({
+            "timestamp": self.timestamp.to_rfc3339(),
+            "level": self.level,
+            "message": self.message,
+            "vertex_id": self.vertex_id,
+            "operation": self.operation,
+            "status": self.status,
+            "error_message": self.error_message,
+            "extra": self.extra
+        })
+    }
 }
 
 impl fmt::Display for VertexLogEntry {
@@ -168,17 +187,35 @@ impl fmt::Display for VertexLogEntry {
 /// Log cache for displaying recent logs on the web interface
 pub struct LogManager {
     logs: Arc<RwLock<VecDeque<LogEntry>>>,
-    max_logs: usize,
+    max_logs: AtomicUsize,
 }
 
 impl LogManager {
     pub fn new(max_logs: usize) -> Self {
         Self {
             logs: Arc::new(RwLock::new(VecDeque::with_capacity(max_logs))),
-            max_logs,
+            max_logs: AtomicUsize::new(max_logs),
         }
     }
 
+    /// Keep at most `max_logs` entries from now on, dropping the oldest ones over it
+    pub fn resize(&self, max_logs: usize) {
+        let max_logs = max_logs.max(1);
+        self.max_logs.store(max_logs, Ordering::Relaxed);
+        let mut logs = self.logs.write().unwrap();
+        while logs.len() > max_logs {
+            logs.pop_front();
+        }
+    }
+
+    /// Drop the entries logged before `cutoff`; returns how many were dropped
+    pub fn prune_before(&self, cutoff: DateTime<Utc>) -> usize {
+        let mut logs = self.logs.write().unwrap();
+        let before = logs.len();
+        logs.retain(|log| log.timestamp >= cutoff);
+        before - logs.len()
+    }
+
     pub fn add_log(&self, entry: LogEntry) {
         let mut logs = self.logs.write().unwrap();
 
@@ -189,7 +226,7 @@ impl LogManager {
         logs.push_back(entry);
 
         // Keep only the last max_logs entries
-        while logs.len() > self.max_logs {
+        while logs.len() > self.max_logs.load(Ordering::Relaxed) {
             logs.pop_front();
         }
     }
@@ -211,36 +248,12 @@ impl LogManager {
             .collect()
     }
 
-    pub fn get_logs_by_level(&self, level: &str) -> Vec<LogEntry> {
-        let logs = self.logs.read().unwrap();
-        logs.iter()
-            .filter(|log| log.level.eq_ignore_ascii_case(level))
-            .cloned()
-            .collect()
-    }
-
-    pub fn get_logs_json(&self) -> Value {
-        let logs = self.get_logs();
-        let log_values: Vec<Value> = logs.into_iter().map(|log| {
-            json!({
-                "timestamp": log.timestamp.to_rfc3339(),
-                "level": log.level,
-                "message": log.message,
-                "key": log.key,
-                "request_type": log.request_type,
-                "model": log.model,
-                "status_code": log.status_code,
-                "error_message": log.error_message,
-                "extra": log.extra
-            })
-        }).collect();
-
-        json!(log_values)
-    }
-
-    pub fn clear(&self) {
+    /// Drop every entry; returns how many there were
+    pub fn clear(&self) -> usize {
         let mut logs = self.logs.write().unwrap();
+        let count = logs.len();
         logs.clear();
+        count
     }
 
     pub fn count(&self) -> usize {
@@ -252,17 +265,35 @@ impl LogManager {
 /// Vertex-specific log manager
 pub struct VertexLogManager {
     logs: Arc<RwLock<VecDeque<VertexLogEntry>>>,
-    max_logs: usize,
+    max_logs: AtomicUsize,
 }
 
 impl VertexLogManager {
     pub fn new(max_logs: usize) -> Self {
         Self {
             logs: Arc::new(RwLock::new(VecDeque::with_capacity(max_logs))),
-            max_logs,
+            max_logs: AtomicUsize::new(max_logs),
+        }
+    }
+
+    /// Keep at most `max_logs` entries from now on, dropping the oldest ones over it
+    pub fn resize(&self, max_logs: usize) {
+        let max_logs = max_logs.max(1);
+        self.max_logs.store(max_logs, Ordering::Relaxed);
+        let mut logs = self.logs.write().unwrap();
+        while logs.len() > max_logs {
+            logs.pop_front();
         }
     }
 
+    /// Drop the entries logged before `cutoff`; returns how many were dropped
+    pub fn prune_before(&self, cutoff: DateTime<Utc>) -> usize {
+        let mut logs = self.logs.write().unwrap();
+        let before = logs.len();
+        logs.retain(|log| log.timestamp >= cutoff);
+        before - logs.len()
+    }
+
     pub fn add_log(&self, entry: VertexLogEntry) {
         let mut logs = self.logs.write().unwrap();
 
@@ -273,35 +304,44 @@ impl VertexLogManager {
         logs.push_back(entry);
 
         // Keep only the last max_logs entries
-        while logs.len() > self.max_logs {
+        while logs.len() > self.max_logs.load(Ordering::Relaxed) {
             logs.pop_front();
         }
     }
 
-    pub fn get_logs(&self) -> Vec<VertexLogEntry> {
+    /// The newest `limit` entries, oldest first, optionally only those of `level`
+    pub fn query(&self, level: Option<&str>, limit: usize) -> Vec<VertexLogEntry> {
         let logs = self.logs.read().unwrap();
-        logs.iter().cloned().collect()
+        let mut matching: Vec<VertexLogEntry> = logs
+            .iter()
+            .rev()
+            .filter(|log| level.is_none_or(|level| log.level.eq_ignore_ascii_case(level)))
+            .take(limit)
+            .cloned()
+            .collect();
+        matching.reverse();
+        matching
     }
 
-    pub fn get_recent_logs(&self, count: usize) -> Vec<VertexLogEntry> {
+    /// Entries of `level` logged at or after `since`
+    pub fn count_since(&self, level: &str, since: DateTime<Utc>) -> usize {
         let logs = self.logs.read().unwrap();
         logs.iter()
-            .rev()
-            .take(count)
-            .cloned()
-            .collect::<Vec<_>>()
-            .into_iter()
-            .rev()
-            .collect()
+            .filter(|log| log.timestamp >= since && log.level.eq_ignore_ascii_case(level))
+            .count()
     }
 
-    pub fn clear(&self) {
+    /// Drop every entry; returns how many there were
+    pub fn clear(&self) -> usize {
         let mut logs = self.logs.write().unwrap();
+        let count = logs.len();
         logs.clear();
+        count
     }
 }
 
-// Global log manager instances
+// Global log manager instances, resized to `log_buffer_size` and `vertex_log_buffer_size` once
+// the settings are loaded
 lazy_static::lazy_static! {
     pub static ref LOG_MANAGER: LogManager = LogManager::new(100);
     pub static ref VERTEX_LOG_MANAGER: VertexLogManager = VertexLogManager::new(100);
@@ -358,6 +398,7 @@ pub fn vertex_format_log_message(
         if let Some(error_message) = extra_data.get("error_message").and_then(|v| v.as_str()) {
             entry = entry.with_error_message(error_message);
         }
+        entry = entry.with_extra(extra_data);
     }
 
     entry
@@ -394,6 +435,14 @@ pub fn vertex_log(level: &str, message: &str, extra: Option<HashMap<String, Valu
     }
 }
 
+/// `vertex_log` for a request lifecycle event, tagged with its operation and outcome
+pub fn vertex_log_event(level: &str, operation: &str, status: &str, message: &str) {
+    let mut extra = HashMap::new();
+    extra.insert("operation".to_string(), json!(operation));
+    extra.insert("status".to_string(), json!(status));
+    vertex_log(level, message, Some(extra));
+}
+
 /// Convenience logging macros
 #[macro_export]
 macro_rules! log_info {
@@ -505,6 +554,57 @@ mod tests {
         assert!(logs.last().unwrap().message.contains("Test message 9"));
     }
 
+    #[test]
+    fn test_log_buffers_resize_and_prune_by_age() {
+        let manager = LogManager::new(5);
+        for i in 0..5 {
+            manager.add_log(LogEntry::new("info", &format!("Test message {}", i)));
+        }
+        manager.resize(3);
+        let messages: Vec<String> = manager.get_logs().into_iter().map(|log| log.message).collect();
+        assert_eq!(messages, ["Test message 2", "Test message 3", "Test message 4"]);
+        manager.resize(10);
+        for i in 5..10 {
+            manager.add_log(LogEntry::new("info", &format!("Test message {}", i)));
+        }
+        assert_eq!(manager.count(), 8);
+
+        let mut old = LogEntry::new("info", "Old message");
+        old.timestamp = Utc::now() - chrono::Duration::hours(2);
+        let vertex = VertexLogManager::new(10);
+        let mut old_vertex = VertexLogEntry::new("info", "Old vertex message");
+        old_vertex.timestamp = old.timestamp;
+        manager.resize(3);
+        manager.add_log(old);
+        vertex.add_log(old_vertex);
+        vertex.add_log(VertexLogEntry::new("info", "Recent vertex message"));
+
+        let hour_ago = Utc::now() - chrono::Duration::hours(1);
+        assert_eq!(manager.prune_before(hour_ago), 1);
+        assert_eq!(manager.count(), 2);
+        assert_eq!(vertex.prune_before(hour_ago), 1);
+        assert_eq!(vertex.query(None, 10)[0].message, "Recent vertex message");
+        assert_eq!(vertex.clear(), 1);
+    }
+
+    #[test]
+    fn test_vertex_log_manager_query() {
+        let manager = VertexLogManager::new(10);
+        for (i, level) in ["info", "error", "info", "ERROR"].iter().enumerate() {
+            manager.add_log(VertexLogEntry::new(level, &format!("Vertex message {}", i)));
+        }
+
+        let errors = manager.query(Some("error"), 10);
+        assert_eq!(errors.iter().map(|log| log.message.as_str()).collect::<Vec<_>>(), ["Vertex message 1", "Vertex message 3"]);
+        let latest = manager.query(None, 2);
+        assert_eq!(latest[0].message, "Vertex message 2");
+        assert_eq!(latest[1].message, "Vertex message 3");
+
+        let hour_ago = Utc::now() - chrono::Duration::hours(1);
+        assert_eq!(manager.count_since("error", hour_ago), 2);
+        assert_eq!(manager.count_since("error", Utc::now() + chrono::Duration::seconds(1)), 0);
+    }
+
     #[test]
     fn test_format_log_message() {
         let mut extra = HashMap::new();
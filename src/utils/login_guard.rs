@@ -0,0 +1,246 @@
+use dashmap::DashMap;
+use std::collections::VecDeque;
+use std::sync::{Arc, RwLock};
+use std::time::{Duration, Instant};
+
+use crate::config::Settings;
+use crate::utils::logging::log;
+
+/// Failures allowed before responses start being delayed
+const FREE_ATTEMPTS: usize = 3;
+const MAX_FAILURE_DELAY: Duration = Duration::from_secs(5);
+
+/// Outcome of a failed authentication attempt
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LoginFailure {
+    /// Slow down the response by this much before answering
+    Delay(Duration),
+    /// The client is now locked out for this long
+    LockedOut(Duration),
+}
+
+#[derive(Debug, Default)]
+struct LoginAttempts {
+    failures: VecDeque<Instant>,
+    locked_until: Option<Instant>,
+}
+
+/// `login_max_failures`, `login_failure_window_secs` and `login_lockout_secs`
+#[derive(Debug, Clone, Copy)]
+struct LoginLimits {
+    max_failures: usize,
+    window: Duration,
+    lockout: Duration,
+}
+
+impl LoginLimits {
+    fn from_settings(settings: &Settings) -> Self {
+        Self {
+            max_failures: settings.login_max_failures.max(1) as usize,
+            window: Duration::from_secs(settings.login_failure_window_secs.max(1)),
+            lockout: Duration::from_secs(settings.login_lockout_secs.max(1)),
+        }
+    }
+}
+
+/// Tracks failed dashboard logins per client IP over a sliding window and locks
+/// out clients that keep guessing
+#[derive(Debug, Clone)]
+pub struct LoginGuard {
+    attempts: Arc<DashMap<String, LoginAttempts>>,
+    limits: Arc<RwLock<LoginLimits>>,
+}
+
+impl LoginGuard {
+    pub fn new(settings: &Settings) -> Self {
+        Self {
+            attempts: Arc::new(DashMap::new()),
+            limits: Arc::new(RwLock::new(LoginLimits::from_settings(settings))),
+        }
+    }
+
+    /// Apply changed limits; attempts already recorded are kept
+    pub fn set_limits(&self, settings: &Settings) {
+        *self.limits.write().unwrap_or_else(|e| e.into_inner()) = LoginLimits::from_settings(settings);
+    }
+
+    fn limits(&self) -> LoginLimits {
+        *self.limits.read().unwrap_or_else(|e| e.into_inner())
+    }
+
+    /// `Err(retry_after)` while the client is locked out
+    pub fn check(&self, ip: &str, now: Instant) -> Result<(), Duration> {
+        let mut entry = match self.attempts.get_mut(ip) {
+            Some(entry) => entry,
+            None => return Ok(()),
+        };
+
+        match entry.locked_until {
+            Some(until) if until > now => Err(until - now),
+            Some(_) => {
+                // Lockout served, start over with a clean slate
+                entry.locked_until = None;
+                entry.failures.clear();
+                Ok(())
+            }
+            None => Ok(()),
+        }
+    }
+
+    pub fn record_failure(&self, ip: &str, now: Instant) -> LoginFailure {
+        let limits = self.limits();
+        self.forget_stale(now, limits.window);
+        let mut entry = self.attempts.entry(ip.to_string()).or_default();
+
+        while entry.failures.front().is_some_and(|at| now.duration_since(*at) > limits.window) {
+            entry.failures.pop_front();
+        }
+        entry.failures.push_back(now);
+
+        let failures = entry.failures.len();
+        if failures >= limits.max_failures {
+            entry.locked_until = Some(now + limits.lockout);
+            log(
+                "warning",
+                &format!(
+                    "Client {} locked out for {}s after {} failed dashboard logins",
+                    ip,
+                    limits.lockout.as_secs(),
+                    failures
+                ),
+                None,
+            );
+            return LoginFailure::LockedOut(limits.lockout);
+        }
+
+        LoginFailure::Delay(failure_delay(failures))
+    }
+
+    /// Drop clients that are not locked out and whose failures have all left the window, so
+    /// only clients with something left to count are kept
+    fn forget_stale(&self, now: Instant, window: Duration) {
+        self.attempts.retain(|_, attempts| {
+            attempts.locked_until.is_some_and(|until| until > now)
+                || attempts.failures.back().is_some_and(|at| now.duration_since(*at) <= window)
+        });
+    }
+
+    pub fn record_success(&self, ip: &str) {
+        self.attempts.remove(ip);
+    }
+
+    /// Clients currently locked out and the time left on their lockout
+    pub fn active_lockouts(&self, now: Instant) -> Vec<(String, Duration)> {
+        self.attempts
+            .iter()
+            .filter_map(|entry| match entry.locked_until {
+                Some(until) if until > now => Some((entry.key().clone(), until - now)),
+                _ => None,
+            })
+            .collect()
+    }
+}
+
+/// No delay for the first few failures, then doubling from 500ms up to `MAX_FAILURE_DELAY`
+fn failure_delay(failures: usize) -> Duration {
+    if failures <= FREE_ATTEMPTS {
+        return Duration::ZERO;
+    }
+    let exponent = (failures - FREE_ATTEMPTS - 1).min(8) as u32;
+    (Duration::from_millis(500) * 2u32.pow(exponent)).min(MAX_FAILURE_DELAY)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn guard() -> LoginGuard {
+        LoginGuard::new(&Settings {
+            login_max_failures: 10,
+            login_failure_window_secs: 900,
+            login_lockout_secs: 600,
+            ..Default::default()
+        })
+    }
+
+    #[test]
+    fn test_brute_force_triggers_lockout_that_expires() {
+        let guard = guard();
+        let start = Instant::now();
+        let ip = "203.0.113.7";
+
+        let mut outcomes = Vec::new();
+        for i in 0..10 {
+            assert!(guard.check(ip, start + Duration::from_secs(i)).is_ok());
+            outcomes.push(guard.record_failure(ip, start + Duration::from_secs(i)));
+        }
+
+        assert_eq!(outcomes[0], LoginFailure::Delay(Duration::ZERO));
+        assert_eq!(outcomes[3], LoginFailure::Delay(Duration::from_millis(500)));
+        assert_eq!(outcomes[4], LoginFailure::Delay(Duration::from_secs(1)));
+        assert_eq!(outcomes[8], LoginFailure::Delay(MAX_FAILURE_DELAY));
+        assert_eq!(outcomes[9], LoginFailure::LockedOut(Duration::from_secs(600)));
+
+        let locked_at = start + Duration::from_secs(9);
+        let retry_after = guard.check(ip, locked_at + Duration::from_secs(60)).unwrap_err();
+        assert_eq!(retry_after, Duration::from_secs(540));
+        assert_eq!(guard.active_lockouts(locked_at).len(), 1);
+
+        // Other clients are unaffected
+        assert!(guard.check("198.51.100.1", locked_at).is_ok());
+
+        let after = locked_at + Duration::from_secs(601);
+        assert!(guard.check(ip, after).is_ok());
+        assert!(guard.active_lockouts(after).is_empty());
+        assert_eq!(guard.record_failure(ip, after), LoginFailure::Delay(Duration::ZERO));
+    }
+
+    #[test]
+    fn test_failures_outside_window_are_forgotten() {
+        let guard = guard();
+        let start = Instant::now();
+
+        for i in 0..9 {
+            guard.record_failure("ip", start + Duration::from_secs(i));
+        }
+        // The next failure arrives after the whole window has slid past
+        let later = start + Duration::from_secs(2000);
+        assert_eq!(guard.record_failure("ip", later), LoginFailure::Delay(Duration::ZERO));
+    }
+
+    #[test]
+    fn test_success_resets_counter() {
+        let guard = guard();
+        let now = Instant::now();
+
+        for _ in 0..9 {
+            guard.record_failure("ip", now);
+        }
+        guard.record_success("ip");
+        assert_eq!(guard.record_failure("ip", now), LoginFailure::Delay(Duration::ZERO));
+    }
+
+    #[test]
+    fn test_stale_clients_are_forgotten() {
+        let guard = guard();
+        let start = Instant::now();
+
+        for i in 0..100 {
+            guard.record_failure(&format!("198.51.100.{}", i), start);
+        }
+        for i in 400..410 {
+            guard.record_failure("203.0.113.7", start + Duration::from_secs(i));
+        }
+        assert_eq!(guard.attempts.len(), 101);
+
+        // Once the window has passed the first failures, only the locked out client is left
+        let later = start + Duration::from_secs(901);
+        guard.record_failure("192.0.2.1", later);
+        assert_eq!(guard.attempts.len(), 2);
+        assert!(guard.check("203.0.113.7", later).is_err());
+
+        // Once its lockout is served and its failures are out of the window it goes too
+        guard.record_failure("192.0.2.1", start + Duration::from_secs(1400));
+        assert_eq!(guard.attempts.len(), 1);
+    }
+}
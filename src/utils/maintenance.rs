@@ -1,65 +1,26 @@
-use std::panic;
 use std::sync::Arc;
 use tokio::time::Duration;
 use tokio_cron_scheduler::{JobScheduler, Job};
 use crate::utils::{
-    logging::{log, LOG_MANAGER},
+    logging::{log, LOG_MANAGER, VERTEX_LOG_MANAGER},
     stats::ApiStatsManager,
     cache::ResponseCacheManager,
 };
 use crate::config::Settings;
 use anyhow::Result;
 use std::collections::HashMap;
+use serde::Serialize;
 use serde_json::{Value, json};
 
 // Rust equivalent of Python utils/maintenance.py
 
-/// Global exception handler - equivalent to Python's handle_exception
-pub fn setup_global_exception_handler() {
-    panic::set_hook(Box::new(|panic_info| {
-        let location = panic_info.location().unwrap_or_else(|| {
-            std::panic::Location::caller()
-        });
-
-        let message = if let Some(s) = panic_info.payload().downcast_ref::<&str>() {
-            s.to_string()
-        } else if let Some(s) = panic_info.payload().downcast_ref::<String>() {
-            s.clone()
-        } else {
-            "Unknown panic occurred".to_string()
-        };
-
-        let error_message = crate::utils::error_handling::translate_error(&message);
-
-        let mut extra = HashMap::new();
-        extra.insert("status_code".to_string(), json!(500));
-        extra.insert("error_message".to_string(), json!(error_message));
-        extra.insert("file".to_string(), json!(location.file()));
-        extra.insert("line".to_string(), json!(location.line()));
-
-        log(
-            "error",
-            &format!("未捕获的异常: {}", error_message),
-            Some(extra),
-        );
-
-        // Also print to stderr for immediate visibility
-        eprintln!(
-            "PANIC at {}:{}: {}",
-            location.file(),
-            location.line(),
-            message
-        );
-    }));
-}
-
 /// Handle specific types of exceptions with context
 pub fn handle_exception_with_context(
     error: &dyn std::error::Error,
     context: &str,
     extra_data: Option<HashMap<String, Value>>,
 ) {
-    let error_message = crate::utils::error_handling::translate_error(&error.to_string());
+    let error_message = crate::utils::error_handling::translate_error_message(&error.to_string());
 
     let mut extra = extra_data.unwrap_or_default();
     extra.insert("context".to_string(), json!(context));
@@ -104,91 +65,20 @@ impl MaintenanceScheduler {
         self.stats_manager = Some(stats_manager);
     }
 
-    /// Schedule cache cleanup - equivalent to Python's schedule_cache_cleanup
-    pub async fn schedule_cache_cleanup(&mut self) -> Result<()> {
-        if self.cache_manager.is_none() {
-            log::warn!("Cache manager not set, skipping cache cleanup scheduling");
-            return Ok(());
-        }
-
-        let cache_manager = self.cache_manager.clone();
-
-        // Schedule cache cleanup every 10 minutes
-        let job = Job::new_async("0 */10 * * * *", move |_uuid, _l| {
-            let cache_manager = cache_manager.clone();
-            Box::pin(async move {
-                if let Some(ref cache_mgr) = cache_manager {
-                    let cleaned_count = cache_mgr.cleanup_expired().await;
-                    log(
-                        "info",
-                        &format!("定时清理缓存完成，清理了 {} 个过期项", cleaned_count),
-                        Some({
-                            let mut extra = HashMap::new();
-                            extra.insert("cleanup".to_string(), json!("cache"));
-                            extra.insert("cleaned_count".to_string(), json!(cleaned_count));
-                            extra
-                        }),
-                    );
-                } else {
-                    log::warn!("Cache manager not available during cleanup");
-                }
-            })
-        })?;
-
-        self.scheduler.add(job).await?;
-        log::info!("已安排缓存清理任务，每10分钟执行一次");
-        Ok(())
-    }
-
-    /// Schedule API call statistics cleanup
-    pub async fn schedule_api_stats_cleanup(&mut self) -> Result<()> {
-        if self.stats_manager.is_none() {
-            log::warn!("Stats manager not set, skipping stats cleanup scheduling");
-            return Ok(());
-        }
-
-        let stats_manager = self.stats_manager.clone();
-
-        // Schedule stats cleanup every hour
-        let job = Job::new_async("0 0 * * * *", move |_uuid, _l| {
-            let stats_manager = stats_manager.clone();
-            Box::pin(async move {
-                if let Some(ref stats_mgr) = stats_manager {
-                    let cleaned_count = stats_mgr.cleanup_expired_records(Duration::from_secs(86400)); // 24 hours
-                    log(
-                        "info",
-                        &format!("定时清理API统计完成，清理了 {} 个过期记录", cleaned_count),
-                        Some({
-                            let mut extra = HashMap::new();
-                            extra.insert("cleanup".to_string(), json!("api_stats"));
-                            extra.insert("cleaned_count".to_string(), json!(cleaned_count));
-                            extra
-                        }),
-                    );
-                } else {
-                    log::warn!("Stats manager not available during cleanup");
-                }
-            })
-        })?;
-
-        self.scheduler.add(job).await?;
-        log::info!("已安排API统计清理任务，每小时执行一次");
-        Ok(())
-    }
-
-    /// Schedule log cleanup
+    /// Schedule the hourly prune of log entries older than `log_retention_secs`
     pub async fn schedule_log_cleanup(&mut self) -> Result<()> {
-        // Schedule log cleanup every 6 hours
-        let job = Job::new_async("0 0 */6 * * *", move |_uuid, _l| {
+        let retention = chrono::Duration::seconds(self.settings.log_retention_secs as i64);
+        let job = Job::new_async("0 0 * * * *", move |_uuid, _l| {
             Box::pin(async move {
-                // Clean up old logs to prevent memory bloat
-                LOG_MANAGER.clear();
+                let cutoff = chrono::Utc::now() - retention;
+                let pruned = LOG_MANAGER.prune_before(cutoff) + VERTEX_LOG_MANAGER.prune_before(cutoff);
                 log(
                     "info",
-                    "定时清理日志缓存完成",
+                    &format!("定时清理日志缓存完成，清理了 {} 条过期日志", pruned),
                     Some({
                         let mut extra = HashMap::new();
                         extra.insert("cleanup".to_string(), json!("logs"));
+                        extra.insert("cleaned_count".to_string(), json!(pruned));
                         extra
                     }),
                 );
@@ -196,19 +86,23 @@ impl MaintenanceScheduler {
         })?;
 
         self.scheduler.add(job).await?;
-        log::info!("已安排日志清理任务，每6小时执行一次");
+        log::info!("已安排日志清理任务，每小时执行一次");
         Ok(())
     }
 
     /// Schedule system health check
     pub async fn schedule_health_check(&mut self) -> Result<()> {
         let settings = self.settings.clone();
+        let cache_manager = self.cache_manager.clone();
+        let stats_manager = self.stats_manager.clone();
 
         // Schedule health check every 30 minutes
         let job = Job::new_async("0 */30 * * * *", move |_uuid, _l| {
             let settings = settings.clone();
+            let cache_manager = cache_manager.clone();
+            let stats_manager = stats_manager.clone();
             Box::pin(async move {
-                perform_health_check(&settings).await;
+                perform_health_check(&settings, cache_manager.as_deref(), stats_manager.as_deref()).await;
             })
         })?;
 
@@ -223,27 +117,15 @@ impl MaintenanceScheduler {
         log::info!("维护调度器已启动");
         Ok(())
     }
-
-    /// Shutdown the scheduler
-    pub async fn shutdown(&mut self) -> Result<()> {
-        self.scheduler.shutdown().await?;
-        log::info!("维护调度器已停止");
-        Ok(())
-    }
-
-    /// Get scheduler status
-    pub async fn get_status(&self) -> Value {
-        json!({
-            "running": true,
-            "jobs_count": 0, // JobScheduler doesn't expose job count in this version
-            "cache_manager_set": self.cache_manager.is_some(),
-            "stats_manager_set": self.stats_manager.is_some()
-        })
-    }
 }
 
-/// Perform system health check
-async fn perform_health_check(settings: &Settings) {
+/// Perform system health check, running `emergency_cleanup` when memory use reaches
+/// `emergency_cleanup_memory_percent`
+async fn perform_health_check(
+    settings: &Settings,
+    cache_manager: Option<&ResponseCacheManager>,
+    stats_manager: Option<&ApiStatsManager>,
+) {
     let mut health_status = HashMap::new();
     let mut issues_found = 0;
 
@@ -265,6 +147,12 @@ async fn perform_health_check(settings: &Settings) {
                 }),
             );
         }
+
+        let threshold = settings.emergency_cleanup_memory_percent;
+        if threshold > 0.0 && memory_usage_percent >= threshold {
+            log::warn!("内存使用率 {:.1}% 达到紧急清理阈值 {:.1}%", memory_usage_percent, threshold);
+            emergency_cleanup(cache_manager, stats_manager).await;
+        }
     }
 
     // Check log manager status
@@ -333,62 +221,52 @@ async fn perform_health_check(settings: &Settings) {
     }
 }
 
-/// API call stats cleanup function - equivalent to Python's api_call_stats_clean
-pub async fn api_call_stats_clean(stats_manager: &ApiStatsManager) {
-    let cleaned_count = stats_manager.cleanup_expired_records(Duration::from_secs(86400 * 7)); // 7 days
-
-    log(
-        "info",
-        &format!("API统计清理完成，清理了 {} 个过期记录", cleaned_count),
-        Some({
-            let mut extra = HashMap::new();
-            extra.insert("cleanup".to_string(), json!("api_call_stats"));
-            extra.insert("cleaned_count".to_string(), json!(cleaned_count));
-            extra
-        }),
-    );
+/// What an emergency cleanup removed
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Serialize)]
+pub struct EmergencyCleanupReport {
+    pub cache_entries: usize,
+    pub stats_records: usize,
+    pub log_entries: usize,
 }
 
-/// Emergency cleanup function for critical situations
+/// Emergency cleanup function for critical situations: empties the response cache, drops
+/// every per-call stats record and clears the log buffers
 pub async fn emergency_cleanup(
     cache_manager: Option<&ResponseCacheManager>,
     stats_manager: Option<&ApiStatsManager>,
-) {
-    log(
-        "warning",
-        "执行紧急清理操作",
-        Some({
-            let mut extra = HashMap::new();
-            extra.insert("cleanup".to_string(), json!("emergency"));
-            extra
-        }),
-    );
+) -> EmergencyCleanupReport {
+    let mut report = EmergencyCleanupReport::default();
 
     if let Some(cache_mgr) = cache_manager {
+        report.cache_entries = cache_mgr.size().await;
         cache_mgr.clear_sync();
         log::info!("紧急清理: 已清空所有缓存");
     }
 
     if let Some(stats_mgr) = stats_manager {
-        let cleaned = stats_mgr.cleanup_expired_records(Duration::from_secs(3600)); // 1 hour
-        log::info!("紧急清理: 清理了 {} 个统计记录", cleaned);
+        report.stats_records = stats_mgr.cleanup_expired_records(Duration::ZERO).await;
+        log::info!("紧急清理: 清理了 {} 个统计记录", report.stats_records);
     }
 
-    LOG_MANAGER.clear();
+    report.log_entries = LOG_MANAGER.clear();
     log::info!("紧急清理: 已清空日志缓存");
 
-    // Force garbage collection
+    // Logged after the buffers are cleared so the entry survives
+    log(
+        "warning",
+        &format!(
+            "执行紧急清理操作: 清理了 {} 个缓存项, {} 个统计记录, {} 条日志",
+            report.cache_entries, report.stats_records, report.log_entries
+        ),
+        Some({
+            let mut extra = HashMap::new();
+            extra.insert("cleanup".to_string(), json!("emergency"));
+            extra.insert("cleared".to_string(), json!(report));
+            extra
+        }),
+    );
     log::info!("紧急清理完成");
-}
-
-/// Get maintenance system status
-pub async fn get_maintenance_status() -> Value {
-    json!({
-        "log_entries": LOG_MANAGER.count(),
-        "panic_handler_installed": true,
-        "health_check_available": true,
-        "emergency_cleanup_available": true
-    })
+    report
 }
 
 #[cfg(test)]
@@ -417,7 +295,7 @@ mod tests {
     #[tokio::test]
     async fn test_health_check() {
         let settings = Settings::default();
-        perform_health_check(&settings).await;
+        perform_health_check(&settings, None, None).await;
         // Health check should complete without panicking
     }
 
@@ -1,14 +1,32 @@
 pub mod api_key;
+pub mod audit;
 pub mod auth;
 pub mod browser;
 pub mod cache;
+pub mod circuit_breaker;
+pub mod clock;
+pub mod dashboard_sections;
+pub mod endpoints;
 pub mod error_handling;
+pub mod fair_queue;
+pub mod health;
+pub mod hedge;
+pub mod http_client;
+pub mod insights;
+pub mod ip_filter;
+pub mod live_stats;
 pub mod logging;
+pub mod login_guard;
 pub mod maintenance;
+pub mod model_policy;
 pub mod rate_limiting;
 pub mod request;
+pub mod request_overrides;
 pub mod response;
+pub mod route_metrics;
 pub mod stats;
+pub mod tls;
+pub mod tokens;
 pub mod version;
 
 // Re-export commonly used items from logging
@@ -30,24 +48,21 @@ pub use response::{
 };
 
 // Re-export commonly used items from maintenance
-// Note: Maintenance functions exist but are not currently integrated into rujimi's startup flow
+// Note: only the health check and log cleanup are scheduled at startup; the other maintenance jobs are not
 #[allow(dead_code)]
-pub use maintenance::{
-    MaintenanceScheduler, setup_global_exception_handler,
-    handle_exception_with_context, api_call_stats_clean, emergency_cleanup
-};
+pub use maintenance::emergency_cleanup;
 
 // Re-export from other modules for convenience
-pub use api_key::{ApiKeyManager, ApiKeyStats};
-pub use auth::{AuthState, AuthResult, AuthScope};
-pub use cache::{ResponseCacheManager, CacheEntry, CacheStats};
+pub use api_key::ApiKeyManager;
+pub use auth::{AuthState, AuthResult};
+pub use cache::ResponseCacheManager;
 pub use error_handling::{translate_error, ErrorContext};
 
 // Note: Rate limiting and version checking exist but are not currently active
 #[allow(dead_code)]
 pub use rate_limiting::{RateLimiter, RateLimitError, RateLimitInfo};
 
-pub use stats::{ApiStatsManager, ApiCallRecord, ApiStats, ModelStats};
+pub use stats::{ApiStatsManager, CallOrigin};
 
 #[allow(dead_code)]
 pub use version::{VersionInfo, check_for_updates};
\ No newline at end of file
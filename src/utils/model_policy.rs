@@ -0,0 +1,138 @@
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeSet;
+use std::sync::{Arc, RwLock};
+
+use crate::config::settings::model_name_matches;
+use crate::config::Settings;
+
+/// Which models and user agents may use the proxy
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct ModelPolicy {
+    /// Model patterns that are rejected; `*` matches any run of characters
+    pub blocked_models: BTreeSet<String>,
+    /// When not empty, only models matching one of these patterns are allowed
+    pub whitelist_models: BTreeSet<String>,
+    /// When not empty, only user agents containing one of these are allowed
+    pub whitelist_user_agent: BTreeSet<String>,
+}
+
+impl ModelPolicy {
+    pub fn from_settings(settings: &Settings) -> Self {
+        Self {
+            blocked_models: settings.blocked_models.iter().cloned().collect(),
+            whitelist_models: settings.whitelist_models.iter().cloned().collect(),
+            whitelist_user_agent: settings.whitelist_user_agent.iter().cloned().collect(),
+        }
+    }
+
+    /// The whitelist is checked first, then the blocklist
+    pub fn allows_model(&self, model: &str) -> bool {
+        if !self.whitelist_models.is_empty() {
+            return self.whitelist_models.iter().any(|pattern| model_name_matches(pattern, model));
+        }
+        !self.blocked_models.iter().any(|pattern| model_name_matches(pattern, model))
+    }
+
+    /// Trim patterns, lowercase user agents and reject patterns that cannot match as meant
+    pub fn normalized(self) -> Result<Self, String> {
+        let patterns = |set: BTreeSet<String>, field: &str| -> Result<BTreeSet<String>, String> {
+            set.into_iter()
+                .map(|pattern| {
+                    let pattern = pattern.trim().to_string();
+                    validate_pattern(&pattern).map_err(|e| format!("Invalid {} entry {:?}: {}", field, pattern, e))?;
+                    Ok(pattern)
+                })
+                .collect()
+        };
+        Ok(Self {
+            blocked_models: patterns(self.blocked_models, "blocked_models")?,
+            whitelist_models: patterns(self.whitelist_models, "whitelist_models")?,
+            whitelist_user_agent: self
+                .whitelist_user_agent
+                .into_iter()
+                .map(|agent| agent.trim().to_lowercase())
+                .filter(|agent| !agent.is_empty())
+                .collect(),
+        })
+    }
+}
+
+/// Model patterns are names with optional `*` wildcards
+fn validate_pattern(pattern: &str) -> Result<(), &'static str> {
+    if pattern.is_empty() {
+        return Err("empty pattern");
+    }
+    if pattern.chars().any(|c| c.is_whitespace() || matches!(c, '?' | '[' | ']' | '{' | '}')) {
+        return Err("only `*` is supported as a wildcard and names cannot contain whitespace");
+    }
+    Ok(())
+}
+
+/// The model policy in effect, replaceable at runtime from the dashboard
+#[derive(Debug, Clone)]
+pub struct ModelFilter {
+    policy: Arc<RwLock<ModelPolicy>>,
+}
+
+impl ModelFilter {
+    pub fn new(settings: &Settings) -> Self {
+        Self { policy: Arc::new(RwLock::new(ModelPolicy::from_settings(settings))) }
+    }
+
+    pub fn policy(&self) -> ModelPolicy {
+        self.policy.read().unwrap_or_else(|e| e.into_inner()).clone()
+    }
+
+    pub fn set_policy(&self, policy: ModelPolicy) {
+        *self.policy.write().unwrap_or_else(|e| e.into_inner()) = policy;
+    }
+
+    pub fn allows_model(&self, model: &str) -> bool {
+        self.policy.read().unwrap_or_else(|e| e.into_inner()).allows_model(model)
+    }
+
+    pub fn allows_user_agent(&self, user_agent: Option<&str>) -> bool {
+        let policy = self.policy.read().unwrap_or_else(|e| e.into_inner());
+        crate::utils::auth::validate_user_agent(user_agent, &policy.whitelist_user_agent)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn set(items: &[&str]) -> BTreeSet<String> {
+        items.iter().map(|item| item.to_string()).collect()
+    }
+
+    #[test]
+    fn test_model_policy() {
+        let policy = ModelPolicy { blocked_models: set(&["gemini-exp-*", "gemini-1.0-pro"]), ..Default::default() };
+        assert!(!policy.allows_model("gemini-exp-1206"));
+        assert!(!policy.allows_model("gemini-1.0-pro"));
+        assert!(policy.allows_model("gemini-2.0-flash"));
+
+        let policy = ModelPolicy { whitelist_models: set(&["gemini-2.5-*"]), ..policy };
+        assert!(policy.allows_model("gemini-2.5-pro"));
+        assert!(!policy.allows_model("gemini-2.0-flash"));
+
+        // Rules on a model cover its suffixed variants; a rule on a variant only that variant
+        let policy = ModelPolicy { blocked_models: set(&["gemini-2.5-pro", "gemini-2.5-flash-search"]), ..Default::default() };
+        assert!(!policy.allows_model("gemini-2.5-pro-thinking-search"));
+        assert!(!policy.allows_model("gemini-2.5-flash-search"));
+        assert!(policy.allows_model("gemini-2.5-flash-nothinking"));
+        let policy = ModelPolicy { whitelist_models: set(&["gemini-2.5-flash"]), ..Default::default() };
+        assert!(policy.allows_model("gemini-2.5-flash-search"));
+        assert!(!policy.allows_model("gemini-2.5-pro-search"));
+
+        let normalized = ModelPolicy { blocked_models: set(&[" gemini-exp-* "]), whitelist_user_agent: set(&["Curl"]), ..Default::default() }
+            .normalized()
+            .unwrap();
+        assert_eq!(normalized.blocked_models, set(&["gemini-exp-*"]));
+        assert_eq!(normalized.whitelist_user_agent, set(&["curl"]));
+
+        let invalid = ModelPolicy { blocked_models: set(&["gemini-?"]), ..Default::default() }.normalized();
+        assert!(invalid.unwrap_err().contains("blocked_models"));
+    }
+}
@@ -5,6 +5,8 @@ use std::time::{Duration, SystemTime};
 use tokio::sync::RwLock;
 use tracing::{debug, warn};
 
+use crate::utils::clock::{system_clock, SharedClock};
+
 #[derive(Debug, Clone)]
 pub struct RateLimiter {
     // IP-based rate limiting
@@ -14,6 +16,7 @@ pub struct RateLimiter {
     // Configuration
     max_requests_per_minute: u32,
     max_requests_per_day_per_ip: u32,
+    clock: SharedClock,
 }
 
 impl RateLimiter {
@@ -23,11 +26,17 @@ impl RateLimiter {
             global_requests: Arc::new(RwLock::new(Vec::new())),
             max_requests_per_minute,
             max_requests_per_day_per_ip,
+            clock: system_clock(),
         }
     }
 
+    pub fn with_clock(mut self, clock: SharedClock) -> Self {
+        self.clock = clock;
+        self
+    }
+
     pub async fn check_rate_limit(&self, ip: Option<&str>) -> Result<(), RateLimitError> {
-        let now = SystemTime::now();
+        let now = self.clock.now();
 
         // Check global rate limit (per minute)
         if let Err(e) = self.check_global_rate_limit(now).await {
@@ -92,7 +101,7 @@ impl RateLimiter {
     }
 
     pub async fn get_rate_limit_info(&self, ip: Option<&str>) -> RateLimitInfo {
-        let now = SystemTime::now();
+        let now = self.clock.now();
 
         // Get global info
         let global_requests = self.global_requests.read().await;
@@ -126,7 +135,7 @@ impl RateLimiter {
     }
 
     async fn cleanup_old_entries(&self) {
-        let now = SystemTime::now();
+        let now = self.clock.now();
         let day_ago = now - Duration::from_secs(24 * 60 * 60);
         let minute_ago = now - Duration::from_secs(60);
 
@@ -200,7 +209,7 @@ impl RateLimitError {
 #[cfg(test)]
 mod tests {
     use super::*;
-    
+    use crate::utils::clock::MockClock;
 
     #[tokio::test]
     async fn test_global_rate_limiting() {
@@ -244,4 +253,21 @@ mod tests {
         assert_eq!(info.global_requests_per_minute, 1);
         assert_eq!(info.ip_requests_per_day, 1);
     }
-}
\ No newline at end of file
+
+    #[tokio::test]
+    async fn test_windows_roll_over() {
+        let clock = MockClock::new();
+        let limiter = RateLimiter::new(1, 2).with_clock(clock.clone());
+
+        assert!(limiter.check_rate_limit(Some("127.0.0.1")).await.is_ok());
+        assert!(limiter.check_rate_limit(Some("127.0.0.1")).await.is_err());
+
+        // The per-minute window frees up after a minute, the per-day one only after a day
+        clock.advance(Duration::from_secs(61));
+        assert!(limiter.check_rate_limit(Some("127.0.0.1")).await.is_ok());
+        clock.advance(Duration::from_secs(61));
+        assert!(limiter.check_rate_limit(Some("127.0.0.1")).await.is_err());
+        clock.advance(Duration::from_secs(24 * 60 * 60));
+        assert!(limiter.check_rate_limit(Some("127.0.0.1")).await.is_ok());
+    }
+}
@@ -417,7 +417,6 @@ pub async fn cleanup_global_requests() -> usize {
 #[cfg(test)]
 mod tests {
     use super::*;
-    use tokio::time::{sleep, Duration};
 
     #[tokio::test]
     async fn test_active_request_creation() {
@@ -468,21 +467,19 @@ mod tests {
     async fn test_cleanup_completed() {
         let manager = ActiveRequestsManager::new();
 
-        // Add a completed task (simulate with a finished handle)
-        let handle = tokio::spawn(async {
-            // Task that completes immediately
-        });
-
-        // Wait for the task to complete
-        let _ = handle.await;
-
-        let request = ActiveRequest::new();
-        manager.add("completed-1".to_string(), request).await;
-
-        // Clean completed should work without errors
-        let cleaned = manager.clean_completed().await;
-        // Note: This test might not always remove the request since it's hard to
-        // guarantee the task is marked as finished immediately
+        // A task that has finished, and one that never will
+        let finished = tokio::spawn(async {});
+        while !finished.is_finished() {
+            tokio::task::yield_now().await;
+        }
+        manager.add("completed-1".to_string(), ActiveRequest::new().with_task_handle(finished)).await;
+        let pending = tokio::spawn(std::future::pending::<()>());
+        manager.add("running-1".to_string(), ActiveRequest::new().with_task_handle(pending)).await;
+
+        assert_eq!(manager.clean_completed().await, 1);
+        assert!(manager.get("completed-1").await.is_none());
+        assert!(manager.get("running-1").await.is_some());
+        manager.get("running-1").await.unwrap().abort();
     }
 
     #[test]
@@ -0,0 +1,203 @@
+use axum::http::HeaderMap;
+use serde_json::{json, Value};
+use std::collections::HashMap;
+use thiserror::Error;
+
+use crate::config::Settings;
+use crate::models::schemas::{ChatCompletionRequest, RequestOverrides, SafetyOverride};
+use crate::utils::auth::AuthScope;
+
+pub const NO_CACHE_HEADER: &str = "x-rujimi-no-cache";
+pub const CACHE_TTL_HEADER: &str = "x-rujimi-cache-ttl";
+pub const KEY_INDEX_HEADER: &str = "x-rujimi-key-index";
+pub const SAFETY_HEADER: &str = "x-rujimi-safety";
+pub const SESSION_HEADER: &str = "x-rujimi-session";
+
+#[derive(Error, Debug, PartialEq, Eq)]
+pub enum OverrideError {
+    #[error("Invalid {header} header: {value}")]
+    Invalid { header: &'static str, value: String },
+    #[error("{header} is not allowed for this caller")]
+    Forbidden { header: &'static str },
+}
+
+impl OverrideError {
+    /// Error type for `create_error_response`
+    pub fn error_type(&self) -> &'static str {
+        match self {
+            Self::Invalid { .. } => "invalid_request_error",
+            Self::Forbidden { .. } => "forbidden_error",
+        }
+    }
+}
+
+/// Read the `X-Rujimi-*` headers of a chat completion. Public callers get none of them, key
+/// pinning needs the admin scope and the safety switch needs `allow_safety_override`.
+pub fn parse_request_overrides(
+    headers: &HeaderMap,
+    scope: &AuthScope,
+    settings: &Settings,
+) -> Result<RequestOverrides, OverrideError> {
+    let mut overrides = RequestOverrides::default();
+
+    if let Some(value) = header(headers, NO_CACHE_HEADER)? {
+        require(!matches!(scope, AuthScope::Public), NO_CACHE_HEADER)?;
+        overrides.no_cache = match value.to_ascii_lowercase().as_str() {
+            "1" | "true" | "yes" => true,
+            "0" | "false" | "no" => false,
+            _ => return Err(invalid(NO_CACHE_HEADER, value)),
+        };
+    }
+
+    // Only ever shortens the lifetime, so a caller cannot pin answers beyond `cache_expiry_time`
+    if let Some(value) = header(headers, CACHE_TTL_HEADER)? {
+        require(!matches!(scope, AuthScope::Public), CACHE_TTL_HEADER)?;
+        let ttl: u64 = value.parse().map_err(|_| invalid(CACHE_TTL_HEADER, value))?;
+        if ttl == 0 {
+            return Err(invalid(CACHE_TTL_HEADER, value));
+        }
+        overrides.cache_ttl = Some(ttl.min(settings.cache_expiry_time));
+    }
+
+    if let Some(value) = header(headers, KEY_INDEX_HEADER)? {
+        require(matches!(scope, AuthScope::Admin), KEY_INDEX_HEADER)?;
+        overrides.key_index = Some(value.parse().map_err(|_| invalid(KEY_INDEX_HEADER, value))?);
+    }
+
+    if let Some(value) = header(headers, SAFETY_HEADER)? {
+        require(settings.allow_safety_override && !matches!(scope, AuthScope::Public), SAFETY_HEADER)?;
+        overrides.safety = Some(match value.to_ascii_lowercase().as_str() {
+            "off" => SafetyOverride::Off,
+            "default" => SafetyOverride::Default,
+            _ => return Err(invalid(SAFETY_HEADER, value)),
+        });
+    }
+
+    Ok(overrides)
+}
+
+/// The overrides in effect, for the request log entry's extra map
+pub fn override_log_fields(overrides: &RequestOverrides) -> HashMap<String, Value> {
+    let mut extra = HashMap::new();
+    if overrides.no_cache {
+        extra.insert("no_cache".to_string(), json!(true));
+    }
+    if let Some(ttl) = overrides.cache_ttl {
+        extra.insert("cache_ttl".to_string(), json!(ttl));
+    }
+    if let Some(index) = overrides.key_index {
+        extra.insert("key_index".to_string(), json!(index));
+    }
+    if let Some(safety) = overrides.safety {
+        let safety = match safety {
+            SafetyOverride::Off => "off",
+            SafetyOverride::Default => "default",
+        };
+        extra.insert("safety".to_string(), json!(safety));
+    }
+    extra
+}
+
+/// Stable id of the conversation a request belongs to, for sticky key assignment: the
+/// `X-Rujimi-Session` header, else the `user` field, else the first user message
+pub fn conversation_session(headers: &HeaderMap, request: &ChatCompletionRequest) -> Option<String> {
+    let session = headers
+        .get(SESSION_HEADER)
+        .and_then(|value| value.to_str().ok())
+        .map(str::trim)
+        .filter(|value| !value.is_empty())
+        .map(|value| format!("session:{}", value));
+    let user = || {
+        request
+            .user
+            .as_deref()
+            .filter(|user| !user.is_empty())
+            .map(|user| format!("user:{}", user))
+    };
+    let first_message = || {
+        let message = request.messages.iter().find(|message| message.role == "user")?;
+        Some(format!("message:{}", serde_json::to_string(&message.content).ok()?))
+    };
+    let source = session.or_else(user).or_else(first_message)?;
+    Some(blake3::hash(source.as_bytes()).to_hex().to_string())
+}
+
+fn header<'a>(headers: &'a HeaderMap, name: &'static str) -> Result<Option<&'a str>, OverrideError> {
+    match headers.get(name) {
+        Some(value) => value
+            .to_str()
+            .map(|value| Some(value.trim()))
+            .map_err(|_| invalid(name, "<non-ASCII>")),
+        None => Ok(None),
+    }
+}
+
+fn require(allowed: bool, header: &'static str) -> Result<(), OverrideError> {
+    if allowed {
+        Ok(())
+    } else {
+        Err(OverrideError::Forbidden { header })
+    }
+}
+
+fn invalid(header: &'static str, value: &str) -> OverrideError {
+    OverrideError::Invalid { header, value: value.to_string() }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn request(body: Value) -> ChatCompletionRequest {
+        serde_json::from_value(body).unwrap()
+    }
+
+    #[test]
+    fn test_cache_ttl_override() {
+        let settings = Settings { cache_expiry_time: 3600, ..Default::default() };
+        let parse = |value: &str, scope: AuthScope| {
+            let mut headers = HeaderMap::new();
+            headers.insert(CACHE_TTL_HEADER, value.parse().unwrap());
+            parse_request_overrides(&headers, &scope, &settings).map(|overrides| overrides.cache_ttl)
+        };
+
+        assert_eq!(parse("600", AuthScope::Authenticated), Ok(Some(600)));
+        assert_eq!(parse("86400", AuthScope::Authenticated), Ok(Some(3600)));
+        assert_eq!(parse("0", AuthScope::Authenticated), Err(invalid(CACHE_TTL_HEADER, "0")));
+        assert_eq!(parse("10m", AuthScope::Authenticated), Err(invalid(CACHE_TTL_HEADER, "10m")));
+        assert_eq!(parse("600", AuthScope::Public), Err(OverrideError::Forbidden { header: CACHE_TTL_HEADER }));
+    }
+
+    #[test]
+    fn test_conversation_session() {
+        let first_turn = request(json!({
+            "model": "gemini-2.0-flash",
+            "messages": [{"role": "system", "content": "Be brief"}, {"role": "user", "content": "Hi"}]
+        }));
+        let later_turn = request(json!({
+            "model": "gemini-2.0-flash",
+            "messages": [
+                {"role": "system", "content": "Be brief"},
+                {"role": "user", "content": "Hi"},
+                {"role": "assistant", "content": "Hello"},
+                {"role": "user", "content": "How are you?"}
+            ]
+        }));
+        let headers = HeaderMap::new();
+        let session = conversation_session(&headers, &first_turn);
+        assert!(session.is_some());
+        assert_eq!(conversation_session(&headers, &later_turn), session);
+
+        let with_user = request(json!({"model": "m", "messages": [{"role": "user", "content": "Hi"}], "user": "alice"}));
+        assert_ne!(conversation_session(&headers, &with_user), session);
+
+        let mut with_header = HeaderMap::new();
+        with_header.insert(SESSION_HEADER, "chat-42".parse().unwrap());
+        let pinned = conversation_session(&with_header, &first_turn);
+        assert_eq!(conversation_session(&with_header, &with_user), pinned);
+        assert_ne!(pinned, session);
+
+        let no_user = request(json!({"model": "m", "messages": [{"role": "system", "content": "Be brief"}]}));
+        assert_eq!(conversation_session(&headers, &no_user), None);
+    }
+}
@@ -1,11 +1,15 @@
 use rand::{distributions::Alphanumeric, Rng};
 use serde_json::{Value, json};
+use axum::extract::State;
 use axum::http::StatusCode;
 use axum::response::{IntoResponse, Response};
 use axum::Json;
 use chrono::Utc;
 use uuid::Uuid;
 
+use crate::utils::tokens::estimate_tokens;
+use crate::models::schemas::{GeminiUsageMetadata, Usage};
+
 pub fn generate_random_string(length: usize) -> String {
     rand::thread_rng()
         .sample_iter(&Alphanumeric)
@@ -44,11 +48,6 @@ pub fn extract_text_from_value(value: &Value) -> String {
     }
 }
 
-pub fn estimate_tokens(text: &str) -> u32 {
-    // Simple token estimation: roughly 4 characters per token
-    (text.len() as f32 / 4.0).ceil() as u32
-}
-
 pub fn create_error_response(message: &str, error_type: &str) -> Response {
     let error_json = serde_json::json!({
         "error": {
@@ -62,16 +61,26 @@ pub fn create_error_response(message: &str, error_type: &str) -> Response {
     let status = match error_type {
         "authentication_error" => StatusCode::UNAUTHORIZED,
         "forbidden_error" => StatusCode::FORBIDDEN,
+        "not_found_error" => StatusCode::NOT_FOUND,
         "invalid_model" => StatusCode::BAD_REQUEST,
-        "service_unavailable" => StatusCode::SERVICE_UNAVAILABLE,
+        "service_unavailable" | "model_unavailable" => StatusCode::SERVICE_UNAVAILABLE,
         "api_error" => StatusCode::INTERNAL_SERVER_ERROR,
         "stream_error" => StatusCode::INTERNAL_SERVER_ERROR,
+        "upstream_timeout" => StatusCode::GATEWAY_TIMEOUT,
+        "request_too_large" => StatusCode::PAYLOAD_TOO_LARGE,
         _ => StatusCode::BAD_REQUEST,
     };
 
     (status, Json(error_json)).into_response()
 }
 
+/// 400 `invalid_request_error` whose `param` names the request field at fault
+pub fn create_param_error_response(message: &str, param: &str) -> Response {
+    let mut error_json = create_error_json(message, "invalid_request_error");
+    error_json["error"]["param"] = Value::from(param);
+    (StatusCode::BAD_REQUEST, Json(error_json)).into_response()
+}
+
 pub fn create_error_json(message: &str, error_type: &str) -> Value {
     serde_json::json!({
         "error": {
@@ -83,6 +92,369 @@ pub fn create_error_json(message: &str, error_type: &str) -> Value {
     })
 }
 
+/// Replace axum's plain-text body-limit rejection with an OpenAI-style 413 error
+pub async fn request_too_large(State(limit_mb): State<u64>, response: Response) -> Response {
+    if response.status() != StatusCode::PAYLOAD_TOO_LARGE {
+        return response;
+    }
+
+    create_error_response(
+        &format!(
+            "Request body exceeds the {} MB limit. Large images or videos sent inline as base64 count towards it; \
+             send smaller or fewer media parts, or ask the operator to raise MAX_REQUEST_BODY_MB",
+            limit_mb
+        ),
+        "request_too_large",
+    )
+}
+
+/// Wrap an SSE body with headers that stop proxies (nginx) and clients from buffering it
+pub fn sse_response<S>(sse: axum::response::Sse<S>) -> Response
+where
+    axum::response::Sse<S>: IntoResponse,
+{
+    let mut response = sse.into_response();
+    let headers = response.headers_mut();
+    headers.insert(axum::http::header::CACHE_CONTROL, axum::http::HeaderValue::from_static("no-cache"));
+    headers.insert("x-accel-buffering", axum::http::HeaderValue::from_static("no"));
+    headers.insert(axum::http::header::CONNECTION, axum::http::HeaderValue::from_static("keep-alive"));
+    response
+}
+
+/// Interleave `: ping` comment frames whenever `inner` stays silent for `interval`.
+/// Heartbeats are SSE comments, so clients never see them as content, and they stop with `inner`.
+pub fn with_heartbeat<S, E>(
+    inner: S,
+    interval: std::time::Duration,
+) -> impl futures_util::Stream<Item = Result<axum::response::sse::Event, E>> + Send
+where
+    S: futures_util::Stream<Item = Result<axum::response::sse::Event, E>> + Send + Unpin + 'static,
+    E: Send + 'static,
+{
+    use futures_util::StreamExt;
+
+    futures_util::stream::unfold(inner, move |mut inner| async move {
+        match tokio::time::timeout(interval, inner.next()).await {
+            Ok(Some(item)) => Some((item, inner)),
+            Ok(None) => None,
+            Err(_) => Some((Ok(axum::response::sse::Event::default().comment("ping")), inner)),
+        }
+    })
+}
+
+/// Pass `inner` through, but when its first item has not arrived by `deadline`, drop `inner`
+/// and end with the item `timed_out` produces instead. Later items are not timed.
+pub fn with_first_item_deadline<S, F, Fut>(
+    inner: S,
+    deadline: std::time::Instant,
+    timed_out: F,
+) -> impl futures_util::Stream<Item = S::Item> + Send
+where
+    S: futures_util::Stream + Send + Unpin + 'static,
+    S::Item: Send,
+    F: FnOnce() -> Fut + Send + 'static,
+    Fut: std::future::Future<Output = S::Item> + Send,
+{
+    use futures_util::future::Either;
+    use futures_util::{stream, StreamExt};
+
+    stream::once(async move {
+        let mut inner = inner;
+        let (first, rest) = match tokio::time::timeout_at(deadline.into(), inner.next()).await {
+            Ok(Some(first)) => (Some(first), Either::Left(inner)),
+            Ok(None) => (None, Either::Right(stream::empty())),
+            Err(_) => (Some(timed_out().await), Either::Right(stream::empty())),
+        };
+        stream::iter(first).chain(rest)
+    })
+    .flatten()
+}
+
+/// A chat completion body or stream chunk reshaped as a legacy `text_completion`, with each
+/// choice's message or delta content as its `text`. Anything without `choices`, such as an
+/// error, is returned unchanged.
+pub fn text_completion_from_chat(chat: &Value) -> Value {
+    let choices = match chat.get("choices").and_then(Value::as_array) {
+        Some(choices) => choices,
+        None => return chat.clone(),
+    };
+    let choices: Vec<Value> = choices
+        .iter()
+        .map(|choice| {
+            let message = choice.get("message").or_else(|| choice.get("delta"));
+            let text = message.and_then(|message| message.get("content")).map(extract_text_from_value);
+            json!({
+                "text": text.unwrap_or_default(),
+                "index": choice.get("index").cloned().unwrap_or(json!(0)),
+                "logprobs": null,
+                "finish_reason": choice.get("finish_reason").cloned().unwrap_or(Value::Null),
+            })
+        })
+        .collect();
+
+    let mut completion = json!({
+        "id": chat.get("id").cloned().unwrap_or(Value::Null),
+        "object": "text_completion",
+        "created": chat.get("created").cloned().unwrap_or(Value::Null),
+        "model": chat.get("model").cloned().unwrap_or(Value::Null),
+        "choices": choices,
+    });
+    if let Some(usage) = chat.get("usage").filter(|usage| !usage.is_null()) {
+        completion["usage"] = usage.clone();
+    }
+    completion
+}
+
+/// Rewrite a chat completion response, JSON or SSE, as a legacy text completion response.
+/// Error responses pass through unchanged.
+pub async fn text_completion_response(response: Response) -> Response {
+    use axum::body::Body;
+    use axum::http::header::{CONTENT_LENGTH, CONTENT_TYPE};
+
+    if !response.status().is_success() {
+        return response;
+    }
+    let streaming = response
+        .headers()
+        .get(CONTENT_TYPE)
+        .and_then(|value| value.to_str().ok())
+        .is_some_and(|value| value.starts_with("text/event-stream"));
+    let (mut parts, body) = response.into_parts();
+
+    if streaming {
+        return Response::from_parts(parts, Body::from_stream(rewrite_sse_frames(body, text_completion_frame)));
+    }
+
+    let bytes = match axum::body::to_bytes(body, usize::MAX).await {
+        Ok(bytes) => bytes,
+        Err(e) => return create_error_response(&format!("Failed to read the completion: {}", e), "api_error"),
+    };
+    match serde_json::from_slice::<Value>(&bytes) {
+        Ok(chat) => {
+            parts.headers.remove(CONTENT_LENGTH);
+            Response::from_parts(parts, Json(text_completion_from_chat(&chat)).into_response().into_body())
+        }
+        Err(_) => Response::from_parts(parts, Body::from(bytes)),
+    }
+}
+
+/// Pass each SSE frame of `body`, without its blank-line terminator, through `rewrite`,
+/// which returns the complete replacement text (empty to drop the frame). Frames split
+/// across body chunks are reassembled first.
+pub fn rewrite_sse_frames<F>(
+    body: axum::body::Body,
+    rewrite: F,
+) -> impl futures_util::Stream<Item = Result<axum::body::Bytes, axum::Error>> + Send
+where
+    F: FnMut(&str) -> String + Send + 'static,
+{
+    use futures_util::StreamExt;
+
+    futures_util::stream::unfold((body.into_data_stream(), Vec::new(), rewrite), |(mut body, mut pending, mut rewrite)| async move {
+        loop {
+            if let Some(end) = pending.windows(2).position(|window| window == b"\n\n") {
+                let frame: Vec<u8> = pending.drain(..end + 2).collect();
+                let rewritten = rewrite(String::from_utf8_lossy(&frame[..end]).as_ref());
+                if rewritten.is_empty() {
+                    continue;
+                }
+                return Some((Ok(axum::body::Bytes::from(rewritten)), (body, pending, rewrite)));
+            }
+            match body.next().await {
+                Some(Ok(chunk)) => pending.extend_from_slice(&chunk),
+                Some(Err(e)) => return Some((Err(e), (body, pending, rewrite))),
+                None if pending.is_empty() => return None,
+                None => {
+                    let rest = axum::body::Bytes::from(std::mem::take(&mut pending));
+                    return Some((Ok(rest), (body, pending, rewrite)));
+                }
+            }
+        }
+    })
+}
+
+/// One SSE frame with each chat chunk rewritten by `text_completion_from_chat`. Comments,
+/// `[DONE]` and error frames pass through.
+fn text_completion_frame(frame: &str) -> String {
+    let lines: Vec<String> = frame
+        .lines()
+        .map(|line| {
+            let data = match line.strip_prefix("data:") {
+                Some(data) => data.trim(),
+                None => return line.to_string(),
+            };
+            match serde_json::from_str::<Value>(data) {
+                Ok(chunk) => format!("data: {}", text_completion_from_chat(&chunk)),
+                Err(_) => line.to_string(),
+            }
+        })
+        .collect();
+    format!("{}\n\n", lines.join("\n"))
+}
+
+/// `resp_` and `msg_` ids derived from a chat completion id
+fn responses_ids(chat: &Value) -> (String, String) {
+    let chat_id = chat.get("id").and_then(Value::as_str).unwrap_or_default();
+    let suffix = chat_id.strip_prefix("chatcmpl-").unwrap_or(chat_id);
+    (format!("resp_{}", suffix), format!("msg_{}", suffix))
+}
+
+/// A Responses API `response` object holding `text` as its one output message
+fn responses_object(chat: &Value, text: &str, finish_reason: Option<&str>, usage: Option<&Value>, status: &str) -> Value {
+    let (id, message_id) = responses_ids(chat);
+    let status = match (status, finish_reason) {
+        ("completed", Some("length")) => "incomplete",
+        (status, _) => status,
+    };
+    let output = if status == "in_progress" {
+        json!([])
+    } else {
+        json!([{
+            "type": "message",
+            "id": message_id,
+            "status": status,
+            "role": "assistant",
+            "content": [{"type": "output_text", "text": text, "annotations": []}],
+        }])
+    };
+    let usage = usage.filter(|usage| !usage.is_null()).map(|usage| {
+        json!({
+            "input_tokens": usage.get("prompt_tokens").cloned().unwrap_or(json!(0)),
+            "output_tokens": usage.get("completion_tokens").cloned().unwrap_or(json!(0)),
+            "total_tokens": usage.get("total_tokens").cloned().unwrap_or(json!(0)),
+        })
+    });
+    json!({
+        "id": id,
+        "object": "response",
+        "created_at": chat.get("created").cloned().unwrap_or(Value::Null),
+        "status": status,
+        "incomplete_details": if status == "incomplete" { json!({"reason": "max_output_tokens"}) } else { Value::Null },
+        "model": chat.get("model").cloned().unwrap_or(Value::Null),
+        "output": output,
+        "usage": usage.unwrap_or(Value::Null),
+    })
+}
+
+/// A chat completion reshaped as a Responses API `response`. Anything without `choices`,
+/// such as an error, is returned unchanged.
+pub fn responses_from_chat(chat: &Value) -> Value {
+    let choice = match chat.get("choices").and_then(Value::as_array).and_then(|choices| choices.first()) {
+        Some(choice) => choice,
+        None => return chat.clone(),
+    };
+    let text = choice.get("message").and_then(|message| message.get("content")).map(extract_text_from_value);
+    let finish_reason = choice.get("finish_reason").and_then(Value::as_str);
+    responses_object(chat, &text.unwrap_or_default(), finish_reason, chat.get("usage"), "completed")
+}
+
+/// Rewrite a chat completion response, JSON or SSE, as a Responses API response. Streams
+/// become `response.created`, `response.output_text.delta` and `response.completed` events.
+/// Error responses pass through unchanged.
+pub async fn responses_api_response(response: Response) -> Response {
+    use axum::body::Body;
+    use axum::http::header::{CONTENT_LENGTH, CONTENT_TYPE};
+
+    if !response.status().is_success() {
+        return response;
+    }
+    let streaming = response
+        .headers()
+        .get(CONTENT_TYPE)
+        .and_then(|value| value.to_str().ok())
+        .is_some_and(|value| value.starts_with("text/event-stream"));
+    let (mut parts, body) = response.into_parts();
+
+    if streaming {
+        let mut stream = ResponsesStream::default();
+        return Response::from_parts(parts, Body::from_stream(rewrite_sse_frames(body, move |frame| stream.frame(frame))));
+    }
+
+    let bytes = match axum::body::to_bytes(body, usize::MAX).await {
+        Ok(bytes) => bytes,
+        Err(e) => return create_error_response(&format!("Failed to read the completion: {}", e), "api_error"),
+    };
+    match serde_json::from_slice::<Value>(&bytes) {
+        Ok(chat) => {
+            parts.headers.remove(CONTENT_LENGTH);
+            Response::from_parts(parts, Json(responses_from_chat(&chat)).into_response().into_body())
+        }
+        Err(_) => Response::from_parts(parts, Body::from(bytes)),
+    }
+}
+
+/// What a streamed Responses API answer has seen so far
+#[derive(Default)]
+struct ResponsesStream {
+    /// The first chunk, which names the response
+    first: Option<Value>,
+    text: String,
+    finish_reason: Option<String>,
+    usage: Option<Value>,
+}
+
+impl ResponsesStream {
+    /// The Responses events for one chat SSE frame. Comments pass through; `[DONE]` becomes
+    /// `response.completed` and upstream errors become `error` events.
+    fn frame(&mut self, frame: &str) -> String {
+        let mut events = String::new();
+        for line in frame.lines() {
+            let data = match line.strip_prefix("data:") {
+                Some(data) => data.trim(),
+                None => {
+                    events.push_str(line);
+                    events.push_str("\n\n");
+                    continue;
+                }
+            };
+            if data == "[DONE]" {
+                let first = self.first.clone().unwrap_or_else(|| json!({}));
+                let response = responses_object(&first, &self.text, self.finish_reason.as_deref(), self.usage.as_ref(), "completed");
+                events.push_str(&create_sse_event("response.completed", &json!({"type": "response.completed", "response": response}).to_string()));
+                continue;
+            }
+            let chunk: Value = match serde_json::from_str(data) {
+                Ok(chunk) => chunk,
+                Err(_) => continue,
+            };
+            if let Some(error) = chunk.get("error") {
+                events.push_str(&create_sse_event("error", &json!({"type": "error", "error": error}).to_string()));
+                continue;
+            }
+
+            if self.first.is_none() {
+                let response = responses_object(&chunk, "", None, None, "in_progress");
+                events.push_str(&create_sse_event("response.created", &json!({"type": "response.created", "response": response}).to_string()));
+                self.first = Some(chunk.clone());
+            }
+            if let Some(usage) = chunk.get("usage").filter(|usage| !usage.is_null()) {
+                self.usage = Some(usage.clone());
+            }
+            let choice = match chunk.get("choices").and_then(Value::as_array).and_then(|choices| choices.first()) {
+                Some(choice) => choice,
+                None => continue,
+            };
+            if let Some(reason) = choice.get("finish_reason").and_then(Value::as_str) {
+                self.finish_reason = Some(reason.to_string());
+            }
+            let delta = choice.get("delta").and_then(|delta| delta.get("content")).map(extract_text_from_value).unwrap_or_default();
+            if !delta.is_empty() {
+                self.text.push_str(&delta);
+                let (_, message_id) = responses_ids(self.first.as_ref().unwrap_or(&chunk));
+                let event = json!({
+                    "type": "response.output_text.delta",
+                    "item_id": message_id,
+                    "output_index": 0,
+                    "content_index": 0,
+                    "delta": delta,
+                });
+                events.push_str(&create_sse_event("response.output_text.delta", &event.to_string()));
+            }
+        }
+        events
+    }
+}
+
 pub fn create_sse_data(data: &str) -> String {
     format!("data: {}\n\n", data)
 }
@@ -238,35 +610,12 @@ fn extract_gemini_content(gemini_response: &Value) -> String {
 
 /// Extract usage information from Gemini response
 fn extract_gemini_usage(gemini_response: &Value) -> Value {
-    if let Some(usage_metadata) = gemini_response.get("usageMetadata") {
-        let prompt_tokens = usage_metadata
-            .get("promptTokenCount")
-            .and_then(|v| v.as_u64())
-            .unwrap_or(0) as u32;
-
-        let completion_tokens = usage_metadata
-            .get("candidatesTokenCount")
-            .and_then(|v| v.as_u64())
-            .unwrap_or(0) as u32;
-
-        let total_tokens = usage_metadata
-            .get("totalTokenCount")
-            .and_then(|v| v.as_u64())
-            .unwrap_or((prompt_tokens + completion_tokens) as u64) as u32;
-
-        return json!({
-            "prompt_tokens": prompt_tokens,
-            "completion_tokens": completion_tokens,
-            "total_tokens": total_tokens
-        });
-    }
-
-    // Default usage if not available
-    json!({
-        "prompt_tokens": 0,
-        "completion_tokens": 0,
-        "total_tokens": 0
-    })
+    let usage = gemini_response
+        .get("usageMetadata")
+        .and_then(|meta| serde_json::from_value::<GeminiUsageMetadata>(meta.clone()).ok())
+        .map(|meta| Usage::from_gemini(&meta))
+        .unwrap_or_else(|| Usage::new(0, 0));
+    serde_json::to_value(usage).unwrap_or_default()
 }
 
 /// Create streaming completion chunk
@@ -369,12 +718,6 @@ mod tests {
         assert_ne!(s1, s2); // Should be different
     }
 
-    #[test]
-    fn test_estimate_tokens() {
-        assert_eq!(estimate_tokens("hello"), 2); // 5 chars / 4 = 1.25 -> 2
-        assert_eq!(estimate_tokens("hello world"), 3); // 11 chars / 4 = 2.75 -> 3
-    }
-
     #[test]
     fn test_sanitize_response_content() {
         let input = "  hello  \n\n  world  \n\n  ";
@@ -460,4 +803,148 @@ mod tests {
         assert!(chunk.contains("data: [DONE]"));
         assert!(chunk.contains("finish_reason"));
     }
-}
\ No newline at end of file
+
+    #[tokio::test]
+    async fn test_heartbeat_fills_idle_gaps_and_stops_with_stream() {
+        use axum::response::sse::Event;
+        use futures_util::{stream, StreamExt};
+        use std::time::Duration;
+
+        let inner = stream::iter(0..2).then(|i| async move {
+            if i > 0 {
+                tokio::time::sleep(Duration::from_millis(120)).await;
+            }
+            Ok::<Event, std::convert::Infallible>(Event::default().data(i.to_string()))
+        });
+
+        let items: Vec<_> = with_heartbeat(Box::pin(inner), Duration::from_millis(40)).collect().await;
+
+        // Two real events plus at least one heartbeat in the gap, none after the end
+        assert!(items.len() >= 3);
+        assert!(items.len() <= 5);
+    }
+
+    #[tokio::test]
+    async fn test_text_completion_response() {
+        use axum::body::Body;
+
+        let chat = json!({
+            "id": "chatcmpl-1", "object": "chat.completion", "created": 1, "model": "gemini-2.0-flash",
+            "choices": [{"index": 0, "message": {"role": "assistant", "content": "Hi there"}, "finish_reason": "stop"}],
+            "usage": {"prompt_tokens": 2, "completion_tokens": 2, "total_tokens": 4}
+        });
+        let response = text_completion_response(Json(chat).into_response()).await;
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX).await.unwrap();
+        let completion: Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(completion["object"], "text_completion");
+        assert_eq!(completion["choices"][0]["text"], "Hi there");
+        assert_eq!(completion["choices"][0]["finish_reason"], "stop");
+        assert_eq!(completion["usage"]["total_tokens"], 4);
+
+        // Frames split across body chunks are reassembled before rewriting
+        let chunk = json!({
+            "id": "chatcmpl-1", "object": "chat.completion.chunk", "created": 1, "model": "gemini-2.0-flash",
+            "choices": [{"index": 0, "delta": {"content": "Hi"}, "finish_reason": null}]
+        });
+        let sse = format!(": ping\n\ndata: {}\n\ndata: [DONE]\n\n", chunk);
+        let (first, second) = sse.split_at(30);
+        let chunks = vec![Ok::<_, std::io::Error>(first.to_string()), Ok(second.to_string())];
+        let response = Response::builder()
+            .header("content-type", "text/event-stream")
+            .body(Body::from_stream(futures_util::stream::iter(chunks)))
+            .unwrap();
+        let body = axum::body::to_bytes(text_completion_response(response).await.into_body(), usize::MAX).await.unwrap();
+        let frames: Vec<&str> = std::str::from_utf8(&body).unwrap().split("\n\n").collect();
+        assert_eq!(frames[0], ": ping");
+        let converted: Value = serde_json::from_str(frames[1].strip_prefix("data: ").unwrap()).unwrap();
+        assert_eq!(converted["object"], "text_completion");
+        assert_eq!(converted["choices"][0]["text"], "Hi");
+        assert_eq!(frames[2], "data: [DONE]");
+
+        // Errors are left alone
+        let error = create_error_response("No API keys available", "service_unavailable");
+        let status = error.status();
+        assert_eq!(text_completion_response(error).await.status(), status);
+    }
+
+    #[tokio::test]
+    async fn test_responses_api_response() {
+        use axum::body::Body;
+
+        let chat = json!({
+            "id": "chatcmpl-1", "object": "chat.completion", "created": 1, "model": "gemini-2.0-flash",
+            "choices": [{"index": 0, "message": {"role": "assistant", "content": "Hi there"}, "finish_reason": "length"}],
+            "usage": {"prompt_tokens": 2, "completion_tokens": 3, "total_tokens": 5}
+        });
+        let response = responses_api_response(Json(chat).into_response()).await;
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX).await.unwrap();
+        let converted: Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(converted["id"], "resp_1");
+        assert_eq!(converted["object"], "response");
+        assert_eq!(converted["status"], "incomplete");
+        assert_eq!(converted["incomplete_details"]["reason"], "max_output_tokens");
+        assert_eq!(converted["output"][0]["content"][0]["type"], "output_text");
+        assert_eq!(converted["output"][0]["content"][0]["text"], "Hi there");
+        assert_eq!(converted["usage"], json!({"input_tokens": 2, "output_tokens": 3, "total_tokens": 5}));
+
+        let chunk = |delta: Value, finish: Value, usage: Value| json!({
+            "id": "chatcmpl-2", "object": "chat.completion.chunk", "created": 1, "model": "gemini-2.0-flash",
+            "choices": if delta.is_null() { json!([]) } else { json!([{"index": 0, "delta": delta, "finish_reason": finish}]) },
+            "usage": usage,
+        });
+        let sse = format!(
+            "data: {}\n\ndata: {}\n\ndata: {}\n\ndata: [DONE]\n\n",
+            chunk(json!({"role": "assistant", "content": "Hel"}), Value::Null, Value::Null),
+            chunk(json!({"content": "lo"}), json!("stop"), Value::Null),
+            chunk(Value::Null, Value::Null, json!({"prompt_tokens": 1, "completion_tokens": 2, "total_tokens": 3})),
+        );
+        let response = Response::builder()
+            .header("content-type", "text/event-stream")
+            .body(Body::from(sse))
+            .unwrap();
+        let body = axum::body::to_bytes(responses_api_response(response).await.into_body(), usize::MAX).await.unwrap();
+        let events: Vec<(&str, Value)> = std::str::from_utf8(&body)
+            .unwrap()
+            .split("\n\n")
+            .filter(|frame| !frame.is_empty())
+            .map(|frame| {
+                let (event, data) = frame.split_once('\n').unwrap();
+                (event.strip_prefix("event: ").unwrap(), serde_json::from_str(data.strip_prefix("data: ").unwrap()).unwrap())
+            })
+            .collect();
+        let names: Vec<&str> = events.iter().map(|(name, _)| *name).collect();
+        assert_eq!(names, ["response.created", "response.output_text.delta", "response.output_text.delta", "response.completed"]);
+        assert_eq!(events[0].1["response"]["status"], "in_progress");
+        assert_eq!(events[2].1["delta"], "lo");
+        let completed = &events[3].1["response"];
+        assert_eq!(completed["id"], "resp_2");
+        assert_eq!(completed["status"], "completed");
+        assert_eq!(completed["output"][0]["content"][0]["text"], "Hello");
+        assert_eq!(completed["usage"]["output_tokens"], 2);
+    }
+
+    #[tokio::test]
+    async fn test_first_item_deadline() {
+        use futures_util::{stream, StreamExt};
+        use std::time::{Duration, Instant};
+
+        let slow_start = |delay_ms: u64| {
+            Box::pin(stream::iter(0..3).then(move |i| async move {
+                if i == 0 {
+                    tokio::time::sleep(Duration::from_millis(delay_ms)).await;
+                } else {
+                    tokio::time::sleep(Duration::from_millis(80)).await;
+                }
+                i
+            }))
+        };
+        let deadline = || Instant::now() + Duration::from_millis(50);
+
+        // Only the wait for the first item counts against the deadline
+        let items: Vec<_> = with_first_item_deadline(slow_start(0), deadline(), || async { -1 }).collect().await;
+        assert_eq!(items, vec![0, 1, 2]);
+
+        let items: Vec<_> = with_first_item_deadline(slow_start(200), deadline(), || async { -1 }).collect().await;
+        assert_eq!(items, vec![-1]);
+    }
+}
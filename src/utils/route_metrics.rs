@@ -0,0 +1,272 @@
+//! Latency and status codes per route, measured inside rujimi. Each route keeps a ring of
+//! five-minute histograms over the last hour; recording only touches atomics, so the hot
+//! path never waits on the stats locks.
+
+use axum::extract::{MatchedPath, Request, State};
+use axum::middleware::Next;
+use axum::response::Response;
+use dashmap::DashMap;
+use serde::Serialize;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+/// Length of one histogram slot
+const SLOT_SECS: u64 = 300;
+/// Slots kept per route, together covering the last hour
+const SLOTS: usize = 12;
+/// Each power of two of microseconds is split into this many buckets, so a reported
+/// percentile is within 12.5% of the true value
+const SUB_BUCKETS: u64 = 4;
+/// Enough buckets for latencies up to about 19 hours
+const BUCKETS: usize = 144;
+/// Route label for requests that matched no route, so unknown paths cannot grow the map
+const UNMATCHED_ROUTE: &str = "unmatched";
+
+struct Slot {
+    /// Which five-minute period the counts belong to
+    period: AtomicU64,
+    latencies: [AtomicU64; BUCKETS],
+    /// Counts of 1xx to 5xx responses
+    statuses: [AtomicU64; 5],
+}
+
+impl Slot {
+    fn new() -> Self {
+        Self {
+            period: AtomicU64::new(0),
+            latencies: std::array::from_fn(|_| AtomicU64::new(0)),
+            statuses: std::array::from_fn(|_| AtomicU64::new(0)),
+        }
+    }
+}
+
+struct RouteHistogram {
+    slots: [Slot; SLOTS],
+}
+
+impl RouteHistogram {
+    fn new() -> Self {
+        Self { slots: std::array::from_fn(|_| Slot::new()) }
+    }
+
+    /// A request recorded while another thread resets the same slot may be lost, which is
+    /// fine for a diagnostic histogram
+    fn record(&self, status: u16, elapsed: Duration, now_secs: u64) {
+        let period = now_secs / SLOT_SECS;
+        let slot = &self.slots[(period % SLOTS as u64) as usize];
+        let seen = slot.period.load(Ordering::Acquire);
+        if period < seen {
+            // Late for a period the slot has already moved past
+            return;
+        }
+        if period > seen && slot.period.compare_exchange(seen, period, Ordering::AcqRel, Ordering::Acquire).is_ok() {
+            for counter in slot.latencies.iter().chain(slot.statuses.iter()) {
+                counter.store(0, Ordering::Relaxed);
+            }
+        }
+
+        slot.latencies[bucket_index(elapsed.as_micros() as u64)].fetch_add(1, Ordering::Relaxed);
+        if let Some(class) = (status / 100).checked_sub(1).filter(|class| *class < 5) {
+            slot.statuses[class as usize].fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    fn summary(&self, method: &str, route: &str, now_secs: u64) -> Option<RouteLatency> {
+        let current = now_secs / SLOT_SECS;
+        let mut latencies = [0u64; BUCKETS];
+        let mut statuses = [0u64; 5];
+        for slot in &self.slots {
+            let period = slot.period.load(Ordering::Acquire);
+            if period + (SLOTS as u64) <= current || period > current {
+                continue;
+            }
+            for (total, counter) in latencies.iter_mut().zip(&slot.latencies) {
+                *total += counter.load(Ordering::Relaxed);
+            }
+            for (total, counter) in statuses.iter_mut().zip(&slot.statuses) {
+                *total += counter.load(Ordering::Relaxed);
+            }
+        }
+
+        let count: u64 = latencies.iter().sum();
+        if count == 0 {
+            return None;
+        }
+        Some(RouteLatency {
+            method: method.to_string(),
+            route: route.to_string(),
+            count,
+            status_classes: statuses
+                .iter()
+                .enumerate()
+                .filter(|(_, count)| **count > 0)
+                .map(|(class, count)| (format!("{}xx", class + 1), *count))
+                .collect(),
+            p50_ms: percentile(&latencies, count, 0.50),
+            p95_ms: percentile(&latencies, count, 0.95),
+            p99_ms: percentile(&latencies, count, 0.99),
+        })
+    }
+}
+
+/// Latency of one route over the last hour
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct RouteLatency {
+    pub method: String,
+    /// Route template, such as `/v1/models/:id`
+    pub route: String,
+    pub count: u64,
+    /// Responses per status class, such as `"2xx"`
+    pub status_classes: std::collections::BTreeMap<String, u64>,
+    pub p50_ms: f64,
+    pub p95_ms: f64,
+    pub p99_ms: f64,
+}
+
+/// Per-route latency histograms, shared by the middleware and the stats endpoint
+#[derive(Default)]
+pub struct RouteMetrics {
+    routes: DashMap<String, Arc<RouteHistogram>>,
+}
+
+impl std::fmt::Debug for RouteMetrics {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("RouteMetrics").field("routes", &self.routes.len()).finish()
+    }
+}
+
+impl RouteMetrics {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn record(&self, method: &str, route: &str, status: u16, elapsed: Duration) {
+        self.record_at(method, route, status, elapsed, unix_secs());
+    }
+
+    fn record_at(&self, method: &str, route: &str, status: u16, elapsed: Duration, now_secs: u64) {
+        let key = format!("{} {}", method, route);
+        let histogram = match self.routes.get(&key) {
+            Some(histogram) => histogram.clone(),
+            None => self.routes.entry(key).or_insert_with(|| Arc::new(RouteHistogram::new())).clone(),
+        };
+        histogram.record(status, elapsed, now_secs);
+    }
+
+    /// Every route used in the last hour, busiest first
+    pub fn summary(&self) -> Vec<RouteLatency> {
+        self.summary_at(unix_secs())
+    }
+
+    fn summary_at(&self, now_secs: u64) -> Vec<RouteLatency> {
+        let mut routes: Vec<RouteLatency> = self
+            .routes
+            .iter()
+            .filter_map(|entry| {
+                let (method, route) = entry.key().split_once(' ')?;
+                entry.value().summary(method, route, now_secs)
+            })
+            .collect();
+        routes.sort_by(|a, b| b.count.cmp(&a.count).then_with(|| a.route.cmp(&b.route)));
+        routes
+    }
+
+    pub fn clear(&self) {
+        self.routes.clear();
+    }
+}
+
+/// Middleware recording the route template, method, status and time until the response
+/// headers are ready. Time spent streaming a body afterwards is not included.
+pub async fn track_route_metrics(State(metrics): State<Arc<RouteMetrics>>, request: Request, next: Next) -> Response {
+    let method = request.method().clone();
+    let route = request.extensions().get::<MatchedPath>().map(|path| path.as_str().to_string());
+    let started = Instant::now();
+
+    let response = next.run(request).await;
+
+    let route = route.as_deref().unwrap_or(UNMATCHED_ROUTE);
+    metrics.record(method.as_str(), route, response.status().as_u16(), started.elapsed());
+    response
+}
+
+fn unix_secs() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default().as_secs()
+}
+
+fn bucket_index(micros: u64) -> usize {
+    if micros < SUB_BUCKETS {
+        return micros as usize;
+    }
+    let exponent = 63 - micros.leading_zeros() as u64;
+    let sub = (micros >> (exponent - 2)) & (SUB_BUCKETS - 1);
+    (((exponent - 1) * SUB_BUCKETS + sub) as usize).min(BUCKETS - 1)
+}
+
+/// Lower and upper bound in microseconds of the latencies counted in `index`
+fn bucket_bounds(index: usize) -> (u64, u64) {
+    let index = index as u64;
+    if index < SUB_BUCKETS {
+        return (index, index + 1);
+    }
+    let exponent = index / SUB_BUCKETS + 1;
+    let sub = index % SUB_BUCKETS;
+    let lower = (SUB_BUCKETS + sub) << (exponent - 2);
+    (lower, lower + (1 << (exponent - 2)))
+}
+
+/// The `quantile` of the counted latencies in milliseconds, taken as the middle of its bucket
+fn percentile(latencies: &[u64; BUCKETS], count: u64, quantile: f64) -> f64 {
+    let rank = ((count as f64 * quantile).ceil() as u64).max(1);
+    let mut seen = 0;
+    for (index, bucket) in latencies.iter().enumerate() {
+        seen += bucket;
+        if seen >= rank {
+            let (lower, upper) = bucket_bounds(index);
+            return (lower + upper) as f64 / 2.0 / 1000.0;
+        }
+    }
+    0.0
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_buckets_cover_latencies() {
+        for micros in [0, 3, 4, 7, 8, 1_000, 12_345, 250_000, 60_000_000] {
+            let (lower, upper) = bucket_bounds(bucket_index(micros));
+            assert!(lower <= micros && micros < upper, "{} not in [{}, {})", micros, lower, upper);
+        }
+        assert_eq!(bucket_index(u64::MAX), BUCKETS - 1);
+    }
+
+    #[test]
+    fn test_route_percentiles_over_the_last_hour() {
+        let metrics = RouteMetrics::new();
+        let now = 1_000_000 * SLOT_SECS;
+        for i in 0..100u64 {
+            let status = if i < 98 { 200 } else { 502 };
+            metrics.record_at("POST", "/v1/chat/completions", status, Duration::from_millis(10 + i), now);
+        }
+        metrics.record_at("GET", "/health", 200, Duration::from_micros(50), now);
+        // Older than an hour, so left out
+        metrics.record_at("GET", "/health", 200, Duration::from_secs(5), now - 3600);
+
+        let summary = metrics.summary_at(now + 60);
+        assert_eq!(summary.len(), 2);
+        let chat = &summary[0];
+        assert_eq!((chat.method.as_str(), chat.route.as_str(), chat.count), ("POST", "/v1/chat/completions", 100));
+        assert_eq!(chat.status_classes.get("2xx"), Some(&98));
+        assert_eq!(chat.status_classes.get("5xx"), Some(&2));
+        assert!((chat.p50_ms - 60.0).abs() / 60.0 < 0.15, "p50 {}", chat.p50_ms);
+        assert!((chat.p99_ms - 109.0).abs() / 109.0 < 0.15, "p99 {}", chat.p99_ms);
+        assert!(chat.p50_ms <= chat.p95_ms && chat.p95_ms <= chat.p99_ms);
+        assert_eq!(summary[1].count, 1);
+        assert!(summary[1].p99_ms < 0.1);
+
+        assert!(metrics.summary_at(now + 3600).is_empty());
+    }
+}
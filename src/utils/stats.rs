@@ -1,18 +1,148 @@
 use dashmap::DashMap;
 use serde::{Deserialize, Serialize};
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
 use std::sync::Arc;
-use std::time::{Duration, SystemTime};
+use std::time::{Duration, Instant, SystemTime};
 use tokio::sync::RwLock;
 use tracing::info;
 
+use crate::models::schemas::Usage;
+use crate::models::variant::ModelVariant;
+use crate::storage::CallRecordStore;
+use crate::utils::clock::{system_clock, SharedClock};
+use crate::utils::insights::UsageWindows;
+use crate::utils::route_metrics::RouteMetrics;
+
+/// `client_id` recorded for anonymous public-mode traffic
+pub const PUBLIC_CLIENT_ID: &str = "public";
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct ApiCallRecord {
     pub timestamp: SystemTime,
+    /// Base model, without `-search` or thinking suffixes
     pub model: String,
+    /// Suffixes of the requested model (see `ModelVariant::flags`)
+    #[serde(default)]
+    pub model_flags: Vec<String>,
+    #[serde(default)]
+    pub prompt_tokens: u32,
+    #[serde(default)]
+    pub completion_tokens: u32,
+    /// Sum of prompt and completion tokens. Records stored before the split only carry
+    /// this total, so it is kept as a field rather than computed.
     pub tokens_used: u32,
+    /// Part of `completion_tokens` spent reasoning by a thinking model
+    #[serde(default)]
+    pub reasoning_tokens: u32,
     pub success: bool,
     pub response_time_ms: u64,
     pub ip_address: Option<String>,
+    #[serde(default)]
+    pub error_type: Option<String>,
+    /// Name of the client key that made the call, if one was used
+    #[serde(default)]
+    pub client_key: Option<String>,
+    /// Masked identity of the credential used (see `auth::client_fingerprint`)
+    #[serde(default)]
+    pub client_id: Option<String>,
+    /// Provider that served the call when the route's own upstream could not
+    #[serde(default)]
+    pub fallback_provider: Option<String>,
+    /// A second upstream call on another key was raced against the first
+    #[serde(default)]
+    pub hedged: bool,
+    /// Made from the dashboard's test request rather than by a client
+    #[serde(default)]
+    pub diagnostic: bool,
+    /// Streamed calls: from sending the upstream request to its first chunk
+    #[serde(default)]
+    pub ttfb_ms: Option<u64>,
+    /// Streamed calls: from sending the upstream request to the end of its stream
+    #[serde(default)]
+    pub stream_duration_ms: Option<u64>,
+    /// Hash of the request's `user` field (see `end_user_id`)
+    #[serde(default)]
+    pub end_user: Option<String>,
+    /// Names of the incoming headers sent on upstream (see `forward_request_headers`)
+    #[serde(default)]
+    pub forwarded_headers: Vec<String>,
+}
+
+/// Upstream timing of a streamed call, carried along with the stream. `chunk` only
+/// compares and stores an `Instant`, so it costs nothing per chunk.
+#[derive(Debug, Clone, Copy)]
+pub struct StreamTimer {
+    sent: Instant,
+    first_chunk: Option<Instant>,
+}
+
+impl StreamTimer {
+    /// Start timing as the upstream request is sent
+    pub fn start() -> Self {
+        Self { sent: Instant::now(), first_chunk: None }
+    }
+
+    pub fn chunk(&mut self) {
+        if self.first_chunk.is_none() {
+            self.first_chunk = Some(Instant::now());
+        }
+    }
+
+    /// The timing of a stream that ends now
+    pub fn finish(&self) -> StreamTiming {
+        StreamTiming {
+            ttfb_ms: self.first_chunk.map(|first| first.duration_since(self.sent).as_millis() as u64),
+            duration_ms: self.sent.elapsed().as_millis() as u64,
+        }
+    }
+}
+
+/// What [`StreamTimer`] measured
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct StreamTiming {
+    /// `None` when the stream ended before its first chunk
+    pub ttfb_ms: Option<u64>,
+    pub duration_ms: u64,
+}
+
+/// Who made a call, used for per-IP limits and per-client attribution
+#[derive(Debug, Clone, Default)]
+pub struct CallOrigin {
+    pub ip_address: Option<String>,
+    pub client_key: Option<String>,
+    pub client_id: Option<String>,
+    /// Set once the call has been handed to the fallback provider, which is never left again
+    pub fallback_provider: Option<String>,
+    /// Set when the call was hedged on a second key (see `hedge_after_ms`)
+    pub hedged: bool,
+    /// Set for the dashboard's test request
+    pub diagnostic: bool,
+    /// Hash of the request's `user` field (see `end_user_id`)
+    pub end_user: Option<String>,
+    /// Names of the incoming headers sent on upstream
+    pub forwarded_headers: Vec<String>,
+}
+
+/// Stable, non-reversible id for the end user a caller named in the OpenAI `user` field. It is
+/// what the stats, the audit log and the upstream request carry, so a report from Google about
+/// one id can be traced to the downstream user by whoever knows the raw value.
+pub fn end_user_id(user: &str) -> String {
+    blake3::hash(user.as_bytes()).to_hex()[..16].to_string()
+}
+
+/// Usage aggregated per client over a time window
+#[derive(Debug, Clone, Serialize)]
+pub struct ClientUsage {
+    /// Client key name, or the masked credential id when no named key was used (the end-user
+    /// id instead when grouping by end user)
+    pub client: String,
+    pub named: bool,
+    pub requests: u64,
+    pub errors: u64,
+    pub prompt_tokens: u64,
+    pub completion_tokens: u64,
+    pub total_tokens: u64,
+    pub last_seen: SystemTime,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -21,10 +151,23 @@ pub struct ApiStats {
     pub successful_requests: u64,
     pub failed_requests: u64,
     pub total_tokens: u64,
+    pub total_prompt_tokens: u64,
+    pub total_completion_tokens: u64,
+    pub total_reasoning_tokens: u64,
     pub requests_last_minute: u32,
     pub requests_last_hour: u32,
     pub requests_last_day: u32,
     pub average_response_time: f64,
+    pub upstream_timeouts: u64,
+    /// Requests and tokens served to anonymous public-mode callers
+    pub public_requests: u64,
+    pub public_tokens: u64,
+    /// Requests served by the fallback provider
+    pub fallback_requests: u64,
+    /// Requests raced on a second key
+    pub hedged_requests: u64,
+    /// Test requests sent from the dashboard
+    pub diagnostic_requests: u64,
 }
 
 impl Default for ApiStats {
@@ -34,21 +177,59 @@ impl Default for ApiStats {
             successful_requests: 0,
             failed_requests: 0,
             total_tokens: 0,
+            total_prompt_tokens: 0,
+            total_completion_tokens: 0,
+            total_reasoning_tokens: 0,
             requests_last_minute: 0,
             requests_last_hour: 0,
             requests_last_day: 0,
             average_response_time: 0.0,
+            upstream_timeouts: 0,
+            public_requests: 0,
+            public_tokens: 0,
+            fallback_requests: 0,
+            hedged_requests: 0,
+            diagnostic_requests: 0,
         }
     }
 }
 
-#[derive(Debug, Clone, Serialize)]
+/// Running totals for one model. Only counters are stored; rates and averages are derived
+/// when read, so they do not drift as calls accumulate.
+#[derive(Debug, Clone, Default, Serialize)]
 pub struct ModelStats {
     pub model_name: String,
     pub request_count: u64,
     pub token_count: u64,
-    pub success_rate: f64,
-    pub average_response_time: f64,
+    pub prompt_token_count: u64,
+    pub completion_token_count: u64,
+    pub reasoning_token_count: u64,
+    pub success_count: u64,
+    pub failure_count: u64,
+    pub total_response_time_ms: u64,
+    /// Percentiles over the streamed calls of the last 7 days, filled in by `get_model_stats`
+    pub ttfb_p50_ms: Option<u64>,
+    pub ttfb_p95_ms: Option<u64>,
+    pub stream_duration_p50_ms: Option<u64>,
+    pub stream_duration_p95_ms: Option<u64>,
+}
+
+impl ModelStats {
+    /// Successful calls as a percentage, 100 before any call
+    pub fn success_rate(&self) -> f64 {
+        if self.request_count == 0 {
+            return 100.0;
+        }
+        self.success_count as f64 * 100.0 / self.request_count as f64
+    }
+
+    /// Mean response time in milliseconds
+    pub fn average_response_time(&self) -> f64 {
+        if self.request_count == 0 {
+            return 0.0;
+        }
+        self.total_response_time_ms as f64 / self.request_count as f64
+    }
 }
 
 #[derive(Debug, Clone)]
@@ -57,6 +238,25 @@ pub struct ApiStatsManager {
     model_stats: Arc<DashMap<String, ModelStats>>,
     cached_stats: Arc<RwLock<ApiStats>>,
     last_cleanup: Arc<RwLock<SystemTime>>,
+    route_metrics: Arc<RouteMetrics>,
+    usage_windows: Arc<UsageWindows>,
+    /// SSE responses currently being sent
+    active_streams: Arc<AtomicUsize>,
+    /// Bumped whenever the aggregated stats change
+    changes: Arc<AtomicU64>,
+    /// Durable copy of the call records when a storage backend is configured
+    store: Option<Arc<dyn CallRecordStore>>,
+    clock: SharedClock,
+}
+
+/// Counts a response in `active_streams` until it is dropped, whether it finished or the
+/// client went away
+pub struct OpenStream(Arc<AtomicUsize>);
+
+impl Drop for OpenStream {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::Relaxed);
+    }
 }
 
 impl ApiStatsManager {
@@ -66,25 +266,119 @@ impl ApiStatsManager {
             model_stats: Arc::new(DashMap::new()),
             cached_stats: Arc::new(RwLock::new(ApiStats::default())),
             last_cleanup: Arc::new(RwLock::new(SystemTime::now())),
+            route_metrics: Arc::new(RouteMetrics::new()),
+            usage_windows: Arc::new(UsageWindows::new()),
+            active_streams: Arc::new(AtomicUsize::new(0)),
+            changes: Arc::new(AtomicU64::new(0)),
+            store: None,
+            clock: system_clock(),
+        }
+    }
+
+    pub fn with_store(mut self, store: Arc<dyn CallRecordStore>) -> Self {
+        self.store = Some(store);
+        self
+    }
+
+    pub fn with_clock(mut self, clock: SharedClock) -> Self {
+        self.last_cleanup = Arc::new(RwLock::new(clock.now()));
+        self.clock = clock;
+        self
+    }
+
+    /// Load the last 7 days of stored calls and rebuild the stats from them
+    pub async fn restore(&self) -> anyhow::Result<usize> {
+        let store = match &self.store {
+            Some(store) => store,
+            None => return Ok(0),
+        };
+        let restored = store.load_calls(self.clock.now() - Duration::from_secs(7 * 24 * 3600))?;
+        for record in &restored {
+            self.update_model_stats(record);
+            let secs = record.timestamp.duration_since(std::time::UNIX_EPOCH).unwrap_or_default().as_secs();
+            self.usage_windows.record_at(&record.model, record.tokens_used as u64, record.success, secs);
         }
+        let count = restored.len();
+        *self.call_records.write().await = restored;
+        self.update_cached_stats().await;
+        Ok(count)
     }
 
     pub async fn record_api_call(
         &self,
         model: String,
-        tokens_used: u32,
+        prompt_tokens: u32,
+        completion_tokens: u32,
         success: bool,
         response_time_ms: u64,
-        ip_address: Option<String>,
+        origin: CallOrigin,
     ) {
-        let record = ApiCallRecord {
-            timestamp: SystemTime::now(),
-            model: model.clone(),
-            tokens_used,
-            success,
-            response_time_ms,
-            ip_address,
-        };
+        self.record(model, Usage::new(prompt_tokens, completion_tokens), success, response_time_ms, origin, None).await;
+    }
+
+    /// Record a successful call with the usage of its response, including reasoning tokens
+    pub async fn record_api_usage(
+        &self,
+        model: String,
+        usage: Option<&Usage>,
+        response_time_ms: u64,
+        origin: CallOrigin,
+    ) {
+        let usage = usage.cloned().unwrap_or_else(|| Usage::new(0, 0));
+        self.record(model, usage, true, response_time_ms, origin, None).await;
+    }
+
+    /// Record a failed call together with its error type (e.g. `upstream_timeout`)
+    pub async fn record_api_error(
+        &self,
+        model: String,
+        error_type: &str,
+        response_time_ms: u64,
+        origin: CallOrigin,
+    ) {
+        self.record(model, Usage::new(0, 0), false, response_time_ms, origin, Some(error_type.to_string())).await;
+    }
+
+    /// Record a streamed call once its stream has ended, failed when `error_type` is set
+    pub async fn record_stream(
+        &self,
+        model: String,
+        usage: Option<&Usage>,
+        error_type: Option<&str>,
+        response_time_ms: u64,
+        timing: StreamTiming,
+        origin: CallOrigin,
+    ) {
+        let usage = usage.cloned().unwrap_or_else(|| Usage::new(0, 0));
+        let error_type = error_type.map(str::to_string);
+        let mut record = call_record(self.clock.now(), model, usage, error_type.is_none(), response_time_ms, origin, error_type);
+        record.ttfb_ms = timing.ttfb_ms;
+        record.stream_duration_ms = Some(timing.duration_ms);
+        self.store_record(record).await;
+    }
+
+    async fn record(
+        &self,
+        model: String,
+        usage: Usage,
+        success: bool,
+        response_time_ms: u64,
+        origin: CallOrigin,
+        error_type: Option<String>,
+    ) {
+        let record = call_record(self.clock.now(), model, usage, success, response_time_ms, origin, error_type);
+        self.store_record(record).await;
+    }
+
+    async fn store_record(&self, record: ApiCallRecord) {
+        if let Some(store) = &self.store {
+            store.append_call(&record);
+        }
+
+        // Update model-specific stats
+        self.update_model_stats(&record);
+        let secs = record.timestamp.duration_since(std::time::UNIX_EPOCH).unwrap_or_default().as_secs();
+        self.usage_windows.record_at(&record.model, record.tokens_used as u64, record.success, secs);
 
         // Add to call records
         {
@@ -92,47 +386,39 @@ impl ApiStatsManager {
             records.push(record);
 
             // Keep only recent records (last 7 days)
-            let cutoff = SystemTime::now() - Duration::from_secs(7 * 24 * 3600);
+            let cutoff = self.clock.now() - Duration::from_secs(7 * 24 * 3600);
             records.retain(|r| r.timestamp > cutoff);
         }
 
-        // Update model-specific stats
-        self.update_model_stats(&model, tokens_used, success, response_time_ms).await;
-
         // Update cached global stats
         self.update_cached_stats().await;
     }
 
-    async fn update_model_stats(&self, model: &str, tokens: u32, success: bool, response_time: u64) {
-        let mut stats = self.model_stats.entry(model.to_string()).or_insert_with(|| ModelStats {
-            model_name: model.to_string(),
-            request_count: 0,
-            token_count: 0,
-            success_rate: 100.0,
-            average_response_time: 0.0,
+    /// Not async on purpose: the map entry is locked for the duration of the call, which
+    /// must never span an await
+    fn update_model_stats(&self, record: &ApiCallRecord) {
+        let mut stats = self.model_stats.entry(record.model.clone()).or_insert_with(|| ModelStats {
+            model_name: record.model.clone(),
+            ..Default::default()
         });
 
-        let old_count = stats.request_count;
-        let old_avg_time = stats.average_response_time;
-
         stats.request_count += 1;
-        stats.token_count += tokens as u64;
-
-        // Update success rate
-        let successful_requests = if success {
-            (stats.success_rate * old_count as f64 / 100.0) + 1.0
+        stats.prompt_token_count += record.prompt_tokens as u64;
+        stats.completion_token_count += record.completion_tokens as u64;
+        stats.reasoning_token_count += record.reasoning_tokens as u64;
+        stats.token_count += record.tokens_used as u64;
+        if record.success {
+            stats.success_count += 1;
         } else {
-            stats.success_rate * old_count as f64 / 100.0
-        };
-        stats.success_rate = (successful_requests / stats.request_count as f64) * 100.0;
-
-        // Update average response time
-        stats.average_response_time = (old_avg_time * old_count as f64 + response_time as f64) / stats.request_count as f64;
+            stats.failure_count += 1;
+        }
+        stats.total_response_time_ms += record.response_time_ms;
     }
 
     async fn update_cached_stats(&self) {
+        self.changes.fetch_add(1, Ordering::Relaxed);
         let records = self.call_records.read().await;
-        let now = SystemTime::now();
+        let now = self.clock.now();
 
         let minute_ago = now - Duration::from_secs(60);
         let hour_ago = now - Duration::from_secs(3600);
@@ -151,10 +437,30 @@ impl ApiStatsManager {
                 stats.successful_requests += 1;
             } else {
                 stats.failed_requests += 1;
+                if record.error_type.as_deref() == Some("upstream_timeout") {
+                    stats.upstream_timeouts += 1;
+                }
+            }
+
+            if record.client_id.as_deref() == Some(PUBLIC_CLIENT_ID) {
+                stats.public_requests += 1;
+                stats.public_tokens += record.tokens_used as u64;
+            }
+            if record.fallback_provider.is_some() {
+                stats.fallback_requests += 1;
+            }
+            if record.hedged {
+                stats.hedged_requests += 1;
+            }
+            if record.diagnostic {
+                stats.diagnostic_requests += 1;
             }
 
             // Count tokens
             stats.total_tokens += record.tokens_used as u64;
+            stats.total_prompt_tokens += record.prompt_tokens as u64;
+            stats.total_completion_tokens += record.completion_tokens as u64;
+            stats.total_reasoning_tokens += record.reasoning_tokens as u64;
 
             // Calculate average response time
             total_response_time += record.response_time_ms;
@@ -180,16 +486,69 @@ impl ApiStatsManager {
         *cached_stats = stats;
     }
 
+    /// Latency of rujimi's own routes, fed by `route_metrics::track_route_metrics`
+    pub fn route_metrics(&self) -> Arc<RouteMetrics> {
+        self.route_metrics.clone()
+    }
+
+    /// Recent per-model usage and failures, read by the dashboard insights
+    pub fn usage_windows(&self) -> Arc<UsageWindows> {
+        self.usage_windows.clone()
+    }
+
+    /// Count an SSE response as active for as long as the returned guard lives
+    pub fn stream_opened(&self) -> OpenStream {
+        self.active_streams.fetch_add(1, Ordering::Relaxed);
+        OpenStream(self.active_streams.clone())
+    }
+
+    pub fn active_streams(&self) -> usize {
+        self.active_streams.load(Ordering::Relaxed)
+    }
+
+    /// Calls and tokens recorded within the last `window`
+    /// Changes whenever the stats do; cheaper to compare than the stats themselves
+    pub fn changes(&self) -> u64 {
+        self.changes.load(Ordering::Relaxed)
+    }
+
+    pub async fn recent_throughput(&self, window: Duration) -> (u64, u64) {
+        let since = self.clock.now() - window;
+        let records = self.call_records.read().await;
+        records
+            .iter()
+            .rev()
+            .take_while(|record| record.timestamp > since)
+            .fold((0, 0), |(calls, tokens), record| (calls + 1, tokens + record.tokens_used as u64))
+    }
+
     pub async fn get_stats(&self) -> ApiStats {
         let cached_stats = self.cached_stats.read().await;
         cached_stats.clone()
     }
 
     pub async fn get_model_stats(&self) -> Vec<ModelStats> {
-        self.model_stats
-            .iter()
-            .map(|entry| entry.value().clone())
-            .collect()
+        let mut stats: Vec<ModelStats> = self.model_stats.iter().map(|entry| entry.value().clone()).collect();
+
+        let mut samples: std::collections::HashMap<&str, (Vec<u64>, Vec<u64>)> = std::collections::HashMap::new();
+        let records = self.call_records.read().await;
+        for record in records.iter() {
+            if record.ttfb_ms.is_none() && record.stream_duration_ms.is_none() {
+                continue;
+            }
+            let (ttfb, duration) = samples.entry(record.model.as_str()).or_default();
+            ttfb.extend(record.ttfb_ms);
+            duration.extend(record.stream_duration_ms);
+        }
+        for model_stats in &mut stats {
+            if let Some((ttfb, duration)) = samples.get_mut(model_stats.model_name.as_str()) {
+                model_stats.ttfb_p50_ms = percentile(ttfb, 50);
+                model_stats.ttfb_p95_ms = percentile(ttfb, 95);
+                model_stats.stream_duration_p50_ms = percentile(duration, 50);
+                model_stats.stream_duration_p95_ms = percentile(duration, 95);
+            }
+        }
+        stats
     }
 
     pub async fn get_recent_calls(&self, limit: usize) -> Vec<ApiCallRecord> {
@@ -202,6 +561,13 @@ impl ApiStatsManager {
             .collect()
     }
 
+    /// Drop the running totals of `model`, returning them as they were; its call records stay
+    pub fn reset_model_stats(&self, model: &str) -> Option<ModelStats> {
+        let (_, stats) = self.model_stats.remove(model)?;
+        self.changes.fetch_add(1, Ordering::Relaxed);
+        Some(stats)
+    }
+
     pub async fn clear_stats(&self) {
         {
             let mut records = self.call_records.write().await;
@@ -209,18 +575,24 @@ impl ApiStatsManager {
         }
 
         self.model_stats.clear();
+        self.route_metrics.clear();
+        self.usage_windows.clear();
+        if let Some(store) = &self.store {
+            store.clear_calls();
+        }
 
         {
             let mut cached_stats = self.cached_stats.write().await;
             *cached_stats = ApiStats::default();
         }
+        self.changes.fetch_add(1, Ordering::Relaxed);
 
         info!("API statistics cleared");
     }
 
     pub async fn get_requests_per_ip_last_day(&self) -> std::collections::HashMap<String, u32> {
         let records = self.call_records.read().await;
-        let day_ago = SystemTime::now() - Duration::from_secs(86400);
+        let day_ago = self.clock.now() - Duration::from_secs(86400);
 
         let mut ip_counts = std::collections::HashMap::new();
 
@@ -235,6 +607,73 @@ impl ApiStatsManager {
         ip_counts
     }
 
+    /// Requests and tokens used by a client key over the last 24 hours
+    pub async fn get_client_usage_last_day(&self, client_key: &str) -> (u32, u64) {
+        let records = self.call_records.read().await;
+        let day_ago = self.clock.now() - Duration::from_secs(86400);
+
+        records
+            .iter()
+            .filter(|r| r.timestamp > day_ago && r.client_key.as_deref() == Some(client_key))
+            .fold((0, 0), |(requests, tokens), r| (requests + 1, tokens + r.tokens_used as u64))
+    }
+
+    /// Per-client usage over the last `window`, heaviest token users first. With `by_end_user`,
+    /// calls made without a client key are grouped by their end-user id where they have one.
+    pub async fn get_client_usage(&self, window: Duration, by_end_user: bool) -> Vec<ClientUsage> {
+        let records = self.call_records.read().await;
+        let cutoff = self.clock.now() - window;
+        let mut usage: std::collections::HashMap<(String, bool), ClientUsage> = std::collections::HashMap::new();
+
+        for record in records.iter().filter(|r| r.timestamp > cutoff) {
+            let end_user = record.end_user.as_ref().filter(|_| by_end_user);
+            let (client, named) = match (&record.client_key, end_user, &record.client_id) {
+                (Some(name), _, _) => (name.clone(), true),
+                (None, Some(end_user), _) => (end_user.clone(), false),
+                (None, None, Some(id)) => (id.clone(), false),
+                (None, None, None) => continue,
+            };
+
+            let entry = usage.entry((client.clone(), named)).or_insert_with(|| ClientUsage {
+                client,
+                named,
+                requests: 0,
+                errors: 0,
+                prompt_tokens: 0,
+                completion_tokens: 0,
+                total_tokens: 0,
+                last_seen: record.timestamp,
+            });
+            entry.requests += 1;
+            if !record.success {
+                entry.errors += 1;
+            }
+            entry.prompt_tokens += record.prompt_tokens as u64;
+            entry.completion_tokens += record.completion_tokens as u64;
+            entry.total_tokens += record.tokens_used as u64;
+            entry.last_seen = entry.last_seen.max(record.timestamp);
+        }
+
+        let mut usage: Vec<ClientUsage> = usage.into_values().collect();
+        usage.sort_by(|a, b| b.total_tokens.cmp(&a.total_tokens).then_with(|| a.client.cmp(&b.client)));
+        usage
+    }
+
+    /// Anonymous public-mode requests from `ip` over the last `window`
+    pub async fn get_public_requests_for_ip(&self, ip: &str, window: Duration) -> u32 {
+        let records = self.call_records.read().await;
+        let cutoff = self.clock.now() - window;
+
+        records
+            .iter()
+            .filter(|r| {
+                r.timestamp > cutoff
+                    && r.client_id.as_deref() == Some(PUBLIC_CLIENT_ID)
+                    && r.ip_address.as_deref() == Some(ip)
+            })
+            .count() as u32
+    }
+
     pub async fn get_requests_for_ip_last_day(&self, ip: &str) -> u32 {
         let ip_counts = self.get_requests_per_ip_last_day().await;
         ip_counts.get(ip).copied().unwrap_or(0)
@@ -246,7 +685,7 @@ impl ApiStatsManager {
         loop {
             interval.tick().await;
 
-            let now = SystemTime::now();
+            let now = self.clock.now();
             let mut last_cleanup = self.last_cleanup.write().await;
 
             // Only clean up if it's been at least an hour since last cleanup
@@ -258,50 +697,32 @@ impl ApiStatsManager {
     }
 
     async fn cleanup_old_records(&self) {
-        let cutoff = SystemTime::now() - Duration::from_secs(7 * 24 * 3600); // Keep 7 days
+        let removed = self.cleanup_expired_records(Duration::from_secs(7 * 24 * 3600)).await; // Keep 7 days
+        if removed > 0 {
+            info!("Cleaned up {} old API call records", removed);
+        }
+    }
+
+    /// Drop call records older than `max_age` and return how many were dropped
+    pub async fn cleanup_expired_records(&self, max_age: Duration) -> usize {
+        let cutoff = self.clock.now().checked_sub(max_age).unwrap_or(SystemTime::UNIX_EPOCH);
 
         let mut records = self.call_records.write().await;
         let old_count = records.len();
         records.retain(|r| r.timestamp > cutoff);
-        let new_count = records.len();
+        let cleaned = old_count - records.len();
 
-        if old_count != new_count {
-            info!("Cleaned up {} old API call records", old_count - new_count);
+        if cleaned > 0 {
             drop(records); // Release the lock before updating cached stats
             self.update_cached_stats().await;
         }
-    }
-
-    /// Public method to cleanup expired records - called by maintenance scheduler
-    pub fn cleanup_expired_records(&self, max_age: Duration) -> usize {
-        let cutoff = SystemTime::now() - max_age;
-
-        // Use blocking to avoid async in sync context
-        let rt = tokio::runtime::Handle::try_current();
-        if let Ok(handle) = rt {
-            handle.block_on(async {
-                let mut records = self.call_records.write().await;
-                let old_count = records.len();
-                records.retain(|r| r.timestamp > cutoff);
-                let new_count = records.len();
-                let cleaned = old_count - new_count;
-
-                if cleaned > 0 {
-                    drop(records); // Release the lock before updating cached stats
-                    self.update_cached_stats().await;
-                }
-                cleaned
-            })
-        } else {
-            // Fallback if no runtime available
-            0
-        }
+        cleaned
     }
 
     // Get time series data for charts (last 24 hours, hourly buckets)
     pub async fn get_hourly_stats(&self) -> Vec<(SystemTime, u32, u64)> {
         let records = self.call_records.read().await;
-        let now = SystemTime::now();
+        let now = self.clock.now();
         let mut hourly_data = Vec::new();
 
         for hour in (0..24).rev() {
@@ -325,9 +746,54 @@ impl ApiStatsManager {
     }
 }
 
+fn call_record(
+    timestamp: SystemTime,
+    model: String,
+    usage: Usage,
+    success: bool,
+    response_time_ms: u64,
+    origin: CallOrigin,
+    error_type: Option<String>,
+) -> ApiCallRecord {
+    let variant = ModelVariant::parse(&model);
+    ApiCallRecord {
+        timestamp,
+        model_flags: variant.flags().into_iter().map(str::to_string).collect(),
+        model: variant.base,
+        prompt_tokens: usage.prompt_tokens,
+        completion_tokens: usage.completion_tokens,
+        tokens_used: usage.prompt_tokens + usage.completion_tokens,
+        reasoning_tokens: usage.reasoning_tokens(),
+        success,
+        response_time_ms,
+        ip_address: origin.ip_address,
+        error_type,
+        client_key: origin.client_key,
+        client_id: origin.client_id,
+        fallback_provider: origin.fallback_provider,
+        hedged: origin.hedged,
+        diagnostic: origin.diagnostic,
+        ttfb_ms: None,
+        stream_duration_ms: None,
+        end_user: origin.end_user,
+        forwarded_headers: origin.forwarded_headers,
+    }
+}
+
+/// Nearest-rank percentile; sorts `samples` in place
+pub fn percentile(samples: &mut [u64], percent: usize) -> Option<u64> {
+    if samples.is_empty() {
+        return None;
+    }
+    samples.sort_unstable();
+    let rank = (samples.len() * percent).div_ceil(100).max(1);
+    Some(samples[rank - 1])
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::utils::clock::MockClock;
 
     #[tokio::test]
     async fn test_api_stats_manager() {
@@ -336,18 +802,38 @@ mod tests {
         // Record some API calls
         manager.record_api_call(
             "gpt-4".to_string(),
-            100,
+            60,
+            40,
             true,
             500,
-            Some("127.0.0.1".to_string()),
+            CallOrigin {
+                ip_address: Some("127.0.0.1".to_string()),
+                client_key: Some("team-a".to_string()),
+                client_id: Some("key-aaaaaaaaaaaa".to_string()),
+                fallback_provider: None,
+                hedged: true,
+                diagnostic: false,
+                end_user: None,
+                forwarded_headers: Vec::new(),
+            },
         ).await;
 
         manager.record_api_call(
             "gpt-3.5".to_string(),
             50,
+            0,
             false,
             1000,
-            Some("127.0.0.1".to_string()),
+            CallOrigin {
+                ip_address: Some("127.0.0.1".to_string()),
+                client_key: None,
+                client_id: Some("key-bbbbbbbbbbbb".to_string()),
+                fallback_provider: Some("vertex".to_string()),
+                hedged: false,
+                diagnostic: true,
+                end_user: Some(end_user_id("alice")),
+                forwarded_headers: Vec::new(),
+            },
         ).await;
 
         let stats = manager.get_stats().await;
@@ -355,8 +841,185 @@ mod tests {
         assert_eq!(stats.successful_requests, 1);
         assert_eq!(stats.failed_requests, 1);
         assert_eq!(stats.total_tokens, 150);
+        assert_eq!(stats.total_prompt_tokens, 110);
+        assert_eq!(stats.total_completion_tokens, 40);
+        assert_eq!(stats.fallback_requests, 1);
+        assert_eq!(stats.hedged_requests, 1);
+        assert_eq!(stats.diagnostic_requests, 1);
 
         let model_stats = manager.get_model_stats().await;
         assert_eq!(model_stats.len(), 2);
+        let gpt4 = model_stats.iter().find(|s| s.model_name == "gpt-4").unwrap();
+        assert_eq!((gpt4.prompt_token_count, gpt4.completion_token_count, gpt4.token_count), (60, 40, 100));
+
+        assert_eq!(manager.get_client_usage_last_day("team-a").await, (1, 100));
+        assert_eq!(manager.get_client_usage_last_day("team-b").await, (0, 0));
+
+        let clients = manager.get_client_usage(Duration::from_secs(86400), false).await;
+        assert_eq!(clients.len(), 2);
+        assert_eq!((clients[0].client.as_str(), clients[0].named, clients[0].total_tokens), ("team-a", true, 100));
+        assert_eq!((clients[1].client.as_str(), clients[1].errors, clients[1].prompt_tokens), ("key-bbbbbbbbbbbb", 1, 50));
+
+        // Named keys keep their name; the keyless call is attributed to its end user
+        let clients = manager.get_client_usage(Duration::from_secs(86400), true).await;
+        assert_eq!(clients[0].client, "team-a");
+        assert_eq!(clients[1].client, end_user_id("alice"));
+        assert_eq!(end_user_id("alice").len(), 16);
+    }
+
+    #[test]
+    fn test_legacy_record_keeps_total() {
+        // Records serialized before the prompt/completion split only have `tokens_used`
+        let legacy = r#"{
+            "timestamp": {"secs_since_epoch": 1700000000, "nanos_since_epoch": 0},
+            "model": "gemini-1.5-pro",
+            "tokens_used": 42,
+            "success": true,
+            "response_time_ms": 120,
+            "ip_address": null
+        }"#;
+        let record: ApiCallRecord = serde_json::from_str(legacy).unwrap();
+        assert_eq!(record.tokens_used, 42);
+        assert_eq!(record.prompt_tokens, 0);
+        assert_eq!(record.completion_tokens, 0);
+    }
+
+    #[tokio::test]
+    async fn test_upstream_timeouts_counted_separately() {
+        let manager = ApiStatsManager::new();
+
+        manager.record_api_error("gemini-1.5-pro".to_string(), "upstream_timeout", 600_000, CallOrigin::default()).await;
+        manager.record_api_error("gemini-1.5-pro".to_string(), "api_error", 100, CallOrigin::default()).await;
+
+        let stats = manager.get_stats().await;
+        assert_eq!(stats.failed_requests, 2);
+        assert_eq!(stats.upstream_timeouts, 1);
+    }
+
+    #[tokio::test]
+    async fn test_suffixed_models_recorded_under_their_base() {
+        let manager = ApiStatsManager::new();
+        manager.record_api_call("gemini-2.5-flash-search-thinking".to_string(), 10, 5, true, 100, CallOrigin::default()).await;
+        manager.record_api_call("gemini-2.5-flash".to_string(), 10, 5, true, 100, CallOrigin::default()).await;
+
+        let model_stats = manager.get_model_stats().await;
+        assert_eq!(model_stats.len(), 1);
+        assert_eq!((model_stats[0].model_name.as_str(), model_stats[0].request_count), ("gemini-2.5-flash", 2));
+
+        let records = manager.call_records.read().await;
+        assert_eq!(records[0].model_flags, ["thinking", "search"]);
+        assert!(records[1].model_flags.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_stream_timing_percentiles() {
+        let manager = ApiStatsManager::new();
+        for ms in 1..=20 {
+            let timing = StreamTiming { ttfb_ms: Some(ms * 10), duration_ms: ms * 100 };
+            manager.record_stream("gemini-2.0-flash".to_string(), None, None, ms * 100, timing, CallOrigin::default()).await;
+        }
+        let failed = StreamTiming { ttfb_ms: None, duration_ms: 5 };
+        manager.record_stream("gemini-2.0-flash".to_string(), None, Some("api_error"), 5, failed, CallOrigin::default()).await;
+        manager.record_api_call("gemini-1.5-pro".to_string(), 1, 1, true, 50, CallOrigin::default()).await;
+
+        let model_stats = manager.get_model_stats().await;
+        let flash = model_stats.iter().find(|s| s.model_name == "gemini-2.0-flash").unwrap();
+        assert_eq!((flash.request_count, flash.failure_count), (21, 1));
+        assert_eq!((flash.ttfb_p50_ms, flash.ttfb_p95_ms), (Some(100), Some(190)));
+        assert_eq!((flash.stream_duration_p50_ms, flash.stream_duration_p95_ms), (Some(1000), Some(1900)));
+        let pro = model_stats.iter().find(|s| s.model_name == "gemini-1.5-pro").unwrap();
+        assert_eq!((pro.ttfb_p50_ms, pro.stream_duration_p95_ms), (None, None));
+
+        let recent = manager.get_recent_calls(1).await;
+        assert_eq!((recent[0].ttfb_ms, recent[0].stream_duration_ms), (None, None));
+        let failed = &manager.get_recent_calls(2).await[1];
+        assert_eq!((failed.ttfb_ms, failed.stream_duration_ms, failed.success), (None, Some(5), false));
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+    async fn test_concurrent_model_stats() {
+        let manager = ApiStatsManager::new();
+        let done = Arc::new(std::sync::atomic::AtomicBool::new(false));
+
+        // Read the per-model stats for as long as the writers run
+        let reader = tokio::spawn({
+            let manager = manager.clone();
+            let done = done.clone();
+            async move {
+                while !done.load(std::sync::atomic::Ordering::SeqCst) {
+                    for stats in manager.get_model_stats().await {
+                        assert_eq!(stats.success_count + stats.failure_count, stats.request_count);
+                    }
+                    tokio::task::yield_now().await;
+                }
+            }
+        });
+
+        let writers = (0..16).map(|task| {
+            let manager = manager.clone();
+            tokio::spawn(async move {
+                for call in 0..50 {
+                    let model = format!("model-{}", call % 2);
+                    manager.record_api_call(model, 10, 5, task % 4 != 0, 100, CallOrigin::default()).await;
+                }
+            })
+        });
+        let finished = tokio::time::timeout(Duration::from_secs(30), futures::future::join_all(writers)).await;
+        done.store(true, std::sync::atomic::Ordering::SeqCst);
+        assert!(finished.expect("record_api_call deadlocked").iter().all(|writer| writer.is_ok()));
+        reader.await.unwrap();
+
+        let model_stats = manager.get_model_stats().await;
+        assert_eq!(model_stats.len(), 2);
+        for stats in &model_stats {
+            assert_eq!(stats.request_count, 400);
+            assert_eq!((stats.success_count, stats.failure_count), (300, 100));
+            assert_eq!(stats.token_count, 6000);
+            assert_eq!(stats.success_rate(), 75.0);
+            assert_eq!(stats.average_response_time(), 100.0);
+        }
+        assert_eq!(manager.get_stats().await.total_requests, 800);
     }
-}
\ No newline at end of file
+
+    #[tokio::test]
+    async fn test_live_counters() {
+        let clock = MockClock::new();
+        let manager = ApiStatsManager::new().with_clock(clock.clone());
+        manager.record_api_call("gemini-pro".to_string(), 10, 5, true, 100, CallOrigin::default()).await;
+        clock.advance(Duration::from_secs(120));
+        manager.record_api_call("gemini-pro".to_string(), 10, 5, true, 100, CallOrigin::default()).await;
+        manager.record_api_call("gemini-pro".to_string(), 20, 0, false, 100, CallOrigin::default()).await;
+        assert_eq!(manager.recent_throughput(Duration::from_secs(60)).await, (2, 35));
+
+        let first = manager.stream_opened();
+        let second = manager.stream_opened();
+        assert_eq!(manager.active_streams(), 2);
+        drop(first);
+        assert_eq!(manager.active_streams(), 1);
+        drop(second);
+        assert_eq!(manager.active_streams(), 0);
+    }
+
+    #[tokio::test]
+    async fn test_time_windows_follow_the_clock() {
+        let clock = MockClock::new();
+        let manager = ApiStatsManager::new().with_clock(clock.clone());
+        manager.record_api_call("gemini-pro".to_string(), 10, 5, true, 100, CallOrigin::default()).await;
+        clock.advance(Duration::from_secs(90 * 60));
+        manager.record_api_call("gemini-pro".to_string(), 1, 1, true, 100, CallOrigin::default()).await;
+
+        let stats = manager.get_stats().await;
+        assert_eq!((stats.requests_last_minute, stats.requests_last_hour, stats.requests_last_day), (1, 1, 2));
+
+        // Buckets start a whole number of hours before now, newest last
+        let hourly = manager.get_hourly_stats().await;
+        assert_eq!(hourly.len(), 24);
+        assert_eq!((hourly[23].1, hourly[23].2), (1, 2));
+        assert_eq!(hourly[22].1, 0);
+        assert_eq!((hourly[21].1, hourly[21].2), (1, 15));
+
+        clock.advance(Duration::from_secs(23 * 3600));
+        assert_eq!(manager.cleanup_expired_records(Duration::from_secs(24 * 3600)).await, 1);
+        assert_eq!(manager.get_stats().await.requests_last_day, 1);
+    }
+}
@@ -0,0 +1,112 @@
+use anyhow::{Context, Result};
+use axum_server::tls_rustls::RustlsConfig;
+use std::path::Path;
+use std::time::Duration;
+use tracing::{error, info};
+
+use crate::config::Settings;
+
+/// TLS is enabled only when both the certificate and the key path are configured
+pub fn tls_enabled(settings: &Settings) -> bool {
+    !settings.tls_cert_path.trim().is_empty() && !settings.tls_key_path.trim().is_empty()
+}
+
+/// Load the PEM certificate chain and private key, failing with an actionable message
+pub async fn load_rustls_config(cert_path: &str, key_path: &str) -> Result<RustlsConfig> {
+    check_readable(cert_path, "TLS_CERT_PATH")?;
+    check_readable(key_path, "TLS_KEY_PATH")?;
+
+    RustlsConfig::from_pem_file(cert_path, key_path)
+        .await
+        .with_context(|| format!(
+            "Failed to load TLS certificate '{}' with key '{}' - make sure both are PEM encoded and the key belongs to the certificate",
+            cert_path, key_path
+        ))
+}
+
+fn check_readable(path: &str, setting: &str) -> Result<()> {
+    std::fs::File::open(Path::new(path))
+        .map(|_| ())
+        .with_context(|| format!("{} points to '{}' which cannot be read", setting, path))
+}
+
+/// Reload the certificate on SIGHUP and, if configured, every `interval_secs` seconds,
+/// so certificate renewals are picked up without a restart
+pub fn spawn_tls_reloader(config: RustlsConfig, cert_path: String, key_path: String, interval_secs: u64) {
+    if interval_secs > 0 {
+        let config = config.clone();
+        let cert_path = cert_path.clone();
+        let key_path = key_path.clone();
+        tokio::spawn(async move {
+            let mut interval = tokio::time::interval(Duration::from_secs(interval_secs));
+            interval.tick().await;
+            loop {
+                interval.tick().await;
+                reload(&config, &cert_path, &key_path).await;
+            }
+        });
+    }
+
+    #[cfg(unix)]
+    tokio::spawn(async move {
+        use tokio::signal::unix::{signal, SignalKind};
+
+        let mut hangup = match signal(SignalKind::hangup()) {
+            Ok(hangup) => hangup,
+            Err(e) => {
+                error!("Failed to listen for SIGHUP, TLS reload on signal disabled: {}", e);
+                return;
+            }
+        };
+
+        while hangup.recv().await.is_some() {
+            info!("SIGHUP received, reloading TLS certificate");
+            reload(&config, &cert_path, &key_path).await;
+        }
+    });
+}
+
+async fn reload(config: &RustlsConfig, cert_path: &str, key_path: &str) {
+    // Keep serving the previous certificate if the new files are broken
+    match config.reload_from_pem_file(cert_path, key_path).await {
+        Ok(()) => info!("🔐 TLS certificate reloaded from {}", cert_path),
+        Err(e) => error!("Failed to reload TLS certificate, keeping the current one: {}", e),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_tls_enabled_requires_both_paths() {
+        let mut settings = Settings::default();
+        assert!(!tls_enabled(&settings));
+
+        settings.tls_cert_path = "/etc/rujimi/cert.pem".to_string();
+        assert!(!tls_enabled(&settings));
+
+        settings.tls_key_path = "/etc/rujimi/key.pem".to_string();
+        assert!(tls_enabled(&settings));
+    }
+
+    #[tokio::test]
+    async fn test_unreadable_files_fail_with_setting_name() {
+        let error = load_rustls_config("/nonexistent/cert.pem", "/nonexistent/key.pem")
+            .await
+            .unwrap_err();
+        assert!(error.to_string().contains("TLS_CERT_PATH"));
+    }
+
+    #[tokio::test]
+    async fn test_invalid_pem_is_rejected() {
+        let dir = tempfile::tempdir().unwrap();
+        let cert = dir.path().join("cert.pem");
+        let key = dir.path().join("key.pem");
+        std::fs::write(&cert, "not a certificate").unwrap();
+        std::fs::write(&key, "not a key").unwrap();
+
+        let result = load_rustls_config(cert.to_str().unwrap(), key.to_str().unwrap()).await;
+        assert!(result.is_err());
+    }
+}
@@ -0,0 +1,170 @@
+//! Token counts for text Gemini has not counted for us. `estimate_tokens` is a local
+//! heuristic shaped after Gemini's tokenizer; `TokenCountCache` remembers exact counts from
+//! `countTokens` when `accurate_token_estimates` is on.
+
+use anyhow::Result;
+use moka::future::Cache;
+use std::future::Future;
+
+/// Gemini needs one token for every one or two CJK characters
+const CJK_TOKENS_PER_CHAR: f64 = 0.67;
+
+/// Letters of a word covered by one token; most English words are a single token
+const LETTERS_PER_TOKEN: usize = 8;
+
+/// Exact counts kept before the least recent are forgotten
+const MAX_CACHED_COUNTS: u64 = 10_000;
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum CharClass {
+    Letter,
+    Digit,
+    Cjk,
+    Space,
+    Newline,
+    Symbol,
+}
+
+fn classify(c: char) -> CharClass {
+    match c {
+        '\n' | '\r' => CharClass::Newline,
+        c if c.is_whitespace() => CharClass::Space,
+        c if c.is_ascii_digit() => CharClass::Digit,
+        c if is_cjk(c) => CharClass::Cjk,
+        c if c.is_alphabetic() || c == '_' => CharClass::Letter,
+        _ => CharClass::Symbol,
+    }
+}
+
+/// Han, kana, Hangul and the CJK punctuation and full-width forms
+fn is_cjk(c: char) -> bool {
+    matches!(c as u32,
+        0x3000..=0x30FF
+        | 0x3400..=0x4DBF
+        | 0x4E00..=0x9FFF
+        | 0xAC00..=0xD7AF
+        | 0xF900..=0xFAFF
+        | 0xFF00..=0xFFEF
+        | 0x20000..=0x2FA1F)
+}
+
+/// Estimate how many tokens Gemini would count for `text`. Words cost one token per
+/// `LETTERS_PER_TOKEN` letters, digits and CJK characters are counted one by one, runs of
+/// symbols cost a token per pair, and whitespace only counts when it breaks a line or indents.
+pub fn estimate_tokens(text: &str) -> u32 {
+    let mut tokens = 0.0;
+    let mut chars = text.chars().peekable();
+
+    while let Some(c) = chars.next() {
+        let class = classify(c);
+        // Length of the run of characters of this class starting at `c`
+        let mut run = 1;
+        let mut run_has_newline = class == CharClass::Newline;
+        let mut wide_letters = usize::from(class == CharClass::Letter && !c.is_ascii());
+        while let Some(&next) = chars.peek() {
+            let next_class = classify(next);
+            let same_run = match class {
+                CharClass::Space | CharClass::Newline => matches!(next_class, CharClass::Space | CharClass::Newline),
+                _ => next_class == class,
+            };
+            if !same_run {
+                break;
+            }
+            run_has_newline |= next_class == CharClass::Newline;
+            wide_letters += usize::from(class == CharClass::Letter && !next.is_ascii());
+            run += 1;
+            chars.next();
+        }
+
+        tokens += match class {
+            // Letters outside ASCII tokenize about twice as finely
+            CharClass::Letter => (run + wide_letters).div_ceil(LETTERS_PER_TOKEN) as f64,
+            CharClass::Digit => run as f64,
+            CharClass::Cjk => run as f64 * CJK_TOKENS_PER_CHAR,
+            CharClass::Space | CharClass::Newline if run_has_newline || run > 1 => 1.0,
+            CharClass::Space | CharClass::Newline => 0.0,
+            CharClass::Symbol => run.div_ceil(2) as f64,
+        };
+    }
+
+    tokens.ceil() as u32
+}
+
+/// Exact token counts from Gemini, keyed by model and counted input
+#[derive(Debug, Clone)]
+pub struct TokenCountCache {
+    counts: Cache<String, u32>,
+}
+
+impl Default for TokenCountCache {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl TokenCountCache {
+    pub fn new() -> Self {
+        Self { counts: Cache::new(MAX_CACHED_COUNTS) }
+    }
+
+    /// The cached count of `input` for `model`, or the result of `count` on a miss.
+    /// Failed counts are not cached.
+    pub async fn get_or_count(&self, model: &str, input: &str, count: impl Future<Output = Result<u32>>) -> Result<u32> {
+        let key = blake3::hash(format!("{}\0{}", model, input).as_bytes()).to_hex().to_string();
+        if let Some(tokens) = self.counts.get(&key).await {
+            return Ok(tokens);
+        }
+        let tokens = count.await?;
+        self.counts.insert(key, tokens).await;
+        Ok(tokens)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Within a quarter of what Gemini's countTokens reports for the same text
+    fn assert_close(text: &str, gemini_count: u32) {
+        let estimate = estimate_tokens(text);
+        let tolerance = (gemini_count as f64 * 0.25).ceil() as u32;
+        assert!(
+            estimate.abs_diff(gemini_count) <= tolerance,
+            "estimated {} tokens for {:?}, Gemini counts {}",
+            estimate,
+            text,
+            gemini_count
+        );
+    }
+
+    #[test]
+    fn test_estimate_tokens() {
+        assert_eq!(estimate_tokens(""), 0);
+        assert_eq!(estimate_tokens("hello"), 1);
+        assert_eq!(estimate_tokens("The quick brown fox jumps over the lazy dog."), 10);
+
+        assert_close(
+            "Large language models are trained on vast amounts of text data, which allows them to generate coherent responses.",
+            21,
+        );
+        assert_close("今天天气很好，我们去公园散步吧。", 10);
+        assert_close("机器学习是人工智能的一个分支，它使计算机能够从数据中学习。", 19);
+        assert_close("fn main() {\n    println!(\"Hello, world!\");\n}", 16);
+        assert_close("for (let i = 0; i < items.length; i++) {\n    total += items[i].price * 1.08;\n}", 33);
+    }
+
+    #[tokio::test]
+    async fn test_token_count_cache() {
+        let cache = TokenCountCache::new();
+        let counted = cache.get_or_count("gemini-2.0-flash", "hello", async { Ok(2) }).await.unwrap();
+        assert_eq!(counted, 2);
+        // A hit never runs the counter
+        let cached = cache.get_or_count("gemini-2.0-flash", "hello", async { unreachable!() }).await.unwrap();
+        assert_eq!(cached, 2);
+
+        let failed = cache.get_or_count("gemini-2.0-flash", "bye", async { Err(anyhow::anyhow!("quota")) }).await;
+        assert!(failed.is_err());
+        let retried = cache.get_or_count("gemini-2.0-flash", "bye", async { Ok(1) }).await.unwrap();
+        assert_eq!(retried, 1);
+    }
+}
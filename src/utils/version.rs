@@ -1,9 +1,21 @@
 use anyhow::Result;
+use rand::Rng;
 use serde::{Deserialize, Serialize};
-use tracing::{info, warn};
+use std::sync::RwLock;
+use std::time::Duration;
+use tracing::{debug, info};
 
 const CURRENT_VERSION: &str = "1.0.2";
-const VERSION_CHECK_URL: &str = "https://api.github.com/repos/wyeeeee/hajimi/releases/latest";
+const VERSION_CHECK_URL: &str = "https://api.github.com/repos/HappyFox001/rujimi/releases/latest";
+
+/// How often the background task checks for a new release
+pub const UPDATE_CHECK_INTERVAL: Duration = Duration::from_secs(24 * 3600);
+
+/// Up to this much random delay before each check, so instances do not all call GitHub at once
+const UPDATE_CHECK_JITTER: Duration = Duration::from_secs(3600);
+
+/// The result of the last successful update check
+static LATEST_RELEASE: RwLock<Option<VersionInfo>> = RwLock::new(None);
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct VersionInfo {
@@ -12,17 +24,14 @@ pub struct VersionInfo {
     pub has_update_available: bool,
     pub release_notes: Option<String>,
     pub release_date: Option<String>,
+    /// Web page of the latest release
+    #[serde(default)]
+    pub release_url: Option<String>,
 }
 
 impl Default for VersionInfo {
     fn default() -> Self {
-        Self {
-            current_version: CURRENT_VERSION.to_string(),
-            latest_version: None,
-            has_update_available: false,
-            release_notes: None,
-            release_date: None,
-        }
+        Self::current()
     }
 }
 
@@ -34,6 +43,7 @@ impl VersionInfo {
             has_update_available: false,
             release_notes: None,
             release_date: None,
+            release_url: None,
         }
     }
 }
@@ -41,27 +51,37 @@ impl VersionInfo {
 #[derive(Debug, Deserialize)]
 struct GitHubRelease {
     tag_name: String,
-    name: String,
-    body: String,
-    published_at: String,
+    #[serde(default)]
+    body: Option<String>,
+    #[serde(default)]
+    published_at: Option<String>,
+    #[serde(default)]
+    html_url: Option<String>,
+    #[serde(default)]
     draft: bool,
+    #[serde(default)]
     prerelease: bool,
 }
 
 pub async fn check_for_updates() -> Result<VersionInfo> {
+    check_for_updates_at(VERSION_CHECK_URL).await
+}
+
+/// Fetch the latest release from `url`. Errors, including non-success replies, are returned
+/// so the caller can keep what it knew.
+async fn check_for_updates_at(url: &str) -> Result<VersionInfo> {
     info!("Checking for updates...");
 
     let client = reqwest::Client::new();
     let response = client
-        .get(VERSION_CHECK_URL)
-        .header("User-Agent", "rujimi/1.0.2")
+        .get(url)
+        .header("User-Agent", concat!("rujimi/", env!("CARGO_PKG_VERSION")))
         .timeout(std::time::Duration::from_secs(10))
         .send()
         .await?;
 
     if !response.status().is_success() {
-        warn!("Failed to check for updates: HTTP {}", response.status());
-        return Ok(VersionInfo::current());
+        anyhow::bail!("Update check failed: HTTP {}", response.status());
     }
 
     let release: GitHubRelease = response.json().await?;
@@ -80,8 +100,9 @@ pub async fn check_for_updates() -> Result<VersionInfo> {
         current_version: CURRENT_VERSION.to_string(),
         latest_version: Some(latest_version),
         has_update_available: has_update,
-        release_notes: Some(release.body),
-        release_date: Some(release.published_at),
+        release_notes: release.body,
+        release_date: release.published_at,
+        release_url: release.html_url,
     };
 
     if has_update {
@@ -138,6 +159,35 @@ pub fn get_current_version() -> String {
     CURRENT_VERSION.to_string()
 }
 
+/// The last successfully checked release, or just the current version before any check
+pub fn latest_version_info() -> VersionInfo {
+    LATEST_RELEASE
+        .read()
+        .unwrap_or_else(|e| e.into_inner())
+        .clone()
+        .unwrap_or_else(VersionInfo::current)
+}
+
+/// Check for updates and cache the result. A failed check is only logged at debug level and
+/// leaves the last known result in place.
+async fn refresh_latest_version(url: &str) {
+    match check_for_updates_at(url).await {
+        Ok(info) => *LATEST_RELEASE.write().unwrap_or_else(|e| e.into_inner()) = Some(info),
+        Err(e) => debug!("Update check failed, keeping the last known version: {:#}", e),
+    }
+}
+
+/// Check for a new release now and then every `UPDATE_CHECK_INTERVAL`, each time after a
+/// random delay of up to `UPDATE_CHECK_JITTER`
+pub async fn start_update_check_task() {
+    loop {
+        let jitter = rand::thread_rng().gen_range(0..UPDATE_CHECK_JITTER.as_secs());
+        tokio::time::sleep(Duration::from_secs(jitter)).await;
+        refresh_latest_version(VERSION_CHECK_URL).await;
+        tokio::time::sleep(UPDATE_CHECK_INTERVAL).await;
+    }
+}
+
 pub fn format_version_for_display(version_info: &VersionInfo) -> String {
     if version_info.has_update_available {
         if let Some(latest) = &version_info.latest_version {
@@ -191,6 +241,49 @@ mod tests {
         assert!(!is_newer_version("1.2.3", "1.2.3"));
     }
 
+    #[tokio::test]
+    async fn test_refresh_keeps_last_known_version() {
+        use axum::{http::StatusCode, routing::get, Json, Router};
+        use std::sync::atomic::{AtomicBool, Ordering};
+        use std::sync::Arc;
+
+        let failing = Arc::new(AtomicBool::new(false));
+        let state = failing.clone();
+        let app = Router::new().route(
+            "/releases/latest",
+            get(move || {
+                let failing = state.load(Ordering::SeqCst);
+                async move {
+                    if failing {
+                        return (StatusCode::SERVICE_UNAVAILABLE, Json(serde_json::json!({})));
+                    }
+                    (StatusCode::OK, Json(serde_json::json!({
+                        "tag_name": "v9.0.0",
+                        "name": null,
+                        "body": "Notes",
+                        "published_at": "2026-01-01T00:00:00Z",
+                        "html_url": "https://github.com/HappyFox001/rujimi/releases/tag/v9.0.0",
+                        "draft": false,
+                        "prerelease": false
+                    })))
+                }
+            }),
+        );
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}/releases/latest", listener.local_addr().unwrap());
+        tokio::spawn(async move { axum::serve(listener, app).await.unwrap() });
+
+        refresh_latest_version(&url).await;
+        let info = latest_version_info();
+        assert!(info.has_update_available);
+        assert_eq!(info.latest_version.as_deref(), Some("9.0.0"));
+        assert_eq!(info.release_url.as_deref(), Some("https://github.com/HappyFox001/rujimi/releases/tag/v9.0.0"));
+
+        failing.store(true, Ordering::SeqCst);
+        refresh_latest_version(&url).await;
+        assert_eq!(latest_version_info().latest_version.as_deref(), Some("9.0.0"));
+    }
+
     #[test]
     fn test_format_version_for_display() {
         let mut version_info = VersionInfo::current();
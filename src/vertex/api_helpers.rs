@@ -223,14 +223,14 @@ pub fn validate_request_parameters(request: &OpenAIRequest) -> Result<()> {
 
     // Check temperature range
     if let Some(temp) = request.temperature {
-        if temp < 0.0 || temp > 2.0 {
+        if !(0.0..=2.0).contains(&temp) {
             return Err(anyhow::anyhow!("Temperature must be between 0.0 and 2.0"));
         }
     }
 
     // Check top_p range
     if let Some(top_p) = request.top_p {
-        if top_p < 0.0 || top_p > 1.0 {
+        if !(0.0..=1.0).contains(&top_p) {
             return Err(anyhow::anyhow!("top_p must be between 0.0 and 1.0"));
         }
     }
@@ -293,12 +293,13 @@ mod tests {
             response_logprobs: None,
             n: None,
             extra: HashMap::new(),
+            forwarded_headers: Vec::new(),
         };
 
         let config = create_generation_config(&request);
-        assert_eq!(config.get("temperature").unwrap(), &json!(0.7));
+        assert_eq!(config.get("temperature").unwrap(), &json!(0.7f32));
         assert_eq!(config.get("max_output_tokens").unwrap(), &json!(1000));
-        assert_eq!(config.get("top_p").unwrap(), &json!(0.9));
+        assert_eq!(config.get("top_p").unwrap(), &json!(0.9f32));
         assert_eq!(config.get("top_k").unwrap(), &json!(40));
     }
 }
\ No newline at end of file
@@ -1,28 +1,406 @@
-use anyhow::Result;
+use anyhow::{anyhow, Context, Result};
+use futures_util::{future, stream, stream::BoxStream, StreamExt};
+use reqwest::StatusCode;
+use serde_json::Value;
 use std::sync::Arc;
 
 use crate::config::Settings;
-use crate::models::schemas::{ChatCompletionRequest, ChatCompletionResponse};
+use crate::services::gemini_stream::SseEventParser;
+use crate::utils::browser;
+use crate::utils::http_client::{self, UpstreamTimeouts};
+use crate::vertex::config::VertexConfig;
+use crate::vertex::credentials_manager::CredentialManager;
+use crate::vertex::express::{ExpressKeyManager, EXPRESS_MODEL_PREFIX};
+use crate::vertex::model_loader::is_vertex_express_model;
+use crate::vertex::vertex_ai_init::VertexAIClient;
 
+/// Calls Vertex AI publisher models with service account credentials, or with an
+/// API key in express mode
 #[derive(Debug, Clone)]
 pub struct VertexClient {
+    http: reqwest::Client,
+    timeouts: UpstreamTimeouts,
+    credential_manager: Arc<CredentialManager>,
+    express_keys: Arc<ExpressKeyManager>,
+    config: VertexConfig,
+    /// Needed to look up which models are express-eligible
     settings: Arc<Settings>,
+    /// Incoming request headers sent on with every call (`forward_request_headers`)
+    forwarded_headers: Vec<(String, String)>,
 }
 
 impl VertexClient {
-    pub fn new(settings: Arc<Settings>) -> Self {
-        Self { settings }
+    pub fn new(settings: &Settings, client: &VertexAIClient) -> Result<Self> {
+        let timeouts = UpstreamTimeouts::from_settings(settings);
+        // No client-wide timeout: it would cut off long streams, so requests set their own
+        let builder = reqwest::Client::builder().connect_timeout(timeouts.connect);
+        let http = http_client::apply_upstream_proxy(builder, settings)?
+            .build()
+            .context("Failed to create HTTP client")?;
+        Ok(Self {
+            http,
+            timeouts,
+            credential_manager: client.credential_manager.clone(),
+            express_keys: client.express_keys.clone(),
+            config: client.config.clone(),
+            settings: Arc::new(settings.clone()),
+            forwarded_headers: Vec::new(),
+        })
     }
 
-    pub async fn chat_completion(
+    pub fn with_forwarded_headers(mut self, headers: Vec<(String, String)>) -> Self {
+        self.forwarded_headers = headers;
+        self
+    }
+
+    pub fn config(&self) -> &VertexConfig {
+        &self.config
+    }
+
+    /// URL of `method` (e.g. `generateContent`) on a Google publisher model
+    pub fn model_url(&self, project_id: &str, model: &str, method: &str) -> String {
+        format!(
+            "{}/v1/projects/{}/locations/{}/publishers/google/models/{}:{}",
+            self.config.base_url, project_id, self.config.location, model, method
+        )
+    }
+
+    /// URL of `method` on a publisher model through the express endpoint
+    pub fn express_model_url(&self, model: &str, method: &str) -> String {
+        format!("{}/v1/publishers/google/models/{}:{}", self.config.express_base_url, model, method)
+    }
+
+    /// Call `generateContent` with a Gemini-format payload and return the raw response
+    pub async fn generate_content(&self, model: &str, payload: &Value) -> Result<Value> {
+        let response = self.send(model, "generateContent", payload, false).await?;
+        let body = response.text().await
+            .map_err(|e| http_client::map_upstream_error(e, &self.timeouts, "Vertex AI response failed"))?;
+
+        serde_json::from_str(&body).map_err(|e| anyhow!("Vertex AI returned an unreadable response: {}", e))
+    }
+
+    /// Call `streamGenerateContent?alt=sse` and yield each streamed Gemini response
+    pub async fn stream_generate_content(&self, model: &str, payload: &Value) -> Result<BoxStream<'static, Result<Value>>> {
+        let response = self.send(model, "streamGenerateContent?alt=sse", payload, true).await?;
+
+        let bytes = response.bytes_stream()
+            .map(|chunk| chunk.map_err(|e| anyhow!("Vertex AI stream error: {}", e)));
+        let bytes = http_client::with_idle_timeout(Box::pin(bytes), self.timeouts.stream_idle)
+            .map(Some)
+            .chain(stream::once(async { None }));
+
+        // `None` marks the end of the body so a trailing unterminated event is still parsed
+        let events = bytes
+            .scan(SseEventParser::new(), |parser, item| {
+                let items: Vec<Result<Value>> = match item {
+                    Some(Ok(bytes)) => parser.push(&bytes).iter().map(|data| parse_event(data)).collect(),
+                    Some(Err(e)) => vec![Err(e)],
+                    None => parser.finish().iter().map(|data| parse_event(data)).collect(),
+                };
+                future::ready(Some(stream::iter(items)))
+            })
+            .flatten();
+
+        Ok(events.boxed())
+    }
+
+    /// POST `payload` to a model method, failing on non-2xx replies. `[EXPRESS]` models
+    /// always use an express key; other express-eligible models use one while one is usable,
+    /// and everything else uses a service account token.
+    async fn send(&self, model: &str, method: &str, payload: &Value, stream: bool) -> Result<reqwest::Response> {
+        if let Some(model) = model.strip_prefix(EXPRESS_MODEL_PREFIX) {
+            let key = self
+                .config
+                .vertex_express_enabled
+                .then(|| self.express_keys.next_key())
+                .flatten()
+                .ok_or_else(|| anyhow!("No Vertex AI Express key available: keys are disabled, rate limited or invalid"))?;
+            return self.send_express(&key, model, method, payload, stream).await;
+        }
+
+        if let Some(key) = self.express_key(model).await {
+            return self.send_express(&key, model, method, payload, stream).await;
+        }
+
+        let credential = self.credential_manager.access_token(&self.http).await?;
+        // Without VERTEX_PROJECT_ID each request goes to its own credential's project
+        let project_id = match self.config.project_id.as_deref() {
+            Some(project_id) => project_id,
+            None => {
+                log::debug!("Using project {} from credential {:?}", credential.key.project_id, credential.file);
+                &credential.key.project_id
+            }
+        };
+
+        log::debug!("Calling Vertex AI {} for {} in {}", method, model, project_id);
+        let request = self
+            .http
+            .post(self.model_url(project_id, model, method))
+            .bearer_auth(&credential.token.token)
+            .json(payload);
+        self.execute(request, stream, |status| match status {
+            Some(status) if status.is_success() => self.credential_manager.record_success(&credential.file),
+            status => self.credential_manager.record_failure(&credential.file, status),
+        })
+        .await
+    }
+
+    async fn send_express(&self, key: &str, model: &str, method: &str, payload: &Value, stream: bool) -> Result<reqwest::Response> {
+        log::debug!("Calling Vertex AI Express {} for {}", method, model);
+        let request = self
+            .http
+            .post(self.express_model_url(model, method))
+            .query(&[("key", key)])
+            .json(payload);
+        self.execute(request, stream, |status| {
+            let success = status.is_some_and(|status| status.is_success());
+            self.express_keys.mark_key_used(key, success, status)
+        })
+        .await
+    }
+
+    /// Express key for `model`, or `None` to use service accounts: express mode is off,
+    /// the model is not express-eligible, or every key is cooling down
+    async fn express_key(&self, model: &str) -> Option<String> {
+        if !self.config.vertex_express_enabled || !self.express_keys.has_keys() {
+            return None;
+        }
+
+        match is_vertex_express_model(&self.settings, model).await {
+            Ok(true) => {}
+            Ok(false) => {
+                log::debug!("{} is not available in Vertex AI Express, using service accounts", model);
+                return None;
+            }
+            Err(e) => {
+                log::warn!("Could not check Vertex AI Express models, using service accounts: {}", e);
+                return None;
+            }
+        }
+
+        let key = self.express_keys.next_key();
+        if key.is_none() {
+            log::warn!("All Vertex AI Express keys are cooling down or invalid, using service accounts");
+        }
+        key
+    }
+
+    /// Send `request` and report the reply status (`None` when there was no reply) to `record`
+    async fn execute(
         &self,
-        _request: ChatCompletionRequest,
-    ) -> Result<ChatCompletionResponse> {
-        // Placeholder implementation - will be implemented later
-        Err(anyhow::anyhow!("Vertex AI not implemented yet"))
+        mut request: reqwest::RequestBuilder,
+        stream: bool,
+        record: impl FnOnce(Option<StatusCode>),
+    ) -> Result<reqwest::Response> {
+        if !stream {
+            request = request.timeout(self.timeouts.request);
+        }
+        request = browser::with_forwarded_headers(request, &self.forwarded_headers);
+
+        let response = match request.send().await {
+            Ok(response) => response,
+            Err(e) => {
+                record(None);
+                // The URL may carry an express key
+                let e = e.without_url();
+                return Err(http_client::map_upstream_error(e, &self.timeouts, "Vertex AI request failed"));
+            }
+        };
+
+        let status = response.status();
+        record(Some(status));
+        if !status.is_success() {
+            let body = response.text().await.unwrap_or_default();
+            return Err(upstream_error(status, &body));
+        }
+        Ok(response)
     }
+}
+
+fn parse_event(data: &str) -> Result<Value> {
+    serde_json::from_str(data).map_err(|e| anyhow!("Vertex AI sent an unreadable stream event: {}", e))
+}
 
-    pub fn is_enabled(&self) -> bool {
-        self.settings.enable_vertex
+/// Describe a failed Vertex response in the terms `handle_chat_completion_error` classifies
+fn upstream_error(status: StatusCode, body: &str) -> anyhow::Error {
+    let message = serde_json::from_str::<Value>(body)
+        .ok()
+        .and_then(|value| value["error"]["message"].as_str().map(str::to_string))
+        .unwrap_or_else(|| body.to_string());
+
+    match status {
+        StatusCode::TOO_MANY_REQUESTS => anyhow!("Vertex AI rate limit or quota exceeded: {}", message),
+        StatusCode::UNAUTHORIZED | StatusCode::FORBIDDEN => anyhow!("Vertex AI authentication failed: {}", message),
+        StatusCode::NOT_FOUND => anyhow!("Vertex AI model not found: {}", message),
+        StatusCode::BAD_REQUEST => anyhow!("Vertex AI rejected an invalid request: {}", message),
+        _ => anyhow!("Vertex AI returned {}: {}", status, message),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::vertex::credentials_manager::test_service_account;
+    use crate::vertex::routes::chat_api::handle_chat_completion_error;
+    use axum::{extract::{Path, State}, routing::post, Json, Router};
+    use serde_json::json;
+    use std::sync::Mutex;
+
+    #[test]
+    fn test_upstream_errors_map_to_openai_statuses() {
+        let status = |code: StatusCode, body: &str| {
+            handle_chat_completion_error(&upstream_error(code, body))["error"]["code"].clone()
+        };
+
+        let quota = r#"{"error": {"code": 429, "message": "Resource exhausted. Please try again later."}}"#;
+        assert_eq!(status(StatusCode::TOO_MANY_REQUESTS, quota), 429);
+        assert_eq!(status(StatusCode::FORBIDDEN, "Permission denied on resource project"), 401);
+        assert_eq!(status(StatusCode::NOT_FOUND, "Publisher model was not found"), 404);
+        assert_eq!(status(StatusCode::BAD_REQUEST, "Unable to submit request"), 400);
+        assert_eq!(status(StatusCode::SERVICE_UNAVAILABLE, "The service is currently unavailable."), 500);
+
+        let error = upstream_error(StatusCode::TOO_MANY_REQUESTS, quota);
+        assert!(error.to_string().ends_with("Resource exhausted. Please try again later."));
+    }
+
+    type Calls = Arc<Mutex<Vec<String>>>;
+
+    async fn mock_token() -> Json<Value> {
+        Json(json!({"access_token": "token", "expires_in": 3600, "token_type": "Bearer"}))
+    }
+
+    /// Answers 429 for `quota-project` and a short reply for every other project
+    async fn mock_aiplatform(State(calls): State<Calls>, Path(path): Path<String>) -> (axum::http::StatusCode, Json<Value>) {
+        let project = path.split('/').nth(1).unwrap_or_default().to_string();
+        calls.lock().unwrap().push(project.clone());
+        if project == "quota-project" {
+            let body = json!({"error": {"code": 429, "message": "Quota exceeded", "status": "RESOURCE_EXHAUSTED"}});
+            return (axum::http::StatusCode::TOO_MANY_REQUESTS, Json(body));
+        }
+        let body = json!({"candidates": [{"content": {"role": "model", "parts": [{"text": "ok"}]}, "finishReason": "STOP"}]});
+        (axum::http::StatusCode::OK, Json(body))
     }
-}
\ No newline at end of file
+
+    #[tokio::test]
+    async fn test_rotation_moves_traffic_off_throttled_credential() {
+        let calls = Calls::default();
+        let app = Router::new()
+            .route("/token", post(mock_token))
+            .route("/v1/*path", post(mock_aiplatform))
+            .with_state(calls.clone());
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let base_url = format!("http://{}", listener.local_addr().unwrap());
+        tokio::spawn(async move { axum::serve(listener, app).await.unwrap() });
+
+        let token_uri = format!("{}/token", base_url);
+        let accounts: Vec<Value> = ["quota-project", "project-a", "project-b"]
+            .iter()
+            .map(|project| {
+                let mut account = test_service_account(&token_uri);
+                account["project_id"] = json!(project);
+                account["client_email"] = json!(format!("proxy@{}.iam.gserviceaccount.com", project));
+                account
+            })
+            .collect();
+        let dir = tempfile::tempdir().unwrap();
+        let credential_manager = CredentialManager::new(dir.path().to_path_buf());
+        credential_manager.save_multiple_credentials_to_files(&accounts).unwrap();
+
+        let settings = Settings { vertex_base_url: base_url, ..Default::default() };
+        let mut config = VertexConfig::from_settings(&settings);
+        config.project_id = None;
+        let client = VertexClient::new(&settings, &VertexAIClient::new(credential_manager, config)).unwrap();
+
+        let payload = json!({"contents": [{"role": "user", "parts": [{"text": "Hi"}]}]});
+        assert!(client.generate_content("gemini-2.5-pro", &payload).await.is_err());
+        for _ in 0..4 {
+            client.generate_content("gemini-2.5-pro", &payload).await.unwrap();
+        }
+
+        // The throttled project is hit once, then its cooldown shifts traffic to the others
+        let calls = calls.lock().unwrap().clone();
+        assert_eq!(calls, ["quota-project", "project-a", "project-b", "project-a", "project-b"]);
+
+        let stats = client.credential_manager.credential_stats();
+        assert_eq!(stats[0].failures, 1);
+        assert!(stats[0].is_cooling_down());
+        assert_eq!(stats[0].summary()["client_email"], "pr***@quota-project.iam.gserviceaccount.com");
+        assert_eq!((stats[1].successes, stats[2].successes), (2, 2));
+    }
+
+    async fn mock_models_config() -> Json<Value> {
+        Json(json!({"vertex_models": ["gemini-2.5-pro"], "vertex_express_models": ["gemini-2.0-flash"]}))
+    }
+
+    /// Answers 429 for the `throttled` express key and a short reply otherwise
+    async fn mock_express(State(calls): State<Calls>, uri: axum::http::Uri) -> (axum::http::StatusCode, Json<Value>) {
+        let call = uri.path_and_query().map(|path| path.as_str().to_string()).unwrap_or_default();
+        calls.lock().unwrap().push(call.clone());
+        if call.ends_with("key=throttled") {
+            let body = json!({"error": {"code": 429, "message": "Quota exceeded", "status": "RESOURCE_EXHAUSTED"}});
+            return (axum::http::StatusCode::TOO_MANY_REQUESTS, Json(body));
+        }
+        let body = json!({"candidates": [{"content": {"role": "model", "parts": [{"text": "ok"}]}, "finishReason": "STOP"}]});
+        (axum::http::StatusCode::OK, Json(body))
+    }
+
+    #[tokio::test]
+    async fn test_express_mode_rotates_keys_and_falls_back_to_service_accounts() {
+        let calls = Calls::default();
+        let app = Router::new()
+            .route("/token", post(mock_token))
+            .route("/models.json", axum::routing::get(mock_models_config))
+            .route("/v1/*path", post(mock_express))
+            .with_state(calls.clone());
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let base_url = format!("http://{}", listener.local_addr().unwrap());
+        tokio::spawn(async move { axum::serve(listener, app).await.unwrap() });
+
+        let dir = tempfile::tempdir().unwrap();
+        let credential_manager = CredentialManager::new(dir.path().to_path_buf());
+        credential_manager
+            .save_multiple_credentials_to_files(&[test_service_account(&format!("{}/token", base_url))])
+            .unwrap();
+
+        let settings = Settings {
+            vertex_base_url: base_url.clone(),
+            vertex_models_config_url: format!("{}/models.json", base_url),
+            enable_vertex_express: true,
+            vertex_express_api_key: "express-a,throttled".to_string(),
+            ..Default::default()
+        };
+        let mut config = VertexConfig::from_settings(&settings);
+        config.project_id = None;
+        let vertex = VertexAIClient::new(credential_manager, config);
+        assert_eq!(vertex.mode(), "express");
+        let client = VertexClient::new(&settings, &vertex).unwrap();
+
+        let payload = json!({"contents": [{"role": "user", "parts": [{"text": "Hi"}]}]});
+        client.generate_content("gemini-2.0-flash", &payload).await.unwrap();
+        assert!(client.generate_content("gemini-2.0-flash", &payload).await.is_err());
+        client.generate_content("gemini-2.0-flash", &payload).await.unwrap();
+        // Not express-eligible, so it goes through a service account
+        client.generate_content("gemini-2.5-pro", &payload).await.unwrap();
+
+        let express = "/v1/publishers/google/models/gemini-2.0-flash:generateContent";
+        let service_account = "/v1/projects/test-project/locations/us-central1/publishers/google/models";
+        assert_eq!(
+            calls.lock().unwrap().clone(),
+            [
+                format!("{}?key=express-a", express),
+                format!("{}?key=throttled", express),
+                format!("{}?key=express-a", express),
+                format!("{}/gemini-2.5-pro:generateContent", service_account),
+            ]
+        );
+
+        // With the last express key cooling down, eligible models fall back too
+        vertex.express_keys.mark_key_used("express-a", false, Some(StatusCode::TOO_MANY_REQUESTS));
+        assert_eq!(vertex.mode(), "service_account");
+        client.generate_content("gemini-2.0-flash", &payload).await.unwrap();
+        assert_eq!(
+            calls.lock().unwrap().last().unwrap(),
+            &format!("{}/gemini-2.0-flash:generateContent", service_account)
+        );
+    }
+}
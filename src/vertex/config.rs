@@ -12,8 +12,13 @@ pub struct VertexConfig {
     pub google_credentials_json: Option<String>,
     pub project_id: Option<String>,
     pub location: String,
+    /// API root the `/v1/projects/...` paths are appended to
+    pub base_url: String,
     pub models_config_url: String,
+    pub vertex_express_enabled: bool,
     pub vertex_express_api_keys: Vec<String>,
+    /// API root for express calls, which are not regional
+    pub express_base_url: String,
     pub fake_streaming_enabled: bool,
     pub fake_streaming_interval_seconds: f64,
     pub fake_streaming_chunk_size: usize,
@@ -23,14 +28,7 @@ pub struct VertexConfig {
 impl VertexConfig {
     pub fn from_settings(settings: &Settings) -> Self {
         // Set default credentials directory if not present
-        let credentials_dir = settings.credentials_dir
-            .as_ref()
-            .map(PathBuf::from)
-            .unwrap_or_else(|| {
-                let mut path = PathBuf::from(&settings.storage_dir);
-                path.push("credentials");
-                path
-            });
+        let credentials_dir = resolve_credentials_dir(settings);
 
         log::info!("Using credentials directory: {:?}", credentials_dir);
 
@@ -44,46 +42,44 @@ impl VertexConfig {
         };
 
         // Google Credentials JSON
-        let google_credentials_json = settings.google_credentials_json.clone();
+        let google_credentials_json = non_empty(&settings.google_credentials_json);
         if google_credentials_json.is_some() {
             log::info!("Using GOOGLE_CREDENTIALS_JSON environment variable for authentication");
         }
 
         // Project and location configuration
         let project_id = env::var("VERTEX_PROJECT_ID").ok()
-            .or_else(|| settings.vertex_project_id.clone());
+            .filter(|id| !id.is_empty())
+            .or_else(|| non_empty(&settings.vertex_project_id));
 
         let location = env::var("VERTEX_LOCATION")
-            .unwrap_or_else(|_| settings.vertex_location.clone().unwrap_or_else(|| "us-central1".to_string()));
+            .ok()
+            .filter(|location| !location.is_empty())
+            .unwrap_or_else(|| settings.vertex_location.clone());
+
+        let base_url = non_empty(&settings.vertex_base_url)
+            .unwrap_or_else(|| default_base_url(&location));
+        let express_base_url = non_empty(&settings.vertex_base_url)
+            .unwrap_or_else(|| default_base_url("global"));
 
         // Model configuration URL
         let default_models_config_url = "https://raw.githubusercontent.com/gzzhongqi/vertex2openai/refs/heads/main/vertexModels.json";
-        let models_config_url = env::var("VERTEX_MODELS_CONFIG_URL")
-            .unwrap_or_else(|_| default_models_config_url.to_string());
+        let models_config_url = non_empty(&settings.vertex_models_config_url)
+            .unwrap_or_else(|| default_models_config_url.to_string());
         log::info!("Using models config URL: {}", models_config_url);
 
         // Vertex Express API Key configuration
-        let vertex_express_api_keys = settings.vertex_express_api_key
-            .as_ref()
-            .map(|keys| {
-                let key_list: Vec<String> = keys
-                    .split(',')
-                    .map(|s| s.trim().to_string())
-                    .filter(|s| !s.is_empty())
-                    .collect();
-
-                if !key_list.is_empty() {
-                    log::info!("Loaded {} Vertex Express API keys from settings", key_list.len());
-                }
-                key_list
-            })
-            .unwrap_or_default();
+        let vertex_express_enabled = settings.enable_vertex_express;
+        let vertex_express_api_keys = split_keys(&settings.vertex_express_api_key);
+        if !vertex_express_api_keys.is_empty() {
+            log::info!("Loaded {} Vertex Express API keys from settings", vertex_express_api_keys.len());
+        }
 
         // Fake streaming configuration
-        let fake_streaming_enabled = settings.fake_streaming.unwrap_or(false);
-        let fake_streaming_interval_seconds = settings.fake_streaming_interval.unwrap_or(1.0);
-        let fake_streaming_chunk_size = settings.fake_streaming_chunk_size.unwrap_or(10);
-        let fake_streaming_delay_per_chunk = settings.fake_streaming_delay_per_chunk.unwrap_or(0.1);
+        let fake_streaming_enabled = settings.fake_streaming;
+        let fake_streaming_interval_seconds = settings.fake_streaming_interval;
+        let fake_streaming_chunk_size = settings.fake_streaming_chunk_size.max(1) as usize;
+        let fake_streaming_delay_per_chunk = settings.fake_streaming_delay_per_chunk;
 
         log::info!(
             "Fake streaming is {} with interval {} seconds, chunk size {}, delay per chunk {} seconds",
@@ -99,8 +95,11 @@ impl VertexConfig {
             google_credentials_json,
             project_id,
             location,
+            base_url,
             models_config_url,
+            vertex_express_enabled,
             vertex_express_api_keys,
+            express_base_url,
             fake_streaming_enabled,
             fake_streaming_interval_seconds,
             fake_streaming_chunk_size,
@@ -119,38 +118,44 @@ impl VertexConfig {
         match name {
             "VERTEX_API_KEY" => {
                 settings.password = value.clone();
-                self.api_key = value;
+                self.api_key = value.clone();
                 log::info!("Updated API Key");
             }
             "GOOGLE_CREDENTIALS_JSON" => {
-                settings.google_credentials_json = Some(value.clone());
-                self.google_credentials_json = Some(value);
+                settings.google_credentials_json = value.clone();
+                self.google_credentials_json = Some(value.clone());
                 log::info!("Updated Google Credentials JSON");
             }
             "VERTEX_PROJECT_ID" => {
                 env::set_var("VERTEX_PROJECT_ID", &value);
-                settings.vertex_project_id = Some(value.clone());
+                settings.vertex_project_id = value.clone();
                 self.project_id = Some(value.clone());
                 log::info!("Updated Project ID to {}", value);
             }
             "VERTEX_LOCATION" => {
                 env::set_var("VERTEX_LOCATION", &value);
-                settings.vertex_location = Some(value.clone());
+                settings.vertex_location = value.clone();
                 self.location = value.clone();
+                if non_empty(&settings.vertex_base_url).is_none() {
+                    self.base_url = default_base_url(&value);
+                }
                 log::info!("Updated Location to {}", value);
             }
             "VERTEX_MODELS_CONFIG_URL" => {
                 env::set_var("VERTEX_MODELS_CONFIG_URL", &value);
+                settings.vertex_models_config_url = value.clone();
                 self.models_config_url = value.clone();
                 log::info!("Updated Models Config URL to {}", value);
             }
+            "ENABLE_VERTEX_EXPRESS" => {
+                let bool_val = value.parse::<bool>()?;
+                settings.enable_vertex_express = bool_val;
+                self.vertex_express_enabled = bool_val;
+                log::info!("Updated ENABLE_VERTEX_EXPRESS to {}", bool_val);
+            }
             "VERTEX_EXPRESS_API_KEY" => {
-                settings.vertex_express_api_key = Some(value.clone());
-                self.vertex_express_api_keys = value
-                    .split(',')
-                    .map(|s| s.trim().to_string())
-                    .filter(|s| !s.is_empty())
-                    .collect();
+                settings.vertex_express_api_key = value.clone();
+                self.vertex_express_api_keys = split_keys(&value);
                 log::info!(
                     "Updated Vertex Express API Key, now have {} keys",
                     self.vertex_express_api_keys.len()
@@ -158,25 +163,25 @@ impl VertexConfig {
             }
             "FAKE_STREAMING" => {
                 let bool_val = value.parse::<bool>()?;
-                settings.fake_streaming = Some(bool_val);
+                settings.fake_streaming = bool_val;
                 self.fake_streaming_enabled = bool_val;
                 log::info!("Updated FAKE_STREAMING to {}", bool_val);
             }
             "FAKE_STREAMING_INTERVAL" => {
                 let float_val = value.parse::<f64>()?;
-                settings.fake_streaming_interval = Some(float_val);
+                settings.fake_streaming_interval = float_val;
                 self.fake_streaming_interval_seconds = float_val;
                 log::info!("Updated FAKE_STREAMING_INTERVAL to {}", float_val);
             }
             "FAKE_STREAMING_CHUNK_SIZE" => {
                 let int_val = value.parse::<usize>()?;
-                settings.fake_streaming_chunk_size = Some(int_val);
+                settings.fake_streaming_chunk_size = int_val as i32;
                 self.fake_streaming_chunk_size = int_val;
                 log::info!("Updated FAKE_STREAMING_CHUNK_SIZE to {}", int_val);
             }
             "FAKE_STREAMING_DELAY_PER_CHUNK" => {
                 let float_val = value.parse::<f64>()?;
-                settings.fake_streaming_delay_per_chunk = Some(float_val);
+                settings.fake_streaming_delay_per_chunk = float_val;
                 self.fake_streaming_delay_per_chunk = float_val;
                 log::info!("Updated FAKE_STREAMING_DELAY_PER_CHUNK to {}", float_val);
             }
@@ -194,27 +199,17 @@ impl VertexConfig {
     /// Reload configuration - usually called after persistent settings are loaded
     pub fn reload_config(&mut self, settings: &Settings) {
         // Reload Google Credentials JSON
-        self.google_credentials_json = settings.google_credentials_json.clone();
+        self.google_credentials_json = non_empty(&settings.google_credentials_json);
         if self.google_credentials_json.is_some() {
             log::info!("Reloaded GOOGLE_CREDENTIALS_JSON configuration");
         }
 
         // Reload Vertex Express API Key
-        self.vertex_express_api_keys = settings.vertex_express_api_key
-            .as_ref()
-            .map(|keys| {
-                let key_list: Vec<String> = keys
-                    .split(',')
-                    .map(|s| s.trim().to_string())
-                    .filter(|s| !s.is_empty())
-                    .collect();
-
-                if !key_list.is_empty() {
-                    log::info!("Reloaded {} Vertex Express API keys", key_list.len());
-                }
-                key_list
-            })
-            .unwrap_or_default();
+        self.vertex_express_enabled = settings.enable_vertex_express;
+        self.vertex_express_api_keys = split_keys(&settings.vertex_express_api_key);
+        if !self.vertex_express_api_keys.is_empty() {
+            log::info!("Reloaded {} Vertex Express API keys", self.vertex_express_api_keys.len());
+        }
 
         // Reload API Key
         self.api_key = if !settings.password.is_empty() {
@@ -226,6 +221,36 @@ impl VertexConfig {
     }
 }
 
+/// `credentials_dir`, or `<storage_dir>/credentials` when it is not set
+pub fn resolve_credentials_dir(settings: &Settings) -> PathBuf {
+    match non_empty(&settings.credentials_dir) {
+        Some(dir) => PathBuf::from(dir),
+        None => PathBuf::from(&settings.storage_dir).join("credentials"),
+    }
+}
+
+/// Regional Vertex AI endpoint; the `global` location has no regional prefix
+fn default_base_url(location: &str) -> String {
+    if location == "global" {
+        "https://aiplatform.googleapis.com".to_string()
+    } else {
+        format!("https://{}-aiplatform.googleapis.com", location)
+    }
+}
+
+/// `None` for an empty setting
+pub fn non_empty(value: &str) -> Option<String> {
+    let value = value.trim();
+    (!value.is_empty()).then(|| value.to_string())
+}
+
+fn split_keys(keys: &str) -> Vec<String> {
+    keys.split(',')
+        .map(|s| s.trim().to_string())
+        .filter(|s| !s.is_empty())
+        .collect()
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -235,4 +260,16 @@ mod tests {
         VertexConfig::update_env_var("TEST_VAR", "test_value");
         assert_eq!(env::var("TEST_VAR").unwrap(), "test_value");
     }
+
+    #[test]
+    fn test_base_url() {
+        assert_eq!(default_base_url("europe-west4"), "https://europe-west4-aiplatform.googleapis.com");
+        assert_eq!(default_base_url("global"), "https://aiplatform.googleapis.com");
+
+        let config = VertexConfig::from_settings(&Settings {
+            vertex_base_url: "http://127.0.0.1:9000".to_string(),
+            ..Default::default()
+        });
+        assert_eq!(config.base_url, "http://127.0.0.1:9000");
+    }
 }
\ No newline at end of file
@@ -0,0 +1,189 @@
+use reqwest::StatusCode;
+use serde_json::{json, Value};
+use std::collections::{HashMap, VecDeque};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use crate::config::Settings;
+use crate::utils::auth::client_fingerprint;
+
+/// Model id prefix that forces a request through Vertex AI Express
+pub const EXPRESS_MODEL_PREFIX: &str = "[EXPRESS] ";
+
+/// How long an express key is skipped after Vertex answers 429 for it
+pub const EXPRESS_KEY_COOLDOWN: Duration = Duration::from_secs(60);
+
+/// Consecutive failures after which a key is dropped, as `ApiKeyManager` does
+const MAX_CONSECUTIVE_FAILURES: u32 = 5;
+
+/// Express mode is on and at least one express key is configured
+pub fn express_available(settings: &Settings) -> bool {
+    settings.enable_vertex_express && settings.vertex_express_api_key.split(',').any(|key| !key.trim().is_empty())
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct ExpressKeyStats {
+    pub daily_usage: u32,
+    pub last_used: Option<chrono::DateTime<chrono::Utc>>,
+    pub consecutive_failures: u32,
+    pub cooldown_until: Option<Instant>,
+    pub invalid: bool,
+}
+
+impl ExpressKeyStats {
+    fn is_cooling_down(&self) -> bool {
+        self.cooldown_until.is_some_and(|until| until > Instant::now())
+    }
+
+    fn is_usable(&self) -> bool {
+        !self.invalid && !self.is_cooling_down()
+    }
+}
+
+#[derive(Debug, Default)]
+struct ExpressState {
+    queue: VecDeque<String>,
+    stats: HashMap<String, ExpressKeyStats>,
+}
+
+/// Round-robin rotation over the Vertex Express API keys
+#[derive(Debug, Default)]
+pub struct ExpressKeyManager {
+    state: Mutex<ExpressState>,
+}
+
+impl ExpressKeyManager {
+    pub fn new(keys: &[String]) -> Self {
+        let state = ExpressState {
+            queue: keys.iter().cloned().collect(),
+            stats: keys.iter().map(|key| (key.clone(), ExpressKeyStats::default())).collect(),
+        };
+        Self { state: Mutex::new(state) }
+    }
+
+    pub fn has_keys(&self) -> bool {
+        !self.lock().queue.is_empty()
+    }
+
+    /// Keys that are neither invalid nor cooling down
+    pub fn available_count(&self) -> usize {
+        self.lock().stats.values().filter(|stats| stats.is_usable()).count()
+    }
+
+    /// Next usable key in turn; `None` when every key is invalid or cooling down
+    pub fn next_key(&self) -> Option<String> {
+        let mut state = self.lock();
+        let ExpressState { queue, stats } = &mut *state;
+
+        for _ in 0..queue.len() {
+            let key = queue.pop_front()?;
+            queue.push_back(key.clone());
+            if stats.get(&key).is_some_and(|stats| stats.is_usable()) {
+                return Some(key);
+            }
+        }
+        None
+    }
+
+    /// Record the outcome of a call; `status` is `None` when the request never got a reply
+    pub fn mark_key_used(&self, key: &str, success: bool, status: Option<StatusCode>) {
+        let mut state = self.lock();
+        let stats = match state.stats.get_mut(key) {
+            Some(stats) => stats,
+            None => return,
+        };
+        stats.last_used = Some(chrono::Utc::now());
+
+        if success {
+            stats.daily_usage += 1;
+            stats.consecutive_failures = 0;
+            stats.cooldown_until = None;
+            return;
+        }
+
+        stats.consecutive_failures += 1;
+        if status == Some(StatusCode::TOO_MANY_REQUESTS) {
+            log::warn!("Cooling down Vertex Express key {} for {:?}", client_fingerprint(key), EXPRESS_KEY_COOLDOWN);
+            stats.cooldown_until = Some(Instant::now() + EXPRESS_KEY_COOLDOWN);
+        }
+        if stats.consecutive_failures >= MAX_CONSECUTIVE_FAILURES {
+            log::warn!("Marking Vertex Express key {} invalid due to consecutive failures", client_fingerprint(key));
+            stats.invalid = true;
+        }
+    }
+
+    /// Per-key stats labelled with the masked key id used in usage stats
+    pub fn summary(&self) -> Vec<Value> {
+        let state = self.lock();
+        state
+            .queue
+            .iter()
+            .filter_map(|key| state.stats.get(key).map(|stats| (key, stats)))
+            .map(|(key, stats)| {
+                let status = if stats.invalid {
+                    "invalid"
+                } else if stats.is_cooling_down() {
+                    "cooling_down"
+                } else {
+                    "available"
+                };
+                json!({
+                    "key": client_fingerprint(key),
+                    "status": status,
+                    "daily_usage": stats.daily_usage,
+                    "consecutive_failures": stats.consecutive_failures,
+                    "last_used": stats.last_used.map(|time| time.to_rfc3339())
+                })
+            })
+            .collect()
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, ExpressState> {
+        self.state.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn keys(count: usize) -> Vec<String> {
+        (1..=count).map(|index| format!("express-key-{}", index)).collect()
+    }
+
+    #[test]
+    fn test_rotation_and_cooldown() {
+        let keys = keys(3);
+        let manager = ExpressKeyManager::new(&keys);
+        let picks: Vec<String> = (0..3).map(|_| manager.next_key().unwrap()).collect();
+        assert_eq!(picks, keys);
+
+        manager.mark_key_used(&keys[0], false, Some(StatusCode::TOO_MANY_REQUESTS));
+        manager.mark_key_used(&keys[1], true, Some(StatusCode::OK));
+        assert_eq!(manager.available_count(), 2);
+        let picks: Vec<String> = (0..4).map(|_| manager.next_key().unwrap()).collect();
+        assert_eq!(picks, [1, 2, 1, 2].map(|index| keys[index].clone()));
+
+        manager.mark_key_used(&keys[1], false, Some(StatusCode::TOO_MANY_REQUESTS));
+        manager.mark_key_used(&keys[2], false, Some(StatusCode::TOO_MANY_REQUESTS));
+        assert!(manager.next_key().is_none());
+
+        let summary = manager.summary();
+        assert_eq!(summary[0]["key"], client_fingerprint(&keys[0]));
+        assert_eq!(summary[0]["status"], "cooling_down");
+        assert_eq!(summary[1]["daily_usage"], 1);
+    }
+
+    #[test]
+    fn test_consecutive_failures_invalidate_key() {
+        let keys = keys(1);
+        let manager = ExpressKeyManager::new(&keys);
+        for _ in 0..MAX_CONSECUTIVE_FAILURES {
+            assert_eq!(manager.next_key().as_deref(), Some("express-key-1"));
+            manager.mark_key_used(&keys[0], false, Some(StatusCode::FORBIDDEN));
+        }
+        assert!(manager.next_key().is_none());
+        assert_eq!(manager.summary()[0]["status"], "invalid");
+        assert!(!ExpressKeyManager::new(&[]).has_keys());
+    }
+}
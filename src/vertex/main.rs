@@ -1,19 +1,25 @@
 use axum::{
-    extract::State,
-    http::StatusCode,
-    response::Json,
+    body::Body,
+    extract::{ConnectInfo, Query, State},
+    http::{header, HeaderMap, HeaderValue, StatusCode},
+    response::{IntoResponse, Json, Response},
     routing::{get, post},
     Router,
 };
+use futures_util::{stream::BoxStream, StreamExt};
+use std::convert::Infallible;
+use std::net::SocketAddr;
 use std::sync::Arc;
 use serde_json::{Value, json};
 
+use crate::api::fallback;
 use crate::config::Settings;
+use crate::utils::auth::AuthQuery;
+use crate::utils::browser;
+use crate::AppState;
 use crate::vertex::{
-    auth::api_key_middleware,
-    credentials_manager::CredentialManager,
-    vertex_ai_init::{init_vertex_ai, get_vertex_ai_status},
-    routes::{chat_api, models_api},
+    vertex_ai_init::{init_vertex_ai, get_global_fallback_client, get_vertex_ai_status},
+    routes::{chat_api::{self, handle_chat_completion_error, ChatCompletionOutput}, models_api},
 };
 
 // Rust equivalent of Python vertex/main.py
@@ -21,20 +27,23 @@ use crate::vertex::{
 #[derive(Clone)]
 pub struct VertexAppState {
     pub settings: Arc<Settings>,
+    /// Main app state, used to retry chat completions on the Gemini API (`FALLBACK_PROVIDER=gemini`)
+    pub gemini_fallback: Option<AppState>,
 }
 
 /// Create Vertex AI router with all routes
-pub fn create_vertex_router(settings: Arc<Settings>) -> Router {
-    let state = VertexAppState { settings };
+pub fn create_vertex_router(settings: Arc<Settings>, gemini_fallback: Option<AppState>) -> Router {
+    let state = VertexAppState { settings, gemini_fallback };
 
     Router::new()
         .route("/v1/models", get(handle_models_list))
+        .route("/models/refresh", post(handle_models_refresh))
         .route("/v1/chat/completions", post(handle_chat_completions))
         .route("/v1/completions", post(handle_completions))
-        .route("/vertex/status", get(handle_vertex_status))
-        .route("/vertex/init", post(handle_vertex_init))
-        .route("/vertex/reinit", post(handle_vertex_reinit))
-        .layer(axum::middleware::from_fn(api_key_middleware))
+        .route("/status", get(handle_vertex_status))
+        .route("/init", post(handle_vertex_init))
+        .route("/reinit", post(handle_vertex_reinit))
+        .route("/credentials/stats", get(handle_credential_stats))
         .with_state(state)
 }
 
@@ -51,16 +60,41 @@ async fn handle_models_list(
     }
 }
 
+/// Reload the model list from the models config URL
+async fn handle_models_refresh(State(state): State<VertexAppState>) -> Response {
+    match models_api::refresh_models_cache(&state.settings).await {
+        Ok(summary) => Json(summary).into_response(),
+        Err(e) => {
+            log::error!("Failed to refresh models: {:#}", e);
+            let body = json!({
+                "status": "error",
+                "message": format!("Model configuration refresh failed, keeping the previous list: {}", e)
+            });
+            (StatusCode::BAD_GATEWAY, Json(body)).into_response()
+        }
+    }
+}
+
 /// Handle chat completions endpoint
 async fn handle_chat_completions(
     State(state): State<VertexAppState>,
-    Json(request): Json<crate::vertex::models::OpenAIRequest>,
-) -> Result<Json<Value>, StatusCode> {
-    match chat_api::handle_chat_completion(&state.settings, request).await {
-        Ok(response) => Ok(Json(response)),
+    connect_info: Option<ConnectInfo<SocketAddr>>,
+    headers: HeaderMap,
+    Query(query): Query<AuthQuery>,
+    Json(mut request): Json<crate::vertex::models::OpenAIRequest>,
+) -> Response {
+    request.forwarded_headers = browser::forwarded_headers(&headers, &state.settings.forward_request_headers);
+    match chat_api::handle_chat_completion(&state.settings, request.clone()).await {
+        Ok(ChatCompletionOutput::Json(response)) => Json(response).into_response(),
+        Ok(ChatCompletionOutput::Stream(frames)) => event_stream_response(frames),
         Err(e) => {
-            log::error!("Chat completion failed: {}", e);
-            Err(StatusCode::INTERNAL_SERVER_ERROR)
+            log::error!("Chat completion failed: {:#}", e);
+            if let Some(app) = state.gemini_fallback.filter(|_| fallback::vertex_exhausted(&e)) {
+                if let Some(response) = fallback::serve_with_gemini(app, connect_info, &headers, &query, &request).await {
+                    return response;
+                }
+            }
+            error_response(&e)
         }
     }
 }
@@ -69,21 +103,56 @@ async fn handle_chat_completions(
 async fn handle_completions(
     State(state): State<VertexAppState>,
     Json(request): Json<crate::vertex::models::GeminiCompletionRequest>,
-) -> Result<Json<Value>, StatusCode> {
+) -> Response {
     match chat_api::handle_completion(&state.settings, request).await {
-        Ok(response) => Ok(Json(response)),
+        Ok(response) => Json(response).into_response(),
         Err(e) => {
-            log::error!("Completion failed: {}", e);
-            Err(StatusCode::INTERNAL_SERVER_ERROR)
+            log::error!("Completion failed: {:#}", e);
+            error_response(&e)
         }
     }
 }
 
+/// Stream pre-formatted SSE frames without buffering
+pub(crate) fn event_stream_response(frames: BoxStream<'static, String>) -> Response {
+    let mut response = Body::from_stream(frames.map(Ok::<_, Infallible>)).into_response();
+    let headers = response.headers_mut();
+    headers.insert(header::CONTENT_TYPE, HeaderValue::from_static("text/event-stream"));
+    headers.insert(header::CACHE_CONTROL, HeaderValue::from_static("no-cache"));
+    headers.insert("x-accel-buffering", HeaderValue::from_static("no"));
+    response
+}
+
+/// OpenAI-style error body with the status `handle_chat_completion_error` picked
+pub(crate) fn error_response(error: &anyhow::Error) -> Response {
+    let body = handle_chat_completion_error(error);
+    let status = body["error"]["code"]
+        .as_u64()
+        .and_then(|code| StatusCode::from_u16(code as u16).ok())
+        .unwrap_or(StatusCode::INTERNAL_SERVER_ERROR);
+    (status, Json(body)).into_response()
+}
+
 /// Handle vertex status endpoint
 async fn handle_vertex_status() -> Json<Value> {
     Json(get_vertex_ai_status().await)
 }
 
+/// Per-credential rotation stats; only masked emails and project ids are exposed
+async fn handle_credential_stats() -> Json<Value> {
+    let stats = match get_global_fallback_client().await {
+        Some(client) => client.credential_manager.credential_stats(),
+        None => Vec::new(),
+    };
+    let available = stats.iter().filter(|stats| !stats.invalid && !stats.is_cooling_down()).count();
+
+    Json(json!({
+        "total": stats.len(),
+        "available": available,
+        "credentials": stats.iter().map(|stats| stats.summary()).collect::<Vec<_>>()
+    }))
+}
+
 /// Handle vertex initialization endpoint
 async fn handle_vertex_init(
     State(state): State<VertexAppState>,
@@ -115,7 +184,7 @@ async fn handle_vertex_init(
     }
 }
 
-/// Handle vertex reinitialization endpoint
+/// Handle vertex reinitialization endpoint; rescans the credentials directory and resets rotation
 async fn handle_vertex_reinit(
     State(state): State<VertexAppState>,
 ) -> Result<Json<Value>, StatusCode> {
@@ -187,6 +256,10 @@ pub async fn get_vertex_config_summary(settings: &Settings) -> Json<Value> {
 
     let models_summary = get_models_summary(settings).await.unwrap_or_default();
     let status = get_vertex_ai_status().await;
+    let effective_project_ids = match get_global_fallback_client().await {
+        Some(client) => client.effective_project_ids(),
+        None => configured_project_ids(settings),
+    };
 
     Json(json!({
         "vertex_ai": {
@@ -194,14 +267,36 @@ pub async fn get_vertex_config_summary(settings: &Settings) -> Json<Value> {
             "models": models_summary,
             "configuration": {
                 "project_id": settings.vertex_project_id,
+                "effective_project_ids": effective_project_ids,
                 "location": settings.vertex_location,
                 "fake_streaming_enabled": settings.fake_streaming,
-                "credentials_dir": settings.credentials_dir
+                "credentials_dir": crate::vertex::config::resolve_credentials_dir(settings)
             }
         }
     }))
 }
 
+/// Projects implied by the settings alone: `VERTEX_PROJECT_ID`, or the projects in
+/// `GOOGLE_CREDENTIALS_JSON`
+fn configured_project_ids(settings: &Settings) -> Vec<String> {
+    use crate::vertex::config::non_empty;
+    use crate::vertex::credentials_manager::CredentialManager;
+
+    if let Some(project_id) = non_empty(&settings.vertex_project_id) {
+        return vec![project_id];
+    }
+
+    let credentials = CredentialManager::parse_multiple_json_credentials(&settings.google_credentials_json)
+        .unwrap_or_default();
+    let mut projects: Vec<String> = credentials
+        .iter()
+        .filter_map(|credential| credential["project_id"].as_str().map(str::to_string))
+        .collect();
+    projects.sort();
+    projects.dedup();
+    projects
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -217,9 +312,8 @@ mod tests {
     #[test]
     fn test_create_vertex_router() {
         let settings = Arc::new(Settings::default());
-        let router = create_vertex_router(settings);
         // Router creation should not panic
-        assert!(true);
+        let _router = create_vertex_router(settings, None);
     }
 
     #[tokio::test]
@@ -230,4 +324,20 @@ mod tests {
         assert!(json_value["vertex_ai"].is_object());
         assert!(json_value["vertex_ai"]["status"].is_object());
     }
+
+    #[test]
+    fn test_configured_project_ids() {
+        let credential = |project: &str| {
+            format!(
+                r#"{{"type":"service_account","project_id":"{0}","private_key_id":"k","private_key":"k","client_email":"sa@{0}.iam.gserviceaccount.com"}}"#,
+                project
+            )
+        };
+        let credentials = format!("{},{}", credential("project-b"), credential("project-a"));
+        let settings = Settings { google_credentials_json: credentials, ..Default::default() };
+        assert_eq!(configured_project_ids(&settings), ["project-a", "project-b"]);
+
+        let settings = Settings { vertex_project_id: "pinned".to_string(), ..settings };
+        assert_eq!(configured_project_ids(&settings), ["pinned"]);
+    }
 }
\ No newline at end of file
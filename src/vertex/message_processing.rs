@@ -1,10 +1,9 @@
-use std::collections::HashMap;
 use serde_json::{Value, json};
 use base64::{Engine, engine::general_purpose};
-use regex::Regex;
 use url::Url;
-use crate::vertex::models::{OpenAIMessage, MessageContent, ContentPart, ImageUrl};
+use crate::vertex::models::{OpenAIMessage, MessageContent, ContentPart};
 use anyhow::{Result, anyhow};
+use crate::utils::tokens::estimate_tokens;
 
 // Rust equivalent of Python vertex/message_processing.py
 
@@ -39,14 +38,10 @@ pub fn create_gemini_prompt(messages: &[OpenAIMessage]) -> Result<Vec<Value>> {
         }
 
         if !SUPPORTED_ROLES.contains(&role.as_str()) {
-            if role == "tool" {
-                role = "user".to_string();
-            } else if idx == messages.len() - 1 {
-                role = "user".to_string();
-            } else {
+            if role != "tool" && idx != messages.len() - 1 {
                 log::warn!("Unsupported role '{}', converting to 'user'", role);
-                role = "user".to_string();
             }
+            role = "user".to_string();
         }
 
         let gemini_message = json!({
@@ -92,26 +87,13 @@ pub fn deobfuscate_text(text: &str) -> String {
 
     let mut result = text.to_string();
 
-    // Remove zero-width characters and similar Unicode obfuscation
+    // Remove zero-width characters and similar Unicode obfuscation. Whitespace, markdown and
+    // tags are left alone: they carry meaning, and streamed chunks must keep their spacing.
     let zero_width_chars = ['\u{200B}', '\u{200C}', '\u{200D}', '\u{FEFF}'];
     for &ch in &zero_width_chars {
         result = result.replace(ch, "");
     }
 
-    // Remove excessive whitespace and normalize
-    let whitespace_regex = Regex::new(r"\s+").unwrap();
-    result = whitespace_regex.replace_all(&result, " ").to_string();
-
-    // Remove markdown formatting artifacts that might be used for obfuscation
-    let markdown_regex = Regex::new(r"\*{1,3}([^*]+)\*{1,3}").unwrap();
-    result = markdown_regex.replace_all(&result, "$1").to_string();
-
-    // Remove HTML-like tags
-    let html_regex = Regex::new(r"<[^>]*>").unwrap();
-    result = html_regex.replace_all(&result, "").to_string();
-
-    result = result.trim().to_string();
-
     if result != text {
         log::debug!("Text deobfuscated: {} -> {}", text.len(), result.len());
     }
@@ -173,7 +155,7 @@ pub fn convert_chunk_to_openai(
 ) -> Result<String> {
     let deobfuscated_chunk = deobfuscate_text(chunk);
 
-    let finish_reason = if is_final { "stop" } else { null };
+    let finish_reason = if is_final { Some("stop") } else { None };
 
     let openai_chunk = json!({
         "id": format!("chatcmpl-{}", uuid::Uuid::new_v4()),
@@ -218,7 +200,7 @@ pub fn create_final_chunk(model: &str) -> String {
 }
 
 /// Extract content from Gemini response
-fn extract_gemini_content(response: &Value) -> Result<String> {
+pub fn extract_gemini_content(response: &Value) -> Result<String> {
     // Try to extract from different possible response structures
     if let Some(candidates) = response.get("candidates") {
         if let Some(candidate) = candidates.get(0) {
@@ -262,12 +244,6 @@ pub fn parse_gemini_response_for_reasoning_and_content(response: &Value) -> Resu
     }
 }
 
-/// Estimate token count (rough approximation)
-fn estimate_tokens(text: &str) -> i32 {
-    // Rough estimation: ~4 characters per token for most languages
-    ((text.len() as f64) / 4.0).ceil() as i32
-}
-
 /// Validate image URL format
 pub fn validate_image_url(url: &str) -> Result<()> {
     // Check if it's a data URL
@@ -315,18 +291,11 @@ mod tests {
 
     #[test]
     fn test_deobfuscate_text() {
-        let obfuscated = "This\u{200B}is\u{200C}test\u{FEFF}text";
+        let obfuscated = "This \u{200B}is te\u{200C}st\u{FEFF} text";
         let result = deobfuscate_text(obfuscated);
         assert_eq!(result, "This is test text");
     }
 
-    #[test]
-    fn test_estimate_tokens() {
-        assert_eq!(estimate_tokens("hello world"), 3);
-        assert_eq!(estimate_tokens("a"), 1);
-        assert_eq!(estimate_tokens(""), 0);
-    }
-
     #[test]
     fn test_validate_image_url() {
         assert!(validate_image_url("https://example.com/image.jpg").is_ok());
@@ -1,18 +1,34 @@
 use std::collections::HashMap;
 use std::sync::Arc;
+use std::time::{Duration, Instant};
 use tokio::sync::{Mutex, RwLock};
 use serde_json::Value;
 use anyhow::{Result, anyhow};
-use reqwest;
 use crate::config::Settings;
+use crate::utils::http_client::build_upstream_client;
 
 // Rust equivalent of Python vertex/model_loader.py
 
+/// How long a fetched model configuration is served before the URL is fetched again
+pub const MODELS_CONFIG_TTL: Duration = Duration::from_secs(3600);
+
+/// After a failed fetch, the previous (or built-in) list is served this long before retrying
+const FAILED_FETCH_RETRY: Duration = Duration::from_secs(60);
+
+const MODELS_CONFIG_TIMEOUT: Duration = Duration::from_secs(30);
+
 lazy_static::lazy_static! {
-    static ref MODEL_CACHE: Arc<RwLock<Option<ModelConfig>>> = Arc::new(RwLock::new(None));
+    /// Model configuration per models config URL
+    static ref MODEL_CACHE: Arc<RwLock<HashMap<String, CachedModels>>> = Arc::new(RwLock::new(HashMap::new()));
     static ref CACHE_LOCK: Arc<Mutex<()>> = Arc::new(Mutex::new(()));
 }
 
+#[derive(Debug, Clone)]
+struct CachedModels {
+    config: ModelConfig,
+    expires_at: Instant,
+}
+
 #[derive(Debug, Clone)]
 pub struct ModelConfig {
     pub vertex_models: Vec<String>,
@@ -33,72 +49,58 @@ impl ModelConfig {
             vertex_express_models,
         }
     }
-}
-
-/// Fetch and parse models configuration from remote URL
-pub async fn fetch_and_parse_models_config(settings: &Settings) -> Result<ModelConfig> {
-    // Get models config URL from settings or use default
-    let models_config_url = settings.models_config_url.as_ref()
-        .map(|s| s.as_str())
-        .or_else(|| std::env::var("VERTEX_MODELS_CONFIG_URL").ok().as_deref())
-        .unwrap_or("https://raw.githubusercontent.com/gzzhongqi/vertex2openai/refs/heads/main/vertexModels.json");
-
-    if models_config_url.is_empty() {
-        log::error!("MODELS_CONFIG_URL is not set in the environment/config");
-        log::info!("Using default model configuration with empty lists");
-        return Ok(ModelConfig::new());
-    }
-
-    log::info!("Fetching model configuration from: {}", models_config_url);
 
-    // Retry mechanism
-    let max_retries = 3;
-    let mut retry_delay = 1; // Initial delay 1 second
-
-    for retry in 0..max_retries {
-        match try_fetch_models_config(models_config_url).await {
-            Ok(config) => {
-                log::info!("Successfully fetched and parsed model configuration on attempt {}", retry + 1);
-                return Ok(config);
-            }
-            Err(e) => {
-                log::warn!("Attempt {} failed: {}", retry + 1, e);
-                if retry < max_retries - 1 {
-                    log::info!("Waiting {} seconds before retry...", retry_delay);
-                    tokio::time::sleep(tokio::time::Duration::from_secs(retry_delay)).await;
-                    retry_delay *= 2; // Exponential backoff
-                }
-            }
-        }
+    /// Served until the models config URL has been fetched successfully
+    pub fn builtin() -> Self {
+        let to_strings = |models: &[&str]| models.iter().map(|model| model.to_string()).collect();
+        Self::with_models(
+            to_strings(&[
+                "gemini-2.5-pro",
+                "gemini-2.5-flash",
+                "gemini-2.5-flash-lite",
+                "gemini-2.0-flash",
+                "gemini-2.0-flash-lite",
+            ]),
+            to_strings(&["gemini-2.5-pro", "gemini-2.5-flash", "gemini-2.0-flash", "gemini-2.0-flash-lite"]),
+        )
     }
-
-    log::error!("Failed to fetch model configuration after {} attempts, using empty configuration", max_retries);
-    Ok(ModelConfig::new())
 }
 
-/// Single attempt to fetch models config
-async fn try_fetch_models_config(url: &str) -> Result<ModelConfig> {
-    let client = reqwest::Client::builder()
-        .timeout(std::time::Duration::from_secs(30))
-        .build()?;
+/// Models config URL from settings, or the upstream default
+fn models_config_url(settings: &Settings) -> String {
+    crate::vertex::config::non_empty(&settings.vertex_models_config_url)
+        .unwrap_or_else(|| "https://raw.githubusercontent.com/gzzhongqi/vertex2openai/refs/heads/main/vertexModels.json".to_string())
+}
 
-    log::info!("Attempting to fetch model configuration");
-    let response = client.get(url).send().await?;
+/// Fetch and parse models configuration from remote URL
+pub async fn fetch_and_parse_models_config(settings: &Settings) -> Result<ModelConfig> {
+    let url = models_config_url(settings);
+    log::info!("Fetching model configuration from: {}", url);
 
+    let client = build_upstream_client(settings, MODELS_CONFIG_TIMEOUT)?;
+    let response = client.get(&url).send().await?;
     if !response.status().is_success() {
         return Err(anyhow!("HTTP error: {}", response.status()));
     }
 
     let response_text = response.text().await?;
     log::debug!("Received response, length: {} characters", response_text.len());
+    parse_models_config(&response_text)
+}
 
-    // Parse JSON response
-    let json_data: Value = serde_json::from_str(&response_text)
+/// Parse and validate a vertexModels.json document
+fn parse_models_config(text: &str) -> Result<ModelConfig> {
+    let json_data: Value = serde_json::from_str(text)
         .map_err(|e| anyhow!("Failed to parse JSON response: {}", e))?;
+    if !json_data.is_object() {
+        return Err(anyhow!("Model configuration must be a JSON object"));
+    }
 
-    // Extract model lists
     let vertex_models = extract_model_list(&json_data, "vertex_models")?;
     let vertex_express_models = extract_model_list(&json_data, "vertex_express_models")?;
+    if vertex_models.is_empty() && vertex_express_models.is_empty() {
+        return Err(anyhow!("Model configuration lists no models"));
+    }
 
     log::info!("Successfully parsed {} vertex models and {} vertex express models",
               vertex_models.len(), vertex_express_models.len());
@@ -112,7 +114,7 @@ fn extract_model_list(json_data: &Value, key: &str) -> Result<Vec<String>> {
         Some(Value::Array(models)) => {
             let model_list: Result<Vec<String>, _> = models
                 .iter()
-                .map(|v| v.as_str().ok_or_else(|| anyhow!("Model name is not a string")))
+                .map(|v| v.as_str().ok_or_else(|| anyhow!("Model name in '{}' is not a string", key)))
                 .map(|r| r.map(|s| s.to_string()))
                 .collect();
 
@@ -120,10 +122,7 @@ fn extract_model_list(json_data: &Value, key: &str) -> Result<Vec<String>> {
             log::debug!("Found {} models for key '{}'", models.len(), key);
             Ok(models)
         }
-        Some(_) => {
-            log::warn!("Key '{}' exists but is not an array", key);
-            Ok(Vec::new())
-        }
+        Some(_) => Err(anyhow!("Key '{}' is not an array", key)),
         None => {
             log::warn!("Key '{}' not found in configuration", key);
             Ok(Vec::new())
@@ -131,48 +130,52 @@ fn extract_model_list(json_data: &Value, key: &str) -> Result<Vec<String>> {
     }
 }
 
-/// Get cached model configuration or fetch if not available
+/// Get the cached model configuration, fetching it again once the TTL has passed.
+/// A failed fetch keeps serving the last good copy, or the built-in list if there is none.
 pub async fn get_models_config(settings: &Settings) -> Result<ModelConfig> {
     let _lock = CACHE_LOCK.lock().await;
+    let url = models_config_url(settings);
 
-    // Try to get from cache first
     {
         let cache = MODEL_CACHE.read().await;
-        if let Some(ref config) = *cache {
-            log::debug!("Returning cached model configuration");
-            return Ok(config.clone());
+        if let Some(cached) = cache.get(&url) {
+            if cached.expires_at > Instant::now() {
+                log::debug!("Returning cached model configuration");
+                return Ok(cached.config.clone());
+            }
         }
     }
 
-    // Cache miss, fetch new configuration
-    log::info!("Model cache is empty, fetching configuration");
-    let config = fetch_and_parse_models_config(settings).await?;
-
-    // Update cache
-    {
-        let mut cache = MODEL_CACHE.write().await;
-        *cache = Some(config.clone());
+    match fetch_and_parse_models_config(settings).await {
+        Ok(config) => Ok(store(url, config, MODELS_CONFIG_TTL).await),
+        Err(e) => {
+            log::error!("Failed to load model configuration from {}: {}", url, e);
+            let fallback = MODEL_CACHE.read().await.get(&url).map(|cached| cached.config.clone());
+            let config = fallback.unwrap_or_else(|| {
+                log::warn!("Using the built-in Vertex AI model list");
+                ModelConfig::builtin()
+            });
+            Ok(store(url, config, FAILED_FETCH_RETRY).await)
+        }
     }
+}
 
-    log::info!("Model configuration cached successfully");
-    Ok(config)
+async fn store(url: String, config: ModelConfig, ttl: Duration) -> ModelConfig {
+    let cached = CachedModels { config: config.clone(), expires_at: Instant::now() + ttl };
+    MODEL_CACHE.write().await.insert(url, cached);
+    config
 }
 
-/// Refresh the models configuration cache
-pub async fn refresh_models_config_cache(settings: &Settings) -> Result<()> {
+/// Fetch the model configuration now. On failure the cache is left as it was.
+pub async fn refresh_models_config_cache(settings: &Settings) -> Result<ModelConfig> {
     let _lock = CACHE_LOCK.lock().await;
 
     log::info!("Refreshing model configuration cache");
     let config = fetch_and_parse_models_config(settings).await?;
-
-    // Update cache
-    {
-        let mut cache = MODEL_CACHE.write().await;
-        *cache = Some(config);
-    }
+    let config = store(models_config_url(settings), config, MODELS_CONFIG_TTL).await;
 
     log::info!("Model configuration cache refreshed successfully");
-    Ok(())
+    Ok(config)
 }
 
 /// Get vertex models list
@@ -203,7 +206,7 @@ pub async fn is_vertex_express_model(settings: &Settings, model_name: &str) -> R
 pub async fn clear_models_cache() {
     let _lock = CACHE_LOCK.lock().await;
     let mut cache = MODEL_CACHE.write().await;
-    *cache = None;
+    cache.clear();
     log::info!("Model configuration cache cleared");
 }
 
@@ -259,6 +262,62 @@ mod tests {
         assert!(missing.is_empty());
     }
 
+    #[test]
+    fn test_parse_models_config_rejects_malformed_documents() {
+        assert!(parse_models_config("{\"vertex_models\": [\"gemini-2.5-pro\"]}").is_ok());
+        assert!(parse_models_config("{\"vertex_models\": [\"gemini-2.5-pro\"").is_err());
+        assert!(parse_models_config("[\"gemini-2.5-pro\"]").is_err());
+        assert!(parse_models_config("{\"vertex_models\": \"gemini-2.5-pro\"}").is_err());
+        assert!(parse_models_config("{\"vertex_models\": [1]}").is_err());
+        assert!(parse_models_config("{}").is_err());
+    }
+
+    #[tokio::test]
+    async fn test_cache_keeps_last_good_copy() {
+        use axum::{extract::State, routing::get, Router};
+        use std::sync::atomic::{AtomicUsize, Ordering};
+
+        // First reply is valid, every later one is truncated JSON
+        let fetches = Arc::new(AtomicUsize::new(0));
+        let app = Router::new()
+            .route("/models.json", get(|State(fetches): State<Arc<AtomicUsize>>| async move {
+                match fetches.fetch_add(1, Ordering::SeqCst) {
+                    0 => r#"{"vertex_models": ["gemini-2.5-pro"], "vertex_express_models": ["gemini-2.5-flash"]}"#,
+                    _ => r#"{"vertex_models": ["#,
+                }
+            }))
+            .with_state(fetches.clone());
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}/models.json", listener.local_addr().unwrap());
+        tokio::spawn(async move { axum::serve(listener, app).await.unwrap() });
+        let settings = Settings { vertex_models_config_url: url.clone(), ..Default::default() };
+
+        assert_eq!(get_vertex_models(&settings).await.unwrap(), vec!["gemini-2.5-pro".to_string()]);
+        // Served from cache within the TTL
+        assert!(is_vertex_express_model(&settings, "gemini-2.5-flash").await.unwrap());
+        assert_eq!(fetches.load(Ordering::SeqCst), 1);
+
+        // A malformed reply is rejected and the last good copy stays in place
+        assert!(refresh_models_config_cache(&settings).await.is_err());
+        assert_eq!(get_vertex_models(&settings).await.unwrap(), vec!["gemini-2.5-pro".to_string()]);
+
+        // Once expired, a failed fetch still serves the last good copy
+        MODEL_CACHE.write().await.get_mut(&url).unwrap().expires_at = Instant::now();
+        assert_eq!(get_vertex_models(&settings).await.unwrap(), vec!["gemini-2.5-pro".to_string()]);
+        assert_eq!(fetches.load(Ordering::SeqCst), 3);
+    }
+
+    #[tokio::test]
+    async fn test_unreachable_url_serves_builtin_list() {
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}/models.json", listener.local_addr().unwrap());
+        drop(listener);
+        let settings = Settings { vertex_models_config_url: url, ..Default::default() };
+
+        let models = get_vertex_models(&settings).await.unwrap();
+        assert_eq!(models, ModelConfig::builtin().vertex_models);
+    }
+
     #[tokio::test]
     async fn test_clear_models_cache() {
         clear_models_cache().await;
@@ -58,6 +58,9 @@ pub struct OpenAIRequest {
     /// Allow extra fields to pass through without causing validation errors
     #[serde(flatten)]
     pub extra: HashMap<String, serde_json::Value>,
+    /// Incoming headers sent on with the upstream call (`forward_request_headers`)
+    #[serde(skip)]
+    pub forwarded_headers: Vec<(String, String)>,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
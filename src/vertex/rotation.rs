@@ -0,0 +1,259 @@
+use reqwest::StatusCode;
+use serde_json::{json, Value};
+use std::collections::{HashMap, VecDeque};
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use crate::vertex::credentials_manager::ServiceAccountKey;
+
+/// How long a credential is skipped after Vertex answers 429 or 403 for it
+pub const CREDENTIAL_COOLDOWN: Duration = Duration::from_secs(60);
+
+#[derive(Debug, Clone)]
+pub struct CredentialStats {
+    pub client_email: String,
+    pub project_id: String,
+    pub successes: u64,
+    pub failures: u64,
+    pub consecutive_failures: u32,
+    pub last_used: Option<chrono::DateTime<chrono::Utc>>,
+    pub cooldown_until: Option<Instant>,
+    /// The token endpoint rejected the key; it stays out of rotation until the next rescan
+    pub invalid: bool,
+}
+
+impl CredentialStats {
+    fn new(key: &ServiceAccountKey) -> Self {
+        Self {
+            client_email: key.client_email.clone(),
+            project_id: key.project_id.clone(),
+            successes: 0,
+            failures: 0,
+            consecutive_failures: 0,
+            last_used: None,
+            cooldown_until: None,
+            invalid: false,
+        }
+    }
+
+    fn cooldown_remaining(&self) -> Option<Duration> {
+        self.cooldown_until
+            .map(|until| until.saturating_duration_since(Instant::now()))
+            .filter(|remaining| !remaining.is_zero())
+    }
+
+    pub fn is_cooling_down(&self) -> bool {
+        self.cooldown_remaining().is_some()
+    }
+
+    /// Public view of the stats; the only identity shown is the masked email and project
+    pub fn summary(&self) -> Value {
+        let status = if self.invalid {
+            "invalid"
+        } else if self.is_cooling_down() {
+            "cooling_down"
+        } else {
+            "available"
+        };
+
+        json!({
+            "client_email": mask_email(&self.client_email),
+            "project_id": self.project_id,
+            "status": status,
+            "successes": self.successes,
+            "failures": self.failures,
+            "consecutive_failures": self.consecutive_failures,
+            "cooldown_remaining_seconds": self.cooldown_remaining().map(|remaining| remaining.as_secs()),
+            "last_used": self.last_used.map(|time| time.to_rfc3339())
+        })
+    }
+}
+
+#[derive(Debug, Default)]
+struct RotationState {
+    queue: VecDeque<PathBuf>,
+    stats: HashMap<PathBuf, CredentialStats>,
+}
+
+/// Round-robin order over the service account files, with per-file health
+#[derive(Debug, Default)]
+pub struct CredentialRotation {
+    state: Mutex<RotationState>,
+}
+
+impl CredentialRotation {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Replace the rotation with `credentials`, dropping all previous stats
+    pub fn reset(&self, credentials: Vec<(PathBuf, ServiceAccountKey)>) {
+        let mut state = self.lock();
+        state.queue = credentials.iter().map(|(file, _)| file.clone()).collect();
+        state.stats = credentials
+            .iter()
+            .map(|(file, key)| (file.clone(), CredentialStats::new(key)))
+            .collect();
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.lock().queue.is_empty()
+    }
+
+    /// Next credential in turn, skipping invalid ones and those cooling down.
+    /// When every valid credential is cooling down, the one that recovers first is used.
+    pub fn next(&self) -> Option<PathBuf> {
+        let mut state = self.lock();
+        let RotationState { queue, stats } = &mut *state;
+
+        for _ in 0..queue.len() {
+            let file = queue.pop_front()?;
+            queue.push_back(file.clone());
+            match stats.get(&file) {
+                Some(stats) if stats.invalid || stats.is_cooling_down() => continue,
+                _ => return Some(file),
+            }
+        }
+
+        let fallback = stats
+            .iter()
+            .filter(|(_, stats)| !stats.invalid)
+            .min_by_key(|(_, stats)| stats.cooldown_until)
+            .map(|(file, _)| file.clone());
+        if fallback.is_some() {
+            log::warn!("All Vertex AI credentials are cooling down, using the one that recovers first");
+        }
+        fallback
+    }
+
+    pub fn record_success(&self, file: &Path) {
+        self.update(file, |stats| {
+            stats.successes += 1;
+            stats.consecutive_failures = 0;
+            stats.cooldown_until = None;
+        });
+    }
+
+    /// Count a failed call; quota (429) and permission (403) errors also start a cooldown
+    pub fn record_failure(&self, file: &Path, status: Option<StatusCode>) {
+        self.update(file, |stats| {
+            stats.failures += 1;
+            stats.consecutive_failures += 1;
+            if matches!(status, Some(StatusCode::TOO_MANY_REQUESTS | StatusCode::FORBIDDEN)) {
+                log::warn!(
+                    "Cooling down Vertex AI credential {} for {:?}",
+                    mask_email(&stats.client_email),
+                    CREDENTIAL_COOLDOWN
+                );
+                stats.cooldown_until = Some(Instant::now() + CREDENTIAL_COOLDOWN);
+            }
+        });
+    }
+
+    pub fn mark_invalid(&self, file: &Path) {
+        self.update(file, |stats| {
+            stats.failures += 1;
+            stats.consecutive_failures += 1;
+            stats.invalid = true;
+        });
+    }
+
+    /// Stats of every credential, in file order
+    pub fn stats(&self) -> Vec<CredentialStats> {
+        let state = self.lock();
+        let mut files: Vec<&PathBuf> = state.stats.keys().collect();
+        files.sort();
+        files.into_iter().map(|file| state.stats[file].clone()).collect()
+    }
+
+    fn update(&self, file: &Path, apply: impl FnOnce(&mut CredentialStats)) {
+        if let Some(stats) = self.lock().stats.get_mut(file) {
+            stats.last_used = Some(chrono::Utc::now());
+            apply(stats);
+        }
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, RotationState> {
+        self.state.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+/// `proxy@project.iam.gserviceaccount.com` -> `pr***@project.iam.gserviceaccount.com`
+pub fn mask_email(email: &str) -> String {
+    match email.split_once('@') {
+        Some((local, domain)) => {
+            let visible: String = local.chars().take(2).collect();
+            format!("{}***@{}", visible, domain)
+        }
+        None => "***".to_string(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::vertex::credentials_manager::test_service_account;
+
+    fn rotation(count: usize) -> (CredentialRotation, Vec<PathBuf>) {
+        let credentials: Vec<(PathBuf, ServiceAccountKey)> = (1..=count)
+            .map(|index| {
+                let mut value = test_service_account("http://127.0.0.1/token");
+                value["client_email"] = json!(format!("sa{}@project-{}.iam.gserviceaccount.com", index, index));
+                value["project_id"] = json!(format!("project-{}", index));
+                let file = PathBuf::from(format!("service_account_{}.json", index));
+                (file, ServiceAccountKey::from_value(&value).unwrap())
+            })
+            .collect();
+        let files = credentials.iter().map(|(file, _)| file.clone()).collect();
+        let rotation = CredentialRotation::new();
+        rotation.reset(credentials);
+        (rotation, files)
+    }
+
+    #[test]
+    fn test_round_robin_skips_cooling_down_and_invalid() {
+        let (rotation, files) = rotation(3);
+        let picks: Vec<PathBuf> = (0..6).map(|_| rotation.next().unwrap()).collect();
+        assert_eq!(picks, [files.clone(), files.clone()].concat());
+
+        rotation.record_failure(&files[1], Some(StatusCode::TOO_MANY_REQUESTS));
+        rotation.mark_invalid(&files[2]);
+        assert!((0..4).all(|_| rotation.next().unwrap() == files[0]));
+
+        // A server error counts as a failure but keeps the credential in rotation
+        rotation.record_failure(&files[0], Some(StatusCode::INTERNAL_SERVER_ERROR));
+        assert_eq!(rotation.next().unwrap(), files[0]);
+
+        let stats = rotation.stats();
+        assert!(stats[1].is_cooling_down());
+        assert_eq!(stats[0].failures, 1);
+        assert_eq!(stats[1].summary()["status"], "cooling_down");
+        assert_eq!(stats[2].summary()["status"], "invalid");
+    }
+
+    #[test]
+    fn test_all_cooling_down_falls_back() {
+        let (rotation, files) = rotation(2);
+        rotation.record_failure(&files[0], Some(StatusCode::FORBIDDEN));
+        rotation.record_failure(&files[1], Some(StatusCode::TOO_MANY_REQUESTS));
+        assert_eq!(rotation.next().unwrap(), files[0]);
+
+        rotation.record_success(&files[0]);
+        assert!(!rotation.stats()[0].is_cooling_down());
+
+        rotation.mark_invalid(&files[0]);
+        rotation.mark_invalid(&files[1]);
+        assert!(rotation.next().is_none());
+    }
+
+    #[test]
+    fn test_summary_masks_email() {
+        let (rotation, _) = rotation(1);
+        let summary = rotation.stats()[0].summary();
+        assert_eq!(summary["client_email"], "sa***@project-1.iam.gserviceaccount.com");
+        assert_eq!(summary["project_id"], "project-1");
+        assert_eq!(summary["status"], "available");
+        assert_eq!(mask_email("not-an-email"), "***");
+    }
+}
@@ -1,37 +1,67 @@
 use serde_json::{Value, json};
-use anyhow::Result;
+use anyhow::{Result, anyhow};
+use futures_util::{future, stream, stream::BoxStream, StreamExt};
+use std::time::Duration;
+use tokio::sync::mpsc;
+use tokio_stream::wrappers::ReceiverStream;
 use crate::config::Settings;
+use crate::utils::logging::vertex_log_event;
+use crate::utils::stats::end_user_id;
 use crate::vertex::{
+    client::VertexClient,
     models::{OpenAIRequest, GeminiCompletionRequest},
-    message_processing::{create_gemini_prompt, convert_to_openai_format, deobfuscate_text},
+    message_processing::{
+        convert_chunk_to_openai, convert_to_openai_format, create_final_chunk, create_gemini_prompt,
+        extract_gemini_content,
+    },
     api_helpers::{create_generation_config, create_openai_error_response, validate_request_parameters},
-    credentials_manager::CredentialManager,
     vertex_ai_init::get_global_fallback_client,
 };
 
 // Rust equivalent of Python vertex/routes/chat_api.py
 
+/// SSE comment sent while fake streaming waits for the upstream reply
+const KEEPALIVE_FRAME: &str = ": ping\n\n";
+
+/// A chat completion reply: one JSON body, or SSE frames when the client asked to stream
+pub enum ChatCompletionOutput {
+    Json(Value),
+    Stream(BoxStream<'static, String>),
+}
+
 /// Handle chat completions request
 pub async fn handle_chat_completion(
     settings: &Settings,
     request: OpenAIRequest,
-) -> Result<Value> {
+) -> Result<ChatCompletionOutput> {
     log::info!("Processing chat completion request for model: {}", request.model);
+    let model = request.model.clone();
+    let streaming = request.stream.unwrap_or(false);
+
+    let result = async {
+        // Validate request parameters
+        validate_request_parameters(&request).map_err(|e| anyhow!("invalid request: {}", e))?;
 
-    // Validate request parameters
-    validate_request_parameters(&request)?;
+        // Log request details
+        log::debug!("Request parameters: temp={:?}, max_tokens={:?}, stream={:?}",
+                   request.temperature, request.max_tokens, request.stream);
 
-    // Log request details
-    log::debug!("Request parameters: temp={:?}, max_tokens={:?}, stream={:?}",
-               request.temperature, request.max_tokens, request.stream);
+        // Check if streaming is requested
+        if streaming {
+            return handle_streaming_chat_completion(settings, request).await;
+        }
 
-    // Check if streaming is requested
-    if request.stream.unwrap_or(false) {
-        return handle_streaming_chat_completion(settings, request).await;
+        // Handle non-streaming request
+        handle_non_streaming_chat_completion(settings, request).await.map(ChatCompletionOutput::Json)
     }
+    .await;
 
-    // Handle non-streaming request
-    handle_non_streaming_chat_completion(settings, request).await
+    match &result {
+        Ok(_) if streaming => vertex_log_event("info", "chat_completion", "streaming", &format!("Streaming chat completion for {}", model)),
+        Ok(_) => vertex_log_event("info", "chat_completion", "success", &format!("Chat completion for {} succeeded", model)),
+        Err(e) => vertex_log_event("error", "chat_completion", "failed", &format!("Chat completion for {} failed: {:#}", model, e)),
+    }
+    result
 }
 
 /// Handle non-streaming chat completion
@@ -41,32 +71,133 @@ async fn handle_non_streaming_chat_completion(
 ) -> Result<Value> {
     log::debug!("Processing non-streaming chat completion");
 
-    // Convert OpenAI messages to Gemini format
-    let gemini_messages = create_gemini_prompt(&request.messages)?;
-    let generation_config = create_generation_config(&request);
-
-    // For now, return a placeholder response since we don't have the actual Gemini client integration
-    // In a full implementation, this would call the Gemini API
-    let mock_response = create_mock_chat_response(&request.model, &request.messages);
+    let client = vertex_client(settings).await?.with_forwarded_headers(request.forwarded_headers.clone());
+    let response = send_chat_completion(&client, &request).await?;
 
     log::info!("Chat completion processed successfully");
-    Ok(mock_response)
+    Ok(response)
+}
+
+/// Gemini payload of an OpenAI chat request. The end user named in `user` goes into the
+/// request's labels, hashed since label values only allow short lowercase ids
+fn request_payload(request: &OpenAIRequest) -> Result<Value> {
+    let mut payload = json!({
+        "contents": create_gemini_prompt(&request.messages)?,
+        "generationConfig": create_generation_config(request),
+    });
+    if let Some(user) = request.extra.get("user").and_then(Value::as_str).filter(|user| !user.is_empty()) {
+        payload["labels"] = json!({ "end_user": end_user_id(user) });
+    }
+    Ok(payload)
+}
+
+/// Convert an OpenAI chat request, send it to Vertex AI and convert the reply back
+pub async fn send_chat_completion(client: &VertexClient, request: &OpenAIRequest) -> Result<Value> {
+    let payload = request_payload(request)?;
+
+    let response = client.generate_content(&request.model, &payload).await?;
+    convert_to_openai_format(&response, &request.model, openai_usage(&response).as_ref())
 }
 
 /// Handle streaming chat completion
 async fn handle_streaming_chat_completion(
     settings: &Settings,
     request: OpenAIRequest,
-) -> Result<Value> {
+) -> Result<ChatCompletionOutput> {
     log::debug!("Processing streaming chat completion");
 
-    // Convert OpenAI messages to Gemini format
-    let _gemini_messages = create_gemini_prompt(&request.messages)?;
-    let _generation_config = create_generation_config(&request);
+    let client = vertex_client(settings).await?.with_forwarded_headers(request.forwarded_headers.clone());
+    stream_chat_completion(&client, &request).await.map(ChatCompletionOutput::Stream)
+}
+
+/// Stream a chat completion as OpenAI SSE frames, ending with `[DONE]`.
+/// With fake streaming enabled the reply is fetched in one call and replayed in chunks.
+pub async fn stream_chat_completion(client: &VertexClient, request: &OpenAIRequest) -> Result<BoxStream<'static, String>> {
+    let payload = request_payload(request)?;
+
+    if client.config().fake_streaming_enabled {
+        return Ok(fake_stream(client.clone(), request.model.clone(), payload));
+    }
+
+    let model = request.model.clone();
+    let final_model = model.clone();
+    let events = client.stream_generate_content(&request.model, &payload).await?;
+    let frames = events
+        .filter_map(move |event| {
+            let frame = match event {
+                Ok(event) => {
+                    let text = extract_gemini_content(&event).unwrap_or_default();
+                    if text.is_empty() {
+                        None
+                    } else {
+                        convert_chunk_to_openai(&text, &model, false).ok()
+                    }
+                }
+                Err(e) => {
+                    log::error!("Vertex AI stream failed: {:#}", e);
+                    Some(error_frame(&e))
+                }
+            };
+            future::ready(frame)
+        })
+        .chain(stream::once(async move { create_final_chunk(&final_model) }));
+
+    Ok(frames.boxed())
+}
 
-    // For streaming, we would typically return a streaming response
-    // For now, return an error indicating streaming is not yet implemented
-    Err(anyhow::anyhow!("Streaming is not yet implemented in the Rust version"))
+/// Send keepalive frames while a non-streaming call runs, then replay its text in chunks
+fn fake_stream(client: VertexClient, model: String, payload: Value) -> BoxStream<'static, String> {
+    let config = client.config().clone();
+    let (tx, rx) = mpsc::channel(16);
+
+    tokio::spawn(async move {
+        let call = client.generate_content(&model, &payload);
+        tokio::pin!(call);
+        let mut keepalive = tokio::time::interval(Duration::from_secs_f64(config.fake_streaming_interval_seconds.max(0.1)));
+        keepalive.tick().await;
+
+        let result = loop {
+            tokio::select! {
+                result = &mut call => break result,
+                _ = keepalive.tick() => {
+                    if tx.send(KEEPALIVE_FRAME.to_string()).await.is_err() {
+                        return;
+                    }
+                }
+            }
+        };
+
+        let response = match result {
+            Ok(response) => response,
+            Err(e) => {
+                log::error!("Vertex AI fake streaming request failed: {:#}", e);
+                let _ = tx.send(error_frame(&e)).await;
+                return;
+            }
+        };
+
+        let text: Vec<char> = extract_gemini_content(&response).unwrap_or_default().chars().collect();
+        let delay = Duration::from_secs_f64(config.fake_streaming_delay_per_chunk.max(0.0));
+        for (index, chunk) in text.chunks(config.fake_streaming_chunk_size.max(1)).enumerate() {
+            if index > 0 && !delay.is_zero() {
+                tokio::time::sleep(delay).await;
+            }
+            let chunk: String = chunk.iter().collect();
+            if let Ok(frame) = convert_chunk_to_openai(&chunk, &model, false) {
+                if tx.send(frame).await.is_err() {
+                    return;
+                }
+            }
+        }
+        let _ = tx.send(create_final_chunk(&model)).await;
+    });
+
+    ReceiverStream::new(rx).boxed()
+}
+
+/// Error reported inside an already started stream, followed by `[DONE]`
+fn error_frame(error: &anyhow::Error) -> String {
+    format!("data: {}\n\ndata: [DONE]\n\n", handle_chat_completion_error(error))
 }
 
 /// Handle completion request (legacy endpoint)
@@ -78,77 +209,54 @@ pub async fn handle_completion(
 
     request.log_request();
 
-    // For now, return a placeholder response
-    let mock_response = create_mock_completion_response(&request.model, &request.prompt);
-
-    log::info!("Completion processed successfully");
-    Ok(mock_response)
-}
-
-/// Create a mock chat completion response for testing
-fn create_mock_chat_response(model: &str, messages: &[crate::vertex::models::OpenAIMessage]) -> Value {
-    let last_message = messages.last()
-        .map(|m| match &m.content {
-            crate::vertex::models::MessageContent::Text(text) => text.clone(),
-            crate::vertex::models::MessageContent::Parts(_) => "I received your message with multiple parts.".to_string(),
-        })
-        .unwrap_or_else(|| "No message received.".to_string());
-
-    let response_content = format!("This is a mock response to: {}", last_message);
+    let payload = json!({
+        "contents": [{"role": "user", "parts": [{"text": request.prompt}]}],
+        "generationConfig": {
+            "temperature": request.temperature,
+            "topP": request.top_p,
+            "topK": request.top_k,
+            "maxOutputTokens": request.max_output_tokens,
+        },
+    });
 
-    json!({
-        "id": format!("chatcmpl-{}", uuid::Uuid::new_v4()),
-        "object": "chat.completion",
-        "created": std::time::SystemTime::now()
-            .duration_since(std::time::UNIX_EPOCH)
-            .unwrap()
-            .as_secs(),
-        "model": model,
-        "choices": [{
-            "index": 0,
-            "message": {
-                "role": "assistant",
-                "content": response_content
-            },
-            "finish_reason": "stop"
-        }],
-        "usage": {
-            "prompt_tokens": estimate_tokens(&last_message),
-            "completion_tokens": estimate_tokens(&response_content),
-            "total_tokens": estimate_tokens(&last_message) + estimate_tokens(&response_content)
-        }
-    })
-}
+    let client = vertex_client(settings).await?;
+    let response = client.generate_content(&request.model, &payload).await?;
+    let chat = convert_to_openai_format(&response, &request.model, openai_usage(&response).as_ref())?;
 
-/// Create a mock completion response for testing
-fn create_mock_completion_response(model: &str, prompt: &str) -> Value {
-    let response_content = format!("This is a mock completion for: {}", prompt);
-
-    json!({
+    log::info!("Completion processed successfully");
+    Ok(json!({
         "id": format!("cmpl-{}", uuid::Uuid::new_v4()),
         "object": "text_completion",
-        "created": std::time::SystemTime::now()
-            .duration_since(std::time::UNIX_EPOCH)
-            .unwrap()
-            .as_secs(),
-        "model": model,
+        "created": chat["created"],
+        "model": request.model,
         "choices": [{
-            "text": response_content,
+            "text": chat["choices"][0]["message"]["content"],
             "index": 0,
             "logprobs": null,
             "finish_reason": "stop"
         }],
-        "usage": {
-            "prompt_tokens": estimate_tokens(prompt),
-            "completion_tokens": estimate_tokens(&response_content),
-            "total_tokens": estimate_tokens(prompt) + estimate_tokens(&response_content)
-        }
-    })
+        "usage": chat["usage"]
+    }))
 }
 
-/// Estimate token count (rough approximation)
-fn estimate_tokens(text: &str) -> i32 {
-    ((text.len() as f64) / 4.0).ceil() as i32
+/// Build a client from the credentials loaded by `init_vertex_ai`
+async fn vertex_client(settings: &Settings) -> Result<VertexClient> {
+    match get_global_fallback_client().await {
+        Some(client) => VertexClient::new(settings, &client),
+        None => Err(anyhow!("Vertex AI client not initialized")),
+    }
+}
+
+/// OpenAI usage block from Gemini `usageMetadata`, if the response has one
+fn openai_usage(response: &Value) -> Option<Value> {
+    let usage = response.get("usageMetadata")?;
+    let prompt_tokens = usage["promptTokenCount"].as_u64().unwrap_or(0);
+    let completion_tokens = usage["candidatesTokenCount"].as_u64().unwrap_or(0);
+    Some(json!({
+        "prompt_tokens": prompt_tokens,
+        "completion_tokens": completion_tokens,
+        "total_tokens": usage["totalTokenCount"].as_u64().unwrap_or(prompt_tokens + completion_tokens)
+    }))
 }
 
 /// Validate model access and availability
@@ -181,6 +289,8 @@ pub fn handle_chat_completion_error(error: &anyhow::Error) -> Value {
 
     if error_message.contains("rate limit") || error_message.contains("quota") {
         create_openai_error_response(429, &error_message, "rate_limit_exceeded")
+    } else if error_message.contains("not initialized") {
+        create_openai_error_response(503, &error_message, "service_unavailable")
     } else if error_message.contains("authentication") || error_message.contains("credential") {
         create_openai_error_response(401, "Authentication failed", "authentication_error")
     } else if error_message.contains("not found") {
@@ -241,36 +351,189 @@ pub async fn get_request_metrics() -> Value {
 #[cfg(test)]
 mod tests {
     use super::*;
-    use crate::vertex::models::{OpenAIMessage, MessageContent};
-
-    #[test]
-    fn test_create_mock_chat_response() {
-        let messages = vec![
-            OpenAIMessage {
-                role: "user".to_string(),
-                content: MessageContent::Text("Hello, world!".to_string()),
-            }
-        ];
+    use crate::vertex::config::VertexConfig;
+    use crate::vertex::credentials_manager::{test_service_account, CredentialManager};
+    use crate::vertex::vertex_ai_init::VertexAIClient;
+    use axum::{
+        extract::State,
+        http::{HeaderMap, Uri},
+        response::{IntoResponse, Response},
+        routing::post,
+        Form, Json, Router,
+    };
+    use std::collections::HashMap;
+    use std::sync::{Arc, Mutex};
+    use tempfile::TempDir;
+
+    /// Path and query, authorization header and JSON body of a call to the mocked endpoint
+    type Call = (String, Option<String>, Value);
+
+    #[derive(Clone, Default)]
+    struct Captured {
+        token_forms: Arc<Mutex<Vec<HashMap<String, String>>>>,
+        calls: Arc<Mutex<Vec<Call>>>,
+        headers: Arc<Mutex<Vec<HeaderMap>>>,
+    }
+
+    async fn mock_token(State(captured): State<Captured>, Form(form): Form<HashMap<String, String>>) -> Json<Value> {
+        captured.token_forms.lock().unwrap().push(form);
+        Json(json!({"access_token": "ya29.mock-token", "expires_in": 3599, "token_type": "Bearer"}))
+    }
+
+    async fn mock_aiplatform(
+        State(captured): State<Captured>,
+        uri: Uri,
+        headers: HeaderMap,
+        Json(body): Json<Value>,
+    ) -> Response {
+        let auth = headers.get("authorization").map(|value| value.to_str().unwrap().to_string());
+        captured.calls.lock().unwrap().push((uri.to_string(), auth, body));
+        captured.headers.lock().unwrap().push(headers);
+
+        if uri.path().ends_with(":streamGenerateContent") {
+            let events = ["Hello ", "from ", "Vertex"]
+                .iter()
+                .map(|text| format!("data: {}\r\n\r\n", json!({"candidates": [{"content": {"role": "model", "parts": [{"text": text}]}}]})))
+                .collect::<String>();
+            return ([("content-type", "text/event-stream")], events).into_response();
+        }
+
+        Json(json!({
+            "candidates": [{"content": {"role": "model", "parts": [{"text": "Hello from Vertex"}]}, "finishReason": "STOP"}],
+            "usageMetadata": {"promptTokenCount": 7, "candidatesTokenCount": 4, "totalTokenCount": 11}
+        }))
+        .into_response()
+    }
+
+    /// Client whose token endpoint and aiplatform API are served by a local mock
+    async fn mock_client(configure: impl FnOnce(&mut VertexConfig)) -> (VertexClient, Captured, TempDir) {
+        let captured = Captured::default();
+        let app = Router::new()
+            .route("/token", post(mock_token))
+            .route("/v1/*path", post(mock_aiplatform))
+            .with_state(captured.clone());
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let base_url = format!("http://{}", listener.local_addr().unwrap());
+        tokio::spawn(async move { axum::serve(listener, app).await.unwrap() });
+
+        let credentials_dir = tempfile::tempdir().unwrap();
+        let credential_manager = CredentialManager::new(credentials_dir.path().to_path_buf());
+        credential_manager
+            .save_multiple_credentials_to_files(&[test_service_account(&format!("{}/token", base_url))])
+            .unwrap();
+
+        let settings = Settings { vertex_base_url: base_url, ..Default::default() };
+        let mut config = VertexConfig::from_settings(&settings);
+        config.project_id = None;
+        config.location = "us-central1".to_string();
+        config.fake_streaming_enabled = false;
+        configure(&mut config);
+        let client = VertexClient::new(&settings, &VertexAIClient::new(credential_manager, config)).unwrap();
+        (client, captured, credentials_dir)
+    }
+
+    fn chat_request(stream: bool) -> OpenAIRequest {
+        serde_json::from_value(json!({
+            "model": "gemini-2.5-pro",
+            "messages": [
+                {"role": "system", "content": "Be brief."},
+                {"role": "user", "content": "Hi"}
+            ],
+            "temperature": 0.5,
+            "max_tokens": 64,
+            "stream": stream,
+            "user": "alice"
+        }))
+        .unwrap()
+    }
+
+    /// Concatenated delta content of the SSE frames, and whether they ended with `[DONE]`
+    fn streamed_text(frames: &[String]) -> (String, bool) {
+        let body = frames.concat();
+        let text = body
+            .split("\n\n")
+            .filter_map(|frame| frame.strip_prefix("data: "))
+            .filter(|data| *data != "[DONE]")
+            .map(|data| serde_json::from_str::<Value>(data).unwrap())
+            .filter_map(|chunk| chunk["choices"][0]["delta"]["content"].as_str().map(str::to_string))
+            .collect();
+        (text, body.ends_with("data: [DONE]\n\n"))
+    }
+
+    #[tokio::test]
+    async fn test_chat_completion_against_mocked_aiplatform() {
+        let (client, captured, _dir) = mock_client(|_| {}).await;
+        let response = send_chat_completion(&client, &chat_request(false)).await.unwrap();
+
+        let forms = captured.token_forms.lock().unwrap().clone();
+        assert_eq!(forms.len(), 1);
+        assert_eq!(forms[0]["grant_type"], "urn:ietf:params:oauth:grant-type:jwt-bearer");
+        assert_eq!(forms[0]["assertion"].split('.').count(), 3);
+
+        let calls = captured.calls.lock().unwrap().clone();
+        let (path, auth, body) = &calls[0];
+        assert_eq!(
+            path,
+            "/v1/projects/test-project/locations/us-central1/publishers/google/models/gemini-2.5-pro:generateContent"
+        );
+        assert_eq!(auth.as_deref(), Some("Bearer ya29.mock-token"));
+        assert_eq!(body["contents"][1], json!({"role": "user", "parts": [{"text": "Hi"}]}));
+        assert_eq!(body["generationConfig"]["max_output_tokens"], 64);
+        assert_eq!(body["labels"]["end_user"], end_user_id("alice"));
 
-        let response = create_mock_chat_response("test-model", &messages);
         assert_eq!(response["object"], "chat.completion");
-        assert_eq!(response["model"], "test-model");
-        assert!(response["choices"].is_array());
+        assert_eq!(response["choices"][0]["message"]["content"], "Hello from Vertex");
+        assert_eq!(response["usage"]["total_tokens"], 11);
+    }
+
+    #[tokio::test]
+    async fn test_only_whitelisted_headers_reach_aiplatform() {
+        let (client, captured, _dir) = mock_client(|_| {}).await;
+        let mut incoming = HeaderMap::new();
+        incoming.insert("x-goog-user-project", "billing-project".parse().unwrap());
+        incoming.insert("x-billing-label", "team-a".parse().unwrap());
+        incoming.insert("x-goog-api-key", "caller-key".parse().unwrap());
+        incoming.insert("authorization", "Bearer caller-token".parse().unwrap());
+        let allowed = ["X-Goog-User-Project", "x-goog-api-key", "authorization"].map(String::from);
+
+        let forwarded = crate::utils::browser::forwarded_headers(&incoming, &allowed);
+        assert_eq!(forwarded, [("x-goog-user-project".to_string(), "billing-project".to_string())]);
+        let client = client.with_forwarded_headers(forwarded);
+        send_chat_completion(&client, &chat_request(false)).await.unwrap();
+
+        let headers = captured.headers.lock().unwrap()[0].clone();
+        assert_eq!(headers["x-goog-user-project"], "billing-project");
+        assert!(headers.get("x-billing-label").is_none());
+        assert!(headers.get("x-goog-api-key").is_none());
+        assert_eq!(headers["authorization"], "Bearer ya29.mock-token");
     }
 
-    #[test]
-    fn test_create_mock_completion_response() {
-        let response = create_mock_completion_response("test-model", "Hello");
-        assert_eq!(response["object"], "text_completion");
-        assert_eq!(response["model"], "test-model");
-        assert!(response["choices"].is_array());
+    #[tokio::test]
+    async fn test_streaming_chat_completion() {
+        let (client, captured, _dir) = mock_client(|_| {}).await;
+        let frames: Vec<String> = stream_chat_completion(&client, &chat_request(true)).await.unwrap().collect().await;
+
+        let calls = captured.calls.lock().unwrap().clone();
+        assert!(calls[0].0.ends_with("/models/gemini-2.5-pro:streamGenerateContent?alt=sse"));
+        assert_eq!(streamed_text(&frames), ("Hello from Vertex".to_string(), true));
+        assert_eq!(frames.len(), 4);
     }
 
-    #[test]
-    fn test_estimate_tokens() {
-        assert_eq!(estimate_tokens("hello"), 2);
-        assert_eq!(estimate_tokens("hello world"), 3);
-        assert_eq!(estimate_tokens(""), 0);
+    #[tokio::test]
+    async fn test_fake_streaming_replays_full_reply_in_chunks() {
+        let (client, captured, _dir) = mock_client(|config| {
+            config.fake_streaming_enabled = true;
+            config.fake_streaming_chunk_size = 5;
+            config.fake_streaming_delay_per_chunk = 0.0;
+        })
+        .await;
+        let frames: Vec<String> = stream_chat_completion(&client, &chat_request(true)).await.unwrap().collect().await;
+
+        let calls = captured.calls.lock().unwrap().clone();
+        assert!(calls[0].0.ends_with(":generateContent"));
+        assert_eq!(streamed_text(&frames), ("Hello from Vertex".to_string(), true));
+        // "Hello from Vertex" is 17 characters: four content chunks plus the final chunk
+        assert_eq!(frames.iter().filter(|frame| frame.starts_with("data: ")).count(), 5);
     }
 
     #[tokio::test]
@@ -279,4 +542,4 @@ mod tests {
         assert_eq!(metrics["total_requests"], 0);
         assert!(metrics["models_used"].is_object());
     }
-}
\ No newline at end of file
+}
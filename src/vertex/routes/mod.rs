@@ -3,5 +3,3 @@
 pub mod models_api;
 pub mod chat_api;
 
-pub use models_api::*;
-pub use chat_api::*;
\ No newline at end of file
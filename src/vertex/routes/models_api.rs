@@ -1,50 +1,46 @@
 use serde_json::{Value, json};
 use anyhow::Result;
 use crate::config::Settings;
+use crate::vertex::express::{express_available, EXPRESS_MODEL_PREFIX};
 use crate::vertex::model_loader::{get_vertex_models, get_vertex_express_models, refresh_models_config_cache};
 
 // Rust equivalent of Python vertex/routes/models_api.py
 
+/// Model ids served through Vertex AI: the configured models, plus an `[EXPRESS]`
+/// variant of each express model when express keys are available
+pub async fn vertex_model_ids(settings: &Settings) -> Result<Vec<String>> {
+    let mut ids = get_vertex_models(settings).await?;
+    if express_available(settings) {
+        let express_models = get_vertex_express_models(settings).await?;
+        ids.extend(express_models.iter().map(|model| format!("{}{}", EXPRESS_MODEL_PREFIX, model)));
+    }
+    Ok(ids)
+}
+
+fn model_entry(model_id: &str) -> Value {
+    let model_type = if model_id.starts_with(EXPRESS_MODEL_PREFIX) { "vertex_express" } else { "vertex" };
+    json!({
+        "id": model_id,
+        "object": "model",
+        "created": 1677610602, // Placeholder timestamp
+        "owned_by": "google",
+        "permission": [],
+        "root": model_id,
+        "parent": null,
+        "max_tokens": 32768, // Default max tokens for Vertex models
+        "type": model_type
+    })
+}
+
 /// List available models for Vertex generation
 /// Returns a list of models in OpenAI-compatible format
 pub async fn list_models(settings: &Settings) -> Result<Value> {
     log::info!("Retrieving list of available models");
 
-    // Get available models
-    let standard_models = get_vertex_models(settings).await?;
-    let express_models = get_vertex_express_models(settings).await?;
-
-    let mut all_models = Vec::new();
-
-    // Format standard models
-    for model_name in standard_models {
-        all_models.push(json!({
-            "id": model_name,
-            "object": "model",
-            "created": 1677610602, // Placeholder timestamp
-            "owned_by": "google",
-            "permission": [],
-            "root": model_name,
-            "parent": null,
-            "max_tokens": 32768, // Default max tokens for Vertex models
-            "type": "vertex"
-        }));
-    }
-
-    // Format express models
-    for model_name in express_models {
-        all_models.push(json!({
-            "id": model_name,
-            "object": "model",
-            "created": 1677610602, // Placeholder timestamp
-            "owned_by": "google",
-            "permission": [],
-            "root": model_name,
-            "parent": null,
-            "max_tokens": 32768, // Default max tokens for Vertex Express models
-            "type": "vertex_express"
-        }));
-    }
+    let all_models: Vec<Value> = vertex_model_ids(settings).await?
+        .iter()
+        .map(|model_id| model_entry(model_id))
+        .collect();
 
     log::info!("Found {} total models ({} standard, {} express)",
               all_models.len(),
@@ -61,81 +57,39 @@ pub async fn list_models(settings: &Settings) -> Result<Value> {
 pub async fn get_model_info(settings: &Settings, model_id: &str) -> Result<Value> {
     log::debug!("Getting info for model: {}", model_id);
 
-    let standard_models = get_vertex_models(settings).await?;
-    let express_models = get_vertex_express_models(settings).await?;
-
-    // Check if model exists in standard models
-    if standard_models.contains(&model_id.to_string()) {
-        return Ok(json!({
-            "id": model_id,
-            "object": "model",
-            "created": 1677610602,
-            "owned_by": "google",
-            "permission": [],
-            "root": model_id,
-            "parent": null,
-            "max_tokens": 32768,
-            "type": "vertex"
-        }));
-    }
-
-    // Check if model exists in express models
-    if express_models.contains(&model_id.to_string()) {
-        return Ok(json!({
-            "id": model_id,
-            "object": "model",
-            "created": 1677610602,
-            "owned_by": "google",
-            "permission": [],
-            "root": model_id,
-            "parent": null,
-            "max_tokens": 32768,
-            "type": "vertex_express"
-        }));
+    if is_model_available(settings, model_id).await? {
+        return Ok(model_entry(model_id));
     }
 
     Err(anyhow::anyhow!("Model '{}' not found", model_id))
 }
 
-/// Refresh models configuration cache
+/// Fetch the models configuration again, keeping the last good copy on failure
 pub async fn refresh_models_cache(settings: &Settings) -> Result<Value> {
     log::info!("Refreshing models configuration cache");
 
-    refresh_models_config_cache(settings).await?;
-
-    let standard_models = get_vertex_models(settings).await?;
-    let express_models = get_vertex_express_models(settings).await?;
+    let config = refresh_models_config_cache(settings).await?;
 
     Ok(json!({
         "status": "success",
         "message": "Model configuration cache refreshed successfully",
-        "vertex_models_count": standard_models.len(),
-        "vertex_express_models_count": express_models.len(),
-        "total_models": standard_models.len() + express_models.len()
+        "vertex_models_count": config.vertex_models.len(),
+        "vertex_express_models_count": config.vertex_express_models.len(),
+        "models": vertex_model_ids(settings).await?
     }))
 }
 
 /// Check if a model is available
 pub async fn is_model_available(settings: &Settings, model_id: &str) -> Result<bool> {
-    let standard_models = get_vertex_models(settings).await?;
-    let express_models = get_vertex_express_models(settings).await?;
-
-    Ok(standard_models.contains(&model_id.to_string()) ||
-       express_models.contains(&model_id.to_string()))
+    Ok(vertex_model_ids(settings).await?.iter().any(|id| id == model_id))
 }
 
 /// Get model type (vertex or vertex_express)
 pub async fn get_model_type(settings: &Settings, model_id: &str) -> Result<String> {
-    let standard_models = get_vertex_models(settings).await?;
-    let express_models = get_vertex_express_models(settings).await?;
-
-    if standard_models.contains(&model_id.to_string()) {
-        Ok("vertex".to_string())
-    } else if express_models.contains(&model_id.to_string()) {
-        Ok("vertex_express".to_string())
-    } else {
-        Err(anyhow::anyhow!("Model '{}' not found", model_id))
+    if !is_model_available(settings, model_id).await? {
+        return Err(anyhow::anyhow!("Model '{}' not found", model_id));
     }
+    Ok(model_entry(model_id)["type"].as_str().unwrap_or("vertex").to_string())
 }
 
 /// Get model capabilities and limitations
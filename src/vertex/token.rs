@@ -0,0 +1,183 @@
+use anyhow::Result;
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::sync::Mutex;
+
+use crate::utils::logging::vertex_log_event;
+use crate::vertex::credentials_manager::{AccessToken, ServiceAccountKey};
+
+/// Tokens are refreshed once less than this much lifetime remains
+pub const TOKEN_REFRESH_MARGIN: Duration = Duration::from_secs(300);
+
+type TokenSlot = Arc<Mutex<Option<AccessToken>>>;
+
+/// Access tokens per service account, so a JWT is only signed and exchanged when the
+/// cached token is about to expire
+#[derive(Debug, Default)]
+pub struct TokenCache {
+    slots: std::sync::Mutex<HashMap<String, TokenSlot>>,
+}
+
+impl TokenCache {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Return the cached token for `key`, minting a new one when it is missing or close to
+    /// expiry. Concurrent callers for the same account wait for a single refresh.
+    pub async fn get_or_refresh(&self, key: &ServiceAccountKey, http: &reqwest::Client) -> Result<AccessToken> {
+        let slot = self.slot(&key.client_email);
+        let mut cached = slot.lock().await;
+
+        if let Some(token) = cached.as_ref() {
+            if token.expires_at > Instant::now() + TOKEN_REFRESH_MARGIN {
+                return Ok(token.clone());
+            }
+        }
+
+        log::debug!("Minting Vertex AI access token for {}", key.client_email);
+        match key.fetch_access_token(http).await {
+            Ok(token) => {
+                vertex_log_event("info", "token_refresh", "success", &format!("Refreshed access token for {}", key.client_email));
+                *cached = Some(token.clone());
+                Ok(token)
+            }
+            Err(e) => {
+                vertex_log_event("error", "token_refresh", "failed", &format!("Token refresh for {} failed: {:#}", key.client_email, e));
+                Err(e)
+            }
+        }
+    }
+
+    fn slot(&self, client_email: &str) -> TokenSlot {
+        self.slots
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .entry(client_email.to_string())
+            .or_default()
+            .clone()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::vertex::credentials_manager::{test_service_account, CredentialManager};
+    use axum::{extract::State, http::StatusCode, routing::post, Json, Router};
+    use serde_json::json;
+    use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+
+    #[derive(Clone, Default)]
+    struct TokenEndpoint {
+        calls: Arc<AtomicUsize>,
+        expires_in: Arc<AtomicU64>,
+        status: Arc<AtomicU64>,
+    }
+
+    async fn mock_token(State(endpoint): State<TokenEndpoint>) -> (StatusCode, Json<serde_json::Value>) {
+        let call = endpoint.calls.fetch_add(1, Ordering::SeqCst) + 1;
+        let status = StatusCode::from_u16(endpoint.status.load(Ordering::SeqCst) as u16).unwrap();
+        if !status.is_success() {
+            return (status, Json(json!({"error": "invalid_grant", "error_description": "Invalid JWT Signature."})));
+        }
+        let body = json!({
+            "access_token": format!("token-{}", call),
+            "expires_in": endpoint.expires_in.load(Ordering::SeqCst),
+            "token_type": "Bearer"
+        });
+        (status, Json(body))
+    }
+
+    async fn token_endpoint() -> (TokenEndpoint, String) {
+        let endpoint = TokenEndpoint::default();
+        endpoint.expires_in.store(3600, Ordering::SeqCst);
+        endpoint.status.store(200, Ordering::SeqCst);
+        let app = Router::new().route("/token", post(mock_token)).with_state(endpoint.clone());
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let token_uri = format!("http://{}/token", listener.local_addr().unwrap());
+        tokio::spawn(async move { axum::serve(listener, app).await.unwrap() });
+        (endpoint, token_uri)
+    }
+
+    #[tokio::test]
+    async fn test_cached_token_is_reused() {
+        let (endpoint, token_uri) = token_endpoint().await;
+        let key = ServiceAccountKey::from_value(&test_service_account(&token_uri)).unwrap();
+        let cache = TokenCache::new();
+        let http = reqwest::Client::new();
+
+        let first = cache.get_or_refresh(&key, &http).await.unwrap();
+        let second = cache.get_or_refresh(&key, &http).await.unwrap();
+        assert_eq!(first.token, "token-1");
+        assert_eq!(second.token, "token-1");
+        assert_eq!(endpoint.calls.load(Ordering::SeqCst), 1);
+    }
+
+    #[tokio::test]
+    async fn test_concurrent_refresh_is_single_flight() {
+        let (endpoint, token_uri) = token_endpoint().await;
+        let key = ServiceAccountKey::from_value(&test_service_account(&token_uri)).unwrap();
+        let cache = TokenCache::new();
+        let http = reqwest::Client::new();
+
+        let tokens = futures_util::future::join_all((0..8).map(|_| cache.get_or_refresh(&key, &http))).await;
+        assert!(tokens.iter().all(|token| token.as_ref().unwrap().token == "token-1"));
+        assert_eq!(endpoint.calls.load(Ordering::SeqCst), 1);
+    }
+
+    #[tokio::test]
+    async fn test_token_close_to_expiry_is_refreshed() {
+        let (endpoint, token_uri) = token_endpoint().await;
+        // Inside the refresh margin, so every lookup mints a new token
+        endpoint.expires_in.store(TOKEN_REFRESH_MARGIN.as_secs() - 10, Ordering::SeqCst);
+        let key = ServiceAccountKey::from_value(&test_service_account(&token_uri)).unwrap();
+        let cache = TokenCache::new();
+        let http = reqwest::Client::new();
+
+        assert_eq!(cache.get_or_refresh(&key, &http).await.unwrap().token, "token-1");
+        assert_eq!(cache.get_or_refresh(&key, &http).await.unwrap().token, "token-2");
+
+        endpoint.expires_in.store(3600, Ordering::SeqCst);
+        assert_eq!(cache.get_or_refresh(&key, &http).await.unwrap().token, "token-3");
+        assert_eq!(cache.get_or_refresh(&key, &http).await.unwrap().token, "token-3");
+        assert_eq!(endpoint.calls.load(Ordering::SeqCst), 3);
+    }
+
+    #[tokio::test]
+    async fn test_rejected_credential_is_skipped() {
+        let (endpoint, token_uri) = token_endpoint().await;
+        endpoint.status.store(401, Ordering::SeqCst);
+        let dir = tempfile::tempdir().unwrap();
+        let manager = CredentialManager::new(dir.path().to_path_buf());
+        manager.save_multiple_credentials_to_files(&[test_service_account(&token_uri)]).unwrap();
+        let http = reqwest::Client::new();
+
+        let error = manager.access_token(&http).await.unwrap_err();
+        assert!(error.to_string().contains("rejected service account credential"));
+        assert!(manager.next_credential_file().unwrap().is_none());
+
+        // The file is no longer picked, so the endpoint is not asked again
+        let error = manager.access_token(&http).await.unwrap_err();
+        assert!(error.to_string().contains("No usable Vertex AI service account"));
+        assert_eq!(endpoint.calls.load(Ordering::SeqCst), 1);
+    }
+
+    #[tokio::test]
+    async fn test_token_endpoint_outage_keeps_credential() {
+        let (endpoint, token_uri) = token_endpoint().await;
+        endpoint.status.store(503, Ordering::SeqCst);
+        let dir = tempfile::tempdir().unwrap();
+        let manager = CredentialManager::new(dir.path().to_path_buf());
+        manager.save_multiple_credentials_to_files(&[test_service_account(&token_uri)]).unwrap();
+        let http = reqwest::Client::new();
+
+        assert!(manager.access_token(&http).await.is_err());
+        assert!(manager.next_credential_file().unwrap().is_some());
+
+        endpoint.status.store(200, Ordering::SeqCst);
+        let credential = manager.access_token(&http).await.unwrap();
+        assert_eq!(credential.key.project_id, "test-project");
+        assert_eq!(credential.token.token, "token-2");
+    }
+}
@@ -1,9 +1,11 @@
 use std::sync::Arc;
 use tokio::sync::RwLock;
 use crate::vertex::credentials_manager::CredentialManager;
+use crate::vertex::express::ExpressKeyManager;
 use crate::vertex::model_loader::refresh_models_config_cache;
 use crate::vertex::config::VertexConfig;
 use crate::config::Settings;
+use crate::utils::logging::{vertex_log_event, VERTEX_LOG_MANAGER};
 use anyhow::Result;
 
 // Rust equivalent of Python vertex/vertex_ai_init.py
@@ -16,6 +18,7 @@ lazy_static::lazy_static! {
 #[derive(Debug, Clone)]
 pub struct VertexAIClient {
     pub credential_manager: Arc<CredentialManager>,
+    pub express_keys: Arc<ExpressKeyManager>,
     pub config: VertexConfig,
     pub is_initialized: bool,
 }
@@ -24,11 +27,30 @@ impl VertexAIClient {
     pub fn new(credential_manager: CredentialManager, config: VertexConfig) -> Self {
         Self {
             credential_manager: Arc::new(credential_manager),
+            express_keys: Arc::new(ExpressKeyManager::new(&config.vertex_express_api_keys)),
             config,
             is_initialized: false,
         }
     }
 
+    /// Projects requests are sent to: `VERTEX_PROJECT_ID` when set, otherwise the
+    /// project of each service account
+    pub fn effective_project_ids(&self) -> Vec<String> {
+        match self.config.project_id {
+            Some(ref project_id) => vec![project_id.clone()],
+            None => self.credential_manager.project_ids(),
+        }
+    }
+
+    /// `express` while express mode is on and a key is usable, otherwise `service_account`
+    pub fn mode(&self) -> &'static str {
+        if self.config.vertex_express_enabled && self.express_keys.available_count() > 0 {
+            "express"
+        } else {
+            "service_account"
+        }
+    }
+
     /// Check if the client has valid credentials
     pub async fn has_credentials(&self) -> bool {
         // Check if we have environment credentials
@@ -36,6 +58,11 @@ impl VertexAIClient {
             return true;
         }
 
+        // Express mode only needs API keys
+        if self.config.vertex_express_enabled && self.express_keys.has_keys() {
+            return true;
+        }
+
         // Check if we have file-based credentials
         match self.credential_manager.get_all_credential_files() {
             Ok(files) => !files.is_empty(),
@@ -88,22 +115,12 @@ pub async fn init_vertex_ai(
         }
         None => {
             log::info!("Creating new CredentialManager instance");
-            let credentials_dir = settings.credentials_dir
-                .as_ref()
-                .map(std::path::PathBuf::from)
-                .unwrap_or_else(|| {
-                    let mut path = std::path::PathBuf::from(&settings.storage_dir);
-                    path.push("credentials");
-                    path
-                });
-            CredentialManager::new(credentials_dir)
+            CredentialManager::new(crate::vertex::config::resolve_credentials_dir(settings))
         }
     };
 
-    let mut env_creds_loaded_into_manager = false;
-
     // Process Google credentials JSON if available
-    if let Some(ref credentials_json_str) = settings.google_credentials_json {
+    if let Some(ref credentials_json_str) = crate::vertex::config::non_empty(&settings.google_credentials_json) {
         if !credentials_json_str.trim().is_empty() {
             log::info!("Processing GOOGLE_CREDENTIALS_JSON from environment");
 
@@ -115,7 +132,6 @@ pub async fn init_vertex_ai(
                         match cred_manager.save_multiple_credentials_to_files(&credentials_list) {
                             Ok(saved_files) => {
                                 log::info!("Successfully saved {} credential file(s)", saved_files.len());
-                                env_creds_loaded_into_manager = true;
                             }
                             Err(e) => {
                                 log::error!("Failed to save credentials to files: {}", e);
@@ -136,6 +152,11 @@ pub async fn init_vertex_ai(
         log::debug!("GOOGLE_CREDENTIALS_JSON not set in environment");
     }
 
+    // Start the rotation from the files now on disk
+    if let Err(e) = cred_manager.rescan() {
+        log::warn!("Failed to scan credentials directory: {}", e);
+    }
+
     // Create Vertex configuration
     let vertex_config = VertexConfig::from_settings(settings);
 
@@ -151,6 +172,7 @@ pub async fn init_vertex_ai(
             }
 
             log::info!("Vertex AI initialization completed successfully");
+            vertex_log_event("info", "init", "success", "Vertex AI initialization completed");
 
             // Refresh model configuration cache
             if let Err(e) = refresh_models_config_cache(settings).await {
@@ -161,6 +183,7 @@ pub async fn init_vertex_ai(
         }
         Err(e) => {
             log::error!("Failed to initialize Vertex AI client: {}", e);
+            vertex_log_event("error", "init", "failed", &format!("Vertex AI initialization failed: {:#}", e));
             Ok(false)
         }
     }
@@ -183,6 +206,7 @@ pub async fn is_vertex_ai_available() -> bool {
 /// Reinitialize Vertex AI with updated settings
 pub async fn reinitialize_vertex_ai(settings: &Settings) -> Result<bool> {
     log::info!("Reinitializing Vertex AI");
+    vertex_log_event("info", "reinit", "started", "Reinitializing Vertex AI");
 
     // Reset the global client first
     reset_global_fallback_client().await;
@@ -191,11 +215,16 @@ pub async fn reinitialize_vertex_ai(settings: &Settings) -> Result<bool> {
     init_vertex_ai(settings, None).await
 }
 
+/// Error-level vertex logs within this window count towards `recent_error_logs`
+const RECENT_ERROR_WINDOW_MINUTES: i64 = 15;
+
 /// Get initialization status and diagnostic information
 pub async fn get_vertex_ai_status() -> serde_json::Value {
     use serde_json::json;
 
     let client = get_global_fallback_client().await;
+    let since = chrono::Utc::now() - chrono::Duration::minutes(RECENT_ERROR_WINDOW_MINUTES);
+    let recent_error_logs = VERTEX_LOG_MANAGER.count_since("error", since);
 
     match client {
         Some(client) => {
@@ -206,14 +235,20 @@ pub async fn get_vertex_ai_status() -> serde_json::Value {
                 "api_key_set": !client.config.api_key.is_empty(),
                 "google_credentials_set": client.config.google_credentials_json.is_some(),
                 "project_id": client.config.project_id,
+                "effective_project_ids": client.effective_project_ids(),
                 "location": client.config.location,
-                "vertex_express_keys_count": client.config.vertex_express_api_keys.len()
+                "mode": client.mode(),
+                "vertex_express_enabled": client.config.vertex_express_enabled,
+                "vertex_express_keys_count": client.config.vertex_express_api_keys.len(),
+                "vertex_express_keys": client.express_keys.summary(),
+                "recent_error_logs": recent_error_logs
             })
         }
         None => {
             json!({
                 "initialized": false,
                 "has_credentials": false,
+                "recent_error_logs": recent_error_logs,
                 "error": "No Vertex AI client available"
             })
         }